@@ -0,0 +1,9 @@
+fn main() {
+    // proto3 optional fields need the flag on protoc before 3.15
+    #[cfg(feature = "grpc")]
+    tonic_build::configure()
+        .build_client(false)
+        .protoc_arg("--experimental_allow_proto3_optional")
+        .compile(&["proto/darkfid.proto"], &["proto"])
+        .expect("Compiling proto/darkfid.proto failed");
+}
@@ -1,7 +1,9 @@
 CREATE TABLE IF NOT EXISTS keys(
 	key_id INTEGER PRIMARY KEY NOT NULL,
 	key_public BLOB NOT NULL,
-	key_private BLOB NOT NULL
+	key_private BLOB NOT NULL,
+	account INTEGER,
+	key_index INTEGER
 );
 PRAGMA foreign_keys=on;
 CREATE TABLE IF NOT EXISTS coins(
@@ -13,5 +15,80 @@ CREATE TABLE IF NOT EXISTS coins(
 	token_id INT NOT NULL,
 	witness BLOB NOT NULL,
 	secret BLOB NOT NULL,
-	is_spent BLOB NOT NULL
+	is_spent BLOB NOT NULL,
+	memo BLOB NOT NULL
+);
+CREATE TABLE IF NOT EXISTS lookahead_keys(
+	key_public BLOB PRIMARY KEY NOT NULL
+);
+CREATE TABLE IF NOT EXISTS seed(
+	seed_id INTEGER PRIMARY KEY NOT NULL,
+	mnemonic BLOB NOT NULL
+);
+CREATE TABLE IF NOT EXISTS spends(
+	spend_id INTEGER NOT NULL,
+	coin BLOB NOT NULL
+);
+CREATE TABLE IF NOT EXISTS tx_history(
+	tx_id INTEGER PRIMARY KEY NOT NULL,
+	direction TEXT NOT NULL,
+	amount INT NOT NULL,
+	token_id BLOB NOT NULL,
+	counterparty BLOB,
+	slab_index INT,
+	slab_hash BLOB,
+	timestamp INT NOT NULL,
+	change_coin BLOB,
+	memo BLOB NOT NULL
+);
+CREATE TABLE IF NOT EXISTS tx_status(
+	tx_hash BLOB PRIMARY KEY NOT NULL,
+	status TEXT NOT NULL,
+	slab_index INT,
+	updated INT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS diversifiers(
+	key_public BLOB NOT NULL,
+	diversifier BLOB NOT NULL,
+	PRIMARY KEY(key_public, diversifier)
+);
+CREATE TABLE IF NOT EXISTS lookahead_diversifiers(
+	diversifier BLOB PRIMARY KEY NOT NULL
+);
+CREATE TABLE IF NOT EXISTS address_labels(
+	diversifier BLOB PRIMARY KEY NOT NULL,
+	label TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS view_keys(
+	view_key BLOB PRIMARY KEY NOT NULL
+);
+CREATE TABLE IF NOT EXISTS watched_coins(
+	coin BLOB PRIMARY KEY NOT NULL,
+	value INT NOT NULL,
+	token_id BLOB NOT NULL,
+	memo BLOB NOT NULL
+);
+CREATE TABLE IF NOT EXISTS audit_log(
+	event_id INTEGER PRIMARY KEY NOT NULL,
+	timestamp INT NOT NULL,
+	event TEXT NOT NULL,
+	detail TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS freeze(
+	frozen_at INT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS failed_operations(
+	op_id INTEGER PRIMARY KEY NOT NULL,
+	timestamp INT NOT NULL,
+	method TEXT NOT NULL,
+	params TEXT NOT NULL,
+	reason TEXT NOT NULL,
+	detail TEXT NOT NULL,
+	retried INT NOT NULL DEFAULT 0
+);
+CREATE TABLE IF NOT EXISTS contacts(
+	name TEXT PRIMARY KEY NOT NULL,
+	address TEXT NOT NULL,
+	key_public BLOB NOT NULL,
+	payment_limit INT
 );
@@ -1,5 +1,6 @@
 use async_std::sync::{Arc, Mutex};
 use std::collections::HashMap;
+use std::fs;
 use std::path::PathBuf;
 use std::str::FromStr;
 
@@ -13,20 +14,25 @@ use rand::rngs::OsRng;
 use serde_json::{json, Value};
 
 use drk::{
-    blockchain::{rocks::columns, Rocks, RocksColumn},
-    cli::{CashierdConfig, Config},
+    blockchain::{kv::columns, KvBackend, KvColumn, SyncMetrics},
+    cli::{cli_config::Cashier, CashierdConfig, Config},
     client::{Client, State},
     crypto::{
-        load_params, merkle::CommitmentTree, save_params, setup_mint_prover, setup_spend_prover,
+        load_params, save_params, schnorr, setup_mint_prover, setup_spend_prover, Address,
+        AddressNetwork, BatchVerifyingKey, VerifyPool, MINT_PARAMS_HASH, SPEND_PARAMS_HASH,
     },
     rpc::{
         jsonrpc::{error as jsonerr, response as jsonresp},
         jsonrpc::{ErrorCode::*, JsonRequest, JsonResult},
-        rpcserver::{listen_and_serve, RequestHandler, RpcServerConfig},
+        rpcserver::{listen_and_serve, RequestHandler, RpcServerConfig, DEFAULT_MAX_REQUEST_SIZE},
     },
     serial::{deserialize, serialize},
-    service::{bridge, bridge::Bridge},
-    util::{expand_path, generate_id, join_config_path, parse::truncate, NetworkName},
+    service::{bridge, bridge::Bridge, server_identity, Socks5Proxy, TlsClientConfig},
+    system::Subscriber,
+    util::{
+        expand_path, generate_id, join_config_path, parse::truncate, CashierProvisioning, Datadir,
+        NetworkName,
+    },
     wallet::{cashierdb::TokenKey, CashierDb, WalletDb},
     Error, Result,
 };
@@ -53,6 +59,7 @@ struct Cashierd {
     bridge: Arc<Bridge>,
     cashier_wallet: Arc<CashierDb>,
     networks: Vec<Network>,
+    address_network: AddressNetwork,
 }
 
 #[async_trait]
@@ -68,6 +75,7 @@ impl RequestHandler for Cashierd {
             Some("deposit") => return self.deposit(req.id, req.params, executor).await,
             Some("withdraw") => return self.withdraw(req.id, req.params).await,
             Some("features") => return self.features(req.id, req.params).await,
+            Some("ping") => return self.ping(req.id, req.params).await,
             Some(_) => {}
             None => {}
         };
@@ -80,8 +88,15 @@ impl Cashierd {
     async fn new(config: CashierdConfig) -> Result<Self> {
         debug!(target: "CASHIER DAEMON", "Initialize");
 
+        let datadir = Datadir::open(&config.datadir, "cashierd")?;
+        let cashier_wallet_path = datadir.migrate(
+            config.cashier_wallet_path.as_deref(),
+            "~/.config/darkfi/cashier_wallet.db",
+            datadir.file("cashier_wallet.db"),
+        )?;
+
         let cashier_wallet = CashierDb::new(
-            expand_path(&config.cashier_wallet_path.clone())?.as_path(),
+            cashier_wallet_path.as_path(),
             config.cashier_wallet_password.clone(),
         )?;
 
@@ -95,12 +110,15 @@ impl Cashierd {
             });
         }
 
+        let address_network = AddressNetwork::from_str(&config.address_network)?;
+
         let bridge = bridge::Bridge::new();
 
         Ok(Self {
             bridge,
             cashier_wallet,
             networks,
+            address_network,
         })
     }
 
@@ -427,8 +445,8 @@ impl Cashierd {
                 )?;
             }
 
-            let cashier_public_str = bs58::encode(serialize(&cashier_public)).into_string();
-            Ok(cashier_public_str)
+            let cashier_address = Address::legacy(cashier_public).encode(self.address_network);
+            Ok(cashier_address)
         }
         .await;
 
@@ -449,6 +467,12 @@ impl Cashierd {
         ))
     }
 
+    // --> {"method": "ping", "params": []}
+    // <-- {"result": "pong"}
+    async fn ping(&self, id: Value, _params: Value) -> JsonResult {
+        JsonResult::Resp(jsonresp(json!("pong"), id))
+    }
+
     fn check_token_id(network: &NetworkName, _token_id: &str) -> Result<Option<String>> {
         match network {
             #[cfg(feature = "sol")]
@@ -570,7 +594,8 @@ impl Cashierd {
             executor.clone(),
         ));
 
-        client.start().await?;
+        let last_applied_index = state.lock().await.last_applied_index()?;
+        client.start(last_applied_index).await?;
 
         let (notify, recv_coin) = async_channel::unbounded::<(jubjub::SubgroupPoint, u64)>();
 
@@ -619,6 +644,8 @@ impl Cashierd {
                             received_balance,
                             token_notification.token_id,
                             true,
+                            0,
+                            None,
                         )
                         .await?;
                 }
@@ -637,61 +664,118 @@ async fn start(
     executor: Arc<Executor<'_>>,
     config: &CashierdConfig,
     get_address_flag: bool,
+    provision_path: Option<String>,
 ) -> Result<()> {
     let mut cashierd = Cashierd::new(config.clone()).await?;
 
+    let datadir = Datadir::open(&config.datadir, "cashierd")?;
+
+    let client_wallet_path = datadir.migrate(
+        config.client_wallet_path.as_deref(),
+        "~/.config/darkfi/cashier_client_wallet.db",
+        datadir.file("client_wallet.db"),
+    )?;
     let client_wallet = WalletDb::new(
-        expand_path(&config.client_wallet_path.clone())?.as_path(),
+        client_wallet_path.as_path(),
         config.client_wallet_password.clone(),
     )?;
 
-    let rocks = Rocks::new(expand_path(&config.database_path.clone())?.as_path())?;
+    let database_path = datadir.migrate(
+        config.database_path.as_deref(),
+        "~/.config/darkfi/cashier_database.db",
+        datadir.file("client.db"),
+    )?;
+    let store = KvBackend::from_str(&config.kv_backend)?.open(database_path.as_path())?;
 
     let params_paths = (
-        expand_path(&config.mint_params_path.clone())?,
-        expand_path(&config.spend_params_path.clone())?,
+        datadir.migrate(
+            config.mint_params_path.as_deref(),
+            "mint.params",
+            datadir.mint_params(),
+        )?,
+        datadir.migrate(
+            config.spend_params_path.as_deref(),
+            "spend.params",
+            datadir.spend_params(),
+        )?,
     );
 
-    let mint_params_path = params_paths.0.to_str().unwrap_or("mint.params");
-    let spend_params_path = params_paths.1.to_str().unwrap_or("spend.params");
+    let (mint_params_path, spend_params_path) = (&params_paths.0, &params_paths.1);
     // Auto create trusted ceremony parameters if they don't exist
-    if !params_paths.0.exists() {
+    if !mint_params_path.exists() {
         let params = setup_mint_prover();
         save_params(mint_params_path, &params)?;
     }
-    if !params_paths.1.exists() {
+    if !spend_params_path.exists() {
         let params = setup_spend_prover();
         save_params(spend_params_path, &params)?;
     }
 
     // Load trusted setup parameters
-    let (mint_params, mint_pvk) = load_params(mint_params_path)?;
-    let (spend_params, spend_pvk) = load_params(spend_params_path)?;
-
-    let client = Client::new(
-        rocks.clone(),
-        (
+    let (mint_params, mint_pvk) = load_params(mint_params_path, MINT_PARAMS_HASH)?;
+    let (spend_params, spend_pvk) = load_params(spend_params_path, SPEND_PARAMS_HASH)?;
+    let mint_batch_vk = BatchVerifyingKey::new(&mint_params.vk);
+    let spend_batch_vk = BatchVerifyingKey::new(&spend_params.vk);
+    let verify_pool = Arc::new(VerifyPool::new(num_cpus::get()));
+
+    let mut client = Client::new(
+        store.clone(),
+        vec![(
             config.gateway_protocol_url.parse()?,
             config.gateway_publisher_url.parse()?,
-        ),
+        )],
+        TlsClientConfig::load(config.tls_ca_cert_path.as_deref(), &config.tls_pinned_certs)?
+            .with_client_cert(
+                config.tls_client_cert_path.as_deref(),
+                config.tls_client_key_path.as_deref(),
+            )?,
+        config
+            .socks5_proxy
+            .as_deref()
+            .map(Socks5Proxy::parse)
+            .transpose()?,
         client_wallet.clone(),
         mint_params,
         spend_params,
+        verify_pool.clone(),
     )
     .await?;
+    if let Some(key) = &config.gateway_publisher_key {
+        let key = deserialize(&bs58::decode(key).into_vec()?)?;
+        client.set_gateway_publisher_key(schnorr::SecretKey(key));
+    }
+    let mut gateway_keys = vec![];
+    for key in config.gateway_keys.iter() {
+        gateway_keys.push(deserialize(&bs58::decode(key).into_vec()?)?);
+    }
+    client.set_gateway_keys(gateway_keys);
 
-    let merkle_roots = RocksColumn::<columns::MerkleRoots>::new(rocks.clone());
-    let nullifiers = RocksColumn::<columns::Nullifiers>::new(rocks);
+    let merkle_roots = KvColumn::<columns::MerkleRoots>::new(store.clone());
+    let nullifiers = KvColumn::<columns::Nullifiers>::new(store.clone());
+    let sync_state = KvColumn::<columns::SyncState>::new(store.clone());
+    let sync_metrics = SyncMetrics::new(KvColumn::<columns::SyncMetrics>::new(store.clone()));
+    let commitments = KvColumn::<columns::Commitments>::new(store.clone());
+    let undo = KvColumn::<columns::Undo>::new(store);
 
     let cashier_public_keys = vec![client.main_keypair.public];
 
     let state = Arc::new(Mutex::new(State {
-        tree: CommitmentTree::empty(),
+        tree: State::load_tree(&sync_state)?,
         merkle_roots,
         nullifiers,
-        mint_pvk,
-        spend_pvk,
+        mint_pvk: Arc::new(mint_pvk),
+        spend_pvk: Arc::new(spend_pvk),
+        mint_batch_vk: Arc::new(mint_batch_vk),
+        spend_batch_vk: Arc::new(spend_batch_vk),
+        verify_pool,
         public_keys: cashier_public_keys,
+        sync_state,
+        sync_metrics,
+        commitments,
+        rescan_leaves: None,
+        undo,
+        events: Subscriber::new(),
+        light: false,
     }));
 
     if get_address_flag {
@@ -701,11 +785,26 @@ async fn start(
         return Ok(());
     };
 
+    // Sign a list of cashiers with our key, producing a provisioning file
+    // for darkfid
+    if let Some(path) = provision_path {
+        let cashiers: Vec<Cashier> = serde_json::from_str(&fs::read_to_string(path)?)?;
+        let secret = schnorr::SecretKey(client.main_keypair.private);
+        let provisioning = CashierProvisioning::sign(cashiers, &secret)?;
+        println!("{}", serde_json::to_string_pretty(&provisioning)?);
+        return Ok(());
+    }
+
     let cfg = RpcServerConfig {
         socket_addr: config.rpc_listen_address.clone(),
-        use_tls: config.serve_tls,
-        identity_path: expand_path(&config.clone().tls_identity_path)?,
-        identity_pass: config.tls_identity_password.clone(),
+        tls_identity: server_identity(
+            config.serve_tls,
+            config.tls_cert_path.as_deref(),
+            config.tls_key_path.as_deref(),
+        )?,
+        tls_client_ca: None,
+        cert_methods: &[],
+        max_request_size: DEFAULT_MAX_REQUEST_SIZE,
     };
 
     let (t1, t2, t3) = cashierd.start(client, state, executor.clone()).await?;
@@ -723,6 +822,7 @@ async fn main() -> Result<()> {
     let args = clap_app!(cashierd =>
         (@arg CONFIG: -c --config +takes_value "Sets a custom config file")
         (@arg ADDRESS: -a --address "Get Cashier Public key")
+        (@arg PROVISION: --provision +takes_value "Sign a JSON list of cashiers for darkfid")
         (@arg verbose: -v --verbose "Increase verbosity")
     )
     .get_matches();
@@ -749,6 +849,7 @@ async fn main() -> Result<()> {
     let ex2 = ex.clone();
 
     let get_address_flag = args.is_present("ADDRESS");
+    let provision_path = args.value_of("PROVISION").map(String::from);
 
     let nthreads = num_cpus::get();
     debug!(target: "CASHIER DAEMON", "Run {} executor threads", nthreads);
@@ -760,7 +861,7 @@ async fn main() -> Result<()> {
         // Run the main future on the current thread.
         .finish(|| {
             smol::future::block_on(async move {
-                start(ex2, &config, get_address_flag).await?;
+                start(ex2, &config, get_address_flag, provision_path).await?;
                 drop(signal);
                 Ok::<(), drk::Error>(())
             })
@@ -0,0 +1,80 @@
+use std::fs;
+
+use clap::clap_app;
+use serde_json::{json, Value};
+
+use drk::{
+    schema::{check, golden_vectors, schema_json},
+    Error, Result,
+};
+
+// Every entry of the file is a type name and a hex encoding, the same
+// layout `vectors` prints. Returns the number of entries which failed.
+fn check_file(path: &str) -> Result<usize> {
+    let entries: Vec<Value> = serde_json::from_str(&fs::read_to_string(path)?)?;
+
+    let mut failed = 0;
+    for entry in entries {
+        let name = entry["type"]
+            .as_str()
+            .ok_or(Error::ParseFailed("Entry without a type"))?;
+        let data = entry["hex"]
+            .as_str()
+            .ok_or(Error::ParseFailed("Entry without hex data"))?;
+
+        let result = match hex::decode(data) {
+            Ok(data) => check(name, &data),
+            Err(_) => Err(Error::ParseFailed("Invalid hex")),
+        };
+
+        match result {
+            Ok(()) => println!("ok    {}", name),
+            Err(e) => {
+                println!("FAIL  {}: {}", name, e);
+                failed += 1;
+            }
+        }
+    }
+
+    Ok(failed)
+}
+
+fn main() -> Result<()> {
+    let args = clap_app!(darkficonformance =>
+        (about: "Wire encoding schema and conformance checks for other implementations")
+        (@subcommand schema =>
+         (about: "Print the wire schema as JSON")
+        )
+        (@subcommand vectors =>
+         (about: "Print golden vectors to decode and encode back")
+        )
+        (@subcommand check =>
+         (about: "Check encodings made by another implementation")
+         (@arg FILE: +required "JSON list of {\"type\", \"hex\"} entries")
+        )
+    )
+    .get_matches();
+
+    match args.subcommand() {
+        ("schema", Some(_)) => {
+            println!("{}", serde_json::to_string_pretty(&schema_json())?);
+        }
+        ("vectors", Some(_)) => {
+            let vectors: Vec<Value> = golden_vectors()?
+                .into_iter()
+                .map(|(name, data)| json!({"type": name, "hex": hex::encode(data)}))
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&vectors)?);
+        }
+        ("check", Some(matches)) => {
+            let failed = check_file(matches.value_of("FILE").unwrap())?;
+            if failed > 0 {
+                println!("{} encodings don't conform", failed);
+                std::process::exit(1);
+            }
+        }
+        _ => println!("{}", args.usage()),
+    }
+
+    Ok(())
+}
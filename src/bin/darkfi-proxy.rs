@@ -0,0 +1,257 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use async_executor::Executor;
+use async_std::sync::{Arc, Mutex};
+use clap::{clap_app, ArgMatches};
+use easy_parallel::Parallel;
+use log::{debug, info, warn};
+use smol::Timer;
+
+use drk::{
+    serial::serialize,
+    service::{
+        read_recording,
+        reqrep::{Publisher, RepProtocol, Reply, ReqProtocol, Subscriber},
+        FrameKind, RecordingWriter, Replay,
+    },
+    Error, Result,
+};
+
+// Error code the gateway replies with for slabs it doesn't have, used for
+// requests the recording has no reply to
+const NOT_RECORDED_ERROR: u32 = 2;
+
+async fn start_publisher(addr: SocketAddr, queue: async_channel::Receiver<Vec<u8>>) -> Result<()> {
+    let mut publisher = Publisher::new(addr, String::from("PROXY"));
+    publisher.start(queue).await
+}
+
+// Pass the client's requests on to the gateway and the gateway's published
+// slabs on to the client, writing all of them to the recording
+async fn record(executor: Arc<Executor<'_>>, options: &ArgMatches<'_>) -> Result<()> {
+    let gateway_addr: SocketAddr = options.value_of("gateway").unwrap().parse()?;
+    let gateway_pub_addr: SocketAddr = options.value_of("gatewaypub").unwrap().parse()?;
+    let listen_addr: SocketAddr = options.value_of("listen").unwrap().parse()?;
+    let listen_pub_addr: SocketAddr = options.value_of("listenpub").unwrap().parse()?;
+    let output = PathBuf::from(options.value_of("output").unwrap());
+
+    let recording = Arc::new(Mutex::new(RecordingWriter::create(&output)?));
+    info!("Recording to {}", output.display());
+
+    let (publish_queue, publish_recv_queue) = async_channel::unbounded::<Vec<u8>>();
+    let publisher_task = executor.spawn(start_publisher(listen_pub_addr, publish_recv_queue));
+
+    let mut subscriber = Subscriber::new(gateway_pub_addr, String::from("PROXY"));
+    subscriber.start().await?;
+
+    let sub_recording = recording.clone();
+    let subscriber_task: smol::Task<Result<()>> = executor.spawn(async move {
+        loop {
+            let data = subscriber.fetch_bytes().await?;
+            debug!(target: "PROXY", "Published {} bytes", data.len());
+            sub_recording
+                .lock()
+                .await
+                .write(FrameKind::Publish, data.clone())?;
+            publish_queue.send(data).await?;
+        }
+    });
+
+    let mut gateway = ReqProtocol::new(gateway_addr, String::from("PROXY"));
+    gateway.start().await?;
+    let gateway = Arc::new(gateway);
+
+    let mut protocol = RepProtocol::new(listen_addr, String::from("PROXY"));
+    let (send, recv) = protocol.start().await?;
+
+    // Requests are passed on as they come, with any number of them
+    // waiting for the gateway at once
+    let ex = executor.clone();
+    let forward_task = executor.spawn(async move {
+        while let Ok((peer, request)) = recv.recv().await {
+            debug!(target: "PROXY", "Request {{ command: {} }}", request.get_command());
+            recording
+                .lock()
+                .await
+                .write(FrameKind::Request, serialize(&request))?;
+
+            let gateway = gateway.clone();
+            let recording = recording.clone();
+            let send = send.clone();
+            ex.spawn(async move {
+                // A request the gateway doesn't answer goes unanswered
+                // here too, and the client times out
+                let reply = match gateway.forward(&request).await {
+                    Ok(reply) => reply,
+                    Err(e) => {
+                        warn!(target: "PROXY", "Request failed: {}", e);
+                        return Ok(());
+                    }
+                };
+                recording
+                    .lock()
+                    .await
+                    .write(FrameKind::Reply, serialize(&reply))?;
+
+                send.send((peer, reply)).await?;
+                Ok::<(), Error>(())
+            })
+            .detach();
+        }
+        Ok::<(), Error>(())
+    });
+
+    // Runs until interrupted
+    protocol.run(executor.clone()).await?;
+
+    let _ = forward_task.cancel().await;
+    let _ = subscriber_task.cancel().await;
+    let _ = publisher_task.cancel().await;
+    Ok(())
+}
+
+// Stand in for the gateway of a recording. Requests get the recorded reply
+// to the same request, and the recorded slabs are published with their
+// original spacing, counted from the client's first request.
+async fn replay(executor: Arc<Executor<'_>>, options: &ArgMatches<'_>) -> Result<()> {
+    let listen_addr: SocketAddr = options.value_of("listen").unwrap().parse()?;
+    let listen_pub_addr: SocketAddr = options.value_of("listenpub").unwrap().parse()?;
+    let input = PathBuf::from(options.value_of("input").unwrap());
+    let speed = match options.value_of("speed") {
+        Some(speed) => speed.parse::<f64>()?,
+        None => 1.0,
+    };
+    if speed <= 0.0 {
+        return Err(Error::ParseFailed("Replay speed must be positive"));
+    }
+
+    let frames = read_recording(&input)?;
+    let replay = Replay::new(&frames)?;
+    let schedule = replay.publish_schedule(speed);
+    info!(
+        "Replaying {} frames from {} at {}x speed",
+        frames.len(),
+        input.display(),
+        speed
+    );
+
+    let (publish_queue, publish_recv_queue) = async_channel::unbounded::<Vec<u8>>();
+    let publisher_task = executor.spawn(start_publisher(listen_pub_addr, publish_recv_queue));
+
+    let mut protocol = RepProtocol::new(listen_addr, String::from("PROXY"));
+    let (send, recv) = protocol.start().await?;
+
+    let (started_s, started_r) = async_channel::bounded::<()>(1);
+
+    let reply_task = executor.spawn(async move {
+        let mut started = Some(started_s);
+        while let Ok((peer, request)) = recv.recv().await {
+            if let Some(started) = started.take() {
+                started.send(()).await?;
+            }
+
+            let reply = match replay.reply(&request) {
+                Some(reply) => reply,
+                None => {
+                    warn!(
+                        target: "PROXY",
+                        "No recorded reply to command {}",
+                        request.get_command()
+                    );
+                    Reply::from(&request, NOT_RECORDED_ERROR, vec![])
+                }
+            };
+            send.send((peer, reply)).await?;
+        }
+        Ok::<(), Error>(())
+    });
+
+    let publish_task = executor.spawn(async move {
+        started_r.recv().await?;
+        let start = Instant::now();
+        for (offset, data) in schedule {
+            if let Some(wait) = offset.checked_sub(start.elapsed()) {
+                Timer::after(wait).await;
+            }
+            debug!(target: "PROXY", "Publish {} bytes", data.len());
+            publish_queue.send(data).await?;
+        }
+        info!("Replayed all published slabs");
+        Ok::<(), Error>(())
+    });
+
+    // Runs until interrupted
+    protocol.run(executor.clone()).await?;
+
+    let _ = publish_task.cancel().await;
+    let _ = reply_task.cancel().await;
+    let _ = publisher_task.cancel().await;
+    Ok(())
+}
+
+#[async_std::main]
+async fn main() -> Result<()> {
+    let args = clap_app!(darkfiproxy =>
+        (@arg verbose: -v --verbose "Increase verbosity")
+        (@subcommand record =>
+         (about: "Proxy between darkfid and a gateway, recording the traffic")
+         (@arg gateway: +required +takes_value --gateway
+          "Protocol address of the gateway (host:port)")
+         (@arg gatewaypub: +required +takes_value --("gateway-pub")
+          "Publisher address of the gateway (host:port)")
+         (@arg listen: +required +takes_value --listen
+          "Address to serve the protocol API on")
+         (@arg listenpub: +required +takes_value --("listen-pub")
+          "Address to serve the publisher API on")
+         (@arg output: +required +takes_value -o --output "File to write the recording to")
+        )
+        (@subcommand replay =>
+         (about: "Serve a recording to a client in place of the gateway")
+         (@arg input: +required +takes_value -i --input "Recording to replay")
+         (@arg listen: +required +takes_value --listen
+          "Address to serve the protocol API on")
+         (@arg listenpub: +required +takes_value --("listen-pub")
+          "Address to serve the publisher API on")
+         (@arg speed: +takes_value --speed
+          "Speed up published slabs by this factor (default: 1)")
+        )
+    )
+    .get_matches();
+
+    let loglevel = if args.is_present("verbose") {
+        log::Level::Debug
+    } else {
+        log::Level::Info
+    };
+
+    simple_logger::init_with_level(loglevel)?;
+
+    let ex = Arc::new(Executor::new());
+    let (signal, shutdown) = async_channel::unbounded::<()>();
+
+    let ex2 = ex.clone();
+
+    let nthreads = num_cpus::get();
+    debug!(target: "PROXY", "Run {} executor threads", nthreads);
+
+    let (_, result) = Parallel::new()
+        .each(0..nthreads, |_| {
+            smol::future::block_on(ex.run(shutdown.recv()))
+        })
+        // Run the main future on the current thread.
+        .finish(|| {
+            smol::future::block_on(async move {
+                match args.subcommand() {
+                    ("record", Some(matches)) => record(ex2, matches).await?,
+                    ("replay", Some(matches)) => replay(ex2, matches).await?,
+                    _ => println!("{}", args.usage()),
+                }
+                drop(signal);
+                Ok::<(), Error>(())
+            })
+        });
+
+    result
+}
@@ -1,97 +1,578 @@
 use async_std::sync::{Arc, Mutex};
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use async_executor::Executor;
 use async_trait::async_trait;
-use clap::clap_app;
+use clap::{clap_app, Arg, ArgMatches, SubCommand};
 use easy_parallel::Parallel;
-use log::debug;
+use ff::Field;
+use futures::FutureExt;
+use log::{debug, info, warn};
+use rand::rngs::OsRng;
 use serde_json::{json, Value};
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::Signals,
+};
 use url::Url;
 
 use drk::{
-    blockchain::{rocks::columns, Rocks, RocksColumn},
+    blockchain::{
+        integrity::{check_state, repair_state},
+        kv::columns,
+        snapshot::restore_database,
+        KvBackend, KvColumn, KvStore, SnapshotSchedule, SnapshotStatus, StateExport,
+        StateSnapshots, SyncMetrics, SyncSummary,
+    },
     cli::{Config, DarkfidConfig},
-    client::{Client, State},
+    client::{total_amount, Client, ClientFailed, State},
+    state::StateEvent,
     crypto::{
-        load_params, merkle::CommitmentTree, save_params, setup_mint_prover, setup_spend_prover,
+        coin::Coin, load_params, merkle_node::MerkleNode, merkle_proof, note::Memo, save_params,
+        schnorr, setup_mint_prover, setup_spend_prover, Address, AddressNetwork, BatchVerifyingKey,
+        VerifyPool, ViewingKey, MINT_PARAMS_HASH, SPEND_PARAMS_HASH,
     },
     rpc::{
-        jsonrpc::{error as jsonerr, request as jsonreq, response as jsonresp, send_request},
-        jsonrpc::{ErrorCode::*, JsonRequest, JsonResult},
-        rpcserver::{listen_and_serve, RequestHandler, RpcServerConfig},
+        jsonrpc::{error as jsonerr, request as jsonreq, response as jsonresp},
+        jsonrpc::{send_request, ErrorCode::*, JsonError, JsonRequest, JsonResult},
+        prometheus::{listen_and_serve_metrics, write_metric, MetricsSource, METRICS},
+        rest::{listen_and_serve_rest, Route},
+        rpcserver::{
+            listen_and_serve, listen_and_serve_unix, RequestHandler, RestrictedHandler,
+            RpcServerConfig,
+        },
+        wsserver::{listen_and_serve_ws, Pusher, SubscriptionHandler},
     },
     serial::{deserialize, serialize},
+    service::{
+        server_identity, CashierClient, GatewayStats, Heartbeat, Socks5Proxy, TlsClientConfig,
+        GATEWAY_PROTOCOL_VERSION,
+    },
+    net::utility::sleep,
+    system::{Subscriber, SubscriberPtr},
     util::{
-        assign_id, decode_base10, encode_base10, expand_path, join_config_path, DrkTokenList,
-        NetworkName, SolTokenList,
+        assign_id, decode_base10, encode_base10, expand_path, fetch_params, init_file_logger,
+        join_config_path, support_bundle, CashierProvisioning, Clock, Datadir, Devnet,
+        DrkTokenList, NetworkName, RotatingFile, SolTokenList, SupportBundle, SystemClock,
+        Webhook, DATADIR_VERSION, DEFAULT_DEVNET_DIR,
+    },
+    wallet::{
+        platform_enclave, score_payment, BackupStatus, CoinSelectionStrategy, Contact,
+        PaymentPolicy, PaymentRiskInputs, RescanProgress, WalletBackup, WalletDb, WalletPtr,
     },
-    wallet::WalletDb,
     Error, Result,
 };
 
-#[derive(Clone, Debug)]
+#[cfg(feature = "grpc")]
+use drk::rpc::grpc::listen_and_serve_grpc;
+
+/// Number of slabs the sync metrics are aggregated over by default
+const DEFAULT_METRICS_COUNT: u64 = 100;
+
+/// How many state events are kept for RPC clients to catch up on
+const EVENT_LOG_SIZE: usize = 256;
+
+/// Longest an RPC client waits for a state event, in seconds
+const EVENT_WAIT: u32 = 60;
+
+/// Methods that can't move funds, change the wallet or read its keys. They
+/// are served on the read-only listener, and are the only ones that may be
+/// called without the rpc_token when one is set: every other method,
+/// including ones added later, needs it.
+const READ_ONLY_METHODS: &[&str] = &[
+    "say_hello",
+    "get_key",
+    "list_addresses",
+    "get_balances",
+    "get_balance",
+    "get_token_id",
+    "features",
+    "get_info",
+    "wait_events",
+    "get_sync_metrics",
+    "get_merkle_path",
+    "verify_merkle_path",
+    "gateway_stats",
+    "get_going_rate",
+    "privacy_report",
+    "get_tx_history",
+    "get_tx_status",
+    "get_failed_operations",
+    "get_watched_balances",
+    "get_contacts",
+];
+
+/// Methods that move funds, which need a client certificate on top of the
+/// rpc_token when tls_client_ca_path is set
+const CERT_METHODS: &[&str] = &["withdraw", "transfer", "transfer_many", "retry_failed_operation"];
+
+/// What the REST facade serves, with the names of the params each method
+/// takes from the body or query string
+const REST_ROUTES: &[Route] = &[
+    Route {
+        method: "GET",
+        path: "/balance",
+        rpc_method: "get_balance",
+        params: &[],
+    },
+    Route {
+        method: "GET",
+        path: "/txs",
+        rpc_method: "get_tx_history",
+        params: &[],
+    },
+    Route {
+        method: "GET",
+        path: "/tx",
+        rpc_method: "get_tx_status",
+        params: &["id"],
+    },
+    Route {
+        method: "GET",
+        path: "/info",
+        rpc_method: "get_info",
+        params: &[],
+    },
+    Route {
+        method: "POST",
+        path: "/transfer",
+        rpc_method: "transfer",
+        params: &[
+            "token",
+            "address",
+            "amount",
+            "coin_selection",
+            "fee",
+            "memo",
+            "confirmed",
+            "allow_duplicate",
+        ],
+    },
+];
+
+/// Seconds between checks of a transaction's status for subscribe_tx_status,
+/// which can change without a slab, e.g. when the gateway refuses it
+const TX_STATUS_CHECK_INTERVAL: u32 = 5;
+
+// The last state events, numbered from 1 as they happen
+#[derive(Default)]
+struct EventLog {
+    last_seq: u64,
+    events: VecDeque<Value>,
+}
+
+impl EventLog {
+    fn push(&mut self, mut event: Value) -> u64 {
+        self.last_seq += 1;
+        event["seq"] = json!(self.last_seq);
+        self.events.push_back(event);
+        if self.events.len() > EVENT_LOG_SIZE {
+            self.events.pop_front();
+        }
+        self.last_seq
+    }
+
+    // The events still kept that came after `seq`. A seq from before a
+    // restart gets them all.
+    fn after(&self, seq: u64) -> Vec<Value> {
+        let newer = match self.last_seq.checked_sub(seq) {
+            Some(newer) => newer as usize,
+            None => self.events.len(),
+        };
+        let skip = self.events.len().saturating_sub(newer);
+        self.events.iter().skip(skip).cloned().collect()
+    }
+}
+
+#[derive(Clone)]
 pub struct Cashier {
     pub name: String,
     pub rpc_url: String,
     pub public_key: jubjub::SubgroupPoint,
+    pub client: Arc<CashierClient>,
 }
 
 #[async_trait]
 impl RequestHandler for Darkfid {
-    async fn handle_request(&self, req: JsonRequest, _executor: Arc<Executor<'_>>) -> JsonResult {
+    async fn handle_request(
+        &self,
+        mut req: JsonRequest,
+        executor: Arc<Executor<'_>>,
+    ) -> JsonResult {
         if req.params.as_array().is_none() {
             return JsonResult::Err(jsonerr(InvalidParams, None, req.id));
         }
 
+        // Taken out before the request is logged
+        let auth = req.auth.take();
         debug!(target: "RPC", "--> {}", serde_json::to_string(&req).unwrap());
 
-        match req.method.as_str() {
-            Some("say_hello") => return self.say_hello(req.id, req.params).await,
-            Some("create_wallet") => return self.create_wallet(req.id, req.params).await,
-            Some("key_gen") => return self.key_gen(req.id, req.params).await,
-            Some("get_key") => return self.get_key(req.id, req.params).await,
-            Some("get_balances") => return self.get_balances(req.id, req.params).await,
-            Some("get_token_id") => return self.get_token_id(req.id, req.params).await,
-            Some("features") => return self.features(req.id, req.params).await,
-            Some("deposit") => return self.deposit(req.id, req.params).await,
-            Some("withdraw") => return self.withdraw(req.id, req.params).await,
-            Some("transfer") => return self.transfer(req.id, req.params).await,
-            Some(_) | None => return JsonResult::Err(jsonerr(MethodNotFound, None, req.id)),
+        let method = req.method.as_str().unwrap_or("").to_string();
+        if let Some(token) = &self.rpc_token {
+            let authorized = auth.map_or(false, |auth| token_matches(&auth, token));
+            if !READ_ONLY_METHODS.contains(&method.as_str()) && !authorized {
+                warn!("Refused an unauthorized {} request", method);
+                return JsonResult::Err(jsonerr(Unauthorized, None, req.id));
+            }
+        }
+        let reply = match req.method.as_str() {
+            Some("say_hello") => self.say_hello(req.id, req.params).await,
+            Some("create_wallet") => self.create_wallet(req.id, req.params).await,
+            Some("key_gen") => self.key_gen(req.id, req.params).await,
+            Some("get_key") => self.get_key(req.id, req.params).await,
+            Some("new_key") => self.new_key(req.id, req.params).await,
+            Some("new_address") => self.new_address(req.id, req.params).await,
+            Some("list_addresses") => self.list_addresses(req.id, req.params).await,
+            Some("get_balances") => self.get_balances(req.id, req.params).await,
+            Some("get_balance") => self.get_balance(req.id, req.params).await,
+            Some("get_token_id") => self.get_token_id(req.id, req.params).await,
+            Some("features") => self.features(req.id, req.params).await,
+            Some("deposit") => self.deposit(req.id, req.params).await,
+            Some("withdraw") => self.withdraw(req.id, req.params).await,
+            Some("transfer") => self.transfer(req.id, req.params).await,
+            Some("transfer_many") => self.transfer_many(req.id, req.params).await,
+            Some("get_info") => self.get_info(req.id, req.params).await,
+            Some("wait_events") => self.wait_events(req.id, req.params).await,
+            Some("get_sync_metrics") => self.get_sync_metrics(req.id, req.params).await,
+            Some("get_merkle_path") => self.get_merkle_path(req.id, req.params).await,
+            Some("verify_merkle_path") => self.verify_merkle_path(req.id, req.params).await,
+            Some("gateway_stats") => self.gateway_stats(req.id, req.params).await,
+            Some("get_going_rate") => self.get_going_rate(req.id, req.params).await,
+            Some("privacy_report") => self.privacy_report(req.id, req.params).await,
+            Some("get_tx_history") => self.get_tx_history(req.id, req.params).await,
+            Some("get_tx_status") => self.get_tx_status(req.id, req.params).await,
+            Some("get_mnemonic") => self.get_mnemonic(req.id, req.params).await,
+            Some("restore") => self.restore(req.id, req.params).await,
+            Some("rescan") => self.rescan(req.id, req.params, executor).await,
+            Some("backup_database") => self.backup_database(req.id, req.params).await,
+            Some("rollback") => self.rollback(req.id, req.params).await,
+            Some("export_view_key") => self.export_view_key(req.id, req.params).await,
+            Some("import_view_key") => self.import_view_key(req.id, req.params).await,
+            Some("import_sapling_key") => self.import_sapling_key(req.id, req.params).await,
+            Some("emergency_stop") => self.emergency_stop(req.id, req.params).await,
+            Some("unlock") => self.unlock(req.id, req.params).await,
+            Some("get_failed_operations") => self.get_failed_operations(req.id, req.params).await,
+            Some("retry_failed_operation") => self.retry_failed_operation(req.id, req.params).await,
+            Some("get_watched_balances") => self.get_watched_balances(req.id, req.params).await,
+            Some("get_contacts") => self.get_contacts(req.id, req.params).await,
+            Some("add_contact") => self.add_contact(req.id, req.params).await,
+            Some("remove_contact") => self.remove_contact(req.id, req.params).await,
+            Some(_) | None => JsonResult::Err(jsonerr(MethodNotFound, None, req.id)),
         };
+
+        // Unknown methods are counted together, so clients can't add
+        // labels at will
+        match &reply {
+            JsonResult::Err(e) if e.error.code == MethodNotFound.code() => {
+                METRICS.rpc_request("unknown")
+            }
+            _ => METRICS.rpc_request(&method),
+        }
+        reply
+    }
+}
+
+#[async_trait]
+impl SubscriptionHandler for Darkfid {
+    fn is_subscription(&self, method: &str) -> bool {
+        matches!(method, "subscribe_payments" | "subscribe_slabs" | "subscribe_tx_status")
+    }
+
+    // Over WebSocket only. Each is answered with a subscription id, then
+    // notified with [id, payload] until the connection is closed. The
+    // ones about the wallet need the rpc_token.
+    // --> {"method": "subscribe_payments", "params": []}
+    // Payments to the wallet, as POSTed to notify_url
+    // <-- {"method": "subscribe_payments", "params": [1, {"event": "payment_received", ...}]}
+    // --> {"method": "subscribe_slabs", "params": []}
+    // <-- {"method": "subscribe_slabs", "params": [2, {"index": 42}]}
+    // --> {"method": "subscribe_tx_status", "params": [txID]}
+    // The status as get_tx_status returns it, now and whenever it changes
+    // <-- {"method": "subscribe_tx_status", "params": [3, {"status": "confirmed", ...}]}
+    async fn subscribe(
+        &self,
+        req: JsonRequest,
+        pusher: Pusher,
+        executor: Arc<Executor<'_>>,
+    ) -> std::result::Result<(), JsonError> {
+        let method = req.method.as_str().unwrap_or("").to_string();
+        if let Some(token) = &self.rpc_token {
+            let authorized = req.auth.as_deref().map_or(false, |auth| token_matches(auth, token));
+            if method != "subscribe_slabs" && !authorized {
+                warn!("Refused an unauthorized {} request", method);
+                return Err(jsonerr(Unauthorized, None, req.id));
+            }
+        }
+        let tx_hash = match (method.as_str(), req.params.as_array().map(|args| args.as_slice())) {
+            ("subscribe_tx_status", Some([Value::String(tx_hash)])) => match parse_hash(tx_hash) {
+                Some(tx_hash) => Some(tx_hash),
+                None => return Err(jsonerr(InvalidParams, None, req.id)),
+            },
+            ("subscribe_tx_status", _) => return Err(jsonerr(InvalidParams, None, req.id)),
+            (_, Some([])) => None,
+            _ => return Err(jsonerr(InvalidParams, None, req.id)),
+        };
+        METRICS.rpc_request(&method);
+
+        let events = self.state.lock().await.events.clone().subscribe().await;
+        let (wallet, drk_tokenlist) = (self.wallet.clone(), self.drk_tokenlist.clone());
+        executor
+            .spawn(async move {
+                let mut last_status = None;
+                loop {
+                    let params = match tx_hash {
+                        Some(tx_hash) => match tx_status_json(&wallet, &tx_hash) {
+                            Ok(status) if last_status.as_ref() != Some(&status) => {
+                                last_status = Some(status.clone());
+                                Some(status)
+                            }
+                            Ok(_) => None,
+                            Err(e) => {
+                                warn!("Reading the status of a transaction failed: {}", e);
+                                None
+                            }
+                        },
+                        None => None,
+                    };
+                    if let Some(params) = params {
+                        if !pusher.push(params).await {
+                            break;
+                        }
+                    }
+
+                    let recheck = async {
+                        match tx_hash {
+                            Some(_) => sleep(TX_STATUS_CHECK_INTERVAL).await,
+                            None => futures::future::pending().await,
+                        }
+                    };
+                    let event = futures::select! {
+                        event = events.receive().fuse() => Some(event),
+                        _ = recheck.fuse() => None,
+                        _ = pusher.closed().fuse() => break,
+                    };
+                    let params = match (method.as_str(), event) {
+                        ("subscribe_payments", Some(event)) => {
+                            payment_payload(&event, &drk_tokenlist)
+                        }
+                        ("subscribe_slabs", Some(StateEvent::SlabApplied { index })) => {
+                            Some(json!({ "index": index }))
+                        }
+                        _ => None,
+                    };
+                    if let Some(params) = params {
+                        if !pusher.push(params).await {
+                            break;
+                        }
+                    }
+                }
+                events.unsubscribe().await;
+            })
+            .detach();
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MetricsSource for Darkfid {
+    async fn scrape(&self) -> String {
+        let mut out = String::new();
+        {
+            let stats = self.gateway_stats.lock().await;
+            write_metric(
+                &mut out,
+                "darkfi_gateway_subscribed",
+                "gauge",
+                "Whether the slab subscription is open",
+                stats.subscribed as u8,
+            );
+            write_metric(
+                &mut out,
+                "darkfi_gateway_latest_index",
+                "gauge",
+                "Highest slab index the gateway is known to have",
+                stats.latest_index,
+            );
+            write_metric(
+                &mut out,
+                "darkfi_gateway_slabs_received_total",
+                "counter",
+                "Slabs received through the subscription",
+                stats.slabs_received,
+            );
+            write_metric(
+                &mut out,
+                "darkfi_gateway_reconnects_total",
+                "counter",
+                "Times the subscription was re-established",
+                stats.reconnects,
+            );
+            write_metric(
+                &mut out,
+                "darkfi_gateway_failovers_total",
+                "counter",
+                "Times an unreachable gateway was given up for the next",
+                stats.failovers,
+            );
+            write_metric(
+                &mut out,
+                "darkfi_gateway_heartbeat_failures_total",
+                "counter",
+                "Times a connection went silent and was re-established",
+                stats.heartbeat_failures,
+            );
+        }
+
+        // A locked wallet has no coins to count
+        if let Ok(coins) = self.wallet.get_own_coins() {
+            write_metric(
+                &mut out,
+                "darkfi_wallet_coins",
+                "gauge",
+                "Unspent coins in the wallet",
+                coins.len(),
+            );
+        }
+        out
     }
 }
 
 struct Darkfid {
     client: Arc<Mutex<Client>>,
+    state: Arc<Mutex<State>>,
+    // The database the state is kept in, for online backups
+    store: Arc<dyn KvStore>,
+    // Used directly by the emergency stop, which can't wait for the
+    // client lock a transfer holds
+    wallet: WalletPtr,
     sol_tokenlist: SolTokenList,
     drk_tokenlist: DrkTokenList,
     cashiers: Vec<Cashier>,
+    address_network: AddressNetwork,
+    rescan_progress: Arc<RescanProgress>,
+    gateway_stats: Arc<Mutex<GatewayStats>>,
+    verify_pool: Arc<VerifyPool>,
+    backup_status: Option<Arc<Mutex<BackupStatus>>>,
+    snapshot_status: Option<Arc<Mutex<SnapshotStatus>>>,
+    // State events for RPC clients, and the seq of each one as it's added
+    events: Arc<Mutex<EventLog>>,
+    events_added: SubscriberPtr<u64>,
+    // Where payments to the wallet are POSTed, if anywhere
+    webhook: Option<Arc<Webhook>>,
+    // What requests for methods that aren't read-only must carry, if
+    // anything
+    rpc_token: Option<String>,
+    // The task applying the slabs the gateway sends
+    subscriber: Mutex<Option<smol::Task<Result<()>>>>,
 }
 
 impl Darkfid {
-    async fn new(client: Arc<Mutex<Client>>, cashiers: Vec<Cashier>) -> Result<Self> {
+    async fn new(
+        client: Arc<Mutex<Client>>,
+        state: Arc<Mutex<State>>,
+        store: Arc<dyn KvStore>,
+        wallet: WalletPtr,
+        cashiers: Vec<Cashier>,
+        address_network: AddressNetwork,
+        backup_status: Option<Arc<Mutex<BackupStatus>>>,
+        snapshot_status: Option<Arc<Mutex<SnapshotStatus>>>,
+        webhook: Option<Webhook>,
+        rpc_token: Option<String>,
+    ) -> Result<Self> {
         let sol_tokenlist = SolTokenList::new()?;
         let drk_tokenlist = DrkTokenList::new(sol_tokenlist.clone())?;
+        let rescan_progress = client.lock().await.rescan_progress();
+        let gateway_stats = client.lock().await.gateway_stats();
+        let verify_pool = client.lock().await.verify_pool();
 
         Ok(Self {
             client,
+            state,
+            store,
+            wallet,
             sol_tokenlist,
             drk_tokenlist,
             cashiers,
+            address_network,
+            rescan_progress,
+            gateway_stats,
+            verify_pool,
+            backup_status,
+            snapshot_status,
+            events: Arc::new(Mutex::new(EventLog::default())),
+            events_added: Subscriber::new(),
+            webhook: webhook.map(Arc::new),
+            rpc_token,
+            subscriber: Mutex::new(None),
         })
     }
 
-    async fn start(&mut self, state: Arc<Mutex<State>>, executor: Arc<Executor<'_>>) -> Result<()> {
-        self.client.lock().await.start().await?;
-        self.client
+    async fn start(&mut self, executor: Arc<Executor<'_>>) -> Result<()> {
+        // Log the state events for wait_events before any slab is applied
+        let subscription = self.state.lock().await.events.clone().subscribe().await;
+        let (events, events_added) = (self.events.clone(), self.events_added.clone());
+        executor
+            .spawn(async move {
+                loop {
+                    let event = subscription.receive().await;
+                    let seq = events.lock().await.push(event.to_json());
+                    events_added.notify(seq).await;
+                }
+            })
+            .detach();
+
+        if let Some(webhook) = self.webhook.clone() {
+            let subscription = self.state.lock().await.events.clone().subscribe().await;
+            let drk_tokenlist = self.drk_tokenlist.clone();
+            executor
+                .spawn(async move {
+                    loop {
+                        let event = subscription.receive().await;
+                        if let Some(payload) = payment_payload(&event, &drk_tokenlist) {
+                            if let Err(e) = webhook.post(&payload).await {
+                                warn!("Payment not delivered to {}: {}", webhook.url(), e);
+                            }
+                        }
+                    }
+                })
+                .detach();
+        }
+
+        let last_applied_index = self.state.lock().await.last_applied_index()?;
+        self.client.lock().await.start(last_applied_index).await?;
+        let subscriber = self
+            .client
             .lock()
             .await
-            .connect_to_subscriber(state, executor)
+            .connect_to_subscriber(self.state.clone(), executor.clone())
             .await?;
+        *self.subscriber.lock().await = Some(subscriber);
+        Client::start_rebroadcast(self.client.clone(), executor.clone());
 
+        for cashier in self.cashiers.iter() {
+            cashier.client.clone().start_keepalive(executor.clone());
+        }
+
+        Ok(())
+    }
+
+    // Stop applying slabs and write the database out before exiting. The
+    // slab being applied, and its coins' witnesses in the wallet, are
+    // finished first, since the state lock is held until they are. Every
+    // slab is checkpointed along with the tree as it's applied, so what's
+    // left is flushing what the store only has in memory, which spares
+    // replaying its log on the next start.
+    async fn shutdown(&self) -> Result<()> {
+        let state = self.state.lock().await;
+        if let Some(subscriber) = self.subscriber.lock().await.take() {
+            subscriber.cancel().await;
+        }
+        self.store.flush()?;
+        info!("Stopped at slab {}", state.last_applied_index()?);
         Ok(())
     }
 
@@ -124,11 +605,482 @@ impl Darkfid {
     }
 
     // --> {"method": "get_key", "params": []}
-    // <-- {"result": "vdNS7oBj7KvsMWWmo9r96SV4SqATLrGsH2a3PGpCfJC"}
+    // <-- {"result": "tdrk1gqx4m2wdhxu4wnk6xg8qv6unv7k8gu3l4j8sgc7nn...7pjcd9"}
     async fn get_key(&self, id: Value, _params: Value) -> JsonResult {
-        let pk = self.client.lock().await.main_keypair.public;
-        let b58 = bs58::encode(serialize(&pk)).into_string();
-        return JsonResult::Resp(jsonresp(json!(b58), id));
+        let address = self.client.lock().await.address();
+        let address = address.encode(self.address_network);
+        return JsonResult::Resp(jsonresp(json!(address), id));
+    }
+
+    // --> {"method": "new_key", "params": []}
+    // <-- {"result": "tdrk1gqx4m2wdhxu4wnk6xg8qv6unv7k8gu3l4j8sgc7nn...7pjcd9"}
+    async fn new_key(&self, id: Value, _params: Value) -> JsonResult {
+        match self.client.lock().await.new_key().await {
+            Ok(keypair) => {
+                let address = Address::from_secret(&keypair.private);
+                JsonResult::Resp(jsonresp(json!(address.encode(self.address_network)), id))
+            }
+            Err(e) => JsonResult::Err(jsonerr(ServerError(-32002), Some(e.to_string()), id)),
+        }
+    }
+
+    // --> {"method": "new_address", "params": [label (optional)]}
+    // Hands out a new diversified address of the main key. Payments to it
+    // can't be linked to the other addresses of the wallet. The label is
+    // kept in the wallet, e.g. to tell who the address was given to.
+    // <-- {"result": "tdrk1xztpfd8vbj5a3c9p2kmxug6jrl0e4wz8hqsnv7dr...u4lgsx"}
+    async fn new_address(&self, id: Value, params: Value) -> JsonResult {
+        let label = match params.as_array().unwrap().get(0) {
+            None | Some(Value::Null) => None,
+            Some(Value::String(label)) => Some(label.as_str()),
+            Some(_) => return JsonResult::Err(jsonerr(InvalidParams, None, id)),
+        };
+        match self.client.lock().await.new_diversified_address(label) {
+            Ok(address) => {
+                JsonResult::Resp(jsonresp(json!(address.encode(self.address_network)), id))
+            }
+            Err(e) => JsonResult::Err(jsonerr(InternalError, Some(e.to_string()), id)),
+        }
+    }
+
+    // --> {"method": "list_addresses", "params": []}
+    // The main address, then the ones new_address handed out, oldest first
+    // <-- {"result": [{"address": "tdrk1gqx4...", "label": null, "main": true},
+    //                 {"address": "tdrk1xztp...", "label": "alice", "main": false}]}
+    async fn list_addresses(&self, id: Value, _params: Value) -> JsonResult {
+        let client = self.client.lock().await;
+        let diversified = match client.diversified_addresses() {
+            Ok(diversified) => diversified,
+            Err(e) => return JsonResult::Err(jsonerr(InternalError, Some(e.to_string()), id)),
+        };
+
+        let mut addresses = vec![json!({
+            "address": client.address().encode(self.address_network),
+            "label": Value::Null,
+            "main": true,
+        })];
+        for (address, label) in diversified {
+            addresses.push(json!({
+                "address": address.encode(self.address_network),
+                "label": label,
+                "main": false,
+            }));
+        }
+        JsonResult::Resp(jsonresp(json!(addresses), id))
+    }
+
+    // --> {"method": "get_mnemonic", "params": []}
+    // <-- {"result": "abandon ability able about above absent ..."}
+    async fn get_mnemonic(&self, id: Value, _params: Value) -> JsonResult {
+        match self.client.lock().await.get_mnemonic().await {
+            Ok(Some(mnemonic)) => JsonResult::Resp(jsonresp(json!(mnemonic), id)),
+            Ok(None) => JsonResult::Err(jsonerr(
+                ServerError(-32005),
+                Some("Wallet has no mnemonic seed".to_string()),
+                id,
+            )),
+            Err(e) => JsonResult::Err(jsonerr(ServerError(-32005), Some(e.to_string()), id)),
+        }
+    }
+
+    // --> {"method": "restore", "params": [mnemonic]}
+    // Replaces the wallet keys with the ones derived from the mnemonic and
+    // rescans the local slabs for coins belonging to them.
+    // <-- {"result": "tdrk1gqx4m2wdhxu4wnk6xg8qv6unv7k8gu3l4j8sgc7nn...7pjcd9"}
+    async fn restore(&self, id: Value, params: Value) -> JsonResult {
+        let args = params.as_array().unwrap();
+
+        if args.len() != 1 {
+            return JsonResult::Err(jsonerr(InvalidParams, None, id));
+        }
+
+        let mnemonic = match args[0].as_str() {
+            Some(m) => m,
+            None => return JsonResult::Err(jsonerr(InvalidParams, None, id)),
+        };
+
+        let mut client = self.client.lock().await;
+
+        if let Err(e) = client.restore(mnemonic, self.state.clone()).await {
+            return JsonResult::Err(jsonerr(ServerError(-32006), Some(e.to_string()), id));
+        }
+
+        let address = client.address().encode(self.address_network);
+        JsonResult::Resp(jsonresp(json!(address), id))
+    }
+
+    // --> {"method": "rescan", "params": [from_slab (optional)]}
+    // Starts rescanning the local slabs for coins belonging to the wallet
+    // keys, e.g. after importing keys. Given from_slab, the coins in the
+    // slabs before it are kept and only the later ones are looked at.
+    // While a rescan runs, calling it again reports its progress.
+    // <-- {"result": {"running": true, "slabs": [1200, 4000], "witnesses": [0, 0]}}
+    async fn rescan(&self, id: Value, params: Value, executor: Arc<Executor<'_>>) -> JsonResult {
+        let args = params.as_array().unwrap();
+        let from_slab = match args.get(0) {
+            None | Some(Value::Null) => 1,
+            Some(from_slab) => match from_slab.as_u64() {
+                Some(from_slab) if from_slab > 0 => from_slab,
+                _ => return JsonResult::Err(jsonerr(InvalidParams, None, id)),
+            },
+        };
+
+        if self.rescan_progress.start() {
+            let client = self.client.clone();
+            let state = self.state.clone();
+
+            executor
+                .spawn(async move {
+                    if let Err(e) = client.lock().await.rescan(state, from_slab).await {
+                        warn!(target: "DARKFID", "Rescan failed: {}", e);
+                    }
+                })
+                .detach();
+        }
+
+        let status = self.rescan_progress.status();
+        JsonResult::Resp(jsonresp(
+            json!({
+                "running": status.running,
+                "slabs": [status.slabs_done, status.slabs_total],
+                "witnesses": [status.witnesses_done, status.witnesses_total],
+            }),
+            id,
+        ))
+    }
+
+    // --> {"method": "backup_database", "params": [path]}
+    // Copies the client database to a path that doesn't exist yet, on the
+    // node's machine. Slabs aren't applied while the copy is made, so it
+    // holds the state as of one slab. Restore it with `darkfid restore`.
+    // <-- {"result": {"path": "/backups/client.db", "index": 4000}}
+    async fn backup_database(&self, id: Value, params: Value) -> JsonResult {
+        let path = match params.as_array().map(|args| args.as_slice()) {
+            Some([Value::String(path)]) => path,
+            _ => return JsonResult::Err(jsonerr(InvalidParams, None, id)),
+        };
+        let path = match expand_path(path) {
+            Ok(path) if !path.exists() => path,
+            _ => return JsonResult::Err(jsonerr(InvalidParams, None, id)),
+        };
+
+        let state = self.state.lock().await;
+        let backup = state
+            .last_applied_index()
+            .and_then(|index| self.store.checkpoint(&path).map(|()| index));
+        drop(state);
+
+        match backup {
+            Ok(index) => JsonResult::Resp(jsonresp(
+                json!({"path": path.to_string_lossy(), "index": index}),
+                id,
+            )),
+            Err(e) => JsonResult::Err(jsonerr(ServerError(-32009), Some(e.to_string()), id)),
+        }
+    }
+
+    // --> {"method": "rollback", "params": [count]}
+    // Undoes the last count applied slabs, e.g. after following a gateway
+    // that forked, and drops them from the local slabs so the ones the
+    // gateway delivers next are applied in their place.
+    // <-- {"result": {"index": 3990}}
+    async fn rollback(&self, id: Value, params: Value) -> JsonResult {
+        let count = match params.as_array().map(|args| args.as_slice()) {
+            Some([count]) => match count.as_u64() {
+                Some(count) if count > 0 => count,
+                _ => return JsonResult::Err(jsonerr(InvalidParams, None, id)),
+            },
+            _ => return JsonResult::Err(jsonerr(InvalidParams, None, id)),
+        };
+
+        let rollback = self
+            .client
+            .lock()
+            .await
+            .rollback(self.state.clone(), count)
+            .await;
+        match rollback {
+            Ok(index) => JsonResult::Resp(jsonresp(json!({ "index": index }), id)),
+            Err(e) => JsonResult::Err(jsonerr(ServerError(-32010), Some(e.to_string()), id)),
+        }
+    }
+
+    // --> {"method": "export_view_key", "params": []}
+    // The viewing keys decrypt the payments to the main address and to
+    // each diversified address handed out so far, but can't spend them.
+    // <-- {"result": ["8kXjbaAq8jAf5aXW4cJrFbaA8oGcEhZmhDvcb7vPRmGK", ...]}
+    async fn export_view_key(&self, id: Value, _params: Value) -> JsonResult {
+        match self.client.lock().await.export_view_keys() {
+            Ok(view_keys) => {
+                let view_keys: Vec<String> = view_keys.iter().map(|k| k.to_string()).collect();
+                JsonResult::Resp(jsonresp(json!(view_keys), id))
+            }
+            Err(err) => JsonResult::Err(jsonerr(InternalError, Some(err.to_string()), id)),
+        }
+    }
+
+    // --> {"method": "import_view_key", "params": [view_key]}
+    // Payments to the address are watched from the next slab on.
+    // <-- {"result": true}
+    async fn import_view_key(&self, id: Value, params: Value) -> JsonResult {
+        let view_key = match params.as_array().map(|args| args.as_slice()) {
+            Some([Value::String(view_key)]) => view_key,
+            _ => return JsonResult::Err(jsonerr(InvalidParams, None, id)),
+        };
+
+        let result: Result<()> = async {
+            let view_key = ViewingKey::from_str(view_key)?;
+            self.client.lock().await.import_view_key(&view_key).await
+        }
+        .await;
+
+        match result {
+            Ok(()) => JsonResult::Resp(jsonresp(json!(true), id)),
+            Err(err) => JsonResult::Err(jsonerr(InternalError, Some(err.to_string()), id)),
+        }
+    }
+
+    // --> {"method": "import_sapling_key", "params": ["secret-extended-key-main1..."]}
+    // Adds the spend authorizing key of a sapling extended spending key to
+    // the wallet. Its other components have no use in drk and are dropped.
+    // <-- {"result": "tdrk1gqx4m2wdhxu4wnk6xg8qv6unv7k8gu3l4j8sgc7nn...7pjcd9"}
+    async fn import_sapling_key(&self, id: Value, params: Value) -> JsonResult {
+        let key = match params.as_array().map(|args| args.as_slice()) {
+            Some([Value::String(key)]) => key,
+            _ => return JsonResult::Err(jsonerr(InvalidParams, None, id)),
+        };
+
+        match self.client.lock().await.import_sapling_key(key).await {
+            Ok(keypair) => {
+                let address = Address::from_secret(&keypair.private);
+                JsonResult::Resp(jsonresp(json!(address.encode(self.address_network)), id))
+            }
+            Err(e) => JsonResult::Err(jsonerr(ServerError(-32002), Some(e.to_string()), id)),
+        }
+    }
+
+    // --> {"method": "emergency_stop", "params": [reason]}
+    // Freezes the wallet at once. Transfers are refused, including ones
+    // still being built, until it is unlocked. reason is optional and goes
+    // into the audit log.
+    // <-- {"result": true}
+    async fn emergency_stop(&self, id: Value, params: Value) -> JsonResult {
+        let reason = match params.as_array().map(|args| args.as_slice()) {
+            Some([]) => "",
+            Some([Value::String(reason)]) => reason,
+            _ => return JsonResult::Err(jsonerr(InvalidParams, None, id)),
+        };
+
+        match self.wallet.emergency_stop(reason) {
+            Ok(()) => JsonResult::Resp(jsonresp(json!(true), id)),
+            Err(e) => JsonResult::Err(jsonerr(InternalError, Some(e.to_string()), id)),
+        }
+    }
+
+    // --> {"method": "unlock", "params": [passphrase]}
+    // Lifts an emergency stop, once its cooldown has passed. The
+    // passphrase is the wallet password.
+    // <-- {"result": true}
+    async fn unlock(&self, id: Value, params: Value) -> JsonResult {
+        let passphrase = match params.as_array().map(|args| args.as_slice()) {
+            Some([Value::String(passphrase)]) => passphrase,
+            _ => return JsonResult::Err(jsonerr(InvalidParams, None, id)),
+        };
+
+        match self.wallet.unlock(passphrase) {
+            Ok(()) => JsonResult::Resp(jsonresp(json!(true), id)),
+            Err(e) => JsonResult::Err(jsonerr(ServerError(-32007), Some(e.to_string()), id)),
+        }
+    }
+
+    // --> {"method": "get_failed_operations", "params": []}
+    // <-- {"result": [{"id": 1, "timestamp": 1639000000, "method": "transfer",
+    //      "params": [...], "reason": "insufficient_funds", "detail": "...",
+    //      "remediation": "...", "retryable": true}, ...]}
+    async fn get_failed_operations(&self, id: Value, _params: Value) -> JsonResult {
+        match self.wallet.get_failed_operations() {
+            Ok(ops) => {
+                let ops: Vec<Value> = ops.iter().map(|op| op.to_json()).collect();
+                JsonResult::Resp(jsonresp(json!(ops), id))
+            }
+            Err(e) => JsonResult::Err(jsonerr(InternalError, Some(e.to_string()), id)),
+        }
+    }
+
+    // --> {"method": "get_contacts", "params": []}
+    // <-- {"result": [{"name": "cold wallet", "address": "tdrk1...", "limit": null}, ...]}
+    async fn get_contacts(&self, id: Value, _params: Value) -> JsonResult {
+        match self.wallet.get_contacts() {
+            Ok(contacts) => {
+                let contacts: Vec<Value> = contacts
+                    .iter()
+                    .map(|contact| {
+                        let mut contact_json = contact.to_json();
+                        contact_json["limit"] = json!(contact.limit.map(|l| encode_base10(l, 8)));
+                        contact_json
+                    })
+                    .collect();
+                JsonResult::Resp(jsonresp(json!(contacts), id))
+            }
+            Err(e) => JsonResult::Err(jsonerr(InternalError, Some(e.to_string()), id)),
+        }
+    }
+
+    // --> {"method": "add_contact", "params": [name, address, limit (optional)]}
+    // A contact without a limit can be paid any amount. Adding a name
+    // again replaces the contact.
+    // <-- {"result": true}
+    async fn add_contact(&self, id: Value, params: Value) -> JsonResult {
+        let (name, address, limit) = match params.as_array().map(|args| args.as_slice()) {
+            Some([name, address]) => (name, address, &Value::Null),
+            Some([name, address, limit]) => (name, address, limit),
+            _ => return JsonResult::Err(jsonerr(InvalidParams, None, id)),
+        };
+        let (name, address) = match (name.as_str(), address.as_str()) {
+            (Some(name), Some(address)) if !name.is_empty() => (name, address),
+            _ => return JsonResult::Err(jsonerr(InvalidParams, None, id)),
+        };
+
+        let result: Result<()> = async {
+            let public = Address::decode(address, self.address_network)?.public;
+            let limit = match limit {
+                Value::Null => None,
+                limit => Some(decode_base10(
+                    limit.as_str().ok_or(Error::ParseFailed("Invalid limit"))?,
+                    8,
+                    true,
+                )?),
+            };
+            self.wallet.put_contact(&Contact {
+                name: name.to_string(),
+                address: address.to_string(),
+                public,
+                limit,
+            })
+        }
+        .await;
+
+        match result {
+            Ok(()) => JsonResult::Resp(jsonresp(json!(true), id)),
+            Err(e) => JsonResult::Err(jsonerr(InvalidParams, Some(e.to_string()), id)),
+        }
+    }
+
+    // --> {"method": "remove_contact", "params": [name]}
+    // <-- {"result": true}
+    async fn remove_contact(&self, id: Value, params: Value) -> JsonResult {
+        let name = match params.as_array().map(|args| args.as_slice()) {
+            Some([name]) => match name.as_str() {
+                Some(name) => name,
+                None => return JsonResult::Err(jsonerr(InvalidParams, None, id)),
+            },
+            _ => return JsonResult::Err(jsonerr(InvalidParams, None, id)),
+        };
+
+        match self.wallet.remove_contact(name) {
+            Ok(true) => JsonResult::Resp(jsonresp(json!(true), id)),
+            Ok(false) => JsonResult::Err(jsonerr(
+                InvalidParams,
+                Some(format!("No contact named {}", name)),
+                id,
+            )),
+            Err(e) => JsonResult::Err(jsonerr(InternalError, Some(e.to_string()), id)),
+        }
+    }
+
+    // --> {"method": "retry_failed_operation", "params": [id]}
+    // Sends a failed transfer again with its original params. Each one can
+    // be retried once, and only if it failed before anything was spent.
+    // <-- the result of the transfer
+    async fn retry_failed_operation(&self, id: Value, params: Value) -> JsonResult {
+        let op_id = match params.as_array().map(|args| args.as_slice()) {
+            Some([op_id]) => match op_id.as_i64() {
+                Some(op_id) => op_id,
+                None => return JsonResult::Err(jsonerr(InvalidParams, None, id)),
+            },
+            _ => return JsonResult::Err(jsonerr(InvalidParams, None, id)),
+        };
+
+        let op = match self.wallet.get_failed_operation(op_id) {
+            Ok(Some(op)) => op,
+            Ok(None) => {
+                return JsonResult::Err(jsonerr(
+                    InvalidParams,
+                    Some("Unknown operation".to_string()),
+                    id,
+                ))
+            }
+            Err(e) => return JsonResult::Err(jsonerr(InternalError, Some(e.to_string()), id)),
+        };
+
+        if op.retried || !op.reason.is_retryable() {
+            return JsonResult::Err(jsonerr(
+                ServerError(-32008),
+                Some(format!(
+                    "Operation can't be retried: {}",
+                    op.reason.remediation()
+                )),
+                id,
+            ));
+        }
+
+        let op_params: Value = match serde_json::from_str(&op.params) {
+            Ok(op_params) => op_params,
+            Err(e) => return JsonResult::Err(jsonerr(InternalError, Some(e.to_string()), id)),
+        };
+
+        // Marked first, so a retry that fails again is recorded anew
+        // instead of being retried over and over
+        if let Err(e) = self.wallet.set_failed_operation_retried(op.id) {
+            return JsonResult::Err(jsonerr(InternalError, Some(e.to_string()), id));
+        }
+
+        match op.method.as_str() {
+            "transfer" => self.transfer(id, op_params).await,
+            "transfer_many" => self.transfer_many(id, op_params).await,
+            _ => JsonResult::Err(jsonerr(
+                InternalError,
+                Some(format!("Can't retry {}", op.method)),
+                id,
+            )),
+        }
+    }
+
+    // Keep a failed transfer for get_failed_operations. Failing to keep it
+    // doesn't change the error the caller gets.
+    fn record_failure(&self, method: &str, params: &Value, err: &ClientFailed) {
+        if let Err(e) = self.wallet.put_failed_operation(
+            method,
+            &params.to_string(),
+            err.failure_reason(),
+            &err.to_string(),
+        ) {
+            warn!(target: "RPC", "Failed to record failed {}: {}", method, e);
+        }
+    }
+
+    // --> {"method": "get_watched_balances", "params": []}
+    // <-- {"result": {"btc": "13.37", "0x0130...a7bd": "1.5"}}
+    async fn get_watched_balances(&self, id: Value, _params: Value) -> JsonResult {
+        let result: Result<HashMap<String, String>> = async {
+            let balances = self.client.lock().await.get_watched_balances().await?;
+            let mut symbols: HashMap<String, String> = HashMap::new();
+
+            for balance in balances.list.iter() {
+                let symbol = match self.drk_tokenlist.symbol_from_id(balance.token_id)? {
+                    Some(symbol) => symbol,
+                    None => balance.token_id.to_string(),
+                };
+                symbols.insert(symbol, encode_base10(balance.value, 8));
+            }
+            Ok(symbols)
+        }
+        .await;
+
+        match result {
+            Ok(res) => JsonResult::Resp(jsonresp(json!(res), id)),
+            Err(err) => JsonResult::Err(jsonerr(InternalError, Some(err.to_string()), id)),
+        }
     }
 
     // --> {"method": "get_balances", "params": []}
@@ -164,6 +1116,32 @@ impl Darkfid {
         }
     }
 
+    // --> {"method": "get_balance", "params": []}
+    // <-- {"result": {"btc": "13.37", "0x0130...a7bd": "1.5"}}
+    async fn get_balance(&self, id: Value, _params: Value) -> JsonResult {
+        let result: Result<HashMap<String, String>> = async {
+            let client = self.client.lock().await;
+            let state = self.state.lock().await;
+            let balances = client.get_balance(&state).await?;
+            let mut symbols: HashMap<String, String> = HashMap::new();
+
+            for balance in balances.list.iter() {
+                let symbol = match self.drk_tokenlist.symbol_from_id(balance.token_id)? {
+                    Some(symbol) => symbol,
+                    None => balance.token_id.to_string(),
+                };
+                symbols.insert(symbol, encode_base10(balance.value, 8));
+            }
+            Ok(symbols)
+        }
+        .await;
+
+        match result {
+            Ok(res) => JsonResult::Resp(jsonresp(json!(res), id)),
+            Err(err) => JsonResult::Err(jsonerr(InternalError, Some(err.to_string()), id)),
+        }
+    }
+
     // --> {"method": "get_token_id", "params": [network, token]}
     // <-- {"result": "Ht5G1RhkcKnpLVLMhqJc5aqZ4wYUEbxbtZwGCVbgU7DL"}
     async fn get_token_id(&self, id: Value, params: Value) -> JsonResult {
@@ -226,7 +1204,7 @@ impl Darkfid {
         let req = jsonreq(json!("features"), json!([]));
         let rep: JsonResult;
         // NOTE: this just selects the first cashier in the list
-        match send_request(&self.cashiers[0].rpc_url, json!(req)).await {
+        match self.cashiers[0].client.request(req).await {
             Ok(v) => rep = v,
             Err(e) => {
                 return JsonResult::Err(jsonerr(ServerError(-32004), Some(e.to_string()), id))
@@ -289,7 +1267,7 @@ impl Darkfid {
         // If not, an error is returned, and forwarded to the method caller.
         let req = jsonreq(json!("deposit"), json!([network, token_id, pubkey]));
         let rep: JsonResult;
-        match send_request(&self.cashiers[0].rpc_url, json!(req)).await {
+        match self.cashiers[0].client.request(req).await {
             Ok(v) => rep = v,
             Err(e) => {
                 debug!(target: "DARKFID", "REQUEST IS ERR");
@@ -374,7 +1352,7 @@ impl Darkfid {
             json!([network, token_id, address, amount_in_apo]),
         );
         let mut rep: JsonResult;
-        match send_request(&self.cashiers[0].rpc_url, json!(req)).await {
+        match self.cashiers[0].client.request(req).await {
             Ok(v) => rep = v,
             Err(e) => {
                 return JsonResult::Err(jsonerr(ServerError(-32004), Some(e.to_string()), id));
@@ -395,13 +1373,20 @@ impl Darkfid {
             let result: Result<()> = async {
                 let cashier_public = cashier_public.result.as_str().unwrap();
 
-                let cashier_public: jubjub::SubgroupPoint =
-                    deserialize(&bs58::decode(cashier_public).into_vec()?)?;
+                let cashier_public = Address::decode(cashier_public, self.address_network)?;
 
+                // The cashiers are provisioned, so withdrawals aren't held
+                // to the payment policy
                 self.client
                     .lock()
                     .await
-                    .transfer(token_id.clone(), cashier_public, amount_in_apo)
+                    .send_many(
+                        vec![(cashier_public, amount_in_apo, Memo::empty())],
+                        token_id.clone(),
+                        false,
+                        0,
+                        None,
+                    )
                     .await?;
 
                 Ok(())
@@ -431,7 +1416,362 @@ impl Darkfid {
         }
     }
 
-    // --> {"method": "transfer", [dToken, address, amount]}
+    // --> {"method": "get_info", "params": []}
+    // <-- {"result": {"version": "0.1.0", "last_applied_index": 42,
+    //                 "cashiers": [{"name": "...", "connected": true, ...}],
+    //                 "sync": {"last_index": 42, "avg_lag_micros": 1337, ...},
+    //                 "gateway": {"connected": true, "url": "tcp://...", "latest_index": 42,
+    //                             "grade": "good", "protocol_version": "3.0"},
+    //                 "wallet": {"coins": 3, "frozen": false},
+    //                 "backup": {"last_success": 1637000000, "last_error": null, ...},
+    //                 "snapshot": {"latest": {"index": 42, "timestamp": 1637000000, "path": "..."}, ...}}}
+    // backup and snapshot are null if scheduled backups and snapshots
+    // aren't configured, frozen (also under wallet) is whether an emergency
+    // stop is in effect and light_client whether synced slabs are taken on
+    // trust
+    async fn get_info(&self, id: Value, _params: Value) -> JsonResult {
+        let mut cashiers = vec![];
+        for cashier in self.cashiers.iter() {
+            let mut info = cashier.client.health().await.to_json();
+            info["name"] = json!(cashier.name);
+            cashiers.push(info);
+        }
+
+        let (sync, last_applied_index, light_client) = {
+            let state = self.state.lock().await;
+            let sync = state
+                .recent_metrics(DEFAULT_METRICS_COUNT)
+                .and_then(|metrics| Ok((metrics, state.last_applied_index()?)));
+            match sync {
+                Ok((metrics, index)) => {
+                    (SyncSummary::from_metrics(&metrics).to_json(), index, state.light)
+                }
+                Err(e) => return JsonResult::Err(jsonerr(InternalError, Some(e.to_string()), id)),
+            }
+        };
+
+        let gateway = {
+            let stats = self.gateway_stats.lock().await;
+            json!({
+                "connected": stats.subscribed,
+                "url": stats.gateway,
+                "latest_index": stats.latest_index,
+                "grade": stats.grade(),
+                "protocol_version": stats.protocol_version.map(|v| v.to_string()),
+            })
+        };
+
+        let backup = match &self.backup_status {
+            Some(status) => status.lock().await.to_json(),
+            None => Value::Null,
+        };
+
+        let snapshot = match &self.snapshot_status {
+            Some(status) => status.lock().await.to_json(),
+            None => Value::Null,
+        };
+
+        let wallet = self
+            .wallet
+            .is_frozen()
+            .and_then(|frozen| Ok((frozen, self.wallet.get_own_coins()?.len())));
+        let (frozen, coins) = match wallet {
+            Ok(wallet) => wallet,
+            Err(e) => return JsonResult::Err(jsonerr(InternalError, Some(e.to_string()), id)),
+        };
+
+        JsonResult::Resp(jsonresp(
+            json!({
+                "version": env!("CARGO_PKG_VERSION"),
+                "last_applied_index": last_applied_index,
+                "cashiers": cashiers,
+                "sync": sync,
+                "gateway": gateway,
+                "backup": backup,
+                "snapshot": snapshot,
+                "wallet": { "coins": coins, "frozen": frozen },
+                "frozen": frozen,
+                "light_client": light_client,
+            }),
+            id,
+        ))
+    }
+
+    // --> {"method": "wait_events", "params": [seq]}
+    // Returns the state events after the one numbered seq, waiting up to
+    // a minute for one if there are none yet. Pass the last seq back to
+    // follow the events, or 0 for every event still kept. The events are
+    // new_coin_received, nullifier_seen, merkle_root_advanced and
+    // slab_applied.
+    // <-- {"result": {"last": 8, "events": [{"seq": 8, "event": "slab_applied", "index": 4001}]}}
+    async fn wait_events(&self, id: Value, params: Value) -> JsonResult {
+        let seq = match params.as_array().map(|args| args.as_slice()) {
+            Some([]) => 0,
+            Some([seq]) => match seq.as_u64() {
+                Some(seq) => seq,
+                None => return JsonResult::Err(jsonerr(InvalidParams, None, id)),
+            },
+            _ => return JsonResult::Err(jsonerr(InvalidParams, None, id)),
+        };
+
+        // Subscribed before looking, so an event added in between still
+        // wakes us up
+        let added = self.events_added.clone().subscribe().await;
+        let mut events = self.events.lock().await.after(seq);
+        if events.is_empty() {
+            futures::select! {
+                _ = added.receive().fuse() => {}
+                _ = sleep(EVENT_WAIT).fuse() => {}
+            }
+            events = self.events.lock().await.after(seq);
+        }
+        added.unsubscribe().await;
+
+        let last = self.events.lock().await.last_seq;
+        JsonResult::Resp(jsonresp(json!({ "last": last, "events": events }), id))
+    }
+
+    // --> {"method": "get_sync_metrics", "params": [count]}
+    // count is optional and defaults to the last 100 slabs
+    // <-- {"result": {"summary": {"avg_apply_micros": 1337, ...},
+    //                 "recent": [{"index": 42, "apply_micros": 1337, "lag_micros": 1500, ...}],
+    //                 "verify": {"workers": 4, "own": {"depth": 0, ...}, "sync": {"depth": 3, ...}}}}
+    async fn get_sync_metrics(&self, id: Value, params: Value) -> JsonResult {
+        let args = params.as_array().unwrap();
+
+        let count = match args.get(0) {
+            Some(count) => match count.as_u64() {
+                Some(count) => count,
+                None => return JsonResult::Err(jsonerr(InvalidParams, None, id)),
+            },
+            None => DEFAULT_METRICS_COUNT,
+        };
+
+        let metrics = match self.state.lock().await.recent_metrics(count) {
+            Ok(metrics) => metrics,
+            Err(e) => return JsonResult::Err(jsonerr(InternalError, Some(e.to_string()), id)),
+        };
+
+        let summary = SyncSummary::from_metrics(&metrics).to_json();
+        let recent: Vec<Value> = metrics.iter().map(|m| m.to_json()).collect();
+
+        let verify = self.verify_pool.metrics().to_json();
+
+        JsonResult::Resp(jsonresp(
+            json!({ "summary": summary, "recent": recent, "verify": verify }),
+            id,
+        ))
+    }
+
+    // --> {"method": "get_merkle_path", "params": [coin]}
+    // coin is the hex of the coin commitment. The path lists the sibling
+    // of each node from the leaf up, and together with the position leads
+    // from the coin to the root
+    // <-- {"result": {"coin": "2f...", "position": 42, "auth_path": ["a1...", ...], "root": "9c..."}}
+    async fn get_merkle_path(&self, id: Value, params: Value) -> JsonResult {
+        let args = params.as_array().unwrap();
+
+        let coin = match args
+            .get(0)
+            .and_then(|coin| coin.as_str())
+            .and_then(parse_hash)
+        {
+            Some(coin) => Coin::new(coin),
+            None => return JsonResult::Err(jsonerr(InvalidParams, None, id)),
+        };
+
+        let (path, root) = match self.state.lock().await.merkle_path(&coin) {
+            Ok(Some(path)) => path,
+            Ok(None) => {
+                return JsonResult::Err(jsonerr(
+                    InvalidParams,
+                    Some("coin not found in the merkle tree".to_string()),
+                    id,
+                ))
+            }
+            Err(e) => return JsonResult::Err(jsonerr(InternalError, Some(e.to_string()), id)),
+        };
+
+        let auth_path: Vec<String> = path
+            .auth_path
+            .iter()
+            .map(|(node, _)| hex::encode(node.repr))
+            .collect();
+
+        JsonResult::Resp(jsonresp(
+            json!({
+                "coin": hex::encode(coin.repr),
+                "position": path.position,
+                "auth_path": auth_path,
+                "root": hex::encode(root.repr),
+            }),
+            id,
+        ))
+    }
+
+    // --> {"method": "verify_merkle_path", "params": [root, coin, position, [auth_path]]}
+    // Checks a path as returned by get_merkle_path, without the local tree
+    // <-- {"result": true}
+    async fn verify_merkle_path(&self, id: Value, params: Value) -> JsonResult {
+        let args = params.as_array().unwrap();
+        if args.len() != 4 {
+            return JsonResult::Err(jsonerr(InvalidParams, None, id));
+        }
+
+        let root = args[0].as_str().and_then(parse_hash);
+        let coin = args[1].as_str().and_then(parse_hash);
+        let position = args[2].as_u64();
+        let siblings: Option<Vec<MerkleNode>> = args[3].as_array().and_then(|siblings| {
+            siblings
+                .iter()
+                .map(|sibling| sibling.as_str().and_then(parse_hash).map(MerkleNode::new))
+                .collect()
+        });
+
+        match (root, coin, position, siblings) {
+            (Some(root), Some(coin), Some(position), Some(siblings)) => {
+                let path = merkle_proof::path_from_siblings(siblings, position);
+                let valid =
+                    merkle_proof::verify_coin_path(&MerkleNode::new(root), &Coin::new(coin), &path);
+                JsonResult::Resp(jsonresp(json!(valid), id))
+            }
+            _ => JsonResult::Err(jsonerr(InvalidParams, None, id)),
+        }
+    }
+
+    // --> {"method": "gateway_stats", "params": []}
+    // <-- {"result": {"grade": "good", "subscribed": true, "avg_latency_millis": 120,
+    //                 "gaps": 0, "retransmissions": 0, "reconnects": 1, ...}}
+    async fn gateway_stats(&self, id: Value, _params: Value) -> JsonResult {
+        let stats = self.gateway_stats.lock().await.to_json();
+        JsonResult::Resp(jsonresp(stats, id))
+    }
+
+    // --> {"method": "get_going_rate", "params": []}
+    // The fee per kilobyte of transaction the gateway has been admitting
+    // slabs at lately. Paying at least this keeps a transfer from being
+    // held back while the gateway is busy.
+    // <-- {"result": "0.00001"}
+    async fn get_going_rate(&self, id: Value, _params: Value) -> JsonResult {
+        match self.client.lock().await.get_going_rate().await {
+            Ok(rate) => JsonResult::Resp(jsonresp(json!(encode_base10(rate, 8)), id)),
+            Err(e) => JsonResult::Err(jsonerr(InternalError, Some(e.to_string()), id)),
+        }
+    }
+
+    // --> {"method": "privacy_report", "params": []}
+    // <-- {"result": [{"issue": "address_reuse", "explanation": "..."}, ...]}
+    async fn privacy_report(&self, id: Value, _params: Value) -> JsonResult {
+        match self.client.lock().await.privacy_report().await {
+            Ok(findings) => {
+                let findings: Vec<Value> = findings.iter().map(|f| f.to_json()).collect();
+                JsonResult::Resp(jsonresp(json!(findings), id))
+            }
+            Err(e) => JsonResult::Err(jsonerr(InternalError, Some(e.to_string()), id)),
+        }
+    }
+
+    // --> {"method": "get_tx_history", "params": [since, limit, direction]}
+    // All optional: only entries from the since timestamp on, only the
+    // last limit of them, and only the "sent" or "received" ones.
+    // <-- {"result": [{"direction": "sent", "amount": "1.5", "token": "btc", ...}, ...]}
+    // Received entries carry a "risk" score for releasing goods on them.
+    async fn get_tx_history(&self, id: Value, params: Value) -> JsonResult {
+        let args = params.as_array().unwrap();
+        let since = match args.get(0) {
+            None | Some(Value::Null) => 0,
+            Some(since) => match since.as_u64() {
+                Some(since) => since,
+                None => return JsonResult::Err(jsonerr(InvalidParams, None, id)),
+            },
+        };
+        let limit = match args.get(1) {
+            None | Some(Value::Null) => None,
+            Some(limit) => match limit.as_u64() {
+                Some(limit) => Some(limit as usize),
+                None => return JsonResult::Err(jsonerr(InvalidParams, None, id)),
+            },
+        };
+        let direction = match args.get(2) {
+            None | Some(Value::Null) => None,
+            Some(direction) => match direction.as_str() {
+                Some("sent") => Some("sent"),
+                Some("received") => Some("received"),
+                _ => return JsonResult::Err(jsonerr(InvalidParams, None, id)),
+            },
+        };
+
+        let result: Result<Vec<Value>> = async {
+            let mut history = self.client.lock().await.get_tx_history().await?;
+            history.retain(|entry| {
+                entry.timestamp >= since
+                    && direction.map_or(true, |direction| entry.direction.as_str() == direction)
+            });
+            if let Some(limit) = limit {
+                history.drain(..history.len().saturating_sub(limit));
+            }
+            let latest_index = self.gateway_stats.lock().await.latest_index;
+            let mut entries = vec![];
+
+            for entry in history.iter() {
+                let token = match self.drk_tokenlist.symbol_from_id(entry.token_id)? {
+                    Some(symbol) => symbol,
+                    None => entry.token_id.to_string(),
+                };
+                let counterparty = entry
+                    .counterparty
+                    .map(|pk| bs58::encode(serialize(&pk)).into_string());
+                let risk = PaymentRiskInputs::from_history(entry, latest_index)
+                    .map(|inputs| score_payment(inputs).to_json());
+
+                entries.push(json!({
+                    "direction": entry.direction.as_str(),
+                    "amount": encode_base10(entry.amount, 8),
+                    "token": token,
+                    "counterparty": counterparty,
+                    "slab_index": entry.slab_index,
+                    "slab_hash": entry.slab_hash.map(hex::encode),
+                    "timestamp": entry.timestamp,
+                    "memo": entry.memo.to_string(),
+                    "risk": risk,
+                }));
+            }
+            Ok(entries)
+        }
+        .await;
+
+        match result {
+            Ok(res) => JsonResult::Resp(jsonresp(json!(res), id)),
+            Err(err) => JsonResult::Err(jsonerr(InternalError, Some(err.to_string()), id)),
+        }
+    }
+
+    // --> {"method": "get_tx_status", "params": [txID]}
+    // Where a transaction transfer returned the id of is at: pending,
+    // published, confirmed (with the index of its slab), rejected or
+    // expired. Null for transactions the wallet didn't send.
+    // <-- {"result": {"status": "confirmed", "slab_index": 42, "updated": 1639000000}}
+    async fn get_tx_status(&self, id: Value, params: Value) -> JsonResult {
+        let tx_hash = match params.as_array().map(|args| args.as_slice()) {
+            Some([Value::String(tx_hash)]) => parse_hash(tx_hash),
+            _ => None,
+        };
+        let tx_hash = match tx_hash {
+            Some(tx_hash) => tx_hash,
+            None => return JsonResult::Err(jsonerr(InvalidParams, None, id)),
+        };
+
+        match tx_status_json(&self.wallet, &tx_hash) {
+            Ok(status) => JsonResult::Resp(jsonresp(status, id)),
+            Err(e) => JsonResult::Err(jsonerr(InternalError, Some(e.to_string()), id)),
+        }
+    }
+
+    // --> {"method": "transfer",
+    //      [dToken, address, amount, coin_selection (optional), fee (optional),
+    //       memo (optional), confirmed (optional), allow_duplicate (optional)]}
+    // A first payment to an address has to be sent with confirmed true, and
+    // a repeat of a recent payment with allow_duplicate true.
     // <-- {"result": "txID"}
     async fn transfer(&self, id: Value, params: Value) -> JsonResult {
         let args = params.as_array();
@@ -439,10 +1779,23 @@ impl Darkfid {
             return JsonResult::Err(jsonerr(InvalidParams, None, id));
         }
         let args = args.unwrap();
-        if args.len() != 3 {
+        if args.len() < 3 || args.len() > 8 {
             return JsonResult::Err(jsonerr(InvalidParams, None, id));
         }
 
+        let (strategy, fee) = match transfer_options(&args[3..]) {
+            Some(options) => options,
+            None => return JsonResult::Err(jsonerr(InvalidParams, None, id)),
+        };
+        let confirmed = match confirmed_param(args.get(6)) {
+            Some(confirmed) => confirmed,
+            None => return JsonResult::Err(jsonerr(InvalidParams, None, id)),
+        };
+        let allow_duplicate = match confirmed_param(args.get(7)) {
+            Some(allow_duplicate) => allow_duplicate,
+            None => return JsonResult::Err(jsonerr(InvalidParams, None, id)),
+        };
+
         let token: &str;
         let address: &str;
         let amount: &str;
@@ -473,20 +1826,130 @@ impl Darkfid {
             return JsonResult::Err(jsonerr(InvalidParams, None, id));
         }
 
-        let result: Result<()> = async {
-            let drk_address = bs58::decode(&address).into_vec()?;
-            let drk_address: jubjub::SubgroupPoint = deserialize(&drk_address)?;
+        let result: Result<String> = async {
+            let drk_address = Address::decode(address, self.address_network)?;
 
             let decimals: usize = 8;
             let amount = decode_base10(&amount, decimals, true)?;
+            let fee = decode_base10(fee, decimals, true)?;
+            let memo = memo_param(args.get(5))?;
 
-            self.client
+            let sent = self
+                .client
                 .lock()
                 .await
-                .transfer(token_id.clone(), drk_address, amount)
-                .await?;
+                .transfer(
+                    token_id.clone(),
+                    drk_address,
+                    amount,
+                    memo,
+                    fee,
+                    strategy,
+                    confirmed,
+                    allow_duplicate,
+                )
+                .await;
+            match sent {
+                Ok(tx_hash) => Ok(hex::encode(tx_hash)),
+                Err(err) => {
+                    self.record_failure("transfer", &params, &err);
+                    Err(err.into())
+                }
+            }
+        }
+        .await;
 
-            Ok(())
+        match result {
+            Ok(msg) => JsonResult::Resp(jsonresp(json!(msg), json!(id))),
+            Err(err) => JsonResult::Err(jsonerr(InternalError, Some(err.to_string()), json!(id))),
+        }
+    }
+
+    // --> {"method": "transfer_many",
+    //      [dToken, [[address, amount, memo (optional)], ...], coin_selection (optional),
+    //       fee (optional), confirmed (optional), allow_duplicate (optional)]}
+    // <-- {"result": "txID"}
+    async fn transfer_many(&self, id: Value, params: Value) -> JsonResult {
+        let args = params.as_array();
+        if args.is_none() {
+            return JsonResult::Err(jsonerr(InvalidParams, None, id));
+        }
+        let args = args.unwrap();
+        if args.len() < 2 || args.len() > 6 {
+            return JsonResult::Err(jsonerr(InvalidParams, None, id));
+        }
+
+        let token = match args[0].as_str() {
+            Some(token) => token,
+            None => return JsonResult::Err(jsonerr(InvalidTokenIdParam, None, id)),
+        };
+
+        let recipients = match args[1].as_array() {
+            Some(recipients) if !recipients.is_empty() => recipients,
+            _ => return JsonResult::Err(jsonerr(InvalidAddressParam, None, id)),
+        };
+
+        let (strategy, fee) = match transfer_options(&args[2..]) {
+            Some(options) => options,
+            None => return JsonResult::Err(jsonerr(InvalidParams, None, id)),
+        };
+        let confirmed = match confirmed_param(args.get(4)) {
+            Some(confirmed) => confirmed,
+            None => return JsonResult::Err(jsonerr(InvalidParams, None, id)),
+        };
+        let allow_duplicate = match confirmed_param(args.get(5)) {
+            Some(allow_duplicate) => allow_duplicate,
+            None => return JsonResult::Err(jsonerr(InvalidParams, None, id)),
+        };
+
+        let token_id = match self.drk_tokenlist.tokens.get(&token.to_uppercase()) {
+            Some(token_id) => token_id,
+            None => return JsonResult::Err(jsonerr(InvalidParams, None, id)),
+        };
+
+        let result: Result<String> = async {
+            let decimals: usize = 8;
+
+            let mut list = vec![];
+            for recipient in recipients {
+                let (address, amount, memo) = match recipient.as_array().map(|r| r.as_slice()) {
+                    Some([address, amount]) => (address, amount, None),
+                    Some([address, amount, memo]) => (address, amount, Some(memo)),
+                    _ => return Err(Error::ParseFailed("Invalid recipient")),
+                };
+                let (address, amount) = match (address.as_str(), amount.as_str()) {
+                    (Some(address), Some(amount)) => (address, amount),
+                    _ => return Err(Error::ParseFailed("Invalid recipient")),
+                };
+
+                let drk_address = Address::decode(address, self.address_network)?;
+                let amount = decode_base10(amount, decimals, true)?;
+                list.push((drk_address, amount, memo_param(memo)?));
+            }
+            total_amount(&list)?;
+
+            let fee = decode_base10(fee, decimals, true)?;
+
+            let sent = self
+                .client
+                .lock()
+                .await
+                .transfer_many(
+                    token_id.clone(),
+                    list,
+                    fee,
+                    strategy,
+                    confirmed,
+                    allow_duplicate,
+                )
+                .await;
+            match sent {
+                Ok(tx_hash) => Ok(hex::encode(tx_hash)),
+                Err(err) => {
+                    self.record_failure("transfer_many", &params, &err);
+                    Err(err.into())
+                }
+            }
         }
         .await;
 
@@ -497,18 +1960,205 @@ impl Darkfid {
     }
 }
 
-async fn start(executor: Arc<Executor<'_>>, config: &DarkfidConfig) -> Result<()> {
-    let wallet = WalletDb::new(
-        expand_path(&config.wallet_path)?.as_path(),
-        config.wallet_password.clone(),
+// The optional trailing [coin_selection, fee] params of the transfer
+// methods. A null coin_selection keeps the configured strategy.
+fn transfer_options(args: &[Value]) -> Option<(Option<CoinSelectionStrategy>, &str)> {
+    let strategy = match args.get(0) {
+        None | Some(Value::Null) => None,
+        Some(v) => Some(CoinSelectionStrategy::from_str(v.as_str()?).ok()?),
+    };
+    let fee = match args.get(1) {
+        None | Some(Value::Null) => "0",
+        Some(v) => v.as_str()?,
+    };
+    Some((strategy, fee))
+}
+
+// The optional confirmed and allow_duplicate params of the transfer
+// methods, false if missing
+fn confirmed_param(value: Option<&Value>) -> Option<bool> {
+    match value {
+        None | Some(Value::Null) => Some(false),
+        Some(v) => v.as_bool(),
+    }
+}
+
+// What the webhook is sent for a payment to the wallet. Change from our
+// own transfers isn't a payment.
+fn payment_payload(event: &StateEvent, drk_tokenlist: &DrkTokenList) -> Option<Value> {
+    match event {
+        StateEvent::NewCoinReceived {
+            value,
+            token_id,
+            memo,
+            tx_id,
+            slab_index,
+            change: false,
+            ..
+        } => {
+            let token = match drk_tokenlist.symbol_from_id(*token_id) {
+                Ok(Some(symbol)) => symbol,
+                _ => token_id.to_string(),
+            };
+            Some(json!({
+                "event": "payment_received",
+                "amount": encode_base10(*value, 8),
+                "token": token,
+                "token_id": token_id.to_string(),
+                "memo": memo.to_string(),
+                "tx_id": hex::encode(tx_id),
+                "slab_index": slab_index,
+            }))
+        }
+        _ => None,
+    }
+}
+
+// Compared in constant time, so the token can't be guessed a byte at a
+// time from how long refusals take
+fn token_matches(given: &str, token: &str) -> bool {
+    given.len() == token.len()
+        && given.bytes().zip(token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+// A transaction's status as get_tx_status returns it, null if the wallet
+// didn't send it
+fn tx_status_json(wallet: &WalletPtr, tx_hash: &[u8; 32]) -> Result<Value> {
+    Ok(match wallet.get_tx_status(tx_hash)? {
+        Some((status, updated)) => json!({
+            "status": status.as_str(),
+            "slab_index": status.slab_index(),
+            "updated": updated,
+        }),
+        None => Value::Null,
+    })
+}
+
+// An optional memo param. Missing and null both mean no memo.
+fn memo_param(value: Option<&Value>) -> Result<Memo> {
+    match value {
+        None | Some(Value::Null) => Ok(Memo::empty()),
+        Some(v) => v
+            .as_str()
+            .ok_or(Error::ParseFailed("Invalid memo"))?
+            .parse(),
+    }
+}
+
+// A coin commitment, merkle node or transaction id given as 32 bytes of
+// hex
+fn parse_hash(value: &str) -> Option<[u8; 32]> {
+    let bytes = hex::decode(value).ok()?;
+    if bytes.len() != 32 {
+        return None;
+    }
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(&bytes);
+    Some(hash)
+}
+
+// Download the canonical params instead of generating our own, which
+// other nodes couldn't verify our proofs with
+async fn fetch_canonical_params(
+    config: &DarkfidConfig,
+    mint_params_path: &Path,
+    spend_params_path: &Path,
+) -> Result<()> {
+    let missing = || Error::ParamsFetchFailed("No params urls and hashes in the config".into());
+    fetch_params(
+        config.mint_params_url.as_ref().ok_or_else(missing)?,
+        config.mint_params_hash.as_deref().or(MINT_PARAMS_HASH).ok_or_else(missing)?,
+        mint_params_path,
+    )
+    .await?;
+    fetch_params(
+        config.spend_params_url.as_ref().ok_or_else(missing)?,
+        config.spend_params_hash.as_deref().or(SPEND_PARAMS_HASH).ok_or_else(missing)?,
+        spend_params_path,
+    )
+    .await
+}
+
+async fn start(
+    executor: Arc<Executor<'_>>,
+    config: &DarkfidConfig,
+    fetch_canonical: bool,
+    devnet: Option<&Devnet>,
+) -> Result<()> {
+    let mut datadir = Datadir::open(&config.datadir, "darkfid")?;
+    if let Some(params_path) = &config.params_path {
+        datadir.set_params_path(params_path)?;
+    }
+
+    let wallet_path = datadir.migrate(
+        config.wallet_path.as_deref(),
+        "~/.config/darkfi/darkfid_wallet.db",
+        datadir.file("wallet.db"),
+    )?;
+    let wallet = match (config.secure_enclave, platform_enclave()) {
+        (true, Some(enclave)) => WalletDb::new_sealed(
+            wallet_path.as_path(),
+            config.wallet_password.clone(),
+            enclave.as_ref(),
+        )?,
+        (true, None) => {
+            warn!(
+                target: "DARKFID",
+                "secure_enclave is set but this build has no secure-enclave feature"
+            );
+            WalletDb::new(wallet_path.as_path(), config.wallet_password.clone())?
+        }
+        (false, _) => WalletDb::new(wallet_path.as_path(), config.wallet_password.clone())?,
+    };
+
+    let database_path = datadir.migrate(
+        config.database_path.as_deref(),
+        "~/.config/darkfi/darkfid_client.db",
+        datadir.file("client.db"),
     )?;
+    let store = KvBackend::from_str(&config.kv_backend)?
+        .open_with(database_path.as_path(), &config.rocksdb)?;
+
+    // Cashiers can mint coins out of nothing, so only the ones signed
+    // into the provisioning file are trusted
+    if !config.cashiers.is_empty() {
+        return Err(Error::CashierProvisioningFailed(
+            "Unsigned cashiers in the config, use cashier_provisioning_path",
+        ));
+    }
+
+    // The devnet trusts its mock cashier alone
+    let provisioned = match devnet {
+        Some(devnet) => {
+            devnet.start_gateway(executor.clone())?;
+            vec![]
+        }
+        None => {
+            let provisioning_public: jubjub::SubgroupPoint =
+                deserialize(&bs58::decode(&config.provisioning_public_key).into_vec()?)?;
+            let provisioning_path = match &config.cashier_provisioning_path {
+                Some(path) => expand_path(path)?,
+                None => datadir.file("cashiers.json"),
+            };
+            CashierProvisioning::load(&provisioning_path, &schnorr::PublicKey(provisioning_public))?
+        }
+    };
 
-    let rocks = Rocks::new(expand_path(&config.database_path.clone())?.as_path())?;
+    let tls = TlsClientConfig::load(config.tls_ca_cert_path.as_deref(), &config.tls_pinned_certs)?
+        .with_client_cert(
+            config.tls_client_cert_path.as_deref(),
+            config.tls_client_key_path.as_deref(),
+        )?;
+    let proxy = config
+        .socks5_proxy
+        .as_deref()
+        .map(Socks5Proxy::parse)
+        .transpose()?;
 
     let mut cashiers = Vec::new();
     let mut cashier_keys = Vec::new();
 
-    for cashier in config.clone().cashiers {
+    for cashier in provisioned {
         if cashier.public_key.is_empty() {
             return Err(Error::CashierKeysNotFound);
         }
@@ -516,71 +2166,588 @@ async fn start(executor: Arc<Executor<'_>>, config: &DarkfidConfig) -> Result<()
         let cashier_public: jubjub::SubgroupPoint =
             deserialize(&bs58::decode(cashier.public_key).into_vec()?)?;
 
+        let client = CashierClient::new(
+            &cashier.rpc_url,
+            tls.clone(),
+            proxy,
+            config.cashier_request_timeout,
+        )?;
+
         cashiers.push(Cashier {
             name: cashier.name,
             rpc_url: cashier.rpc_url,
             public_key: cashier_public,
+            client,
         });
 
         cashier_keys.push(cashier_public);
     }
+    if let Some(devnet) = devnet {
+        cashier_keys.push(devnet.cashier_public());
+    }
 
     // Load trusted setup parameters
     let params_paths = (
-        expand_path(&config.mint_params_path.clone())?,
-        expand_path(&config.spend_params_path.clone())?,
+        datadir.migrate(
+            config.mint_params_path.as_deref(),
+            "mint.params",
+            datadir.mint_params(),
+        )?,
+        datadir.migrate(
+            config.spend_params_path.as_deref(),
+            "spend.params",
+            datadir.spend_params(),
+        )?,
     );
-    let mint_params_path = params_paths.0.to_str().unwrap_or("mint.params");
-    let spend_params_path = params_paths.1.to_str().unwrap_or("spend.params");
+    let (mint_params_path, spend_params_path) = (&params_paths.0, &params_paths.1);
+    if fetch_canonical {
+        fetch_canonical_params(config, mint_params_path, spend_params_path).await?;
+    }
     // Auto create trusted ceremony parameters if they don't exist
-    if !params_paths.0.exists() {
+    if !mint_params_path.exists() {
         let params = setup_mint_prover();
         save_params(mint_params_path, &params)?;
     }
-    if !params_paths.1.exists() {
+    if !spend_params_path.exists() {
         let params = setup_spend_prover();
         save_params(spend_params_path, &params)?;
     }
-    let (mint_params, mint_pvk) = load_params(mint_params_path)?;
-    let (spend_params, spend_pvk) = load_params(spend_params_path)?;
+    let (mint_params, mint_pvk) = load_params(mint_params_path, MINT_PARAMS_HASH)?;
+    let (spend_params, spend_pvk) = load_params(spend_params_path, SPEND_PARAMS_HASH)?;
+    let mint_batch_vk = BatchVerifyingKey::new(&mint_params.vk);
+    let spend_batch_vk = BatchVerifyingKey::new(&spend_params.vk);
+    let verify_pool = Arc::new(VerifyPool::new(config.verify_workers));
+
+    let mut gateways = vec![(
+        Url::parse(&config.gateway_protocol_url)?,
+        Url::parse(&config.gateway_publisher_url)?,
+    )];
+    for gateway in &config.fallback_gateways {
+        gateways.push((
+            Url::parse(&gateway.protocol_url)?,
+            Url::parse(&gateway.publisher_url)?,
+        ));
+    }
 
-    let client = Client::new(
-        rocks.clone(),
-        (
-            Url::parse(&config.gateway_protocol_url)?,
-            Url::parse(&config.gateway_publisher_url)?,
-        ),
+    let mut client = Client::new(
+        store.clone(),
+        gateways,
+        tls,
+        proxy,
         wallet.clone(),
         mint_params,
         spend_params,
+        verify_pool.clone(),
     )
     .await?;
 
-    let client = Arc::new(Mutex::new(client));
+    client.set_coin_selection(CoinSelectionStrategy::from_str(&config.coin_selection)?);
+    client.set_payment_policy(PaymentPolicy {
+        unknown_limit: config
+            .unknown_address_limit
+            .as_deref()
+            .map(|limit| decode_base10(limit, 8, true))
+            .transpose()?,
+        duplicate_window: config.duplicate_payment_window,
+    });
+    client.set_gateway_heartbeat(Heartbeat {
+        interval: config.gateway_heartbeat_interval,
+        timeout: config.gateway_heartbeat_timeout,
+    });
+    client.set_gateway_request_timeout(config.gateway_request_timeout);
+    if let Some(key) = &config.gateway_publisher_key {
+        let key = deserialize(&bs58::decode(key).into_vec()?)?;
+        client.set_gateway_publisher_key(schnorr::SecretKey(key));
+    }
+    if config.light_client && config.gateway_keys.is_empty() {
+        return Err(Error::ServicesError("light_client needs gateway_keys to trust"));
+    }
+    let mut gateway_keys = vec![];
+    for key in config.gateway_keys.iter() {
+        gateway_keys.push(deserialize(&bs58::decode(key).into_vec()?)?);
+    }
+    client.set_gateway_keys(gateway_keys);
 
-    let mut darkfid = Darkfid::new(client, cashiers).await?;
+    let client = Arc::new(Mutex::new(client));
 
-    let merkle_roots = RocksColumn::<columns::MerkleRoots>::new(rocks.clone());
-    let nullifiers = RocksColumn::<columns::Nullifiers>::new(rocks);
+    let merkle_roots = KvColumn::<columns::MerkleRoots>::new(store.clone());
+    let nullifiers = KvColumn::<columns::Nullifiers>::new(store.clone());
+    let sync_state = KvColumn::<columns::SyncState>::new(store.clone());
+    let sync_metrics = SyncMetrics::new(KvColumn::<columns::SyncMetrics>::new(store.clone()));
+    let commitments = KvColumn::<columns::Commitments>::new(store.clone());
+    let undo = KvColumn::<columns::Undo>::new(store.clone());
 
     let state = Arc::new(Mutex::new(State {
-        tree: CommitmentTree::empty(),
+        tree: State::load_tree(&sync_state)?,
         merkle_roots,
         nullifiers,
-        mint_pvk,
-        spend_pvk,
+        mint_pvk: Arc::new(mint_pvk),
+        spend_pvk: Arc::new(spend_pvk),
+        mint_batch_vk: Arc::new(mint_batch_vk),
+        spend_batch_vk: Arc::new(spend_batch_vk),
+        verify_pool,
         public_keys: cashier_keys,
+        sync_state,
+        sync_metrics,
+        commitments,
+        rescan_leaves: None,
+        undo,
+        events: Subscriber::new(),
+        light: config.light_client,
     }));
 
+    let address_network = AddressNetwork::from_str(&config.address_network)?;
+    let backup_status = match &config.backup {
+        Some(backup_config) => {
+            let backup = Arc::new(WalletBackup::new(
+                wallet.clone(),
+                backup_config,
+                datadir.file("wallet-backup.db"),
+                Arc::new(SystemClock),
+            )?);
+            let status = backup.status();
+            backup.start(executor.clone());
+            Some(status)
+        }
+        None => None,
+    };
+
+    let snapshot_status = match &config.snapshots {
+        Some(snapshot_config) => {
+            let schedule = SnapshotSchedule {
+                every_slabs: snapshot_config.every_slabs,
+                every_secs: snapshot_config.every_hours.saturating_mul(60 * 60),
+                keep: snapshot_config.keep,
+            };
+            let snapshots = Arc::new(StateSnapshots::new(
+                store.clone(),
+                datadir.file("snapshots"),
+                schedule,
+                Arc::new(SystemClock),
+            )?);
+            let status = snapshots.status();
+            snapshots.start(state.clone(), executor.clone());
+            Some(status)
+        }
+        None => None,
+    };
+
+    let webhook = config.notify_url.as_deref().map(Webhook::new).transpose()?;
+
+    let mut darkfid = Darkfid::new(
+        client.clone(),
+        state,
+        store,
+        wallet,
+        cashiers,
+        address_network,
+        backup_status,
+        snapshot_status,
+        webhook,
+        config.rpc_token.clone(),
+    )
+    .await?;
+
+    if config.rpc_token.is_none() && !config.rpc_listen_address.ip().is_loopback() {
+        warn!("RPC is served beyond localhost without an rpc_token. Anyone reaching it can spend.");
+    }
+    // Any web page the user visits can reach a WebSocket on localhost
+    if config.ws_listen_address.is_some() && config.rpc_token.is_none() {
+        return Err(Error::TomlDeserializeError("ws_listen_address needs an rpc_token".to_string()));
+    }
+    // And send requests to a REST listener
+    if config.rest_listen_address.is_some() && config.rpc_token.is_none() {
+        return Err(Error::TomlDeserializeError(
+            "rest_listen_address needs an rpc_token".to_string(),
+        ));
+    }
+
+    if cfg!(not(feature = "grpc")) && config.grpc_listen_address.is_some() {
+        return Err(Error::TomlDeserializeError(
+            "grpc_listen_address needs darkfid built with the grpc feature".to_string(),
+        ));
+    }
+    if config.tls_client_ca_path.is_some() && !config.serve_tls {
+        return Err(Error::TomlDeserializeError("tls_client_ca_path needs serve_tls".to_string()));
+    }
+
+    let tls_identity = server_identity(
+        config.serve_tls,
+        config.tls_cert_path.as_deref(),
+        config.tls_key_path.as_deref(),
+    )?;
     let server_config = RpcServerConfig {
         socket_addr: config.rpc_listen_address.clone(),
-        use_tls: config.serve_tls,
-        identity_path: expand_path(&config.tls_identity_path.clone())?,
-        identity_pass: config.tls_identity_password.clone(),
+        tls_identity: tls_identity.clone(),
+        tls_client_ca: config.tls_client_ca_path.as_deref().map(expand_path).transpose()?,
+        cert_methods: CERT_METHODS,
+        max_request_size: config.rpc_max_request_size,
     };
 
-    darkfid.start(state, executor.clone()).await?;
-    listen_and_serve(server_config, Arc::new(darkfid), executor).await
+    darkfid.start(executor.clone()).await?;
+    if let Some(devnet) = devnet {
+        let (mint_params, spend_params) = client.lock().await.proving_params();
+        if let Err(e) = devnet.fund(&client, mint_params, spend_params).await {
+            warn!(target: "DEVNET", "Funding the devnet wallet failed: {}", e);
+        }
+        let address = client.lock().await.address();
+        let drk_config = devnet.write_drk_config(config)?;
+        println!("{}", devnet.credentials(config, &address, &drk_config));
+    }
+    let darkfid = Arc::new(darkfid);
+    if let Some(metrics_address) = config.metrics_listen_address {
+        let (darkfid, ex) = (darkfid.clone(), executor.clone());
+        executor
+            .spawn(async move {
+                if let Err(e) = listen_and_serve_metrics(metrics_address, darkfid, ex).await {
+                    warn!("Serving metrics failed: {}", e);
+                }
+            })
+            .detach();
+    }
+
+    if let Some(readonly_address) = config.rpc_readonly_listen_address {
+        let readonly_config = RpcServerConfig {
+            socket_addr: readonly_address,
+            tls_identity,
+            tls_client_ca: None,
+            cert_methods: &[],
+            max_request_size: config.rpc_max_request_size,
+        };
+        let readonly = Arc::new(RestrictedHandler::new(darkfid.clone(), READ_ONLY_METHODS));
+        let ex = executor.clone();
+        executor
+            .spawn(async move {
+                if let Err(e) = listen_and_serve(readonly_config, readonly, ex).await {
+                    warn!("Serving read-only RPC failed: {}", e);
+                }
+            })
+            .detach();
+    }
+
+    if let Some(rpc_listen) = &config.rpc_listen {
+        let url = Url::parse(rpc_listen)?;
+        if url.scheme() != "unix" {
+            return Err(Error::UrlParseError);
+        }
+        let path = PathBuf::from(url.path());
+        let max_request_size = config.rpc_max_request_size;
+        let (darkfid, ex) = (darkfid.clone(), executor.clone());
+        executor
+            .spawn(async move {
+                let serving = listen_and_serve_unix(&path, max_request_size, darkfid, ex);
+                if let Err(e) = serving.await {
+                    warn!("Serving RPC on {} failed: {}", path.display(), e);
+                }
+            })
+            .detach();
+    }
+
+    if let Some(rest_address) = config.rest_listen_address {
+        let origins = config.rest_allowed_origins.clone();
+        let (darkfid, ex) = (darkfid.clone(), executor.clone());
+        executor
+            .spawn(async move {
+                let serving =
+                    listen_and_serve_rest(rest_address, REST_ROUTES, origins, darkfid, ex);
+                if let Err(e) = serving.await {
+                    warn!("Serving REST failed: {}", e);
+                }
+            })
+            .detach();
+    }
+
+    if let Some(ws_address) = config.ws_listen_address {
+        let origins = config.ws_allowed_origins.clone();
+        let (darkfid, ex) = (darkfid.clone(), executor.clone());
+        executor
+            .spawn(async move {
+                if let Err(e) = listen_and_serve_ws(ws_address, origins, darkfid, ex).await {
+                    warn!("Serving JSON-RPC over WebSocket failed: {}", e);
+                }
+            })
+            .detach();
+    }
+
+    // tonic brings its own runtime, which gets a thread of its own
+    #[cfg(feature = "grpc")]
+    if let Some(grpc_address) = config.grpc_listen_address {
+        let darkfid = darkfid.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = listen_and_serve_grpc(grpc_address, darkfid) {
+                warn!("Serving gRPC failed: {}", e);
+            }
+        });
+    }
+
+    futures::select! {
+        result = listen_and_serve(server_config, darkfid.clone(), executor).fuse() => result?,
+        signal = wait_for_signal().fuse() => info!("Received signal {}, shutting down", signal?),
+    }
+    darkfid.shutdown().await
+}
+
+// Wait for SIGTERM or SIGINT. Once this is called they no longer kill
+// darkfid right away.
+async fn wait_for_signal() -> Result<i32> {
+    let mut signals = Signals::new(&[SIGTERM, SIGINT])?;
+    let signal = smol::unblock(move || signals.forever().next()).await;
+    signal.ok_or(Error::ServicesError("signal handler closed"))
+}
+
+// Gather what maintainers need to look into a bug report, sanitized, and
+// write it only once the user has seen what's in it
+// Have the running darkfid copy its database to `path`
+fn backup_database(config: &DarkfidConfig, path: &str) -> Result<()> {
+    // The path is resolved by darkfid, which may run somewhere else
+    let path = expand_path(path)?;
+    let path = if path.is_absolute() {
+        path
+    } else {
+        std::env::current_dir()?.join(path)
+    };
+
+    let scheme = if config.serve_tls { "tls" } else { "tcp" };
+    let url = format!("{}://{}", scheme, config.rpc_listen_address);
+    let req = jsonreq(json!("backup_database"), json!([path.to_string_lossy()]));
+    match smol::future::block_on(send_request(&url, json!(req)))? {
+        JsonResult::Resp(r) => {
+            println!(
+                "Backed up the database at slab {} to {}",
+                r.result["index"],
+                path.display()
+            );
+            Ok(())
+        }
+        JsonResult::Err(e) => Err(Error::JsonRpcError(e.error.message.to_string())),
+        JsonResult::Notif(_) => Err(Error::JsonRpcError("Unexpected reply".to_string())),
+    }
+}
+
+// Replace the database of a stopped darkfid with a backup or snapshot
+fn restore_backup(config: &DarkfidConfig, backup: &str) -> Result<()> {
+    let datadir = Datadir::open(&config.datadir, "darkfid")?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let replaced = restore_database(
+        KvBackend::from_str(&config.kv_backend)?,
+        &expand_path(backup)?,
+        &datadir.file("client.db"),
+        now,
+    )?;
+
+    println!("Restored the database from {}", backup);
+    if let Some(replaced) = replaced {
+        println!(
+            "The database it replaced was moved to {}",
+            replaced.display()
+        );
+    }
+    Ok(())
+}
+
+// Write the state of a stopped darkfid to a snapshot file
+fn export_state(config: &DarkfidConfig, path: &str) -> Result<()> {
+    if config.light_client {
+        return Err(Error::ServicesError("a light client doesn't keep every nullifier to export"));
+    }
+    let datadir = Datadir::open(&config.datadir, "darkfid")?;
+    let store = KvBackend::from_str(&config.kv_backend)?
+        .open_with(&datadir.file("client.db"), &config.rocksdb)?;
+
+    let export = StateExport::read(store)?;
+    export.save(&expand_path(path)?)?;
+    println!("Exported the state at slab {} to {}", export.index, path);
+    Ok(())
+}
+
+// Start the empty database of a stopped darkfid from a snapshot file
+fn import_state(config: &DarkfidConfig, path: &str) -> Result<()> {
+    let export = StateExport::load(&expand_path(path)?)?;
+    let datadir = Datadir::open(&config.datadir, "darkfid")?;
+    let store = KvBackend::from_str(&config.kv_backend)?
+        .open_with(&datadir.file("client.db"), &config.rocksdb)?;
+
+    export.write(store)?;
+    println!("Imported the state at slab {} from {}", export.index, path);
+    Ok(())
+}
+
+// Replay the slabs into a scratch state and compare it with the stored
+// one, with darkfid stopped
+fn check_db(config: &DarkfidConfig, devnet: Option<&Devnet>, repair: bool) -> Result<()> {
+    // Without every nullifier there's nothing to check the slabs against
+    if config.light_client {
+        return Err(Error::ServicesError("check-db can't check a light client"));
+    }
+    let mut datadir = Datadir::open(&config.datadir, "darkfid")?;
+    if let Some(params_path) = &config.params_path {
+        datadir.set_params_path(params_path)?;
+    }
+    let store = KvBackend::from_str(&config.kv_backend)?
+        .open_with(&datadir.file("client.db"), &config.rocksdb)?;
+
+    let mut public_keys = vec![];
+    match devnet {
+        Some(devnet) => public_keys.push(devnet.cashier_public()),
+        None => {
+            let provisioning_public: jubjub::SubgroupPoint =
+                deserialize(&bs58::decode(&config.provisioning_public_key).into_vec()?)?;
+            let provisioning_path = match &config.cashier_provisioning_path {
+                Some(path) => expand_path(path)?,
+                None => datadir.file("cashiers.json"),
+            };
+            let provisioned = CashierProvisioning::load(
+                &provisioning_path,
+                &schnorr::PublicKey(provisioning_public),
+            )?;
+            for cashier in provisioned {
+                public_keys.push(deserialize(&bs58::decode(cashier.public_key).into_vec()?)?);
+            }
+        }
+    }
+
+    let (mint_params, mint_pvk) = load_params(&datadir.mint_params(), MINT_PARAMS_HASH)?;
+    let (spend_params, spend_pvk) = load_params(&datadir.spend_params(), SPEND_PARAMS_HASH)?;
+    let sync_state = KvColumn::<columns::SyncState>::new(store.clone());
+    let mut state = State {
+        tree: State::load_tree(&sync_state)?,
+        merkle_roots: KvColumn::<columns::MerkleRoots>::new(store.clone()),
+        nullifiers: KvColumn::<columns::Nullifiers>::new(store.clone()),
+        mint_pvk: Arc::new(mint_pvk),
+        spend_pvk: Arc::new(spend_pvk),
+        mint_batch_vk: Arc::new(BatchVerifyingKey::new(&mint_params.vk)),
+        spend_batch_vk: Arc::new(BatchVerifyingKey::new(&spend_params.vk)),
+        verify_pool: Arc::new(VerifyPool::new(1)),
+        public_keys,
+        sync_state,
+        sync_metrics: SyncMetrics::new(KvColumn::<columns::SyncMetrics>::new(store.clone())),
+        commitments: KvColumn::<columns::Commitments>::new(store.clone()),
+        rescan_leaves: None,
+        undo: KvColumn::<columns::Undo>::new(store.clone()),
+        events: Subscriber::new(),
+        light: false,
+    };
+    let slabs = KvColumn::<columns::Slabs>::new(store);
+
+    let (report, replay) = check_state(&state, &slabs)?;
+    println!("{}", serde_json::to_string_pretty(&report.to_json())?);
+
+    if report.is_consistent() {
+        println!("The database matches the slabs");
+        return Ok(());
+    }
+    if !report.missing_slabs.is_empty() {
+        return Err(Error::ServicesError(
+            "slabs are missing, restore a backup or remove the database to sync again",
+        ));
+    }
+    if !repair {
+        return Err(Error::ServicesError(
+            "the database doesn't match the slabs, run check-db --repair to rebuild it",
+        ));
+    }
+
+    repair_state(&mut state, &replay)?;
+    println!("Rebuilt the database from the slabs. Rescan to rebuild the wallet witnesses.");
+    Ok(())
+}
+
+fn support_bundle(config_path: &Path, config: &DarkfidConfig, args: &ArgMatches) -> Result<()> {
+    let mut bundle = SupportBundle::new();
+    bundle.add_version(
+        "darkfid",
+        &[
+            ("gateway protocol", GATEWAY_PROTOCOL_VERSION.to_string()),
+            ("datadir version", DATADIR_VERSION.to_string()),
+            ("address network", config.address_network.clone()),
+        ],
+    );
+    bundle.add_config(config_path)?;
+    bundle.add_db_stats(&Datadir::open(&config.datadir, "darkfid")?.daemon_dir())?;
+    for log in args.values_of("log").into_iter().flatten() {
+        bundle.add_log(&expand_path(log)?)?;
+    }
+
+    if args.is_present("preview") {
+        print!("{}", bundle.preview());
+        return Ok(());
+    }
+
+    let public = match args.value_of("encrypt-to") {
+        Some(key) => Some(deserialize::<jubjub::SubgroupPoint>(
+            &bs58::decode(key).into_vec()?,
+        )?),
+        None => None,
+    };
+
+    println!("The support bundle contains:\n{}", bundle.manifest());
+    println!("Run with --preview to see the contents of every file.");
+    if public.is_none() {
+        println!("The bundle is not encrypted, use --encrypt-to to encrypt it.");
+    }
+    if !args.is_present("yes") {
+        print!("Write the bundle? [y/N] ");
+        io::stdout().flush()?;
+        let mut answer = String::new();
+        io::stdin().lock().read_line(&mut answer)?;
+        if !matches!(answer.trim(), "y" | "Y" | "yes") {
+            println!("Nothing written.");
+            return Ok(());
+        }
+    }
+
+    let archive = bundle.archive()?;
+    let (contents, extension) = match public {
+        Some(public) => (
+            support_bundle::encrypt_bundle(&archive, &public),
+            "tar.zst.enc",
+        ),
+        None => (archive, "tar.zst"),
+    };
+    let out = match args.value_of("out") {
+        Some(out) => expand_path(out)?,
+        None => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            PathBuf::from(format!("darkfid-support-{}.{}", now, extension))
+        }
+    };
+    std::fs::write(&out, contents)?;
+    println!("Wrote {}", out.display());
+    Ok(())
+}
+
+// Decrypt a bundle encrypted to our support key
+fn open_support_bundle(args: &ArgMatches) -> Result<()> {
+    let secret = std::fs::read_to_string(expand_path(args.value_of("secret-file").unwrap())?)?;
+    let secret: jubjub::Fr = deserialize(&bs58::decode(secret.trim()).into_vec()?)?;
+
+    let bundle = std::fs::read(expand_path(args.value_of("BUNDLE").unwrap())?)?;
+    let archive = support_bundle::decrypt_bundle(&bundle, &secret)?;
+
+    let out = expand_path(args.value_of("out").unwrap())?;
+    std::fs::write(&out, archive)?;
+    println!("Wrote {}", out.display());
+    Ok(())
+}
+
+// A key pair for users to encrypt support bundles to
+fn support_key() {
+    let secret = jubjub::Fr::random(&mut OsRng);
+    let public = zcash_primitives::constants::SPENDING_KEY_GENERATOR * secret;
+    println!(
+        "Secret key: {}",
+        bs58::encode(serialize(&secret)).into_string()
+    );
+    println!(
+        "Public key: {}",
+        bs58::encode(serialize(&public)).into_string()
+    );
 }
 
 #[async_std::main]
@@ -588,9 +2755,121 @@ async fn main() -> Result<()> {
     let args = clap_app!(darkfid =>
         (@arg CONFIG: -c --config +takes_value "Sets a custom config file")
         (@arg verbose: -v --verbose "Increase verbosity")
+        (@arg fetchparams: --("fetch-params")
+            "Download the canonical params from the urls in the config")
+        (@arg devnet: --devnet
+            "Run a self-contained devnet with its own gateway, a mock cashier and a funded wallet")
+        (@arg devnetdir: --("devnet-dir") +takes_value requires[devnet]
+            "Where the devnet is kept (default: ~/.config/darkfi/devnet)")
+    )
+    .subcommand(
+        SubCommand::with_name("support-bundle")
+            .about("Gather sanitized logs, config and version info for a bug report")
+            .arg(
+                Arg::with_name("log")
+                    .long("log")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help("Log file to include, sanitized (repeatable)"),
+            )
+            .arg(
+                Arg::with_name("encrypt-to")
+                    .long("encrypt-to")
+                    .takes_value(true)
+                    .help("Base58 public key of the maintainer to encrypt the bundle to"),
+            )
+            .arg(
+                Arg::with_name("out")
+                    .long("out")
+                    .takes_value(true)
+                    .help("Where to write the bundle"),
+            )
+            .arg(
+                Arg::with_name("preview")
+                    .long("preview")
+                    .help("Print everything the bundle would contain and write nothing"),
+            )
+            .arg(
+                Arg::with_name("yes")
+                    .long("yes")
+                    .help("Write the bundle without asking"),
+            ),
+    )
+    .subcommand(
+        SubCommand::with_name("open-support-bundle")
+            .about("Decrypt a support bundle encrypted to our support key")
+            .arg(Arg::with_name("BUNDLE").required(true))
+            .arg(
+                Arg::with_name("secret-file")
+                    .long("secret-file")
+                    .takes_value(true)
+                    .required(true)
+                    .help("File holding the base58 secret support key"),
+            )
+            .arg(
+                Arg::with_name("out")
+                    .long("out")
+                    .takes_value(true)
+                    .required(true)
+                    .help("Where to write the decrypted tar.zst archive"),
+            ),
+    )
+    .subcommand(
+        SubCommand::with_name("backup")
+            .about("Copy the database of the running darkfid, without stopping it")
+            .arg(
+                Arg::with_name("PATH")
+                    .required(true)
+                    .help("Where to write the copy, which mustn't exist"),
+            ),
+    )
+    .subcommand(
+        SubCommand::with_name("restore")
+            .about("Replace the database with a backup or snapshot, with darkfid stopped")
+            .arg(
+                Arg::with_name("PATH")
+                    .required(true)
+                    .help("The backup or snapshot directory"),
+            ),
+    )
+    .subcommand(
+        SubCommand::with_name("snapshot")
+            .about("Move the state between nodes in a single file, with darkfid stopped")
+            .subcommand(
+                SubCommand::with_name("export")
+                    .about("Write the state to a snapshot file")
+                    .arg(Arg::with_name("FILE").required(true)),
+            )
+            .subcommand(
+                SubCommand::with_name("import")
+                    .about("Start an empty database from a trusted snapshot file")
+                    .arg(Arg::with_name("FILE").required(true)),
+            ),
+    )
+    .subcommand(
+        SubCommand::with_name("check-db")
+            .about("Check the database against the slabs it was built from, with darkfid stopped")
+            .arg(
+                Arg::with_name("repair")
+                    .long("repair")
+                    .help("Rebuild the state in the database from the slabs if it differs"),
+            ),
+    )
+    .subcommand(
+        SubCommand::with_name("support-key")
+            .about("Generate a key pair for users to encrypt support bundles to"),
     )
     .get_matches();
 
+    if let Some(matches) = args.subcommand_matches("open-support-bundle") {
+        return open_support_bundle(matches);
+    }
+    if args.subcommand_matches("support-key").is_some() {
+        support_key();
+        return Ok(());
+    }
+
     let config_path = if args.is_present("CONFIG") {
         PathBuf::from(args.value_of("CONFIG").unwrap())
     } else {
@@ -603,9 +2882,52 @@ async fn main() -> Result<()> {
         log::Level::Info
     };
 
-    simple_logger::init_with_level(loglevel)?;
+    // The devnet needs no config file
+    let devnet = if args.is_present("devnet") {
+        let dir = expand_path(args.value_of("devnetdir").unwrap_or(DEFAULT_DEVNET_DIR))?;
+        Some(Devnet::open(&dir)?)
+    } else {
+        None
+    };
+    let config: DarkfidConfig = match &devnet {
+        Some(devnet) => devnet.config()?,
+        None => Config::<DarkfidConfig>::load(config_path.clone())?,
+    };
 
-    let config: DarkfidConfig = Config::<DarkfidConfig>::load(config_path)?;
+    match &config.log_file {
+        Some(log_file) => {
+            let file = RotatingFile::open(
+                &expand_path(&log_file.path)?,
+                log_file.max_size_mb.saturating_mul(1024 * 1024),
+                log_file.max_age_hours.saturating_mul(60 * 60),
+                log_file.keep,
+                SystemClock.now(),
+            )?;
+            init_file_logger(loglevel, file)?;
+        }
+        None => simple_logger::init_with_level(loglevel)?,
+    }
+
+    if let Some(matches) = args.subcommand_matches("support-bundle") {
+        return support_bundle(&config_path, &config, matches);
+    }
+    if let Some(matches) = args.subcommand_matches("backup") {
+        return backup_database(&config, matches.value_of("PATH").unwrap());
+    }
+    if let Some(matches) = args.subcommand_matches("restore") {
+        return restore_backup(&config, matches.value_of("PATH").unwrap());
+    }
+    if let Some(matches) = args.subcommand_matches("snapshot") {
+        return match matches.subcommand() {
+            ("export", Some(matches)) => export_state(&config, matches.value_of("FILE").unwrap()),
+            ("import", Some(matches)) => import_state(&config, matches.value_of("FILE").unwrap()),
+            _ => Err(Error::ServicesError("snapshot needs export or import")),
+        };
+    }
+    if let Some(matches) = args.subcommand_matches("check-db") {
+        return check_db(&config, devnet.as_ref(), matches.is_present("repair"));
+    }
+    let fetch_canonical = args.is_present("fetchparams");
 
     let ex = Arc::new(Executor::new());
     let (signal, shutdown) = async_channel::unbounded::<()>();
@@ -622,7 +2944,7 @@ async fn main() -> Result<()> {
         // Run the main future on the current thread.
         .finish(|| {
             smol::future::block_on(async move {
-                start(ex2, &config).await?;
+                start(ex2, &config, fetch_canonical, devnet.as_ref()).await?;
                 drop(signal);
                 Ok::<(), drk::Error>(())
             })
@@ -1,24 +1,33 @@
+use std::io::{self, Write};
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[macro_use]
 extern crate prettytable;
-use clap::{clap_app, ArgMatches};
+use clap::{clap_app, Arg, ArgMatches, SubCommand};
 use log::debug;
-use prettytable::{format, Table};
+use prettytable::{format, Cell, Row, Table};
 use serde_json::{json, Value};
 
 use drk::cli::{Config, DrkConfig};
+use drk::crypto::Address;
+use drk::service::TlsClientConfig;
 use drk::util::{join_config_path, NetworkName};
 use drk::{rpc::jsonrpc, rpc::jsonrpc::JsonResult, Error, Result};
 
+/// Seconds without a slab after which `drk status` warns
+const STALE_SLAB_SECS: u64 = 600;
+
 struct Drk {
     url: String,
+    token: Option<String>,
+    tls: TlsClientConfig,
 }
 
 impl Drk {
-    pub fn new(url: String) -> Self {
-        Self { url }
+    pub fn new(url: String, token: Option<String>, tls: TlsClientConfig) -> Self {
+        Self { url, token, tls }
     }
 
     // Retrieve cashier features and error if they
@@ -39,9 +48,10 @@ impl Drk {
         Err(Error::NotSupportedNetwork)
     }
 
-    async fn request(&self, r: jsonrpc::JsonRequest) -> Result<Value> {
+    async fn request(&self, mut r: jsonrpc::JsonRequest) -> Result<Value> {
+        r.auth = self.token.clone();
         let reply: JsonResult;
-        match jsonrpc::send_request(&self.url, json!(r)).await {
+        match jsonrpc::send_request_tls(&self.url, json!(r), &self.tls).await {
             Ok(v) => reply = v,
             Err(e) => return Err(e),
         }
@@ -86,14 +96,78 @@ impl Drk {
     }
 
     // --> {"jsonrpc": "2.0", "method": "get_key", "params": [], "id": 42}
-    // <-- {"jsonrpc": "2.0", "result": "vdNS7oBj7KvsMWWmo9r96SV4SqATLrGsH2a3PGpCfJC", "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": "tdrk1gqx4m2wdhxu4wnk6xg8qv6unv7k8gu3l4j8sgc7nn...7pjcd9", "id": 42}
     async fn get_key(&self) -> Result<Value> {
         let req = jsonrpc::request(json!("get_key"), json!([]));
         Ok(self.request(req).await?)
     }
 
+    // --> {"jsonrpc": "2.0", "method": "new_key", "params": [], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": "tdrk1gqx4m2wdhxu4wnk6xg8qv6unv7k8gu3l4j8sgc7nn...7pjcd9", "id": 42}
+    async fn new_key(&self) -> Result<Value> {
+        let req = jsonrpc::request(json!("new_key"), json!([]));
+        Ok(self.request(req).await?)
+    }
+
+    // --> {"jsonrpc": "2.0", "method": "new_address", "params": [label], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": "tdrk1xztpfd8vbj5a3c9p2kmxug6jrl0e4wz8hqsnv7dr...u4lgsx", "id": 42}
+    async fn new_address(&self, label: Option<&str>) -> Result<Value> {
+        let req = jsonrpc::request(json!("new_address"), json!([label]));
+        Ok(self.request(req).await?)
+    }
+
+    // --> {"jsonrpc": "2.0", "method": "list_addresses", "params": [], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": [{"address": "tdrk1...", "label": "alice", ...}], "id": 42}
+    async fn list_addresses(&self) -> Result<Value> {
+        let req = jsonrpc::request(json!("list_addresses"), json!([]));
+        Ok(self.request(req).await?)
+    }
+
+    // --> {"jsonrpc": "2.0", "method": "get_mnemonic", "params": [], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": "abandon ability able about above absent ...", "id": 42}
+    async fn get_mnemonic(&self) -> Result<Value> {
+        let req = jsonrpc::request(json!("get_mnemonic"), json!([]));
+        Ok(self.request(req).await?)
+    }
+
+    // --> {"jsonrpc": "2.0", "method": "restore",
+    //      "params": ["abandon ability able about above absent ..."], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": "tdrk1gqx4m2wdhxu4wnk6xg8qv6unv7k8gu3l4j8sgc7nn...7pjcd9", "id": 42}
+    async fn restore(&self, mnemonic: &str) -> Result<Value> {
+        let req = jsonrpc::request(json!("restore"), json!([mnemonic]));
+        Ok(self.request(req).await?)
+    }
+
+    // --> {"jsonrpc": "2.0", "method": "emergency_stop", "params": [reason], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": true, "id": 42}
+    async fn emergency_stop(&self, reason: &str) -> Result<Value> {
+        let req = jsonrpc::request(json!("emergency_stop"), json!([reason]));
+        Ok(self.request(req).await?)
+    }
+
+    // --> {"jsonrpc": "2.0", "method": "unlock", "params": [passphrase], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": true, "id": 42}
+    async fn unlock(&self, passphrase: &str) -> Result<Value> {
+        let req = jsonrpc::request(json!("unlock"), json!([passphrase]));
+        Ok(self.request(req).await?)
+    }
+
+    // --> {"jsonrpc": "2.0", "method": "rescan", "params": [from_slab], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": {"running": true, "slabs": [1200, 4000], ..}, "id": 42}
+    async fn rescan(&self, from_slab: Option<u64>) -> Result<Value> {
+        let req = jsonrpc::request(json!("rescan"), json!([from_slab]));
+        Ok(self.request(req).await?)
+    }
+
+    // --> {"jsonrpc": "2.0", "method": "rollback", "params": [count], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": {"index": 3990}, "id": 42}
+    async fn rollback(&self, count: u64) -> Result<Value> {
+        let req = jsonrpc::request(json!("rollback"), json!([count]));
+        Ok(self.request(req).await?)
+    }
+
     // --> {"jsonrpc": "2.0", "method": "get_key", "params": ["solana", "usdc"], "id": 42}
-    // <-- {"jsonrpc": "2.0", "result": "vdNS7oBj7KvsMWWmo9r96SV4SqATLrGsH2a3PGpCfJC", "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": "tdrk1gqx4m2wdhxu4wnk6xg8qv6unv7k8gu3l4j8sgc7nn...7pjcd9", "id": 42}
     async fn get_token_id(&self, network: &str, token: &str) -> Result<Value> {
         let req = jsonrpc::request(json!("get_token_id"), json!([network, token]));
         Ok(self.request(req).await?)
@@ -106,6 +180,90 @@ impl Drk {
         Ok(self.request(req).await?)
     }
 
+    // --> {"jsonrpc": "2.0", "method": "export_view_key", "params": [], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": ["8kXjbaAq8jAf5aXW4cJrFbaA8oGcEhZm...", ...], "id": 42}
+    async fn export_view_key(&self) -> Result<Value> {
+        let req = jsonrpc::request(json!("export_view_key"), json!([]));
+        Ok(self.request(req).await?)
+    }
+
+    // --> {"jsonrpc": "2.0", "method": "import_view_key", "params": [view_key], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": true, "id": 42}
+    async fn import_view_key(&self, view_key: &str) -> Result<Value> {
+        let req = jsonrpc::request(json!("import_view_key"), json!([view_key]));
+        Ok(self.request(req).await?)
+    }
+
+    // --> {"jsonrpc": "2.0", "method": "import_sapling_key",
+    //      "params": ["secret-extended-key-main1..."], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": "tdrk1gqx4m2wdhxu4wnk6xg8qv6unv7k8gu3l4j8sgc7nn...7pjcd9", "id": 42}
+    async fn import_sapling_key(&self, key: &str) -> Result<Value> {
+        let req = jsonrpc::request(json!("import_sapling_key"), json!([key]));
+        Ok(self.request(req).await?)
+    }
+
+    // --> {"method": "get_watched_balances", "params": []}
+    // <-- {"result": {"btc": "13.37", ..}}
+    async fn get_watched_balances(&self) -> Result<Value> {
+        let req = jsonrpc::request(json!("get_watched_balances"), json!([]));
+        Ok(self.request(req).await?)
+    }
+
+    // --> {"jsonrpc": "2.0", "method": "privacy_report", "params": [], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": [{"issue": "address_reuse", "explanation": "..."}], "id": 42}
+    async fn privacy_report(&self) -> Result<Value> {
+        let req = jsonrpc::request(json!("privacy_report"), json!([]));
+        Ok(self.request(req).await?)
+    }
+
+    // --> {"jsonrpc": "2.0", "method": "get_tx_history",
+    //      "params": [since, limit, direction], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": [{"direction": "sent", "amount": "1.5", ...}], "id": 42}
+    async fn get_tx_history(
+        &self,
+        since: Option<u64>,
+        limit: Option<u64>,
+        direction: Option<&str>,
+    ) -> Result<Value> {
+        let req = jsonrpc::request(json!("get_tx_history"), json!([since, limit, direction]));
+        Ok(self.request(req).await?)
+    }
+
+    // --> {"jsonrpc": "2.0", "method": "get_tx_status", "params": ["txID"], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": {"status": "confirmed", "slab_index": 42, ...}, "id": 42}
+    async fn get_tx_status(&self, tx_id: &str) -> Result<Value> {
+        let req = jsonrpc::request(json!("get_tx_status"), json!([tx_id]));
+        Ok(self.request(req).await?)
+    }
+
+    // --> {"jsonrpc": "2.0", "method": "get_failed_operations", "params": [], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": [{"id": 1, "reason": "insufficient_funds", ...}], "id": 42}
+    async fn get_failed_operations(&self) -> Result<Value> {
+        let req = jsonrpc::request(json!("get_failed_operations"), json!([]));
+        Ok(self.request(req).await?)
+    }
+
+    // --> {"jsonrpc": "2.0", "method": "retry_failed_operation", "params": [1], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": "txID", "id": 42}
+    async fn retry_failed_operation(&self, op_id: i64) -> Result<Value> {
+        let req = jsonrpc::request(json!("retry_failed_operation"), json!([op_id]));
+        Ok(self.request(req).await?)
+    }
+
+    // --> {"jsonrpc": "2.0", "method": "get_info", "params": [], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": {"cashiers": [..], "sync": {..}, ..}, "id": 42}
+    async fn get_info(&self) -> Result<Value> {
+        let req = jsonrpc::request(json!("get_info"), json!([]));
+        Ok(self.request(req).await?)
+    }
+
+    // --> {"jsonrpc": "2.0", "method": "gateway_stats", "params": [], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": {"grade": "good", "avg_latency_millis": 120, ..}, "id": 42}
+    async fn gateway_stats(&self) -> Result<Value> {
+        let req = jsonrpc::request(json!("gateway_stats"), json!([]));
+        Ok(self.request(req).await?)
+    }
+
     // --> {"jsonrpc": "2.0", "method": "features", "params": [], "id": 42}
     // <-- {"jsonrpc": "2.0", "result": ["network": "btc", "sol"], "id": 42}
     async fn features(&self) -> Result<Value> {
@@ -135,16 +293,435 @@ impl Drk {
     }
 
     // --> {"jsonrpc": "2.0", "method": "transfer",
-    //      "params": ["dusdc", "vdNS7oBj7KvsMWWmo9r96SV4SqATLrGsH2a3PGpCfJC", 13.37], "id": 42}
+    //      "params": ["dusdc", "tdrk1gqx4m2wdhxu4wnk6xg8qv6unv7k8gu3l4j8sgc7nn...7pjcd9", 13.37, "privacy", 0.01,
+    //                 "invoice #42", true, false],
+    //      "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": "txID", "id": 42}
+    #[allow(clippy::too_many_arguments)]
+    async fn transfer(
+        &self,
+        token: &str,
+        address: &str,
+        amount: &str,
+        coin_selection: Option<&str>,
+        fee: Option<&str>,
+        memo: Option<&str>,
+        confirmed: bool,
+        allow_duplicate: bool,
+    ) -> Result<Value> {
+        let flags = confirmed || allow_duplicate;
+        let mut params = vec![json!(token), json!(address), json!(amount)];
+        if coin_selection.is_some() || fee.is_some() || memo.is_some() || flags {
+            params.push(json!(coin_selection));
+        }
+        if fee.is_some() || memo.is_some() || flags {
+            params.push(json!(fee.unwrap_or("0")));
+        }
+        if memo.is_some() || flags {
+            params.push(json!(memo));
+        }
+        if flags {
+            params.push(json!(confirmed));
+        }
+        if allow_duplicate {
+            params.push(json!(true));
+        }
+        let req = jsonrpc::request(json!("transfer"), json!(params));
+        Ok(self.request(req).await?)
+    }
+
+    // --> {"jsonrpc": "2.0", "method": "transfer_many",
+    //      "params": ["dusdc", [["tdrk1gqx4m2wdhxu4wnk6xg8qv6unv7k8gu3l4j8sgc7nn...7pjcd9", 13.37, "memo"], ...]],
+    //      "id": 42}
     // <-- {"jsonrpc": "2.0", "result": "txID", "id": 42}
-    async fn transfer(&self, token: &str, address: &str, amount: &str) -> Result<Value> {
-        let req = jsonrpc::request(json!("transfer"), json!([token, address, amount]));
+    async fn transfer_many(
+        &self,
+        token: &str,
+        recipients: Vec<(String, String, Option<String>)>,
+        coin_selection: Option<&str>,
+        fee: Option<&str>,
+        confirmed: bool,
+        allow_duplicate: bool,
+    ) -> Result<Value> {
+        let flags = confirmed || allow_duplicate;
+        let recipients: Vec<Value> = recipients
+            .into_iter()
+            .map(|(address, amount, memo)| match memo {
+                Some(memo) => json!([address, amount, memo]),
+                None => json!([address, amount]),
+            })
+            .collect();
+        let mut params = vec![json!(token), json!(recipients)];
+        if coin_selection.is_some() || fee.is_some() || flags {
+            params.push(json!(coin_selection));
+        }
+        if fee.is_some() || flags {
+            params.push(json!(fee.unwrap_or("0")));
+        }
+        if flags {
+            params.push(json!(confirmed));
+        }
+        if allow_duplicate {
+            params.push(json!(true));
+        }
+        let req = jsonrpc::request(json!("transfer_many"), json!(params));
         Ok(self.request(req).await?)
     }
+
+    // --> {"jsonrpc": "2.0", "method": "get_contacts", "params": [], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": [{"name": "shop", "address": "tdrk1...", "limit": "10"}], "id": 42}
+    async fn get_contacts(&self) -> Result<Value> {
+        let req = jsonrpc::request(json!("get_contacts"), json!([]));
+        Ok(self.request(req).await?)
+    }
+
+    // --> {"jsonrpc": "2.0", "method": "add_contact", "params": ["shop", "tdrk1...", "10"], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": true, "id": 42}
+    async fn add_contact(&self, name: &str, address: &str, limit: Option<&str>) -> Result<Value> {
+        let req = jsonrpc::request(json!("add_contact"), json!([name, address, limit]));
+        Ok(self.request(req).await?)
+    }
+
+    // --> {"jsonrpc": "2.0", "method": "remove_contact", "params": ["shop"], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": true, "id": 42}
+    async fn remove_contact(&self, name: &str) -> Result<Value> {
+        let req = jsonrpc::request(json!("remove_contact"), json!([name]));
+        Ok(self.request(req).await?)
+    }
+}
+
+// Older daemons don't return the id of the transaction they sent
+fn print_tx_id(tx_id: &Value) {
+    if let Some(tx_id) = tx_id.as_str() {
+        println!("Transaction {}, follow it with `drk tx`", tx_id);
+    }
+}
+
+// Show the recipients as they will be paid, each with the fingerprint of
+// its address, and ask before sending. Malware swapping an address on the
+// clipboard gets caught when the fingerprint doesn't match the one the
+// recipient sees.
+fn confirm_payment(token_sym: &str, recipients: &[(&str, &str)]) -> Result<bool> {
+    let mut table = Table::new();
+    table.set_format(*format::consts::FORMAT_NO_LINESEP_WITH_TITLE);
+    table.set_titles(row!["address", "network", "fingerprint", "amount"]);
+
+    for (address, amount) in recipients {
+        let (network, parsed) = Address::decode_any(address)?;
+        table.add_row(row![
+            address,
+            network,
+            parsed.fingerprint(),
+            format!("{} {}", amount, token_sym.to_uppercase())
+        ]);
+    }
+
+    table.printstd();
+    println!("Check each fingerprint with its recipient.");
+    print!("Send? [y/N] ");
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim().eq_ignore_ascii_case("y"))
+}
+
+// A --since date as YYYY-MM-DD in UTC, or as seconds since the epoch.
+// Days are counted after the civil date algorithm of Howard Hinnant.
+fn parse_since(value: &str) -> Option<u64> {
+    if let Ok(secs) = value.parse() {
+        return Some(secs);
+    }
+    let mut parts = value.splitn(3, '-').map(|part| part.parse::<i64>().ok());
+    let (year, month, day) = (parts.next()??, parts.next()??, parts.next()??);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let yoe = year - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    if days < 0 {
+        return None;
+    }
+    Some(days as u64 * 86400)
+}
+
+// Combine the replies of the daemon into the summary `drk status` shows,
+// with a warning for everything that needs a look
+fn status_summary(
+    info: &Value,
+    gateway: &Value,
+    balances: &Value,
+    history: &Value,
+    now: u64,
+) -> Value {
+    let mut warnings = vec![];
+
+    // Older daemons only report the last slab they have metrics for
+    let last_index = info["last_applied_index"]
+        .as_u64()
+        .or_else(|| info["sync"]["last_index"].as_u64())
+        .unwrap_or(0);
+    let latest_index = gateway["latest_index"]
+        .as_u64()
+        .unwrap_or(0)
+        .max(last_index);
+    let percent = if latest_index == 0 {
+        100.0
+    } else {
+        last_index as f64 * 100.0 / latest_index as f64
+    };
+    if last_index < latest_index {
+        warnings.push(format!(
+            "Syncing, {} slabs behind the gateway",
+            latest_index - last_index
+        ));
+    }
+
+    let last_slab_age = gateway["last_received"]
+        .as_u64()
+        .map(|received| now.saturating_sub(received));
+    if let Some(age) = last_slab_age {
+        if age > STALE_SLAB_SECS {
+            warnings.push(format!("No slab received for {} minutes", age / 60));
+        }
+    }
+
+    let grade = gateway["grade"].as_str().unwrap_or("down");
+    if grade != "good" {
+        warnings.push(format!("Gateway is {}", grade));
+    }
+
+    let empty = vec![];
+    let cashiers = info["cashiers"].as_array().unwrap_or(&empty);
+    for cashier in cashiers {
+        if cashier["connected"].as_bool() != Some(true) {
+            warnings.push(format!(
+                "Cashier {} is disconnected",
+                cashier["name"].as_str().unwrap_or("?")
+            ));
+        }
+    }
+
+    let pending = history
+        .as_array()
+        .unwrap_or(&empty)
+        .iter()
+        .filter(|entry| entry["slab_index"].is_null())
+        .count();
+    if pending > 0 {
+        warnings.push(format!("{} transactions not in a slab yet", pending));
+    }
+
+    let frozen = info["frozen"].as_bool().unwrap_or(false);
+    if frozen {
+        warnings.push("Wallet is frozen by an emergency stop".to_string());
+    }
+
+    if let Some(error) = info["backup"]["last_error"].as_str() {
+        warnings.push(format!("Last wallet backup failed: {}", error));
+    }
+
+    json!({
+        "daemon": "up",
+        "sync": {
+            "percent": percent,
+            "last_index": last_index,
+            "latest_index": latest_index,
+            "last_slab_age": last_slab_age,
+        },
+        "gateway": {
+            "grade": grade,
+            "url": gateway["gateway"],
+            "failovers": gateway["failovers"],
+        },
+        "cashiers": cashiers,
+        "balances": balances,
+        "pending": pending,
+        "frozen": frozen,
+        "backup": info["backup"],
+        "warnings": warnings,
+    })
+}
+
+// A row of the status table, its value colored green when healthy,
+// yellow when worth a look and red when broken
+fn status_row(name: &str, value: &str, style: &str) -> Row {
+    Row::new(vec![Cell::new(name), Cell::new(value).style_spec(style)])
+}
+
+fn print_info(info: &Value) {
+    let mut table = Table::new();
+    table.set_format(*format::consts::FORMAT_CLEAN);
+
+    table.add_row(row!["version", info["version"].as_str().unwrap_or("-")]);
+
+    let gateway = &info["gateway"];
+    let last_index = info["last_applied_index"].as_u64().unwrap_or(0);
+    let latest_index = gateway["latest_index"].as_u64().unwrap_or(0);
+    table.add_row(status_row(
+        "last slab",
+        &format!("{} of {}", last_index, latest_index.max(last_index)),
+        if last_index >= latest_index { "Fg" } else { "Fy" },
+    ));
+
+    let connected = gateway["connected"].as_bool() == Some(true);
+    table.add_row(status_row(
+        "gateway",
+        &format!(
+            "{} ({}, protocol {})",
+            if connected {
+                "connected"
+            } else {
+                "disconnected"
+            },
+            gateway["url"].as_str().unwrap_or("-"),
+            gateway["protocol_version"].as_str().unwrap_or("-")
+        ),
+        if connected { "Fg" } else { "Fr" },
+    ));
+
+    for cashier in info["cashiers"].as_array().into_iter().flatten() {
+        let connected = cashier["connected"].as_bool() == Some(true);
+        table.add_row(status_row(
+            &format!("cashier {}", cashier["name"].as_str().unwrap_or("?")),
+            if connected {
+                "connected"
+            } else {
+                "disconnected"
+            },
+            if connected { "Fg" } else { "Fr" },
+        ));
+    }
+
+    let wallet = &info["wallet"];
+    let frozen = wallet["frozen"].as_bool().unwrap_or(false);
+    table.add_row(status_row(
+        "wallet",
+        if frozen { "frozen" } else { "unlocked" },
+        if frozen { "Fr" } else { "Fg" },
+    ));
+    table.add_row(row!["coins", wallet["coins"]]);
+
+    if info["light_client"].as_bool() == Some(true) {
+        table.add_row(status_row("mode", "light client", "Fy"));
+    }
+
+    table.printstd();
+}
+
+fn print_status(summary: &Value) {
+    let mut table = Table::new();
+    table.set_format(*format::consts::FORMAT_CLEAN);
+
+    table.add_row(status_row("daemon", "up", "Fg"));
+
+    let sync = &summary["sync"];
+    let percent = sync["percent"].as_f64().unwrap_or(0.0);
+    table.add_row(status_row(
+        "sync",
+        &format!(
+            "{:.1}% (slab {} of {})",
+            percent, sync["last_index"], sync["latest_index"]
+        ),
+        if percent >= 100.0 { "Fg" } else { "Fy" },
+    ));
+    match sync["last_slab_age"].as_u64() {
+        Some(age) => table.add_row(status_row(
+            "last slab",
+            &format!("{}s ago", age),
+            if age > STALE_SLAB_SECS { "Fy" } else { "Fg" },
+        )),
+        None => table.add_row(status_row("last slab", "none received", "Fy")),
+    };
+
+    let gateway = &summary["gateway"];
+    let grade = gateway["grade"].as_str().unwrap_or("down");
+    table.add_row(status_row(
+        "gateway",
+        &format!("{} ({})", grade, gateway["url"].as_str().unwrap_or("-")),
+        match grade {
+            "good" => "Fg",
+            "degraded" => "Fy",
+            _ => "Fr",
+        },
+    ));
+
+    for cashier in summary["cashiers"].as_array().unwrap() {
+        let connected = cashier["connected"].as_bool() == Some(true);
+        table.add_row(status_row(
+            &format!("cashier {}", cashier["name"].as_str().unwrap_or("?")),
+            if connected {
+                "connected"
+            } else {
+                "disconnected"
+            },
+            if connected { "Fg" } else { "Fr" },
+        ));
+    }
+
+    match summary["balances"].as_object() {
+        Some(balances) if !balances.is_empty() => {
+            for (token, data) in balances {
+                table.add_row(row![
+                    format!("balance {}", token),
+                    format!(
+                        "{} ({})",
+                        data[0].as_str().unwrap(),
+                        data[1].as_str().unwrap()
+                    )
+                ]);
+            }
+        }
+        _ => {
+            table.add_row(row!["balance", "0"]);
+        }
+    }
+
+    let pending = summary["pending"].as_u64().unwrap_or(0);
+    table.add_row(status_row(
+        "pending",
+        &pending.to_string(),
+        if pending == 0 { "Fg" } else { "Fy" },
+    ));
+
+    if summary["frozen"].as_bool() == Some(true) {
+        table.add_row(status_row("wallet", "frozen", "Fr"));
+    }
+
+    let backup = &summary["backup"];
+    if !backup.is_null() {
+        let (value, style) = match (
+            backup["last_error"].as_str(),
+            backup["last_success"].as_u64(),
+        ) {
+            (Some(_), _) => ("failing".to_string(), "Fr"),
+            (None, Some(time)) => (format!("last at {}", time), "Fg"),
+            (None, None) => ("none yet".to_string(), "Fy"),
+        };
+        table.add_row(status_row("backup", &value, style));
+    }
+
+    table.printstd();
+
+    for warning in summary["warnings"].as_array().unwrap() {
+        println!("warning: {}", warning.as_str().unwrap());
+    }
 }
 
 async fn start(config: &DrkConfig, options: ArgMatches<'_>) -> Result<()> {
-    let client = Drk::new(config.darkfid_rpc_url.clone());
+    let tls = TlsClientConfig::load(
+        config.darkfid_tls_ca_cert_path.as_deref(),
+        &config.darkfid_tls_pinned_certs,
+    )?
+    .with_client_cert(
+        config.darkfid_tls_client_cert_path.as_deref(),
+        config.darkfid_tls_client_key_path.as_deref(),
+    )?;
+    let client = Drk::new(config.darkfid_rpc_url.clone(), config.darkfid_rpc_token.clone(), tls);
 
     if options.is_present("hello") {
         let reply = client.say_hello().await?;
@@ -173,9 +750,10 @@ async fn start(config: &DrkConfig, options: ArgMatches<'_>) -> Result<()> {
             return Ok(());
         }
 
-        if matches.is_present("address") {
-            let reply = client.get_key().await?;
-            println!("Wallet address: {}", &reply.to_string());
+        if matches.is_present("mnemonic") {
+            let reply = client.get_mnemonic().await?;
+            println!("Write down your seed phrase and keep it somewhere safe:");
+            println!("{}", reply.as_str().unwrap());
             return Ok(());
         }
 
@@ -202,6 +780,138 @@ async fn start(config: &DrkConfig, options: ArgMatches<'_>) -> Result<()> {
 
             return Ok(());
         }
+
+        if matches.is_present("viewkey") {
+            let reply = client.export_view_key().await?;
+            println!("Viewing keys:");
+            for view_key in reply.as_array().unwrap() {
+                println!("{}", view_key.as_str().unwrap());
+            }
+            println!("They reveal every payment to the addresses of this wallet handed out");
+            println!("so far, but can't spend them. Export them again after `drk address new`.");
+            return Ok(());
+        }
+
+        if let Some(view_keys) = matches.values_of("watch") {
+            for view_key in view_keys {
+                let reply = client.import_view_key(view_key).await?;
+                if reply.as_bool().unwrap() != true {
+                    println!("Server replied: {}", &reply.to_string());
+                    return Ok(());
+                }
+            }
+            println!("Viewing keys imported. Payments are watched from the next slab.");
+            return Ok(());
+        }
+
+        if let Some(key) = matches.value_of("sapling") {
+            let reply = client.import_sapling_key(key).await?;
+            println!("Sapling key imported. Address: {}", reply.as_str().unwrap());
+            println!("Run 'drk rescan' to find the coins it already owns.");
+            return Ok(());
+        }
+
+        if matches.is_present("watched") {
+            let reply = client.get_watched_balances().await?;
+
+            if reply.as_object().is_some() && !reply.as_object().unwrap().is_empty() {
+                let mut table = Table::new();
+                table.set_format(*format::consts::FORMAT_NO_LINESEP_WITH_TITLE);
+                table.set_titles(row!["token", "amount"]);
+
+                for (tkn, amount) in reply.as_object().unwrap() {
+                    table.add_row(row![tkn, amount.as_str().unwrap()]);
+                }
+
+                table.printstd();
+            } else {
+                println!("Watched balances: {}", "0".to_string());
+            }
+
+            return Ok(());
+        }
+    }
+
+    if let Some(matches) = options.subcommand_matches("address") {
+        if matches.is_present("list") {
+            let reply = client.list_addresses().await?;
+            let mut table = Table::new();
+            table.set_format(*format::consts::FORMAT_NO_LINESEP_WITH_TITLE);
+            table.set_titles(row!["address", "label", "fingerprint"]);
+            for entry in reply.as_array().unwrap() {
+                let address = entry["address"].as_str().unwrap();
+                let label = match (entry["main"].as_bool(), entry["label"].as_str()) {
+                    (Some(true), _) => "(main)",
+                    (_, Some(label)) => label,
+                    _ => "",
+                };
+                let fingerprint = match Address::decode_any(address) {
+                    Ok((_, address)) => address.fingerprint(),
+                    Err(_) => "-".to_string(),
+                };
+                table.add_row(row![address, label, fingerprint]);
+            }
+            table.printstd();
+            return Ok(());
+        }
+
+        let reply = match matches.subcommand_matches("new") {
+            Some(matches) if matches.is_present("key") => client.new_key().await?,
+            Some(matches) => client.new_address(matches.value_of("label")).await?,
+            None => client.get_key().await?,
+        };
+        println!("Wallet address: {}", &reply.to_string());
+        if let Some(Ok((_, address))) = reply.as_str().map(Address::decode_any) {
+            println!("Fingerprint: {}", address.fingerprint());
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = options.subcommand_matches("restore") {
+        let mnemonic = matches.value_of("MNEMONIC").unwrap();
+        let reply = client.restore(&mnemonic).await?;
+        println!("Wallet restored. Wallet address: {}", &reply.to_string());
+        return Ok(());
+    }
+
+    if let Some(matches) = options.subcommand_matches("freeze") {
+        let reason = matches.value_of("REASON").unwrap_or("");
+        client.emergency_stop(reason).await?;
+        println!("Wallet frozen. Transfers are refused until `drk unlock`.");
+        return Ok(());
+    }
+
+    if let Some(matches) = options.subcommand_matches("unlock") {
+        let passphrase = matches.value_of("PASSPHRASE").unwrap();
+        client.unlock(passphrase).await?;
+        println!("Wallet unlocked.");
+        return Ok(());
+    }
+
+    if let Some(matches) = options.subcommand_matches("rescan") {
+        let from_slab = match matches.value_of("from") {
+            Some(from) => Some(from.parse()?),
+            None => None,
+        };
+        let reply = client.rescan(from_slab).await?;
+        let slabs = reply["slabs"].as_array().unwrap();
+        let witnesses = reply["witnesses"].as_array().unwrap();
+
+        if reply["running"].as_bool().unwrap() {
+            println!("Rescan running. Run `drk rescan` again to follow its progress.");
+        } else {
+            println!("Rescan finished.");
+        }
+        println!("Slabs: {}/{}", slabs[0], slabs[1]);
+        println!("Witnesses: {}/{}", witnesses[0], witnesses[1]);
+        return Ok(());
+    }
+
+    if let Some(matches) = options.subcommand_matches("rollback") {
+        let count: u64 = matches.value_of("COUNT").unwrap().parse()?;
+        let reply = client.rollback(count).await?;
+        println!("Rolled back to slab {}.", reply["index"]);
+        return Ok(());
     }
 
     if let Some(matches) = options.subcommand_matches("id") {
@@ -218,12 +928,199 @@ async fn start(config: &DrkConfig, options: ArgMatches<'_>) -> Result<()> {
         return Ok(());
     }
 
+    if options.is_present("privacy") {
+        let reply = client.privacy_report().await?;
+
+        if reply.as_array().is_some() && !reply.as_array().unwrap().is_empty() {
+            let mut table = Table::new();
+            table.set_format(*format::consts::FORMAT_NO_LINESEP_WITH_TITLE);
+            table.set_titles(row!["issue", "explanation"]);
+
+            for finding in reply.as_array().unwrap() {
+                table.add_row(row![
+                    finding["issue"].as_str().unwrap(),
+                    finding["explanation"].as_str().unwrap()
+                ]);
+            }
+
+            table.printstd();
+        } else {
+            println!("No privacy issues found.");
+        }
+
+        return Ok(());
+    }
+
+    if let Some(matches) = options.subcommand_matches("tx") {
+        let reply = client.get_tx_status(matches.value_of("TXID").unwrap()).await?;
+        match (reply["status"].as_str(), reply["slab_index"].as_u64()) {
+            (Some("confirmed"), Some(index)) => {
+                println!("The transaction is confirmed in slab {}.", index)
+            }
+            (Some(status), _) => println!("The transaction is {}.", status),
+            (None, _) => println!("This wallet didn't send that transaction."),
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = options.subcommand_matches("history") {
+        let since = match matches.value_of("since") {
+            Some(since) => Some(parse_since(since).ok_or(Error::ParseFailed("Bad --since date"))?),
+            None => None,
+        };
+        let limit = match matches.value_of("limit") {
+            Some(limit) => Some(limit.parse()?),
+            None => None,
+        };
+        let direction = if matches.is_present("incoming") {
+            Some("received")
+        } else if matches.is_present("outgoing") {
+            Some("sent")
+        } else {
+            None
+        };
+        let reply = client.get_tx_history(since, limit, direction).await?;
+
+        if reply.as_array().is_some() && !reply.as_array().unwrap().is_empty() {
+            let mut table = Table::new();
+            table.set_format(*format::consts::FORMAT_NO_LINESEP_WITH_TITLE);
+            table.set_titles(row![
+                "direction",
+                "amount",
+                "token",
+                "counterparty",
+                "slab",
+                "timestamp",
+                "memo"
+            ]);
+
+            for entry in reply.as_array().unwrap() {
+                let counterparty = entry["counterparty"].as_str().unwrap_or("-");
+                let slab_index = match entry["slab_index"].as_u64() {
+                    Some(index) => index.to_string(),
+                    None => "pending".to_string(),
+                };
+                table.add_row(row![
+                    entry["direction"].as_str().unwrap(),
+                    entry["amount"].as_str().unwrap(),
+                    entry["token"].as_str().unwrap(),
+                    counterparty,
+                    slab_index,
+                    entry["timestamp"],
+                    entry["memo"].as_str().unwrap_or("")
+                ]);
+            }
+
+            table.printstd();
+        } else {
+            println!("No transactions in the wallet history.");
+        }
+
+        return Ok(());
+    }
+
+    if options.is_present("failed") {
+        let reply = client.get_failed_operations().await?;
+
+        if reply.as_array().is_some() && !reply.as_array().unwrap().is_empty() {
+            let mut table = Table::new();
+            table.set_format(*format::consts::FORMAT_NO_LINESEP_WITH_TITLE);
+            table.set_titles(row![
+                "id",
+                "method",
+                "timestamp",
+                "reason",
+                "detail",
+                "retryable",
+                "remediation"
+            ]);
+
+            for op in reply.as_array().unwrap() {
+                table.add_row(row![
+                    op["id"],
+                    op["method"].as_str().unwrap(),
+                    op["timestamp"],
+                    op["reason"].as_str().unwrap(),
+                    op["detail"].as_str().unwrap(),
+                    op["retryable"],
+                    op["remediation"].as_str().unwrap()
+                ]);
+            }
+
+            table.printstd();
+        } else {
+            println!("No failed operations.");
+        }
+
+        return Ok(());
+    }
+
+    if let Some(matches) = options.subcommand_matches("retry") {
+        let op_id: i64 = matches.value_of("ID").unwrap().parse()?;
+        client.retry_failed_operation(op_id).await?;
+        println!("Operation {} sent again successfully", op_id);
+        return Ok(());
+    }
+
+    if options.is_present("info") {
+        let info = client.get_info().await?;
+        print_info(&info);
+        return Ok(());
+    }
+
     if options.is_present("features") {
         let reply = client.features().await?;
         println!("Features: {}", &reply.to_string());
         return Ok(());
     }
 
+    if let Some(matches) = options.subcommand_matches("status") {
+        let info = match client.get_info().await {
+            Ok(info) => info,
+            Err(e) => {
+                if matches.is_present("json") {
+                    println!("{}", json!({ "daemon": "down", "error": e.to_string() }));
+                } else {
+                    let mut table = Table::new();
+                    table.set_format(*format::consts::FORMAT_CLEAN);
+                    table.add_row(status_row("daemon", "down", "Fr"));
+                    table.printstd();
+                }
+                return Err(e);
+            }
+        };
+        let gateway = client.gateway_stats().await?;
+        let balances = client.get_balances().await?;
+        let history = client.get_tx_history(None, None, None).await?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let summary = status_summary(&info, &gateway, &balances, &history, now);
+
+        if matches.is_present("json") {
+            println!("{}", serde_json::to_string_pretty(&summary)?);
+        } else {
+            print_status(&summary);
+        }
+        return Ok(());
+    }
+
+    if options.is_present("gateway") {
+        let reply = client.gateway_stats().await?;
+
+        let mut table = Table::new();
+        table.set_format(*format::consts::FORMAT_NO_LINESEP_WITH_TITLE);
+        table.set_titles(row!["metric", "value"]);
+        for (metric, value) in reply.as_object().unwrap() {
+            table.add_row(row![metric, value]);
+        }
+        table.printstd();
+
+        return Ok(());
+    }
+
     if let Some(matches) = options.subcommand_matches("deposit") {
         let network = matches.value_of("network").unwrap().to_lowercase();
         let token_sym = matches.value_of("TOKENSYM").unwrap();
@@ -265,18 +1162,134 @@ async fn start(config: &DrkConfig, options: ArgMatches<'_>) -> Result<()> {
         let token_sym = matches.value_of("TOKENSYM").unwrap();
         let address = matches.value_of("ADDRESS").unwrap();
         let amount = matches.value_of("AMOUNT").unwrap();
+        let coin_selection = matches.value_of("selection");
+        let fee = matches.value_of("fee");
+        let memo = matches.value_of("memo");
+
+        // Answering yes also confirms a first payment to the address
+        let allow_duplicate = matches.is_present("allow_duplicate");
+        let confirmed = matches.is_present("confirm");
+        if confirmed && !confirm_payment(token_sym, &[(address, amount)])? {
+            println!("Transfer cancelled.");
+            return Ok(());
+        }
 
-        client.transfer(&token_sym, &address, amount).await?;
+        let tx_id = client
+            .transfer(
+                &token_sym,
+                &address,
+                amount,
+                coin_selection,
+                fee,
+                memo,
+                confirmed,
+                allow_duplicate,
+            )
+            .await?;
 
         println!(
             "{} {} Transfered successfully",
             amount.to_string(),
             token_sym.to_string().to_uppercase(),
         );
+        print_tx_id(&tx_id);
 
         return Ok(());
     }
 
+    if let Some(matches) = options.subcommand_matches("transfer-many") {
+        let token_sym = matches.value_of("TOKENSYM").unwrap();
+        let file = matches.value_of("FILE").unwrap();
+        let coin_selection = matches.value_of("selection");
+        let fee = matches.value_of("fee");
+
+        // [{"address": "vdNS7oBj...", "amount": "13.37", "memo": "optional"}, ...]
+        let contents = std::fs::read_to_string(file)?;
+        let entries: Vec<Value> = serde_json::from_str(&contents)?;
+
+        let mut recipients = vec![];
+        for entry in entries.iter() {
+            match (entry["address"].as_str(), entry["amount"].as_str()) {
+                (Some(address), Some(amount)) => recipients.push((
+                    address.to_string(),
+                    amount.to_string(),
+                    entry["memo"].as_str().map(String::from),
+                )),
+                _ => return Err(Error::ParseFailed("Invalid recipient in transfer file")),
+            }
+        }
+
+        let confirmed = matches.is_present("confirm");
+        if confirmed {
+            let payments: Vec<(&str, &str)> = recipients
+                .iter()
+                .map(|(address, amount, _)| (address.as_str(), amount.as_str()))
+                .collect();
+            if !confirm_payment(token_sym, &payments)? {
+                println!("Transfer cancelled.");
+                return Ok(());
+            }
+        }
+
+        let allow_duplicate = matches.is_present("allow-duplicate");
+        let count = recipients.len();
+        let tx_id = client
+            .transfer_many(
+                &token_sym,
+                recipients,
+                coin_selection,
+                fee,
+                confirmed,
+                allow_duplicate,
+            )
+            .await?;
+
+        println!(
+            "{} Transfered successfully to {} recipients",
+            token_sym.to_string().to_uppercase(),
+            count,
+        );
+        print_tx_id(&tx_id);
+
+        return Ok(());
+    }
+
+    if let Some(matches) = options.subcommand_matches("contacts") {
+        if let Some(matches) = matches.subcommand_matches("add") {
+            let name = matches.value_of("NAME").unwrap();
+            client
+                .add_contact(
+                    name,
+                    matches.value_of("ADDRESS").unwrap(),
+                    matches.value_of("limit"),
+                )
+                .await?;
+            println!("Added {} to the address book", name);
+            return Ok(());
+        }
+
+        if let Some(matches) = matches.subcommand_matches("remove") {
+            let name = matches.value_of("NAME").unwrap();
+            client.remove_contact(name).await?;
+            println!("Removed {} from the address book", name);
+            return Ok(());
+        }
+
+        let reply = client.get_contacts().await?;
+        let mut table = Table::new();
+        table.set_format(*format::consts::FORMAT_NO_LINESEP_WITH_TITLE);
+        table.set_titles(row!["name", "address", "limit"]);
+        for contact in reply.as_array().unwrap_or(&vec![]) {
+            table.add_row(row![
+                contact["name"].as_str().unwrap_or(""),
+                contact["address"].as_str().unwrap_or(""),
+                contact["limit"].as_str().unwrap_or("none")
+            ]);
+        }
+        table.printstd();
+        return Ok(());
+    }
+
     println!("Please run 'drk help' to see usage.");
     Err(Error::MissingParams)
 }
@@ -286,6 +1299,7 @@ async fn main() -> Result<()> {
     let args = clap_app!(drk =>
     (@arg CONFIG: -c --config +takes_value "Sets a custom config file")
     (@arg verbose: -v --verbose "Increase verbosity")
+    (@arg info: --info "Show the daemon version, sync and connection status")
     (@subcommand hello =>
      (about: "Say hello to the RPC")
     )
@@ -293,8 +1307,44 @@ async fn main() -> Result<()> {
      (about: "Wallet operations")
      (@arg create: --create "Initialize a new wallet")
      (@arg keygen: --keygen "Generate wallet keypair")
-     (@arg address: --address "Get wallet address")
      (@arg balances: --balances "Get wallet balances")
+     (@arg mnemonic: --mnemonic "Show the wallet seed phrase for backup")
+     (@arg viewkey: --viewkey "Export the viewing keys for an auditor")
+     (@arg watch: --watch +takes_value +multiple "Watch the payments visible to viewing keys")
+     (@arg watched: --watched "Get balances of the watched viewing keys")
+     (@arg sapling: --("import-sapling") +takes_value
+      "Import a Zcash sapling extended spending key")
+    )
+    (@subcommand address =>
+     (about: "Show the wallet address, or hand out and list new ones")
+     (@subcommand new =>
+      (about: "Hand out a new address of the main key, unlinkable to the others")
+      (@arg label: --label +takes_value "A name to tell later who the address is for")
+      (@arg key: --key conflicts_with[label] "Derive a new keypair of its own instead")
+     )
+     (@subcommand list =>
+      (about: "List the addresses handed out, with their labels")
+     )
+    )
+    (@subcommand restore =>
+     (about: "Restore wallet keys from a seed phrase and rescan for coins")
+     (@arg MNEMONIC: +required "Seed phrase, quoted as a single argument")
+    )
+    (@subcommand freeze =>
+     (about: "Emergency stop: freeze the wallet if its keys may be compromised")
+     (@arg REASON: "Why, recorded in the wallet audit log")
+    )
+    (@subcommand unlock =>
+     (about: "Lift an emergency stop once its cooldown has passed")
+     (@arg PASSPHRASE: +required "The wallet password")
+    )
+    (@subcommand rescan =>
+     (about: "Rescan the local slabs for own coins, or show the progress of a rescan")
+     (@arg from: --("from-slab") +takes_value "Only look for coins from this slab on")
+    )
+    (@subcommand rollback =>
+     (about: "Undo the last applied slabs, e.g. after following a forked gateway")
+     (@arg COUNT: +required "How many slabs to undo")
     )
     (@subcommand id =>
      (about: "Get hexidecimal ID for token symbol")
@@ -303,6 +1353,34 @@ async fn main() -> Result<()> {
      (@arg TOKEN: +required
       "Which token to query (btc/sol/usdc/...)")
     )
+    (@subcommand privacy =>
+     (about: "Audit the wallet history for address reuse and linked coins")
+    )
+    (@subcommand history =>
+     (about: "Show the transfers sent and received by this wallet")
+     (@arg since: --since +takes_value "Only from this date on, as YYYY-MM-DD in UTC")
+     (@arg limit: --limit +takes_value "Only the last N transfers")
+     (@arg incoming: --incoming conflicts_with[outgoing] "Only the received transfers")
+     (@arg outgoing: --outgoing "Only the sent transfers")
+    )
+    (@subcommand tx =>
+     (about: "Show where a sent transaction is: pending, published or confirmed")
+     (@arg TXID: +required "The transaction id printed by the transfer")
+    )
+    (@subcommand failed =>
+     (about: "Show failed transfers with what to do about them")
+    )
+    (@subcommand retry =>
+     (about: "Send a failed transfer again, if it is safe to")
+     (@arg ID: +required "The id shown by `drk failed`")
+    )
+    (@subcommand gateway =>
+     (about: "Show how well the gateway serves the slab subscription")
+    )
+    (@subcommand status =>
+     (about: "Summarize the health of the daemon, gateway, cashiers and wallet")
+     (@arg json: --json "Print the summary as JSON, e.g. for monitoring")
+    )
     (@subcommand features =>
      (about: "Show what features the cashier supports")
     )
@@ -318,6 +1396,14 @@ async fn main() -> Result<()> {
      (@arg TOKENSYM: +required "Desired token (btc/sol/usdc...)")
      (@arg ADDRESS: +required "Recipient address")
      (@arg AMOUNT: +required "Amount to send")
+     (@arg selection: +takes_value --selection
+      "How to pick the coins to spend (smallest-first/largest-first/privacy)")
+     (@arg fee: +takes_value --fee "Fee to pay on top of the amount")
+     (@arg memo: +takes_value --memo "Short message only the recipient can read")
+     (@arg confirm: --confirm
+      "Show the address fingerprint and ask before sending, confirming a first payment")
+     (@arg allow_duplicate: --("allow-duplicate")
+      "Send even if the same payment was sent a moment ago")
     )
     (@subcommand withdraw =>
      (about: "Withdraw Dark tokens for clear tokens")
@@ -328,6 +1414,65 @@ async fn main() -> Result<()> {
      (@arg AMOUNT: +required "Amount to withdraw")
     )
     )
+    .subcommand(
+        SubCommand::with_name("transfer-many")
+            .about("Transfer Dark tokens to several addresses in one transaction")
+            .arg(
+                Arg::with_name("TOKENSYM")
+                    .required(true)
+                    .help("Desired token (btc/sol/usdc...)"),
+            )
+            .arg(Arg::with_name("FILE").required(true).help(
+                "JSON file listing the recipients as {\"address\", \"amount\", \"memo\"} \
+                         objects, the memo being optional",
+            ))
+            .arg(
+                Arg::with_name("selection")
+                    .long("selection")
+                    .takes_value(true)
+                    .help("How to pick the coins to spend (smallest-first/largest-first/privacy)"),
+            )
+            .arg(
+                Arg::with_name("fee")
+                    .long("fee")
+                    .takes_value(true)
+                    .help("Fee to pay on top of the amounts"),
+            )
+            .arg(Arg::with_name("confirm").long("confirm").help(
+                "Show the address fingerprints and ask before sending, \
+                         confirming first payments",
+            ))
+            .arg(
+                Arg::with_name("allow-duplicate")
+                    .long("allow-duplicate")
+                    .help("Send even if the same payments were sent a moment ago"),
+            ),
+    )
+    .subcommand(
+        SubCommand::with_name("contacts")
+            .about("List the address book, or change it")
+            .subcommand(
+                SubCommand::with_name("add")
+                    .about("Add a contact, or replace the one with the same name")
+                    .arg(Arg::with_name("NAME").required(true).help("Contact name"))
+                    .arg(
+                        Arg::with_name("ADDRESS")
+                            .required(true)
+                            .help("Contact address"),
+                    )
+                    .arg(
+                        Arg::with_name("limit")
+                            .long("limit")
+                            .takes_value(true)
+                            .help("Largest single payment to the contact, unlimited if not set"),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("remove")
+                    .about("Remove a contact")
+                    .arg(Arg::with_name("NAME").required(true).help("Contact name")),
+            ),
+    )
     .get_matches();
 
     let config_path = if args.is_present("CONFIG") {
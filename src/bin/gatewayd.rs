@@ -1,40 +1,251 @@
+use std::net::{IpAddr, Ipv4Addr};
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Arc;
 
 use async_executor::Executor;
-use clap::clap_app;
+use clap::{clap_app, ArgMatches, SubCommand};
 use easy_parallel::Parallel;
-use log::debug;
+use log::{debug, warn};
+use url::Url;
 
 use drk::{
-    blockchain::{rocks::columns, Rocks, RocksColumn},
+    blockchain::{kv::columns, KvBackend, KvColumn, SlabShard, SlabStore},
     cli::{Config, GatewaydConfig},
-    service::GatewayService,
+    crypto::schnorr,
+    serial::{deserialize, serialize},
+    service::{
+        find_divergence,
+        rate_limit::RateLimit,
+        tls::{serve_tls_tunnel, tls_acceptor, ClientAuth, TunnelLimits},
+        Compression, GatewayLimits, GatewayReader, GatewayService, SlabSigner,
+    },
     util::{expand_path, join_config_path},
-    Result,
+    Error, Result,
 };
 
 async fn start(executor: Arc<Executor<'_>>, config: Arc<&GatewaydConfig>) -> Result<()> {
-    let rocks = Rocks::new(&expand_path(&config.database_path)?)?;
-    let rocks_slabstore_column = RocksColumn::<columns::Slabs>::new(rocks);
+    let backend = KvBackend::from_str(&config.kv_backend)?;
+    let store = backend.open(&expand_path(&config.database_path)?)?;
+    let slabstore_column = KvColumn::<columns::Slabs>::new(store.clone());
 
+    let mut shards = vec![];
+    for shard in config.slab_shards.iter() {
+        let store = backend.open(&expand_path(&shard.path)?)?;
+        shards.push(SlabShard {
+            first_index: shard.first_index,
+            last_index: shard.last_index,
+            rocks: KvColumn::<columns::Slabs>::new(store),
+        });
+    }
+    let slabstore = SlabStore::sharded(slabstore_column, shards)?;
+
+    let mut peers = vec![];
+    for peer in config.replicate_from.iter() {
+        peers.push(Url::parse(peer)?);
+    }
+
+    let mut publishers = vec![];
+    for key in config.publisher_keys.iter() {
+        publishers.push(deserialize(&bs58::decode(key).into_vec()?)?);
+    }
+
+    let signer = match &config.signing_key {
+        Some(key) => {
+            let key = deserialize(&bs58::decode(key).into_vec()?)?;
+            Some(SlabSigner::new(
+                schnorr::SecretKey(key),
+                KvColumn::<columns::SlabSignatures>::new(store),
+            ))
+        }
+        None => None,
+    };
+
+    let limits = &config.rate_limits;
     let gateway = GatewayService::new(
         config.protocol_listen_address,
         config.publisher_listen_address,
-        rocks_slabstore_column,
+        slabstore,
+        if config.compress_slabs {
+            Compression::Zstd
+        } else {
+            Compression::None
+        },
+        peers,
+        publishers,
+        signer,
+        GatewayLimits {
+            requests: RateLimit {
+                rate: limits.requests_per_second,
+                burst: limits.request_burst,
+            },
+            put_slabs: RateLimit {
+                rate: limits.put_slabs_per_second,
+                burst: limits.put_slab_burst,
+            },
+            max_violations: limits.max_violations,
+            max_slab_size: config.max_slab_size,
+        },
     )?;
 
+    // zmq has no TLS, so it's terminated in front of the plain sockets
+    if config.serve_tls {
+        let (tls_protocol, tls_publisher) =
+            match (
+                config.tls_protocol_listen_address,
+                config.tls_publisher_listen_address,
+            ) {
+                (Some(protocol), Some(publisher)) => (protocol, publisher),
+                _ => return Err(Error::ServicesError(
+                    "serve_tls needs tls_protocol_listen_address and tls_publisher_listen_address",
+                )),
+            };
+        // Only clients with a certificate the CA signed get through, if
+        // one is set
+        let client_auth = ClientAuth::required(config.tls_client_ca_path.as_deref())?;
+        let acceptor = match (&config.tls_cert_path, &config.tls_key_path) {
+            (Some(cert_path), Some(key_path)) => {
+                tls_acceptor(&expand_path(cert_path)?, &expand_path(key_path)?, &client_auth)?
+            }
+            _ => {
+                return Err(Error::ServicesError("serve_tls needs tls_cert_path and tls_key_path"))
+            }
+        };
+
+        let tunnel_limits = TunnelLimits {
+            max_connections: 0,
+            max_connections_per_ip: limits.max_connections_per_ip,
+            max_queued_bytes: 0,
+            upload: RateLimit {
+                rate: limits.upload_bytes_per_second,
+                burst: limits.upload_burst_bytes,
+            },
+            download: RateLimit {
+                rate: limits.download_bytes_per_second,
+                burst: limits.download_burst_bytes,
+            },
+            max_violations: limits.max_violations,
+        };
+        // Subscribers are bounded in number, and dropped once they fall
+        // too far behind rather than queueing slabs for them forever
+        let subscriber_limits = TunnelLimits {
+            max_connections: limits.max_subscribers,
+            max_queued_bytes: limits.subscriber_queue_bytes,
+            ..tunnel_limits
+        };
+        for &(listen, target, tunnel_limits) in &[
+            (tls_protocol, config.protocol_listen_address, tunnel_limits),
+            (tls_publisher, config.publisher_listen_address, subscriber_limits),
+        ] {
+            let (acceptor, ex) = (acceptor.clone(), executor.clone());
+            executor
+                .spawn(async move {
+                    if let Err(e) =
+                        serve_tls_tunnel(listen, target, acceptor, tunnel_limits, ex).await
+                    {
+                        warn!(target: "GATEWAY DAEMON", "TLS on {} stopped: {}", listen, e);
+                    }
+                })
+                .detach();
+        }
+    }
+
     Ok(gateway.start(executor.clone()).await?)
 }
 
+// Compare this gateway's slabs against a mirror and report the first slab
+// they disagree on. Exits with status 1 on divergence so it can run in cron.
+async fn verify(config: &GatewaydConfig, options: &ArgMatches<'_>) -> Result<()> {
+    let gateway_url = match options.value_of("gateway") {
+        Some(url) => Url::parse(url)?,
+        None => {
+            let mut addr = config.protocol_listen_address;
+            if addr.ip().is_unspecified() {
+                addr.set_ip(IpAddr::V4(Ipv4Addr::LOCALHOST));
+            }
+            Url::parse(&format!("tcp://{}", addr))?
+        }
+    };
+    let mirror_url = Url::parse(options.value_of("against").unwrap())?;
+
+    let mut gateway = GatewayReader::new(gateway_url.clone())?;
+    let mut mirror = GatewayReader::new(mirror_url.clone())?;
+    gateway.start().await?;
+    mirror.start().await?;
+
+    let last_index = gateway.get_last_index().await?;
+    let mirror_last_index = mirror.get_last_index().await?;
+
+    println!("{} last index: {}", gateway_url, last_index);
+    println!("{} last index: {}", mirror_url, mirror_last_index);
+
+    let from = match options.value_of("from") {
+        Some(from) => from.parse::<u64>()?,
+        None => 1,
+    };
+    let to = match options.value_of("to") {
+        Some(to) => to.parse::<u64>()?,
+        None => std::cmp::min(last_index, mirror_last_index),
+    };
+
+    match find_divergence(&mut gateway, &mut mirror, from, to).await? {
+        Some(index) => {
+            println!("Gateways diverge at slab {}", index);
+            std::process::exit(1);
+        }
+        None => {
+            println!("Slabs {} to {} match", from, to);
+            if last_index != mirror_last_index {
+                println!("Note: one gateway is behind the other");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Print a new key pair for a publisher: the secret for its config, the
+// public key for the gateway's publisher_keys. Gateways sign slabs with
+// the same kind of key, its public half going in the clients'
+// gateway_keys.
+fn publisher_key() {
+    let secret = schnorr::SecretKey::random();
+    println!(
+        "Secret key: {}",
+        bs58::encode(serialize(&secret.0)).into_string()
+    );
+    println!(
+        "Public key: {}",
+        bs58::encode(serialize(&secret.public_key().0)).into_string()
+    );
+}
+
 #[async_std::main]
 async fn main() -> Result<()> {
     let args = clap_app!(gatewayd =>
         (@arg CONFIG: -c --config +takes_value "Sets a custom config file")
         (@arg verbose: -v --verbose "Increase verbosity")
+        (@subcommand verify =>
+         (about: "Compare slab hashes against a mirror gateway")
+         (@arg against: +required +takes_value --against
+          "Protocol URL of the mirror gateway (tcp://host:port)")
+         (@arg gateway: +takes_value --gateway
+          "Protocol URL of the gateway to check (defaults to this node)")
+         (@arg from: +takes_value --from "First slab index to compare (default: 1)")
+         (@arg to: +takes_value --to "Last slab index to compare (default: lowest last index)")
+        )
+    )
+    .subcommand(
+        SubCommand::with_name("publisher-key")
+            .about("Generate a key pair to sign submitted or accepted slabs with"),
     )
     .get_matches();
 
+    if args.subcommand_matches("publisher-key").is_some() {
+        publisher_key();
+        return Ok(());
+    }
+
     let config_path = if args.is_present("CONFIG") {
         PathBuf::from(args.value_of("CONFIG").unwrap())
     } else {
@@ -54,6 +265,10 @@ async fn main() -> Result<()> {
 
     let config: GatewaydConfig = Config::<GatewaydConfig>::load(config_path)?;
 
+    if let Some(matches) = args.subcommand_matches("verify") {
+        return verify(&config, matches).await;
+    }
+
     let config_ptr = Arc::new(&config);
 
     let ex2 = ex.clone();
@@ -9,7 +9,7 @@ use drk::crypto::{
     load_params,
     merkle::{CommitmentTree, IncrementalWitness},
     merkle_node::MerkleNode,
-    note::{EncryptedNote, Note},
+    note::{EncryptedNote, Memo, Note},
     nullifier::Nullifier,
     save_params, setup_mint_prover, setup_spend_prover,
 };
@@ -120,16 +120,18 @@ async fn main() {
     // Auto create trusted ceremony parameters if they don't exist
     if !Path::new("mint.params").exists() {
         let params = setup_mint_prover();
-        save_params("mint.params", &params).unwrap();
+        save_params(Path::new("mint.params"), &params).unwrap();
     }
     if !Path::new("spend.params").exists() {
         let params = setup_spend_prover();
-        save_params("spend.params", &params).unwrap();
+        save_params(Path::new("spend.params"), &params).unwrap();
     }
 
     // Load trusted setup parameters
-    let (mint_params, mint_pvk) = load_params("mint.params").expect("params should load");
-    let (spend_params, spend_pvk) = load_params("spend.params").expect("params should load");
+    let (mint_params, mint_pvk) =
+        load_params(Path::new("mint.params"), None).expect("params should load");
+    let (spend_params, spend_pvk) =
+        load_params(Path::new("spend.params"), None).expect("params should load");
 
     // Cashier creates a secret key
     let cashier_secret = jubjub::Fr::random(&mut OsRng);
@@ -169,7 +171,11 @@ async fn main() {
             value: 110,
             token_id: token_id,
             public,
+            view_public: public,
+            memo: Memo::empty(),
         }],
+        fee: 0,
+        out_public: None,
     };
 
     // We will 'compile' the tx, and then serialize it to this Vec<u8>
@@ -282,12 +288,16 @@ async fn main() {
             note: state.own_coins[0].1.clone(),
         }],
         // We can add more outputs to this list.
-        // The only constraint is that sum(value in) == sum(value out)
+        // The only constraint is that sum(value in) == sum(value out) + fee
         outputs: vec![tx::TransactionBuilderOutputInfo {
             value: 110,
             token_id: token_id,
             public: public2,
+            view_public: public2,
+            memo: Memo::empty(),
         }],
+        fee: 0,
+        out_public: None,
     };
     // Build the tx
     let mut tx_data = vec![];
@@ -357,15 +367,18 @@ pub fn state_transition<S: ProgramState>(
     // Newly created coins for this tx
     let mut coins = vec![];
     let mut enc_notes = vec![];
+    let mut out_notes = vec![];
     for output in tx.outputs {
         // Gather all the coins
         coins.push(Coin::new(output.revealed.coin));
         enc_notes.push(output.enc_note);
+        out_notes.push(output.out_note);
     }
 
     Ok(StateUpdate {
         nullifiers,
         coins,
         enc_notes,
+        out_notes,
     })
 }
@@ -0,0 +1,314 @@
+//! Checks the node state in the database against the slabs it was built
+//! from. The slabs up to the checkpoint are applied again from the first
+//! one into a scratch state in memory, and the merkle tree, roots,
+//! nullifiers and tree leaves that result are compared with the stored
+//! ones. A database that diverged can be repaired from the scratch state.
+
+use async_std::sync::Arc;
+use std::collections::{BTreeMap, HashSet};
+
+use bellman::groth16;
+use bls12_381::Bls12;
+use log::info;
+use serde_json::{json, Value};
+
+use super::kv::{columns, Column, IteratorMode, KvBatch};
+use super::{KvColumn, Slab};
+use crate::client::State;
+use crate::crypto::{merkle::CommitmentTree, merkle_node::MerkleNode, nullifier::Nullifier};
+use crate::serial::serialize;
+use crate::state::{state_transition, ProgramState};
+use crate::tx;
+use crate::Result;
+
+/// How the stored entries of a column differ from the replayed ones
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ColumnDiff {
+    /// Entries that should be stored but aren't
+    pub missing: usize,
+    /// Entries stored with the wrong value
+    pub wrong: usize,
+    /// Entries stored that shouldn't be
+    pub extra: usize,
+}
+
+impl ColumnDiff {
+    pub fn is_empty(&self) -> bool {
+        self.missing == 0 && self.wrong == 0 && self.extra == 0
+    }
+
+    fn to_json(&self) -> Value {
+        json!({"missing": self.missing, "wrong": self.wrong, "extra": self.extra})
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct IntegrityReport {
+    /// Index of the last applied slab, which the slabs are replayed up to
+    pub checkpoint_index: u64,
+    /// Slabs up to the checkpoint missing from the slabstore
+    pub missing_slabs: Vec<u64>,
+    /// Slabs that don't decode or verify, which count as applied
+    pub skipped_slabs: usize,
+    pub tree_matches: bool,
+    pub merkle_roots: ColumnDiff,
+    pub nullifiers: ColumnDiff,
+    pub commitments: ColumnDiff,
+}
+
+impl IntegrityReport {
+    /// Whether the stored state is the one the slabs give. Missing slabs
+    /// mean it can't be told, or repaired.
+    pub fn is_consistent(&self) -> bool {
+        self.missing_slabs.is_empty()
+            && self.tree_matches
+            && self.merkle_roots.is_empty()
+            && self.nullifiers.is_empty()
+            && self.commitments.is_empty()
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "consistent": self.is_consistent(),
+            "checkpoint_index": self.checkpoint_index,
+            "missing_slabs": self.missing_slabs,
+            "skipped_slabs": self.skipped_slabs,
+            "tree_matches": self.tree_matches,
+            "merkle_roots": self.merkle_roots.to_json(),
+            "nullifiers": self.nullifiers.to_json(),
+            "commitments": self.commitments.to_json(),
+        })
+    }
+}
+
+/// The state the slabs give, built in memory
+pub struct Replay {
+    tree: CommitmentTree<MerkleNode>,
+    merkle_roots: Vec<MerkleNode>,
+    root_keys: HashSet<Vec<u8>>,
+    nullifiers: Vec<[u8; 32]>,
+    nullifier_keys: HashSet<[u8; 32]>,
+    // Coin and tree node of every leaf by position
+    leaves: Vec<([u8; 32], MerkleNode)>,
+    public_keys: Vec<jubjub::SubgroupPoint>,
+    mint_pvk: Arc<groth16::PreparedVerifyingKey<Bls12>>,
+    spend_pvk: Arc<groth16::PreparedVerifyingKey<Bls12>>,
+}
+
+impl ProgramState for Replay {
+    fn is_valid_cashier_public_key(&self, public: &jubjub::SubgroupPoint) -> bool {
+        self.public_keys.contains(public)
+    }
+
+    fn is_valid_merkle(&self, merkle_root: &MerkleNode) -> bool {
+        self.root_keys.contains(&serialize(merkle_root))
+    }
+
+    fn nullifier_exists(&self, nullifier: &Nullifier) -> bool {
+        self.nullifier_keys.contains(&nullifier.repr)
+    }
+
+    fn mint_pvk(&self) -> &groth16::PreparedVerifyingKey<Bls12> {
+        &self.mint_pvk
+    }
+
+    fn spend_pvk(&self) -> &groth16::PreparedVerifyingKey<Bls12> {
+        &self.spend_pvk
+    }
+}
+
+impl Replay {
+    fn new(state: &State) -> Self {
+        Self {
+            tree: CommitmentTree::empty(),
+            merkle_roots: vec![],
+            root_keys: HashSet::new(),
+            nullifiers: vec![],
+            nullifier_keys: HashSet::new(),
+            leaves: vec![],
+            public_keys: state.public_keys.clone(),
+            mint_pvk: state.mint_pvk.clone(),
+            spend_pvk: state.spend_pvk.clone(),
+        }
+    }
+
+    // Like State::apply, without the wallet. Returns whether the slab's
+    // transaction was applied.
+    fn apply(&mut self, slab: &Slab) -> bool {
+        let tx = match tx::Transaction::decode(&slab.get_payload()[..]) {
+            Ok(tx) => tx,
+            Err(_) => return false,
+        };
+        let update = match state_transition(&*self, tx) {
+            Ok(update) => update,
+            Err(_) => return false,
+        };
+
+        for nullifier in update.nullifiers {
+            self.nullifier_keys.insert(nullifier.repr);
+            self.nullifiers.push(nullifier.repr);
+        }
+        for coin in update.coins {
+            let node = MerkleNode::from_coin(&coin);
+            self.tree.append(node).expect("Append to merkle tree");
+            self.leaves.push((coin.repr, node));
+
+            let root = self.tree.root();
+            self.root_keys.insert(serialize(&root));
+            self.merkle_roots.push(root);
+        }
+        true
+    }
+
+    fn expected_roots(&self) -> BTreeMap<Vec<u8>, Vec<u8>> {
+        self.merkle_roots
+            .iter()
+            .map(|root| (serialize(root), serialize(&(vec![] as Vec<u8>))))
+            .collect()
+    }
+
+    fn expected_nullifiers(&self) -> BTreeMap<Vec<u8>, Vec<u8>> {
+        self.nullifiers
+            .iter()
+            .map(|nullifier| (serialize(nullifier), serialize(&(vec![] as Vec<u8>))))
+            .collect()
+    }
+
+    // Leaves are stored both by position and by coin
+    fn expected_commitments(&self) -> BTreeMap<Vec<u8>, Vec<u8>> {
+        let mut expected = BTreeMap::new();
+        for (position, (coin, node)) in self.leaves.iter().enumerate() {
+            let position = position as u64;
+            expected.insert(serialize(&position), serialize(node));
+            expected.insert(serialize(coin), serialize(&position));
+        }
+        expected
+    }
+}
+
+fn diff_column<T: Column>(
+    column: &KvColumn<T>,
+    expected: &BTreeMap<Vec<u8>, Vec<u8>>,
+) -> Result<ColumnDiff> {
+    let mut diff = ColumnDiff::default();
+    let mut found = 0;
+    for (key, value) in column.iterator(IteratorMode::Start)? {
+        match expected.get(&key) {
+            Some(want) if *want == value => found += 1,
+            Some(_) => diff.wrong += 1,
+            None => diff.extra += 1,
+        }
+    }
+    diff.missing = expected.len() - found - diff.wrong;
+    Ok(diff)
+}
+
+/// Replay the slabs up to the checkpoint and compare the result with the
+/// stored state. The proofs are verified again, so this is slow.
+pub fn check_state(
+    state: &State,
+    slabs: &KvColumn<columns::Slabs>,
+) -> Result<(IntegrityReport, Replay)> {
+    let checkpoint_index = state.last_applied_index()?;
+    let mut replay = Replay::new(state);
+    let mut missing_slabs = vec![];
+    let mut skipped_slabs = 0;
+
+    for index in 1..=checkpoint_index {
+        match slabs.get_value_deserialized::<Slab>(serialize(&index))? {
+            Some(slab) => {
+                if !replay.apply(&slab) {
+                    skipped_slabs += 1;
+                }
+            }
+            None => missing_slabs.push(index),
+        }
+        if index % 1000 == 0 {
+            info!(target: "INTEGRITY", "Replayed {} of {} slabs", index, checkpoint_index);
+        }
+    }
+
+    let report = IntegrityReport {
+        checkpoint_index,
+        missing_slabs,
+        skipped_slabs,
+        tree_matches: serialize(&state.tree) == serialize(&replay.tree),
+        merkle_roots: diff_column(&state.merkle_roots, &replay.expected_roots())?,
+        nullifiers: diff_column(&state.nullifiers, &replay.expected_nullifiers())?,
+        commitments: diff_column(&state.commitments, &replay.expected_commitments())?,
+    };
+    Ok((report, replay))
+}
+
+/// Replace the stored tree, roots, nullifiers and leaves with the
+/// replayed ones. The wallet witnesses are left as they are, a rescan
+/// rebuilds them. The undo records no longer match and are dropped.
+pub fn repair_state(state: &mut State, replay: &Replay) -> Result<()> {
+    state.merkle_roots.clear()?;
+    state.nullifiers.clear()?;
+    state.commitments.clear()?;
+    state.undo.clear()?;
+
+    let mut batch = KvBatch::new();
+    for root in replay.merkle_roots.iter() {
+        state
+            .merkle_roots
+            .put_batch(&mut batch, *root, vec![] as Vec<u8>);
+    }
+    for nullifier in replay.nullifiers.iter() {
+        state
+            .nullifiers
+            .put_batch(&mut batch, *nullifier, vec![] as Vec<u8>);
+    }
+    for (position, (coin, node)) in replay.leaves.iter().enumerate() {
+        let position = position as u64;
+        state.commitments.put_batch(&mut batch, position, *node);
+        state.commitments.put_batch(&mut batch, *coin, position);
+    }
+
+    state.tree = replay.tree.clone();
+    let index = state.last_applied_index()?;
+    state.checkpoint_batch(&mut batch, index);
+    state.sync_state.write(batch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::KvBackend;
+
+    #[test]
+    fn test_diff_column() -> Result<()> {
+        let path = std::env::temp_dir().join("darkfi_test_integrity_db");
+        let _ = std::fs::remove_dir_all(&path);
+        let nullifiers = KvColumn::<columns::Nullifiers>::new(KvBackend::default().open(&path)?);
+
+        let mut expected = BTreeMap::new();
+        for repr in 1..=3u8 {
+            expected.insert(serialize(&[repr; 32]), serialize(&(vec![] as Vec<u8>)));
+        }
+        assert_eq!(diff_column(&nullifiers, &expected)?.missing, 3);
+
+        nullifiers.put([1u8; 32], vec![] as Vec<u8>)?;
+        nullifiers.put([2u8; 32], vec![0u8])?;
+        nullifiers.put([4u8; 32], vec![] as Vec<u8>)?;
+        let diff = diff_column(&nullifiers, &expected)?;
+        assert_eq!(
+            diff,
+            ColumnDiff {
+                missing: 1,
+                wrong: 1,
+                extra: 1,
+            }
+        );
+
+        nullifiers.put([2u8; 32], vec![] as Vec<u8>)?;
+        nullifiers.put([3u8; 32], vec![] as Vec<u8>)?;
+        nullifiers.delete([4u8; 32])?;
+        assert!(diff_column(&nullifiers, &expected)?.is_empty());
+
+        drop(nullifiers);
+        std::fs::remove_dir_all(&path)?;
+        Ok(())
+    }
+}
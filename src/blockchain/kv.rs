@@ -0,0 +1,477 @@
+//! The key-value store slabs and the node state are kept in. Columns are
+//! coded against `KvStore`, which rocksdb and sled both implement, so
+//! nodes can be built on targets where rocksdb is painful to build. The
+//! backends are cargo features, rocksdb being the default one, and the
+//! one a node uses is picked in its config.
+
+use async_std::sync::Arc;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::serial::{deserialize, serialize, Decodable, Encodable};
+use crate::{Error, Result};
+
+#[cfg(not(any(feature = "rocksdb", feature = "sled")))]
+compile_error!("a key-value store is needed, enable the rocksdb or sled feature");
+
+pub enum IteratorMode {
+    Start,
+    End,
+}
+
+pub trait Column {
+    const NAME: &'static str;
+}
+
+pub mod columns {
+    pub struct Slabs;
+    pub struct Nullifiers;
+    pub struct MerkleRoots;
+    pub struct SyncState;
+    pub struct SyncMetrics;
+    pub struct Commitments;
+    pub struct SlabSignatures;
+    pub struct Undo;
+    pub struct Pending;
+}
+
+impl Column for columns::Slabs {
+    const NAME: &'static str = "slabs";
+}
+
+impl Column for columns::Nullifiers {
+    const NAME: &'static str = "nullifiers";
+}
+
+impl Column for columns::MerkleRoots {
+    const NAME: &'static str = "merkleroots";
+}
+
+impl Column for columns::SyncState {
+    const NAME: &'static str = "syncstate";
+}
+
+impl Column for columns::SyncMetrics {
+    const NAME: &'static str = "syncmetrics";
+}
+
+impl Column for columns::Commitments {
+    const NAME: &'static str = "commitments";
+}
+
+impl Column for columns::SlabSignatures {
+    const NAME: &'static str = "slabsignatures";
+}
+
+impl Column for columns::Undo {
+    const NAME: &'static str = "undo";
+}
+
+impl Column for columns::Pending {
+    const NAME: &'static str = "pending";
+}
+
+/// The columns every store has
+pub const COLUMNS: &[&str] = &[
+    columns::Slabs::NAME,
+    columns::Nullifiers::NAME,
+    columns::MerkleRoots::NAME,
+    columns::SyncState::NAME,
+    columns::SyncMetrics::NAME,
+    columns::Commitments::NAME,
+    columns::SlabSignatures::NAME,
+    columns::Undo::NAME,
+    columns::Pending::NAME,
+];
+
+/// Writes to any columns of a store, made all at once by `KvStore::write`
+#[derive(Default)]
+pub struct KvBatch {
+    // Column, key and the value to put, or None to delete the key
+    ops: Vec<(&'static str, Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl KvBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn ops(&self) -> &[(&'static str, Vec<u8>, Option<Vec<u8>>)] {
+        &self.ops
+    }
+}
+
+/// Keys and values of a column in key order
+pub type KvIterator<'a> = Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a>;
+
+pub trait KvStore: Send + Sync {
+    fn put(&self, column: &str, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    fn get(&self, column: &str, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    fn delete(&self, column: &str, key: Vec<u8>) -> Result<()>;
+
+    fn key_exist(&self, column: &str, key: Vec<u8>) -> Result<bool> {
+        Ok(self.get(column, key)?.is_some())
+    }
+
+    fn iterator(&self, column: &str, iterator_mode: IteratorMode) -> Result<KvIterator<'_>>;
+
+    /// Keys and values of a column from `key` on
+    fn iterator_from(&self, column: &str, key: Vec<u8>) -> Result<KvIterator<'_>>;
+
+    /// Make every write in `batch`, or none of them
+    fn write(&self, batch: KvBatch) -> Result<()>;
+
+    /// A copy of the store at `path`, which mustn't exist, consistent as
+    /// far as the backend allows
+    fn checkpoint(&self, path: &Path) -> Result<()>;
+
+    /// Write out whatever is only in memory, so the next open needn't
+    /// replay a log
+    fn flush(&self) -> Result<()>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KvBackend {
+    Rocksdb,
+    Sled,
+}
+
+impl KvBackend {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KvBackend::Rocksdb => "rocksdb",
+            KvBackend::Sled => "sled",
+        }
+    }
+
+    /// Open the store at `path`, creating it if it doesn't exist. A store
+    /// made by the other backend can't be opened.
+    pub fn open(&self, path: &Path) -> Result<Arc<dyn KvStore>> {
+        self.open_with(path, &RocksdbOptions::default())
+    }
+
+    /// Like open, with rocksdb tuned by `options`
+    pub fn open_with(&self, path: &Path, options: &RocksdbOptions) -> Result<Arc<dyn KvStore>> {
+        match self {
+            #[cfg(feature = "rocksdb")]
+            KvBackend::Rocksdb => {
+                let store: Arc<dyn KvStore> = super::Rocks::with_options(path, options)?;
+                Ok(store)
+            }
+            #[cfg(feature = "sled")]
+            KvBackend::Sled => {
+                let store: Arc<dyn KvStore> = super::SledStore::new(path)?;
+                Ok(store)
+            }
+            #[allow(unreachable_patterns)]
+            _ => Err(Error::ServicesError(
+                "this build doesn't have the key-value store feature asked for",
+            )),
+        }
+    }
+}
+
+impl Default for KvBackend {
+    #[cfg(feature = "rocksdb")]
+    fn default() -> Self {
+        KvBackend::Rocksdb
+    }
+
+    #[cfg(not(feature = "rocksdb"))]
+    fn default() -> Self {
+        KvBackend::Sled
+    }
+}
+
+impl FromStr for KvBackend {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "rocksdb" => Ok(KvBackend::Rocksdb),
+            "sled" => Ok(KvBackend::Sled),
+            _ => Err(Error::ParseFailed("Unknown key-value store")),
+        }
+    }
+}
+
+/// Tuning of the rocksdb backend, which sled ignores. Options that aren't
+/// set keep the rocksdb defaults.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RocksdbOptions {
+    /// Options of every column
+    #[serde(flatten)]
+    pub defaults: RocksColumnOptions,
+    /// Options of some columns by name, over the ones of every column
+    pub columns: HashMap<String, RocksColumnOptions>,
+}
+
+impl RocksdbOptions {
+    /// The options of the column named `name`
+    pub fn column(&self, name: &str) -> RocksColumnOptions {
+        let defaults = &self.defaults;
+        match self.columns.get(name) {
+            Some(column) => RocksColumnOptions {
+                block_cache_mb: column.block_cache_mb.or(defaults.block_cache_mb),
+                write_buffer_mb: column.write_buffer_mb.or(defaults.write_buffer_mb),
+                compression: column.compression.or(defaults.compression),
+                compaction: column.compaction.or(defaults.compaction),
+            },
+            None => defaults.clone(),
+        }
+    }
+}
+
+/// Tuning of one rocksdb column family
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RocksColumnOptions {
+    /// Size of the block cache, in MiB
+    pub block_cache_mb: Option<usize>,
+    /// Size of the memtable, in MiB. Bigger ones take writes in fewer,
+    /// larger flushes.
+    pub write_buffer_mb: Option<usize>,
+    pub compression: Option<RocksCompression>,
+    pub compaction: Option<RocksCompaction>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RocksCompression {
+    None,
+    Lz4,
+    Zstd,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RocksCompaction {
+    Level,
+    Universal,
+    Fifo,
+}
+
+pub struct KvColumn<T: Column> {
+    store: Arc<dyn KvStore>,
+    column: PhantomData<T>,
+}
+
+impl<T: Column> KvColumn<T> {
+    pub fn new(store: Arc<dyn KvStore>) -> KvColumn<T> {
+        KvColumn {
+            store,
+            column: PhantomData,
+        }
+    }
+
+    pub fn put(&self, key: impl Encodable, value: impl Encodable) -> Result<()> {
+        self.store.put(T::NAME, serialize(&key), serialize(&value))
+    }
+
+    pub fn get(&self, key: impl Encodable) -> Result<Option<Vec<u8>>> {
+        self.store.get(T::NAME, serialize(&key))
+    }
+
+    /// The value at `key`, which is already serialized
+    pub fn get_value_deserialized<D: Decodable>(&self, key: Vec<u8>) -> Result<Option<D>> {
+        let value = self.store.get(T::NAME, key)?;
+        match value {
+            Some(v) => {
+                let v: D = deserialize(&v)?;
+                Ok(Some(v))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn key_exist(&self, key: impl Encodable) -> Result<bool> {
+        self.store.key_exist(T::NAME, serialize(&key))
+    }
+
+    pub fn iterator(&self, iterator_mode: IteratorMode) -> Result<KvIterator<'_>> {
+        self.store.iterator(T::NAME, iterator_mode)
+    }
+
+    /// Every key and value in the column. They come in the order of the
+    /// serialized keys, which for integers isn't the numeric one.
+    pub fn iter<K: Decodable, V: Decodable>(
+        &self,
+    ) -> Result<impl Iterator<Item = Result<(K, V)>> + '_> {
+        Ok(self.iterator(IteratorMode::Start)?.map(decode_entry))
+    }
+
+    /// The keys and values from `key` on, `key` included if stored
+    pub fn iter_from<K: Decodable, V: Decodable>(
+        &self,
+        key: impl Encodable,
+    ) -> Result<impl Iterator<Item = Result<(K, V)>> + '_> {
+        let iter = self.store.iterator_from(T::NAME, serialize(&key))?;
+        Ok(iter.map(decode_entry))
+    }
+
+    /// The keys and values whose serialized key starts with `prefix`
+    pub fn prefix_iter<K: Decodable, V: Decodable>(
+        &self,
+        prefix: &[u8],
+    ) -> Result<impl Iterator<Item = Result<(K, V)>> + '_> {
+        let prefix = prefix.to_vec();
+        let iter = self.store.iterator_from(T::NAME, prefix.clone())?;
+        Ok(iter
+            .take_while(move |(key, _)| key.starts_with(&prefix))
+            .map(decode_entry))
+    }
+
+    pub fn delete(&self, key: impl Encodable) -> Result<()> {
+        self.store.delete(T::NAME, serialize(&key))
+    }
+
+    /// Add a put to `batch` instead of making it now
+    pub fn put_batch(&self, batch: &mut KvBatch, key: impl Encodable, value: impl Encodable) {
+        batch
+            .ops
+            .push((T::NAME, serialize(&key), Some(serialize(&value))));
+    }
+
+    /// Add a delete to `batch` instead of making it now
+    pub fn delete_batch(&self, batch: &mut KvBatch, key: impl Encodable) {
+        batch.ops.push((T::NAME, serialize(&key), None));
+    }
+
+    /// Make the writes in `batch`, which may be to other columns of the
+    /// same store, all at once
+    pub fn write(&self, batch: KvBatch) -> Result<()> {
+        self.store.write(batch)
+    }
+
+    pub fn clear(&self) -> Result<()> {
+        let keys: Vec<Vec<u8>> = self
+            .iterator(IteratorMode::Start)?
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            self.store.delete(T::NAME, key)?;
+        }
+        Ok(())
+    }
+}
+
+fn decode_entry<K: Decodable, V: Decodable>((key, value): (Vec<u8>, Vec<u8>)) -> Result<(K, V)> {
+    Ok((deserialize(&key)?, deserialize(&value)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_store(backend: KvBackend) -> Result<()> {
+        let path = std::env::temp_dir().join(format!("darkfi_test_kv_{}", backend.as_str()));
+        let _ = std::fs::remove_dir_all(&path);
+        let store = backend.open(&path)?;
+
+        let column = KvColumn::<columns::SyncState>::new(store.clone());
+        for index in &[3u64, 1, 2] {
+            column.put(*index, index * 10)?;
+        }
+        assert_eq!(
+            column.get_value_deserialized::<u64>(serialize(&2u64))?,
+            Some(20)
+        );
+        assert!(column.key_exist(3u64)?);
+
+        // Columns are kept apart
+        let other = KvColumn::<columns::Nullifiers>::new(store);
+        assert!(!other.key_exist(3u64)?);
+
+        let (last, _) = column.iterator(IteratorMode::End)?.next().unwrap();
+        assert_eq!(deserialize::<u64>(&last)?, 3);
+
+        let entries: Vec<(u64, u64)> = column.iter()?.collect::<Result<_>>()?;
+        assert_eq!(entries, vec![(1, 10), (2, 20), (3, 30)]);
+        let entries: Vec<(u64, u64)> = column.iter_from(2u64)?.collect::<Result<_>>()?;
+        assert_eq!(entries, vec![(2, 20), (3, 30)]);
+
+        // Keys sharing their first serialized byte
+        let prefixed = KvColumn::<columns::SyncMetrics>::new(store.clone());
+        for key in &[0x0102u16, 0x0202, 0x0101, 0x0103] {
+            prefixed.put(*key, 0u8)?;
+        }
+        let keys: Vec<u16> = prefixed
+            .prefix_iter::<u16, u8>(&[0x02])?
+            .map(|entry| entry.map(|(key, _)| key))
+            .collect::<Result<_>>()?;
+        assert_eq!(keys, vec![0x0102, 0x0202]);
+
+        column.delete(3u64)?;
+        assert!(!column.key_exist(3u64)?);
+
+        // A batch lands in every column it touches
+        let mut batch = KvBatch::new();
+        column.put_batch(&mut batch, 4u64, 40u64);
+        column.delete_batch(&mut batch, 1u64);
+        other.put_batch(&mut batch, 4u64, 40u64);
+        assert!(!column.key_exist(4u64)?);
+        column.write(batch)?;
+        assert!(column.key_exist(4u64)? && other.key_exist(4u64)?);
+        assert!(!column.key_exist(1u64)?);
+
+        column.clear()?;
+        assert!(column.iterator(IteratorMode::Start)?.next().is_none());
+
+        std::fs::remove_dir_all(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_rocksdb_options() {
+        let options: RocksdbOptions = toml::from_str(
+            r#"
+            write_buffer_mb = 64
+            compression = "lz4"
+
+            [columns.nullifiers]
+            write_buffer_mb = 128
+            compaction = "universal"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            options.column(columns::Nullifiers::NAME),
+            RocksColumnOptions {
+                block_cache_mb: None,
+                write_buffer_mb: Some(128),
+                compression: Some(RocksCompression::Lz4),
+                compaction: Some(RocksCompaction::Universal),
+            }
+        );
+        assert_eq!(options.column(columns::Slabs::NAME).write_buffer_mb, Some(64));
+        assert!(toml::from_str::<RocksdbOptions>("compression = \"snappy\"").is_err());
+    }
+
+    #[cfg(feature = "rocksdb")]
+    #[test]
+    fn test_rocksdb_store() -> Result<()> {
+        check_store(KvBackend::Rocksdb)
+    }
+
+    #[cfg(feature = "sled")]
+    #[test]
+    fn test_sled_store() -> Result<()> {
+        check_store(KvBackend::Sled)
+    }
+}
@@ -0,0 +1,195 @@
+use std::io;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use super::kv::{columns, KvColumn};
+use crate::serial::{deserialize, Decodable, Encodable};
+use crate::Result;
+
+/// Number of slabs to keep metrics for
+pub const METRICS_HISTORY_SIZE: u64 = 1000;
+
+/// Timing of a single applied slab
+#[derive(Clone, Debug)]
+pub struct SlabMetric {
+    pub index: u64,
+    /// Time spent verifying and applying the slab
+    pub apply_micros: u64,
+    /// Time between the slab arriving and it being applied
+    pub lag_micros: u64,
+    /// Unix timestamp at which the slab was applied
+    pub timestamp: u64,
+}
+
+impl SlabMetric {
+    pub fn new(index: u64, apply: Duration, lag: Duration, timestamp: u64) -> Self {
+        Self {
+            index,
+            apply_micros: apply.as_micros() as u64,
+            lag_micros: lag.as_micros() as u64,
+            timestamp,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "index": self.index,
+            "apply_micros": self.apply_micros,
+            "lag_micros": self.lag_micros,
+            "timestamp": self.timestamp,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SyncSummary {
+    pub count: u64,
+    pub last_index: u64,
+    pub last_timestamp: u64,
+    pub avg_apply_micros: u64,
+    pub max_apply_micros: u64,
+    pub avg_lag_micros: u64,
+    pub max_lag_micros: u64,
+}
+
+impl SyncSummary {
+    pub fn from_metrics(metrics: &[SlabMetric]) -> Self {
+        let mut summary = Self::default();
+        if metrics.is_empty() {
+            return summary;
+        }
+
+        let mut total_apply = 0u128;
+        let mut total_lag = 0u128;
+        for metric in metrics {
+            total_apply += metric.apply_micros as u128;
+            total_lag += metric.lag_micros as u128;
+            summary.max_apply_micros = summary.max_apply_micros.max(metric.apply_micros);
+            summary.max_lag_micros = summary.max_lag_micros.max(metric.lag_micros);
+            if metric.index > summary.last_index {
+                summary.last_index = metric.index;
+                summary.last_timestamp = metric.timestamp;
+            }
+        }
+
+        summary.count = metrics.len() as u64;
+        summary.avg_apply_micros = (total_apply / metrics.len() as u128) as u64;
+        summary.avg_lag_micros = (total_lag / metrics.len() as u128) as u64;
+        summary
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "count": self.count,
+            "last_index": self.last_index,
+            "last_timestamp": self.last_timestamp,
+            "avg_apply_micros": self.avg_apply_micros,
+            "max_apply_micros": self.max_apply_micros,
+            "avg_lag_micros": self.avg_lag_micros,
+            "max_lag_micros": self.max_lag_micros,
+        })
+    }
+}
+
+/// Bounded history of slab apply timings, keyed by slab index
+pub struct SyncMetrics {
+    rocks: KvColumn<columns::SyncMetrics>,
+}
+
+impl SyncMetrics {
+    pub fn new(rocks: KvColumn<columns::SyncMetrics>) -> Self {
+        SyncMetrics { rocks }
+    }
+
+    pub fn record(&self, metric: SlabMetric) -> Result<()> {
+        let index = metric.index;
+        self.rocks.put(index, metric)?;
+
+        // Drop the entry that just fell out of the window
+        if index > METRICS_HISTORY_SIZE {
+            self.rocks.delete(index - METRICS_HISTORY_SIZE)?;
+        }
+        Ok(())
+    }
+
+    /// Metrics for up to `count` slabs ending at `last_index`
+    pub fn recent(&self, last_index: u64, count: u64) -> Result<Vec<SlabMetric>> {
+        let count = count.min(METRICS_HISTORY_SIZE);
+        let first = last_index.saturating_sub(count) + 1;
+
+        let mut metrics = vec![];
+        for index in first..(last_index + 1) {
+            if let Some(metric) = self.rocks.get(index)? {
+                metrics.push(deserialize(&metric)?);
+            }
+        }
+        Ok(metrics)
+    }
+
+    pub fn clear(&self) -> Result<()> {
+        self.rocks.clear()
+    }
+}
+
+impl Encodable for SlabMetric {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.index.encode(&mut s)?;
+        len += self.apply_micros.encode(&mut s)?;
+        len += self.lag_micros.encode(&mut s)?;
+        len += self.timestamp.encode(s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for SlabMetric {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self {
+            index: Decodable::decode(&mut d)?,
+            apply_micros: Decodable::decode(&mut d)?,
+            lag_micros: Decodable::decode(&mut d)?,
+            timestamp: Decodable::decode(d)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sync_summary() {
+        let metrics = vec![
+            SlabMetric::new(
+                1,
+                Duration::from_micros(100),
+                Duration::from_micros(300),
+                10,
+            ),
+            SlabMetric::new(
+                3,
+                Duration::from_micros(300),
+                Duration::from_micros(500),
+                30,
+            ),
+            SlabMetric::new(
+                2,
+                Duration::from_micros(200),
+                Duration::from_micros(1000),
+                20,
+            ),
+        ];
+
+        let summary = SyncSummary::from_metrics(&metrics);
+        assert_eq!(summary.count, 3);
+        assert_eq!(summary.last_index, 3);
+        assert_eq!(summary.last_timestamp, 30);
+        assert_eq!(summary.avg_apply_micros, 200);
+        assert_eq!(summary.max_apply_micros, 300);
+        assert_eq!(summary.avg_lag_micros, 600);
+        assert_eq!(summary.max_lag_micros, 1000);
+
+        assert_eq!(SyncSummary::from_metrics(&[]).count, 0);
+    }
+}
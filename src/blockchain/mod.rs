@@ -1,7 +1,29 @@
+pub mod integrity;
+pub mod kv;
+pub mod metrics;
+pub mod pending;
+#[cfg(feature = "rocksdb")]
 pub mod rocks;
+pub mod sequencer;
 pub mod slab;
 pub mod slabstore;
+#[cfg(feature = "sled")]
+pub mod sled_store;
+pub mod snapshot;
+pub mod state_export;
+pub mod undo;
 
-pub use rocks::{Rocks, RocksColumn};
-pub use slab::Slab;
-pub use slabstore::SlabStore;
+pub use integrity::IntegrityReport;
+pub use kv::{KvBackend, KvColumn, KvStore, RocksdbOptions};
+pub use metrics::{SlabMetric, SyncMetrics, SyncSummary};
+pub use pending::{PendingTx, PendingTxs};
+#[cfg(feature = "rocksdb")]
+pub use rocks::Rocks;
+pub use sequencer::SlabSequencer;
+pub use slab::{Slab, SlabHash};
+pub use slabstore::{SlabShard, SlabStore};
+#[cfg(feature = "sled")]
+pub use sled_store::SledStore;
+pub use snapshot::{SnapshotSchedule, SnapshotStatus, StateSnapshots};
+pub use state_export::StateExport;
+pub use undo::UndoRecord;
@@ -0,0 +1,207 @@
+//! Our own transactions that were published but whose slab hasn't come
+//! back from the gateway yet. A gateway may drop a slab it took, leaving
+//! the coins it spends looking stuck, so the ones still pending are
+//! published again now and then until their slab is seen.
+
+use std::io;
+
+use serde_json::{json, Value};
+
+use super::kv::{columns, KvColumn};
+use super::slab::{Slab, SlabHash};
+use crate::serial::{deserialize, Decodable, Encodable, VarInt};
+use crate::Result;
+
+/// Seconds after which a pending transaction is published again
+pub const REBROADCAST_INTERVAL: u64 = 300;
+
+/// Seconds between checks for pending transactions to publish again
+pub const REBROADCAST_CHECK_INTERVAL: u32 = 60;
+
+/// Seconds after which a transaction that never came back is given up on
+pub const PENDING_EXPIRY: u64 = 24 * 60 * 60;
+
+/// The id of a transaction, whatever index its slab ends up at: the hash
+/// of its slab before an index is set
+pub fn pending_id(payload: &[u8]) -> SlabHash {
+    Slab::new(payload.to_vec()).hash()
+}
+
+#[derive(Clone, Debug)]
+pub struct PendingTx {
+    /// The encoded transaction
+    pub payload: Vec<u8>,
+    pub fee: u64,
+    /// History entries to point at the slab once it's seen
+    pub tx_ids: Vec<i64>,
+    pub published: u64,
+    pub last_broadcast: u64,
+    pub broadcasts: u32,
+}
+
+impl PendingTx {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "id": hex::encode(pending_id(&self.payload)),
+            "fee": self.fee,
+            "published": self.published,
+            "last_broadcast": self.last_broadcast,
+            "broadcasts": self.broadcasts,
+        })
+    }
+}
+
+pub struct PendingTxs {
+    rocks: KvColumn<columns::Pending>,
+}
+
+impl PendingTxs {
+    pub fn new(rocks: KvColumn<columns::Pending>) -> Self {
+        PendingTxs { rocks }
+    }
+
+    /// Track a transaction just published at `now`
+    pub fn add(&self, payload: Vec<u8>, fee: u64, tx_ids: Vec<i64>, now: u64) -> Result<()> {
+        let tx = PendingTx {
+            payload,
+            fee,
+            tx_ids,
+            published: now,
+            last_broadcast: now,
+            broadcasts: 1,
+        };
+        self.rocks.put(pending_id(&tx.payload), tx)
+    }
+
+    /// Stop tracking the transaction in `slab`, returning it if it was
+    /// one of ours
+    pub fn confirm(&self, slab: &Slab) -> Result<Option<PendingTx>> {
+        let id = pending_id(&slab.get_payload());
+        let tx = match self.rocks.get(id)? {
+            Some(tx) => deserialize(&tx)?,
+            None => return Ok(None),
+        };
+        self.rocks.delete(id)?;
+        Ok(Some(tx))
+    }
+
+    pub fn list(&self) -> Result<Vec<PendingTx>> {
+        let mut txs: Vec<PendingTx> = self
+            .rocks
+            .iter::<SlabHash, PendingTx>()?
+            .map(|entry| entry.map(|(_, tx)| tx))
+            .collect::<Result<_>>()?;
+        txs.sort_by_key(|tx| tx.published);
+        Ok(txs)
+    }
+
+    /// The transactions to publish again at `now`. The ones pending for
+    /// longer than PENDING_EXPIRY are dropped and returned apart.
+    pub fn due(&self, now: u64) -> Result<(Vec<PendingTx>, Vec<PendingTx>)> {
+        let mut due = vec![];
+        let mut expired = vec![];
+        for tx in self.list()? {
+            if now >= tx.published.saturating_add(PENDING_EXPIRY) {
+                self.rocks.delete(pending_id(&tx.payload))?;
+                expired.push(tx);
+            } else if now >= tx.last_broadcast.saturating_add(REBROADCAST_INTERVAL) {
+                due.push(tx);
+            }
+        }
+        Ok((due, expired))
+    }
+
+    /// Record that `tx` was published again at `now`, unless its slab was
+    /// seen meanwhile
+    pub fn rebroadcast(&self, tx: &PendingTx, now: u64) -> Result<()> {
+        let id = pending_id(&tx.payload);
+        if !self.rocks.key_exist(id)? {
+            return Ok(());
+        }
+        let mut tx = tx.clone();
+        tx.last_broadcast = now;
+        tx.broadcasts += 1;
+        self.rocks.put(id, tx)
+    }
+}
+
+impl Encodable for PendingTx {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.payload.encode(&mut s)?;
+        len += self.fee.encode(&mut s)?;
+        len += VarInt(self.tx_ids.len() as u64).encode(&mut s)?;
+        for tx_id in self.tx_ids.iter() {
+            len += tx_id.encode(&mut s)?;
+        }
+        len += self.published.encode(&mut s)?;
+        len += self.last_broadcast.encode(&mut s)?;
+        len += self.broadcasts.encode(s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for PendingTx {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        let payload = Decodable::decode(&mut d)?;
+        let fee = Decodable::decode(&mut d)?;
+        let count: VarInt = Decodable::decode(&mut d)?;
+        let mut tx_ids = vec![];
+        for _ in 0..count.0 {
+            tx_ids.push(Decodable::decode(&mut d)?);
+        }
+        Ok(Self {
+            payload,
+            fee,
+            tx_ids,
+            published: Decodable::decode(&mut d)?,
+            last_broadcast: Decodable::decode(&mut d)?,
+            broadcasts: Decodable::decode(d)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::KvBackend;
+
+    #[test]
+    fn test_pending_txs() -> Result<()> {
+        let path = std::env::temp_dir().join("darkfi_test_pending_txs");
+        let _ = std::fs::remove_dir_all(&path);
+        let pending = PendingTxs::new(KvColumn::new(KvBackend::default().open(&path)?));
+
+        pending.add(vec![1, 2, 3], 10, vec![7, 8], 1000)?;
+        pending.add(vec![4, 5, 6], 10, vec![], 2000)?;
+
+        // Nothing is due before the interval is up
+        let (due, expired) = pending.due(1000 + REBROADCAST_INTERVAL - 1)?;
+        assert!(due.is_empty() && expired.is_empty());
+
+        let (due, _) = pending.due(1000 + REBROADCAST_INTERVAL)?;
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].tx_ids, vec![7, 8]);
+        pending.rebroadcast(&due[0], 1000 + REBROADCAST_INTERVAL)?;
+        assert_eq!(pending.list()?[0].broadcasts, 2);
+
+        // Its slab comes back at whatever index
+        let mut slab = Slab::new(vec![1, 2, 3]);
+        slab.set_index(42);
+        assert_eq!(pending.confirm(&slab)?.map(|tx| tx.fee), Some(10));
+        assert!(pending.confirm(&slab)?.is_none());
+
+        // A rebroadcast racing the confirmation doesn't bring it back
+        pending.rebroadcast(&due[0], 1000 + 2 * REBROADCAST_INTERVAL)?;
+        assert_eq!(pending.list()?.len(), 1);
+
+        let (due, expired) = pending.due(2000 + PENDING_EXPIRY)?;
+        assert!(due.is_empty());
+        assert_eq!(expired.len(), 1);
+        assert!(pending.list()?.is_empty());
+
+        drop(pending);
+        std::fs::remove_dir_all(&path)?;
+        Ok(())
+    }
+}
@@ -1,60 +1,45 @@
 use async_std::sync::Arc;
-use std::marker::PhantomData;
 use std::path::Path;
 
-use rocksdb::{ColumnFamily, ColumnFamilyDescriptor, Options, DB};
+use rocksdb::{
+    BlockBasedOptions, Cache, ColumnFamily, ColumnFamilyDescriptor, DBCompactionStyle,
+    DBCompressionType, Options, DB,
+};
 
-use crate::serial::{deserialize, serialize, Decodable, Encodable};
+use super::kv::{
+    IteratorMode, KvBatch, KvIterator, KvStore, RocksColumnOptions, RocksCompaction,
+    RocksCompression, RocksdbOptions, COLUMNS,
+};
 use crate::{Error, Result};
 
-pub enum IteratorMode {
-    Start,
-    End,
-}
-
-pub trait Column {
-    const NAME: &'static str;
-}
-
-pub mod columns {
-    pub struct Slabs;
-    pub struct Nullifiers;
-    pub struct MerkleRoots;
-}
-
-impl Column for columns::Slabs {
-    const NAME: &'static str = "slabs";
-}
-
-impl Column for columns::Nullifiers {
-    const NAME: &'static str = "nullifiers";
-}
-
-impl Column for columns::MerkleRoots {
-    const NAME: &'static str = "merkleroots";
-}
-
 pub struct Rocks {
     db: DB,
 }
 
 impl Rocks {
     pub fn new(path: &Path) -> Result<Arc<Self>> {
-        // column family options
-        let cf_opts = Options::default();
+        Self::with_options(path, &RocksdbOptions::default())
+    }
+
+    pub fn with_options(path: &Path, options: &RocksdbOptions) -> Result<Arc<Self>> {
+        if let Some(name) = options
+            .columns
+            .keys()
+            .find(|name| !COLUMNS.contains(&name.as_str()))
+        {
+            return Err(Error::RocksdbError(format!("unknown column {}", name)));
+        }
 
         // default column family
         let default_cf =
-            ColumnFamilyDescriptor::new(rocksdb::DEFAULT_COLUMN_FAMILY_NAME, cf_opts.clone());
-        // slabs column family
-        let slab_cf = ColumnFamilyDescriptor::new(columns::Slabs::NAME, cf_opts.clone());
-        // nullifiers column family
-        let nullifiers_cf = ColumnFamilyDescriptor::new(columns::Nullifiers::NAME, cf_opts.clone());
-        // merkleroots column family
-        let merkleroots_cf = ColumnFamilyDescriptor::new(columns::MerkleRoots::NAME, cf_opts);
+            ColumnFamilyDescriptor::new(rocksdb::DEFAULT_COLUMN_FAMILY_NAME, Options::default());
 
         // column families
-        let cfs = vec![default_cf, slab_cf, nullifiers_cf, merkleroots_cf];
+        let mut cfs = vec![default_cf];
+        for name in COLUMNS {
+            let cf_opts = Self::column_options(&options.column(name))?;
+            cfs.push(ColumnFamilyDescriptor::new(*name, cf_opts));
+        }
 
         // database options
         let mut opt = Options::default();
@@ -67,96 +52,105 @@ impl Rocks {
         Ok(Arc::new(Self { db }))
     }
 
-    pub fn cf_handle<C>(&self) -> Result<&ColumnFamily>
-    where
-        C: Column,
-    {
+    fn column_options(tuning: &RocksColumnOptions) -> Result<Options> {
+        let mut opts = Options::default();
+        if let Some(mb) = tuning.block_cache_mb {
+            let mut block_opts = BlockBasedOptions::default();
+            block_opts.set_block_cache(&Cache::new_lru_cache(mb * 1024 * 1024)?);
+            opts.set_block_based_table_factory(&block_opts);
+        }
+        if let Some(mb) = tuning.write_buffer_mb {
+            opts.set_write_buffer_size(mb * 1024 * 1024);
+        }
+        if let Some(compression) = tuning.compression {
+            opts.set_compression_type(match compression {
+                RocksCompression::None => DBCompressionType::None,
+                RocksCompression::Lz4 => DBCompressionType::Lz4,
+                RocksCompression::Zstd => DBCompressionType::Zstd,
+            });
+        }
+        if let Some(compaction) = tuning.compaction {
+            opts.set_compaction_style(match compaction {
+                RocksCompaction::Level => DBCompactionStyle::Level,
+                RocksCompaction::Universal => DBCompactionStyle::Universal,
+                RocksCompaction::Fifo => DBCompactionStyle::Fifo,
+            });
+        }
+        Ok(opts)
+    }
+
+    fn cf_handle(&self, column: &str) -> Result<&ColumnFamily> {
         self.db
-            .cf_handle(C::NAME)
+            .cf_handle(column)
             .ok_or_else(|| Error::RocksdbError("unknown column".to_string()))
     }
 
-    pub fn put_cf(&self, cf: &ColumnFamily, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
-        self.db.put_cf(cf, key, value)?;
+    pub fn destroy(path: &Path) -> Result<()> {
+        DB::destroy(&Options::default(), path)?;
+        Ok(())
+    }
+}
+
+impl KvStore for Rocks {
+    fn put(&self, column: &str, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.db.put_cf(self.cf_handle(column)?, key, value)?;
         Ok(())
     }
 
-    pub fn get_cf(&self, cf: &ColumnFamily, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
-        let val = self.db.get_cf(cf, key)?;
+    fn get(&self, column: &str, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let val = self.db.get_cf(self.cf_handle(column)?, key)?;
         Ok(val)
     }
 
-    pub fn key_exist_cf(&self, cf: &ColumnFamily, key: Vec<u8>) -> Result<bool> {
-        let val = self.db.get_cf(cf, key)?;
-        Ok(val.is_some())
+    fn delete(&self, column: &str, key: Vec<u8>) -> Result<()> {
+        self.db.delete_cf(self.cf_handle(column)?, key)?;
+        Ok(())
     }
 
-    pub fn iterator(&self, cf: &ColumnFamily, iterator_mode: IteratorMode) -> rocksdb::DBIterator {
+    fn iterator(&self, column: &str, iterator_mode: IteratorMode) -> Result<KvIterator<'_>> {
         let iterator_mode = match iterator_mode {
             IteratorMode::Start => rocksdb::IteratorMode::Start,
             IteratorMode::End => rocksdb::IteratorMode::End,
         };
-        self.db.iterator_cf(cf, iterator_mode)
+        let iter = self
+            .db
+            .iterator_cf(self.cf_handle(column)?, iterator_mode)
+            .map(|(key, value)| (key.into_vec(), value.into_vec()));
+        Ok(Box::new(iter))
     }
 
-    pub fn destroy(path: &Path) -> Result<()> {
-        DB::destroy(&Options::default(), path)?;
-        Ok(())
+    fn iterator_from(&self, column: &str, key: Vec<u8>) -> Result<KvIterator<'_>> {
+        let iterator_mode = rocksdb::IteratorMode::From(&key, rocksdb::Direction::Forward);
+        let iter = self
+            .db
+            .iterator_cf(self.cf_handle(column)?, iterator_mode)
+            .map(|(key, value)| (key.into_vec(), value.into_vec()));
+        Ok(Box::new(iter))
     }
-}
 
-pub struct RocksColumn<T: Column> {
-    rocks: Arc<Rocks>,
-    column: PhantomData<T>,
-}
-
-impl<T: Column> RocksColumn<T> {
-    pub fn new(rocks: Arc<Rocks>) -> RocksColumn<T> {
-        RocksColumn {
-            rocks,
-            column: PhantomData,
+    fn write(&self, batch: KvBatch) -> Result<()> {
+        let mut write_batch = rocksdb::WriteBatch::default();
+        for (column, key, value) in batch.ops() {
+            let cf = self.cf_handle(column)?;
+            match value {
+                Some(value) => write_batch.put_cf(cf, key, value),
+                None => write_batch.delete_cf(cf, key),
+            }
         }
-    }
-    fn cf_handle(&self) -> Result<&ColumnFamily> {
-        self.rocks.cf_handle::<T>()
-    }
-
-    pub fn put(&self, key: impl Encodable, value: impl Encodable) -> Result<()> {
-        let key = serialize(&key);
-        let value = serialize(&value);
-        let cf = self.cf_handle()?;
-        self.rocks.put_cf(cf, key, value)?;
+        self.db.write(write_batch)?;
         Ok(())
     }
 
-    pub fn get(&self, key: impl Encodable) -> Result<Option<Vec<u8>>> {
-        let key = serialize(&key);
-        let cf = self.cf_handle()?;
-        let val = self.rocks.get_cf(cf, key)?;
-        Ok(val)
+    /// Files that don't change are hard linked rather than copied
+    fn checkpoint(&self, path: &Path) -> Result<()> {
+        rocksdb::checkpoint::Checkpoint::new(&self.db)?.create_checkpoint(path)?;
+        Ok(())
     }
 
-    pub fn get_value_deserialized<D: Decodable>(&self, key: Vec<u8>) -> Result<Option<D>> {
-        let value = self.get(key)?;
-        match value {
-            Some(v) => {
-                let v: D = deserialize(&v)?;
-                Ok(Some(v))
-            }
-            None => Ok(None),
+    fn flush(&self) -> Result<()> {
+        for column in COLUMNS {
+            self.db.flush_cf(self.cf_handle(column)?)?;
         }
-    }
-
-    pub fn key_exist(&self, key: impl Encodable) -> Result<bool> {
-        let key = serialize(&key);
-        let cf = self.cf_handle()?;
-        let val = self.rocks.key_exist_cf(cf, key)?;
-        Ok(val)
-    }
-
-    pub fn iterator(&self, iterator_mode: IteratorMode) -> Result<rocksdb::DBIterator> {
-        let cf = self.cf_handle()?;
-        let iter = self.rocks.iterator(cf, iterator_mode);
-        Ok(iter)
+        Ok(())
     }
 }
@@ -0,0 +1,186 @@
+//! Puts slabs back in the order the gateway stored them before they are
+//! applied. The slab index is the sequence number: the gateway assigns
+//! them one after the other as it stores slabs, and it's part of the
+//! canonical slab hash, so slabs can't carry a second one. Deliveries from
+//! the subscriber and from syncing race each other, so a slab can arrive
+//! ahead of the one before it. It's held back until the gap is filled.
+
+use std::collections::BTreeMap;
+
+use log::{debug, warn};
+
+use super::{Slab, SlabStore};
+use crate::serial::serialize;
+use crate::Result;
+
+/// How many slabs are held back waiting for a gap to be filled before
+/// the oldest ones are given up on
+pub const MAX_PENDING_SLABS: usize = 1024;
+
+pub struct SlabSequencer {
+    // Index of the last slab handed out in sequence
+    high_water: u64,
+    pending: BTreeMap<u64, Slab>,
+}
+
+impl SlabSequencer {
+    /// Start after `high_water`, the last slab index already applied
+    pub fn new(high_water: u64) -> Self {
+        Self {
+            high_water,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    pub fn high_water(&self) -> u64 {
+        self.high_water
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// The slab the ones held back are waiting for, if any
+    pub fn missing(&self) -> Option<u64> {
+        if self.pending.is_empty() {
+            None
+        } else {
+            Some(self.high_water + 1)
+        }
+    }
+
+    /// Move the high-water mark up to slabs applied elsewhere, e.g. by a
+    /// rescan. Slabs held back at or below it are dropped.
+    pub fn advance_to(&mut self, high_water: u64) {
+        if high_water <= self.high_water {
+            return;
+        }
+        self.high_water = high_water;
+        self.pending = self.pending.split_off(&(high_water + 1));
+    }
+
+    /// Take a delivered slab and return the slabs now in sequence, in
+    /// order. Slabs at or below the high-water mark were already handed
+    /// out and are dropped.
+    pub fn push(&mut self, slab: Slab) -> Vec<Slab> {
+        let index = slab.get_index();
+        if index <= self.high_water {
+            debug!(target: "SEQUENCER", "Slab {} is already in sequence", index);
+            return vec![];
+        }
+        if index > self.high_water + 1 {
+            debug!(
+                target: "SEQUENCER",
+                "Holding back slab {} until slab {} arrives",
+                index,
+                self.high_water + 1
+            );
+        }
+        self.pending.insert(index, slab);
+
+        if self.pending.len() > MAX_PENDING_SLABS {
+            // The gap isn't getting filled, skip over it so the node doesn't
+            // stall. The skipped slabs are picked up by the next rescan.
+            let next = *self.pending.keys().next().unwrap();
+            warn!(
+                target: "SEQUENCER",
+                "Slabs {} to {} never arrived, skipping them",
+                self.high_water + 1,
+                next - 1
+            );
+            self.high_water = next - 1;
+        }
+
+        self.release()
+    }
+
+    /// Like push, for a batch of slabs
+    pub fn push_all(&mut self, slabs: Vec<Slab>) -> Vec<Slab> {
+        let mut ready = vec![];
+        for slab in slabs {
+            ready.extend(self.push(slab));
+        }
+        ready
+    }
+
+    /// Fill gaps from the slabstore. Slabs are stored before they're
+    /// delivered, so one that's late is usually there already.
+    pub fn fill_from(&mut self, slabstore: &SlabStore) -> Result<Vec<Slab>> {
+        let mut ready = vec![];
+        while let Some(missing) = self.missing() {
+            match slabstore.get_value_deserialized(serialize(&missing))? {
+                Some(slab) => ready.extend(self.push(slab)),
+                None => break,
+            }
+        }
+        Ok(ready)
+    }
+
+    fn release(&mut self) -> Vec<Slab> {
+        let mut ready = vec![];
+        while let Some(slab) = self.pending.remove(&(self.high_water + 1)) {
+            self.high_water += 1;
+            ready.push(slab);
+        }
+        ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slab(index: u64) -> Slab {
+        let mut slab = Slab::new(vec![index as u8]);
+        slab.set_index(index);
+        slab
+    }
+
+    fn indexes(slabs: &[Slab]) -> Vec<u64> {
+        slabs.iter().map(|slab| slab.get_index()).collect()
+    }
+
+    #[test]
+    fn test_slab_sequencer() {
+        let mut sequencer = SlabSequencer::new(4);
+
+        // Already applied
+        assert!(sequencer.push(slab(3)).is_empty());
+
+        // Ahead of sequence, held back
+        assert!(sequencer.push(slab(7)).is_empty());
+        assert!(sequencer.push(slab(6)).is_empty());
+        assert_eq!(sequencer.missing(), Some(5));
+
+        // The gap is filled, everything comes out in order
+        assert_eq!(indexes(&sequencer.push(slab(5))), vec![5, 6, 7]);
+        assert_eq!(sequencer.high_water(), 7);
+        assert_eq!(sequencer.missing(), None);
+
+        // A late duplicate is dropped
+        assert!(sequencer.push(slab(6)).is_empty());
+
+        assert_eq!(
+            indexes(&sequencer.push_all(vec![slab(9), slab(8)])),
+            vec![8, 9]
+        );
+
+        // Slabs applied by a rescan are dropped from the ones held back
+        sequencer.push(slab(12));
+        sequencer.push(slab(14));
+        sequencer.advance_to(12);
+        assert_eq!(sequencer.pending(), 1);
+        assert_eq!(indexes(&sequencer.push(slab(13))), vec![13, 14]);
+    }
+
+    #[test]
+    fn test_slab_sequencer_skips_stalled_gap() {
+        let mut sequencer = SlabSequencer::new(0);
+        let mut ready = vec![];
+        for index in 2..=MAX_PENDING_SLABS as u64 + 2 {
+            ready.extend(sequencer.push(slab(index)));
+        }
+        assert_eq!(ready.first().map(Slab::get_index), Some(2));
+        assert_eq!(sequencer.pending(), 0);
+    }
+}
@@ -1,6 +1,12 @@
-use crate::serial::{Decodable, Encodable};
+use blake2b_simd::Params;
+
+use crate::serial::{serialize, Decodable, Encodable};
 use crate::Result;
 
+const SLAB_HASH_PERSONALIZATION: &[u8; 16] = b"DarkFi_SlabHash_";
+
+pub type SlabHash = [u8; 32];
+
 #[derive(Clone, Debug)]
 pub struct Slab {
     index: u64,
@@ -24,6 +30,20 @@ impl Slab {
     pub fn get_payload(&self) -> Vec<u8> {
         self.payload.clone()
     }
+
+    /// The canonical slab hash: a personalized BLAKE2b-256 of the
+    /// serialized slab, index included. Changing it breaks every stored
+    /// and exchanged hash, so the test vectors below must keep passing.
+    pub fn hash(&self) -> SlabHash {
+        let hash = Params::new()
+            .hash_length(32)
+            .personal(SLAB_HASH_PERSONALIZATION)
+            .hash(&serialize(self));
+
+        let mut ret = [0u8; 32];
+        ret.copy_from_slice(hash.as_bytes());
+        ret
+    }
 }
 
 impl Encodable for Slab {
@@ -43,3 +63,36 @@ impl Decodable for Slab {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slab(index: u64, payload: &[u8]) -> Slab {
+        let mut slab = Slab::new(payload.to_vec());
+        slab.set_index(index);
+        slab
+    }
+
+    #[test]
+    fn test_slab_hash_vectors() {
+        let vectors = [
+            (
+                slab(0, b""),
+                "e97240ff1a6c02b35ad9e2696c50cde55445a753c853c2634cefb6c7d3096b33",
+            ),
+            (
+                slab(1, b""),
+                "9c3c9fbb8dbfb21ac331d88c024b6aed45e2b53427460973914461f72d6a6ad6",
+            ),
+            (
+                slab(42, b"darkfi"),
+                "03526c171a5d130c6895d6886097cd63aa89ddf62c242bfbf946c923ab4ac324",
+            ),
+        ];
+
+        for (slab, expected) in vectors.iter() {
+            assert_eq!(hex::encode(slab.hash()), *expected);
+        }
+    }
+}
@@ -1,35 +1,123 @@
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
-use log::debug;
+use log::{debug, warn};
 
-use super::rocks::{columns, IteratorMode, RocksColumn};
+use super::kv::{columns, IteratorMode, KvColumn};
 use super::slab::Slab;
 use crate::serial::{deserialize, serialize};
-use crate::Result;
+use crate::{Error, Result};
 
+/// A range of old slabs kept in a database of its own, e.g. on a cheaper
+/// disk than the one taking the new slabs
+pub struct SlabShard {
+    /// First and last index of the slabs the shard holds
+    pub first_index: u64,
+    pub last_index: u64,
+    pub rocks: KvColumn<columns::Slabs>,
+}
+
+impl SlabShard {
+    fn contains(&self, index: u64) -> bool {
+        self.first_index <= index && index <= self.last_index
+    }
+}
+
+/// The slabs, in one database or split by index range over several.
+/// Slabs outside every shard's range go to the main database. Where the
+/// slabs are kept makes no difference to readers: a slab that isn't
+/// migrated to its shard yet is found where it was.
 pub struct SlabStore {
-    rocks: RocksColumn<columns::Slabs>,
+    rocks: KvColumn<columns::Slabs>,
+    shards: Vec<SlabShard>,
+    // Held while a slab is put, so two slabs can't both take the next
+    // index
+    put_lock: Mutex<()>,
+    // For each shard, the next index to check is in place
+    migrated: Mutex<Vec<u64>>,
 }
 
 impl SlabStore {
-    pub fn new(rocks: RocksColumn<columns::Slabs>) -> Result<Arc<Self>> {
-        Ok(Arc::new(SlabStore { rocks }))
+    pub fn new(rocks: KvColumn<columns::Slabs>) -> Result<Arc<Self>> {
+        Self::sharded(rocks, vec![])
+    }
+
+    /// Keep the slabs of each shard's range in the shard. The ranges may
+    /// not overlap. Slabs stored before a shard was added are moved to it
+    /// by `migrate`.
+    pub fn sharded(rocks: KvColumn<columns::Slabs>, shards: Vec<SlabShard>) -> Result<Arc<Self>> {
+        let mut ranges: Vec<(u64, u64)> = shards
+            .iter()
+            .map(|shard| (shard.first_index, shard.last_index))
+            .collect();
+        ranges.sort_unstable();
+        for (i, range) in ranges.iter().enumerate() {
+            if range.0 == 0 || range.0 > range.1 {
+                return Err(Error::SlabsStore(format!(
+                    "Shard range {} to {} is empty",
+                    range.0, range.1
+                )));
+            }
+            if i > 0 && ranges[i - 1].1 >= range.0 {
+                return Err(Error::SlabsStore(format!(
+                    "Shard ranges {} to {} and {} to {} overlap",
+                    ranges[i - 1].0,
+                    ranges[i - 1].1,
+                    range.0,
+                    range.1
+                )));
+            }
+        }
+
+        let migrated = shards.iter().map(|shard| shard.first_index).collect();
+        Ok(Arc::new(SlabStore {
+            rocks,
+            shards,
+            put_lock: Mutex::new(()),
+            migrated: Mutex::new(migrated),
+        }))
+    }
+
+    // Where the slab at `index` belongs
+    fn route(&self, index: u64) -> &KvColumn<columns::Slabs> {
+        self.shards
+            .iter()
+            .find(|shard| shard.contains(index))
+            .map_or(&self.rocks, |shard| &shard.rocks)
+    }
+
+    // Every database, the one the slab belongs in first
+    fn lookup_order(&self, index: u64) -> Vec<&KvColumn<columns::Slabs>> {
+        let routed = self.route(index);
+        let mut order = vec![routed];
+        order.extend(
+            std::iter::once(&self.rocks)
+                .chain(self.shards.iter().map(|shard| &shard.rocks))
+                .filter(|rocks| !std::ptr::eq(*rocks, routed)),
+        );
+        order
     }
 
     pub fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
         debug!(target: "SLABSTORE", "get value");
         let key: u64 = deserialize(&key)?;
-        let value = self.rocks.get(key)?;
-        Ok(value)
+        for rocks in self.lookup_order(key) {
+            if let Some(value) = rocks.get(key)? {
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
     }
 
+    /// Store the slab if its index is the next one, so the indexes
+    /// stay contiguous. Returns the index it was stored at.
     pub fn put(&self, slab: Slab) -> Result<Option<u64>> {
         debug!(target: "SLABSTORE", "Put slab");
+        let _guard = self.put_lock.lock().unwrap();
         let last_index = self.get_last_index()?;
         let key = last_index + 1;
 
         if slab.get_index() == key {
-            self.rocks.put(key, slab)?;
+            self.route(key).put(key, slab)?;
             Ok(Some(key))
         } else {
             Ok(None)
@@ -37,24 +125,99 @@ impl SlabStore {
     }
 
     pub fn get_value_deserialized(&self, key: Vec<u8>) -> Result<Option<Slab>> {
-        self.rocks.get_value_deserialized::<Slab>(key)
+        let index: u64 = deserialize(&key)?;
+        for rocks in self.lookup_order(index) {
+            if let Some(slab) = rocks.get_value_deserialized::<Slab>(key.clone())? {
+                return Ok(Some(slab));
+            }
+        }
+        Ok(None)
     }
 
     pub fn get_last_index(&self) -> Result<u64> {
         debug!(target: "SLABSTORE", "Get last index");
-        let last_index = self.rocks.iterator(IteratorMode::End)?.next();
-        match last_index {
-            Some((index, _)) => Ok(deserialize(&index)?),
-            None => Ok(0),
+        let mut last_index = Self::last_index_of(&self.rocks)?;
+        for shard in self.shards.iter() {
+            last_index = last_index.max(Self::last_index_of(&shard.rocks)?);
         }
+        Ok(last_index)
     }
 
     pub fn get_last_index_as_bytes(&self) -> Result<Vec<u8>> {
         debug!(target: "SLABSTORE", "Get last index as bytes");
-        let last_index = self.rocks.iterator(IteratorMode::End)?.next();
+        Ok(serialize(&self.get_last_index()?))
+    }
+
+    fn last_index_of(rocks: &KvColumn<columns::Slabs>) -> Result<u64> {
+        let last_index = rocks.iterator(IteratorMode::End)?.next();
         match last_index {
-            Some((index, _)) => Ok(index.to_vec()),
-            None => Ok(serialize::<u64>(&0)),
+            Some((index, _)) => Ok(deserialize(&index)?),
+            None => Ok(0),
+        }
+    }
+
+    /// Delete the slabs after `last_index` from every database, so the
+    /// next ones put take their place. Returns how many were deleted.
+    pub fn truncate(&self, last_index: u64) -> Result<u64> {
+        let _guard = self.put_lock.lock().unwrap();
+        let mut deleted = 0;
+        for index in (last_index + 1)..=self.get_last_index()? {
+            for rocks in std::iter::once(&self.rocks).chain(self.shards.iter().map(|s| &s.rocks)) {
+                if rocks.key_exist(index)? {
+                    rocks.delete(index)?;
+                    deleted += 1;
+                }
+            }
+        }
+        debug!(target: "SLABSTORE", "Deleted {} slabs after {}", deleted, last_index);
+        Ok(deleted)
+    }
+
+    /// Check up to `max` slabs are in their shard, moving the ones that
+    /// aren't, e.g. after a shard was added for slabs that were in the
+    /// main database. Returns how many were checked, fewer than `max`
+    /// once every shard is in place up to the last slab.
+    pub fn migrate(&self, max: usize) -> Result<usize> {
+        let last_index = self.get_last_index()?;
+        let mut migrated = self.migrated.lock().unwrap();
+        let (mut checked, mut moved) = (0, 0);
+
+        for (shard, next) in self.shards.iter().zip(migrated.iter_mut()) {
+            while *next <= shard.last_index.min(last_index) && checked < max {
+                let index = *next;
+                if !shard.rocks.key_exist(index)? {
+                    if self.move_to_shard(shard, index)? {
+                        moved += 1;
+                    } else {
+                        warn!(target: "SLABSTORE", "Slab {} is missing", index);
+                    }
+                }
+                *next += 1;
+                checked += 1;
+            }
+        }
+
+        if moved > 0 {
+            debug!(target: "SLABSTORE", "Migrated {} slabs to their shards", moved);
+        }
+        Ok(checked)
+    }
+
+    // Move the slab at `index` from wherever it is into its shard. It's
+    // written to the shard before it's deleted elsewhere, so a crash in
+    // between only leaves a copy behind.
+    fn move_to_shard(&self, shard: &SlabShard, index: u64) -> Result<bool> {
+        for rocks in std::iter::once(&self.rocks).chain(self.shards.iter().map(|s| &s.rocks)) {
+            if std::ptr::eq(rocks, &shard.rocks) {
+                continue;
+            }
+            if let Some(slab) = rocks.get(index)? {
+                let slab: Slab = deserialize(&slab)?;
+                shard.rocks.put(index, slab)?;
+                rocks.delete(index)?;
+                return Ok(true);
+            }
         }
+        Ok(false)
     }
 }
@@ -0,0 +1,109 @@
+use async_std::sync::Arc;
+use std::path::Path;
+
+use sled::transaction::{TransactionResult, TransactionalTree};
+use sled::Transactional;
+
+use super::kv::{IteratorMode, KvBatch, KvIterator, KvStore};
+use crate::{Error, Result};
+
+/// A store kept in sled, a pure Rust database, with a tree per column
+pub struct SledStore {
+    db: sled::Db,
+}
+
+impl SledStore {
+    pub fn new(path: &Path) -> Result<Arc<Self>> {
+        let db = sled::open(path)?;
+        Ok(Arc::new(Self { db }))
+    }
+
+    fn entries<'a>(
+        iter: impl Iterator<Item = sled::Result<(sled::IVec, sled::IVec)>> + 'a,
+    ) -> KvIterator<'a> {
+        // Like the rocksdb iterator, iterating ends at a read error
+        let iter = iter
+            .take_while(|item| item.is_ok())
+            .filter_map(|item| item.ok())
+            .map(|(key, value)| (key.to_vec(), value.to_vec()));
+        Box::new(iter)
+    }
+}
+
+impl KvStore for SledStore {
+    fn put(&self, column: &str, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.db.open_tree(column)?.insert(key, value)?;
+        Ok(())
+    }
+
+    fn get(&self, column: &str, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let val = self.db.open_tree(column)?.get(key)?;
+        Ok(val.map(|val| val.to_vec()))
+    }
+
+    fn delete(&self, column: &str, key: Vec<u8>) -> Result<()> {
+        self.db.open_tree(column)?.remove(key)?;
+        Ok(())
+    }
+
+    fn key_exist(&self, column: &str, key: Vec<u8>) -> Result<bool> {
+        Ok(self.db.open_tree(column)?.contains_key(key)?)
+    }
+
+    fn iterator(&self, column: &str, iterator_mode: IteratorMode) -> Result<KvIterator<'_>> {
+        let tree = self.db.open_tree(column)?;
+        let iter: Box<dyn Iterator<Item = sled::Result<(sled::IVec, sled::IVec)>>> =
+            match iterator_mode {
+                IteratorMode::Start => Box::new(tree.iter()),
+                IteratorMode::End => Box::new(tree.iter().rev()),
+            };
+        Ok(Self::entries(iter))
+    }
+
+    fn iterator_from(&self, column: &str, key: Vec<u8>) -> Result<KvIterator<'_>> {
+        let tree = self.db.open_tree(column)?;
+        Ok(Self::entries(tree.range(key..)))
+    }
+
+    /// The trees of every column written to are updated in one
+    /// transaction
+    fn write(&self, batch: KvBatch) -> Result<()> {
+        let mut names: Vec<&str> = batch.ops().iter().map(|(column, _, _)| *column).collect();
+        names.sort_unstable();
+        names.dedup();
+
+        let mut trees = vec![];
+        for name in names.iter() {
+            trees.push(self.db.open_tree(name)?);
+        }
+
+        let result: TransactionResult<()> =
+            trees
+                .as_slice()
+                .transaction(|views: &Vec<TransactionalTree>| {
+                    for (column, key, value) in batch.ops() {
+                        let view = &views[names.binary_search(column).unwrap()];
+                        match value {
+                            Some(value) => view.insert(key.clone(), value.clone())?,
+                            None => view.remove(key.clone())?,
+                        };
+                    }
+                    Ok(())
+                });
+        result.map_err(|e| Error::SledError(format!("{:?}", e)))
+    }
+
+    /// sled can't snapshot itself, so the trees are copied into a new
+    /// store. Writes made meanwhile may or may not be in the copy.
+    fn checkpoint(&self, path: &Path) -> Result<()> {
+        let copy = sled::open(path)?;
+        copy.import(self.db.export());
+        copy.flush()?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
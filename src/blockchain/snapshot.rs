@@ -0,0 +1,458 @@
+//! Scheduled snapshots of the node state. The client database, with the
+//! slabs, merkle tree, roots and nullifiers, is checkpointed into a
+//! directory of its own in the datadir, so a node whose database is lost
+//! or corrupted can start again from the last snapshot instead of syncing
+//! from the first slab. Only the newest snapshots are kept.
+
+use async_std::sync::{Arc, Mutex};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use async_executor::Executor;
+use log::{debug, warn};
+use serde_json::{json, Value};
+
+use super::{KvBackend, KvStore};
+use crate::client::State;
+use crate::util::Clock;
+use crate::{Error, Result};
+
+const SNAPSHOT_PREFIX: &str = "snapshot-";
+// Snapshots are written under this prefix and renamed once complete, so
+// a crash never leaves a partial one looking like a snapshot
+const STAGING_PREFIX: &str = ".staging-";
+
+/// How often the schedule is checked
+pub const SNAPSHOT_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+// Snapshots are named after the slab they were taken at and the time
+fn snapshot_name(index: u64, timestamp: u64) -> String {
+    format!("{}{}-{}", SNAPSHOT_PREFIX, index, timestamp)
+}
+
+fn parse_snapshot_name(name: &str) -> Option<(u64, u64)> {
+    let rest = name.strip_prefix(SNAPSHOT_PREFIX)?;
+    let mut parts = rest.splitn(2, '-');
+    let index = parts.next()?.parse().ok()?;
+    let timestamp = parts.next()?.parse().ok()?;
+    Some((index, timestamp))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SnapshotInfo {
+    /// The last slab applied to the snapshotted state
+    pub index: u64,
+    pub timestamp: u64,
+    pub path: PathBuf,
+}
+
+impl SnapshotInfo {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "index": self.index,
+            "timestamp": self.timestamp,
+            "path": self.path.to_string_lossy(),
+        })
+    }
+}
+
+/// A snapshot is taken every `every_slabs` slabs applied or `every_secs`
+/// seconds, whichever comes first, zero turning either off. The newest
+/// `keep` are kept.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SnapshotSchedule {
+    pub every_slabs: u64,
+    pub every_secs: u64,
+    pub keep: usize,
+}
+
+impl SnapshotSchedule {
+    /// Whether to take a snapshot at slab `index` and time `now`, after
+    /// `last`. There's none to take while no slab has been applied since.
+    pub fn is_due(&self, last: Option<&SnapshotInfo>, index: u64, now: u64) -> bool {
+        let last = match last {
+            Some(last) => last,
+            None => return index > 0,
+        };
+        if index == last.index {
+            return false;
+        }
+
+        (self.every_slabs > 0 && index >= last.index.saturating_add(self.every_slabs))
+            || (self.every_secs > 0 && now >= last.timestamp.saturating_add(self.every_secs))
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SnapshotStatus {
+    pub latest: Option<SnapshotInfo>,
+    /// Snapshots kept in the datadir
+    pub snapshots: usize,
+    /// Why the last attempt failed, if it did
+    pub last_error: Option<String>,
+}
+
+impl SnapshotStatus {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "latest": self.latest.as_ref().map(|latest| latest.to_json()),
+            "snapshots": self.snapshots,
+            "last_error": self.last_error,
+        })
+    }
+}
+
+pub struct StateSnapshots {
+    store: Arc<dyn KvStore>,
+    dir: PathBuf,
+    schedule: SnapshotSchedule,
+    status: Arc<Mutex<SnapshotStatus>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl StateSnapshots {
+    /// Snapshots of `store` kept in `dir`. Ones left half written by a
+    /// crash are removed.
+    pub fn new(
+        store: Arc<dyn KvStore>,
+        dir: PathBuf,
+        schedule: SnapshotSchedule,
+        clock: Arc<dyn Clock>,
+    ) -> Result<Self> {
+        fs::create_dir_all(&dir)?;
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            if entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with(STAGING_PREFIX)
+            {
+                fs::remove_dir_all(entry.path())?;
+            }
+        }
+
+        let snapshots = list_snapshots(&dir)?;
+        let status = SnapshotStatus {
+            latest: snapshots.last().cloned(),
+            snapshots: snapshots.len(),
+            last_error: None,
+        };
+
+        Ok(Self {
+            store,
+            dir,
+            schedule,
+            status: Arc::new(Mutex::new(status)),
+            clock,
+        })
+    }
+
+    pub fn status(&self) -> Arc<Mutex<SnapshotStatus>> {
+        self.status.clone()
+    }
+
+    /// The snapshots in the datadir, oldest first
+    pub fn list(&self) -> Result<Vec<SnapshotInfo>> {
+        list_snapshots(&self.dir)
+    }
+
+    /// Check the schedule now and then every poll interval. Failures are
+    /// reported in the status and retried at the next check.
+    pub fn start(self: Arc<Self>, state: Arc<Mutex<State>>, executor: Arc<Executor<'_>>) {
+        executor
+            .spawn(async move {
+                loop {
+                    if let Err(e) = self.check(&state).await {
+                        warn!(target: "SNAPSHOT", "State snapshot failed: {}", e);
+                        self.status.lock().await.last_error = Some(e.to_string());
+                    }
+                    self.clock.sleep(SNAPSHOT_POLL_INTERVAL).await;
+                }
+            })
+            .detach();
+    }
+
+    // The state stays locked while the snapshot is taken, so no slab is
+    // half applied in it
+    async fn check(&self, state: &Mutex<State>) -> Result<()> {
+        let state = state.lock().await;
+        let index = state.last_applied_index()?;
+        let latest = self.status.lock().await.latest.clone();
+        if !self
+            .schedule
+            .is_due(latest.as_ref(), index, self.clock.now())
+        {
+            return Ok(());
+        }
+
+        self.take(index).await?;
+        drop(state);
+        Ok(())
+    }
+
+    /// Snapshot the database as it is now, as the state at slab `index`,
+    /// and remove the snapshots beyond the newest `keep`
+    pub async fn take(&self, index: u64) -> Result<SnapshotInfo> {
+        let now = self.clock.now();
+        let name = snapshot_name(index, now);
+        let staging = self.dir.join(format!("{}{}", STAGING_PREFIX, name));
+        if staging.exists() {
+            fs::remove_dir_all(&staging)?;
+        }
+
+        self.store.checkpoint(&staging)?;
+        let path = self.dir.join(&name);
+        fs::rename(&staging, &path)?;
+        debug!(target: "SNAPSHOT", "Took snapshot {} at slab {}", name, index);
+
+        let snapshots = self.prune()?;
+        let info = SnapshotInfo {
+            index,
+            timestamp: now,
+            path,
+        };
+
+        let mut status = self.status.lock().await;
+        status.latest = Some(info.clone());
+        status.snapshots = snapshots;
+        status.last_error = None;
+        Ok(info)
+    }
+
+    // Returns the number of snapshots left
+    fn prune(&self) -> Result<usize> {
+        let snapshots = self.list()?;
+        let expired = snapshots.len().saturating_sub(self.schedule.keep.max(1));
+        for snapshot in &snapshots[..expired] {
+            debug!(target: "SNAPSHOT", "Remove expired snapshot {:?}", snapshot.path);
+            fs::remove_dir_all(&snapshot.path)?;
+        }
+        Ok(snapshots.len() - expired)
+    }
+}
+
+fn list_snapshots(dir: &Path) -> Result<Vec<SnapshotInfo>> {
+    let mut snapshots = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if let Some((index, timestamp)) = parse_snapshot_name(&name) {
+            snapshots.push(SnapshotInfo {
+                index,
+                timestamp,
+                path: entry.path(),
+            });
+        }
+    }
+    snapshots.sort_by_key(|snapshot| (snapshot.timestamp, snapshot.index));
+    Ok(snapshots)
+}
+
+/// Put `backup`, a snapshot or a copy made by `KvStore::checkpoint`, in
+/// place of the database at `path`. The node must be stopped. The
+/// database replaced is kept next to it, and where is returned.
+pub fn restore_database(
+    backend: KvBackend,
+    backup: &Path,
+    path: &Path,
+    timestamp: u64,
+) -> Result<Option<PathBuf>> {
+    if !backup.is_dir() {
+        return Err(Error::ServicesError("no database backup at that path"));
+    }
+
+    // Fails while the node has the database open
+    if path.exists() {
+        drop(backend.open(path)?);
+    }
+
+    // The copy is checked to open before anything is replaced
+    let staging = sibling(path, "restoring");
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    copy_dir(backup, &staging)?;
+    drop(backend.open(&staging)?);
+
+    let replaced = if path.exists() {
+        let replaced = sibling(path, &format!("before-restore-{}", timestamp));
+        fs::rename(path, &replaced)?;
+        Some(replaced)
+    } else {
+        None
+    };
+    fs::rename(&staging, path)?;
+    debug!(target: "SNAPSHOT", "Restored {:?} from {:?}", path, backup);
+    Ok(replaced)
+}
+
+// `path` with `suffix` appended to its file name
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!("{}.{}", name, suffix))
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{kv::columns, KvBackend, KvColumn};
+    use crate::util::MockClock;
+
+    #[test]
+    fn test_snapshot_schedule() {
+        let schedule = SnapshotSchedule {
+            every_slabs: 100,
+            every_secs: 3600,
+            keep: 3,
+        };
+        let last = SnapshotInfo {
+            index: 50,
+            timestamp: 1000,
+            path: PathBuf::new(),
+        };
+
+        // The first one once there's anything to snapshot
+        assert!(!schedule.is_due(None, 0, 1000));
+        assert!(schedule.is_due(None, 1, 1000));
+
+        // Then after enough slabs or time, whichever comes first
+        assert!(!schedule.is_due(Some(&last), 149, 4599));
+        assert!(schedule.is_due(Some(&last), 150, 1000));
+        assert!(schedule.is_due(Some(&last), 51, 4600));
+
+        // But not while nothing was applied since
+        assert!(!schedule.is_due(Some(&last), 50, 100_000));
+
+        let slabs_only = SnapshotSchedule {
+            every_secs: 0,
+            ..schedule
+        };
+        assert!(!slabs_only.is_due(Some(&last), 51, 100_000));
+    }
+
+    #[test]
+    fn test_snapshot_retention() -> Result<()> {
+        let db_path = std::env::temp_dir().join("darkfi_test_snapshot_db");
+        let dir = std::env::temp_dir().join("darkfi_test_snapshots");
+        let _ = fs::remove_dir_all(&db_path);
+        let _ = fs::remove_dir_all(&dir);
+
+        let store = KvBackend::default().open(&db_path)?;
+        let slabs = KvColumn::<columns::Slabs>::new(store.clone());
+
+        // A staging directory left by a crash is cleaned up
+        fs::create_dir_all(dir.join(format!("{}{}", STAGING_PREFIX, snapshot_name(1, 1))))?;
+        fs::write(dir.join("notes.txt"), "keep me")?;
+
+        let schedule = SnapshotSchedule {
+            every_slabs: 1,
+            every_secs: 0,
+            keep: 2,
+        };
+        let clock = Arc::new(MockClock::new(1000));
+        let snapshots = StateSnapshots::new(store, dir.clone(), schedule, clock.clone())?;
+        assert_eq!(
+            smol::future::block_on(snapshots.status().lock()).snapshots,
+            0
+        );
+
+        for index in 1..=3u64 {
+            slabs.put(index, vec![index as u8])?;
+            smol::future::block_on(snapshots.take(index))?;
+            clock.advance(Duration::from_secs(60));
+        }
+
+        // The newest two are kept, each a database of its own
+        let kept = snapshots.list()?;
+        assert_eq!(
+            kept.iter()
+                .map(|s| (s.index, s.timestamp))
+                .collect::<Vec<_>>(),
+            vec![(2, 1060), (3, 1120)]
+        );
+        let restored = KvBackend::default().open(&kept[0].path)?;
+        let restored_slabs = KvColumn::<columns::Slabs>::new(restored);
+        assert!(restored_slabs.key_exist(2u64)?);
+        assert!(!restored_slabs.key_exist(3u64)?);
+
+        let status = smol::future::block_on(snapshots.status().lock()).clone();
+        assert_eq!(status.snapshots, 2);
+        assert_eq!(status.latest.map(|latest| latest.index), Some(3));
+
+        let mut names: Vec<String> = fs::read_dir(&dir)?
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                "notes.txt".to_string(),
+                snapshot_name(2, 1060),
+                snapshot_name(3, 1120)
+            ]
+        );
+
+        fs::remove_dir_all(&dir)?;
+        fs::remove_dir_all(&db_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_database() -> Result<()> {
+        let db_path = std::env::temp_dir().join("darkfi_test_restore_db");
+        let backup = std::env::temp_dir().join("darkfi_test_restore_backup");
+        let replaced = sibling(&db_path, "before-restore-1000");
+        for path in &[&db_path, &backup, &replaced] {
+            let _ = fs::remove_dir_all(path);
+        }
+
+        let backend = KvBackend::default();
+        let store = backend.open(&db_path)?;
+        let slabs = KvColumn::<columns::Slabs>::new(store.clone());
+        slabs.put(1u64, vec![1u8])?;
+        store.checkpoint(&backup)?;
+        slabs.put(2u64, vec![2u8])?;
+
+        // Not while the database is open
+        assert!(restore_database(backend, &backup, &db_path, 1000).is_err());
+        drop(slabs);
+        drop(store);
+
+        let kept = restore_database(backend, &backup, &db_path, 1000)?;
+        assert_eq!(kept, Some(replaced.clone()));
+
+        let slabs = KvColumn::<columns::Slabs>::new(backend.open(&db_path)?);
+        assert!(slabs.key_exist(1u64)?);
+        assert!(!slabs.key_exist(2u64)?);
+        let replaced_slabs = KvColumn::<columns::Slabs>::new(backend.open(&replaced)?);
+        assert!(replaced_slabs.key_exist(2u64)?);
+
+        // The backup itself is left alone
+        assert!(backup.is_dir());
+
+        drop(slabs);
+        drop(replaced_slabs);
+        for path in &[&db_path, &backup, &replaced] {
+            fs::remove_dir_all(path)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,182 @@
+//! The node state in a single file: the last applied slab, the merkle
+//! tree frontier and every merkle root and nullifier. A new node can take
+//! it in instead of applying every slab. Its slabs are still fetched to
+//! fill the slabstore, but those up to the snapshot aren't applied again.
+//!
+//! Nothing in the file can be checked against the slabs, so it must come
+//! from a node that's trusted.
+
+use async_std::sync::Arc;
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use super::kv::{columns, KvBatch};
+use super::{KvColumn, KvStore};
+use crate::client::{State, LAST_APPLIED_INDEX_KEY, MERKLE_TREE_KEY};
+use crate::crypto::{merkle::CommitmentTree, merkle_node::MerkleNode};
+use crate::serial::{Decodable, Encodable};
+use crate::{Error, Result};
+
+const MAGIC: [u8; 8] = *b"DRKSTATE";
+
+/// Version of the file format, bumped when it changes
+pub const STATE_EXPORT_VERSION: u32 = 1;
+
+pub struct StateExport {
+    /// The last slab applied to the state
+    pub index: u64,
+    pub tree: CommitmentTree<MerkleNode>,
+    pub merkle_roots: Vec<[u8; 32]>,
+    pub nullifiers: Vec<[u8; 32]>,
+}
+
+impl StateExport {
+    /// The state kept in `store`
+    pub fn read(store: Arc<dyn KvStore>) -> Result<Self> {
+        let sync_state = KvColumn::<columns::SyncState>::new(store.clone());
+        let merkle_roots = KvColumn::<columns::MerkleRoots>::new(store.clone());
+        let nullifiers = KvColumn::<columns::Nullifiers>::new(store);
+
+        Ok(Self {
+            index: State::load_index(&sync_state)?,
+            tree: State::load_tree(&sync_state)?,
+            merkle_roots: merkle_roots
+                .iter::<[u8; 32], Vec<u8>>()?
+                .map(|entry| entry.map(|(root, _)| root))
+                .collect::<Result<_>>()?,
+            nullifiers: nullifiers
+                .iter::<[u8; 32], Vec<u8>>()?
+                .map(|entry| entry.map(|(nullifier, _)| nullifier))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// Write the state into `store`, in one batch. The store must not
+    /// have applied any slab yet.
+    pub fn write(&self, store: Arc<dyn KvStore>) -> Result<()> {
+        let sync_state = KvColumn::<columns::SyncState>::new(store.clone());
+        let merkle_roots = KvColumn::<columns::MerkleRoots>::new(store.clone());
+        let nullifiers = KvColumn::<columns::Nullifiers>::new(store);
+        if State::load_index(&sync_state)? > 0 {
+            return Err(Error::ServicesError(
+                "the database already has a state, import into an empty one",
+            ));
+        }
+
+        let mut batch = KvBatch::new();
+        for root in self.merkle_roots.iter() {
+            merkle_roots.put_batch(&mut batch, *root, vec![] as Vec<u8>);
+        }
+        for nullifier in self.nullifiers.iter() {
+            nullifiers.put_batch(&mut batch, *nullifier, vec![] as Vec<u8>);
+        }
+        sync_state.put_batch(&mut batch, MERKLE_TREE_KEY.to_string(), self.tree.clone());
+        sync_state.put_batch(&mut batch, LAST_APPLIED_INDEX_KEY.to_string(), self.index);
+        sync_state.write(batch)
+    }
+
+    /// Write the file next to `path` and move it there once complete
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let staging = path.with_extension("partial");
+        let mut file = BufWriter::new(fs::File::create(&staging)?);
+        self.encode(&mut file)?;
+        file.flush()?;
+        drop(file);
+        fs::rename(&staging, path)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let export: Self = Decodable::decode(BufReader::new(fs::File::open(path)?))?;
+
+        // The tree must be one the roots were taken from
+        if export.tree.size() > 0 && !export.merkle_roots.contains(&export.tree.root().repr) {
+            return Err(Error::ServicesError("the snapshot's merkle tree isn't among its roots"));
+        }
+        Ok(export)
+    }
+}
+
+impl Encodable for StateExport {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += MAGIC.encode(&mut s)?;
+        len += STATE_EXPORT_VERSION.encode(&mut s)?;
+        len += self.index.encode(&mut s)?;
+        len += self.tree.encode(&mut s)?;
+        len += self.merkle_roots.encode(&mut s)?;
+        len += self.nullifiers.encode(s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for StateExport {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        let magic: [u8; 8] = Decodable::decode(&mut d)?;
+        if magic != MAGIC {
+            return Err(Error::ServicesError("not a state snapshot file"));
+        }
+        let version: u32 = Decodable::decode(&mut d)?;
+        if version != STATE_EXPORT_VERSION {
+            return Err(Error::ServicesError("unsupported state snapshot version"));
+        }
+
+        Ok(Self {
+            index: Decodable::decode(&mut d)?,
+            tree: Decodable::decode(&mut d)?,
+            merkle_roots: Decodable::decode(&mut d)?,
+            nullifiers: Decodable::decode(d)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::KvBackend;
+    use crate::crypto::coin::Coin;
+
+    #[test]
+    fn test_state_export() -> Result<()> {
+        let from_path = std::env::temp_dir().join("darkfi_test_state_export_from");
+        let to_path = std::env::temp_dir().join("darkfi_test_state_export_to");
+        let file = std::env::temp_dir().join("darkfi_test_state_export.bin");
+        let _ = fs::remove_dir_all(&from_path);
+        let _ = fs::remove_dir_all(&to_path);
+
+        let mut tree = CommitmentTree::empty();
+        tree.append(MerkleNode::from_coin(&Coin::new([1; 32])))?;
+        let export = StateExport {
+            index: 42,
+            tree: tree.clone(),
+            merkle_roots: vec![tree.root().repr],
+            nullifiers: vec![[2; 32], [3; 32]],
+        };
+
+        let from = KvBackend::default().open(&from_path)?;
+        export.write(from.clone())?;
+        StateExport::read(from.clone())?.save(&file)?;
+
+        let to = KvBackend::default().open(&to_path)?;
+        StateExport::load(&file)?.write(to.clone())?;
+        let imported = StateExport::read(to.clone())?;
+        assert_eq!(imported.index, 42);
+        assert_eq!(imported.tree.root(), tree.root());
+        assert_eq!(imported.merkle_roots, export.merkle_roots);
+        assert_eq!(imported.nullifiers, export.nullifiers);
+
+        // Only into a database with no state yet
+        assert!(export.write(to).is_err());
+
+        // Other files are refused
+        fs::write(&file, b"not a snapshot")?;
+        assert!(StateExport::load(&file).is_err());
+
+        fs::remove_file(&file)?;
+        drop(from);
+        fs::remove_dir_all(&from_path)?;
+        fs::remove_dir_all(&to_path)?;
+        Ok(())
+    }
+}
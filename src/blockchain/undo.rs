@@ -0,0 +1,88 @@
+//! What applying a slab added to the state, kept for the last slabs so
+//! they can be rolled back, e.g. after following a gateway that forked.
+//! Records are written in the same batch as the slab's checkpoint.
+
+use std::io;
+
+use crate::crypto::{merkle::CommitmentTree, merkle_node::MerkleNode};
+use crate::serial::{Decodable, Encodable};
+use crate::Result;
+
+/// How many of the last applied slabs can be rolled back
+pub const UNDO_HISTORY_SIZE: u64 = 1000;
+
+pub struct UndoRecord {
+    /// The merkle tree before the slab was applied
+    pub tree: CommitmentTree<MerkleNode>,
+    pub nullifiers: Vec<[u8; 32]>,
+    pub merkle_roots: Vec<[u8; 32]>,
+    /// Coins appended to the tree, in tree order
+    pub coins: Vec<[u8; 32]>,
+    /// Coins the slab sent to our keys
+    pub own_coins: Vec<[u8; 32]>,
+}
+
+impl UndoRecord {
+    /// The record of a slab that added nothing, yet, to `tree`
+    pub fn new(tree: CommitmentTree<MerkleNode>) -> Self {
+        Self {
+            tree,
+            nullifiers: vec![],
+            merkle_roots: vec![],
+            coins: vec![],
+            own_coins: vec![],
+        }
+    }
+}
+
+impl Encodable for UndoRecord {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.tree.encode(&mut s)?;
+        len += self.nullifiers.encode(&mut s)?;
+        len += self.merkle_roots.encode(&mut s)?;
+        len += self.coins.encode(&mut s)?;
+        len += self.own_coins.encode(s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for UndoRecord {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self {
+            tree: Decodable::decode(&mut d)?,
+            nullifiers: Decodable::decode(&mut d)?,
+            merkle_roots: Decodable::decode(&mut d)?,
+            coins: Decodable::decode(&mut d)?,
+            own_coins: Decodable::decode(d)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::coin::Coin;
+    use crate::serial::{deserialize, serialize};
+
+    #[test]
+    fn test_undo_record() -> Result<()> {
+        let mut tree = CommitmentTree::empty();
+        tree.append(MerkleNode::from_coin(&Coin::new([1; 32])))?;
+
+        let mut record = UndoRecord::new(tree.clone());
+        record.nullifiers.push([2; 32]);
+        record.merkle_roots.push(tree.root().repr);
+        record.coins = vec![[3; 32], [4; 32]];
+        record.own_coins.push([4; 32]);
+
+        let decoded: UndoRecord = deserialize(&serialize(&record))?;
+        assert_eq!(decoded.tree.root(), tree.root());
+        assert_eq!(decoded.tree.size(), 1);
+        assert_eq!(decoded.nullifiers, record.nullifiers);
+        assert_eq!(decoded.merkle_roots, record.merkle_roots);
+        assert_eq!(decoded.coins, record.coins);
+        assert_eq!(decoded.own_coins, record.own_coins);
+        Ok(())
+    }
+}
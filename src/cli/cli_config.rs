@@ -9,6 +9,12 @@ use std::{
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
+use crate::blockchain::{KvBackend, RocksdbOptions};
+use crate::rpc::rpcserver::DEFAULT_MAX_REQUEST_SIZE;
+use crate::service::cashier::CASHIER_TIMEOUT;
+use crate::service::gateway::{GATEWAY_TIMEOUT, HEARTBEAT_INTERVAL, HEARTBEAT_TIMEOUT};
+use crate::util::DEFAULT_DATADIR;
+use crate::wallet::CoinSelectionStrategy;
 use crate::{Error, Result};
 
 pub fn load_keypair_to_str(path: PathBuf) -> Result<String> {
@@ -22,6 +28,82 @@ pub fn load_keypair_to_str(path: PathBuf) -> Result<String> {
     }
 }
 
+fn default_datadir() -> String {
+    DEFAULT_DATADIR.to_string()
+}
+
+fn default_coin_selection() -> String {
+    CoinSelectionStrategy::default().as_str().to_string()
+}
+
+fn default_kv_backend() -> String {
+    KvBackend::default().as_str().to_string()
+}
+
+fn default_duplicate_payment_window() -> u64 {
+    600
+}
+
+fn default_verify_workers() -> usize {
+    num_cpus::get()
+}
+
+fn default_rpc_max_request_size() -> usize {
+    DEFAULT_MAX_REQUEST_SIZE
+}
+
+fn default_backup_interval() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_backup_keep() -> usize {
+    7
+}
+
+fn default_snapshot_every_hours() -> u64 {
+    24
+}
+
+fn default_snapshot_keep() -> usize {
+    3
+}
+
+fn default_log_max_size_mb() -> u64 {
+    100
+}
+
+fn default_log_keep() -> usize {
+    5
+}
+
+fn default_compress_slabs() -> bool {
+    true
+}
+
+fn default_max_slab_size() -> usize {
+    256 * 1024
+}
+
+fn default_heartbeat_interval() -> u32 {
+    HEARTBEAT_INTERVAL
+}
+
+fn default_heartbeat_timeout() -> u32 {
+    HEARTBEAT_TIMEOUT
+}
+
+fn default_gateway_request_timeout() -> u32 {
+    GATEWAY_TIMEOUT
+}
+
+fn default_cashier_request_timeout() -> u32 {
+    CASHIER_TIMEOUT
+}
+
+fn default_address_network() -> String {
+    "testnet".to_string()
+}
+
 #[derive(Clone, Default)]
 pub struct Config<T> {
     config: PhantomData<T>,
@@ -47,6 +129,24 @@ impl<T: Serialize + DeserializeOwned> Config<T> {
 pub struct DrkConfig {
     /// The URL where darkfid RPC is listening on
     pub darkfid_rpc_url: String,
+    /// The rpc_token darkfid is configured with, if any
+    #[serde(default)]
+    pub darkfid_rpc_token: Option<String>,
+    /// PEM certificate to trust for a tls:// darkfid besides the system
+    /// ones, e.g. a self-signed one
+    #[serde(default)]
+    pub darkfid_tls_ca_cert_path: Option<String>,
+    /// SHA-256 fingerprints of the only certificates a tls:// darkfid may
+    /// present
+    #[serde(default)]
+    pub darkfid_tls_pinned_certs: Vec<String>,
+    /// PEM certificate chain presented to a tls:// darkfid, which needs
+    /// one for transfers if it has tls_client_ca_path set
+    #[serde(default)]
+    pub darkfid_tls_client_cert_path: Option<String>,
+    /// Path to the PEM PKCS#8 or RSA key of darkfid_tls_client_cert_path
+    #[serde(default)]
+    pub darkfid_tls_client_key_path: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -59,33 +159,269 @@ pub struct Cashier {
     pub public_key: String,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GatewayEndpoint {
+    /// The endpoint to a gatewayd protocol API
+    pub protocol_url: String,
+    /// The endpoint to a gatewayd publisher API
+    pub publisher_url: String,
+}
+
+/// Scheduled snapshots of the node state
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SnapshotConfig {
+    /// Slabs applied between snapshots, 0 for no limit
+    #[serde(default)]
+    pub every_slabs: u64,
+    /// Hours between snapshots, 0 for no limit
+    #[serde(default = "default_snapshot_every_hours")]
+    pub every_hours: u64,
+    /// Snapshots kept in the datadir, older ones are deleted
+    #[serde(default = "default_snapshot_keep")]
+    pub keep: usize,
+}
+
+/// A debug log written to a file, rotated so it doesn't fill the disk
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LogFileConfig {
+    pub path: String,
+    /// Size in megabytes at which the file is rotated
+    #[serde(default = "default_log_max_size_mb")]
+    pub max_size_mb: u64,
+    /// Hours a file is written to before it's rotated, 0 for no limit
+    #[serde(default)]
+    pub max_age_hours: u64,
+    /// Rotated files kept next to the log, older ones are deleted
+    #[serde(default = "default_log_keep")]
+    pub keep: usize,
+}
+
+/// Scheduled backups of the encrypted wallet
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BackupConfig {
+    /// Where archives are uploaded: a local directory,
+    /// sftp://user@host/dir or, with the s3 feature, s3://bucket/prefix
+    pub destination: String,
+    /// Seconds between backups
+    #[serde(default = "default_backup_interval")]
+    pub interval: u64,
+    /// Archives kept at the destination, older ones are deleted
+    #[serde(default = "default_backup_keep")]
+    pub keep: usize,
+    /// Endpoint of the S3-compatible service
+    #[serde(default)]
+    pub s3_endpoint: Option<String>,
+    /// Region of the bucket, if the service has regions
+    #[serde(default)]
+    pub s3_region: Option<String>,
+    #[serde(default)]
+    pub s3_access_key: Option<String>,
+    #[serde(default)]
+    pub s3_secret_key: Option<String>,
+}
+
 /// The configuration for darkfid
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct DarkfidConfig {
     /// The address where darkfid should bind its RPC socket
     pub rpc_listen_address: SocketAddr,
+    /// A Unix socket to serve RPC on as well, as unix:///path/to/socket
+    #[serde(default)]
+    pub rpc_listen: Option<String>,
+    /// An address to serve only the methods that can't move funds, change
+    /// the wallet or read its keys on, e.g. for monitoring
+    #[serde(default)]
+    pub rpc_readonly_listen_address: Option<SocketAddr>,
+    /// Longest RPC request, or batch of requests, taken in bytes
+    #[serde(default = "default_rpc_max_request_size")]
+    pub rpc_max_request_size: usize,
     /// Whether to listen with TLS or plain TCP
     pub serve_tls: bool,
-    /// Path to DER-formatted PKCS#12 archive. (Unused if serve_tls=false)
-    pub tls_identity_path: String,
-    /// Password for the TLS identity. (Unused if serve_tls=false)
-    pub tls_identity_password: String,
+    /// Path to the PEM certificate chain to serve TLS with. (Unused if
+    /// serve_tls=false)
+    #[serde(default)]
+    pub tls_cert_path: Option<String>,
+    /// Path to the PEM PKCS#8 or RSA key of tls_cert_path. (Unused if
+    /// serve_tls=false)
+    #[serde(default)]
+    pub tls_key_path: Option<String>,
+    /// PEM CA certificate for clients' certificates. If set, transfers
+    /// and withdrawals need a client certificate it signed on top of the
+    /// rpc_token, while other methods can be called without.
+    #[serde(default)]
+    pub tls_client_ca_path: Option<String>,
+    /// A secret RPC requests have to carry to move funds, change the
+    /// wallet or read its keys. Anyone who reaches the RPC can if unset.
+    #[serde(default)]
+    pub rpc_token: Option<String>,
+    /// Where to serve Prometheus metrics over HTTP at /metrics, off if
+    /// not set
+    #[serde(default)]
+    pub metrics_listen_address: Option<SocketAddr>,
+    /// Where to serve JSON-RPC over WebSocket, with subscriptions to
+    /// payments, slabs and transaction statuses. Off if not set, and
+    /// needs rpc_token.
+    #[serde(default)]
+    pub ws_listen_address: Option<SocketAddr>,
+    /// Origins of the web pages allowed to connect over WebSocket, e.g.
+    /// "https://wallet.example". Clients outside a browser send none and
+    /// are always let through.
+    #[serde(default)]
+    pub ws_allowed_origins: Vec<String>,
+    /// Where to serve the REST facade over plain HTTP, off if not set.
+    /// Needs rpc_token.
+    #[serde(default)]
+    pub rest_listen_address: Option<SocketAddr>,
+    /// Origins of the web pages allowed to use the REST facade. Clients
+    /// outside a browser send none and are always let through.
+    #[serde(default)]
+    pub rest_allowed_origins: Vec<String>,
+    /// Where to serve the gRPC interface in proto/darkfid.proto, off if
+    /// not set. Only darkfid built with the grpc feature serves it.
+    #[serde(default)]
+    pub grpc_listen_address: Option<SocketAddr>,
     /// The endpoint to a gatewayd protocol API
     pub gateway_protocol_url: String,
     /// The endpoint to a gatewayd publisher API
     pub gateway_publisher_url: String,
-    /// Path to mint.params
-    pub mint_params_path: String,
-    /// Path to spend.params
-    pub spend_params_path: String,
-    /// Path to the client database
-    pub database_path: String,
-    /// Path to the wallet database
-    pub wallet_path: String,
+    /// Gateways to fail over to, in order, while the one above is
+    /// unreachable
+    #[serde(default)]
+    pub fallback_gateways: Vec<GatewayEndpoint>,
+    /// Seconds a gateway connection may be idle before it's pinged
+    #[serde(default = "default_heartbeat_interval")]
+    pub gateway_heartbeat_interval: u32,
+    /// Seconds a gateway has to answer a ping before the connection is
+    /// re-established
+    #[serde(default = "default_heartbeat_timeout")]
+    pub gateway_heartbeat_timeout: u32,
+    /// Seconds a gateway has to answer a request before the next one is
+    /// tried
+    #[serde(default = "default_gateway_request_timeout")]
+    pub gateway_request_timeout: u32,
+    /// Base58 encoded secret key to sign submitted slabs with, for
+    /// gateways only taking slabs from known publishers
+    #[serde(default)]
+    pub gateway_publisher_key: Option<String>,
+    /// Base58 encoded public keys of the gateways used. If set, only
+    /// slabs one of them signed are taken.
+    #[serde(default)]
+    pub gateway_keys: Vec<String>,
+    /// Seconds a cashier has to answer a request before it fails
+    #[serde(default = "default_cashier_request_timeout")]
+    pub cashier_request_timeout: u32,
+    /// PEM certificate to trust for tls:// gateways and cashiers besides
+    /// the system ones, e.g. a self-signed one
+    #[serde(default)]
+    pub tls_ca_cert_path: Option<String>,
+    /// SHA-256 fingerprints of the only certificates tls:// gateways and
+    /// cashiers may present. Pinned certificates needn't be CA signed.
+    #[serde(default)]
+    pub tls_pinned_certs: Vec<String>,
+    /// PEM certificate chain presented to tls:// gateways and cashiers
+    /// that ask for one, along with tls_client_key_path
+    #[serde(default)]
+    pub tls_client_cert_path: Option<String>,
+    /// Path to the PEM PKCS#8 or RSA key of tls_client_cert_path
+    #[serde(default)]
+    pub tls_client_key_path: Option<String>,
+    /// SOCKS5 proxy to reach gateways and cashiers through, e.g.
+    /// socks5://127.0.0.1:9050 for Tor
+    #[serde(default)]
+    pub socks5_proxy: Option<String>,
+    /// Directory holding the params, the client database and the wallet
+    #[serde(default = "default_datadir")]
+    pub datadir: String,
+    /// Directory holding mint.params and spend.params, if not the datadir
+    #[serde(default)]
+    pub params_path: Option<String>,
+    /// Where --fetch-params downloads the canonical mint.params from
+    #[serde(default)]
+    pub mint_params_url: Option<String>,
+    /// Hash the downloaded mint.params must have, if none is built in
+    #[serde(default)]
+    pub mint_params_hash: Option<String>,
+    /// Where --fetch-params downloads the canonical spend.params from
+    #[serde(default)]
+    pub spend_params_url: Option<String>,
+    /// Hash the downloaded spend.params must have, if none is built in
+    #[serde(default)]
+    pub spend_params_hash: Option<String>,
+    /// Deprecated: mint.params is migrated into the datadir
+    #[serde(default)]
+    pub mint_params_path: Option<String>,
+    /// Deprecated: spend.params is migrated into the datadir
+    #[serde(default)]
+    pub spend_params_path: Option<String>,
+    /// Deprecated: the client database is migrated into the datadir
+    #[serde(default)]
+    pub database_path: Option<String>,
+    /// Key-value store the database is kept in (rocksdb/sled), out of
+    /// the ones this build has. An existing database isn't converted.
+    #[serde(default = "default_kv_backend")]
+    pub kv_backend: String,
+    /// Deprecated: the wallet database is migrated into the datadir
+    #[serde(default)]
+    pub wallet_path: Option<String>,
     /// The wallet password
     pub wallet_password: String,
-    /// The configured cashiers to use
+    /// Bind the wallet key to the OS keyring, so the wallet file can't be
+    /// opened on another machine. The secret isn't sealed to a TPM. Needs
+    /// the secure-enclave feature, and falls back to the password alone
+    /// without it.
+    #[serde(default)]
+    pub secure_enclave: bool,
+    /// How coins are picked to fund a transfer
+    /// (smallest-first/largest-first/privacy)
+    #[serde(default = "default_coin_selection")]
+    pub coin_selection: String,
+    /// Largest payment to an address not in the address book, unlimited
+    /// if not set
+    #[serde(default)]
+    pub unknown_address_limit: Option<String>,
+    /// Seconds after a payment during which sending the same amount to
+    /// the same address again has to be allowed explicitly. 0 disables
+    /// the check.
+    #[serde(default = "default_duplicate_payment_window")]
+    pub duplicate_payment_window: u64,
+    /// Threads verifying zk proofs. Our own transactions are verified
+    /// ahead of the slabs being synced. Defaults to the number of CPUs.
+    #[serde(default = "default_verify_workers")]
+    pub verify_workers: usize,
+    /// Trust the gateways in gateway_keys to only sign valid slabs, and
+    /// skip verifying their proofs and keeping every nullifier. Only the
+    /// merkle roots, our own coins and their witnesses are kept.
+    #[serde(default)]
+    pub light_client: bool,
+    /// http(s) URL each payment to the wallet is POSTed to as JSON, with
+    /// its amount, memo, transaction id and slab index
+    #[serde(default)]
+    pub notify_url: Option<String>,
+    /// Network the addresses are for (mainnet/testnet), which sets their
+    /// bech32 prefix
+    #[serde(default = "default_address_network")]
+    pub address_network: String,
+    /// Signed list of the cashiers to use, see `CashierProvisioning`.
+    /// Defaults to cashiers.json in the datadir.
+    #[serde(default)]
+    pub cashier_provisioning_path: Option<String>,
+    /// Base58 encoded public key the cashier list must be signed with
+    pub provisioning_public_key: String,
+    /// Deprecated: unsigned cashiers are rejected, use the provisioning file
+    #[serde(default)]
     pub cashiers: Vec<Cashier>,
+    /// Scheduled wallet backups, off if not set
+    #[serde(default)]
+    pub backup: Option<BackupConfig>,
+    /// Scheduled state snapshots, off if not set
+    #[serde(default)]
+    pub snapshots: Option<SnapshotConfig>,
+    /// Debug log file, besides the log on stdout, off if not set
+    #[serde(default)]
+    pub log_file: Option<LogFileConfig>,
+    /// Tuning of the database when kv_backend is rocksdb
+    #[serde(default)]
+    pub rocksdb: RocksdbOptions,
 }
 
 /// The configuration for gatewayd
@@ -95,14 +431,120 @@ pub struct GatewaydConfig {
     pub protocol_listen_address: SocketAddr,
     /// The address where gatewayd should bind its publisher socket
     pub publisher_listen_address: SocketAddr,
-    /// Whether to listen with TLS or plain TCP
+    /// Whether to also serve the protocol and publisher over TLS
     pub serve_tls: bool,
-    /// Path to DER-formatted PKCS#12 archive. (Unused if serve_tls=false)
-    pub tls_identity_path: String,
-    /// Password for the TLS identity. (Unused if serve_tls=false)
-    pub tls_identity_password: String,
+    /// Where the protocol is served over TLS (Unused if serve_tls=false)
+    #[serde(default)]
+    pub tls_protocol_listen_address: Option<SocketAddr>,
+    /// Where the publisher is served over TLS (Unused if serve_tls=false)
+    #[serde(default)]
+    pub tls_publisher_listen_address: Option<SocketAddr>,
+    /// Path to the PEM certificate chain to serve TLS with. (Unused if
+    /// serve_tls=false)
+    #[serde(default)]
+    pub tls_cert_path: Option<String>,
+    /// Path to the PEM PKCS#8 or RSA key of tls_cert_path. (Unused if
+    /// serve_tls=false)
+    #[serde(default)]
+    pub tls_key_path: Option<String>,
+    /// PEM CA certificate clients have to present a certificate signed
+    /// by to connect over TLS. Anyone may connect if unset.
+    #[serde(default)]
+    pub tls_client_ca_path: Option<String>,
     /// Path to the database
     pub database_path: String,
+    /// Key-value store the database and shards are kept in
+    /// (rocksdb/sled), out of the ones this build has. Existing ones
+    /// aren't converted.
+    #[serde(default = "default_kv_backend")]
+    pub kv_backend: String,
+    /// Whether to compress large published slabs with zstd
+    #[serde(default = "default_compress_slabs")]
+    pub compress_slabs: bool,
+    /// Ranges of old slabs kept in databases of their own
+    #[serde(default)]
+    pub slab_shards: Vec<SlabShardConfig>,
+    /// Protocol urls of the gateways to replicate, in order of preference
+    #[serde(default)]
+    pub replicate_from: Vec<String>,
+    /// Base58 encoded public keys slabs have to be signed with. Anyone
+    /// may submit slabs if empty.
+    #[serde(default)]
+    pub publisher_keys: Vec<String>,
+    /// Base58 encoded secret key to sign accepted slabs with, so clients
+    /// can tell when the gateway rewrites its history
+    #[serde(default)]
+    pub signing_key: Option<String>,
+    /// Largest slab payload taken, in bytes. Slabs that don't hold a
+    /// transaction are refused whatever their size.
+    #[serde(default = "default_max_slab_size")]
+    pub max_slab_size: usize,
+    /// How fast each client may use the gateway
+    #[serde(default)]
+    pub rate_limits: GatewayRateLimits,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GatewayRateLimits {
+    /// Requests of any kind a second each connection may send, 0 for no
+    /// limit
+    pub requests_per_second: u64,
+    /// Requests a connection may send at once after idling
+    pub request_burst: u64,
+    /// Slabs a second each connection may submit, 0 for no limit
+    pub put_slabs_per_second: u64,
+    /// Slabs a connection may submit at once after idling
+    pub put_slab_burst: u64,
+    /// Connections an address may have open to each TLS socket, 0 for
+    /// no limit
+    pub max_connections_per_ip: usize,
+    /// Subscribers the TLS publisher socket takes at once, 0 for no limit
+    pub max_subscribers: usize,
+    /// Bytes of published slabs a TLS subscriber may fall behind on
+    /// before it's dropped, 0 for no limit
+    pub subscriber_queue_bytes: usize,
+    /// Bytes a second an address may send through the TLS sockets, 0 for
+    /// no limit
+    pub upload_bytes_per_second: u64,
+    /// Bytes an address may send at once after idling
+    pub upload_burst_bytes: u64,
+    /// Bytes a second an address is sent through the TLS sockets, 0 for
+    /// no limit
+    pub download_bytes_per_second: u64,
+    /// Bytes an address may be sent at once after idling
+    pub download_burst_bytes: u64,
+    /// Times within a minute a client may go over a limit before it's
+    /// disconnected
+    pub max_violations: u32,
+}
+
+impl Default for GatewayRateLimits {
+    fn default() -> Self {
+        Self {
+            requests_per_second: 50,
+            request_burst: 200,
+            put_slabs_per_second: 2,
+            put_slab_burst: 10,
+            max_connections_per_ip: 16,
+            max_subscribers: 1024,
+            subscriber_queue_bytes: 16 * 1024 * 1024,
+            upload_bytes_per_second: 256 * 1024,
+            upload_burst_bytes: 1024 * 1024,
+            download_bytes_per_second: 1024 * 1024,
+            download_burst_bytes: 8 * 1024 * 1024,
+            max_violations: 20,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SlabShardConfig {
+    /// Path to the shard's database
+    pub path: String,
+    /// First and last index of the slabs kept in the shard
+    pub first_index: u64,
+    pub last_index: u64,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -121,28 +563,75 @@ pub struct CashierdConfig {
     pub rpc_listen_address: SocketAddr,
     /// Whether to listen with TLS or plain TCP
     pub serve_tls: bool,
-    /// Path to DER-formatted PKCS#12 archive. (Unused if serve_tls=false)
-    pub tls_identity_path: String,
-    /// Password for the TLS identity. (Unused if serve_tls=false)
-    pub tls_identity_password: String,
+    /// Path to the PEM certificate chain to serve TLS with. (Unused if
+    /// serve_tls=false)
+    #[serde(default)]
+    pub tls_cert_path: Option<String>,
+    /// Path to the PEM PKCS#8 or RSA key of tls_cert_path. (Unused if
+    /// serve_tls=false)
+    #[serde(default)]
+    pub tls_key_path: Option<String>,
     /// The endpoint to a gatewayd protocol API
     pub gateway_protocol_url: String,
     /// The endpoint to a gatewayd publisher API
     pub gateway_publisher_url: String,
-    /// Path to mint.params
-    pub mint_params_path: String,
-    /// Path to spend.params
-    pub spend_params_path: String,
-    /// Path to cashierd wallet
-    pub cashier_wallet_path: String,
+    /// PEM certificate to trust for tls:// gateways and cashiers besides
+    /// the system ones, e.g. a self-signed one
+    #[serde(default)]
+    pub tls_ca_cert_path: Option<String>,
+    /// SHA-256 fingerprints of the only certificates tls:// gateways and
+    /// cashiers may present. Pinned certificates needn't be CA signed.
+    #[serde(default)]
+    pub tls_pinned_certs: Vec<String>,
+    /// PEM certificate chain presented to tls:// gateways and cashiers
+    /// that ask for one, along with tls_client_key_path
+    #[serde(default)]
+    pub tls_client_cert_path: Option<String>,
+    /// Path to the PEM PKCS#8 or RSA key of tls_client_cert_path
+    #[serde(default)]
+    pub tls_client_key_path: Option<String>,
+    /// SOCKS5 proxy to reach gateways and cashiers through, e.g.
+    /// socks5://127.0.0.1:9050 for Tor
+    #[serde(default)]
+    pub socks5_proxy: Option<String>,
+    /// Base58 encoded secret key to sign submitted slabs with, for
+    /// gateways only taking slabs from known publishers
+    #[serde(default)]
+    pub gateway_publisher_key: Option<String>,
+    /// Base58 encoded public keys of the gateways used. If set, only
+    /// slabs one of them signed are taken.
+    #[serde(default)]
+    pub gateway_keys: Vec<String>,
+    /// Directory holding the params, the wallets and the client database
+    #[serde(default = "default_datadir")]
+    pub datadir: String,
+    /// Deprecated: mint.params is migrated into the datadir
+    #[serde(default)]
+    pub mint_params_path: Option<String>,
+    /// Deprecated: spend.params is migrated into the datadir
+    #[serde(default)]
+    pub spend_params_path: Option<String>,
+    /// Deprecated: the cashierd wallet is migrated into the datadir
+    #[serde(default)]
+    pub cashier_wallet_path: Option<String>,
     /// Password for cashierd wallet
     pub cashier_wallet_password: String,
-    /// Path to client wallet
-    pub client_wallet_path: String,
+    /// Deprecated: the client wallet is migrated into the datadir
+    #[serde(default)]
+    pub client_wallet_path: Option<String>,
     /// Password for client wallet
     pub client_wallet_password: String,
-    /// Path to database
-    pub database_path: String,
+    /// Network the addresses are for (mainnet/testnet), which sets their
+    /// bech32 prefix
+    #[serde(default = "default_address_network")]
+    pub address_network: String,
+    /// Deprecated: the client database is migrated into the datadir
+    #[serde(default)]
+    pub database_path: Option<String>,
+    /// Key-value store the database is kept in (rocksdb/sled), out of
+    /// the ones this build has. An existing database isn't converted.
+    #[serde(default = "default_kv_backend")]
+    pub kv_backend: String,
     /// The configured networks to use
     pub networks: Vec<FeatureNetwork>,
 }
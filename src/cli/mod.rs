@@ -1,2 +1,5 @@
 pub mod cli_config;
-pub use cli_config::{CashierdConfig, Config, DarkfidConfig, DrkConfig, GatewaydConfig};
+pub use cli_config::{
+    BackupConfig, CashierdConfig, Config, DarkfidConfig, DrkConfig, GatewayEndpoint,
+    GatewayRateLimits, GatewaydConfig, LogFileConfig, SlabShardConfig, SnapshotConfig,
+};
@@ -3,23 +3,49 @@ use async_std::sync::{Arc, Mutex};
 use bellman::groth16;
 use bls12_381::Bls12;
 use log::{debug, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use url::Url;
 
 use crate::{
-    blockchain::{rocks::columns, Rocks, RocksColumn, Slab},
+    blockchain::{
+        kv::{columns, KvBatch},
+        pending::{pending_id, REBROADCAST_CHECK_INTERVAL},
+        undo::UNDO_HISTORY_SIZE,
+        KvColumn, KvStore, PendingTxs, Slab, SlabHash, SlabMetric, SlabSequencer, SlabStore,
+        SyncMetrics, UndoRecord,
+    },
     crypto::{
-        merkle::{CommitmentTree, IncrementalWitness},
+        coin::Coin,
+        merkle::{CommitmentTree, IncrementalWitness, MerklePath},
         merkle_node::MerkleNode,
-        note::{EncryptedNote, Note},
+        merkle_proof,
+        note::{EncryptedNote, EncryptedOutgoingNote, Memo, Note, OutgoingNote},
         nullifier::Nullifier,
-        OwnCoin,
+        sapling, schnorr, Address, BatchVerifier, BatchVerifyingKey, OwnCoin, VerifyHandle,
+        VerifyLane, VerifyPool, ViewingKey,
+    },
+    net::utility::sleep,
+    rpc::prometheus::METRICS,
+    serial::{deserialize, serialize, Decodable, Encodable},
+    service::{
+        GatewayClient, GatewaySlabsSubscriber, GatewayStats, Heartbeat, Socks5Proxy,
+        TlsClientConfig,
     },
-    serial::{serialize, Decodable, Encodable},
-    service::{GatewayClient, GatewaySlabsSubscriber},
-    state::{state_transition, ProgramState, StateUpdate},
+    state::{
+        state_transition_batched, trusted_transition, ProgramState, StateEvent, StateUpdate,
+        VerifyResult,
+    },
+    system::{Subscriber, SubscriberPtr},
     tx,
-    wallet::{walletdb::Balances, CashierDbPtr, Keypair, WalletPtr},
-    Result,
+    wallet::{
+        privacy::{self, PrivacyFinding},
+        rescan::{frontier_witnesses, rebuild_witnesses},
+        walletdb::{Balance, Balances, DEFAULT_ACCOUNT},
+        CashierDbPtr, CoinSelection, CoinSelectionStrategy, FailureReason, Keypair, PaymentPolicy,
+        RescanProgress, TxDirection, TxHistory, TxStatus, WalletPtr,
+    },
+    Error, Result,
 };
 
 #[derive(Debug)]
@@ -27,6 +53,7 @@ pub enum ClientFailed {
     NotEnoughValue(u64),
     InvalidAddress(String),
     InvalidAmount(u64),
+    AmountOverflow,
     UnableToGetDepositAddress,
     UnableToGetWithdrawAddress,
     DoesNotHaveCashierPublicKey,
@@ -34,29 +61,53 @@ pub enum ClientFailed {
     EmptyPassword,
     WalletInitialized,
     KeyExists,
+    WalletFrozen,
+    UnlockRefused(String),
+    ProofRejected(String),
+    GatewayRejected(String),
+    PolicyRefused(String),
     ClientError(String),
 }
 
 pub struct Client {
-    mint_params: bellman::groth16::Parameters<Bls12>,
-    spend_params: bellman::groth16::Parameters<Bls12>,
+    mint_params: Arc<bellman::groth16::Parameters<Bls12>>,
+    spend_params: Arc<bellman::groth16::Parameters<Bls12>>,
+    mint_pvk: Arc<groth16::PreparedVerifyingKey<Bls12>>,
+    spend_pvk: Arc<groth16::PreparedVerifyingKey<Bls12>>,
+    verify_pool: Arc<VerifyPool>,
     gateway: GatewayClient,
     wallet: WalletPtr,
+    coin_selection: CoinSelectionStrategy,
+    policy: PaymentPolicy,
+    rescan_progress: Arc<RescanProgress>,
+    // Coins picked for transactions that aren't published yet. They can't
+    // be picked again until theirs is published, which spends them, or
+    // fails, which releases them.
+    reserved: HashSet<[u8; 32]>,
+    // Our published transactions whose slab hasn't come back yet
+    pending: Arc<PendingTxs>,
     pub main_keypair: Keypair,
 }
 
 impl Client {
     pub async fn new(
-        rocks: Arc<Rocks>,
-        gateway_addrs: (Url, Url),
+        store: Arc<dyn KvStore>,
+        gateways: Vec<(Url, Url)>,
+        tls: TlsClientConfig,
+        proxy: Option<Socks5Proxy>,
         wallet: WalletPtr,
         mint_params: bellman::groth16::Parameters<Bls12>,
         spend_params: bellman::groth16::Parameters<Bls12>,
+        verify_pool: Arc<VerifyPool>,
     ) -> Result<Self> {
         wallet.init_db().await?;
 
         if wallet.get_keypairs()?.is_empty() {
-            wallet.key_gen()?;
+            wallet.seed_gen()?;
+            info!(
+                target: "CLIENT",
+                "Generated a new wallet seed. Back it up with `drk wallet --mnemonic`"
+            );
         }
 
         let main_keypair = wallet.get_keypairs()?[0].clone();
@@ -66,139 +117,422 @@ impl Client {
             bs58::encode(&serialize(&main_keypair.public)).into_string()
         );
 
-        let slabstore = RocksColumn::<columns::Slabs>::new(rocks.clone());
+        let pending = Arc::new(PendingTxs::new(KvColumn::new(store.clone())));
+        let slabstore = KvColumn::<columns::Slabs>::new(store);
 
         // create gateway client
         debug!(target: "CLIENT", "Creating GatewayClient");
-        let gateway = GatewayClient::new(gateway_addrs.0, gateway_addrs.1, slabstore)?;
+        let gateway = GatewayClient::new(gateways, tls, proxy, slabstore)?;
 
         Ok(Self {
-            mint_params,
-            spend_params,
+            mint_pvk: Arc::new(groth16::prepare_verifying_key(&mint_params.vk)),
+            spend_pvk: Arc::new(groth16::prepare_verifying_key(&spend_params.vk)),
+            mint_params: Arc::new(mint_params),
+            spend_params: Arc::new(spend_params),
+            verify_pool,
             wallet,
             gateway,
+            coin_selection: CoinSelectionStrategy::default(),
+            policy: PaymentPolicy::default(),
+            rescan_progress: Arc::new(RescanProgress::default()),
+            reserved: HashSet::new(),
+            pending,
             main_keypair,
         })
     }
 
-    pub async fn start(&mut self) -> Result<()> {
-        self.gateway.start().await?;
+    // Strategy used when a transfer doesn't ask for a specific one
+    pub fn set_coin_selection(&mut self, strategy: CoinSelectionStrategy) {
+        self.coin_selection = strategy;
+    }
+
+    pub fn set_payment_policy(&mut self, policy: PaymentPolicy) {
+        self.policy = policy;
+    }
+
+    // How idle gateway connections are checked, set before the
+    // subscription is started
+    pub fn set_gateway_heartbeat(&mut self, heartbeat: Heartbeat) {
+        self.gateway.set_heartbeat(heartbeat);
+    }
+
+    // Seconds a gateway has to answer before the next one is tried, set
+    // before the client is started
+    pub fn set_gateway_request_timeout(&mut self, seconds: u32) {
+        self.gateway.set_request_timeout(seconds);
+    }
+
+    // Key the slabs we submit are signed with, for gateways that only
+    // take them from known publishers
+    pub fn set_gateway_publisher_key(&mut self, key: schnorr::SecretKey) {
+        self.gateway.set_publisher_key(key);
+    }
+
+    // Keys of the gateways one of which has to sign each slab we take
+    pub fn set_gateway_keys(&mut self, keys: Vec<jubjub::SubgroupPoint>) {
+        self.gateway.set_gateway_keys(keys);
+    }
+
+    pub fn gateway_stats(&self) -> Arc<Mutex<GatewayStats>> {
+        self.gateway.stats()
+    }
+
+    /// Fee per kilobyte of slab the gateway has been admitting at lately
+    pub async fn get_going_rate(&mut self) -> Result<u64> {
+        self.gateway.get_going_rate().await
+    }
+
+    pub fn verify_pool(&self) -> Arc<VerifyPool> {
+        self.verify_pool.clone()
+    }
+
+    pub fn rescan_progress(&self) -> Arc<RescanProgress> {
+        self.rescan_progress.clone()
+    }
+
+    pub async fn start(&mut self, last_applied_index: u64) -> Result<()> {
+        self.gateway.start(last_applied_index).await?;
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn transfer(
         &mut self,
         token_id: jubjub::Fr,
-        pub_key: jubjub::SubgroupPoint,
+        address: Address,
         amount: u64,
-    ) -> ClientResult<()> {
+        memo: Memo,
+        fee: u64,
+        strategy: Option<CoinSelectionStrategy>,
+        confirmed: bool,
+        allow_duplicate: bool,
+    ) -> ClientResult<SlabHash> {
+        self.transfer_many(
+            token_id,
+            vec![(address, amount, memo)],
+            fee,
+            strategy,
+            confirmed,
+            allow_duplicate,
+        )
+        .await
+    }
+
+    // Pay several recipients in a single transaction. `confirmed` says
+    // the user confirmed paying recipients the wallet never paid before,
+    // and `allow_duplicate` repeating payments it sent recently. Returns
+    // the id of the transaction, to follow its status with.
+    pub async fn transfer_many(
+        &mut self,
+        token_id: jubjub::Fr,
+        recipients: Vec<(Address, u64, Memo)>,
+        fee: u64,
+        strategy: Option<CoinSelectionStrategy>,
+        confirmed: bool,
+        allow_duplicate: bool,
+    ) -> ClientResult<SlabHash> {
+        let amount = total_amount(&recipients)?;
+
         debug!(target: "CLIENT", "Start transfer {}", amount);
 
+        self.check_policy(&recipients, &token_id, confirmed, allow_duplicate)?;
+
         let token_id_exists = self.wallet.token_id_exists(&token_id)?;
 
-        if token_id_exists {
-            self.send(pub_key, amount, token_id, false).await?;
-        } else {
+        if !token_id_exists {
             return Err(ClientFailed::NotEnoughValue(amount));
         }
+        let tx_hash = self
+            .send_many(recipients, token_id, false, fee, strategy)
+            .await?;
 
         debug!(target: "CLIENT", "End transfer {}", amount);
 
-        Ok(())
+        Ok(tx_hash)
     }
 
+    // Pay a key without a viewing key, as the cashier does for deposits
     pub async fn send(
         &mut self,
         pub_key: jubjub::SubgroupPoint,
         amount: u64,
         token_id: jubjub::Fr,
         clear_input: bool,
-    ) -> ClientResult<()> {
-        debug!(target: "CLIENT", "Start send {}", amount);
+        fee: u64,
+        strategy: Option<CoinSelectionStrategy>,
+    ) -> ClientResult<SlabHash> {
+        self.send_many(
+            vec![(Address::legacy(pub_key), amount, Memo::empty())],
+            token_id,
+            clear_input,
+            fee,
+            strategy,
+        )
+        .await
+    }
 
-        if amount == 0 {
-            return Err(ClientFailed::InvalidAmount(amount as u64));
+    pub async fn send_many(
+        &mut self,
+        recipients: Vec<(Address, u64, Memo)>,
+        token_id: jubjub::Fr,
+        clear_input: bool,
+        fee: u64,
+        strategy: Option<CoinSelectionStrategy>,
+    ) -> ClientResult<SlabHash> {
+        if recipients.is_empty() {
+            return Err(ClientFailed::InvalidAmount(0));
         }
 
-        let slab = self
-            .build_slab_from_tx(pub_key, amount, token_id, clear_input)
+        for (_, amount, _) in recipients.iter() {
+            if *amount == 0 {
+                return Err(ClientFailed::InvalidAmount(*amount));
+            }
+        }
+
+        if self.wallet.is_frozen()? {
+            return Err(ClientFailed::WalletFrozen);
+        }
+
+        debug!(target: "CLIENT", "Start send to {} recipients", recipients.len());
+
+        let (mut slab, change_coin, coins) = self
+            .build_slab_from_tx(&recipients, token_id, clear_input, fee, strategy)
             .await?;
+        let tx_ids = match self.prepare_send(&slab, change_coin, &recipients, token_id).await {
+            Ok(tx_ids) => tx_ids,
+            Err(e) => {
+                self.release_coins(&coins);
+                return Err(e);
+            }
+        };
 
-        self.gateway.put_slab(slab).await?;
+        let tx_hash = pending_id(&slab.get_payload());
+        let index = self
+            .publish(&slab, fee, &coins)
+            .await
+            .map_err(|e| ClientFailed::GatewayRejected(e.to_string()))?;
+        slab.set_index(index);
+        for tx_id in tx_ids.iter() {
+            self.wallet
+                .set_tx_history_slab(*tx_id, index, &slab.hash())?;
+        }
+        self.track_pending(&slab, fee, tx_ids);
 
-        debug!(target: "CLIENT", "End send {}", amount);
+        debug!(target: "CLIENT", "End send to {} recipients", recipients.len());
 
-        Ok(())
+        Ok(tx_hash)
     }
 
-    async fn build_slab_from_tx(
+    // Check a transaction we built and record it in the history, before
+    // it's published. Returns the ids of its history entries.
+    async fn prepare_send(
         &self,
-        pub_key: jubjub::SubgroupPoint,
-        value: u64,
+        slab: &Slab,
+        change_coin: Option<Coin>,
+        recipients: &[(Address, u64, Memo)],
+        token_id: jubjub::Fr,
+    ) -> ClientResult<Vec<i64>> {
+        self.verify_own_slab(slab)
+            .await
+            .map_err(|e| ClientFailed::ProofRejected(e.to_string()))?;
+
+        // An emergency stop while the transaction was being built cancels
+        // it before anything is recorded or broadcast
+        self.check_not_frozen()?;
+
+        // One history entry per recipient. The change coin only needs to
+        // be recorded once.
+        let timestamp = unix_timestamp();
+        let mut tx_ids = vec![];
+        for (i, (address, amount, memo)) in recipients.iter().enumerate() {
+            let entry = TxHistory {
+                direction: TxDirection::Sent,
+                amount: *amount,
+                token_id,
+                counterparty: Some(address.public),
+                slab_index: None,
+                slab_hash: None,
+                timestamp,
+                memo: *memo,
+            };
+            let change_coin = if i == 0 { change_coin.as_ref() } else { None };
+            tx_ids.push(self.wallet.put_tx_history(&entry, change_coin)?);
+        }
+        Ok(tx_ids)
+    }
+
+    // Build a transaction paying the recipients. Returns its slab, its
+    // change coin and the coins it spends, which stay reserved until it's
+    // published or fails.
+    async fn build_slab_from_tx(
+        &mut self,
+        recipients: &[(Address, u64, Memo)],
         token_id: jubjub::Fr,
         clear_input: bool,
-    ) -> Result<Slab> {
+        fee: u64,
+        strategy: Option<CoinSelectionStrategy>,
+    ) -> ClientResult<(Slab, Option<Coin>, Vec<Coin>)> {
         debug!(target: "CLIENT", "Start build slab from tx");
 
+        let value = total_amount(recipients)?;
+
         let mut clear_inputs: Vec<tx::TransactionBuilderClearInputInfo> = vec![];
-        let mut inputs: Vec<tx::TransactionBuilderInputInfo> = vec![];
         let mut outputs: Vec<tx::TransactionBuilderOutputInfo> = vec![];
 
-        if clear_input {
-            let signature_secret = self.main_keypair.private;
-            let input = tx::TransactionBuilderClearInputInfo {
-                value,
-                token_id,
-                signature_secret,
-            };
-            clear_inputs.push(input);
+        let (inputs, coins) = if clear_input {
+            let minter = tx::Minter::new(self.main_keypair.private);
+            clear_inputs.push(minter.clear_input(value, token_id));
+            (vec![], vec![])
         } else {
-            inputs = self.build_inputs(value, token_id, &mut outputs).await?;
-        }
+            let strategy = strategy.unwrap_or(self.coin_selection);
+            let value = value.checked_add(fee).ok_or(ClientFailed::AmountOverflow)?;
+            self.build_inputs(value, token_id, &strategy).await?
+        };
 
-        outputs.push(tx::TransactionBuilderOutputInfo {
-            value,
-            token_id,
-            public: pub_key,
-        });
+        for (address, amount, memo) in recipients {
+            outputs.push(tx::TransactionBuilderOutputInfo {
+                value: *amount,
+                token_id,
+                public: address.public,
+                view_public: address.view_public,
+                memo: *memo,
+            });
+        }
 
-        let builder = tx::TransactionBuilder {
+        let mut builder = tx::TransactionBuilder {
             clear_inputs,
             inputs,
             outputs,
+            fee,
+            out_public: Some(self.main_keypair.public),
         };
 
+        let has_change = builder.change_value() > 0;
+        if has_change {
+            let address = match self.change_address() {
+                Ok(address) => address,
+                Err(e) => {
+                    self.release_coins(&coins);
+                    return Err(e.into());
+                }
+            };
+            builder.add_change_output(token_id, address);
+        }
+
         let mut tx_data = vec![];
+        let change_coin;
         {
-            let tx = builder.build(&self.mint_params, &self.spend_params);
+            // Proving runs off the executor, which keeps applying slabs
+            let proving = Instant::now();
+            let tx = builder
+                .build_async(self.mint_params.clone(), self.spend_params.clone())
+                .await;
+            METRICS.proof_generation.observe(proving.elapsed());
             tx.encode(&mut tx_data).expect("encode tx");
+            change_coin = if has_change {
+                Some(Coin::new(tx.outputs[0].revealed.coin))
+            } else {
+                None
+            };
         }
 
         let slab = Slab::new(tx_data);
 
         debug!(target: "CLIENT", "End build slab from tx");
 
-        Ok(slab)
+        Ok((slab, change_coin, coins))
     }
 
-    async fn build_inputs(
+    // Refuse the whole transfer if any payment breaks the policy
+    fn check_policy(
         &self,
+        recipients: &[(Address, u64, Memo)],
+        token_id: &jubjub::Fr,
+        confirmed: bool,
+        allow_duplicate: bool,
+    ) -> ClientResult<()> {
+        let history = if allow_duplicate || self.policy.duplicate_window == 0 {
+            vec![]
+        } else {
+            self.wallet.get_tx_history()?
+        };
+        let now = unix_timestamp();
+
+        for (address, amount, _) in recipients {
+            let recipient = format!("address {}", address.fingerprint());
+            let contact = self.wallet.get_contact(&address.public)?;
+            let paid_before = self.wallet.has_paid(&address.public)?;
+            self.policy
+                .check(
+                    &recipient,
+                    contact.as_ref(),
+                    *amount,
+                    paid_before,
+                    confirmed,
+                )
+                .and_then(|()| {
+                    self.policy.check_duplicate(
+                        &recipient,
+                        &address.public,
+                        *amount,
+                        token_id,
+                        &history,
+                        now,
+                        allow_duplicate,
+                    )
+                })
+                .map_err(|e| ClientFailed::PolicyRefused(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    fn check_not_frozen(&self) -> Result<()> {
+        if self.wallet.is_frozen()? {
+            return Err(ClientFailed::WalletFrozen.into());
+        }
+        Ok(())
+    }
+
+    // Catch a broken transaction before it's broadcast. It is verified
+    // ahead of the slabs being synced, so the user isn't kept waiting.
+    async fn verify_own_slab(&self, slab: &Slab) -> Result<()> {
+        let tx = tx::Transaction::decode(&slab.get_payload()[..])?;
+        let (mint_pvk, spend_pvk) = (self.mint_pvk.clone(), self.spend_pvk.clone());
+        self.verify_pool
+            .run(VerifyLane::Own, move || tx.verify(&mint_pvk, &spend_pvk))
+            .await??;
+        Ok(())
+    }
+
+    // Pick coins covering `amount` and reserve them. Returns the inputs
+    // spending them along with the coins.
+    async fn build_inputs(
+        &mut self,
         amount: u64,
         token_id: jubjub::Fr,
-        outputs: &mut Vec<tx::TransactionBuilderOutputInfo>,
-    ) -> Result<Vec<tx::TransactionBuilderInputInfo>> {
+        selection: &dyn CoinSelection,
+    ) -> ClientResult<(Vec<tx::TransactionBuilderInputInfo>, Vec<Coin>)> {
         debug!(target: "CLIENT", "Start build inputs");
 
         let mut inputs: Vec<tx::TransactionBuilderInputInfo> = vec![];
         let mut inputs_value: u64 = 0;
 
-        let own_coins = self.wallet.get_own_coins()?;
+        let own_coins: Vec<OwnCoin> = self
+            .wallet
+            .get_own_coins()?
+            .into_iter()
+            .filter(|c| c.note.token_id == token_id && !self.reserved.contains(&c.coin.repr))
+            .collect();
+        let available: u64 = own_coins.iter().map(|c| c.note.value).sum();
+
+        let own_coins = match selection.select(own_coins, amount) {
+            Some(coins) => coins,
+            None => return Err(ClientFailed::NotEnoughValue(available)),
+        };
+        let mut spent_coins = vec![];
 
         for own_coin in own_coins.iter() {
-            if inputs_value >= amount {
-                break;
-            }
-            self.wallet.confirm_spend_coin(&own_coin.coin)?;
+            spent_coins.push(own_coin.coin.clone());
             let witness = &own_coin.witness;
             let merkle_path = witness.path().unwrap();
             inputs_value += own_coin.note.value;
@@ -212,22 +546,270 @@ impl Client {
         }
 
         if inputs_value < amount {
-            return Err(ClientFailed::NotEnoughValue(inputs_value).into());
+            return Err(ClientFailed::NotEnoughValue(inputs_value));
         }
 
-        if inputs_value > amount {
-            let return_value: u64 = inputs_value - amount;
+        self.reserved.extend(spent_coins.iter().map(|coin| coin.repr));
 
-            outputs.push(tx::TransactionBuilderOutputInfo {
-                value: return_value,
+        debug!(target: "CLIENT", "End build inputs");
+
+        Ok((inputs, spent_coins))
+    }
+
+    // Let coins reserved for a transaction that won't be published be
+    // picked again
+    fn release_coins(&mut self, coins: &[Coin]) {
+        for coin in coins {
+            self.reserved.remove(&coin.repr);
+        }
+    }
+
+    // Mark the coins of a published transaction spent, recording that
+    // they were spent together
+    fn spend_coins(&mut self, coins: &[Coin]) -> Result<()> {
+        if coins.is_empty() {
+            return Ok(());
+        }
+        for coin in coins {
+            self.wallet.confirm_spend_coin(coin)?;
+        }
+        self.wallet.put_spend(coins)?;
+        self.release_coins(coins);
+        Ok(())
+    }
+
+    // Our coins spent by a transaction, found by their nullifiers
+    fn own_inputs(&self, tx: &tx::Transaction) -> Result<Vec<Coin>> {
+        Ok(self
+            .wallet
+            .get_own_nullifiers()?
+            .into_iter()
+            .filter(|(_, nullifier)| {
+                tx.inputs
+                    .iter()
+                    .any(|input| input.revealed.nullifier.repr == nullifier.repr)
+            })
+            .map(|(coin, _)| coin)
+            .collect())
+    }
+
+    // Change is paid to a fresh address derived from the wallet seed, so
+    // it isn't linked to any address we gave out. Wallets created before
+    // seeds existed fall back to the main address.
+    fn change_address(&self) -> Result<Address> {
+        match self.wallet.derive_keypair(DEFAULT_ACCOUNT) {
+            Ok(keypair) => Ok(Address::from_secret(&keypair.private)),
+            Err(Error::MnemonicError(_)) => Ok(self.address()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The address of the main keypair
+    pub fn address(&self) -> Address {
+        Address::from_secret(&self.main_keypair.private)
+    }
+
+    // A fresh address of the main key, unlinkable to the ones handed out
+    // before it
+    pub fn new_diversified_address(&self, label: Option<&str>) -> Result<Address> {
+        let diversifier = self.wallet.new_diversifier(&self.main_keypair)?;
+        if let Some(label) = label {
+            self.wallet.set_address_label(&diversifier, label)?;
+        }
+        Ok(Address::diversified(
+            &self.main_keypair.private,
+            &diversifier,
+        ))
+    }
+
+    // The addresses of the main key handed out so far, oldest first, with
+    // their labels
+    pub fn diversified_addresses(&self) -> Result<Vec<(Address, Option<String>)>> {
+        let diversifiers = self.wallet.get_labeled_diversifiers(&self.main_keypair.public)?;
+        Ok(diversifiers
+            .into_iter()
+            .map(|(diversifier, label)| {
+                (Address::diversified(&self.main_keypair.private, &diversifier), label)
+            })
+            .collect())
+    }
+
+    // Fund `amount` of a jointly built transaction from our own coins. The
+    // returned signer must be kept until the transaction is finalized.
+    // The coins stay reserved until the transaction is broadcast.
+    pub async fn cobuild_contribute(
+        &mut self,
+        cobuild: &mut tx::CoBuildTransaction,
+        amount: u64,
+    ) -> Result<tx::CoBuildSigner> {
+        self.check_not_frozen()?;
+        let token_id = cobuild.output.token_id;
+        let selection = self.coin_selection;
+        let (inputs, coins) = self.build_inputs(amount, token_id, &selection).await?;
+
+        let inputs_value: u64 = inputs.iter().map(|i| i.note.value).sum();
+        let mut change = vec![];
+        if inputs_value > amount {
+            let address = match self.change_address() {
+                Ok(address) => address,
+                Err(e) => {
+                    self.release_coins(&coins);
+                    return Err(e);
+                }
+            };
+            change.push(tx::TransactionBuilderOutputInfo {
+                value: inputs_value - amount,
                 token_id,
-                public: self.main_keypair.public,
+                public: address.public,
+                view_public: address.view_public,
+                memo: Memo::empty(),
             });
         }
 
-        debug!(target: "CLIENT", "End build inputs");
+        let signer = cobuild.contribute(inputs, change, &self.mint_params, &self.spend_params);
+        if signer.is_err() {
+            self.release_coins(&coins);
+        }
+        signer
+    }
+
+    pub async fn cobuild_finalize(&self, cobuild: &mut tx::CoBuildTransaction) -> Result<()> {
+        cobuild.finalize(&self.mint_params)
+    }
+
+    pub async fn cobuild_broadcast(&mut self, cobuild: tx::CoBuildTransaction) -> Result<()> {
+        self.broadcast(cobuild.combine()?).await?;
+        Ok(())
+    }
+
+    // Submit a transaction built elsewhere as it is. Returns the index of
+    // its slab.
+    pub async fn broadcast(&mut self, tx: tx::Transaction) -> Result<u64> {
+        let mut tx_data = vec![];
+        tx.encode(&mut tx_data)?;
+
+        let slab = Slab::new(tx_data);
+        let coins = self.own_inputs(&tx)?;
+        let index = self.publish(&slab, tx.fee, &coins).await?;
+        self.track_pending(&slab, tx.fee, vec![]);
+        Ok(index)
+    }
+
+    // Hand a slab of ours to the gateway, keeping its status in the
+    // wallet. The coins it spends are marked spent once it's published,
+    // and released if it isn't. Returns the index the gateway stored it
+    // at.
+    async fn publish(&mut self, slab: &Slab, fee: u64, coins: &[Coin]) -> Result<u64> {
+        let tx_hash = pending_id(&slab.get_payload());
+        let published = match self.wallet.set_tx_status(&tx_hash, TxStatus::Pending) {
+            Ok(()) => self.gateway.put_slab(slab.clone(), fee).await,
+            Err(e) => Err(e),
+        };
+        match published {
+            Ok(index) => {
+                self.spend_coins(coins)?;
+                self.wallet.set_tx_status(&tx_hash, TxStatus::Published)?;
+                Ok(index)
+            }
+            Err(e) => {
+                self.release_coins(coins);
+                self.wallet.set_tx_status(&tx_hash, TxStatus::Rejected)?;
+                Err(e)
+            }
+        }
+    }
+
+    // Keep a published transaction until its slab comes back, unless it
+    // already did. It's out already, so failing to keep it only costs the
+    // rebroadcasts.
+    fn track_pending(&self, slab: &Slab, fee: u64, tx_ids: Vec<i64>) {
+        let payload = slab.get_payload();
+        let added = match self.wallet.get_tx_status(&pending_id(&payload)) {
+            Ok(Some((TxStatus::Confirmed(_), _))) => return,
+            _ => self.pending.add(payload, fee, tx_ids, unix_timestamp()),
+        };
+        if let Err(e) = added {
+            warn!(target: "CLIENT", "Can't track the pending transaction: {}", e);
+        }
+    }
+
+    // Publish again the transactions whose slab hasn't come back within
+    // REBROADCAST_INTERVAL, e.g. because the gateway dropped it, and give
+    // up on the ones pending for too long
+    pub async fn rebroadcast_pending(&mut self) -> Result<()> {
+        let now = unix_timestamp();
+        let (due, expired) = self.pending.due(now)?;
+        for tx in expired {
+            let tx_hash = pending_id(&tx.payload);
+            warn!(
+                target: "CLIENT",
+                "Gave up on transaction {} after {} broadcasts",
+                hex::encode(tx_hash),
+                tx.broadcasts
+            );
+            self.wallet.set_tx_status(&tx_hash, TxStatus::Expired)?;
+        }
+
+        for tx in due {
+            debug!(
+                target: "CLIENT",
+                "Rebroadcast transaction {}", hex::encode(pending_id(&tx.payload))
+            );
+            match self
+                .gateway
+                .put_slab(Slab::new(tx.payload.clone()), tx.fee)
+                .await
+            {
+                Ok(_) => self.pending.rebroadcast(&tx, now)?,
+                // Tried again at the next check
+                Err(e) => warn!(target: "CLIENT", "Rebroadcast failed: {}", e),
+            }
+        }
+        Ok(())
+    }
+
+    // Check for pending transactions to rebroadcast every
+    // REBROADCAST_CHECK_INTERVAL
+    pub fn start_rebroadcast(client: Arc<Mutex<Client>>, executor: Arc<Executor<'_>>) {
+        executor
+            .spawn(async move {
+                loop {
+                    sleep(REBROADCAST_CHECK_INTERVAL).await;
+                    if let Err(e) = client.lock().await.rebroadcast_pending().await {
+                        warn!(target: "CLIENT", "Pending transactions: {}", e);
+                    }
+                }
+            })
+            .detach();
+    }
+
+    // Our transactions whose slab came back are confirmed and no longer
+    // pending, and their history points at the index the slab ended up at
+    fn confirm_pending(pending: &PendingTxs, wallet: &WalletPtr, slabs: &[Slab]) -> Result<()> {
+        let unconfirmed = wallet.get_unconfirmed_txs()?;
+        for slab in slabs {
+            let tx_hash = pending_id(&slab.get_payload());
+            if unconfirmed.contains(&tx_hash) {
+                wallet.set_tx_status(&tx_hash, TxStatus::Confirmed(slab.get_index()))?;
+            }
+            if let Some(tx) = pending.confirm(slab)? {
+                debug!(target: "CLIENT", "Own transaction seen in slab {}", slab.get_index());
+                for tx_id in tx.tx_ids {
+                    wallet.set_tx_history_slab(tx_id, slab.get_index(), &slab.hash())?;
+                }
+            }
+        }
+        Ok(())
+    }
 
-        Ok(inputs)
+    // The params transactions are proven with
+    pub fn proving_params(
+        &self,
+    ) -> (
+        Arc<bellman::groth16::Parameters<Bls12>>,
+        Arc<bellman::groth16::Parameters<Bls12>>,
+    ) {
+        (self.mint_params.clone(), self.spend_params.clone())
     }
 
     pub async fn connect_to_subscriber_from_cashier(
@@ -242,48 +824,42 @@ impl Client {
         let gateway_slabs_sub: GatewaySlabsSubscriber =
             self.gateway.start_subscriber(executor.clone()).await?;
 
-        let secret_key = self.main_keypair.private;
         let wallet = self.wallet.clone();
+        let slabstore = self.gateway.get_slabstore();
+        let pending = self.pending.clone();
+        let mut sequencer = SlabSequencer::new(state.lock().await.last_applied_index()?);
 
         let task: smol::Task<Result<()>> = executor.spawn(async move {
             loop {
                 let slab = gateway_slabs_sub.recv().await?;
+                let arrived = Instant::now();
 
                 debug!(target: "CLIENT", "Received new slab");
 
-                debug!(target: "CLIENT", "Starting build tx from slab");
-                let tx = tx::Transaction::decode(&slab.get_payload()[..]);
-
-                if let Err(e) = tx {
-                    warn!("TX: {}", e.to_string());
-                    continue;
-                }
+                let slabs = Self::drain_slabs(slab, &gateway_slabs_sub);
 
                 let mut state = state.lock().await;
-
-                let update = state_transition(&state, tx?);
-
-                if let Err(e) = update {
-                    warn!("state transition: {}", e.to_string());
+                let slabs = Self::sequence_slabs(&mut sequencer, slabs, &state, &slabstore)?;
+                if slabs.is_empty() {
                     continue;
                 }
 
-                let mut secret_keys: Vec<jubjub::Fr> = vec![secret_key];
+                let mut secret_keys: Vec<jubjub::Fr> = wallet.get_private_keys()?;
                 let mut withdraw_keys = cashier_wallet.get_withdraw_private_keys()?;
                 secret_keys.append(&mut withdraw_keys);
 
-                let state_apply = state
-                    .apply(
-                        update?,
-                        secret_keys.clone(),
+                state
+                    .apply_slabs(
+                        &slabs,
+                        Some(arrived),
+                        secret_keys,
                         Some(notify.clone()),
                         wallet.clone(),
                     )
-                    .await;
+                    .await?;
 
-                if let Err(e) = state_apply {
-                    warn!("apply state: {}", e.to_string());
-                    continue;
+                if let Err(e) = Self::confirm_pending(&pending, &wallet, &slabs) {
+                    warn!(target: "CLIENT", "Confirm pending transactions: {}", e);
                 }
             }
         });
@@ -293,61 +869,233 @@ impl Client {
         Ok(())
     }
 
+    // Apply the slabs the gateway sends. The task is handed back so it
+    // can be cancelled on shutdown, once it's done with the state.
     pub async fn connect_to_subscriber(
         &self,
         state: Arc<Mutex<State>>,
         executor: Arc<Executor<'_>>,
-    ) -> Result<()> {
+    ) -> Result<smol::Task<Result<()>>> {
         // start subscribing
         debug!(target: "CLIENT", "Start subscriber");
         let gateway_slabs_sub: GatewaySlabsSubscriber =
             self.gateway.start_subscriber(executor.clone()).await?;
 
-        let secret_key = self.main_keypair.private;
         let wallet = self.wallet.clone();
+        let slabstore = self.gateway.get_slabstore();
+        let pending = self.pending.clone();
+        let mut sequencer = SlabSequencer::new(state.lock().await.last_applied_index()?);
 
         let task: smol::Task<Result<()>> = executor.spawn(async move {
             loop {
                 let slab = gateway_slabs_sub.recv().await?;
+                let arrived = Instant::now();
 
                 debug!(target: "CLIENT", "Received new slab");
 
-                debug!(target: "CLIENT", "Starting build tx from slab");
+                let slabs = Self::drain_slabs(slab, &gateway_slabs_sub);
 
-                let tx = tx::Transaction::decode(&slab.get_payload()[..]);
-
-                if let Err(e) = tx {
-                    warn!("TX: {}", e.to_string());
+                let mut state = state.lock().await;
+                let slabs = Self::sequence_slabs(&mut sequencer, slabs, &state, &slabstore)?;
+                if slabs.is_empty() {
                     continue;
                 }
 
-                let mut state = state.lock().await;
+                let secret_keys: Vec<jubjub::Fr> = wallet.get_private_keys()?;
 
-                let update = state_transition(&state, tx?);
+                state
+                    .apply_slabs(&slabs, Some(arrived), secret_keys, None, wallet.clone())
+                    .await?;
 
-                if let Err(e) = update {
-                    warn!("state transition: {}", e.to_string());
-                    continue;
+                if let Err(e) = Self::confirm_pending(&pending, &wallet, &slabs) {
+                    warn!(target: "CLIENT", "Confirm pending transactions: {}", e);
                 }
+            }
+        });
 
-                let secret_keys: Vec<jubjub::Fr> = vec![secret_key];
+        Ok(task)
+    }
 
-                let state_apply = state
-                    .apply(update?, secret_keys.clone(), None, wallet.clone())
-                    .await;
+    // Slabs queued behind the one received, e.g. while catching up on a
+    // backlog, are taken along so their proofs get verified together
+    fn drain_slabs(slab: Slab, gateway_slabs_sub: &GatewaySlabsSubscriber) -> Vec<Slab> {
+        let mut slabs = vec![slab];
+        while slabs.len() < SYNC_BATCH_SIZE {
+            match gateway_slabs_sub.try_recv() {
+                Ok(slab) => slabs.push(slab),
+                Err(_) => break,
+            }
+        }
+        slabs
+    }
 
-                if let Err(e) = state_apply {
-                    warn!("apply state: {}", e.to_string());
-                    continue;
+    // Put delivered slabs in the order the gateway stored them, holding
+    // back any that arrive ahead of the next one to apply. The high-water
+    // mark is the last applied index kept in the client database, which
+    // a rescan may have moved past the sequencer, or a rollback behind it.
+    fn sequence_slabs(
+        sequencer: &mut SlabSequencer,
+        slabs: Vec<Slab>,
+        state: &State,
+        slabstore: &SlabStore,
+    ) -> Result<Vec<Slab>> {
+        let last_applied_index = state.last_applied_index()?;
+        if last_applied_index < sequencer.high_water() {
+            *sequencer = SlabSequencer::new(last_applied_index);
+        }
+        sequencer.advance_to(last_applied_index);
+        let mut ready = sequencer.push_all(slabs);
+        ready.extend(sequencer.fill_from(slabstore)?);
+        Ok(ready)
+    }
+
+    // Drop the local state and wallet coins and apply every slab in the
+    // slabstore again, recovering the coins belonging to our keys. Coins
+    // are only looked for from slab `from_index` on.
+    pub async fn rescan(&self, state: Arc<Mutex<State>>, from_index: u64) -> Result<()> {
+        debug!(target: "CLIENT", "Start rescan from slab {}", from_index);
+        self.rescan_progress.start();
+
+        // Hold the lock for the whole rescan so the subscriber can't apply
+        // new slabs on top of a partially rebuilt state
+        let mut state = state.lock().await;
+        let result = self.rescan_slabs(&mut state, from_index).await;
+
+        state.rescan_leaves = None;
+        self.rescan_progress.finish();
+        result
+    }
+
+    // The tree is always rebuilt from the first slab, but slabs before
+    // `from_index` aren't searched for coins. The coins already in the
+    // wallet from then are kept, since they wouldn't be found again.
+    async fn rescan_slabs(&self, state: &mut State, from_index: u64) -> Result<()> {
+        let slabstore = self.gateway.get_slabstore();
+        let last_index = slabstore.get_last_index()?;
+        self.rescan_progress.set_slabs_total(last_index);
+
+        state.reset()?;
+        if from_index <= 1 {
+            self.wallet.remove_own_coins()?;
+        }
+
+        // Witnesses are caught up once at the end instead of on every coin
+        state.rescan_leaves = Some(vec![]);
+
+        let secret_keys: Vec<jubjub::Fr> = self.wallet.get_private_keys()?;
+
+        let mut index = 1;
+        while index <= last_index {
+            let mut batch_end = (index + SYNC_BATCH_SIZE as u64 - 1).min(last_index);
+            let keys = if index < from_index {
+                batch_end = batch_end.min(from_index - 1);
+                vec![]
+            } else {
+                secret_keys.clone()
+            };
+
+            let mut slabs = vec![];
+            for index in index..=batch_end {
+                match slabstore.get(serialize(&index))? {
+                    Some(slab) => slabs.push(deserialize::<Slab>(&slab)?),
+                    None => warn!(target: "CLIENT", "Slab {} is missing from the slabstore", index),
                 }
             }
-        });
 
-        task.detach();
+            state
+                .apply_slabs(&slabs, None, keys, None, self.wallet.clone())
+                .await?;
+
+            for _ in index..=batch_end {
+                self.rescan_progress.slab_done();
+            }
+            index = batch_end + 1;
+        }
+
+        let leaves = state.rescan_leaves.take().unwrap_or_default();
+        let witnesses = if from_index > 1 {
+            // The witnesses of the coins kept already hold leaves, so they
+            // are all taken again from the tree at their positions
+            let positions = self
+                .wallet
+                .get_witnesses()?
+                .into_iter()
+                .map(|(coin, witness)| (coin, witness.position()))
+                .collect();
+            frontier_witnesses(positions, &leaves)?
+        } else {
+            self.wallet.get_witnesses()?.into_iter().collect()
+        };
+        rebuild_witnesses(
+            witnesses,
+            &leaves,
+            &self.rescan_progress,
+            |coin, witness| self.wallet.update_witness(coin, witness),
+        )?;
+
+        debug!(target: "CLIENT", "End rescan at slab {}", last_index);
 
         Ok(())
     }
 
+    // Roll the state back by `count` slabs, e.g. to leave a gateway that
+    // forked. The slabs leave the slabstore too, so the ones delivered
+    // next take their place. Coins they sent to us leave the wallet and
+    // the witnesses of the others are taken again without their leaves.
+    // Returns the index of the last slab still applied.
+    pub async fn rollback(&self, state: Arc<Mutex<State>>, count: u64) -> Result<u64> {
+        let mut state = state.lock().await;
+        let own_coins = state.rollback(count)?;
+        let index = state.last_applied_index()?;
+        self.gateway.get_slabstore().truncate(index)?;
+
+        for coin in own_coins.iter() {
+            self.wallet.remove_own_coin(coin)?;
+        }
+
+        let leaves = state.leaves()?;
+        let positions = self
+            .wallet
+            .get_witnesses()?
+            .into_iter()
+            .map(|(coin, witness)| (coin, witness.position()))
+            .collect();
+        rebuild_witnesses(
+            frontier_witnesses(positions, &leaves)?,
+            &leaves,
+            &RescanProgress::default(),
+            |coin, witness| self.wallet.update_witness(coin, witness),
+        )?;
+
+        info!(target: "CLIENT", "Rolled back {} slabs to slab {}", count, index);
+        Ok(index)
+    }
+
+    // Replace the wallet keys with the ones derived from the mnemonic, then
+    // rescan to recover the coins they own. Keys are looked ahead for up
+    // to GAP_LIMIT unused ones past the last one paid to, so the ones
+    // derived after the mnemonic was written down are found too.
+    pub async fn restore(&mut self, mnemonic: &str, state: Arc<Mutex<State>>) -> Result<()> {
+        self.wallet.restore_mnemonic(mnemonic)?;
+        self.main_keypair = self.wallet.get_keypairs()?[0].clone();
+
+        info!(
+            target: "CLIENT", "Restored Keypair: {}",
+            bs58::encode(&serialize(&self.main_keypair.public)).into_string()
+        );
+
+        // Every rescan can find coins of keys further ahead
+        while self.wallet.extend_lookahead()? {
+            self.rescan(state.clone(), 1).await?;
+        }
+        self.wallet.settle_lookahead()
+    }
+
+    pub async fn get_mnemonic(&self) -> Result<Option<String>> {
+        self.check_not_frozen()?;
+        self.wallet.get_mnemonic()
+    }
+
     pub async fn init_db(&self) -> Result<()> {
         self.wallet.init_db().await
     }
@@ -356,10 +1104,83 @@ impl Client {
         self.wallet.key_gen()
     }
 
+    // Derive a fresh, never handed out keypair from the wallet seed
+    pub async fn new_key(&self) -> Result<Keypair> {
+        self.wallet.derive_keypair(DEFAULT_ACCOUNT)
+    }
+
+    // Add the key a sapling extended spending key authorizes spends with.
+    // Coins it already owns are found by a rescan.
+    pub async fn import_sapling_key(&self, key: &str) -> Result<Keypair> {
+        let private = sapling::import_extended_spending_key(key)?;
+        let public = zcash_primitives::constants::SPENDING_KEY_GENERATOR * private;
+
+        if self
+            .wallet
+            .get_keypairs()?
+            .iter()
+            .any(|keypair| keypair.public == public)
+        {
+            return Err(Error::from(ClientFailed::KeyExists));
+        }
+
+        self.wallet.put_keypair(&public, &private)?;
+        Ok(Keypair { public, private })
+    }
+
+    pub async fn privacy_report(&self) -> Result<Vec<PrivacyFinding>> {
+        privacy::privacy_report(&self.wallet)
+    }
+
+    pub async fn get_tx_history(&self) -> Result<Vec<TxHistory>> {
+        self.wallet.get_tx_history()
+    }
+
     pub async fn get_balances(&self) -> Result<Balances> {
         self.wallet.get_balances()
     }
 
+    /// Viewing keys of the main address and of the diversified addresses
+    /// handed out so far, for an auditor. Each address has a key of its
+    /// own, so the ones handed out later need another export.
+    pub fn export_view_keys(&self) -> Result<Vec<ViewingKey>> {
+        let mut view_keys = vec![ViewingKey::from_secret(&self.main_keypair.private)];
+        for (diversifier, _) in self.wallet.get_labeled_diversifiers(&self.main_keypair.public)? {
+            let secret = diversifier.secret(&self.main_keypair.private);
+            view_keys.push(ViewingKey::from_secret(&secret));
+        }
+        Ok(view_keys)
+    }
+
+    /// Watch the payments to another address from now on
+    pub async fn import_view_key(&self, view_key: &ViewingKey) -> Result<()> {
+        self.wallet.put_view_key(view_key)
+    }
+
+    pub async fn get_watched_balances(&self) -> Result<Balances> {
+        self.wallet.get_watched_balances()
+    }
+
+    // Sum our unspent coins, leaving out the ones whose nullifier has
+    // already been revealed on chain even if the wallet hasn't caught up.
+    pub async fn get_balance(&self, state: &State) -> Result<Balances> {
+        let mut balances = Balances { list: Vec::new() };
+
+        for own_coin in self.wallet.get_own_coins()?.iter() {
+            let nullifier = Nullifier::derive(&own_coin.secret, &own_coin.note.serial);
+            if state.nullifier_exists(&nullifier) {
+                continue;
+            }
+
+            balances.add(&Balance {
+                token_id: own_coin.note.token_id,
+                value: own_coin.note.value,
+            });
+        }
+
+        Ok(balances)
+    }
+
     pub async fn token_id_exists(&self, token_id: &jubjub::Fr) -> Result<bool> {
         self.wallet.token_id_exists(token_id)
     }
@@ -374,17 +1195,56 @@ pub struct State {
     pub tree: CommitmentTree<MerkleNode>,
     // List of all previous and the current merkle roots
     // This is the hashed value of all the children.
-    pub merkle_roots: RocksColumn<columns::MerkleRoots>,
+    pub merkle_roots: KvColumn<columns::MerkleRoots>,
     // Nullifiers prevent double spending
-    pub nullifiers: RocksColumn<columns::Nullifiers>,
+    pub nullifiers: KvColumn<columns::Nullifiers>,
     // Mint verifying key used by ZK
-    pub mint_pvk: groth16::PreparedVerifyingKey<Bls12>,
+    pub mint_pvk: Arc<groth16::PreparedVerifyingKey<Bls12>>,
     // Spend verifying key used by ZK
-    pub spend_pvk: groth16::PreparedVerifyingKey<Bls12>,
+    pub spend_pvk: Arc<groth16::PreparedVerifyingKey<Bls12>>,
+    // Verifying keys for checking many proofs at once while syncing
+    pub mint_batch_vk: Arc<BatchVerifyingKey>,
+    pub spend_batch_vk: Arc<BatchVerifyingKey>,
+    // Workers the proofs of received slabs are checked on
+    pub verify_pool: Arc<VerifyPool>,
     // List of cashier public keys
     pub public_keys: Vec<jubjub::SubgroupPoint>,
+    // Index of the last applied slab and the merkle tree at that point
+    pub sync_state: KvColumn<columns::SyncState>,
+    // Apply duration and lag of recently applied slabs
+    pub sync_metrics: SyncMetrics,
+    // Every leaf of the merkle tree by position, and the position of each
+    // coin, to give out merkle paths
+    pub commitments: KvColumn<columns::Commitments>,
+    // Coins added to the tree while rescanning, used to rebuild the
+    // witnesses of our coins when it's done
+    pub rescan_leaves: Option<Vec<MerkleNode>>,
+    // What each of the last applied slabs added, to roll them back
+    pub undo: KvColumn<columns::Undo>,
+    // Changes to the state, once they're written
+    pub events: SubscriberPtr<StateEvent>,
+    // Take slabs on trust from the gateways, without checking proofs or
+    // keeping the nullifiers of coins that aren't ours
+    pub light: bool,
 }
 
+/// Most slabs applied together while syncing, their proofs verified over
+/// every worker of the verify pool
+pub const SYNC_BATCH_SIZE: usize = 64;
+
+// A coin a slab adds to the tree, with the notes of it we could read
+struct StagedCoin {
+    coin: Coin,
+    node: MerkleNode,
+    enc_note: EncryptedNote,
+    // A coin sent to us, with its witness right after it was added
+    own: Option<(Note, jubjub::Fr, IncrementalWitness<MerkleNode>)>,
+    out_note: Option<OutgoingNote>,
+}
+
+pub(crate) const LAST_APPLIED_INDEX_KEY: &str = "last_applied_index";
+pub(crate) const MERKLE_TREE_KEY: &str = "merkle_tree";
+
 impl ProgramState for State {
     fn is_valid_cashier_public_key(&self, public: &jubjub::SubgroupPoint) -> bool {
         debug!(target: "CLIENT STATE", "Check if it is valid cashier public key");
@@ -420,86 +1280,684 @@ impl ProgramState for State {
 }
 
 impl State {
+    pub fn load_tree(
+        sync_state: &KvColumn<columns::SyncState>,
+    ) -> Result<CommitmentTree<MerkleNode>> {
+        match sync_state.get(MERKLE_TREE_KEY.to_string())? {
+            Some(tree) => deserialize(&tree),
+            None => Ok(CommitmentTree::empty()),
+        }
+    }
+
+    pub fn last_applied_index(&self) -> Result<u64> {
+        Self::load_index(&self.sync_state)
+    }
+
+    pub fn load_index(sync_state: &KvColumn<columns::SyncState>) -> Result<u64> {
+        match sync_state.get(LAST_APPLIED_INDEX_KEY.to_string())? {
+            Some(index) => deserialize(&index),
+            None => Ok(0),
+        }
+    }
+
+    pub fn recent_metrics(&self, count: u64) -> Result<Vec<SlabMetric>> {
+        let last_index = self.last_applied_index()?;
+        self.sync_metrics.recent(last_index, count)
+    }
+
+    // The merkle path of a coin to the current root, rebuilt from the
+    // stored leaves. None if the coin isn't in the tree. Nodes that synced
+    // before the leaves were stored need a rescan first.
+    pub fn merkle_path(&self, coin: &Coin) -> Result<Option<(MerklePath<MerkleNode>, MerkleNode)>> {
+        let position: u64 = match self.commitments.get(coin.repr)? {
+            Some(position) => deserialize(&position)?,
+            None => return Ok(None),
+        };
+
+        let leaves = self.leaves()?;
+        Ok(merkle_proof::path_from_leaves(&leaves, position).map(|path| (path, self.tree.root())))
+    }
+
+    // Every leaf of the merkle tree, from the stored ones
+    pub fn leaves(&self) -> Result<Vec<MerkleNode>> {
+        let size = self.tree.size() as u64;
+        let mut leaves = Vec::with_capacity(size as usize);
+        for index in 0..size {
+            match self.commitments.get(index)? {
+                Some(leaf) => leaves.push(deserialize(&leaf)?),
+                None => {
+                    return Err(Error::ServicesError(
+                        "merkle tree leaves are incomplete, rescan to rebuild them",
+                    ))
+                }
+            }
+        }
+        Ok(leaves)
+    }
+
+    // Persist the merkle tree together with the index of the slab it was
+    // last updated with, so a restarted node resumes syncing from there.
+    pub fn save_checkpoint(&self, index: u64) -> Result<()> {
+        let mut batch = KvBatch::new();
+        self.checkpoint_batch(&mut batch, index);
+        self.sync_state.write(batch)
+    }
+
+    // Add the checkpoint to a batch of other writes
+    pub fn checkpoint_batch(&self, batch: &mut KvBatch, index: u64) {
+        debug!(target: "CLIENT STATE", "Save checkpoint at slab {}", index);
+        self.sync_state
+            .put_batch(batch, MERKLE_TREE_KEY.to_string(), self.tree.clone());
+        self.sync_state
+            .put_batch(batch, LAST_APPLIED_INDEX_KEY.to_string(), index);
+    }
+
+    // Add the undo record of a slab to a batch of other writes
+    fn undo_batch(&self, batch: &mut KvBatch, index: u64, record: UndoRecord) {
+        self.undo.put_batch(batch, index, record);
+
+        // Drop the record that just fell out of the window
+        if index > UNDO_HISTORY_SIZE {
+            self.undo.delete_batch(batch, index - UNDO_HISTORY_SIZE);
+        }
+    }
+
+    // Checkpoint a slab that leaves the state as it is, e.g. one that
+    // doesn't verify. It still gets an undo record, so it can be rolled
+    // back like the others.
+    fn skip_slab(&self, index: u64) -> Result<()> {
+        let mut batch = KvBatch::new();
+        self.undo_batch(&mut batch, index, UndoRecord::new(self.tree.clone()));
+        self.checkpoint_batch(&mut batch, index);
+        self.sync_state.write(batch)
+    }
+
+    // Undo the last `count` applied slabs, going back to the state after
+    // the slab before them. Every change is written in one batch with the
+    // checkpoint. Returns the coins the slabs sent to our keys, for the
+    // wallet to forget.
+    pub fn rollback(&mut self, count: u64) -> Result<Vec<Coin>> {
+        let last_index = self.last_applied_index()?;
+        if count > last_index.min(UNDO_HISTORY_SIZE) {
+            return Err(Error::ServicesError("can't roll back that many slabs"));
+        }
+        let index = last_index - count;
+        debug!(target: "CLIENT STATE", "Roll back to slab {}", index);
+
+        let mut batch = KvBatch::new();
+        let mut tree = self.tree.clone();
+        let mut own_coins = vec![];
+        for undone in ((index + 1)..=last_index).rev() {
+            let record: UndoRecord = match self.undo.get(undone)? {
+                Some(record) => deserialize(&record)?,
+                None => {
+                    return Err(Error::ServicesError(
+                        "slabs applied before undo records were kept can't be rolled back",
+                    ))
+                }
+            };
+
+            for nullifier in record.nullifiers {
+                self.nullifiers.delete_batch(&mut batch, nullifier);
+            }
+            for root in record.merkle_roots {
+                self.merkle_roots.delete_batch(&mut batch, root);
+            }
+            for (position, coin) in (record.tree.size() as u64..).zip(record.coins) {
+                self.commitments.delete_batch(&mut batch, position);
+                self.commitments.delete_batch(&mut batch, coin);
+            }
+            own_coins.extend(record.own_coins.into_iter().map(Coin::new));
+
+            self.undo.delete_batch(&mut batch, undone);
+            tree = record.tree;
+        }
+
+        let tree = std::mem::replace(&mut self.tree, tree);
+        self.checkpoint_batch(&mut batch, index);
+        if let Err(e) = self.sync_state.write(batch) {
+            self.tree = tree;
+            return Err(e);
+        }
+        Ok(own_coins)
+    }
+
+    // Clear the merkle tree, roots and nullifiers so the slabs can be applied
+    // again from the start.
+    pub fn reset(&mut self) -> Result<()> {
+        debug!(target: "CLIENT STATE", "Reset state");
+        self.tree = CommitmentTree::empty();
+        self.merkle_roots.clear()?;
+        self.nullifiers.clear()?;
+        self.sync_metrics.clear()?;
+        self.commitments.clear()?;
+        self.undo.clear()?;
+        self.save_checkpoint(0)
+    }
+
+    // Verify the transaction in a slab and apply it. Slabs that don't
+    // decode or verify are skipped, but still count as applied. When the
+    // arrival time of the slab is given, its timings are recorded.
+    pub async fn apply_slab(
+        &mut self,
+        slab: &Slab,
+        arrived: Option<Instant>,
+        secret_keys: Vec<jubjub::Fr>,
+        notify: Option<async_channel::Sender<(jubjub::SubgroupPoint, u64)>>,
+        wallet: WalletPtr,
+    ) -> Result<()> {
+        self.apply_slabs(
+            std::slice::from_ref(slab),
+            arrived,
+            secret_keys,
+            notify,
+            wallet,
+        )
+        .await
+    }
+
+    // Apply a run of slabs in order, e.g. a backlog while syncing. The
+    // slabs are split in chunks, one per worker of the verify pool, and
+    // every chunk is queued at once: its transactions are decoded there
+    // and their proofs verified in one batch. Chunks are applied in order
+    // as they come back, while the later ones are still being verified.
+    // If a batch fails, each transaction of the chunk is verified on its
+    // own so only the bad ones get skipped.
+    pub async fn apply_slabs(
+        &mut self,
+        slabs: &[Slab],
+        arrived: Option<Instant>,
+        secret_keys: Vec<jubjub::Fr>,
+        notify: Option<async_channel::Sender<(jubjub::SubgroupPoint, u64)>>,
+        wallet: WalletPtr,
+    ) -> Result<()> {
+        let last_applied_index = self.last_applied_index()?;
+        let slabs: Vec<&Slab> = slabs
+            .iter()
+            .filter(|slab| {
+                let applied = slab.get_index() <= last_applied_index;
+                if applied {
+                    debug!(
+                        target: "CLIENT STATE",
+                        "Slab {} has already been applied",
+                        slab.get_index()
+                    );
+                }
+                !applied
+            })
+            .collect();
+        if slabs.is_empty() {
+            return Ok(());
+        }
+
+        let workers = self.verify_pool.workers();
+        let chunk_size = (slabs.len() + workers - 1) / workers;
+        let chunks: Vec<&[&Slab]> = slabs.chunks(chunk_size).collect();
+
+        debug!(
+            target: "CLIENT STATE",
+            "Verify {} slabs in {} chunks", slabs.len(), chunks.len()
+        );
+        let verifying: Vec<_> = chunks
+            .iter()
+            .map(|chunk| {
+                let payloads = chunk.iter().map(|slab| slab.get_payload()).collect();
+                self.verify_proofs(payloads)
+            })
+            .collect();
+
+        for (chunk, verified) in chunks.into_iter().zip(verifying) {
+            let (txs, proofs) = verified.wait().await?;
+
+            for ((slab, tx), proofs) in chunk.iter().zip(txs).zip(proofs) {
+                let started = Instant::now();
+                self.verify_and_apply(
+                    slab,
+                    tx,
+                    proofs,
+                    secret_keys.clone(),
+                    notify.clone(),
+                    wallet.clone(),
+                )
+                .await?;
+                METRICS.slab_apply.observe(started.elapsed());
+                METRICS.slabs_applied.inc();
+                self.emit(StateEvent::SlabApplied {
+                    index: slab.get_index(),
+                })
+                .await;
+
+                if let Some(arrived) = arrived {
+                    let timestamp = unix_timestamp();
+                    let metric = SlabMetric::new(
+                        slab.get_index(),
+                        started.elapsed(),
+                        arrived.elapsed(),
+                        timestamp,
+                    );
+                    self.sync_metrics.record(metric)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Queue decoding the transactions of a chunk of slabs and checking
+    // their proofs on the verify pool. The handle gives the transactions
+    // back along with the result for each. A light client only decodes.
+    #[allow(clippy::type_complexity)]
+    fn verify_proofs(
+        &self,
+        payloads: Vec<Vec<u8>>,
+    ) -> VerifyHandle<(Vec<Result<tx::Transaction>>, Vec<VerifyResult<()>>)> {
+        let (mint_batch_vk, spend_batch_vk) =
+            (self.mint_batch_vk.clone(), self.spend_batch_vk.clone());
+        let (mint_pvk, spend_pvk) = (self.mint_pvk.clone(), self.spend_pvk.clone());
+        let light = self.light;
+
+        self.verify_pool.submit(VerifyLane::Sync, move || {
+            let started = Instant::now();
+            let txs: Vec<Result<tx::Transaction>> = payloads
+                .iter()
+                .map(|payload| tx::Transaction::decode(&payload[..]))
+                .collect();
+            if light {
+                let proofs = txs.iter().map(|_| Ok(())).collect();
+                return (txs, proofs);
+            }
+
+            // A single proof gains nothing from batching
+            let batched =
+                txs.len() > 1 && Self::verify_proofs_batch(&mint_batch_vk, &spend_batch_vk, &txs);
+
+            let proofs = txs
+                .iter()
+                .map(|tx| match tx {
+                    Ok(tx) if !batched => tx.verify_proofs(&mint_pvk, &spend_pvk),
+                    _ => Ok(()),
+                })
+                .collect();
+
+            let per_slab = started.elapsed() / payloads.len().max(1) as u32;
+            for _ in payloads.iter() {
+                METRICS.slab_verify.observe(per_slab);
+            }
+            (txs, proofs)
+        })
+    }
+
+    fn verify_proofs_batch(
+        mint_batch_vk: &BatchVerifyingKey,
+        spend_batch_vk: &BatchVerifyingKey,
+        txs: &[Result<tx::Transaction>],
+    ) -> bool {
+        let mut mint_batch = BatchVerifier::new(mint_batch_vk);
+        let mut spend_batch = BatchVerifier::new(spend_batch_vk);
+
+        for tx in txs.iter().flatten() {
+            tx.add_proofs_to_batch(&mut mint_batch, &mut spend_batch);
+        }
+
+        let proofs = mint_batch.len() + spend_batch.len();
+        let verified = mint_batch.verify() && spend_batch.verify();
+        if !verified {
+            warn!(
+                target: "CLIENT STATE",
+                "Batch of {} proofs failed, verifying them one by one", proofs
+            );
+        }
+        verified
+    }
+
+    async fn verify_and_apply(
+        &mut self,
+        slab: &Slab,
+        tx: Result<tx::Transaction>,
+        proofs: VerifyResult<()>,
+        secret_keys: Vec<jubjub::Fr>,
+        notify: Option<async_channel::Sender<(jubjub::SubgroupPoint, u64)>>,
+        wallet: WalletPtr,
+    ) -> Result<()> {
+        let index = slab.get_index();
+
+        if let Err(e) = tx {
+            warn!("TX: {}", e.to_string());
+            return self.skip_slab(index);
+        }
+
+        // The proofs were already checked on the verify pool
+        let tx = tx?;
+        let update = if self.light {
+            Ok(trusted_transition(tx))
+        } else {
+            proofs.and_then(|()| state_transition_batched(&*self, tx))
+        };
+
+        if let Err(e) = update {
+            warn!("state transition: {}", e.to_string());
+            return self.skip_slab(index);
+        }
+
+        self.apply(update?, slab, secret_keys, notify, wallet).await
+    }
+
+    // Apply the update of a slab and checkpoint it. Everything it changes
+    // in the tree and the columns is staged first, then the nullifiers,
+    // roots and leaves are written together with the checkpoint in one
+    // batch. The tree in memory is only replaced once the batch is
+    // written, so a failed write leaves the state as it was. Our coins and
+    // witnesses are staged too and written in one wallet transaction right
+    // after the batch. If that fails the slab is rolled back, so it's
+    // applied to both again rather than leave the wallet behind.
     pub async fn apply(
         &mut self,
         update: StateUpdate,
+        slab: &Slab,
         secret_keys: Vec<jubjub::Fr>,
         notify: Option<async_channel::Sender<(jubjub::SubgroupPoint, u64)>>,
         wallet: WalletPtr,
     ) -> Result<()> {
+        let update = self.light_update(update, &wallet);
+        let mut batch = KvBatch::new();
+        let (tree, coins, undo) = self.stage_update(update, &mut batch, &secret_keys);
+        let (own_coins, witnesses) = self.stage_wallet(&coins, &wallet)?;
+        let nullifiers = undo.nullifiers.clone();
+        self.undo_batch(&mut batch, slab.get_index(), undo);
+
+        let tree = std::mem::replace(&mut self.tree, tree);
+        self.checkpoint_batch(&mut batch, slab.get_index());
+        if let Err(e) = self.sync_state.write(batch) {
+            self.tree = tree;
+            return Err(e);
+        }
+
+        if let Err(e) = wallet.put_slab_coins(own_coins, &witnesses) {
+            self.rollback(1)?;
+            return Err(e);
+        }
+
+        if let Some(leaves) = self.rescan_leaves.as_mut() {
+            leaves.extend(coins.iter().map(|coin| coin.node));
+        }
+        if !coins.is_empty() {
+            self.emit(StateEvent::MerkleRootAdvanced {
+                root: self.tree.root(),
+                size: self.tree.size() as u64,
+            })
+            .await;
+        }
+
+        self.apply_to_wallet(coins, &nullifiers, slab, notify, wallet).await
+    }
+
+    // A light client only keeps the nullifiers of its own coins, to know
+    // when they're spent. If the wallet can't say which those are, it
+    // keeps them all rather than miss one.
+    fn light_update(&self, mut update: StateUpdate, wallet: &WalletPtr) -> StateUpdate {
+        if !self.light || update.nullifiers.is_empty() {
+            return update;
+        }
+        match wallet.get_own_nullifiers() {
+            Ok(own) => update
+                .nullifiers
+                .retain(|nullifier| own.iter().any(|(_, own)| own.repr == nullifier.repr)),
+            Err(e) => warn!("light client nullifiers: {}", e.to_string()),
+        }
+        update
+    }
+
+    // Broadcast a change to the state. A rescan only goes over old
+    // changes again, so it sends none.
+    async fn emit(&self, event: StateEvent) {
+        if self.rescan_leaves.is_none() {
+            self.events.notify(event).await;
+        }
+    }
+
+    // Work out the tree after the update and add its nullifiers, roots and
+    // leaves to the batch, without touching the state. What's added goes
+    // in the undo record too.
+    fn stage_update(
+        &self,
+        update: StateUpdate,
+        batch: &mut KvBatch,
+        secret_keys: &[jubjub::Fr],
+    ) -> (CommitmentTree<MerkleNode>, Vec<StagedCoin>, UndoRecord) {
+        let mut undo = UndoRecord::new(self.tree.clone());
+
         // Extend our list of nullifiers with the ones from the update
 
         debug!(target: "CLIENT STATE", "Extend nullifiers");
         for nullifier in update.nullifiers {
-            self.nullifiers.put(nullifier, vec![] as Vec<u8>)?;
+            undo.nullifiers.push(nullifier.repr);
+            self.nullifiers
+                .put_batch(batch, nullifier, vec![] as Vec<u8>);
         }
 
-        debug!(target: "CLIENT STATE", "Update merkle tree and witness ");
-        // Update merkle tree and witnesses
-        for (coin, enc_note) in update.coins.into_iter().zip(update.enc_notes.iter()) {
+        debug!(target: "CLIENT STATE", "Update merkle tree");
+        let mut tree = self.tree.clone();
+        let mut coins = Vec::with_capacity(update.coins.len());
+        for ((coin, enc_note), out_note) in update
+            .coins
+            .into_iter()
+            .zip(update.enc_notes.into_iter())
+            .zip(update.out_notes.into_iter())
+        {
             // Add the new coins to the merkle tree
             let node = MerkleNode::from_coin(&coin);
-            self.tree.append(node).expect("Append to merkle tree");
+            tree.append(node).expect("Append to merkle tree");
 
-            debug!(target: "CLIENT STATE", "Keep track of all merkle roots");
+            let position = tree.size() as u64 - 1;
+            self.commitments.put_batch(batch, position, node);
+            self.commitments.put_batch(batch, coin.repr, position);
 
             // Keep track of all merkle roots that have existed
-            self.merkle_roots.put(self.tree.root(), vec![] as Vec<u8>)?;
+            let root = tree.root();
+            self.merkle_roots.put_batch(batch, root, vec![] as Vec<u8>);
+            undo.merkle_roots.push(root.repr);
+            undo.coins.push(coin.repr);
+
+            // The witness of a coin sent to us is taken right after it's
+            // added to the tree, before any other coins are
+            let own = Self::try_decrypt_note(&enc_note, secret_keys)
+                .map(|(note, secret)| (note, secret, IncrementalWitness::from_tree(&tree)));
+            if own.is_some() {
+                undo.own_coins.push(coin.repr);
+            }
 
-            debug!(target: "CLIENT STATE", "Update witness");
+            // Outputs we created ourselves carry a copy we can read, which
+            // lets us rebuild the sent side of our history
+            let out_note = out_note
+                .as_ref()
+                .and_then(|out_note| Self::try_decrypt_out_note(out_note, secret_keys));
+
+            coins.push(StagedCoin {
+                coin,
+                node,
+                enc_note,
+                own,
+                out_note,
+            });
+        }
 
-            // Also update all the coin witnesses
-            for (coin, witness) in wallet.get_witnesses()?.iter_mut() {
-                witness.append(node).expect("Append to witness");
-                wallet.update_witness(&coin, witness.clone())?;
-            }
+        (tree, coins, undo)
+    }
 
-            debug!(target: "CLIENT STATE", "iterate over secret_keys to decrypt note");
+    // Work out the coins a slab sends us and the witnesses of all our
+    // unspent coins after it, without touching the wallet. Every coin the
+    // slab added is appended to the witnesses of the coins before it.
+    // While rescanning the witnesses are rebuilt from the leaves when it's
+    // done, so only the new coins are staged.
+    fn stage_wallet(
+        &self,
+        coins: &[StagedCoin],
+        wallet: &WalletPtr,
+    ) -> Result<(Vec<OwnCoin>, HashMap<Vec<u8>, IncrementalWitness<MerkleNode>>)> {
+        let rescanning = self.rescan_leaves.is_some();
+        let mut witnesses = if rescanning {
+            HashMap::new()
+        } else {
+            wallet.get_witnesses()?
+        };
 
-            for secret in secret_keys.iter() {
-                if let Some(note) = Self::try_decrypt_note(enc_note, *secret) {
-                    // We need to keep track of the witness for this coin.
-                    // This allows us to prove inclusion of the coin in the merkle tree with ZK.
-                    // Just as we update the merkle tree with every new coin, so we do the same with
-                    // the witness.
+        let mut own_coins = vec![];
+        for staged in coins {
+            if !rescanning {
+                debug!(target: "CLIENT STATE", "Update witness");
+                for witness in witnesses.values_mut() {
+                    witness.append(staged.node)?;
+                }
+            }
 
-                    // Derive the current witness from the current tree.
-                    // This is done right after we add our coin to the tree (but before any other
-                    // coins are added)
+            if let Some((note, secret, witness)) = &staged.own {
+                // We need to keep track of the witness for this coin.
+                // This allows us to prove inclusion of the coin in the merkle tree with ZK.
+                // Just as we update the merkle tree with every new coin, so we do the same with
+                // the witness.
+                witnesses.insert(serialize(&staged.coin.repr), witness.clone());
+                own_coins.push((staged.coin.clone(), note.clone(), *secret));
+            }
+        }
 
-                    // Make a new witness for this coin
-                    let witness = IncrementalWitness::from_tree(&self.tree);
+        let own_coins = own_coins
+            .into_iter()
+            .map(|(coin, note, secret)| OwnCoin {
+                witness: witnesses[&serialize(&coin.repr)].clone(),
+                coin,
+                note,
+                secret,
+            })
+            .collect();
+        Ok((own_coins, witnesses))
+    }
 
-                    let own_coin = OwnCoin {
-                        coin: coin.clone(),
-                        note: note.clone(),
-                        secret: *secret,
-                        witness: witness.clone(),
-                    };
+    // Bring the rest of the wallet up to date with the coins a slab added
+    // to the tree, once our coins and witnesses are written
+    async fn apply_to_wallet(
+        &self,
+        coins: Vec<StagedCoin>,
+        nullifiers: &[[u8; 32]],
+        slab: &Slab,
+        notify: Option<async_channel::Sender<(jubjub::SubgroupPoint, u64)>>,
+        wallet: WalletPtr,
+    ) -> Result<()> {
+        // Our coins the slab spent, wherever the spend was made from
+        if !nullifiers.is_empty() && self.rescan_leaves.is_none() {
+            for (coin, nullifier) in wallet.get_own_nullifiers()? {
+                if nullifiers.contains(&nullifier.repr) {
+                    let slab_index = slab.get_index();
+                    self.emit(StateEvent::NullifierSeen { coin, slab_index }).await;
+                }
+            }
+        }
 
-                    wallet.put_own_coins(own_coin)?;
-                    let pub_key = zcash_primitives::constants::SPENDING_KEY_GENERATOR * secret;
+        // Keys imported to watch someone else's incoming payments
+        let view_keys = wallet.get_view_keys()?;
+
+        for staged in coins {
+            let StagedCoin {
+                coin,
+                enc_note,
+                own,
+                out_note,
+                ..
+            } = staged;
+
+            if let Some((note, secret, _)) = own {
+                // Coins from our own transfers, like change, are part of
+                // the sent side of the history
+                let change = out_note.is_some() || wallet.is_change_coin(&coin)?;
+                self.emit(StateEvent::NewCoinReceived {
+                    coin: coin.clone(),
+                    value: note.value,
+                    token_id: note.token_id,
+                    memo: note.memo,
+                    tx_id: pending_id(&slab.get_payload()),
+                    slab_index: slab.get_index(),
+                    change,
+                })
+                .await;
+                let pub_key = zcash_primitives::constants::SPENDING_KEY_GENERATOR * secret;
+
+                if !change {
+                    let entry = TxHistory {
+                        direction: TxDirection::Received,
+                        amount: note.value,
+                        token_id: note.token_id,
+                        counterparty: None,
+                        slab_index: Some(slab.get_index()),
+                        slab_hash: Some(slab.hash()),
+                        timestamp: unix_timestamp(),
+                        memo: note.memo,
+                    };
+                    wallet.put_tx_history(&entry, None)?;
+                }
 
-                    debug!(target: "CLIENT STATE", "Received a coin: amount {} ", note.value);
+                debug!(target: "CLIENT STATE", "Received a coin: amount {} ", note.value);
 
-                    debug!(target: "CLIENT STATE", "Send a notification");
+                debug!(target: "CLIENT STATE", "Send a notification");
 
-                    if let Some(ch) = notify.clone() {
-                        ch.send((pub_key, note.value)).await?
-                    }
+                if let Some(ch) = notify.clone() {
+                    ch.send((pub_key, note.value)).await?
+                }
+            } else if let Some(out_note) = out_note {
+                // A payment we made. It's normally recorded when sending,
+                // but not after restoring the wallet and rescanning.
+                let entry = TxHistory {
+                    direction: TxDirection::Sent,
+                    amount: out_note.value,
+                    token_id: out_note.token_id,
+                    counterparty: Some(out_note.recipient),
+                    slab_index: Some(slab.get_index()),
+                    slab_hash: Some(slab.hash()),
+                    timestamp: unix_timestamp(),
+                    memo: Memo::empty(),
+                };
+
+                if !wallet.has_tx_history(&entry)? {
+                    debug!(target: "CLIENT STATE", "Recovered a sent payment: amount {}", entry.amount);
+                    wallet.put_tx_history(&entry, None)?;
                 }
+            } else if let Some(note) = view_keys
+                .iter()
+                .find_map(|view_key| view_key.decrypt_note(&enc_note).ok())
+            {
+                debug!(target: "CLIENT STATE", "Watched a coin: amount {}", note.value);
+                wallet.put_watched_coin(&coin, &note)?;
             }
         }
         Ok(())
     }
 
-    fn try_decrypt_note(ciphertext: &EncryptedNote, secret: jubjub::Fr) -> Option<Note> {
-        match ciphertext.decrypt(&secret) {
-            // ... and return the decrypted note for this coin.
-            Ok(note) => Some(note),
-            // We weren't able to decrypt the note with our key.
-            Err(_) => None,
+    fn try_decrypt_out_note(
+        ciphertext: &EncryptedOutgoingNote,
+        secret_keys: &[jubjub::Fr],
+    ) -> Option<OutgoingNote> {
+        secret_keys
+            .iter()
+            .find_map(|secret| ciphertext.decrypt(secret).ok())
+    }
+
+    fn try_decrypt_note(
+        ciphertext: &EncryptedNote,
+        secret_keys: &[jubjub::Fr],
+    ) -> Option<(Note, jubjub::Fr)> {
+        // Loop through all our secret keys...
+        for secret in secret_keys {
+            // ... attempt to decrypt the note, with the viewing key of our
+            // address or, for a legacy address, with the secret itself ...
+            let view_key = ViewingKey::from_secret(secret);
+            if let Ok(note) = view_key
+                .decrypt_note(ciphertext)
+                .or_else(|_| ciphertext.decrypt(secret))
+            {
+                // ... and return the decrypted note along with the key it belongs to.
+                return Some((note, *secret));
+            }
         }
+        // We weren't able to decrypt the note with any of our keys.
+        None
     }
 }
 
@@ -517,6 +1975,7 @@ impl std::fmt::Display for ClientFailed {
             ClientFailed::InvalidAmount(i) => {
                 write!(f, "Invalid Amount {}", i)
             }
+            ClientFailed::AmountOverflow => f.write_str("The amounts add up to too much"),
             ClientFailed::UnableToGetDepositAddress => f.write_str("Unable to get deposit address"),
             ClientFailed::UnableToGetWithdrawAddress => {
                 f.write_str("Unable to get withdraw address")
@@ -528,6 +1987,17 @@ impl std::fmt::Display for ClientFailed {
             ClientFailed::EmptyPassword => f.write_str("Password is empty. Cannot create database"),
             ClientFailed::WalletInitialized => f.write_str("Wallet already initalized"),
             ClientFailed::KeyExists => f.write_str("Keypair already exists"),
+            ClientFailed::WalletFrozen => {
+                f.write_str("Wallet is frozen by an emergency stop, unlock it first")
+            }
+            ClientFailed::UnlockRefused(i) => write!(f, "Unlock refused: {}", i),
+            ClientFailed::ProofRejected(i) => {
+                write!(f, "Transaction failed verification: {}", i)
+            }
+            ClientFailed::GatewayRejected(i) => {
+                write!(f, "Gateway didn't accept the transaction: {}", i)
+            }
+            ClientFailed::PolicyRefused(i) => write!(f, "Refused by the payment policy: {}", i),
             ClientFailed::ClientError(i) => {
                 write!(f, "ClientError: {}", i)
             }
@@ -535,6 +2005,23 @@ impl std::fmt::Display for ClientFailed {
     }
 }
 
+impl ClientFailed {
+    /// Why a transfer failed with this error, for the failed operations log
+    pub fn failure_reason(&self) -> FailureReason {
+        match self {
+            ClientFailed::NotEnoughValue(_) => FailureReason::InsufficientFunds,
+            ClientFailed::ProofRejected(_) => FailureReason::ProofRejected,
+            ClientFailed::GatewayRejected(_) => FailureReason::GatewayRejected,
+            ClientFailed::WalletFrozen => FailureReason::WalletFrozen,
+            ClientFailed::PolicyRefused(_) => FailureReason::PolicyRefused,
+            ClientFailed::InvalidAddress(_)
+            | ClientFailed::InvalidAmount(_)
+            | ClientFailed::AmountOverflow => FailureReason::InvalidRequest,
+            _ => FailureReason::Other,
+        }
+    }
+}
+
 impl From<super::error::Error> for ClientFailed {
     fn from(err: super::error::Error) -> ClientFailed {
         ClientFailed::ClientError(err.to_string())
@@ -542,3 +2029,252 @@ impl From<super::error::Error> for ClientFailed {
 }
 
 pub type ClientResult<T> = std::result::Result<T, ClientFailed>;
+
+/// What the recipients are paid in total, refused if it overflows
+pub fn total_amount(recipients: &[(Address, u64, Memo)]) -> ClientResult<u64> {
+    recipients
+        .iter()
+        .try_fold(0u64, |total, (_, amount, _)| total.checked_add(*amount))
+        .ok_or(ClientFailed::AmountOverflow)
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::KvBackend;
+    use crate::crypto::{setup_mint_prover, setup_spend_prover, Diversifier};
+    use crate::tx::{Deposit, Minter};
+    use crate::wallet::WalletDb;
+    use ff::Field;
+    use rand::rngs::OsRng;
+    use std::path::PathBuf;
+
+    // A client whose slabs are only the ones the test stores, and the
+    // state they're applied to, trusting mints from `cashier`
+    async fn test_client(
+        dir: &PathBuf,
+        cashier: jubjub::SubgroupPoint,
+        mint_params: &groth16::Parameters<Bls12>,
+        spend_params: &groth16::Parameters<Bls12>,
+    ) -> Result<(Client, Arc<Mutex<State>>)> {
+        let store = KvBackend::default().open(&dir.join("client.db"))?;
+        let wallet = WalletDb::new(&dir.join("wallet.db"), "test".to_string())?;
+        let unused = Url::parse("tcp://127.0.0.1:1")?;
+        let verify_pool = Arc::new(VerifyPool::new(1));
+
+        let client = Client::new(
+            store.clone(),
+            vec![(unused.clone(), unused)],
+            TlsClientConfig::default(),
+            None,
+            wallet,
+            mint_params.clone(),
+            spend_params.clone(),
+            verify_pool.clone(),
+        )
+        .await?;
+
+        let sync_state = KvColumn::<columns::SyncState>::new(store.clone());
+        let state = State {
+            tree: State::load_tree(&sync_state)?,
+            merkle_roots: KvColumn::new(store.clone()),
+            nullifiers: KvColumn::new(store.clone()),
+            mint_pvk: Arc::new(groth16::prepare_verifying_key(&mint_params.vk)),
+            spend_pvk: Arc::new(groth16::prepare_verifying_key(&spend_params.vk)),
+            mint_batch_vk: Arc::new(BatchVerifyingKey::new(&mint_params.vk)),
+            spend_batch_vk: Arc::new(BatchVerifyingKey::new(&spend_params.vk)),
+            verify_pool,
+            public_keys: vec![cashier],
+            sync_state,
+            sync_metrics: SyncMetrics::new(KvColumn::new(store.clone())),
+            commitments: KvColumn::new(store.clone()),
+            rescan_leaves: None,
+            undo: KvColumn::new(store),
+            events: Subscriber::new(),
+            light: false,
+        };
+        Ok((client, Arc::new(Mutex::new(state))))
+    }
+
+    // Store a slab minting `value` to `recipient` as the next slab
+    fn store_mint(
+        client: &Client,
+        minter: &Minter,
+        recipient: Address,
+        value: u64,
+        mint_params: &groth16::Parameters<Bls12>,
+        spend_params: &groth16::Parameters<Bls12>,
+    ) -> Result<()> {
+        let deposit = Deposit {
+            value,
+            token_id: jubjub::Fr::random(&mut OsRng),
+            recipient,
+            memo: Memo::empty(),
+        };
+        let tx = minter.mint(&[deposit], mint_params, spend_params);
+
+        let slabstore = client.gateway.get_slabstore();
+        let mut slab = Slab::new(serialize(&tx));
+        slab.set_index(slabstore.get_last_index()? + 1);
+        assert!(slabstore.put(slab)?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_total_amount() {
+        let public = zcash_primitives::constants::SPENDING_KEY_GENERATOR * jubjub::Fr::one();
+        let pay = |amount| (Address::legacy(public), amount, Memo::empty());
+        assert_eq!(total_amount(&[pay(1), pay(2)]).ok(), Some(3));
+        match total_amount(&[pay(u64::MAX), pay(1)]) {
+            Err(ClientFailed::AmountOverflow) => {}
+            _ => panic!("an overflowing total should be refused"),
+        }
+    }
+
+    #[test]
+    fn test_rescan() -> Result<()> {
+        let dir = std::env::temp_dir().join("darkfi_test_client_rescan");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+        let mint_params = setup_mint_prover();
+        let spend_params = setup_spend_prover();
+        let minter = Minter::new(jubjub::Fr::random(&mut OsRng));
+
+        smol::future::block_on(async {
+            let (client, state) =
+                test_client(&dir, minter.public(), &mint_params, &spend_params).await?;
+            let stranger = Address::from_secret(&jubjub::Fr::random(&mut OsRng));
+            store_mint(&client, &minter, stranger, 50, &mint_params, &spend_params)?;
+            store_mint(&client, &minter, client.address(), 110, &mint_params, &spend_params)?;
+
+            // The slabs are only in the slabstore, as if the wallet was
+            // restored after they were synced
+            assert!(client.wallet.get_own_coins()?.is_empty());
+            client.rescan(state.clone(), 1).await?;
+
+            let coins = client.wallet.get_own_coins()?;
+            assert_eq!(coins.len(), 1);
+            assert_eq!(coins[0].note.value, 110);
+            assert_eq!(state.lock().await.last_applied_index()?, 2);
+
+            // A rescan from after the coin's slab keeps it
+            client.rescan(state, 3).await?;
+            assert_eq!(client.wallet.get_own_coins()?.len(), 1);
+            Ok::<(), Error>(())
+        })?;
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejected_publish_releases_coins() -> Result<()> {
+        let dir = std::env::temp_dir().join("darkfi_test_client_rejected_publish");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+        let mint_params = setup_mint_prover();
+        let spend_params = setup_spend_prover();
+        let minter = Minter::new(jubjub::Fr::random(&mut OsRng));
+
+        smol::future::block_on(async {
+            let (mut client, state) =
+                test_client(&dir, minter.public(), &mint_params, &spend_params).await?;
+            store_mint(&client, &minter, client.address(), 110, &mint_params, &spend_params)?;
+            client.rescan(state, 1).await?;
+            let token_id = client.wallet.get_own_coins()?[0].note.token_id;
+
+            // Nothing answers at the gateway's address
+            client.gateway.set_request_timeout(1);
+            let stranger = zcash_primitives::constants::SPENDING_KEY_GENERATOR
+                * jubjub::Fr::random(&mut OsRng);
+            match client.send(stranger, 50, token_id, false, 0, None).await {
+                Err(ClientFailed::GatewayRejected(_)) => {}
+                _ => panic!("the transfer should fail to publish"),
+            }
+
+            // The coin is neither spent nor reserved, so it can fund a retry
+            let coins = client.wallet.get_own_coins()?;
+            assert_eq!(coins.len(), 1);
+            assert!(client.reserved.is_empty());
+            assert!(client.wallet.get_spends()?.is_empty());
+            assert_eq!(client.wallet.get_balances()?.list[0].value, 110);
+            let selection = CoinSelectionStrategy::default();
+            let (inputs, _) = client.build_inputs(110, token_id, &selection).await?;
+            assert_eq!(inputs.len(), 1);
+            Ok::<(), Error>(())
+        })?;
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_derived_keys() -> Result<()> {
+        let dir = std::env::temp_dir().join("darkfi_test_client_restore");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+        let mint_params = setup_mint_prover();
+        let spend_params = setup_spend_prover();
+        let minter = Minter::new(jubjub::Fr::random(&mut OsRng));
+
+        smol::future::block_on(async {
+            let (mut client, state) =
+                test_client(&dir, minter.public(), &mint_params, &spend_params).await?;
+            let mnemonic = client.wallet.get_mnemonic()?.unwrap();
+
+            // Paid to the key at index 3, to the first key of the next
+            // account, and to the third diversified address
+            let mut key = client.new_key().await?;
+            for _ in 0..2 {
+                key = client.new_key().await?;
+            }
+            let other = client.wallet.derive_keypair(DEFAULT_ACCOUNT + 1)?;
+            let recipient = Address::from_secret(&key.private);
+            store_mint(&client, &minter, recipient, 110, &mint_params, &spend_params)?;
+            let recipient = Address::from_secret(&other.private);
+            store_mint(&client, &minter, recipient, 70, &mint_params, &spend_params)?;
+            let mut recipient = client.new_diversified_address(None)?;
+            for _ in 0..2 {
+                recipient = client.new_diversified_address(None)?;
+            }
+            store_mint(&client, &minter, recipient, 30, &mint_params, &spend_params)?;
+
+            client.restore(&mnemonic, state.clone()).await?;
+
+            let mut values: Vec<u64> = client
+                .wallet
+                .get_own_coins()?
+                .iter()
+                .map(|coin| coin.note.value)
+                .collect();
+            values.sort_unstable();
+            assert_eq!(values, vec![30, 70, 110]);
+
+            // Keys are handed out again after the last one paid to
+            assert_eq!(client.wallet.get_next_key_index(DEFAULT_ACCOUNT)?, 4);
+            assert_eq!(client.wallet.get_next_key_index(DEFAULT_ACCOUNT + 1)?, 1);
+            let next = client.new_key().await?;
+            assert_eq!(client.wallet.get_next_key_index(DEFAULT_ACCOUNT)?, 5);
+            let keypairs = client.wallet.get_keypairs()?;
+            assert_eq!(keypairs.iter().filter(|k| k.public == next.public).count(), 1);
+
+            // And so are diversified addresses
+            let main = client.main_keypair.clone();
+            assert_eq!(client.wallet.get_labeled_diversifiers(&main.public)?.len(), 3);
+            let address = client.new_diversified_address(None)?;
+            let diversifier = Diversifier::derive(&main.private, 3);
+            assert_eq!(address, Address::diversified(&main.private, &diversifier));
+            Ok::<(), Error>(())
+        })?;
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
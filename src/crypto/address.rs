@@ -0,0 +1,344 @@
+use std::fmt;
+use std::str::FromStr;
+
+use blake2b_simd::Params;
+
+use super::bech32;
+use super::util::hash_to_scalar;
+use super::viewing_key::ViewingKey;
+use crate::error::{Error, Result};
+use crate::serial::{deserialize, serialize};
+
+/// Where coins are paid to: the key that owns them, and the key their
+/// notes are encrypted to. Addresses from wallets without viewing keys
+/// carry only the first, and notes are encrypted to it directly.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Address {
+    pub public: jubjub::SubgroupPoint,
+    pub view_public: jubjub::SubgroupPoint,
+}
+
+impl Address {
+    pub fn from_secret(secret: &jubjub::Fr) -> Self {
+        Self {
+            public: zcash_primitives::constants::SPENDING_KEY_GENERATOR * secret,
+            view_public: ViewingKey::from_secret(secret).public(),
+        }
+    }
+
+    /// One of the many addresses a key can hand out. Payments to them
+    /// can't be linked to each other or to the key's own address.
+    ///
+    /// Unlike a Sapling diversified address, each one is owned by a key of
+    /// its own: the mint and spend circuits commit a coin to a public key
+    /// that is part of the address, so addresses sharing it would be
+    /// linkable. The wallet therefore tries every address it handed out
+    /// when decrypting notes, and a viewing key only reveals the payments
+    /// to one address.
+    pub fn diversified(secret: &jubjub::Fr, diversifier: &Diversifier) -> Self {
+        Self::from_secret(&diversifier.secret(secret))
+    }
+
+    pub fn legacy(public: jubjub::SubgroupPoint) -> Self {
+        Self {
+            public,
+            view_public: public,
+        }
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.public == self.view_public
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = serialize(&self.public);
+        if !self.is_legacy() {
+            bytes.extend_from_slice(&serialize(&self.view_public));
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        match bytes.len() {
+            32 => Ok(Self::legacy(deserialize(bytes)?)),
+            64 => Ok(Self {
+                public: deserialize(&bytes[..32])?,
+                view_public: deserialize(&bytes[32..])?,
+            }),
+            _ => Err(Error::ParseFailed("Invalid address length")),
+        }
+    }
+
+    /// Bech32 form of the address, e.g. `tdrk1...` on the testnet. Its
+    /// checksum catches typos the base58 form lets through.
+    pub fn encode(&self, network: AddressNetwork) -> String {
+        bech32::encode(network.prefix(), &self.to_bytes()).unwrap()
+    }
+
+    /// Parse a bech32 address, rejecting ones meant for another network
+    pub fn decode(s: &str, network: AddressNetwork) -> Result<Self> {
+        let (network_of, address) = Self::decode_any(s)?;
+        if network_of != network {
+            return Err(Error::ParseFailed("Address is for another network"));
+        }
+        Ok(address)
+    }
+
+    /// Parse a bech32 address along with the network it is meant for
+    pub fn decode_any(s: &str) -> Result<(AddressNetwork, Self)> {
+        let (prefix, bytes) = bech32::decode(s)?;
+        let network = AddressNetwork::from_prefix(&prefix)?;
+        Ok((network, Self::from_bytes(&bytes)?))
+    }
+
+    /// A few words derived from the hash of the address, which a user can
+    /// compare with the recipient at a glance. An address swapped for
+    /// another one on the way gets different words.
+    pub fn fingerprint(&self) -> String {
+        let hash = Params::new()
+            .hash_length(32)
+            .personal(b"DarkFi_AddrPrint")
+            .hash(&self.to_bytes());
+        let hash = hash.as_bytes();
+
+        let words = bip39::Language::English.word_list();
+        (0..FINGERPRINT_WORDS)
+            .map(|i| {
+                let index = u16::from_le_bytes([hash[2 * i], hash[2 * i + 1]]) as usize;
+                words[index % words.len()]
+            })
+            .collect::<Vec<_>>()
+            .join("-")
+    }
+}
+
+/// Number of words in an address fingerprint
+pub const FINGERPRINT_WORDS: usize = 4;
+
+/// The network an address is meant for, which sets its bech32 prefix
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AddressNetwork {
+    Mainnet,
+    Testnet,
+}
+
+impl AddressNetwork {
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            AddressNetwork::Mainnet => "drk",
+            AddressNetwork::Testnet => "tdrk",
+        }
+    }
+
+    pub fn from_prefix(prefix: &str) -> Result<Self> {
+        match prefix {
+            "drk" => Ok(AddressNetwork::Mainnet),
+            "tdrk" => Ok(AddressNetwork::Testnet),
+            _ => Err(Error::ParseFailed("Not a DarkFi address")),
+        }
+    }
+}
+
+impl fmt::Display for AddressNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AddressNetwork::Mainnet => f.write_str("mainnet"),
+            AddressNetwork::Testnet => f.write_str("testnet"),
+        }
+    }
+}
+
+impl FromStr for AddressNetwork {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "mainnet" => Ok(AddressNetwork::Mainnet),
+            "testnet" => Ok(AddressNetwork::Testnet),
+            _ => Err(Error::ParseFailed(
+                "Unknown network, expected mainnet or testnet",
+            )),
+        }
+    }
+}
+
+pub const DIVERSIFIER_SIZE: usize = 11;
+
+/// Picks one of the addresses of a key. Coins paid to a diversified
+/// address are owned by a key derived from the diversifier, so the wallet
+/// must keep the diversifiers it handed out to find and spend them.
+/// They're derived from the key and an index, so a wallet restored from
+/// its seed can derive them again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Diversifier(pub [u8; DIVERSIFIER_SIZE]);
+
+impl Diversifier {
+    /// The diversifier at `index` of the key `secret`
+    pub fn derive(secret: &jubjub::Fr, index: u32) -> Self {
+        let hash = Params::new()
+            .hash_length(DIVERSIFIER_SIZE)
+            .personal(b"DarkFi_DivIndex_")
+            .to_state()
+            .update(&secret.to_bytes())
+            .update(&index.to_le_bytes())
+            .finalize();
+        let mut diversifier = [0u8; DIVERSIFIER_SIZE];
+        diversifier.copy_from_slice(hash.as_bytes());
+        Self(diversifier)
+    }
+
+    pub fn secret(&self, secret: &jubjub::Fr) -> jubjub::Fr {
+        hash_to_scalar(b"DarkFi_Diversify", &secret.to_bytes(), &self.0)
+    }
+}
+
+// The base58 form, as found in configs and the cashier protocol
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&bs58::encode(self.to_bytes()).into_string())
+    }
+}
+
+impl FromStr for Address {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::from_bytes(&bs58::decode(s).into_vec()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::note::{Memo, Note};
+    use ff::Field;
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn test_address_viewing_key() -> Result<()> {
+        let secret = jubjub::Fr::random(&mut OsRng);
+        let address = Address::from_secret(&secret);
+
+        assert!(!address.is_legacy());
+        assert_eq!(address.to_string().parse::<Address>()?, address);
+
+        let legacy = Address::legacy(address.public);
+        assert_eq!(
+            legacy.to_string(),
+            bs58::encode(serialize(&address.public)).into_string()
+        );
+        assert_eq!(legacy.to_string().parse::<Address>()?, legacy);
+
+        let note = Note {
+            serial: jubjub::Fr::random(&mut OsRng),
+            value: 110,
+            token_id: jubjub::Fr::random(&mut OsRng),
+            coin_blind: jubjub::Fr::random(&mut OsRng),
+            valcom_blind: jubjub::Fr::random(&mut OsRng),
+            memo: Memo::empty(),
+        };
+        let enc_note = note.encrypt(&address.view_public)?;
+
+        // The viewing key reads the note, and survives an export
+        let view_key: ViewingKey = ViewingKey::from_secret(&secret).to_string().parse()?;
+        assert_eq!(view_key.decrypt_note(&enc_note)?.value, 110);
+
+        // The spending key alone isn't what the note is encrypted to
+        assert!(enc_note.decrypt(&secret).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_address_bech32() -> Result<()> {
+        let address = Address::from_secret(&jubjub::Fr::random(&mut OsRng));
+
+        let encoded = address.encode(AddressNetwork::Testnet);
+        assert!(encoded.starts_with("tdrk1"));
+        assert_eq!(Address::decode(&encoded, AddressNetwork::Testnet)?, address);
+        assert!(Address::decode(&encoded, AddressNetwork::Mainnet).is_err());
+
+        let encoded = address.encode(AddressNetwork::Mainnet);
+        assert!(encoded.starts_with("drk1"));
+        assert_eq!(Address::decode(&encoded, AddressNetwork::Mainnet)?, address);
+
+        let legacy = Address::legacy(address.public);
+        let encoded = legacy.encode(AddressNetwork::Mainnet);
+        assert_eq!(Address::decode(&encoded, AddressNetwork::Mainnet)?, legacy);
+
+        // A typo no longer decodes to some other valid address
+        let mut typo: Vec<char> = encoded.chars().collect();
+        typo[10] = if typo[10] == 'q' { 'p' } else { 'q' };
+        let typo: String = typo.into_iter().collect();
+        assert!(Address::decode(&typo, AddressNetwork::Mainnet).is_err());
+
+        // The base58 form has no network
+        assert!(Address::decode(&address.to_string(), AddressNetwork::Mainnet).is_err());
+
+        let encoded = address.encode(AddressNetwork::Testnet);
+        assert_eq!(
+            Address::decode_any(&encoded)?,
+            (AddressNetwork::Testnet, address)
+        );
+
+        // Fingerprints are stable and tell addresses apart
+        let fingerprint = address.fingerprint();
+        assert_eq!(fingerprint.split('-').count(), FINGERPRINT_WORDS);
+        assert_eq!(fingerprint, address.fingerprint());
+        assert_ne!(fingerprint, legacy.fingerprint());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_diversified_address() -> Result<()> {
+        let secret = jubjub::Fr::random(&mut OsRng);
+        let address = Address::from_secret(&secret);
+
+        let d1 = Diversifier::derive(&secret, 0);
+        let d2 = Diversifier::derive(&secret, 1);
+        let address1 = Address::diversified(&secret, &d1);
+        let address2 = Address::diversified(&secret, &d2);
+
+        // Nothing in the addresses is shared
+        for a in [address, address1].iter() {
+            assert_ne!(a.public, address2.public);
+            assert_ne!(a.view_public, address2.view_public);
+        }
+        assert_ne!(address.public, address1.public);
+
+        // The same index always gives the same address, and another key
+        // other ones
+        assert_eq!(Diversifier::derive(&secret, 0), d1);
+        assert_eq!(Address::diversified(&secret, &d1), address1);
+        let other = jubjub::Fr::random(&mut OsRng);
+        assert_ne!(Diversifier::derive(&other, 0), d1);
+
+        // The key derived for the diversifier owns its coins and reads its notes
+        let secret1 = d1.secret(&secret);
+        assert_eq!(
+            address1.public,
+            zcash_primitives::constants::SPENDING_KEY_GENERATOR * secret1
+        );
+        let note = Note {
+            serial: jubjub::Fr::random(&mut OsRng),
+            value: 110,
+            token_id: jubjub::Fr::random(&mut OsRng),
+            coin_blind: jubjub::Fr::random(&mut OsRng),
+            valcom_blind: jubjub::Fr::random(&mut OsRng),
+            memo: Memo::empty(),
+        };
+        let enc_note = note.encrypt(&address1.view_public)?;
+        assert_eq!(
+            ViewingKey::from_secret(&secret1)
+                .decrypt_note(&enc_note)?
+                .value,
+            110
+        );
+        assert!(ViewingKey::from_secret(&secret)
+            .decrypt_note(&enc_note)
+            .is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,197 @@
+//! Batch verification of Groth16 proofs made with the same verifying key.
+//!
+//! A proof (A, B, C) for the public inputs x checks
+//! e(A, B) = e(alpha, beta) * e(IC(x), gamma) * e(C, delta). Raising the
+//! check of each proof to a random power and multiplying them together
+//! moves all the gamma and delta terms into a single pairing each, so a
+//! batch costs one miller loop per proof plus one final exponentiation.
+//! A bad proof only passes if it cancels out against the random powers,
+//! which happens with negligible probability.
+
+use bellman::groth16;
+use bls12_381::{
+    multi_miller_loop, pairing, Bls12, G1Affine, G1Projective, G2Affine, G2Prepared, Gt, Scalar,
+};
+use ff::Field;
+use group::Group;
+use log::debug;
+use rand::rngs::OsRng;
+use std::time::Instant;
+
+/// The parts of a verifying key used by batch verification
+pub struct BatchVerifyingKey {
+    alpha_g1_beta_g2: Gt,
+    neg_gamma_g2: G2Prepared,
+    neg_delta_g2: G2Prepared,
+    ic: Vec<G1Affine>,
+}
+
+impl BatchVerifyingKey {
+    pub fn new(vk: &groth16::VerifyingKey<Bls12>) -> Self {
+        Self {
+            alpha_g1_beta_g2: pairing(&vk.alpha_g1, &vk.beta_g2),
+            neg_gamma_g2: G2Prepared::from(-vk.gamma_g2),
+            neg_delta_g2: G2Prepared::from(-vk.delta_g2),
+            ic: vk.ic.clone(),
+        }
+    }
+}
+
+pub struct BatchVerifier<'a> {
+    vk: &'a BatchVerifyingKey,
+    items: Vec<(G1Affine, G2Affine, G1Affine, Vec<Scalar>)>,
+}
+
+impl<'a> BatchVerifier<'a> {
+    pub fn new(vk: &'a BatchVerifyingKey) -> Self {
+        Self { vk, items: vec![] }
+    }
+
+    pub fn add(&mut self, proof: &groth16::Proof<Bls12>, public_input: &[Scalar]) {
+        self.items
+            .push((proof.a, proof.b, proof.c, public_input.to_vec()));
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Returns true if every proof in the batch is valid. It doesn't say
+    /// which proof is bad when one is, for that they have to be verified
+    /// one by one.
+    pub fn verify(self) -> bool {
+        if self.items.is_empty() {
+            return true;
+        }
+
+        let start = Instant::now();
+
+        let mut acc_input = G1Projective::identity();
+        let mut acc_c = G1Projective::identity();
+        let mut r_sum = Scalar::zero();
+        let mut scaled_a = vec![];
+        let mut prepared_b = vec![];
+
+        for (a, b, c, public_input) in &self.items {
+            if public_input.len() + 1 != self.vk.ic.len() {
+                return false;
+            }
+
+            let r = Scalar::random(&mut OsRng);
+
+            let mut input = G1Projective::from(self.vk.ic[0]);
+            for (ic, x) in self.vk.ic.iter().skip(1).zip(public_input) {
+                input += ic * x;
+            }
+
+            acc_input += input * r;
+            acc_c += c * &r;
+            r_sum += r;
+            scaled_a.push(G1Affine::from(a * &r));
+            prepared_b.push(G2Prepared::from(*b));
+        }
+
+        let acc_input = G1Affine::from(acc_input);
+        let acc_c = G1Affine::from(acc_c);
+
+        let mut terms: Vec<(&G1Affine, &G2Prepared)> =
+            scaled_a.iter().zip(prepared_b.iter()).collect();
+        terms.push((&acc_input, &self.vk.neg_gamma_g2));
+        terms.push((&acc_c, &self.vk.neg_delta_g2));
+
+        let result =
+            multi_miller_loop(&terms).final_exponentiation() == self.vk.alpha_g1_beta_g2 * r_sum;
+
+        debug!(
+            target: "BATCH VERIFY",
+            "Verified {} proofs in [{:?}]",
+            self.items.len(),
+            start.elapsed()
+        );
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bellman::{Circuit, ConstraintSystem, SynthesisError};
+
+    // Knowledge of x and y such that x * y is the public z
+    struct Product {
+        x: Option<Scalar>,
+        y: Option<Scalar>,
+    }
+
+    impl Circuit<Scalar> for Product {
+        fn synthesize<CS: ConstraintSystem<Scalar>>(
+            self,
+            cs: &mut CS,
+        ) -> Result<(), SynthesisError> {
+            let x = cs.alloc(|| "x", || self.x.ok_or(SynthesisError::AssignmentMissing))?;
+            let y = cs.alloc(|| "y", || self.y.ok_or(SynthesisError::AssignmentMissing))?;
+            let z = cs.alloc_input(
+                || "z",
+                || {
+                    let x = self.x.ok_or(SynthesisError::AssignmentMissing)?;
+                    let y = self.y.ok_or(SynthesisError::AssignmentMissing)?;
+                    Ok(x * y)
+                },
+            )?;
+            cs.enforce(|| "x * y = z", |lc| lc + x, |lc| lc + y, |lc| lc + z);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_batch_verify() {
+        let params = groth16::generate_random_parameters::<Bls12, _, _>(
+            Product { x: None, y: None },
+            &mut OsRng,
+        )
+        .unwrap();
+        let pvk = groth16::prepare_verifying_key(&params.vk);
+        let vk = BatchVerifyingKey::new(&params.vk);
+
+        let proofs: Vec<_> = (1..10u64)
+            .map(|i| {
+                let (x, y) = (Scalar::from(i), Scalar::from(i + 1));
+                let circuit = Product {
+                    x: Some(x),
+                    y: Some(y),
+                };
+                let proof = groth16::create_random_proof(circuit, &params, &mut OsRng).unwrap();
+                (proof, x * y)
+            })
+            .collect();
+
+        let mut batch = BatchVerifier::new(&vk);
+        for (proof, z) in &proofs {
+            assert!(groth16::verify_proof(&pvk, proof, &[*z]).is_ok());
+            batch.add(proof, &[*z]);
+        }
+        assert_eq!(batch.len(), proofs.len());
+        assert!(batch.verify());
+
+        // A single wrong public input fails the whole batch
+        let mut batch = BatchVerifier::new(&vk);
+        for (i, (proof, z)) in proofs.iter().enumerate() {
+            let z = if i == 4 { *z + Scalar::one() } else { *z };
+            batch.add(proof, &[z]);
+        }
+        assert!(!batch.verify());
+
+        // As does a proof for another statement
+        let mut batch = BatchVerifier::new(&vk);
+        batch.add(&proofs[0].0, &[proofs[1].1]);
+        batch.add(&proofs[1].0, &[proofs[0].1]);
+        assert!(!batch.verify());
+
+        assert!(BatchVerifier::new(&vk).verify());
+    }
+}
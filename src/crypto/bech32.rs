@@ -0,0 +1,146 @@
+//! Bech32 encoding as specified in BIP-173, without its 90 character
+//! limit since addresses carry two curve points.
+
+use crate::error::{Error, Result};
+
+const CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+
+fn polymod(values: &[u8]) -> u32 {
+    let mut chk: u32 = 1;
+    for value in values {
+        let top = chk >> 25;
+        chk = ((chk & 0x1ffffff) << 5) ^ *value as u32;
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                chk ^= generator;
+            }
+        }
+    }
+    chk
+}
+
+fn hrp_expand(hrp: &str) -> Vec<u8> {
+    let mut expanded: Vec<u8> = hrp.bytes().map(|b| b >> 5).collect();
+    expanded.push(0);
+    expanded.extend(hrp.bytes().map(|b| b & 31));
+    expanded
+}
+
+fn checksum(hrp: &str, data: &[u8]) -> Vec<u8> {
+    let mut values = hrp_expand(hrp);
+    values.extend_from_slice(data);
+    values.extend_from_slice(&[0; 6]);
+    let polymod = polymod(&values) ^ 1;
+    (0..6)
+        .map(|i| ((polymod >> (5 * (5 - i))) & 31) as u8)
+        .collect()
+}
+
+// Regroup bits, e.g. bytes into the 5 bit groups bech32 is written in
+fn convert_bits(data: &[u8], from: u32, to: u32, pad: bool) -> Result<Vec<u8>> {
+    let mut acc: u32 = 0;
+    let mut bits: u32 = 0;
+    let maxv: u32 = (1 << to) - 1;
+    let mut ret = vec![];
+
+    for value in data {
+        acc = (acc << from) | *value as u32;
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            ret.push(((acc >> bits) & maxv) as u8);
+        }
+    }
+
+    if pad {
+        if bits > 0 {
+            ret.push(((acc << (to - bits)) & maxv) as u8);
+        }
+    } else if bits >= from || ((acc << (to - bits)) & maxv) != 0 {
+        return Err(Error::ParseFailed("Invalid bech32 padding"));
+    }
+
+    Ok(ret)
+}
+
+pub fn encode(hrp: &str, data: &[u8]) -> Result<String> {
+    let data = convert_bits(data, 8, 5, true)?;
+    let checksum = checksum(hrp, &data);
+
+    let mut encoded = hrp.to_string();
+    encoded.push('1');
+    for value in data.iter().chain(checksum.iter()) {
+        encoded.push(CHARSET[*value as usize] as char);
+    }
+    Ok(encoded)
+}
+
+/// Returns the human readable part and the data, if the checksum matches
+pub fn decode(s: &str) -> Result<(String, Vec<u8>)> {
+    if s.to_lowercase() != s && s.to_uppercase() != s {
+        return Err(Error::ParseFailed("Mixed case bech32 string"));
+    }
+    let s = s.to_lowercase();
+
+    let sep = s
+        .rfind('1')
+        .ok_or(Error::ParseFailed("Missing bech32 separator"))?;
+    if sep == 0 || sep + 7 > s.len() {
+        return Err(Error::ParseFailed("Invalid bech32 length"));
+    }
+
+    let hrp = &s[..sep];
+    if hrp.bytes().any(|b| !(33..=126).contains(&b)) {
+        return Err(Error::ParseFailed("Invalid bech32 prefix"));
+    }
+
+    let mut data = vec![];
+    for c in s[sep + 1..].bytes() {
+        let value = CHARSET
+            .iter()
+            .position(|x| *x == c)
+            .ok_or(Error::ParseFailed("Invalid bech32 character"))?;
+        data.push(value as u8);
+    }
+
+    let mut values = hrp_expand(hrp);
+    values.extend_from_slice(&data);
+    if polymod(&values) != 1 {
+        return Err(Error::ParseFailed("Invalid bech32 checksum"));
+    }
+
+    let data = convert_bits(&data[..data.len() - 6], 5, 8, false)?;
+    Ok((hrp.to_string(), data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bech32() -> Result<()> {
+        // BIP-173 test vectors without data
+        assert_eq!(decode("a12uel5l")?, ("a".to_string(), vec![]));
+        assert_eq!(decode("A12UEL5L")?, ("a".to_string(), vec![]));
+        assert!(decode("A12uEL5L").is_err());
+        assert!(decode("pzry9x0s0muk").is_err());
+        assert_eq!(encode("a", &[])?, "a12uel5l");
+
+        let data: Vec<u8> = (0..64).collect();
+        let encoded = encode("tdrk", &data)?;
+        assert!(encoded.starts_with("tdrk1"));
+        assert_eq!(decode(&encoded)?, ("tdrk".to_string(), data));
+
+        // Every single character typo is caught
+        let chars: Vec<char> = encoded.chars().collect();
+        for i in 5..chars.len() {
+            let mut typo = chars.clone();
+            typo[i] = if typo[i] == 'q' { 'p' } else { 'q' };
+            let typo: String = typo.into_iter().collect();
+            assert!(decode(&typo).is_err());
+        }
+
+        Ok(())
+    }
+}
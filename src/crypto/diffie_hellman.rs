@@ -2,6 +2,8 @@ use blake2b_simd::{Hash as Blake2bHash, Params as Blake2bParams};
 use group::{cofactor::CofactorGroup, GroupEncoding};
 
 pub const KDF_SAPLING_PERSONALIZATION: &[u8; 16] = b"DarkFiSaplingKDF";
+pub const NOTE_KEY_PERSONALIZATION: &[u8; 16] = b"DarkFiNoteEncKey";
+pub const NOTE_KEY_COMMIT_PERSONALIZATION: &[u8; 16] = b"DarkFiNoteKeyCom";
 
 /// Functions used for encrypting the note in transaction outputs.
 
@@ -34,3 +36,24 @@ pub fn kdf_sapling(dhsecret: jubjub::SubgroupPoint, epk: &jubjub::ExtendedPoint)
         .update(&epk.to_bytes())
         .finalize()
 }
+
+/// Split the KDF output into the AEAD key and a commitment to it.
+///
+/// Poly1305 alone doesn't bind a ciphertext to a single key, so the
+/// commitment is sent along with the note and checked before decrypting.
+pub fn note_keys(kdf_output: &Blake2bHash) -> ([u8; 32], [u8; 32]) {
+    let derive = |persona: &[u8; 16]| {
+        let hash = Blake2bParams::new()
+            .hash_length(32)
+            .personal(persona)
+            .hash(kdf_output.as_bytes());
+        let mut ret = [0u8; 32];
+        ret.copy_from_slice(hash.as_bytes());
+        ret
+    };
+
+    (
+        derive(NOTE_KEY_PERSONALIZATION),
+        derive(NOTE_KEY_COMMIT_PERSONALIZATION),
+    )
+}
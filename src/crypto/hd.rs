@@ -0,0 +1,116 @@
+use blake2b_simd::Params;
+
+const MASTER_PERSONALIZATION: &[u8; 16] = b"DarkFi_HD_Master";
+const CHILD_PERSONALIZATION: &[u8; 16] = b"DarkFi_HD_Child_";
+const SPENDING_KEY_PERSONALIZATION: &[u8; 16] = b"DarkFi_SpendKey_";
+
+/// Purpose field of the derivation path, following ZIP32
+pub const PURPOSE: u32 = 32;
+
+const HARDENED: u32 = 1 << 31;
+
+/// Hardened-only extended spending key, derived ZIP32-style from a seed.
+/// Wallet keys live at the path m/32'/account'/index'.
+#[derive(Clone)]
+pub struct ExtendedSpendingKey {
+    pub depth: u8,
+    pub child_index: u32,
+    key: [u8; 32],
+    chain_code: [u8; 32],
+}
+
+impl ExtendedSpendingKey {
+    pub fn master(seed: &[u8]) -> Self {
+        let hash = Params::new()
+            .hash_length(64)
+            .personal(MASTER_PERSONALIZATION)
+            .hash(seed);
+        Self::from_hash(0, 0, hash.as_bytes())
+    }
+
+    pub fn derive_child(&self, index: u32) -> Self {
+        let index = index | HARDENED;
+        let hash = Params::new()
+            .hash_length(64)
+            .key(&self.chain_code)
+            .personal(CHILD_PERSONALIZATION)
+            .to_state()
+            .update(&[0x11])
+            .update(&self.key)
+            .update(&index.to_le_bytes())
+            .finalize();
+        Self::from_hash(self.depth + 1, index, hash.as_bytes())
+    }
+
+    pub fn derive_path(&self, path: &[u32]) -> Self {
+        path.iter()
+            .fold(self.clone(), |xsk, index| xsk.derive_child(*index))
+    }
+
+    /// Derive the wallet key at m/32'/account'/index'
+    pub fn from_seed(seed: &[u8], account: u32, index: u32) -> Self {
+        Self::master(seed).derive_path(&[PURPOSE, account, index])
+    }
+
+    pub fn secret(&self) -> jubjub::Fr {
+        let hash = Params::new()
+            .hash_length(64)
+            .personal(SPENDING_KEY_PERSONALIZATION)
+            .hash(&self.key);
+        jubjub::Fr::from_bytes_wide(hash.as_array())
+    }
+
+    pub fn public(&self) -> jubjub::SubgroupPoint {
+        zcash_primitives::constants::SPENDING_KEY_GENERATOR * self.secret()
+    }
+
+    fn from_hash(depth: u8, child_index: u32, hash: &[u8]) -> Self {
+        let mut key = [0u8; 32];
+        let mut chain_code = [0u8; 32];
+        key.copy_from_slice(&hash[..32]);
+        chain_code.copy_from_slice(&hash[32..]);
+        Self {
+            depth,
+            child_index,
+            key,
+            chain_code,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hd_derivation() {
+        let seed = [7u8; 64];
+
+        let xsk = ExtendedSpendingKey::from_seed(&seed, 0, 0);
+        assert_eq!(xsk.depth, 3);
+        assert_eq!(xsk.child_index, HARDENED);
+        assert_eq!(
+            xsk.secret(),
+            ExtendedSpendingKey::from_seed(&seed, 0, 0).secret()
+        );
+
+        let path = ExtendedSpendingKey::master(&seed)
+            .derive_child(PURPOSE)
+            .derive_child(0)
+            .derive_child(0);
+        assert_eq!(xsk.secret(), path.secret());
+
+        assert_ne!(
+            xsk.secret(),
+            ExtendedSpendingKey::from_seed(&seed, 0, 1).secret()
+        );
+        assert_ne!(
+            xsk.secret(),
+            ExtendedSpendingKey::from_seed(&seed, 1, 0).secret()
+        );
+        assert_ne!(
+            xsk.secret(),
+            ExtendedSpendingKey::from_seed(&[8u8; 64], 0, 0).secret()
+        );
+    }
+}
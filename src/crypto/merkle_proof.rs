@@ -0,0 +1,143 @@
+//! Merkle paths proving a coin is in the commitment tree, for explorers and
+//! auditors checking a coin against a root without keeping the tree. A path
+//! is the sibling of each node on the way up from the leaf, and the leaf's
+//! position, whose bits tell which side each sibling goes on.
+
+use super::coin::Coin;
+use super::merkle::{CommitmentTree, Hashable, IncrementalWitness, MerklePath};
+use super::merkle_node::{MerkleNode, SAPLING_COMMITMENT_TREE_DEPTH};
+
+/// The path from the leaf at `position`, taken from every leaf in the tree
+/// in the order they were appended
+pub fn path_from_leaves(leaves: &[MerkleNode], position: u64) -> Option<MerklePath<MerkleNode>> {
+    let position = position as usize;
+    if position >= leaves.len() {
+        return None;
+    }
+
+    let mut tree = CommitmentTree::empty();
+    for leaf in &leaves[..=position] {
+        tree.append(*leaf).ok()?;
+    }
+    let mut witness = IncrementalWitness::from_tree(&tree);
+    for leaf in &leaves[position + 1..] {
+        witness.append(*leaf).ok()?;
+    }
+    witness.path()
+}
+
+/// A path from its siblings, lowest first, and the leaf position
+pub fn path_from_siblings(siblings: Vec<MerkleNode>, position: u64) -> MerklePath<MerkleNode> {
+    let auth_path = siblings
+        .into_iter()
+        .enumerate()
+        .map(|(depth, sibling)| (sibling, (position >> depth) & 1 == 1))
+        .collect();
+    MerklePath::from_path(auth_path, position)
+}
+
+/// Whether `path` leads from `leaf` up to `root`. The path must span the
+/// whole depth of the tree, and the sides of its siblings agree with its
+/// position, so it can't be passed off as the path of another leaf.
+pub fn verify_merkle_path(
+    root: &MerkleNode,
+    leaf: &MerkleNode,
+    path: &MerklePath<MerkleNode>,
+) -> bool {
+    if path.auth_path.len() != SAPLING_COMMITMENT_TREE_DEPTH {
+        return false;
+    }
+    let sides_match = path
+        .auth_path
+        .iter()
+        .enumerate()
+        .all(|(depth, (_, is_right))| ((path.position >> depth) & 1 == 1) == *is_right);
+    if !sides_match {
+        return false;
+    }
+    path.root(*leaf) == *root
+}
+
+/// Same as verify_merkle_path(), for the leaf of `coin`
+pub fn verify_coin_path(root: &MerkleNode, coin: &Coin, path: &MerklePath<MerkleNode>) -> bool {
+    verify_merkle_path(root, &MerkleNode::from_coin(coin), path)
+}
+
+/// The root of an empty tree, which no path leads to
+pub fn empty_root() -> MerkleNode {
+    MerkleNode::empty_root(SAPLING_COMMITMENT_TREE_DEPTH)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Coins with fixed commitments, so the paths are the same on every run
+    fn vector_coins(count: u8) -> Vec<Coin> {
+        (0..count).map(|i| Coin::new([i + 1; 32])).collect()
+    }
+
+    fn vector_tree(coins: &[Coin]) -> (Vec<MerkleNode>, MerkleNode) {
+        let leaves: Vec<MerkleNode> = coins.iter().map(MerkleNode::from_coin).collect();
+        let mut tree = CommitmentTree::empty();
+        for leaf in &leaves {
+            tree.append(*leaf).unwrap();
+        }
+        (leaves, tree.root())
+    }
+
+    #[test]
+    fn test_merkle_path_vectors() {
+        let coins = vector_coins(5);
+        let (leaves, root) = vector_tree(&coins);
+        assert_ne!(root, empty_root());
+
+        // Every coin has a path to the root, also when rebuilt from its
+        // siblings alone as an external verifier would
+        for (position, coin) in coins.iter().enumerate() {
+            let path = path_from_leaves(&leaves, position as u64).unwrap();
+            assert_eq!(path.position, position as u64);
+            assert!(verify_coin_path(&root, coin, &path));
+
+            let siblings = path.auth_path.iter().map(|(node, _)| *node).collect();
+            let rebuilt = path_from_siblings(siblings, position as u64);
+            assert_eq!(rebuilt.auth_path, path.auth_path);
+            assert!(verify_coin_path(&root, coin, &rebuilt));
+        }
+
+        // The path of a coin appended later than the root was taken
+        let (_, old_root) = vector_tree(&coins[..3]);
+        let path = path_from_leaves(&leaves, 1).unwrap();
+        assert!(!verify_coin_path(&old_root, &coins[1], &path));
+
+        assert!(path_from_leaves(&leaves, 5).is_none());
+        assert!(path_from_leaves(&[], 0).is_none());
+    }
+
+    #[test]
+    fn test_merkle_path_tampered() {
+        let coins = vector_coins(4);
+        let (leaves, root) = vector_tree(&coins);
+        let path = path_from_leaves(&leaves, 2).unwrap();
+        assert!(verify_coin_path(&root, &coins[2], &path));
+
+        // Another coin doesn't verify with this path
+        assert!(!verify_coin_path(&root, &coins[3], &path));
+        assert!(!verify_coin_path(&root, &Coin::new([0xff; 32]), &path));
+
+        // Nor does a path with a sibling changed
+        let mut tampered = path.clone();
+        tampered.auth_path[0].0 = leaves[0];
+        assert!(!verify_coin_path(&root, &coins[2], &tampered));
+
+        // Or claiming another position with the same siblings
+        let mut moved = path.clone();
+        moved.position = 6;
+        assert!(!verify_coin_path(&root, &coins[2], &moved));
+
+        // Or cut short
+        let mut short = path;
+        short.auth_path.pop();
+        assert!(!verify_coin_path(&root, &coins[2], &short));
+    }
+}
@@ -18,7 +18,7 @@ pub struct MintRevealedValues {
 }
 
 impl MintRevealedValues {
-    fn compute(
+    pub fn compute(
         value: u64,
         token_id: jubjub::Fr,
         randomness_value: &jubjub::Fr,
@@ -59,7 +59,7 @@ impl MintRevealedValues {
         }
     }
 
-    fn make_outputs(&self) -> [bls12_381::Scalar; 6] {
+    pub fn make_outputs(&self) -> [bls12_381::Scalar; 6] {
         let mut public_input = [bls12_381::Scalar::zero(); 6];
 
         {
@@ -0,0 +1,39 @@
+use bip39::Mnemonic;
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+use crate::error::Result;
+
+/// Entropy size in bytes, giving a 24 word mnemonic
+pub const MNEMONIC_ENTROPY_SIZE: usize = 32;
+
+pub fn generate_mnemonic() -> Result<String> {
+    let mut entropy = [0u8; MNEMONIC_ENTROPY_SIZE];
+    OsRng.fill_bytes(&mut entropy);
+    let mnemonic = Mnemonic::from_entropy(&entropy)?;
+    Ok(mnemonic.to_string())
+}
+
+pub fn mnemonic_to_seed(phrase: &str) -> Result<[u8; 64]> {
+    let mnemonic = Mnemonic::parse(phrase)?;
+    Ok(mnemonic.to_seed(""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mnemonic_seed() -> Result<()> {
+        let phrase = generate_mnemonic()?;
+        assert_eq!(phrase.split_whitespace().count(), 24);
+
+        let seed = mnemonic_to_seed(&phrase)?;
+        assert_eq!(seed[..], mnemonic_to_seed(&phrase)?[..]);
+        assert_ne!(seed[..], mnemonic_to_seed(&generate_mnemonic()?)?[..]);
+
+        assert!(mnemonic_to_seed("not a valid mnemonic").is_err());
+
+        Ok(())
+    }
+}
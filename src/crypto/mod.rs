@@ -1,23 +1,39 @@
+pub mod address;
+pub mod batch_verify;
+pub mod bech32;
 pub mod coin;
 pub mod diffie_hellman;
 pub mod fr_serial;
+pub mod hd;
 pub mod merkle;
 pub mod merkle_node;
+pub mod merkle_proof;
 pub mod mint_proof;
+pub mod mnemonic;
 pub mod note;
 pub mod nullifier;
+pub mod sapling;
 pub mod schnorr;
 pub mod spend_proof;
 pub mod util;
+pub mod verify_pool;
+pub mod viewing_key;
+
+use std::path::{Path, PathBuf};
 
 use bellman::groth16;
+use blake2b_simd::Params;
 use bls12_381::Bls12;
 
-use crate::error::Result;
+use crate::error::{Error, Result};
+pub use address::{Address, AddressNetwork, Diversifier};
+pub use batch_verify::{BatchVerifier, BatchVerifyingKey};
 pub use mint_proof::{create_mint_proof, setup_mint_prover, verify_mint_proof, MintRevealedValues};
 pub use spend_proof::{
     create_spend_proof, setup_spend_prover, verify_spend_proof, SpendRevealedValues,
 };
+pub use verify_pool::{VerifyHandle, VerifyLane, VerifyPool, VerifyPoolMetrics};
+pub use viewing_key::ViewingKey;
 
 #[derive(Clone)]
 pub struct OwnCoin {
@@ -29,20 +45,107 @@ pub struct OwnCoin {
 
 pub type OwnCoins = Vec<OwnCoin>;
 
-pub fn save_params(filename: &str, params: &groth16::Parameters<Bls12>) -> Result<()> {
-    let buffer = std::fs::File::create(filename)?;
-    params.write(buffer)?;
+/// Hex encoded hash of the contents of a params file
+pub fn params_hash(data: &[u8]) -> String {
+    let hash = Params::new()
+        .hash_length(32)
+        .personal(b"DarkFi_ParamHash")
+        .hash(data);
+    hex::encode(hash.as_bytes())
+}
+
+// The hash of a params file is kept next to it in <filename>.hash
+pub fn params_hash_path(filename: &Path) -> PathBuf {
+    let mut path = filename.as_os_str().to_owned();
+    path.push(".hash");
+    PathBuf::from(path)
+}
+
+/// Hashes of the canonical mint and spend params, built in from
+/// DARKFI_MINT_PARAMS_HASH and DARKFI_SPEND_PARAMS_HASH. Params with any
+/// other hash are refused, whatever is recorded next to them.
+pub const MINT_PARAMS_HASH: Option<&str> = option_env!("DARKFI_MINT_PARAMS_HASH");
+pub const SPEND_PARAMS_HASH: Option<&str> = option_env!("DARKFI_SPEND_PARAMS_HASH");
+
+/// Writes the params along with the hash load_params checks them against
+pub fn save_params(filename: &Path, params: &groth16::Parameters<Bls12>) -> Result<()> {
+    let mut data = vec![];
+    params.write(&mut data)?;
+    std::fs::write(filename, &data)?;
+    std::fs::write(params_hash_path(filename), params_hash(&data))?;
     Ok(())
 }
 
+/// Reads params whose hash is known: `known` if given, otherwise the one
+/// recorded when they were generated or fetched. Params without a known
+/// hash are refused rather than trusted.
 pub fn load_params(
-    filename: &str,
+    filename: &Path,
+    known: Option<&str>,
 ) -> Result<(
     groth16::Parameters<Bls12>,
     groth16::PreparedVerifyingKey<Bls12>,
 )> {
-    let buffer = std::fs::File::open(filename)?;
-    let params = groth16::Parameters::<Bls12>::read(buffer, false)?;
+    let data = std::fs::read(filename)?;
+    let hash = params_hash(&data);
+
+    let hash_path = params_hash_path(filename);
+    let expected = match known {
+        Some(known) => known.to_string(),
+        None if hash_path.exists() => std::fs::read_to_string(&hash_path)?,
+        None => return Err(Error::ParamsHashUnknown(filename.display().to_string())),
+    };
+    if expected.trim() != hash {
+        return Err(Error::ParamsHashMismatch(filename.display().to_string()));
+    }
+
+    let params = groth16::Parameters::<Bls12>::read(&data[..], false)?;
     let pvk = groth16::prepare_verifying_key(&params.vk);
     Ok((params, pvk))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_params_hash() -> Result<()> {
+        let dir = std::env::temp_dir().join("darkfi_test_params_hash");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+        let filename = dir.join("mint.params");
+        let filename = filename.as_path();
+
+        let params = setup_mint_prover();
+        save_params(filename, &params)?;
+        assert!(load_params(filename, None).is_ok());
+        let hash = params_hash(&std::fs::read(filename)?);
+        assert!(load_params(filename, Some(&hash)).is_ok());
+        match load_params(filename, Some(&params_hash(b"other"))) {
+            Err(Error::ParamsHashMismatch(_)) => {}
+            _ => panic!("params with another hash than the known one should be refused"),
+        }
+
+        // Nothing to check them against, and loading doesn't record one
+        std::fs::remove_file(params_hash_path(filename))?;
+        match load_params(filename, None) {
+            Err(Error::ParamsHashUnknown(_)) => {}
+            _ => panic!("params without a known hash should be refused"),
+        }
+        assert!(!params_hash_path(filename).exists());
+        assert!(load_params(filename, Some(&hash)).is_ok());
+
+        // Flip a byte in the middle of the file
+        let mut data = std::fs::read(filename)?;
+        let middle = data.len() / 2;
+        data[middle] ^= 1;
+        std::fs::write(filename, &data)?;
+        match load_params(filename, Some(&hash)) {
+            Err(Error::ParamsHashMismatch(_)) => {}
+            _ => panic!("corrupted params should be refused"),
+        }
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -1,9 +1,12 @@
 use crypto_api_chachapoly::ChachaPolyIetf;
 use ff::Field;
+use group::GroupEncoding;
 use rand::rngs::OsRng;
+use std::fmt;
 use std::io;
+use std::str::FromStr;
 
-use super::diffie_hellman::{kdf_sapling, sapling_ka_agree};
+use super::diffie_hellman::{kdf_sapling, note_keys, sapling_ka_agree};
 use crate::error::{Error, Result};
 use crate::serial::{Decodable, Encodable, ReadExt, WriteExt};
 
@@ -11,9 +14,90 @@ pub const NOTE_PLAINTEXT_SIZE: usize = 32 +    // serial
     8 +     // value
     32 +    // token_id
     32 +    // coin_blind
-    32; // valcom_blind
+    32 +    // valcom_blind
+    MEMO_SIZE; // memo
 pub const AEAD_TAG_SIZE: usize = 16;
 pub const ENC_CIPHERTEXT_SIZE: usize = NOTE_PLAINTEXT_SIZE + AEAD_TAG_SIZE;
+pub const KEY_COMMITMENT_SIZE: usize = 32;
+pub const OUT_PLAINTEXT_SIZE: usize = 32 +    // recipient
+    8 +     // value
+    32; // token_id
+pub const OUT_CIPHERTEXT_SIZE: usize = OUT_PLAINTEXT_SIZE + AEAD_TAG_SIZE;
+
+/// Version of the note encryption scheme. Notes of unknown versions are
+/// rejected, so the scheme can be replaced later without ambiguity.
+pub const NOTE_ENCRYPTION_VERSION: u8 = 2;
+
+/// Notes from before memos were added. They're still decrypted, with an
+/// empty memo, so coins received back then are found on a rescan.
+pub const NOTE_ENCRYPTION_V1: u8 = 1;
+pub const NOTE_V1_PLAINTEXT_SIZE: usize = NOTE_PLAINTEXT_SIZE - MEMO_SIZE;
+pub const ENC_V1_CIPHERTEXT_SIZE: usize = NOTE_V1_PLAINTEXT_SIZE + AEAD_TAG_SIZE;
+
+pub const MEMO_SIZE: usize = 128;
+
+/// A short message from the sender, such as an invoice id. It is padded
+/// with zeroes to a fixed size so its length isn't revealed.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Memo([u8; MEMO_SIZE]);
+
+impl Memo {
+    pub fn empty() -> Self {
+        Self([0; MEMO_SIZE])
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|b| *b == 0)
+    }
+}
+
+impl Default for Memo {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+impl FromStr for Memo {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.len() > MEMO_SIZE {
+            return Err(Error::ParseFailed("Memo is too long"));
+        }
+
+        let mut memo = [0; MEMO_SIZE];
+        memo[..s.len()].copy_from_slice(s.as_bytes());
+        Ok(Self(memo))
+    }
+}
+
+impl fmt::Display for Memo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let len = self.0.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+        f.write_str(&String::from_utf8_lossy(&self.0[..len]))
+    }
+}
+
+impl fmt::Debug for Memo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Memo({:?})", self.to_string())
+    }
+}
+
+impl Encodable for Memo {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        s.write_slice(&self.0)?;
+        Ok(MEMO_SIZE)
+    }
+}
+
+impl Decodable for Memo {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        let mut memo = [0; MEMO_SIZE];
+        d.read_slice(&mut memo)?;
+        Ok(Self(memo))
+    }
+}
 
 #[derive(Clone)]
 pub struct Note {
@@ -22,6 +106,7 @@ pub struct Note {
     pub token_id: jubjub::Fr,
     pub coin_blind: jubjub::Fr,
     pub valcom_blind: jubjub::Fr,
+    pub memo: Memo,
 }
 
 impl Encodable for Note {
@@ -32,6 +117,7 @@ impl Encodable for Note {
         len += self.token_id.encode(&mut s)?;
         len += self.coin_blind.encode(&mut s)?;
         len += self.valcom_blind.encode(&mut s)?;
+        len += self.memo.encode(&mut s)?;
         Ok(len)
     }
 }
@@ -43,46 +129,117 @@ impl Decodable for Note {
             value: Decodable::decode(&mut d)?,
             token_id: Decodable::decode(&mut d)?,
             coin_blind: Decodable::decode(&mut d)?,
-            valcom_blind: Decodable::decode(d)?,
+            valcom_blind: Decodable::decode(&mut d)?,
+            memo: Decodable::decode(d)?,
         })
     }
 }
 
 impl Note {
     pub fn encrypt(&self, public: &jubjub::SubgroupPoint) -> Result<EncryptedNote> {
-        let ephem_secret = jubjub::Fr::random(&mut OsRng);
-        let ephem_public = zcash_primitives::constants::SPENDING_KEY_GENERATOR * ephem_secret;
-        let shared_secret = sapling_ka_agree(&ephem_secret, public.into());
-        let key = kdf_sapling(shared_secret, &ephem_public.into());
-
         let mut input = Vec::new();
         self.encode(&mut input)?;
 
-        let mut ciphertext = [0u8; ENC_CIPHERTEXT_SIZE];
-        assert_eq!(
-            ChachaPolyIetf::aead_cipher()
-                .seal_to(&mut ciphertext, &input, &[], key.as_ref(), &[0u8; 12])
-                .unwrap(),
-            ENC_CIPHERTEXT_SIZE
-        );
+        let mut ciphertext = vec![0u8; ENC_CIPHERTEXT_SIZE];
+        let (key_commitment, ephem_public) =
+            seal(NOTE_ENCRYPTION_VERSION, &input, &mut ciphertext, public);
 
         Ok(EncryptedNote {
+            version: NOTE_ENCRYPTION_VERSION,
+            key_commitment,
             ciphertext,
             ephem_public,
         })
     }
 }
 
+// The version and ephemeral key are authenticated along with the note
+fn aad(version: u8, ephem_public: &jubjub::SubgroupPoint) -> Vec<u8> {
+    let mut aad = vec![version];
+    aad.extend_from_slice(&ephem_public.to_bytes());
+    aad
+}
+
+// Encrypt `plaintext` to `public` under a fresh ephemeral key, returning
+// the key commitment and the ephemeral public key.
+fn seal(
+    version: u8,
+    plaintext: &[u8],
+    ciphertext: &mut [u8],
+    public: &jubjub::SubgroupPoint,
+) -> ([u8; KEY_COMMITMENT_SIZE], jubjub::SubgroupPoint) {
+    let ephem_secret = jubjub::Fr::random(&mut OsRng);
+    let ephem_public = zcash_primitives::constants::SPENDING_KEY_GENERATOR * ephem_secret;
+    let shared_secret = sapling_ka_agree(&ephem_secret, public.into());
+    let (key, key_commitment) = note_keys(&kdf_sapling(shared_secret, &ephem_public.into()));
+
+    let aad = aad(version, &ephem_public);
+
+    // Every note gets its own ephemeral key, so the nonce can be fixed
+    assert_eq!(
+        ChachaPolyIetf::aead_cipher()
+            .seal_to(ciphertext, plaintext, &aad, &key, &[0u8; 12])
+            .unwrap(),
+        ciphertext.len()
+    );
+
+    (key_commitment, ephem_public)
+}
+
+fn is_known_version(version: u8) -> bool {
+    version == NOTE_ENCRYPTION_VERSION || version == NOTE_ENCRYPTION_V1
+}
+
+fn open(
+    ciphertext: &[u8],
+    plaintext: &mut [u8],
+    version: u8,
+    key_commitment: &[u8; KEY_COMMITMENT_SIZE],
+    ephem_public: &jubjub::SubgroupPoint,
+    secret: &jubjub::Fr,
+) -> Result<()> {
+    if !is_known_version(version) {
+        return Err(Error::NoteDecryptionFailed);
+    }
+
+    let shared_secret = sapling_ka_agree(&secret, &(*ephem_public).into());
+    let (key, expected_commitment) =
+        note_keys(&kdf_sapling(shared_secret, &(*ephem_public).into()));
+
+    // A note encrypted under a different key is rejected here, before
+    // it can be opened at all.
+    if &expected_commitment != key_commitment {
+        return Err(Error::NoteDecryptionFailed);
+    }
+
+    let aad = aad(version, ephem_public);
+
+    assert_eq!(
+        ChachaPolyIetf::aead_cipher()
+            .open_to(plaintext, ciphertext, &aad, &key, &[0u8; 12])
+            .map_err(|_| Error::NoteDecryptionFailed)?,
+        plaintext.len()
+    );
+
+    Ok(())
+}
+
 pub struct EncryptedNote {
-    ciphertext: [u8; ENC_CIPHERTEXT_SIZE],
+    version: u8,
+    key_commitment: [u8; KEY_COMMITMENT_SIZE],
+    // Version 1 notes are shorter
+    ciphertext: Vec<u8>,
     ephem_public: jubjub::SubgroupPoint,
 }
 
 impl Encodable for EncryptedNote {
     fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
         let mut len = 0;
+        len += self.version.encode(&mut s)?;
+        s.write_slice(&self.key_commitment)?;
+        len += KEY_COMMITMENT_SIZE;
         s.write_slice(&self.ciphertext)?;
-        len += ENC_CIPHERTEXT_SIZE;
+        len += self.ciphertext.len();
         len += self.ephem_public.encode(&mut s)?;
         Ok(len)
     }
@@ -90,9 +247,20 @@ impl Encodable for EncryptedNote {
 
 impl Decodable for EncryptedNote {
     fn decode<D: io::Read>(mut d: D) -> Result<Self> {
-        let mut ciphertext = [0u8; ENC_CIPHERTEXT_SIZE];
+        let version: u8 = Decodable::decode(&mut d)?;
+        let ciphertext_size = match version {
+            NOTE_ENCRYPTION_VERSION => ENC_CIPHERTEXT_SIZE,
+            NOTE_ENCRYPTION_V1 => ENC_V1_CIPHERTEXT_SIZE,
+            _ => return Err(Error::ParseFailed("Unsupported note encryption version")),
+        };
+
+        let mut key_commitment = [0u8; KEY_COMMITMENT_SIZE];
+        d.read_slice(&mut key_commitment[..])?;
+        let mut ciphertext = vec![0u8; ciphertext_size];
         d.read_slice(&mut ciphertext[..])?;
         Ok(Self {
+            version,
+            key_commitment,
             ciphertext,
             ephem_public: Decodable::decode(d)?,
         })
@@ -101,27 +269,127 @@ impl Decodable for EncryptedNote {
 
 impl EncryptedNote {
     pub fn decrypt(&self, secret: &jubjub::Fr) -> Result<Note> {
-        let shared_secret = sapling_ka_agree(&secret, &self.ephem_public.into());
-        let key = kdf_sapling(shared_secret, &self.ephem_public.into());
-
-        let mut plaintext = [0; ENC_CIPHERTEXT_SIZE];
-        assert_eq!(
-            ChachaPolyIetf::aead_cipher()
-                .open_to(
-                    &mut plaintext,
-                    &self.ciphertext,
-                    &[],
-                    key.as_ref(),
-                    &[0u8; 12]
-                )
-                .map_err(|_| Error::NoteDecryptionFailed)?,
-            NOTE_PLAINTEXT_SIZE
-        );
+        let mut plaintext = vec![0; self.ciphertext.len() - AEAD_TAG_SIZE];
+        open(
+            &self.ciphertext,
+            &mut plaintext,
+            self.version,
+            &self.key_commitment,
+            &self.ephem_public,
+            secret,
+        )?;
 
+        // A version 1 note is read with an empty memo
+        plaintext.resize(NOTE_PLAINTEXT_SIZE, 0);
         Note::decode(&plaintext[..])
     }
 }
 
+/// The sender's own record of an output: who was paid and how much.
+/// It is encrypted to the sender's key and published next to the note,
+/// so a wallet restored from its seed can rebuild its sent history.
+#[derive(Clone)]
+pub struct OutgoingNote {
+    pub recipient: jubjub::SubgroupPoint,
+    pub value: u64,
+    pub token_id: jubjub::Fr,
+}
+
+impl Encodable for OutgoingNote {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.recipient.encode(&mut s)?;
+        len += self.value.encode(&mut s)?;
+        len += self.token_id.encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for OutgoingNote {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self {
+            recipient: Decodable::decode(&mut d)?,
+            value: Decodable::decode(&mut d)?,
+            token_id: Decodable::decode(d)?,
+        })
+    }
+}
+
+impl OutgoingNote {
+    pub fn encrypt(&self, public: &jubjub::SubgroupPoint) -> Result<EncryptedOutgoingNote> {
+        let mut input = Vec::new();
+        self.encode(&mut input)?;
+
+        let mut ciphertext = [0u8; OUT_CIPHERTEXT_SIZE];
+        let (key_commitment, ephem_public) =
+            seal(NOTE_ENCRYPTION_VERSION, &input, &mut ciphertext, public);
+
+        Ok(EncryptedOutgoingNote {
+            version: NOTE_ENCRYPTION_VERSION,
+            key_commitment,
+            ciphertext,
+            ephem_public,
+        })
+    }
+}
+
+pub struct EncryptedOutgoingNote {
+    version: u8,
+    key_commitment: [u8; KEY_COMMITMENT_SIZE],
+    ciphertext: [u8; OUT_CIPHERTEXT_SIZE],
+    ephem_public: jubjub::SubgroupPoint,
+}
+
+impl Encodable for EncryptedOutgoingNote {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.version.encode(&mut s)?;
+        s.write_slice(&self.key_commitment)?;
+        len += KEY_COMMITMENT_SIZE;
+        s.write_slice(&self.ciphertext)?;
+        len += OUT_CIPHERTEXT_SIZE;
+        len += self.ephem_public.encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for EncryptedOutgoingNote {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        let version: u8 = Decodable::decode(&mut d)?;
+        // Outgoing notes are the same in both versions
+        if !is_known_version(version) {
+            return Err(Error::ParseFailed("Unsupported note encryption version"));
+        }
+
+        let mut key_commitment = [0u8; KEY_COMMITMENT_SIZE];
+        d.read_slice(&mut key_commitment[..])?;
+        let mut ciphertext = [0u8; OUT_CIPHERTEXT_SIZE];
+        d.read_slice(&mut ciphertext[..])?;
+        Ok(Self {
+            version,
+            key_commitment,
+            ciphertext,
+            ephem_public: Decodable::decode(d)?,
+        })
+    }
+}
+
+impl EncryptedOutgoingNote {
+    pub fn decrypt(&self, secret: &jubjub::Fr) -> Result<OutgoingNote> {
+        let mut plaintext = [0; OUT_PLAINTEXT_SIZE];
+        open(
+            &self.ciphertext,
+            &mut plaintext,
+            self.version,
+            &self.key_commitment,
+            &self.ephem_public,
+            secret,
+        )?;
+
+        OutgoingNote::decode(&plaintext[..])
+    }
+}
+
 #[test]
 fn test_note_encdec() {
     let note = Note {
@@ -130,6 +398,7 @@ fn test_note_encdec() {
         token_id: jubjub::Fr::random(&mut OsRng),
         coin_blind: jubjub::Fr::random(&mut OsRng),
         valcom_blind: jubjub::Fr::random(&mut OsRng),
+        memo: Memo::empty(),
     };
 
     let secret = jubjub::Fr::random(&mut OsRng);
@@ -139,4 +408,143 @@ fn test_note_encdec() {
     let note2 = encrypted_note.decrypt(&secret).unwrap();
     assert_eq!(note.value, note2.value);
     assert_eq!(note.token_id, note2.token_id);
+    assert!(note2.memo.is_empty());
+}
+
+#[test]
+fn test_note_memo() {
+    let memo: Memo = "invoice #42".parse().unwrap();
+    assert_eq!(memo.to_string(), "invoice #42");
+    assert!(!memo.is_empty());
+    assert!("x".repeat(MEMO_SIZE + 1).parse::<Memo>().is_err());
+
+    let note = Note {
+        serial: jubjub::Fr::random(&mut OsRng),
+        value: 110,
+        token_id: jubjub::Fr::random(&mut OsRng),
+        coin_blind: jubjub::Fr::random(&mut OsRng),
+        valcom_blind: jubjub::Fr::random(&mut OsRng),
+        memo,
+    };
+
+    let secret = jubjub::Fr::random(&mut OsRng);
+    let public = zcash_primitives::constants::SPENDING_KEY_GENERATOR * secret;
+
+    let note2 = note.encrypt(&public).unwrap().decrypt(&secret).unwrap();
+    assert_eq!(note2.memo, memo);
+}
+
+#[test]
+fn test_note_tampering() {
+    use crate::serial::{deserialize, serialize};
+
+    let note = Note {
+        serial: jubjub::Fr::random(&mut OsRng),
+        value: 110,
+        token_id: jubjub::Fr::random(&mut OsRng),
+        coin_blind: jubjub::Fr::random(&mut OsRng),
+        valcom_blind: jubjub::Fr::random(&mut OsRng),
+        memo: Memo::empty(),
+    };
+
+    let secret = jubjub::Fr::random(&mut OsRng);
+    let public = zcash_primitives::constants::SPENDING_KEY_GENERATOR * secret;
+
+    let encoded = serialize(&note.encrypt(&public).unwrap());
+    assert!(deserialize::<EncryptedNote>(&encoded)
+        .unwrap()
+        .decrypt(&secret)
+        .is_ok());
+
+    // Someone else's key never opens the note
+    let other_secret = jubjub::Fr::random(&mut OsRng);
+    assert!(deserialize::<EncryptedNote>(&encoded)
+        .unwrap()
+        .decrypt(&other_secret)
+        .is_err());
+
+    // Flipping any bit of the encoding either breaks decoding or decryption
+    for i in 0..encoded.len() {
+        let mut tampered = encoded.clone();
+        tampered[i] ^= 0x01;
+
+        if let Ok(encrypted_note) = deserialize::<EncryptedNote>(&tampered) {
+            assert!(encrypted_note.decrypt(&secret).is_err());
+        }
+    }
+}
+
+#[test]
+fn test_outgoing_note_encdec() {
+    let secret = jubjub::Fr::random(&mut OsRng);
+    let public = zcash_primitives::constants::SPENDING_KEY_GENERATOR * secret;
+    let recipient =
+        zcash_primitives::constants::SPENDING_KEY_GENERATOR * jubjub::Fr::random(&mut OsRng);
+
+    let out_note = OutgoingNote {
+        recipient,
+        value: 110,
+        token_id: jubjub::Fr::random(&mut OsRng),
+    };
+
+    let encrypted = out_note.encrypt(&public).unwrap();
+    let out_note2 = encrypted.decrypt(&secret).unwrap();
+    assert_eq!(out_note2.recipient, recipient);
+    assert_eq!(out_note2.value, 110);
+    assert_eq!(out_note2.token_id, out_note.token_id);
+
+    assert!(encrypted.decrypt(&jubjub::Fr::random(&mut OsRng)).is_err());
+}
+
+#[test]
+fn test_note_v1() {
+    use crate::serial::{deserialize, serialize};
+
+    let note = Note {
+        serial: jubjub::Fr::random(&mut OsRng),
+        value: 110,
+        token_id: jubjub::Fr::random(&mut OsRng),
+        coin_blind: jubjub::Fr::random(&mut OsRng),
+        valcom_blind: jubjub::Fr::random(&mut OsRng),
+        memo: "dropped".parse().unwrap(),
+    };
+
+    let secret = jubjub::Fr::random(&mut OsRng);
+    let public = zcash_primitives::constants::SPENDING_KEY_GENERATOR * secret;
+
+    // A note as version 1 encrypted it, without the memo
+    let mut input = Vec::new();
+    note.encode(&mut input).unwrap();
+    let mut ciphertext = vec![0u8; ENC_V1_CIPHERTEXT_SIZE];
+    let (key_commitment, ephem_public) = seal(
+        NOTE_ENCRYPTION_V1,
+        &input[..NOTE_V1_PLAINTEXT_SIZE],
+        &mut ciphertext,
+        &public,
+    );
+    let encoded = serialize(&EncryptedNote {
+        version: NOTE_ENCRYPTION_V1,
+        key_commitment,
+        ciphertext,
+        ephem_public,
+    });
+    assert_eq!(encoded.len(), 1 + KEY_COMMITMENT_SIZE + ENC_V1_CIPHERTEXT_SIZE + 32);
+
+    let note2 = deserialize::<EncryptedNote>(&encoded)
+        .unwrap()
+        .decrypt(&secret)
+        .unwrap();
+    assert_eq!(note2.serial, note.serial);
+    assert_eq!(note2.value, 110);
+    assert_eq!(note2.valcom_blind, note.valcom_blind);
+    assert!(note2.memo.is_empty());
+
+    // Nor is it read as a note of another version
+    let mut bumped = encoded.clone();
+    bumped[0] = NOTE_ENCRYPTION_VERSION;
+    assert!(deserialize::<EncryptedNote>(&bumped).is_err());
+
+    let mut unknown = encoded;
+    unknown[0] = 3;
+    assert!(deserialize::<EncryptedNote>(&unknown).is_err());
 }
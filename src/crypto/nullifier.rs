@@ -1,3 +1,4 @@
+use blake2s_simd::Params as Blake2sParams;
 use std::io;
 
 use crate::{
@@ -13,6 +14,22 @@ impl Nullifier {
     pub fn new(repr: [u8; 32]) -> Self {
         Self { repr }
     }
+
+    /// The nullifier revealed when spending the coin with this secret and serial
+    pub fn derive(secret: &jubjub::Fr, serial: &jubjub::Fr) -> Self {
+        let mut repr = [0; 32];
+        repr.copy_from_slice(
+            Blake2sParams::new()
+                .hash_length(32)
+                .personal(zcash_primitives::constants::PRF_NF_PERSONALIZATION)
+                .to_state()
+                .update(&secret.to_bytes())
+                .update(&serial.to_bytes())
+                .finalize()
+                .as_bytes(),
+        );
+        Self { repr }
+    }
 }
 
 impl Encodable for Nullifier {
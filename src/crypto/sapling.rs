@@ -0,0 +1,105 @@
+//! Import of Zcash sapling extended keys, as encoded by ZIP32.
+//!
+//! Sapling authorizes spends with ak = ask * SpendAuthG, and SpendAuthG is
+//! the generator drk keys are made with, so the spend authorizing key of
+//! a sapling spending key carries over as a drk secret key. The rest of
+//! the sapling key has no counterpart here: drk derives nullifiers, note
+//! encryption keys and diversified addresses from the secret key itself,
+//! and derives child keys in its own way. Those parts are checked and
+//! then dropped.
+
+use super::bech32;
+use crate::error::{Error, Result};
+use crate::serial::deserialize;
+
+// depth, parent fingerprint tag, child index and chain code, followed by
+// four 32 byte keys
+const EXTENDED_KEY_LENGTH: usize = 1 + 4 + 4 + 32 + 4 * 32;
+const KEYS_OFFSET: usize = 1 + 4 + 4 + 32;
+
+const SPENDING_KEY_PREFIXES: [&str; 2] = ["secret-extended-key-main", "secret-extended-key-test"];
+const VIEWING_KEY_PREFIXES: [&str; 2] = ["zxviews", "zxviewtestsapling"];
+const ADDRESS_PREFIXES: [&str; 2] = ["zs", "ztestsapling"];
+
+/// The spend authorizing key of a bech32 encoded sapling extended
+/// spending key, as a drk secret key
+pub fn import_extended_spending_key(s: &str) -> Result<jubjub::Fr> {
+    let (prefix, bytes) = bech32::decode(s)?;
+
+    if VIEWING_KEY_PREFIXES.contains(&prefix.as_str()) {
+        return Err(Error::ParseFailed(
+            "Sapling viewing keys can't be imported: drk notes are encrypted to a key \
+             derived from the spending key, so import the extended spending key instead",
+        ));
+    }
+    if ADDRESS_PREFIXES.contains(&prefix.as_str()) {
+        return Err(Error::ParseFailed(
+            "This is a sapling payment address, import the extended spending key instead",
+        ));
+    }
+    if !SPENDING_KEY_PREFIXES.contains(&prefix.as_str()) {
+        return Err(Error::ParseFailed(
+            "Not a sapling extended spending key (secret-extended-key-main1...)",
+        ));
+    }
+    if bytes.len() != EXTENDED_KEY_LENGTH {
+        return Err(Error::ParseFailed("Invalid sapling extended key length"));
+    }
+
+    let keys = &bytes[KEYS_OFFSET..];
+    let ask: jubjub::Fr = deserialize(&keys[..32])
+        .map_err(|_| Error::ParseFailed("Sapling spend authorizing key is not a jubjub scalar"))?;
+    let _nsk: jubjub::Fr = deserialize(&keys[32..64])
+        .map_err(|_| Error::ParseFailed("Sapling nullifier deriving key is not a jubjub scalar"))?;
+
+    if ask == jubjub::Fr::zero() {
+        return Err(Error::ParseFailed("Sapling spend authorizing key is zero"));
+    }
+
+    Ok(ask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zcash_primitives::zip32::{ExtendedFullViewingKey, ExtendedSpendingKey};
+
+    #[test]
+    fn test_import_sapling_key() -> Result<()> {
+        let xsk = ExtendedSpendingKey::master(&[7u8; 32]);
+        let xfvk = ExtendedFullViewingKey::from(&xsk);
+
+        let mut bytes = vec![];
+        xsk.write(&mut bytes)?;
+        let encoded = bech32::encode("secret-extended-key-main", &bytes)?;
+
+        // The imported key signs for the same public key as the sapling one
+        let secret = import_extended_spending_key(&encoded)?;
+        assert_eq!(secret, xsk.expsk.ask);
+        assert_eq!(
+            zcash_primitives::constants::SPENDING_KEY_GENERATOR * secret,
+            xfvk.fvk.vk.ak
+        );
+
+        let mut bytes = vec![];
+        xfvk.write(&mut bytes)?;
+        let encoded = bech32::encode("zxviews", &bytes)?;
+        assert!(import_extended_spending_key(&encoded).is_err());
+
+        assert!(import_extended_spending_key(&bech32::encode("zs", &[0; 43])?).is_err());
+        assert!(import_extended_spending_key(&bech32::encode(
+            "secret-extended-key-main",
+            &[0; 100]
+        )?)
+        .is_err());
+
+        let mut bytes = vec![0u8; EXTENDED_KEY_LENGTH];
+        for byte in &mut bytes[KEYS_OFFSET..KEYS_OFFSET + 32] {
+            *byte = 0xff;
+        }
+        let encoded = bech32::encode("secret-extended-key-test", &bytes)?;
+        assert!(import_extended_spending_key(&encoded).is_err());
+
+        Ok(())
+    }
+}
@@ -33,6 +33,7 @@ impl SecretKey {
 
 pub struct PublicKey(pub jubjub::SubgroupPoint);
 
+#[derive(Clone)]
 pub struct Signature {
     commit: jubjub::SubgroupPoint,
     response: jubjub::Fr,
@@ -47,18 +47,7 @@ impl SpendRevealedValues {
             + (zcash_primitives::constants::VALUE_COMMITMENT_RANDOMNESS_GENERATOR
                 * randomness_token);
 
-        let mut nullifier = [0; 32];
-        nullifier.copy_from_slice(
-            Blake2sParams::new()
-                .hash_length(32)
-                .personal(zcash_primitives::constants::PRF_NF_PERSONALIZATION)
-                .to_state()
-                .update(&secret.to_bytes())
-                .update(&serial.to_bytes())
-                .finalize()
-                .as_bytes(),
-        );
-        let nullifier = Nullifier::new(nullifier);
+        let nullifier = Nullifier::derive(secret, serial);
 
         let public = zcash_primitives::constants::SPENDING_KEY_GENERATOR * secret;
         let signature_public =
@@ -106,7 +95,7 @@ impl SpendRevealedValues {
         }
     }
 
-    fn make_outputs(&self) -> [bls12_381::Scalar; 9] {
+    pub fn make_outputs(&self) -> [bls12_381::Scalar; 9] {
         let mut public_input = [bls12_381::Scalar::zero(); 9];
 
         // CV
@@ -0,0 +1,321 @@
+//! Worker threads the zk proofs of transactions are checked on, away from
+//! the executor. Jobs come in two lanes: our own transactions, which a
+//! user is waiting on, always go ahead of the slabs being synced.
+
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use serde_json::{json, Value};
+
+use crate::{Error, Result};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VerifyLane {
+    /// Transactions we made and are about to broadcast
+    Own,
+    /// Slabs received from the gateway
+    Sync,
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+#[derive(Default)]
+struct Lane {
+    jobs: VecDeque<(Instant, Job)>,
+    max_depth: usize,
+    done: u64,
+    waited: Duration,
+}
+
+impl Lane {
+    fn push(&mut self, job: Job) {
+        self.jobs.push_back((Instant::now(), job));
+        self.max_depth = self.max_depth.max(self.jobs.len());
+    }
+
+    fn pop(&mut self) -> Option<Job> {
+        let (queued, job) = self.jobs.pop_front()?;
+        self.done += 1;
+        self.waited += queued.elapsed();
+        Some(job)
+    }
+
+    fn metrics(&self) -> LaneMetrics {
+        LaneMetrics {
+            depth: self.jobs.len(),
+            max_depth: self.max_depth,
+            done: self.done,
+            avg_wait_micros: if self.done == 0 {
+                0
+            } else {
+                self.waited.as_micros() as u64 / self.done
+            },
+        }
+    }
+}
+
+#[derive(Default)]
+struct Queues {
+    own: Lane,
+    sync: Lane,
+    stopped: bool,
+}
+
+struct Shared {
+    queues: Mutex<Queues>,
+    available: Condvar,
+}
+
+/// Queue depth and wait times of a lane
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LaneMetrics {
+    /// Jobs waiting for a worker
+    pub depth: usize,
+    /// The most jobs that were ever waiting at once
+    pub max_depth: usize,
+    /// Jobs handed to a worker
+    pub done: u64,
+    /// Average time a job waited for a worker
+    pub avg_wait_micros: u64,
+}
+
+impl LaneMetrics {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "depth": self.depth,
+            "max_depth": self.max_depth,
+            "done": self.done,
+            "avg_wait_micros": self.avg_wait_micros,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VerifyPoolMetrics {
+    pub workers: usize,
+    pub own: LaneMetrics,
+    pub sync: LaneMetrics,
+}
+
+impl VerifyPoolMetrics {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "workers": self.workers,
+            "own": self.own.to_json(),
+            "sync": self.sync.to_json(),
+        })
+    }
+}
+
+/// The result of a job queued with `VerifyPool::submit`
+pub struct VerifyHandle<T> {
+    recv: async_channel::Receiver<T>,
+}
+
+impl<T> VerifyHandle<T> {
+    pub async fn wait(self) -> Result<T> {
+        self.recv
+            .recv()
+            .await
+            .map_err(|_| Error::ServicesError("Proof verification job failed"))
+    }
+}
+
+pub struct VerifyPool {
+    shared: Arc<Shared>,
+    workers: usize,
+}
+
+impl VerifyPool {
+    /// Start a pool with the given number of worker threads, at least one
+    pub fn new(workers: usize) -> Self {
+        let workers = workers.max(1);
+        let shared = Arc::new(Shared {
+            queues: Mutex::new(Queues::default()),
+            available: Condvar::new(),
+        });
+
+        for i in 0..workers {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name(format!("verify-{}", i))
+                .spawn(move || Self::work(shared))
+                .expect("spawn verify worker");
+        }
+
+        debug!(target: "VERIFY POOL", "Started {} workers", workers);
+        Self { shared, workers }
+    }
+
+    /// Run a verification job in the given lane and wait for its result
+    pub async fn run<T, F>(&self, lane: VerifyLane, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        self.submit(lane, f).wait().await
+    }
+
+    /// Queue a verification job in the given lane without waiting for it,
+    /// so several can be checked on the workers at once
+    pub fn submit<T, F>(&self, lane: VerifyLane, f: F) -> VerifyHandle<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (send, recv) = async_channel::bounded(1);
+        let job: Job = Box::new(move || {
+            let _ = send.try_send(f());
+        });
+
+        {
+            let mut queues = self.shared.queues.lock().unwrap();
+            match lane {
+                VerifyLane::Own => queues.own.push(job),
+                VerifyLane::Sync => queues.sync.push(job),
+            }
+        }
+        self.shared.available.notify_one();
+
+        VerifyHandle { recv }
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers
+    }
+
+    pub fn metrics(&self) -> VerifyPoolMetrics {
+        let queues = self.shared.queues.lock().unwrap();
+        VerifyPoolMetrics {
+            workers: self.workers,
+            own: queues.own.metrics(),
+            sync: queues.sync.metrics(),
+        }
+    }
+
+    fn work(shared: Arc<Shared>) {
+        loop {
+            let job = {
+                let mut queues = shared.queues.lock().unwrap();
+                loop {
+                    if queues.stopped {
+                        return;
+                    }
+                    if let Some(job) = queues.own.pop().or_else(|| queues.sync.pop()) {
+                        break job;
+                    }
+                    queues = shared.available.wait(queues).unwrap();
+                }
+            };
+
+            // A panicking job drops its result sender, which fails the
+            // caller, but the worker carries on
+            if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                warn!(target: "VERIFY POOL", "Verification job panicked");
+            }
+        }
+    }
+}
+
+impl Drop for VerifyPool {
+    fn drop(&mut self) {
+        self.shared.queues.lock().unwrap().stopped = true;
+        self.shared.available.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_pool_lanes() -> Result<()> {
+        let pool = Arc::new(VerifyPool::new(1));
+
+        // Hold up the only worker while jobs queue behind it
+        let (release_s, release_r) = std::sync::mpsc::channel::<()>();
+        let blocker = {
+            let pool = pool.clone();
+            thread::spawn(move || {
+                smol::future::block_on(pool.run(VerifyLane::Sync, move || {
+                    release_r.recv().unwrap();
+                }))
+            })
+        };
+        while pool.metrics().sync.done == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let order = Arc::new(Mutex::new(vec![]));
+        let mut waiting = vec![];
+        for (lane, name) in &[
+            (VerifyLane::Sync, "sync"),
+            (VerifyLane::Own, "own"),
+            (VerifyLane::Sync, "sync"),
+        ] {
+            let (queue, order, lane, name) = (pool.clone(), order.clone(), *lane, *name);
+            waiting.push(thread::spawn(move || {
+                smol::future::block_on(queue.run(lane, move || order.lock().unwrap().push(name)))
+            }));
+            let queued = |m: VerifyPoolMetrics| m.own.depth + m.sync.depth;
+            while queued(pool.metrics()) < waiting.len() {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+
+        let metrics = pool.metrics();
+        assert_eq!(metrics.workers, 1);
+        assert_eq!(metrics.own.depth, 1);
+        assert_eq!(metrics.sync.depth, 2);
+
+        release_s.send(()).unwrap();
+        blocker.join().unwrap()?;
+        for waiting in waiting {
+            waiting.join().unwrap()?;
+        }
+
+        // Our own transaction went ahead of the sync jobs queued before it
+        assert_eq!(*order.lock().unwrap(), vec!["own", "sync", "sync"]);
+
+        let metrics = pool.metrics();
+        assert_eq!(metrics.own.depth, 0);
+        assert_eq!(metrics.sync.max_depth, 2);
+        assert_eq!(metrics.sync.done, 3);
+
+        // A panicking job fails its caller but not the pool
+        assert!(smol::future::block_on(pool.run(VerifyLane::Own, || panic!("bad job"))).is_err());
+        assert_eq!(smol::future::block_on(pool.run(VerifyLane::Own, || 7))?, 7);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_pool_submit() -> Result<()> {
+        let pool = VerifyPool::new(2);
+        assert_eq!(pool.workers(), 2);
+
+        // Each job waits for the other to start, which only works if both
+        // run at once
+        let barrier = Arc::new(std::sync::Barrier::new(2));
+        let handles: Vec<VerifyHandle<usize>> = (0..2)
+            .map(|i| {
+                let barrier = barrier.clone();
+                pool.submit(VerifyLane::Sync, move || {
+                    barrier.wait();
+                    i
+                })
+            })
+            .collect();
+
+        let mut results = vec![];
+        for handle in handles {
+            results.push(smol::future::block_on(handle.wait())?);
+        }
+        assert_eq!(results, vec![0, 1]);
+        Ok(())
+    }
+}
@@ -0,0 +1,57 @@
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use super::note::{EncryptedNote, Note};
+use super::util::hash_to_scalar;
+use crate::error::{Error, Result};
+use crate::serial::{deserialize, serialize, Decodable, Encodable};
+
+/// Key that decrypts the notes sent to an address but can't spend them,
+/// so it can be handed to an auditor. It is derived from the spending
+/// key, and notes are encrypted to its public half, which is part of the
+/// address.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ViewingKey(pub jubjub::Fr);
+
+impl ViewingKey {
+    pub fn from_secret(secret: &jubjub::Fr) -> Self {
+        Self(hash_to_scalar(b"DarkFi_ViewKey", &secret.to_bytes(), b""))
+    }
+
+    pub fn public(&self) -> jubjub::SubgroupPoint {
+        zcash_primitives::constants::SPENDING_KEY_GENERATOR * self.0
+    }
+
+    pub fn decrypt_note(&self, enc_note: &EncryptedNote) -> Result<Note> {
+        enc_note.decrypt(&self.0)
+    }
+}
+
+impl fmt::Display for ViewingKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&bs58::encode(serialize(&self.0)).into_string())
+    }
+}
+
+impl FromStr for ViewingKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let bytes = bs58::decode(s).into_vec()?;
+        let key = deserialize(&bytes).map_err(|_| Error::ParseFailed("Invalid viewing key"))?;
+        Ok(Self(key))
+    }
+}
+
+impl Encodable for ViewingKey {
+    fn encode<S: io::Write>(&self, s: S) -> Result<usize> {
+        self.0.encode(s)
+    }
+}
+
+impl Decodable for ViewingKey {
+    fn decode<D: io::Read>(d: D) -> Result<Self> {
+        Ok(Self(Decodable::decode(d)?))
+    }
+}
@@ -37,12 +37,25 @@ pub enum Error {
     BadConstraintType,
     InvalidParamName,
     MissingParams,
+    ParamsHashMismatch(String),
+    ParamsHashUnknown(String),
+    ParamsFetchFailed(String),
+    BackupFailed(String),
+    TlsPinMismatch(String),
+    ProxyFailed(String),
+    ProtocolVersionMismatch(String),
+    BadFrame(String),
+    EnclaveFailed(String),
+    RequestTimeout(String),
+    WebhookFailed(String),
     VmError,
     BadContract,
     Groth16Error,
     OperationFailed,
     NoteDecryptionFailed,
     VerifyFailed,
+    MnemonicError(String),
+    CoBuildFailed(String),
     TreeFull,
 
     /// Service
@@ -52,11 +65,14 @@ pub enum Error {
     BtcFailed(String),
     #[cfg(feature = "sol")]
     SolFailed(String),
+    #[cfg(feature = "grpc")]
+    GrpcFailed(String),
     BridgeError(String),
     ZmqError(String),
 
     /// Database/Sql errors
     RocksdbError(String),
+    SledError(String),
     RusqliteError(String),
     SlabsStore(String),
 
@@ -67,6 +83,7 @@ pub enum Error {
     TokenParseError,
     NetworkParseError,
     AsyncNativeTlsError,
+    TlsFailed(String),
     TungsteniteError,
 
     /// Network
@@ -80,6 +97,8 @@ pub enum Error {
     ConfigNotFound,
     KeypairPathNotFound,
     CashierKeysNotFound,
+    CashierProvisioningFailed(&'static str),
+    SupportBundleFailed(String),
     SetLoggerError,
     AsyncChannelSenderError,
     AsyncChannelReceiverError,
@@ -100,6 +119,7 @@ impl fmt::Display for Error {
             Error::AsyncChannelSenderError => f.write_str("Async_channel sender error"),
             Error::AsyncChannelReceiverError => f.write_str("Async_channel receiver error"),
             Error::AsyncNativeTlsError => f.write_str("Async_Native_TLS error"),
+            Error::TlsFailed(ref err) => write!(f, "TLS failed: {}", err),
             Error::MalformedPacket => f.write_str("Malformed packet"),
             Error::AddrParseError => f.write_str("Unable to parse address"),
             Error::BadVariableRefType => f.write_str("Bad variable ref type byte"),
@@ -107,6 +127,25 @@ impl fmt::Display for Error {
             Error::BadConstraintType => f.write_str("Bad constraint type byte"),
             Error::InvalidParamName => f.write_str("Invalid param name"),
             Error::MissingParams => f.write_str("Missing params"),
+            Error::ParamsHashMismatch(ref err) => {
+                write!(f, "Params file {} doesn't match its known hash", err)
+            }
+            Error::ParamsHashUnknown(ref err) => {
+                write!(f, "No hash is known for params file {}, fetch or regenerate them", err)
+            }
+            Error::ParamsFetchFailed(ref err) => write!(f, "Failed to fetch params: {}", err),
+            Error::BackupFailed(ref err) => write!(f, "Wallet backup failed: {}", err),
+            Error::TlsPinMismatch(ref host) => {
+                write!(f, "Certificate of {} doesn't match the pinned ones", host)
+            }
+            Error::ProxyFailed(ref err) => write!(f, "SOCKS5 proxy failed: {}", err),
+            Error::ProtocolVersionMismatch(ref err) => {
+                write!(f, "Incompatible protocol versions: {}", err)
+            }
+            Error::BadFrame(ref err) => write!(f, "Bad frame: {}", err),
+            Error::EnclaveFailed(ref err) => write!(f, "Key store failed: {}", err),
+            Error::RequestTimeout(ref err) => write!(f, "Request timed out: {}", err),
+            Error::WebhookFailed(ref err) => write!(f, "Webhook failed: {}", err),
             Error::VmError => f.write_str("VM error"),
             Error::BadContract => f.write_str("Contract is poorly defined"),
             Error::Groth16Error => f.write_str("Groth16 error"),
@@ -123,14 +162,19 @@ impl fmt::Display for Error {
             Error::ServicesError(ref err) => write!(f, "Services error: {}", err),
             Error::ZmqError(ref err) => write!(f, "ZmqError: {}", err),
             Error::VerifyFailed => f.write_str("Verify failed"),
+            Error::MnemonicError(ref err) => write!(f, "Mnemonic error: {}", err),
+            Error::CoBuildFailed(ref err) => write!(f, "Co-build failed: {}", err),
             Error::ClientFailed(ref err) => write!(f, "Client failed: {}", err),
             #[cfg(feature = "btc")]
             Error::BtcFailed(ref err) => write!(f, "Btc client failed: {}", err),
             #[cfg(feature = "sol")]
             Error::SolFailed(ref err) => write!(f, "Sol client failed: {}", err),
+            #[cfg(feature = "grpc")]
+            Error::GrpcFailed(ref err) => write!(f, "gRPC failed: {}", err),
             Error::TryIntoError => f.write_str("TryInto error"),
             Error::TryFromError => f.write_str("TryFrom error"),
             Error::RocksdbError(ref err) => write!(f, "Rocksdb Error: {}", err),
+            Error::SledError(ref err) => write!(f, "Sled Error: {}", err),
             Error::SlabsStore(ref err) => write!(f, "SlabsStore Error: {}", err),
             Error::JsonRpcError(ref err) => write!(f, "JsonRpc Error: {}", err),
             Error::TreeFull => f.write_str("MerkleTree is full"),
@@ -147,6 +191,10 @@ impl fmt::Display for Error {
             }
             Error::KeypairPathNotFound => f.write_str("No keypair file detected."),
             Error::CashierKeysNotFound => f.write_str("No cashier public keys detected."),
+            Error::CashierProvisioningFailed(ref err) => {
+                write!(f, "Cashier provisioning failed: {}", err)
+            }
+            Error::SupportBundleFailed(ref err) => write!(f, "Support bundle failed: {}", err),
             Error::SetLoggerError => f.write_str("SetLoggerError"),
             Error::TokenParseError => f.write_str("Could not parse token parameter"),
             Error::TungsteniteError => f.write_str("TungsteniteError"),
@@ -161,12 +209,20 @@ impl From<zeromq::ZmqError> for Error {
     }
 }
 
+#[cfg(feature = "rocksdb")]
 impl From<rocksdb::Error> for Error {
     fn from(err: rocksdb::Error) -> Error {
         Error::RocksdbError(err.to_string())
     }
 }
 
+#[cfg(feature = "sled")]
+impl From<sled::Error> for Error {
+    fn from(err: sled::Error) -> Error {
+        Error::SledError(err.to_string())
+    }
+}
+
 impl From<serde_json::Error> for Error {
     fn from(err: serde_json::Error) -> Error {
         Error::SerdeJsonError(err.to_string())
@@ -215,6 +271,12 @@ impl From<async_native_tls::Error> for Error {
     }
 }
 
+impl From<rustls::TLSError> for Error {
+    fn from(err: rustls::TLSError) -> Error {
+        Error::TlsFailed(err.to_string())
+    }
+}
+
 impl From<std::net::AddrParseError> for Error {
     fn from(_err: std::net::AddrParseError) -> Error {
         Error::AddrParseError
@@ -301,6 +363,12 @@ impl From<bs58::decode::Error> for Error {
     }
 }
 
+impl From<bip39::Error> for Error {
+    fn from(err: bip39::Error) -> Error {
+        Error::MnemonicError(err.to_string())
+    }
+}
+
 impl From<log::SetLoggerError> for Error {
     fn from(_err: log::SetLoggerError) -> Error {
         Error::SetLoggerError
@@ -14,6 +14,7 @@ pub mod endian;
 pub mod error;
 pub mod net;
 pub mod rpc;
+pub mod schema;
 pub mod serial;
 pub mod service;
 pub mod state;
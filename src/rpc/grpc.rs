@@ -0,0 +1,339 @@
+//! The darkfid wallet API over gRPC, as proto/darkfid.proto defines it,
+//! for exchange backends that would rather have typed bindings generated
+//! for their language. Each call is made into the JSON-RPC request of the
+//! same name and handled as one, and the Subscribe calls stream what the
+//! WebSocket subscriptions push. The rpc_token is sent in the metadata as
+//! authorization: Bearer <token>.
+//!
+//! tonic runs on tokio rather than smol, so the server gets a runtime of
+//! its own.
+
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use async_executor::Executor;
+use async_trait::async_trait;
+use futures::{Stream, StreamExt};
+use log::info;
+use serde_json::{json, Value};
+use tonic::{Code, Request, Response, Status};
+
+use crate::rpc::jsonrpc::{request as jsonreq, JsonError, JsonRequest, JsonResult};
+use crate::rpc::wsserver::{Pusher, SubscriptionHandler};
+use crate::{Error, Result};
+
+pub mod proto {
+    tonic::include_proto!("darkfi.darkfid.v1");
+}
+
+use proto::darkfid_server::{Darkfid, DarkfidServer};
+use proto::{
+    DepositReply, DepositRequest, GetBalanceReply, GetBalanceRequest, GetTxStatusRequest, Payment,
+    SlabApplied, SubscribePaymentsRequest, SubscribeSlabsRequest, TransferReply, TransferRequest,
+    TxStatus, WithdrawReply, WithdrawRequest,
+};
+
+struct GrpcService<H> {
+    rh: Arc<H>,
+    // What the handlers spawn on, e.g. subscriptions
+    executor: Arc<Executor<'static>>,
+}
+
+// The JSON-RPC request a call is made into, with the rpc_token from its
+// metadata
+fn json_request<T>(request: &Request<T>, method: &str, params: Value) -> JsonRequest {
+    let mut req = jsonreq(json!(method), params);
+    req.auth = request
+        .metadata()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.to_string());
+    req
+}
+
+// The status a JSON-RPC error is answered with
+fn status(e: JsonError) -> Status {
+    let code = match e.error.code.as_i64() {
+        Some(-32020) => Code::Unauthenticated,
+        Some(-32099) => Code::PermissionDenied,
+        Some(-32601) => Code::Unimplemented,
+        Some(-32602) | Some(-32014..=-32010) => Code::InvalidArgument,
+        _ => Code::Internal,
+    };
+    let message = match &e.error.message {
+        Value::String(message) => message.clone(),
+        message => message.to_string(),
+    };
+    Status::new(code, message)
+}
+
+fn result(reply: JsonResult) -> std::result::Result<Value, Status> {
+    match reply {
+        JsonResult::Resp(r) => Ok(r.result),
+        JsonResult::Err(e) => Err(status(e)),
+        JsonResult::Notif(_) => Err(Status::internal("Unexpected reply")),
+    }
+}
+
+fn string(value: Value) -> std::result::Result<String, Status> {
+    match value {
+        Value::String(s) => Ok(s),
+        _ => Err(Status::internal("Unexpected reply")),
+    }
+}
+
+// Empty strings are how proto3 leaves a field out
+fn optional(value: &str) -> Value {
+    if value.is_empty() {
+        Value::Null
+    } else {
+        json!(value)
+    }
+}
+
+fn payment(payload: &Value) -> Option<Payment> {
+    Some(Payment {
+        amount: payload["amount"].as_str()?.to_string(),
+        token: payload["token"].as_str()?.to_string(),
+        token_id: payload["token_id"].as_str()?.to_string(),
+        memo: payload["memo"].as_str()?.to_string(),
+        tx_id: payload["tx_id"].as_str()?.to_string(),
+        slab_index: payload["slab_index"].as_u64()?,
+    })
+}
+
+fn slab_applied(payload: &Value) -> Option<SlabApplied> {
+    Some(SlabApplied {
+        index: payload["index"].as_u64()?,
+    })
+}
+
+fn tx_status(payload: &Value) -> Option<TxStatus> {
+    Some(TxStatus {
+        status: payload["status"].as_str()?.to_string(),
+        slab_index: payload["slab_index"].as_u64(),
+        updated: payload["updated"].as_u64()?,
+    })
+}
+
+// What a subscription pushes, as the messages of a stream. Null payloads,
+// e.g. the status of a transaction not sent yet, are left out. The
+// subscription ends once the stream is dropped.
+struct Notifications<T> {
+    queued: async_channel::Receiver<String>,
+    convert: fn(&Value) -> Option<T>,
+    _open: async_channel::Sender<()>,
+}
+
+impl<T> Stream for Notifications<T> {
+    type Item = std::result::Result<T, Status>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let text = match self.queued.poll_next_unpin(cx) {
+                Poll::Ready(Some(text)) => text,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            let notif: Value = serde_json::from_str(&text).unwrap_or(Value::Null);
+            let payload = &notif["params"][1];
+            if payload.is_null() {
+                continue;
+            }
+            return Poll::Ready(Some(
+                (self.convert)(payload).ok_or_else(|| Status::internal("Unexpected notification")),
+            ));
+        }
+    }
+}
+
+impl<H: SubscriptionHandler + 'static> GrpcService<H> {
+    async fn call<T>(
+        &self,
+        request: &Request<T>,
+        method: &str,
+        params: Value,
+    ) -> std::result::Result<Value, Status> {
+        let req = json_request(request, method, params);
+        result(self.rh.handle_request(req, self.executor.clone()).await)
+    }
+
+    async fn subscribe<T, M>(
+        &self,
+        request: &Request<T>,
+        method: &str,
+        params: Value,
+        convert: fn(&Value) -> Option<M>,
+    ) -> std::result::Result<Response<Notifications<M>>, Status> {
+        let req = json_request(request, method, params);
+        let (sender, queued) = async_channel::unbounded::<String>();
+        let (open, closed) = async_channel::bounded::<()>(1);
+        let pusher = Pusher::new(json!(method), 1, sender, closed);
+        self.rh.subscribe(req, pusher, self.executor.clone()).await.map_err(status)?;
+        Ok(Response::new(Notifications {
+            queued,
+            convert,
+            _open: open,
+        }))
+    }
+}
+
+#[async_trait]
+impl<H: SubscriptionHandler + 'static> Darkfid for GrpcService<H> {
+    type SubscribePaymentsStream = Notifications<Payment>;
+    type SubscribeSlabsStream = Notifications<SlabApplied>;
+    type SubscribeTxStatusStream = Notifications<TxStatus>;
+
+    async fn get_balance(
+        &self,
+        request: Request<GetBalanceRequest>,
+    ) -> std::result::Result<Response<GetBalanceReply>, Status> {
+        let balances = self.call(&request, "get_balance", json!([])).await?;
+        let balances =
+            serde_json::from_value(balances).map_err(|_| Status::internal("Unexpected reply"))?;
+        Ok(Response::new(GetBalanceReply { balances }))
+    }
+
+    async fn transfer(
+        &self,
+        request: Request<TransferRequest>,
+    ) -> std::result::Result<Response<TransferReply>, Status> {
+        let r = request.get_ref();
+        let params = json!([
+            r.token,
+            r.address,
+            r.amount,
+            optional(&r.coin_selection),
+            optional(&r.fee),
+            optional(&r.memo),
+            r.confirmed,
+            r.allow_duplicate,
+        ]);
+        let tx_id = string(self.call(&request, "transfer", params).await?)?;
+        Ok(Response::new(TransferReply { tx_id }))
+    }
+
+    async fn deposit(
+        &self,
+        request: Request<DepositRequest>,
+    ) -> std::result::Result<Response<DepositReply>, Status> {
+        let r = request.get_ref();
+        let params = json!([r.network, r.token, r.public_key]);
+        let deposit_address = string(self.call(&request, "deposit", params).await?)?;
+        Ok(Response::new(DepositReply { deposit_address }))
+    }
+
+    async fn withdraw(
+        &self,
+        request: Request<WithdrawRequest>,
+    ) -> std::result::Result<Response<WithdrawReply>, Status> {
+        let r = request.get_ref();
+        let params = json!([r.network, r.token, r.public_key, r.amount]);
+        let tx_id = string(self.call(&request, "withdraw", params).await?)?;
+        Ok(Response::new(WithdrawReply { tx_id }))
+    }
+
+    async fn get_tx_status(
+        &self,
+        request: Request<GetTxStatusRequest>,
+    ) -> std::result::Result<Response<TxStatus>, Status> {
+        let params = json!([request.get_ref().tx_id]);
+        let status = self.call(&request, "get_tx_status", params).await?;
+        if status.is_null() {
+            return Err(Status::not_found("The wallet didn't send this transaction"));
+        }
+        let status = tx_status(&status).ok_or_else(|| Status::internal("Unexpected reply"))?;
+        Ok(Response::new(status))
+    }
+
+    async fn subscribe_payments(
+        &self,
+        request: Request<SubscribePaymentsRequest>,
+    ) -> std::result::Result<Response<Self::SubscribePaymentsStream>, Status> {
+        self.subscribe(&request, "subscribe_payments", json!([]), payment).await
+    }
+
+    async fn subscribe_slabs(
+        &self,
+        request: Request<SubscribeSlabsRequest>,
+    ) -> std::result::Result<Response<Self::SubscribeSlabsStream>, Status> {
+        self.subscribe(&request, "subscribe_slabs", json!([]), slab_applied).await
+    }
+
+    async fn subscribe_tx_status(
+        &self,
+        request: Request<GetTxStatusRequest>,
+    ) -> std::result::Result<Response<Self::SubscribeTxStatusStream>, Status> {
+        let params = json!([request.get_ref().tx_id]);
+        self.subscribe(&request, "subscribe_tx_status", params, tx_status).await
+    }
+}
+
+/// Serve `rh` over gRPC on `socket_addr` until it fails. Blocks the thread
+/// it's called on, which runs the tokio runtime tonic needs.
+pub fn listen_and_serve_grpc(
+    socket_addr: SocketAddr,
+    rh: Arc<impl SubscriptionHandler + 'static>,
+) -> Result<()> {
+    let executor = Arc::new(Executor::new());
+    let ex = executor.clone();
+    std::thread::spawn(move || smol::future::block_on(ex.run(futures::future::pending::<()>())));
+
+    let service = DarkfidServer::new(GrpcService { rh, executor });
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+    info!("Serving gRPC on {}", socket_addr);
+    runtime
+        .block_on(tonic::transport::Server::builder().add_service(service).serve(socket_addr))
+        .map_err(|e| Error::GrpcFailed(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::jsonrpc::{error as jsonerr, ErrorCode};
+
+    #[test]
+    fn test_status() {
+        let id = json!(1);
+        let unauthorized = status(jsonerr(ErrorCode::Unauthorized, None, id.clone()));
+        assert_eq!(unauthorized.code(), Code::Unauthenticated);
+        let message = Some("Bad amount".to_string());
+        let invalid = status(jsonerr(ErrorCode::InvalidAmountParam, message, id.clone()));
+        assert_eq!(invalid.code(), Code::InvalidArgument);
+        assert_eq!(invalid.message(), "Bad amount");
+        let failed = status(jsonerr(ErrorCode::InternalError, None, id));
+        assert_eq!(failed.code(), Code::Internal);
+    }
+
+    #[test]
+    fn test_notifications() {
+        let (sender, queued) = async_channel::unbounded::<String>();
+        let (open, closed) = async_channel::bounded::<()>(1);
+        let pusher = Pusher::new(json!("subscribe_tx_status"), 1, sender, closed);
+        let mut stream = Notifications {
+            queued,
+            convert: tx_status,
+            _open: open,
+        };
+
+        smol::future::block_on(async {
+            // Not sent yet
+            assert!(pusher.push(Value::Null).await);
+            let confirmed = json!({ "status": "confirmed", "slab_index": 42, "updated": 1 });
+            assert!(pusher.push(confirmed).await);
+            let status = stream.next().await.unwrap().unwrap();
+            assert_eq!(status.status, "confirmed");
+            assert_eq!(status.slab_index, Some(42));
+
+            assert!(pusher.push(json!({ "status": 1 })).await);
+            assert!(stream.next().await.unwrap().is_err());
+
+            // The subscription ends with the stream
+            drop(stream);
+            pusher.closed().await;
+        });
+    }
+}
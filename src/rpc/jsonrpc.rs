@@ -1,4 +1,5 @@
 use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
 use std::str;
 
 use async_std::io::{ReadExt, WriteExt};
@@ -7,6 +8,7 @@ use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use smol::Async;
 
+use crate::service::TlsClientConfig;
 use crate::Error;
 
 #[derive(Debug, Clone)]
@@ -21,6 +23,7 @@ pub enum ErrorCode {
     InvalidTokenIdParam,
     InvalidAddressParam,
     InvalidSymbolParam,
+    Unauthorized,
     ServerError(i64),
 }
 
@@ -37,6 +40,7 @@ impl ErrorCode {
             ErrorCode::InvalidTokenIdParam => -32012,
             ErrorCode::InvalidAddressParam => -32013,
             ErrorCode::InvalidSymbolParam => -32014,
+            ErrorCode::Unauthorized => -32020,
             ErrorCode::ServerError(c) => c,
         }
     }
@@ -53,6 +57,7 @@ impl ErrorCode {
             ErrorCode::InvalidTokenIdParam => "Invalid token id param",
             ErrorCode::InvalidAddressParam => "Invalid address param",
             ErrorCode::InvalidSymbolParam => "Invalid symbol param",
+            ErrorCode::Unauthorized => "Unauthorized",
             ErrorCode::ServerError(_) => "Server error",
         };
         desc.to_string()
@@ -73,6 +78,9 @@ pub struct JsonRequest {
     pub method: Value,
     pub params: Value,
     pub id: Value,
+    /// The token the server requires for some methods, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -110,6 +118,7 @@ pub fn request(m: Value, p: Value) -> JsonRequest {
         method: m,
         params: p,
         id: json!(rng.gen::<u32>()),
+        auth: None,
     }
 }
 
@@ -150,9 +159,20 @@ pub async fn send_request(url: &str, data: Value) -> Result<JsonResult, Error> {
     let use_tls: bool;
     let parsed_url = url::Url::parse(url)?;
 
+    let mut buf = [0; 2048];
+    let bytes_read: usize;
+    let data_str = serde_json::to_string(&data)?;
+
     match parsed_url.scheme() {
         "tcp" => use_tls = false,
         "tls" => use_tls = true,
+        "unix" => {
+            let mut stream = Async::<UnixStream>::connect(parsed_url.path()).await?;
+            stream.write_all(&data_str.as_bytes()).await?;
+            bytes_read = stream.read(&mut buf[..]).await?;
+            let reply: JsonResult = serde_json::from_slice(&buf[0..bytes_read])?;
+            return Ok(reply);
+        }
         _ => return Err(Error::UrlParseError),
     }
 
@@ -168,10 +188,6 @@ pub async fn send_request(url: &str, data: Value) -> Result<JsonResult, Error> {
             .ok_or(Error::UrlParseError)?
     };
 
-    let mut buf = [0; 2048];
-    let bytes_read: usize;
-    let data_str = serde_json::to_string(&data)?;
-
     let mut stream = Async::<TcpStream>::connect(socket_addr).await?;
 
     if use_tls {
@@ -186,3 +202,36 @@ pub async fn send_request(url: &str, data: Value) -> Result<JsonResult, Error> {
     let reply: JsonResult = serde_json::from_slice(&buf[0..bytes_read])?;
     Ok(reply)
 }
+
+/// Same as send_request(), with tls:// URLs connected to as `tls` says,
+/// e.g. presenting a client certificate
+pub async fn send_request_tls(
+    url: &str,
+    data: Value,
+    tls: &TlsClientConfig,
+) -> Result<JsonResult, Error> {
+    let parsed_url = url::Url::parse(url)?;
+    if parsed_url.scheme() != "tls" {
+        return send_request(url, data).await;
+    }
+
+    let host = parsed_url.host_str().ok_or(Error::UrlParseError)?.to_string();
+    let port = parsed_url.port().ok_or(Error::UrlParseError)?;
+    let socket_addr = {
+        let host = host.clone();
+        smol::unblock(move || (host.as_str(), port).to_socket_addrs())
+            .await?
+            .next()
+            .ok_or(Error::UrlParseError)?
+    };
+
+    let stream = Async::<TcpStream>::connect(socket_addr).await?;
+    let mut stream = tls.connect(&host, stream).await?;
+    stream.write_all(serde_json::to_string(&data)?.as_bytes()).await?;
+    stream.flush().await?;
+
+    let mut buf = [0; 2048];
+    let bytes_read = stream.read(&mut buf[..]).await?;
+    let reply: JsonResult = serde_json::from_slice(&buf[0..bytes_read])?;
+    Ok(reply)
+}
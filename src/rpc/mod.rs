@@ -1,3 +1,8 @@
+#[cfg(feature = "grpc")]
+pub mod grpc;
 pub mod jsonrpc;
+pub mod prometheus;
+pub mod rest;
 pub mod rpcserver;
 pub mod websockets;
+pub mod wsserver;
@@ -0,0 +1,235 @@
+//! Metrics of a running node in the Prometheus text format, served over
+//! plain HTTP at /metrics. Counters and histograms are kept in a global
+//! registry any part of the node records to, and the daemon adds the
+//! values it reads when scraped, like the number of coins in the wallet.
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_executor::Executor;
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use log::{debug, info};
+use smol::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    Async,
+};
+
+use crate::Result;
+
+/// Upper bounds, in seconds, of the buckets durations are counted in
+const DURATION_BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Longest request head read from a scraper
+const MAX_REQUEST_SIZE: usize = 8192;
+
+lazy_static! {
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// How many durations fell in each of DURATION_BUCKETS, and their sum
+pub struct Histogram {
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: DURATION_BUCKETS.iter().map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = DURATION_BUCKETS.iter().position(|le| seconds <= *le) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        // Buckets count every duration up to their bound
+        let mut cumulative = 0;
+        for (le, bucket) in DURATION_BUCKETS.iter().zip(self.buckets.iter()) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, cumulative);
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    pub slabs_applied: Counter,
+    /// Time checking the proofs of a slab, averaged over the chunk it was
+    /// verified in
+    pub slab_verify: Histogram,
+    /// Time applying a slab once its proofs were checked
+    pub slab_apply: Histogram,
+    /// Time making the proofs of a transaction we send
+    pub proof_generation: Histogram,
+    rpc_requests: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Metrics {
+    pub fn rpc_request(&self, method: &str) {
+        let mut requests = self.rpc_requests.lock().unwrap();
+        *requests.entry(method.to_string()).or_insert(0) += 1;
+    }
+
+    /// The registry in the text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        write_metric(
+            &mut out,
+            "darkfi_slabs_applied_total",
+            "counter",
+            "Slabs applied to the state",
+            self.slabs_applied.get(),
+        );
+        self.slab_verify.render(
+            &mut out,
+            "darkfi_slab_verify_seconds",
+            "Time checking the proofs of a slab",
+        );
+        self.slab_apply.render(
+            &mut out,
+            "darkfi_slab_apply_seconds",
+            "Time applying a verified slab",
+        );
+        self.proof_generation.render(
+            &mut out,
+            "darkfi_proof_generation_seconds",
+            "Time making the proofs of a sent transaction",
+        );
+
+        let name = "darkfi_rpc_requests_total";
+        let _ = writeln!(out, "# HELP {} JSON-RPC requests by method", name);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        for (method, count) in self.rpc_requests.lock().unwrap().iter() {
+            let _ = writeln!(out, "{}{{method=\"{}\"}} {}", name, method, count);
+        }
+        out
+    }
+}
+
+/// Append a metric with a single value to `out`
+pub fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, value: impl Display) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+#[async_trait]
+pub trait MetricsSource: Sync + Send {
+    /// The metrics only read when scraped, in the text format
+    async fn scrape(&self) -> String;
+}
+
+async fn serve(
+    mut stream: Async<TcpStream>,
+    source: Arc<impl MetricsSource + 'static>,
+) -> Result<()> {
+    let mut request = vec![];
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_SIZE {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or("").split_whitespace();
+    let reply = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = METRICS.render() + &source.scrape().await;
+            format!(
+                "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\
+                 Content-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_string(),
+    };
+    stream.write_all(reply.as_bytes()).await?;
+    Ok(())
+}
+
+pub async fn listen_and_serve_metrics(
+    socket_addr: SocketAddr,
+    source: Arc<impl MetricsSource + 'static>,
+    executor: Arc<Executor<'_>>,
+) -> Result<()> {
+    let listener = Async::<TcpListener>::bind(socket_addr)?;
+    info!("Serving metrics on http://{}/metrics", socket_addr);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let source = source.clone();
+        executor
+            .spawn(async move {
+                if let Err(e) = serve(stream, source).await {
+                    debug!(target: "METRICS", "Connection error: {}", e);
+                }
+            })
+            .detach();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let histogram = Histogram::default();
+        histogram.observe(Duration::from_millis(3));
+        histogram.observe(Duration::from_millis(30));
+        histogram.observe(Duration::from_secs(60));
+
+        let mut out = String::new();
+        histogram.render(&mut out, "test_seconds", "Test");
+        assert!(out.contains("test_seconds_bucket{le=\"0.001\"} 0\n"));
+        assert!(out.contains("test_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(out.contains("test_seconds_bucket{le=\"0.05\"} 2\n"));
+        assert!(out.contains("test_seconds_bucket{le=\"10\"} 2\n"));
+        assert!(out.contains("test_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(out.contains("test_seconds_sum 60.033\n"));
+        assert!(out.contains("test_seconds_count 3\n"));
+    }
+}
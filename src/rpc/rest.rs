@@ -0,0 +1,295 @@
+//! A REST facade over the JSON-RPC methods, for web shops and scripts
+//! without a JSON-RPC library. Each route calls a method with the fields
+//! of the JSON body, or of the query string, as its params in the order
+//! the route names them. The reply is the method's result, or its error
+//! with a 4xx or 5xx status. A token the method needs is given as
+//! Authorization: Bearer <token>.
+//!
+//! Web pages the user visits can send requests to localhost too. Those
+//! from an Origin that isn't allowed are refused, and bodies have to be
+//! sent as application/json, which a page can't without the server's
+//! consent.
+
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+
+use async_executor::Executor;
+use log::{debug, info};
+use serde_json::{json, Map, Value};
+use smol::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    Async,
+};
+use url::Url;
+
+use crate::rpc::jsonrpc::{request as jsonreq, ErrorCode, JsonResult};
+use crate::rpc::rpcserver::RequestHandler;
+use crate::Result;
+
+/// Longest request, head and body, read from a client
+const MAX_REQUEST_SIZE: usize = 65536;
+
+pub struct Route {
+    /// The HTTP method, e.g. GET
+    pub method: &'static str,
+    pub path: &'static str,
+    pub rpc_method: &'static str,
+    /// The names of the params, in the order the RPC method takes them
+    pub params: &'static [&'static str],
+}
+
+impl Route {
+    // The params for the RPC method from the named ones given. The ones
+    // missing are null, and left out at the end where they're optional.
+    fn params(&self, mut named: Map<String, Value>) -> Value {
+        let mut params: Vec<Value> = self
+            .params
+            .iter()
+            .map(|name| named.remove(*name).unwrap_or(Value::Null))
+            .collect();
+        while params.last() == Some(&Value::Null) {
+            params.pop();
+        }
+        json!(params)
+    }
+}
+
+struct HttpRequest {
+    method: String,
+    target: String,
+    authorization: Option<String>,
+    content_type: Option<String>,
+    origin: Option<String>,
+    body: Vec<u8>,
+}
+
+impl HttpRequest {
+    // The status and body to refuse the request with, if it comes from a
+    // web page that isn't allowed, or has a body that isn't JSON
+    fn refusal(&self, allowed_origins: &[String]) -> Option<(&'static str, Value)> {
+        if let Some(origin) = &self.origin {
+            if !allowed_origins.contains(origin) {
+                return Some(("403 Forbidden", json!({ "message": "Origin not allowed" })));
+            }
+        }
+
+        let media_type = self
+            .content_type
+            .as_deref()
+            .and_then(|content_type| content_type.split(';').next())
+            .map(|media_type| media_type.trim().to_lowercase());
+        if self.method == "POST" && media_type.as_deref() != Some("application/json") {
+            return Some((
+                "415 Unsupported Media Type",
+                json!({ "message": "The body has to be application/json" }),
+            ));
+        }
+        None
+    }
+}
+
+async fn read_request(stream: &mut Async<TcpStream>) -> Result<Option<HttpRequest>> {
+    let mut request = vec![];
+    let mut buf = [0; 4096];
+    let head_len = loop {
+        if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || request.len() + n > MAX_REQUEST_SIZE {
+            return Ok(None);
+        }
+        request.extend_from_slice(&buf[..n]);
+    };
+
+    let head = String::from_utf8_lossy(&request[..head_len]).to_string();
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or("").split_whitespace();
+    let (method, target) = match (request_line.next(), request_line.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+        _ => return Ok(None),
+    };
+
+    let mut content_length = 0;
+    let mut authorization = None;
+    let mut content_type = None;
+    let mut origin = None;
+    for line in lines {
+        let (name, value) = match line.find(':') {
+            Some(colon) => (line[..colon].trim().to_lowercase(), line[colon + 1..].trim()),
+            None => continue,
+        };
+        match name.as_str() {
+            "content-length" => content_length = value.parse().unwrap_or(0),
+            "authorization" => {
+                authorization = value.strip_prefix("Bearer ").map(|token| token.to_string())
+            }
+            "content-type" => content_type = Some(value.to_string()),
+            "origin" => origin = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    if head_len + content_length > MAX_REQUEST_SIZE {
+        return Ok(None);
+    }
+
+    let mut body = request.split_off(head_len);
+    while body.len() < content_length {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        body.extend_from_slice(&buf[..n]);
+    }
+    body.truncate(content_length);
+
+    Ok(Some(HttpRequest {
+        method,
+        target,
+        authorization,
+        content_type,
+        origin,
+        body,
+    }))
+}
+
+// The HTTP status for an error an RPC method replied with
+fn error_status(code: i64) -> &'static str {
+    match code {
+        c if c == ErrorCode::MethodNotFound.code() => "404 Not Found",
+        c if c == ErrorCode::Unauthorized.code() => "401 Unauthorized",
+        c if c == ErrorCode::InternalError.code() => "500 Internal Server Error",
+        // Invalid requests and params, including the ones for amounts,
+        // addresses and tokens
+        -32700 | -32600 | -32602 | -32014..=-32010 => "400 Bad Request",
+        _ => "500 Internal Server Error",
+    }
+}
+
+async fn serve(
+    mut stream: Async<TcpStream>,
+    routes: &'static [Route],
+    allowed_origins: Arc<Vec<String>>,
+    rh: Arc<impl RequestHandler + 'static>,
+    executor: Arc<Executor<'_>>,
+) -> Result<()> {
+    let request = match read_request(&mut stream).await? {
+        Some(request) => request,
+        None => return Ok(()),
+    };
+
+    let url = Url::parse(&format!("http://localhost{}", request.target))?;
+    let route = routes
+        .iter()
+        .find(|route| route.method == request.method && route.path == url.path());
+
+    let (status, body) = match (route, request.refusal(&allowed_origins)) {
+        (_, Some(refusal)) => refusal,
+        (Some(route), None) => {
+            // The JSON body's fields for a POST, the query string's
+            // otherwise
+            let named = if request.body.is_empty() {
+                Ok(url.query_pairs().map(|(k, v)| (k.to_string(), json!(v))).collect())
+            } else {
+                serde_json::from_slice::<Map<String, Value>>(&request.body)
+            };
+            match named {
+                Ok(named) => {
+                    let mut req = jsonreq(json!(route.rpc_method), route.params(named));
+                    req.auth = request.authorization;
+                    match rh.handle_request(req, executor).await {
+                        JsonResult::Resp(r) => ("200 OK", r.result),
+                        JsonResult::Err(e) => {
+                            (error_status(e.error.code.as_i64().unwrap_or(0)), json!(e.error))
+                        }
+                        JsonResult::Notif(_) => ("500 Internal Server Error", Value::Null),
+                    }
+                }
+                Err(e) => ("400 Bad Request", json!({ "message": e.to_string() })),
+            }
+        }
+        (None, None) => ("404 Not Found", json!({ "message": "No such route" })),
+    };
+
+    let body = body.to_string();
+    let reply = format!(
+        "HTTP/1.0 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(reply.as_bytes()).await?;
+    Ok(())
+}
+
+pub async fn listen_and_serve_rest(
+    socket_addr: SocketAddr,
+    routes: &'static [Route],
+    allowed_origins: Vec<String>,
+    rh: Arc<impl RequestHandler + 'static>,
+    executor: Arc<Executor<'_>>,
+) -> Result<()> {
+    let listener = Async::<TcpListener>::bind(socket_addr)?;
+    info!("Serving REST on http://{}", socket_addr);
+
+    let allowed_origins = Arc::new(allowed_origins);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let (allowed_origins, rh, ex) = (allowed_origins.clone(), rh.clone(), executor.clone());
+        executor
+            .spawn(async move {
+                if let Err(e) = serve(stream, routes, allowed_origins, rh, ex).await {
+                    debug!(target: "REST", "Connection error: {}", e);
+                }
+            })
+            .detach();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_params() {
+        let route = Route {
+            method: "POST",
+            path: "/transfer",
+            rpc_method: "transfer",
+            params: &["token", "address", "amount", "coin_selection", "fee", "memo"],
+        };
+        let named = json!({"amount": "1.5", "token": "btc", "address": "tdrk1", "memo": "hi"});
+        let named = named.as_object().unwrap().clone();
+        assert_eq!(route.params(named), json!(["btc", "tdrk1", "1.5", null, null, "hi"]));
+
+        let named = json!({"token": "btc"}).as_object().unwrap().clone();
+        assert_eq!(route.params(named), json!(["btc"]));
+    }
+
+    #[test]
+    fn test_request_refusal() {
+        let allowed = vec!["https://shop.example".to_string()];
+        let request = |method: &str, content_type: Option<&str>, origin: Option<&str>| {
+            HttpRequest {
+                method: method.to_string(),
+                target: "/transfer".to_string(),
+                authorization: None,
+                content_type: content_type.map(|value| value.to_string()),
+                origin: origin.map(|value| value.to_string()),
+                body: vec![],
+            }
+            .refusal(&allowed)
+            .map(|(status, _)| status)
+        };
+
+        let json = Some("application/json; charset=utf-8");
+        assert_eq!(request("POST", json, None), None);
+        assert_eq!(request("POST", json, Some("https://shop.example")), None);
+        assert_eq!(request("GET", None, None), None);
+
+        // What a page can send without the server's consent
+        assert_eq!(request("POST", Some("text/plain"), None), Some("415 Unsupported Media Type"));
+        assert_eq!(request("POST", None, None), Some("415 Unsupported Media Type"));
+        assert_eq!(request("GET", None, Some("https://evil.example")), Some("403 Forbidden"));
+    }
+}
@@ -1,24 +1,49 @@
 use std::net::{SocketAddr, TcpListener, TcpStream};
-use std::path::PathBuf;
+use std::os::unix::{
+    fs::{DirBuilderExt, PermissionsExt},
+    net::UnixListener,
+};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use async_executor::Executor;
-use async_native_tls::{Identity, TlsAcceptor};
+use async_rustls::TlsAcceptor;
 use async_trait::async_trait;
 use log::{debug, error};
+use rustls::Session;
 use smol::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     Async,
 };
 
-use crate::rpc::jsonrpc::{JsonRequest, JsonResult};
-use crate::Result;
+use serde_json::{json, Value};
+
+use crate::rpc::jsonrpc::{
+    error as jsonerr,
+    ErrorCode::{InvalidRequest, ServerError},
+    JsonRequest, JsonResult,
+};
+use crate::service::tls::{tls_acceptor, ClientAuth};
+use crate::{Error, Result};
+
+/// Longest request, or batch of requests, taken by default. Longer ones
+/// close the connection.
+pub const DEFAULT_MAX_REQUEST_SIZE: usize = 1024 * 1024;
+
+/// Bytes read from a connection at once
+const READ_CHUNK_SIZE: usize = 8192;
 
 pub struct RpcServerConfig {
     pub socket_addr: SocketAddr,
-    pub use_tls: bool,
-    pub identity_path: PathBuf,
-    pub identity_pass: String,
+    /// PEM certificate chain and key to serve TLS with, plain TCP if None
+    pub tls_identity: Option<(PathBuf, PathBuf)>,
+    /// PEM CA certificate for clients' certificates. Connections without
+    /// one it signed may call anything but `cert_methods`.
+    pub tls_client_ca: Option<PathBuf>,
+    /// Methods that need a client certificate if tls_client_ca is set
+    pub cert_methods: &'static [&'static str],
+    /// Longest request, or batch of requests, taken
+    pub max_request_size: usize,
 }
 
 #[async_trait]
@@ -26,83 +51,195 @@ pub trait RequestHandler: Sync + Send {
     async fn handle_request(&self, req: JsonRequest, executor: Arc<Executor<'_>>) -> JsonResult;
 }
 
-async fn serve(
-    mut stream: Async<TcpStream>,
-    tls: Option<TlsAcceptor>,
-    rh: Arc<impl RequestHandler + 'static>,
+/// Serves only some methods of the handler it wraps, e.g. on a listener
+/// for monitoring that mustn't be able to move funds
+pub struct RestrictedHandler<H> {
+    inner: Arc<H>,
+    methods: &'static [&'static str],
+}
+
+impl<H> RestrictedHandler<H> {
+    pub fn new(inner: Arc<H>, methods: &'static [&'static str]) -> Self {
+        Self { inner, methods }
+    }
+}
+
+#[async_trait]
+impl<H: RequestHandler> RequestHandler for RestrictedHandler<H> {
+    async fn handle_request(&self, req: JsonRequest, executor: Arc<Executor<'_>>) -> JsonResult {
+        match req.method.as_str() {
+            Some(method) if self.methods.contains(&method) => {
+                self.inner.handle_request(req, executor).await
+            }
+            _ => {
+                let message = "Not allowed on this listener".to_string();
+                JsonResult::Err(jsonerr(ServerError(-32099), Some(message), req.id))
+            }
+        }
+    }
+}
+
+// What connections without a client certificate are served through:
+// the methods that need one are refused
+struct UncertifiedHandler<H> {
+    inner: Arc<H>,
+    cert_methods: &'static [&'static str],
+}
+
+#[async_trait]
+impl<H: RequestHandler> RequestHandler for UncertifiedHandler<H> {
+    async fn handle_request(&self, req: JsonRequest, executor: Arc<Executor<'_>>) -> JsonResult {
+        match req.method.as_str() {
+            Some(method) if self.cert_methods.contains(&method) => {
+                let message = "Needs a client certificate".to_string();
+                JsonResult::Err(jsonerr(ServerError(-32099), Some(message), req.id))
+            }
+            _ => self.inner.handle_request(req, executor).await,
+        }
+    }
+}
+
+/// Answer each request of a batch, in an array of their replies in the
+/// same order. A batch that's empty gets a single error instead.
+pub async fn handle_batch(
+    batch: Vec<Value>,
+    rh: &(impl RequestHandler + ?Sized),
     executor: Arc<Executor<'_>>,
-) -> Result<()> {
-    debug!(target: "RPC SERVER", "Accepted connection");
+) -> Value {
+    if batch.is_empty() {
+        return json!(jsonerr(InvalidRequest, None, Value::Null));
+    }
 
-    let mut buf = [0; 2048];
+    let mut replies = Vec::with_capacity(batch.len());
+    for req in batch {
+        let reply = match serde_json::from_value::<JsonRequest>(req) {
+            Ok(req) => rh.handle_request(req, executor.clone()).await,
+            Err(_) => JsonResult::Err(jsonerr(InvalidRequest, None, Value::Null)),
+        };
+        replies.push(reply);
+    }
+    json!(replies)
+}
 
-    match tls {
-        None => loop {
-            let n = match stream.read(&mut buf).await {
-                Ok(n) if n == 0 => {
-                    debug!(target: "RPC SERVER", "Closed connection");
-                    return Ok(());
-                }
-                Ok(n) => n,
-                Err(e) => {
-                    debug!(target: "RPC SERVER", "Failed to read from socket: {:#?}", e);
-                    debug!(target: "RPC SERVER", "Closed connection");
-                    return Ok(());
+// The first complete JSON value in `buf` and the bytes it took up, None
+// if more has to be read for it
+fn next_value(buf: &[u8]) -> serde_json::Result<Option<(Value, usize)>> {
+    let mut values = serde_json::Deserializer::from_slice(buf).into_iter::<Value>();
+    match values.next() {
+        Some(Ok(value)) => Ok(Some((value, values.byte_offset()))),
+        Some(Err(e)) if e.is_eof() => Ok(None),
+        Some(Err(e)) => Err(e),
+        None => Ok(None),
+    }
+}
+
+// Read until `pending` starts with a complete JSON value and take it
+// out. None once the connection is closed, or if the value would be
+// longer than `max_size`.
+async fn read_value<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    pending: &mut Vec<u8>,
+    max_size: usize,
+) -> std::io::Result<Option<serde_json::Result<Value>>> {
+    let mut buf = [0; READ_CHUNK_SIZE];
+    loop {
+        match next_value(pending) {
+            Ok(Some((value, len))) if len <= max_size => {
+                pending.drain(..len);
+                return Ok(Some(Ok(value)));
+            }
+            Ok(Some(_)) => {}
+            Ok(None) if pending.len() <= max_size => {
+                let n = stream.read(&mut buf).await?;
+                if n == 0 {
+                    return Ok(None);
                 }
-            };
+                pending.extend_from_slice(&buf[..n]);
+                continue;
+            }
+            Ok(None) => {}
+            Err(e) => return Ok(Some(Err(e))),
+        }
+        debug!(target: "RPC SERVER", "Received a request over {} bytes", max_size);
+        return Ok(None);
+    }
+}
+
+// Answer the requests on a connection until it's closed. A request may
+// arrive over several reads, and the next one may follow in the same.
+async fn serve_requests<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    max_request_size: usize,
+    rh: Arc<impl RequestHandler + 'static>,
+    executor: Arc<Executor<'_>>,
+) -> Result<()> {
+    let mut pending = Vec::new();
+
+    loop {
+        let value = match read_value(&mut stream, &mut pending, max_request_size).await {
+            Ok(Some(value)) => value,
+            Ok(None) => {
+                debug!(target: "RPC SERVER", "Closed connection");
+                return Ok(());
+            }
+            Err(e) => {
+                debug!(target: "RPC SERVER", "Failed to read from socket: {:#?}", e);
+                debug!(target: "RPC SERVER", "Closed connection");
+                return Ok(());
+            }
+        };
 
-            let r: JsonRequest = match serde_json::from_slice(&buf[0..n]) {
-                Ok(r) => r,
+        let j = match value {
+            Ok(Value::Array(batch)) => {
+                handle_batch(batch, rh.as_ref(), executor.clone()).await.to_string()
+            }
+            Ok(r) => match serde_json::from_value::<JsonRequest>(r) {
+                Ok(r) => serde_json::to_string(&rh.handle_request(r, executor.clone()).await)?,
                 Err(e) => {
                     debug!(target: "RPC SERVER", "Received invalid JSON: {:#?}", e);
                     debug!(target: "RPC SERVER", "Closed connection");
                     return Ok(());
                 }
-            };
-
-            let reply = rh.handle_request(r, executor.clone()).await;
-            let j = serde_json::to_string(&reply).unwrap();
-            debug!(target: "RPC", "<-- {}", j);
-
-            if let Err(e) = stream.write_all(j.as_bytes()).await {
-                debug!(target: "RPC SERVER", "Failed to write to socket: {:#?}", e);
+            },
+            Err(e) => {
+                debug!(target: "RPC SERVER", "Received invalid JSON: {:#?}", e);
                 debug!(target: "RPC SERVER", "Closed connection");
                 return Ok(());
             }
-        },
+        };
+        debug!(target: "RPC", "<-- {}", j);
+
+        if let Err(e) = stream.write_all(j.as_bytes()).await {
+            debug!(target: "RPC SERVER", "Failed to write to socket: {:#?}", e);
+            debug!(target: "RPC SERVER", "Closed connection");
+            return Ok(());
+        }
+    }
+}
+
+// `cert_methods` are refused to TLS connections without a client
+// certificate, and should be empty if none are asked for
+async fn serve(
+    stream: Async<TcpStream>,
+    tls: Option<TlsAcceptor>,
+    cert_methods: &'static [&'static str],
+    max_request_size: usize,
+    rh: Arc<impl RequestHandler + 'static>,
+    executor: Arc<Executor<'_>>,
+) -> Result<()> {
+    debug!(target: "RPC SERVER", "Accepted connection");
+
+    match tls {
+        None => serve_requests(stream, max_request_size, rh, executor).await,
         Some(tls) => match tls.accept(stream).await {
-            Ok(mut stream) => loop {
-                let n = match stream.read(&mut buf).await {
-                    Ok(n) if n == 0 => {
-                        debug!(target: "RPC SERVER", "Closed connection");
-                        return Ok(());
-                    }
-                    Ok(n) => n,
-                    Err(e) => {
-                        debug!(target: "RPC SERVER", "Failed to read from socket: {:#?}", e);
-                        debug!(target: "RPC SERVER", "Closed connection");
-                        return Ok(());
-                    }
-                };
-
-                let r: JsonRequest = match serde_json::from_slice(&buf[0..n]) {
-                    Ok(r) => r,
-                    Err(e) => {
-                        debug!(target: "RPC SERVER", "Received invalid JSON: {:#?}", e);
-                        debug!(target: "RPC SERVER", "Closed connection");
-                        return Ok(());
-                    }
-                };
-
-                let reply = rh.handle_request(r, executor.clone()).await;
-                let j = serde_json::to_string(&reply).unwrap();
-                debug!(target: "RPC", "<-- {}", j);
-
-                if let Err(e) = stream.write_all(j.as_bytes()).await {
-                    debug!(target: "RPC SERVER", "Failed to write to socket: {:#?}", e);
-                    return Ok(());
-                }
-            },
+            Ok(stream) if stream.get_ref().1.get_peer_certificates().is_none() => {
+                let rh = Arc::new(UncertifiedHandler {
+                    inner: rh,
+                    cert_methods,
+                });
+                serve_requests(stream, max_request_size, rh, executor).await
+            }
+            Ok(stream) => serve_requests(stream, max_request_size, rh, executor).await,
             Err(e) => {
                 debug!(target: "RPC SERVER", "Failed to establish TLS connection: {:#}", e);
                 Ok(())
@@ -114,6 +251,8 @@ async fn serve(
 async fn listen(
     listener: Async<TcpListener>,
     tls: Option<TlsAcceptor>,
+    cert_methods: &'static [&'static str],
+    max_request_size: usize,
     rh: Arc<impl RequestHandler + 'static>,
     executor: Arc<Executor<'_>>,
 ) -> Result<()> {
@@ -134,7 +273,8 @@ async fn listen(
 
         let ex2 = ex.clone();
         ex.spawn(async move {
-            if let Err(err) = serve(stream, tls, rh_c, ex2.clone()).await {
+            let served = serve(stream, tls, cert_methods, max_request_size, rh_c, ex2.clone());
+            if let Err(err) = served.await {
                 error!(target: "RPC SERVER", "Connection error: {:#?}", err);
             }
         })
@@ -147,21 +287,154 @@ pub async fn listen_and_serve(
     rh: Arc<impl RequestHandler + 'static>,
     executor: Arc<Executor<'_>>,
 ) -> Result<()> {
-    let tls: Option<TlsAcceptor>;
-
-    if cfg.use_tls {
-        let ident_bytes = std::fs::read(cfg.identity_path)?;
-        let identity = Identity::from_pkcs12(&ident_bytes, &cfg.identity_pass)?;
-        tls = Some(TlsAcceptor::from(native_tls::TlsAcceptor::new(identity)?));
-    } else {
-        tls = None;
-    }
+    let (client_auth, cert_methods) = match cfg.tls_client_ca {
+        Some(ca_path) => (ClientAuth::Optional(ca_path), cfg.cert_methods),
+        None => (ClientAuth::Off, &[][..]),
+    };
+    let tls = match &cfg.tls_identity {
+        Some((cert_path, key_path)) => Some(tls_acceptor(cert_path, key_path, &client_auth)?),
+        None => None,
+    };
 
     let listener = listen(
         Async::<TcpListener>::bind(cfg.socket_addr)?,
         tls,
+        cert_methods,
+        cfg.max_request_size,
         rh,
         executor,
     );
     listener.await
 }
+
+// Bind the socket in a directory only the daemon's user may enter, and
+// move it to `path` once its permissions are set, so nobody else can
+// connect to it in between
+fn bind_unix(path: &Path) -> Result<Async<UnixListener>> {
+    let name = path.file_name().ok_or(Error::UrlParseError)?;
+    let staging = path.with_file_name(format!(
+        ".{}.{}",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    std::fs::DirBuilder::new().mode(0o700).create(&staging)?;
+
+    let staged = staging.join("socket");
+    let bound = Async::<UnixListener>::bind(&staged).and_then(|listener| {
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o660))?;
+        std::fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&staged);
+    std::fs::remove_dir(&staging)?;
+    Ok(bound?)
+}
+
+/// Serve on a Unix socket at `path`, which only the daemon's user and
+/// group may connect to. A socket left over from a previous run is
+/// replaced.
+pub async fn listen_and_serve_unix(
+    path: &Path,
+    max_request_size: usize,
+    rh: Arc<impl RequestHandler + 'static>,
+    executor: Arc<Executor<'_>>,
+) -> Result<()> {
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = bind_unix(path)?;
+    debug!(target: "RPC SERVER", "Listening on unix://{}", path.display());
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let (rh, ex) = (rh.clone(), executor.clone());
+        executor
+            .spawn(async move {
+                debug!(target: "RPC SERVER", "Accepted connection");
+                if let Err(err) = serve_requests(stream, max_request_size, rh, ex).await {
+                    error!(target: "RPC SERVER", "Connection error: {:#?}", err);
+                }
+            })
+            .detach();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::jsonrpc::{request as jsonreq, response as jsonresp};
+
+    #[test]
+    fn test_bind_unix() -> Result<()> {
+        let dir = std::env::temp_dir().join("darkfi_test_rpc_unix");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("darkfid.sock");
+
+        let _listener = bind_unix(&path)?;
+        let mode = std::fs::metadata(&path)?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+        // Nothing is left behind but the socket
+        assert_eq!(std::fs::read_dir(&dir)?.count(), 1);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_value() -> Result<()> {
+        // Longer than a read, and followed by the next request
+        let long = json!({ "method": "say_hello", "params": ["x".repeat(3 * READ_CHUNK_SIZE)] });
+        let data = format!("{} [{{\"method\": \"get_info\"}}]", long);
+
+        smol::future::block_on(async {
+            let mut stream = smol::io::Cursor::new(data.as_bytes().to_vec());
+            let mut pending = Vec::new();
+            let first = read_value(&mut stream, &mut pending, data.len()).await?;
+            assert_eq!(first.unwrap()?, long);
+            let second = read_value(&mut stream, &mut pending, data.len()).await?;
+            assert!(second.unwrap()?.is_array());
+            assert!(read_value(&mut stream, &mut pending, data.len()).await?.is_none());
+
+            // Too long
+            let mut stream = smol::io::Cursor::new(data.as_bytes().to_vec());
+            let mut pending = Vec::new();
+            assert!(read_value(&mut stream, &mut pending, 1024).await?.is_none());
+
+            // Not JSON
+            let mut stream = smol::io::Cursor::new(b"{]".to_vec());
+            assert!(read_value(&mut stream, &mut Vec::new(), 1024).await?.unwrap().is_err());
+            Ok(())
+        })
+    }
+
+    struct Echo;
+
+    #[async_trait]
+    impl RequestHandler for Echo {
+        async fn handle_request(&self, req: JsonRequest, _ex: Arc<Executor<'_>>) -> JsonResult {
+            JsonResult::Resp(jsonresp(req.method, req.id))
+        }
+    }
+
+    #[test]
+    fn test_uncertified_handler() {
+        let handler = UncertifiedHandler {
+            inner: Arc::new(Echo),
+            cert_methods: &["transfer"],
+        };
+        let executor = Arc::new(Executor::new());
+
+        smol::future::block_on(async {
+            let req = jsonreq(json!("get_info"), json!([]));
+            let reply = handler.handle_request(req, executor.clone()).await;
+            assert!(matches!(reply, JsonResult::Resp(_)));
+
+            let req = jsonreq(json!("transfer"), json!([]));
+            match handler.handle_request(req, executor).await {
+                JsonResult::Err(e) => assert_eq!(e.error.code, json!(-32099)),
+                _ => panic!("transfer went through without a client certificate"),
+            }
+        });
+    }
+}
@@ -0,0 +1,223 @@
+//! JSON-RPC over WebSocket, for clients that would rather be told about
+//! payments and slabs than poll for them. Requests are answered as over
+//! the plain server, and a subscribe_* request is answered with the id of
+//! a subscription whose notifications then come on the same connection
+//! until it's closed:
+//! {"jsonrpc": "2.0", "method": "subscribe_slabs", "params": [1, {"index": 42}]}
+//!
+//! Any web page can open a WebSocket to localhost, so handshakes from a
+//! browser are refused unless their Origin is allowed. Clients outside a
+//! browser send none.
+
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+
+use async_executor::Executor;
+use async_trait::async_trait;
+use futures::{SinkExt, StreamExt};
+use log::{debug, info, warn};
+use serde_json::{json, Value};
+use smol::Async;
+use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tungstenite::http::StatusCode;
+use tungstenite::Message;
+
+use crate::rpc::jsonrpc::{
+    error as jsonerr, notification, response as jsonresp, ErrorCode::ParseError, JsonError,
+    JsonRequest, JsonResult,
+};
+use crate::rpc::rpcserver::{handle_batch, RequestHandler};
+use crate::Result;
+
+/// Where a subscription sends its notifications, for as long as the
+/// connection that made it is open
+#[derive(Clone)]
+pub struct Pusher {
+    method: Value,
+    id: u64,
+    sender: async_channel::Sender<String>,
+    closed: async_channel::Receiver<()>,
+}
+
+impl Pusher {
+    pub(crate) fn new(
+        method: Value,
+        id: u64,
+        sender: async_channel::Sender<String>,
+        closed: async_channel::Receiver<()>,
+    ) -> Self {
+        Self {
+            method,
+            id,
+            sender,
+            closed,
+        }
+    }
+
+    /// Send `params` as a notification of the subscription. False once
+    /// the connection is closed.
+    pub async fn push(&self, params: Value) -> bool {
+        let notif = notification(self.method.clone(), json!([self.id, params]));
+        self.sender.send(serde_json::to_string(&notif).unwrap()).await.is_ok()
+    }
+
+    /// Resolves once the connection is closed
+    pub async fn closed(&self) {
+        let _ = self.closed.recv().await;
+    }
+}
+
+#[async_trait]
+pub trait SubscriptionHandler: RequestHandler {
+    /// Whether `method` opens a subscription rather than getting a reply
+    fn is_subscription(&self, method: &str) -> bool;
+
+    /// Start sending what `req` subscribes to through `pusher`, or refuse
+    /// it with the error to reply
+    async fn subscribe(
+        &self,
+        req: JsonRequest,
+        pusher: Pusher,
+        executor: Arc<Executor<'_>>,
+    ) -> std::result::Result<(), JsonError>;
+}
+
+// Let the handshake through if it doesn't come from a browser page, or
+// from one of the `allowed` origins
+fn check_origin(
+    allowed: &[String],
+    req: &Request,
+    resp: Response,
+) -> std::result::Result<Response, ErrorResponse> {
+    let origin = match req.headers().get("origin") {
+        Some(origin) => origin,
+        None => return Ok(resp),
+    };
+    if allowed.iter().any(|allowed| origin.as_bytes() == allowed.as_bytes()) {
+        return Ok(resp);
+    }
+
+    warn!(target: "WS SERVER", "Refused a connection from origin {:?}", origin);
+    let mut refusal = ErrorResponse::new(Some("Origin not allowed".to_string()));
+    *refusal.status_mut() = StatusCode::FORBIDDEN;
+    Err(refusal)
+}
+
+async fn serve(
+    stream: Async<TcpStream>,
+    allowed_origins: Arc<Vec<String>>,
+    rh: Arc<impl SubscriptionHandler + 'static>,
+    executor: Arc<Executor<'_>>,
+) -> Result<()> {
+    let check = move |req: &Request, resp| check_origin(&allowed_origins, req, resp);
+    let (mut writer, mut reader) = async_tungstenite::accept_hdr_async(stream, check)
+        .await?
+        .split();
+    debug!(target: "WS SERVER", "Accepted connection");
+
+    // Replies and notifications are written in the order they're queued
+    let (sender, queued) = async_channel::unbounded::<String>();
+    executor
+        .spawn(async move {
+            while let Ok(msg) = queued.recv().await {
+                if writer.send(Message::Text(msg)).await.is_err() {
+                    break;
+                }
+            }
+        })
+        .detach();
+
+    // Dropped when the connection closes, which ends its subscriptions
+    let (_open, closed) = async_channel::bounded::<()>(1);
+
+    let mut last_sub_id = 0;
+    while let Some(msg) = reader.next().await {
+        let text = match msg {
+            Ok(Message::Text(text)) => text,
+            Ok(Message::Close(_)) | Err(_) => break,
+            Ok(_) => continue,
+        };
+
+        // Subscriptions can't be made in a batch, whose requests only get
+        // replies
+        if let Ok(Value::Array(batch)) = serde_json::from_str(&text) {
+            let replies = handle_batch(batch, rh.as_ref(), executor.clone()).await;
+            if sender.send(replies.to_string()).await.is_err() {
+                break;
+            }
+            continue;
+        }
+
+        let reply = match serde_json::from_str::<JsonRequest>(&text) {
+            Ok(req) if rh.is_subscription(req.method.as_str().unwrap_or("")) => {
+                last_sub_id += 1;
+                let pusher =
+                    Pusher::new(req.method.clone(), last_sub_id, sender.clone(), closed.clone());
+                let id = req.id.clone();
+                match rh.subscribe(req, pusher, executor.clone()).await {
+                    Ok(()) => JsonResult::Resp(jsonresp(json!(last_sub_id), id)),
+                    Err(e) => JsonResult::Err(e),
+                }
+            }
+            Ok(req) => rh.handle_request(req, executor.clone()).await,
+            Err(e) => {
+                debug!(target: "WS SERVER", "Received invalid JSON: {}", e);
+                JsonResult::Err(jsonerr(ParseError, None, Value::Null))
+            }
+        };
+
+        let j = serde_json::to_string(&reply).unwrap();
+        debug!(target: "RPC", "<-- {}", j);
+        if sender.send(j).await.is_err() {
+            break;
+        }
+    }
+
+    debug!(target: "WS SERVER", "Closed connection");
+    Ok(())
+}
+
+pub async fn listen_and_serve_ws(
+    socket_addr: SocketAddr,
+    allowed_origins: Vec<String>,
+    rh: Arc<impl SubscriptionHandler + 'static>,
+    executor: Arc<Executor<'_>>,
+) -> Result<()> {
+    let listener = Async::<TcpListener>::bind(socket_addr)?;
+    info!("Serving JSON-RPC on ws://{}", socket_addr);
+
+    let allowed_origins = Arc::new(allowed_origins);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let (allowed_origins, rh, ex) = (allowed_origins.clone(), rh.clone(), executor.clone());
+        executor
+            .spawn(async move {
+                if let Err(e) = serve(stream, allowed_origins, rh, ex).await {
+                    debug!(target: "WS SERVER", "Connection error: {}", e);
+                }
+            })
+            .detach();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_origin() {
+        let allowed = vec!["https://wallet.example".to_string()];
+        let handshake = |origin: Option<&str>| {
+            let mut req = Request::builder().uri("/");
+            if let Some(origin) = origin {
+                req = req.header("Origin", origin);
+            }
+            check_origin(&allowed, &req.body(()).unwrap(), Response::new(()))
+        };
+
+        assert!(handshake(None).is_ok());
+        assert!(handshake(Some("https://wallet.example")).is_ok());
+        let refused = handshake(Some("https://evil.example")).unwrap_err();
+        assert_eq!(refused.status(), StatusCode::FORBIDDEN);
+    }
+}
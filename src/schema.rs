@@ -0,0 +1,461 @@
+//! Machine readable description of the wire encoding of the types other
+//! implementations exchange with us: slabs, the gateway protocol and
+//! transactions. Each type is listed field by field in the order its
+//! Encodable implementation writes them.
+//!
+//! The description is checked against the serial code itself: sample
+//! values are laid out from the schema and have to decode and encode
+//! back to the same bytes, and the walker has to accept what the encoders
+//! produce. Those samples are the golden vectors darkfi-conformance hands
+//! out to other implementations.
+
+use std::io::Cursor;
+
+use bls12_381::{G1Affine, G2Affine};
+use serde_json::{json, Value};
+
+use crate::blockchain::Slab;
+use crate::crypto::{
+    note::{
+        EncryptedNote, EncryptedOutgoingNote, ENC_CIPHERTEXT_SIZE, NOTE_ENCRYPTION_VERSION,
+        OUT_CIPHERTEXT_SIZE,
+    },
+    schnorr, MintRevealedValues, SpendRevealedValues,
+};
+use crate::serial::{deserialize, serialize, Decodable, Encodable, ReadExt, VarInt};
+use crate::service::{
+    gateway::PublishedSlab,
+    reqrep::{Reply, Request},
+};
+use crate::tx::{Transaction, TransactionClearInput, TransactionInput, TransactionOutput};
+use crate::{Error, Result};
+
+/// Bumped whenever the encoding of any type below changes
+pub const WIRE_SCHEMA_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum WireType {
+    U8,
+    U32,
+    U64,
+    /// A byte which has to hold this version
+    Version(u8),
+    /// A fixed number of raw bytes
+    Bytes(usize),
+    /// VarInt length followed by that many bytes
+    VarBytes,
+    /// Canonical little-endian jubjub scalar, 32 bytes
+    JubjubScalar,
+    /// Compressed jubjub prime order subgroup point, 32 bytes
+    JubjubPoint,
+    /// Canonical little-endian BLS12-381 scalar, 32 bytes
+    BlsScalar,
+    /// Compressed BLS12-381 points A (G1), B (G2) and C (G1), 192 bytes
+    Groth16Proof,
+    /// VarInt count followed by that many values
+    Vec(Box<WireType>),
+    /// Bool byte (0 or 1) followed by the value when it's 1
+    Option(Box<WireType>),
+    /// Another type of the schema
+    Struct(&'static str),
+}
+
+impl WireType {
+    fn vec_of(ty: WireType) -> Self {
+        WireType::Vec(Box::new(ty))
+    }
+
+    fn option_of(ty: WireType) -> Self {
+        WireType::Option(Box::new(ty))
+    }
+
+    fn size(&self) -> Option<usize> {
+        match self {
+            WireType::U8 | WireType::Version(_) => Some(1),
+            WireType::U32 => Some(4),
+            WireType::U64 => Some(8),
+            WireType::Bytes(size) => Some(*size),
+            WireType::JubjubScalar | WireType::JubjubPoint | WireType::BlsScalar => Some(32),
+            WireType::Groth16Proof => Some(48 + 96 + 48),
+            _ => None,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        match self {
+            WireType::U8 => json!({"type": "u8"}),
+            WireType::U32 => json!({"type": "u32"}),
+            WireType::U64 => json!({"type": "u64"}),
+            WireType::Version(version) => json!({"type": "version", "value": version}),
+            WireType::Bytes(size) => json!({"type": "bytes", "size": size}),
+            WireType::VarBytes => json!({"type": "varbytes"}),
+            WireType::JubjubScalar => json!({"type": "jubjub_scalar", "size": 32}),
+            WireType::JubjubPoint => json!({"type": "jubjub_point", "size": 32}),
+            WireType::BlsScalar => json!({"type": "bls12_381_scalar", "size": 32}),
+            WireType::Groth16Proof => json!({"type": "groth16_proof", "size": 192}),
+            WireType::Vec(ty) => json!({"type": "vec", "of": ty.to_json()}),
+            WireType::Option(ty) => json!({"type": "option", "of": ty.to_json()}),
+            WireType::Struct(name) => json!({"type": "struct", "name": name}),
+        }
+    }
+}
+
+pub struct WireStruct {
+    pub name: &'static str,
+    pub fields: Vec<(&'static str, WireType)>,
+}
+
+pub fn wire_structs() -> Vec<WireStruct> {
+    use WireType::{
+        BlsScalar, Bytes, Groth16Proof, JubjubPoint, JubjubScalar, Struct, VarBytes, Version, U32,
+        U64, U8,
+    };
+
+    vec![
+        WireStruct {
+            name: "Slab",
+            fields: vec![("index", U64), ("payload", VarBytes)],
+        },
+        WireStruct {
+            name: "PublishedSlab",
+            fields: vec![("slab", Struct("Slab")), ("timestamp", U64)],
+        },
+        WireStruct {
+            name: "Request",
+            fields: vec![("command", U8), ("id", U32), ("payload", VarBytes)],
+        },
+        WireStruct {
+            name: "Reply",
+            fields: vec![("id", U32), ("error", U32), ("payload", VarBytes)],
+        },
+        WireStruct {
+            name: "Transaction",
+            fields: vec![
+                (
+                    "clear_inputs",
+                    WireType::vec_of(Struct("TransactionClearInput")),
+                ),
+                ("inputs", WireType::vec_of(Struct("TransactionInput"))),
+                ("outputs", WireType::vec_of(Struct("TransactionOutput"))),
+                ("fee", U64),
+            ],
+        },
+        WireStruct {
+            name: "TransactionClearInput",
+            fields: vec![
+                ("value", U64),
+                ("token_id", JubjubScalar),
+                ("valcom_blind", JubjubScalar),
+                ("token_commit_blind", JubjubScalar),
+                ("signature_public", JubjubPoint),
+                ("signature", Struct("Signature")),
+            ],
+        },
+        WireStruct {
+            name: "TransactionInput",
+            fields: vec![
+                ("spend_proof", Groth16Proof),
+                ("revealed", Struct("SpendRevealedValues")),
+                ("signature", Struct("Signature")),
+            ],
+        },
+        WireStruct {
+            name: "TransactionOutput",
+            fields: vec![
+                ("mint_proof", Groth16Proof),
+                ("revealed", Struct("MintRevealedValues")),
+                ("enc_note", Struct("EncryptedNote")),
+                (
+                    "out_note",
+                    WireType::option_of(Struct("EncryptedOutgoingNote")),
+                ),
+            ],
+        },
+        WireStruct {
+            name: "SpendRevealedValues",
+            fields: vec![
+                ("value_commit", JubjubPoint),
+                ("token_commit", JubjubPoint),
+                ("nullifier", Bytes(32)),
+                ("merkle_root", BlsScalar),
+                ("signature_public", JubjubPoint),
+            ],
+        },
+        WireStruct {
+            name: "MintRevealedValues",
+            fields: vec![
+                ("value_commit", JubjubPoint),
+                ("token_commit", JubjubPoint),
+                ("coin", Bytes(32)),
+            ],
+        },
+        WireStruct {
+            name: "EncryptedNote",
+            fields: vec![
+                ("version", Version(NOTE_ENCRYPTION_VERSION)),
+                ("key_commitment", Bytes(32)),
+                ("ciphertext", Bytes(ENC_CIPHERTEXT_SIZE)),
+                ("ephem_public", JubjubPoint),
+            ],
+        },
+        WireStruct {
+            name: "EncryptedOutgoingNote",
+            fields: vec![
+                ("version", Version(NOTE_ENCRYPTION_VERSION)),
+                ("key_commitment", Bytes(32)),
+                ("ciphertext", Bytes(OUT_CIPHERTEXT_SIZE)),
+                ("ephem_public", JubjubPoint),
+            ],
+        },
+        WireStruct {
+            name: "Signature",
+            fields: vec![("commit", JubjubPoint), ("response", JubjubScalar)],
+        },
+    ]
+}
+
+fn find_struct(name: &str) -> Result<WireStruct> {
+    wire_structs()
+        .into_iter()
+        .find(|st| st.name == name)
+        .ok_or(Error::ParseFailed("Unknown wire type"))
+}
+
+pub fn schema_json() -> Value {
+    let types: Vec<Value> = wire_structs()
+        .iter()
+        .map(|st| {
+            let fields: Vec<Value> = st
+                .fields
+                .iter()
+                .map(|(name, ty)| {
+                    let mut field = ty.to_json();
+                    field["name"] = json!(name);
+                    field
+                })
+                .collect();
+            json!({"name": st.name, "fields": fields})
+        })
+        .collect();
+
+    json!({
+        "version": WIRE_SCHEMA_VERSION,
+        "encoding": {
+            "integers": "little-endian",
+            "varint": "1 byte below 0xfd, else 0xfd, 0xfe or 0xff followed by a u16, u32 or u64; the shortest form is required",
+        },
+        "types": types,
+    })
+}
+
+fn walk_type(ty: &WireType, d: &mut Cursor<&[u8]>) -> Result<()> {
+    match ty {
+        WireType::Version(version) => {
+            if u8::decode(&mut *d)? != *version {
+                return Err(Error::ParseFailed("Unsupported version"));
+            }
+        }
+        WireType::VarBytes => {
+            Vec::<u8>::decode(&mut *d)?;
+        }
+        WireType::Vec(ty) => {
+            for _ in 0..VarInt::decode(&mut *d)?.0 {
+                walk_type(ty, d)?;
+            }
+        }
+        WireType::Option(ty) => match u8::decode(&mut *d)? {
+            0 => {}
+            1 => walk_type(ty, d)?,
+            _ => return Err(Error::ParseFailed("Invalid option flag")),
+        },
+        WireType::Struct(name) => {
+            for (_, ty) in find_struct(name)?.fields.iter() {
+                walk_type(ty, d)?;
+            }
+        }
+        // The rest only have a size to check
+        ty => {
+            let mut bytes = vec![0u8; ty.size().unwrap()];
+            d.read_slice(&mut bytes)?;
+        }
+    }
+    Ok(())
+}
+
+/// Check data is laid out as the schema says for the type, without
+/// decoding any of the values
+pub fn walk(name: &str, data: &[u8]) -> Result<()> {
+    let mut cursor = Cursor::new(data);
+    walk_type(&WireType::Struct(find_struct(name)?.name), &mut cursor)?;
+    if cursor.position() as usize != data.len() {
+        return Err(Error::ParseFailed("Trailing bytes after wire type"));
+    }
+    Ok(())
+}
+
+fn roundtrip<T: Encodable + Decodable>(data: &[u8]) -> Result<Vec<u8>> {
+    let value: T = deserialize(data)?;
+    Ok(serialize(&value))
+}
+
+/// Decode data as the type and encode it again
+pub fn reencode(name: &str, data: &[u8]) -> Result<Vec<u8>> {
+    match name {
+        "Slab" => roundtrip::<Slab>(data),
+        "PublishedSlab" => roundtrip::<PublishedSlab>(data),
+        "Request" => roundtrip::<Request>(data),
+        "Reply" => roundtrip::<Reply>(data),
+        "Transaction" => roundtrip::<Transaction>(data),
+        "TransactionClearInput" => roundtrip::<TransactionClearInput>(data),
+        "TransactionInput" => roundtrip::<TransactionInput>(data),
+        "TransactionOutput" => roundtrip::<TransactionOutput>(data),
+        "SpendRevealedValues" => roundtrip::<SpendRevealedValues>(data),
+        "MintRevealedValues" => roundtrip::<MintRevealedValues>(data),
+        "EncryptedNote" => roundtrip::<EncryptedNote>(data),
+        "EncryptedOutgoingNote" => roundtrip::<EncryptedOutgoingNote>(data),
+        "Signature" => roundtrip::<schnorr::Signature>(data),
+        _ => Err(Error::ParseFailed("Unknown wire type")),
+    }
+}
+
+/// Check data is a canonical encoding of the type: the schema accepts it,
+/// it decodes, and encodes back to the same bytes.
+pub fn check(name: &str, data: &[u8]) -> Result<()> {
+    walk(name, data)?;
+    if reencode(name, data)? != data {
+        return Err(Error::ParseFailed("Not the canonical encoding"));
+    }
+    Ok(())
+}
+
+// Lays out sample values following the schema. Every value comes from a
+// counter so the samples are the same on every run.
+struct Sampler {
+    counter: u64,
+}
+
+impl Sampler {
+    fn next(&mut self) -> u64 {
+        self.counter += 1;
+        self.counter
+    }
+
+    fn sample(&mut self, ty: &WireType, out: &mut Vec<u8>) -> Result<()> {
+        match ty {
+            WireType::U8 => {
+                (self.next() as u8).encode(&mut *out)?;
+            }
+            WireType::U32 => {
+                (self.next() as u32).encode(&mut *out)?;
+            }
+            WireType::U64 => {
+                self.next().encode(&mut *out)?;
+            }
+            WireType::Version(version) => {
+                version.encode(&mut *out)?;
+            }
+            WireType::Bytes(size) => out.extend(vec![self.next() as u8; *size]),
+            WireType::VarBytes => {
+                vec![self.next() as u8; 3].encode(&mut *out)?;
+            }
+            WireType::JubjubScalar => {
+                jubjub::Fr::from(self.next()).encode(&mut *out)?;
+            }
+            WireType::JubjubPoint => {
+                let point = zcash_primitives::constants::SPENDING_KEY_GENERATOR
+                    * jubjub::Fr::from(self.next());
+                point.encode(&mut *out)?;
+            }
+            WireType::BlsScalar => {
+                bls12_381::Scalar::from(self.next()).encode(&mut *out)?;
+            }
+            WireType::Groth16Proof => {
+                let scalar = bls12_381::Scalar::from(self.next());
+                let a = G1Affine::from(G1Affine::generator() * scalar);
+                let b = G2Affine::from(G2Affine::generator() * scalar);
+                out.extend_from_slice(&a.to_compressed());
+                out.extend_from_slice(&b.to_compressed());
+                out.extend_from_slice(&a.to_compressed());
+            }
+            // Two values, so a test of the count can't pass by accident
+            WireType::Vec(ty) => {
+                VarInt(2).encode(&mut *out)?;
+                self.sample(ty, out)?;
+                self.sample(ty, out)?;
+            }
+            WireType::Option(ty) => {
+                true.encode(&mut *out)?;
+                self.sample(ty, out)?;
+            }
+            WireType::Struct(name) => {
+                for (_, ty) in find_struct(name)?.fields.iter() {
+                    self.sample(ty, out)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A sample encoding of every type of the schema, which any conforming
+/// implementation decodes and encodes back to the same bytes
+pub fn golden_vectors() -> Result<Vec<(&'static str, Vec<u8>)>> {
+    let mut vectors = vec![];
+    for st in wire_structs() {
+        let mut data = vec![];
+        Sampler { counter: 0 }.sample(&WireType::Struct(st.name), &mut data)?;
+        vectors.push((st.name, data));
+    }
+    Ok(vectors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_golden_vectors() -> Result<()> {
+        let vectors = golden_vectors()?;
+        assert_eq!(vectors.len(), wire_structs().len());
+
+        for (name, data) in &vectors {
+            assert!(
+                check(name, data).is_ok(),
+                "{} doesn't match its schema",
+                name
+            );
+        }
+
+        // Pinned down by hand, so a change to the primitives shows up here
+        // even if the schema changes along with it
+        let slab = &vectors.iter().find(|(name, _)| *name == "Slab").unwrap().1;
+        assert_eq!(hex::encode(slab), "010000000000000003020202");
+        let request = &vectors
+            .iter()
+            .find(|(name, _)| *name == "Request")
+            .unwrap()
+            .1;
+        assert_eq!(hex::encode(request), "010200000003030303");
+
+        let mut slab = Slab::new(vec![1, 2, 3]);
+        slab.set_index(7);
+        assert_eq!(hex::encode(serialize(&slab)), "070000000000000003010203");
+        assert!(check("Slab", &serialize(&slab)).is_ok());
+
+        // Non canonical encodings are rejected
+        let mut trailing = serialize(&slab);
+        trailing.push(0);
+        assert!(check("Slab", &trailing).is_err());
+        let non_minimal = hex::decode("0700000000000000fd0300010203").unwrap();
+        assert!(check("Slab", &non_minimal).is_err());
+
+        let schema = schema_json();
+        assert_eq!(schema["version"], json!(WIRE_SCHEMA_VERSION));
+        assert_eq!(
+            schema["types"][0]["fields"][1],
+            json!({"name": "payload", "type": "varbytes"})
+        );
+
+        Ok(())
+    }
+}
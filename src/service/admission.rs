@@ -0,0 +1,142 @@
+//! The order in which a gateway stores the slabs submitted to it. Under
+//! load the slabs paying the highest fee rate go first, but a slab that
+//! has waited long enough goes ahead of any fee, so a low fee only
+//! delays a slab rather than starving it. The fee rate is a cleartext
+//! hint from the submitter, fee units per kilobyte of slab.
+
+use std::collections::VecDeque;
+
+/// Slabs waiting to be stored at most. Further ones are refused.
+pub const MAX_PENDING_SLABS: usize = 1000;
+
+/// Milliseconds after which a waiting slab goes first whatever its fee
+pub const MAX_ADMISSION_WAIT_MILLIS: u64 = 30_000;
+
+/// Number of recently stored slabs the going rate is taken over
+pub const GOING_RATE_WINDOW: usize = 100;
+
+/// Fee rate, in fee units per kilobyte, of a slab of `size` bytes
+pub fn fee_rate(fee: u64, size: usize) -> u64 {
+    fee.saturating_mul(1024) / (size.max(1) as u64)
+}
+
+struct Pending<T> {
+    fee_rate: u64,
+    arrived: u64,
+    item: T,
+}
+
+pub struct AdmissionQueue<T> {
+    // In order of arrival
+    pending: VecDeque<Pending<T>>,
+    // Fee rates of the recently stored slabs
+    recent: VecDeque<u64>,
+}
+
+impl<T> AdmissionQueue<T> {
+    pub fn new() -> Self {
+        Self {
+            pending: VecDeque::new(),
+            recent: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Queue an item arriving at `now` in milliseconds. Gives it back if
+    /// the queue is full.
+    pub fn push(&mut self, fee_rate: u64, now: u64, item: T) -> std::result::Result<(), T> {
+        if self.pending.len() >= MAX_PENDING_SLABS {
+            return Err(item);
+        }
+        self.pending.push_back(Pending {
+            fee_rate,
+            arrived: now,
+            item,
+        });
+        Ok(())
+    }
+
+    /// The next item to store: the oldest if it has waited too long,
+    /// otherwise the one with the highest fee rate, the earlier one of
+    /// equal rates.
+    pub fn pop(&mut self, now: u64) -> Option<T> {
+        let oldest = self.pending.front()?;
+        let next = if now.saturating_sub(oldest.arrived) >= MAX_ADMISSION_WAIT_MILLIS {
+            0
+        } else {
+            let mut next = 0;
+            for (i, pending) in self.pending.iter().enumerate() {
+                if pending.fee_rate > self.pending[next].fee_rate {
+                    next = i;
+                }
+            }
+            next
+        };
+
+        let pending = self.pending.remove(next)?;
+        self.recent.push_back(pending.fee_rate);
+        if self.recent.len() > GOING_RATE_WINDOW {
+            self.recent.pop_front();
+        }
+        Some(pending.item)
+    }
+
+    /// The median fee rate of the recently stored slabs, for clients
+    /// estimating the fee to pay
+    pub fn going_rate(&self) -> u64 {
+        if self.recent.is_empty() {
+            return 0;
+        }
+        let mut rates: Vec<u64> = self.recent.iter().copied().collect();
+        rates.sort_unstable();
+        rates[rates.len() / 2]
+    }
+}
+
+impl<T> Default for AdmissionQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admission_order() {
+        let mut queue = AdmissionQueue::new();
+        assert_eq!(queue.going_rate(), 0);
+
+        queue.push(10, 0, "low").unwrap();
+        queue.push(50, 1, "high").unwrap();
+        queue.push(50, 2, "high later").unwrap();
+        queue.push(20, 3, "mid").unwrap();
+
+        assert_eq!(queue.pop(10), Some("high"));
+        assert_eq!(queue.pop(10), Some("high later"));
+        assert_eq!(queue.going_rate(), 50);
+
+        // The low fee slab has waited too long to be passed over again
+        assert_eq!(queue.pop(MAX_ADMISSION_WAIT_MILLIS), Some("low"));
+        assert_eq!(queue.pop(MAX_ADMISSION_WAIT_MILLIS), Some("mid"));
+        assert_eq!(queue.pop(MAX_ADMISSION_WAIT_MILLIS), None);
+        assert_eq!(queue.going_rate(), 50);
+
+        for i in 0..MAX_PENDING_SLABS {
+            queue.push(0, 0, "filler").unwrap();
+            assert_eq!(queue.len(), i + 1);
+        }
+        assert_eq!(queue.push(100, 0, "refused"), Err("refused"));
+
+        assert_eq!(fee_rate(10, 2048), 5);
+        assert_eq!(fee_rate(10, 0), 10240);
+    }
+}
@@ -0,0 +1,375 @@
+use async_std::sync::{Arc, Mutex};
+use std::io;
+use std::net::TcpStream;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_executor::Executor;
+use async_rustls::client::TlsStream;
+use futures::FutureExt;
+use log::{debug, warn};
+use serde_json::{json, Value};
+use smol::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
+    Async,
+};
+use url::Url;
+
+use super::in_flight::{InFlight, Waiter};
+use super::socks5::{self, Socks5Proxy};
+use super::tls::TlsClientConfig;
+use crate::net::utility::sleep;
+use crate::rpc::jsonrpc::{self, JsonRequest, JsonResult};
+use crate::{Error, Result};
+
+/// Seconds between health pings on an idle cashier connection
+pub const KEEPALIVE_INTERVAL: u32 = 30;
+
+/// Seconds a cashier may take to answer a request, unless configured
+/// otherwise
+pub const CASHIER_TIMEOUT: u32 = 30;
+
+enum CashierStream {
+    Tcp(Async<TcpStream>),
+    Tls(TlsStream<Async<TcpStream>>),
+}
+
+impl AsyncRead for CashierStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            CashierStream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            CashierStream::Tls(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for CashierStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            CashierStream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            CashierStream::Tls(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            CashierStream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            CashierStream::Tls(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            CashierStream::Tcp(s) => Pin::new(s).poll_close(cx),
+            CashierStream::Tls(s) => Pin::new(s).poll_close(cx),
+        }
+    }
+}
+
+// The reading half of a connection, with the bytes read past the last
+// whole reply
+struct ReplyReader {
+    stream: ReadHalf<CashierStream>,
+    buf: Vec<u8>,
+}
+
+impl ReplyReader {
+    async fn next_reply(&mut self) -> Result<JsonResult> {
+        loop {
+            let mut replies =
+                serde_json::Deserializer::from_slice(&self.buf).into_iter::<JsonResult>();
+            match replies.next() {
+                Some(Ok(reply)) => {
+                    let read = replies.byte_offset();
+                    self.buf.drain(..read);
+                    return Ok(reply);
+                }
+                Some(Err(e)) if !e.is_eof() => return Err(e.into()),
+                _ => {}
+            }
+
+            let mut chunk = [0; 2048];
+            let n = self.stream.read(&mut chunk[..]).await?;
+            if n == 0 {
+                return Err(Error::ConnectFailed);
+            }
+            self.buf.extend_from_slice(&chunk[0..n]);
+        }
+    }
+}
+
+struct Connection {
+    writer: Mutex<WriteHalf<CashierStream>>,
+    reader: Mutex<ReplyReader>,
+}
+
+// Replies are matched to requests by the id's JSON text
+fn reply_id(reply: &JsonResult) -> String {
+    match reply {
+        JsonResult::Resp(r) => r.id.to_string(),
+        JsonResult::Err(e) => e.id.to_string(),
+        JsonResult::Notif(_) => Value::Null.to_string(),
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CashierHealth {
+    /// Whether a connection to the cashier is currently open
+    pub connected: bool,
+    /// Unix timestamp of the last successful reply
+    pub last_seen: Option<u64>,
+    /// Number of consecutive failed requests
+    pub failures: u64,
+}
+
+impl CashierHealth {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "connected": self.connected,
+            "last_seen": self.last_seen,
+            "failures": self.failures,
+        })
+    }
+}
+
+/// A persistent JSON-RPC connection to a cashier. Requests from all callers
+/// share the same connection, any number of them in flight at once, and are
+/// matched to their replies by id. A broken connection is dropped and
+/// re-established on the next request.
+pub struct CashierClient {
+    url: Url,
+    tls: TlsClientConfig,
+    proxy: Option<Socks5Proxy>,
+    connection: Mutex<Option<Arc<Connection>>>,
+    in_flight: InFlight<String, JsonResult>,
+    timeout: u32,
+    health: Mutex<CashierHealth>,
+}
+
+impl CashierClient {
+    /// Requests fail with Error::RequestTimeout once they went unanswered
+    /// for `timeout` seconds
+    pub fn new(
+        url: &str,
+        tls: TlsClientConfig,
+        proxy: Option<Socks5Proxy>,
+        timeout: u32,
+    ) -> Result<Arc<Self>> {
+        let url = Url::parse(url)?;
+
+        match url.scheme() {
+            "tcp" | "tls" => {}
+            _ => return Err(Error::UrlParseError),
+        }
+
+        Ok(Arc::new(Self {
+            url,
+            tls,
+            proxy,
+            connection: Mutex::new(None),
+            in_flight: InFlight::new(),
+            timeout,
+            health: Mutex::new(CashierHealth::default()),
+        }))
+    }
+
+    async fn connect(&self) -> Result<CashierStream> {
+        debug!(target: "CASHIER CLIENT", "Connecting to {}", self.url);
+
+        let host = self.url.host_str().ok_or(Error::UrlParseError)?.to_string();
+        let port = self.url.port().ok_or(Error::UrlParseError)?;
+        let stream = socks5::connect(self.proxy.as_ref(), &host, port).await?;
+
+        match self.url.scheme() {
+            "tls" => {
+                let stream = self.tls.connect(&host, stream).await?;
+                Ok(CashierStream::Tls(stream))
+            }
+            _ => Ok(CashierStream::Tcp(stream)),
+        }
+    }
+
+    // The open connection, or a new one
+    async fn connection(&self) -> Result<Arc<Connection>> {
+        let mut connection = self.connection.lock().await;
+        if let Some(connection) = connection.as_ref() {
+            return Ok(connection.clone());
+        }
+
+        let (reader, writer) = smol::io::split(self.connect().await?);
+        let opened = Arc::new(Connection {
+            writer: Mutex::new(writer),
+            reader: Mutex::new(ReplyReader {
+                stream: reader,
+                buf: vec![],
+            }),
+        });
+        *connection = Some(opened.clone());
+        Ok(opened)
+    }
+
+    // Requests still waiting on a dropped connection see it break, and
+    // later requests get a new one
+    async fn drop_connection(&self, dropped: &Arc<Connection>) {
+        let mut connection = self.connection.lock().await;
+        if connection
+            .as_ref()
+            .map_or(false, |c| Arc::ptr_eq(c, dropped))
+        {
+            *connection = None;
+        }
+    }
+
+    async fn send(&self, connection: &Connection, data: &str) -> Result<()> {
+        connection
+            .writer
+            .lock()
+            .await
+            .write_all(data.as_bytes())
+            .await?;
+        Ok(())
+    }
+
+    async fn reply(
+        &self,
+        connection: &Connection,
+        req: &JsonRequest,
+        waiter: &Waiter<'_, String, JsonResult>,
+    ) -> Result<JsonResult> {
+        futures::select! {
+            reply = self.wait_for(connection, waiter).fuse() => reply,
+            _ = sleep(self.timeout).fuse() => Err(Error::RequestTimeout(format!(
+                "cashier {} didn't answer {} in {} seconds",
+                self.url, req.method, self.timeout
+            ))),
+        }
+    }
+
+    // Until its reply comes in, a request takes its turn reading the
+    // connection whenever no other request does, and passes the replies
+    // to other requests on.
+    async fn wait_for(
+        &self,
+        connection: &Connection,
+        waiter: &Waiter<'_, String, JsonResult>,
+    ) -> Result<JsonResult> {
+        loop {
+            let mut reader = futures::select! {
+                reply = waiter.recv().fuse() => return reply,
+                reader = connection.reader.lock().fuse() => reader,
+            };
+            // The reply may have come in before the connection was free
+            if let Some(reply) = waiter.try_take() {
+                return Ok(reply);
+            }
+
+            loop {
+                let reply = reader.next_reply().await?;
+                let id = reply_id(&reply);
+                if id == *waiter.id() {
+                    return Ok(reply);
+                }
+                // A stale reply to a request that timed out earlier
+                if !self.in_flight.deliver(&id, reply) {
+                    warn!(target: "CASHIER CLIENT", "Dropping reply with unexpected id {}", id);
+                }
+            }
+        }
+    }
+
+    /// Send a request over the shared connection, reconnecting once if it
+    /// couldn't be written. A request that was written isn't sent again, as
+    /// the cashier may have acted on it already. If the cashier doesn't
+    /// answer in time it fails with Error::RequestTimeout, and if the
+    /// connection breaks before the reply it fails with the connection's
+    /// error.
+    pub async fn request(&self, req: JsonRequest) -> Result<JsonResult> {
+        let data = serde_json::to_string(&req)?;
+        for _ in 0..2 {
+            let connection = match self.connection().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    self.record_failure().await;
+                    return Err(e);
+                }
+            };
+
+            let waiter = self.in_flight.register(req.id.to_string());
+            if let Err(e) = self.send(&connection, &data).await {
+                debug!(target: "CASHIER CLIENT", "Connection to {} broken: {}", self.url, e);
+                self.drop_connection(&connection).await;
+                continue;
+            }
+
+            match self.reply(&connection, &req, &waiter).await {
+                Ok(reply) => {
+                    self.record_success().await;
+                    return Ok(reply);
+                }
+                Err(e) => {
+                    // The connection may be broken or half open, so the
+                    // next request gets a new one
+                    self.drop_connection(&connection).await;
+                    self.record_failure().await;
+                    return Err(e);
+                }
+            }
+        }
+
+        self.record_failure().await;
+        Err(Error::ConnectFailed)
+    }
+
+    async fn record_success(&self) {
+        let mut health = self.health.lock().await;
+        health.connected = true;
+        health.failures = 0;
+        health.last_seen = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|d| d.as_secs());
+    }
+
+    async fn record_failure(&self) {
+        let mut health = self.health.lock().await;
+        health.connected = false;
+        health.failures += 1;
+    }
+
+    pub async fn health(&self) -> CashierHealth {
+        self.health.lock().await.clone()
+    }
+
+    pub async fn ping(&self) -> Result<()> {
+        let req = jsonrpc::request(json!("ping"), json!([]));
+        match self.request(req).await? {
+            JsonResult::Resp(_) => Ok(()),
+            JsonResult::Err(e) => Err(Error::JsonRpcError(e.error.message.to_string())),
+            JsonResult::Notif(_) => Err(Error::JsonRpcError("Unexpected reply".to_string())),
+        }
+    }
+
+    /// Periodically ping the cashier so the connection is kept open and its
+    /// health stays current.
+    pub fn start_keepalive(self: Arc<Self>, executor: Arc<Executor<'_>>) {
+        executor
+            .spawn(async move {
+                loop {
+                    sleep(KEEPALIVE_INTERVAL).await;
+                    if let Err(e) = self.ping().await {
+                        warn!(target: "CASHIER CLIENT", "Ping to {} failed: {}", self.url, e);
+                    }
+                }
+            })
+            .detach();
+    }
+}
@@ -0,0 +1,105 @@
+//! Compression of the slabs a gateway publishes. Transactions with many
+//! inputs and outputs carry a lot of proof bytes, and every subscriber
+//! gets a copy of each. Slabs below the threshold are sent as they are,
+//! since compressing them saves too little to be worth it.
+
+use super::wire::MAX_FRAME_SIZE;
+use crate::{Error, Result};
+
+/// Payloads smaller than this are never compressed
+pub const COMPRESSION_THRESHOLD: usize = 16 * 1024;
+
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Zstd,
+}
+
+/// The compression this build can read, announced in the handshake
+pub const SUPPORTED_COMPRESSION: [Compression; 2] = [Compression::None, Compression::Zstd];
+
+impl Compression {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd => 1,
+        }
+    }
+
+    pub fn from_u8(compression: u8) -> Option<Self> {
+        match compression {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Zstd => "zstd",
+        }
+    }
+}
+
+/// Compress the payload with `compression` if it is large enough and
+/// the compressed payload is smaller. Returns how it ended up encoded.
+pub fn compress(compression: Compression, payload: &[u8]) -> Result<(Compression, Vec<u8>)> {
+    if compression == Compression::None || payload.len() < COMPRESSION_THRESHOLD {
+        return Ok((Compression::None, payload.to_vec()));
+    }
+
+    let compressed = zstd::bulk::compress(payload, ZSTD_LEVEL)?;
+    if compressed.len() >= payload.len() {
+        return Ok((Compression::None, payload.to_vec()));
+    }
+    Ok((Compression::Zstd, compressed))
+}
+
+/// Undo `compress`. Payloads are held to the frame limit, so a small
+/// malicious payload can't expand without bound.
+pub fn decompress(compression: Compression, payload: &[u8]) -> Result<Vec<u8>> {
+    match compression {
+        Compression::None => Ok(payload.to_vec()),
+        Compression::Zstd => zstd::bulk::decompress(payload, MAX_FRAME_SIZE)
+            .map_err(|e| Error::BadFrame(format!("Can't decompress payload: {}", e))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compression() -> Result<()> {
+        // Repetitive, like the padding in a large transaction
+        let large: Vec<u8> = (0..COMPRESSION_THRESHOLD * 4)
+            .map(|i| (i % 7) as u8)
+            .collect();
+        let (compression, compressed) = compress(Compression::Zstd, &large)?;
+        assert_eq!(compression, Compression::Zstd);
+        assert!(compressed.len() < large.len());
+        assert_eq!(decompress(compression, &compressed)?, large);
+
+        // Small payloads and disabled compression leave the payload as is
+        let small = vec![1u8; 100];
+        assert_eq!(
+            compress(Compression::Zstd, &small)?,
+            (Compression::None, small.clone())
+        );
+        assert_eq!(compress(Compression::None, &large)?.0, Compression::None);
+
+        assert!(decompress(Compression::Zstd, &[1, 2, 3]).is_err());
+        for compression in SUPPORTED_COMPRESSION.iter() {
+            assert_eq!(
+                Compression::from_u8(compression.to_u8()),
+                Some(*compression)
+            );
+        }
+        assert_eq!(Compression::from_u8(2), None);
+
+        Ok(())
+    }
+}
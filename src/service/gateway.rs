@@ -1,24 +1,701 @@
 use std::net::ToSocketAddrs;
 
-use async_std::sync::Arc;
+use async_std::sync::{Arc, Mutex};
+use std::collections::VecDeque;
 use std::convert::From;
+use std::future::Future;
+use std::io;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 use async_executor::Executor;
-use log::debug;
+use futures::FutureExt;
+use log::{debug, warn};
+use serde_json::{json, Value};
 use url::Url;
 
+use super::admission::{fee_rate, AdmissionQueue};
+use super::compression::{compress, decompress, Compression, SUPPORTED_COMPRESSION};
+use super::rate_limit::{RateLimit, RateLimiter, Verdict};
+use super::replication::Replication;
 use super::reqrep::{PeerId, Publisher, RepProtocol, Reply, ReqProtocol, Request, Subscriber};
-use crate::blockchain::{rocks::columns, RocksColumn, Slab, SlabStore};
-use crate::{serial::deserialize, serial::serialize, Error, Result};
+use super::socks5::Socks5Proxy;
+use super::tls::{gateway_endpoint, TlsClientConfig};
+use super::wire::MAX_FRAME_SIZE;
+use crate::blockchain::{kv::columns, KvColumn, Slab, SlabHash, SlabStore};
+use crate::crypto::schnorr;
+use crate::impl_vec;
+use crate::net::utility::sleep;
+use crate::serial::{deserialize, deserialize_partial, serialize, Decodable, Encodable, VarInt};
+use crate::tx::Transaction;
+use crate::{Error, Result};
 
 pub type GatewaySlabsSubscriber = async_channel::Receiver<Slab>;
 
+/// Seconds to wait before re-establishing a dropped subscription
+pub const RESUBSCRIBE_DELAY: u32 = 5;
+
+/// Seconds a gateway may take to connect or answer a request before the
+/// next one is tried, unless configured otherwise
+pub const GATEWAY_TIMEOUT: u32 = 10;
+
+/// Number of recent slabs the subscription latency is measured over
+pub const LATENCY_WINDOW: usize = 100;
+
+/// Average latency in milliseconds above which the gateway is degraded,
+/// and above which it is poor
+pub const DEGRADED_LATENCY_MILLIS: u64 = 2000;
+pub const POOR_LATENCY_MILLIS: u64 = 10000;
+
+/// Slabs checked to be in their shard in one go, and seconds to wait
+/// before checking again once all are
+pub const SHARD_MIGRATION_BATCH: usize = 1000;
+pub const SHARD_MIGRATION_INTERVAL: u32 = 60;
+
+/// Seconds a gateway connection may sit idle before it's pinged
+pub const HEARTBEAT_INTERVAL: u32 = 30;
+
+/// Seconds a ping may go unanswered before the connection is dropped
+pub const HEARTBEAT_TIMEOUT: u32 = 10;
+
+/// Version of the protocol between gateway clients and gatewayd. Builds
+/// with different major versions can't talk to each other; 2.0 framed
+/// the messages and 3.0 compressed the published slabs.
+pub const GATEWAY_PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion { major: 3, minor: 5 };
+
+/// Seconds a signed submission stays valid after it was signed, which
+/// bounds how long a captured one can be replayed
+pub const SIGNED_SUBMISSION_WINDOW: u64 = 60;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProtocolVersion {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl ProtocolVersion {
+    pub fn is_compatible(&self, other: &ProtocolVersion) -> bool {
+        self.major == other.major
+    }
+
+    /// Whether a gateway speaking this version answers resume requests
+    pub fn supports_resume(&self) -> bool {
+        self.major > 1 || (self.major == 1 && self.minor >= 1)
+    }
+
+    /// Whether a gateway speaking this version takes fee rate hints and
+    /// reports the going rate
+    pub fn supports_fee_hints(&self) -> bool {
+        self.major > 3 || (self.major == 3 && self.minor >= 1)
+    }
+
+    /// Whether a gateway speaking this version answers pings
+    pub fn supports_ping(&self) -> bool {
+        self.major > 3 || (self.major == 3 && self.minor >= 2)
+    }
+
+    /// Whether a gateway speaking this version sends ranges of slabs
+    pub fn supports_slab_ranges(&self) -> bool {
+        self.major > 3 || (self.major == 3 && self.minor >= 3)
+    }
+
+    /// Whether a gateway speaking this version takes signed submissions
+    pub fn supports_signed_submissions(&self) -> bool {
+        self.major > 3 || (self.major == 3 && self.minor >= 4)
+    }
+
+    /// Whether a gateway speaking this version sends the signatures of
+    /// the slabs it signs
+    pub fn supports_slab_signatures(&self) -> bool {
+        self.major > 3 || (self.major == 3 && self.minor >= 5)
+    }
+}
+
+/// How idle gateway connections are checked. NATs drop connections that
+/// stay idle for long without telling either side, so a connection quiet
+/// for `interval` seconds is pinged, and re-established if the gateway
+/// doesn't answer within `timeout` seconds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Heartbeat {
+    pub interval: u32,
+    pub timeout: u32,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            interval: HEARTBEAT_INTERVAL,
+            timeout: HEARTBEAT_TIMEOUT,
+        }
+    }
+}
+
+impl std::fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+impl Encodable for ProtocolVersion {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.major.encode(&mut s)?;
+        len += self.minor.encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for ProtocolVersion {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self {
+            major: Decodable::decode(&mut d)?,
+            minor: Decodable::decode(&mut d)?,
+        })
+    }
+}
+
+/// Slabs a gateway sends at most in reply to one resume request
+pub const MAX_RESUME_SLABS: u64 = 100;
+
+/// Slabs a gateway sends at most in reply to one request for a range, so
+/// a client syncing the full history fetches it page by page
+pub const MAX_SLAB_RANGE: u64 = 500;
+
+/// Bytes of slabs a gateway sends at most in reply to one request for a
+/// range. Full pages of the largest slabs would overflow a frame, so a
+/// page ends early once it holds this much, but always has a slab.
+pub const MAX_SLAB_RANGE_BYTES: usize = MAX_FRAME_SIZE / 2;
+
+/// The inclusive range of slab indexes `from..=to` a client asks for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SlabRange {
+    pub from: u64,
+    pub to: u64,
+}
+
+impl SlabRange {
+    /// The last index of the range that is sent in one reply, given the
+    /// gateway's last index. Below `from` if there's nothing to send.
+    pub fn page_end(&self, last_index: u64) -> u64 {
+        self.to
+            .min(last_index)
+            .min(self.from.saturating_add(MAX_SLAB_RANGE - 1))
+    }
+}
+
+impl Encodable for SlabRange {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.from.encode(&mut s)?;
+        len += self.to.encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for SlabRange {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self {
+            from: Decodable::decode(&mut d)?,
+            to: Decodable::decode(&mut d)?,
+        })
+    }
+}
+
+// Answer a request for a range of slabs from what the slabstore has. The
+// slabs are consecutive, starting at `from`, and stop short at a missing
+// one or once they add up to `max_bytes`.
+fn slab_range(slabstore: &SlabStore, range: SlabRange, max_bytes: usize) -> Result<Vec<Slab>> {
+    let until = range.page_end(slabstore.get_last_index()?);
+
+    let mut slabs = vec![];
+    let mut bytes = 0;
+    for index in range.from.max(1)..=until {
+        let slab = match slabstore.get_value_deserialized(serialize(&index))? {
+            Some(slab) => slab,
+            None => break,
+        };
+        bytes += serialize(&slab).len();
+        if bytes > max_bytes && !slabs.is_empty() {
+            break;
+        }
+        slabs.push(slab);
+    }
+    Ok(slabs)
+}
+
+/// Lets a client whose subscription dropped pick up where it left off
+/// with a single request. Slab indexes are only known to line up within
+/// the history of one gateway run, so the token names the session it was
+/// issued in along with the client's cursor, the last slab it has. The
+/// subscription carries every slab, so there are no filters to restore.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ResumeToken {
+    pub session: u64,
+    pub cursor: u64,
+}
+
+impl Encodable for ResumeToken {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.session.encode(&mut s)?;
+        len += self.cursor.encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for ResumeToken {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self {
+            session: Decodable::decode(&mut d)?,
+            cursor: Decodable::decode(&mut d)?,
+        })
+    }
+}
+
+pub struct ResumeReply {
+    /// Whether the token was issued in this session. If not, the slabs
+    /// are left out and the client must check for gaps itself.
+    pub resumed: bool,
+    /// Token to resume with next time
+    pub token: ResumeToken,
+    /// Slabs following the cursor, at most MAX_RESUME_SLABS of them
+    pub slabs: Vec<Slab>,
+}
+
+impl_vec!(Slab);
+
+impl Encodable for ResumeReply {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.resumed.encode(&mut s)?;
+        len += self.token.encode(&mut s)?;
+        len += self.slabs.encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for ResumeReply {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self {
+            resumed: Decodable::decode(&mut d)?,
+            token: Decodable::decode(&mut d)?,
+            slabs: Decodable::decode(&mut d)?,
+        })
+    }
+}
+
+// Answer a resume request from what the slabstore has
+fn resume_reply(slabstore: &SlabStore, session: u64, token: ResumeToken) -> Result<ResumeReply> {
+    if token.session != session {
+        return Ok(ResumeReply {
+            resumed: false,
+            token: ResumeToken {
+                session,
+                cursor: token.cursor,
+            },
+            slabs: vec![],
+        });
+    }
+
+    let last_index = slabstore.get_last_index()?;
+    let until = last_index.min(token.cursor.saturating_add(MAX_RESUME_SLABS));
+
+    let mut slabs = vec![];
+    for index in (token.cursor + 1)..=until {
+        match slabstore.get_value_deserialized(serialize(&index))? {
+            Some(slab) => slabs.push(slab),
+            None => break,
+        }
+    }
+
+    Ok(ResumeReply {
+        resumed: true,
+        token: ResumeToken {
+            session,
+            cursor: token.cursor + slabs.len() as u64,
+        },
+        slabs,
+    })
+}
+
+/// A slab submitted with the fee rate it pays, see `admission`. The
+/// gateway gives it the next index once it is admitted.
+pub struct SlabSubmission {
+    pub slab: Slab,
+    pub fee_rate: u64,
+}
+
+impl Encodable for SlabSubmission {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.slab.encode(&mut s)?;
+        len += self.fee_rate.encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for SlabSubmission {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self {
+            slab: Decodable::decode(&mut d)?,
+            fee_rate: Decodable::decode(&mut d)?,
+        })
+    }
+}
+
+/// A submission signed by a publisher, for gateways that only take slabs
+/// from an allowlist of keys. The signature covers the submission and the
+/// unix time it was signed at.
+pub struct SignedSubmission {
+    pub submission: SlabSubmission,
+    pub timestamp: u64,
+    pub public: jubjub::SubgroupPoint,
+    pub signature: schnorr::Signature,
+}
+
+impl SignedSubmission {
+    pub fn sign(submission: SlabSubmission, secret: &schnorr::SecretKey, timestamp: u64) -> Self {
+        let signature = secret.sign(&Self::message(&submission, timestamp));
+        Self {
+            submission,
+            timestamp,
+            public: secret.public_key().0,
+            signature,
+        }
+    }
+
+    fn message(submission: &SlabSubmission, timestamp: u64) -> Vec<u8> {
+        let mut message = serialize(submission);
+        message.extend(serialize(&timestamp));
+        message
+    }
+
+    /// Whether a key of `publishers` made the signature, no longer than
+    /// SIGNED_SUBMISSION_WINDOW seconds from `now` either way
+    pub fn verify(&self, publishers: &[jubjub::SubgroupPoint], now: u64) -> bool {
+        if now.saturating_sub(self.timestamp) > SIGNED_SUBMISSION_WINDOW
+            || self.timestamp.saturating_sub(now) > SIGNED_SUBMISSION_WINDOW
+            || !publishers.contains(&self.public)
+        {
+            return false;
+        }
+        schnorr::PublicKey(self.public).verify(
+            &Self::message(&self.submission, self.timestamp),
+            &self.signature,
+        )
+    }
+}
+
+impl Encodable for SignedSubmission {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.submission.encode(&mut s)?;
+        len += self.timestamp.encode(&mut s)?;
+        len += self.public.encode(&mut s)?;
+        len += self.signature.encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for SignedSubmission {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self {
+            submission: Decodable::decode(&mut d)?,
+            timestamp: Decodable::decode(&mut d)?,
+            public: Decodable::decode(&mut d)?,
+            signature: Decodable::decode(&mut d)?,
+        })
+    }
+}
+
+/// A gateway's signature on a slab it accepted, covering the index and
+/// the slab hash. Clients holding the gateway key refuse slabs without
+/// one, and two slabs signed for the same index are evidence the gateway
+/// rewrote its history.
+#[derive(Clone)]
+pub struct SlabSignature {
+    pub index: u64,
+    pub public: jubjub::SubgroupPoint,
+    pub signature: schnorr::Signature,
+}
+
+impl SlabSignature {
+    pub fn sign(slab: &Slab, secret: &schnorr::SecretKey) -> Self {
+        let signature = secret.sign(&Self::message(slab.get_index(), &slab.hash()));
+        Self {
+            index: slab.get_index(),
+            public: secret.public_key().0,
+            signature,
+        }
+    }
+
+    fn message(index: u64, hash: &SlabHash) -> Vec<u8> {
+        let mut message = b"DarkFi_SlabSignature".to_vec();
+        message.extend(serialize(&index));
+        message.extend_from_slice(hash);
+        message
+    }
+
+    /// Whether a key of `gateways` signed `slab`
+    pub fn verify(&self, slab: &Slab, gateways: &[jubjub::SubgroupPoint]) -> bool {
+        if self.index != slab.get_index() || !gateways.contains(&self.public) {
+            return false;
+        }
+        schnorr::PublicKey(self.public)
+            .verify(&Self::message(self.index, &slab.hash()), &self.signature)
+    }
+}
+
+impl Encodable for SlabSignature {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.index.encode(&mut s)?;
+        len += self.public.encode(&mut s)?;
+        len += self.signature.encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for SlabSignature {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self {
+            index: Decodable::decode(&mut d)?,
+            public: Decodable::decode(&mut d)?,
+            signature: Decodable::decode(&mut d)?,
+        })
+    }
+}
+
+impl_vec!(SlabSignature);
+
+/// Signs the slabs a gateway accepts and keeps the signatures, so they
+/// are sent along with slabs fetched later
+pub struct SlabSigner {
+    secret: schnorr::SecretKey,
+    signatures: KvColumn<columns::SlabSignatures>,
+}
+
+impl SlabSigner {
+    pub fn new(secret: schnorr::SecretKey, signatures: KvColumn<columns::SlabSignatures>) -> Self {
+        Self { secret, signatures }
+    }
+
+    /// The signature of `slab`. Slabs stored before the gateway had a key
+    /// are signed the first time they're asked for.
+    pub fn sign(&self, slab: &Slab) -> Result<SlabSignature> {
+        let index = slab.get_index();
+        if let Some(signature) = self
+            .signatures
+            .get_value_deserialized::<SlabSignature>(serialize(&index))?
+        {
+            if signature.verify(slab, &[self.secret.public_key().0]) {
+                return Ok(signature);
+            }
+        }
+
+        let signature = SlabSignature::sign(slab, &self.secret);
+        self.signatures.put(index, signature.clone())?;
+        Ok(signature)
+    }
+}
+
+// The signatures of a range of slabs, for a client fetching them rather
+// than following the subscription. Like the slabs, they're consecutive
+// from `from` and stop short at a missing one. A gateway without a key
+// has none.
+fn signature_range(
+    slabstore: &SlabStore,
+    signer: Option<&SlabSigner>,
+    range: SlabRange,
+) -> Result<Vec<SlabSignature>> {
+    match signer {
+        Some(signer) => slab_range(slabstore, range, MAX_SLAB_RANGE_BYTES)?
+            .iter()
+            .map(|slab| signer.sign(slab))
+            .collect(),
+        None => Ok(vec![]),
+    }
+}
+
+/// A slab as published to subscribers, stamped with the unix time in
+/// milliseconds at which the gateway accepted it. Gateways with a key
+/// sign it; the signature isn't part of the encoding but follows it, see
+/// encode_published().
+pub struct PublishedSlab {
+    pub slab: Slab,
+    pub timestamp: u64,
+    pub signature: Option<SlabSignature>,
+}
+
+impl Encodable for PublishedSlab {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.slab.encode(&mut s)?;
+        len += self.timestamp.encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for PublishedSlab {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self {
+            slab: Decodable::decode(&mut d)?,
+            timestamp: Decodable::decode(&mut d)?,
+            signature: None,
+        })
+    }
+}
+
+/// A published slab as it goes out to subscribers: the compression
+/// byte, then the encoded `PublishedSlab` followed by the signature if
+/// there is one, compressed if large enough. Clients that predate slab
+/// signatures can't read signed slabs.
+pub fn encode_published(published: &PublishedSlab, compression: Compression) -> Result<Vec<u8>> {
+    let mut encoded = serialize(published);
+    if let Some(signature) = &published.signature {
+        encoded.extend(serialize(signature));
+    }
+    let (compression, payload) = compress(compression, &encoded)?;
+    let mut data = Vec::with_capacity(1 + payload.len());
+    data.push(compression.to_u8());
+    data.extend(payload);
+    Ok(data)
+}
+
+pub fn decode_published(data: &[u8]) -> Result<PublishedSlab> {
+    let (compression, payload) = match data.split_first() {
+        Some((compression, payload)) => (compression, payload),
+        None => return Err(Error::BadFrame("Empty published message".to_string())),
+    };
+    let compression = Compression::from_u8(*compression)
+        .ok_or_else(|| Error::BadFrame(format!("Unknown compression {}", compression)))?;
+    let payload = decompress(compression, payload)?;
+    let (mut published, read): (PublishedSlab, usize) = deserialize_partial(&payload)?;
+    if read < payload.len() {
+        published.signature = Some(deserialize(&payload[read..])?);
+    }
+    Ok(published)
+}
+
+/// How well the gateway serves the slab subscription
+#[derive(Clone, Debug, Default)]
+pub struct GatewayStats {
+    /// Whether the subscription is currently open
+    pub subscribed: bool,
+    /// Number of slabs received through the subscription
+    pub slabs_received: u64,
+    /// Milliseconds between the gateway accepting and us receiving each of
+    /// the recent slabs. Only as accurate as the two clocks agree.
+    pub latencies: VecDeque<u64>,
+    /// Number of times the subscription skipped slabs
+    pub gaps: u64,
+    /// Number of skipped slabs fetched again from the gateway
+    pub retransmissions: u64,
+    /// Number of times the subscription was re-established
+    pub reconnects: u64,
+    /// Protocol url of the gateway in use
+    pub gateway: String,
+    /// Number of times an unreachable gateway was given up for the next
+    pub failovers: u64,
+    /// Unix timestamp of the last slab received
+    pub last_received: Option<u64>,
+    /// Highest slab index the gateway is known to have
+    pub latest_index: u64,
+    /// Protocol version the gateway in use answered the handshake with
+    pub protocol_version: Option<ProtocolVersion>,
+    /// Compression the gateway in use publishes large slabs with
+    pub slab_compression: Option<Compression>,
+    /// Number of times a connection went silent and was re-established
+    pub heartbeat_failures: u64,
+    /// Number of slabs refused for lacking a valid gateway signature
+    pub bad_signatures: u64,
+    /// Number of validly signed slabs that differ from the one stored at
+    /// their index, i.e. times the gateway was caught rewriting history
+    pub rewrites: u64,
+}
+
+impl GatewayStats {
+    fn record_slab(&mut self, published: u64, received: u64) {
+        self.slabs_received += 1;
+        self.last_received = Some(received / 1000);
+
+        self.latencies.push_back(received.saturating_sub(published));
+        if self.latencies.len() > LATENCY_WINDOW {
+            self.latencies.pop_front();
+        }
+    }
+
+    pub fn avg_latency_millis(&self) -> u64 {
+        if self.latencies.is_empty() {
+            return 0;
+        }
+        self.latencies.iter().sum::<u64>() / self.latencies.len() as u64
+    }
+
+    pub fn max_latency_millis(&self) -> u64 {
+        self.latencies.iter().copied().max().unwrap_or(0)
+    }
+
+    /// Overall health: "down" while unsubscribed, otherwise "good",
+    /// "degraded" or "poor" depending on latency and how often the
+    /// subscription skips slabs.
+    pub fn grade(&self) -> &'static str {
+        if !self.subscribed {
+            return "down";
+        }
+
+        let latency = self.avg_latency_millis();
+        let gap_percent = self.gaps * 100 / self.slabs_received.max(1);
+
+        if latency > POOR_LATENCY_MILLIS || gap_percent >= 10 {
+            "poor"
+        } else if latency > DEGRADED_LATENCY_MILLIS || gap_percent >= 1 {
+            "degraded"
+        } else {
+            "good"
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "grade": self.grade(),
+            "subscribed": self.subscribed,
+            "slabs_received": self.slabs_received,
+            "avg_latency_millis": self.avg_latency_millis(),
+            "max_latency_millis": self.max_latency_millis(),
+            "gaps": self.gaps,
+            "retransmissions": self.retransmissions,
+            "reconnects": self.reconnects,
+            "gateway": self.gateway,
+            "failovers": self.failovers,
+            "last_received": self.last_received,
+            "latest_index": self.latest_index,
+            "protocol_version": self.protocol_version.map(|v| v.to_string()),
+            "slab_compression": self.slab_compression.map(|c| c.as_str()),
+            "heartbeat_failures": self.heartbeat_failures,
+            "bad_signatures": self.bad_signatures,
+            "rewrites": self.rewrites,
+        })
+    }
+}
+
+pub(super) fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
 #[repr(u8)]
 enum GatewayError {
     NoError,
     UpdateIndex,
     IndexNotExist,
+    QueueFull,
+    Unauthorized,
+    RateLimited,
+    InvalidSlab,
 }
 
 #[repr(u8)]
@@ -26,10 +703,108 @@ enum GatewayCommand {
     PutSlab,
     GetSlab,
     GetLastIndex,
+    Hello,
+    Resume,
+    PutSlabWithFee,
+    GetGoingRate,
+    Ping,
+    GetSlabs,
+    PutSlabSigned,
+    GetSlabSignatures,
+}
+
+// A slab waiting for admission, with the request to answer once it's
+// stored
+struct Submission {
+    peer: PeerId,
+    request: Request,
+    slab: Slab,
+    // Whether the gateway picks the index, or keeps the one the client
+    // set as older clients expect
+    assign_index: bool,
+}
+
+// Slabs submitted but not stored yet. The channel carries a signal for
+// each one queued.
+struct Admission {
+    queue: Mutex<AdmissionQueue<Submission>>,
+    ready_s: async_channel::Sender<()>,
+    ready_r: async_channel::Receiver<()>,
+}
+
+impl Admission {
+    fn new() -> Self {
+        let (ready_s, ready_r) = async_channel::unbounded();
+        Self {
+            queue: Mutex::new(AdmissionQueue::new()),
+            ready_s,
+            ready_r,
+        }
+    }
+}
+
+/// Largest slab payload a gateway takes by default
+pub const DEFAULT_MAX_SLAB_SIZE: usize = 256 * 1024;
+
+/// How fast each connection may send requests of any kind and submit
+/// slabs, and how many times within the violation window it may go over
+/// either before it's dropped. Slabs with a larger payload than
+/// `max_slab_size` are refused.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GatewayLimits {
+    pub requests: RateLimit,
+    pub put_slabs: RateLimit,
+    pub max_violations: u32,
+    pub max_slab_size: usize,
+}
+
+impl Default for GatewayLimits {
+    fn default() -> Self {
+        Self {
+            requests: RateLimit::unlimited(),
+            put_slabs: RateLimit::unlimited(),
+            max_violations: u32::MAX,
+            max_slab_size: DEFAULT_MAX_SLAB_SIZE,
+        }
+    }
+}
+
+// Whether a submitted slab may be stored: no larger than the limit, and
+// holding a transaction subscribers can decode. Garbage is refused here
+// rather than stored and published to every subscriber.
+fn validate_slab(slab: &Slab, max_slab_size: usize) -> std::result::Result<(), String> {
+    let payload = slab.get_payload();
+    if payload.len() > max_slab_size {
+        return Err(format!(
+            "slab of {} bytes is over the limit of {}",
+            payload.len(),
+            max_slab_size
+        ));
+    }
+    if let Err(e) = Transaction::decode(&payload[..]) {
+        return Err(format!("slab doesn't hold a transaction: {}", e));
+    }
+    Ok(())
 }
 
 pub struct GatewayService {
     slabstore: Arc<SlabStore>,
+    admission: Arc<Admission>,
+    // The gateways followed, if this one replicates others
+    replication: Option<Arc<Replication>>,
+    // Keys slabs have to be submitted with, anyone may submit if empty
+    publishers: Arc<Vec<jubjub::SubgroupPoint>>,
+    // Signs the slabs accepted, if the gateway has a key
+    signer: Option<Arc<SlabSigner>>,
+    // Requests allowed to each connection, and slab submissions among
+    // them
+    request_limiter: Mutex<RateLimiter<PeerId>>,
+    put_limiter: Mutex<RateLimiter<PeerId>>,
+    max_slab_size: usize,
+    // Identifies this run of the gateway in resume tokens
+    session: u64,
+    // How large published slabs are compressed
+    compression: Compression,
     addr: SocketAddr,
     pub_addr: SocketAddr,
 }
@@ -38,12 +813,30 @@ impl GatewayService {
     pub fn new(
         addr: SocketAddr,
         pub_addr: SocketAddr,
-        rocks: RocksColumn<columns::Slabs>,
+        slabstore: Arc<SlabStore>,
+        compression: Compression,
+        peers: Vec<Url>,
+        publishers: Vec<jubjub::SubgroupPoint>,
+        signer: Option<SlabSigner>,
+        limits: GatewayLimits,
     ) -> Result<Arc<GatewayService>> {
-        let slabstore = SlabStore::new(rocks)?;
+        let replication = if peers.is_empty() {
+            None
+        } else {
+            Some(Replication::new(peers)?)
+        };
 
         Ok(Arc::new(GatewayService {
             slabstore,
+            admission: Arc::new(Admission::new()),
+            replication,
+            publishers: Arc::new(publishers),
+            signer: signer.map(Arc::new),
+            request_limiter: Mutex::new(RateLimiter::new(limits.requests, limits.max_violations)),
+            put_limiter: Mutex::new(RateLimiter::new(limits.put_slabs, limits.max_violations)),
+            max_slab_size: limits.max_slab_size,
+            session: rand::random(),
+            compression,
             addr,
             pub_addr,
         }))
@@ -63,20 +856,54 @@ impl GatewayService {
             publish_recv_queue.clone(),
         ));
 
-        let handle_request_task = executor.spawn(self.handle_request_loop(
+        let admission_task = executor.spawn(Self::admission_loop(
+            self.slabstore.clone(),
+            self.admission.clone(),
+            self.signer.clone(),
+            self.compression,
             send.clone(),
-            recv.clone(),
             publish_queue.clone(),
-            executor.clone(),
         ));
 
+        let migrate_task = executor.spawn(Self::migrate_loop(self.slabstore.clone()));
+
+        let replication_task = self.replication.clone().map(|replication| {
+            executor.spawn(replication.run(
+                self.slabstore.clone(),
+                self.signer.clone(),
+                self.compression,
+                publish_queue.clone(),
+            ))
+        });
+
+        let handle_request_task =
+            executor.spawn(self.handle_request_loop(send.clone(), recv.clone(), executor.clone()));
+
         protocol.run(executor.clone()).await?;
 
         let _ = publisher_task.cancel().await;
+        let _ = admission_task.cancel().await;
+        let _ = migrate_task.cancel().await;
+        if let Some(task) = replication_task {
+            let _ = task.cancel().await;
+        }
         let _ = handle_request_task.cancel().await;
         Ok(())
     }
 
+    // Move slabs stored before their shard was configured into it, a
+    // batch at a time off the executor, checking again for a while once
+    // all are in place
+    async fn migrate_loop(slabstore: Arc<SlabStore>) -> Result<()> {
+        loop {
+            let store = slabstore.clone();
+            let checked = smol::unblock(move || store.migrate(SHARD_MIGRATION_BATCH)).await?;
+            if checked < SHARD_MIGRATION_BATCH {
+                sleep(SHARD_MIGRATION_INTERVAL).await;
+            }
+        }
+    }
+
     async fn start_publisher(
         pub_addr: SocketAddr,
         service_name: String,
@@ -91,52 +918,299 @@ impl GatewayService {
         self: Arc<Self>,
         send_queue: async_channel::Sender<(PeerId, Reply)>,
         recv_queue: async_channel::Receiver<(PeerId, Request)>,
-        publish_queue: async_channel::Sender<Vec<u8>>,
         executor: Arc<Executor<'_>>,
     ) -> Result<()> {
         while let Ok(msg) = recv_queue.recv().await {
+            if !self.within_limits(&msg, &send_queue).await? {
+                continue;
+            }
+
             let slabstore = self.slabstore.clone();
             let _ = executor
                 .spawn(Self::handle_request(
                     msg,
                     slabstore,
+                    self.admission.clone(),
+                    self.replication.clone(),
+                    self.publishers.clone(),
+                    self.signer.clone(),
+                    self.max_slab_size,
+                    self.session,
+                    self.compression,
                     send_queue.clone(),
-                    publish_queue.clone(),
                 ))
                 .detach();
         }
         Ok(())
     }
 
-    async fn handle_request(
-        msg: (PeerId, Request),
+    // Charge the connection for the request, and for a slab submission
+    // if it is one. One over a limit is told so, and one that keeps going
+    // over it is dropped: its requests go unanswered until it stays quiet
+    // for the violation window.
+    async fn within_limits(
+        &self,
+        msg: &(PeerId, Request),
+        send_queue: &async_channel::Sender<(PeerId, Reply)>,
+    ) -> Result<bool> {
+        let (peer, request) = msg;
+        let now = unix_millis();
+
+        let verdict = self.request_limiter.lock().await.check(peer, 1, now);
+        if !Self::enforce(verdict, "sends requests", msg, send_queue).await? {
+            return Ok(false);
+        }
+
+        if !matches!(request.get_command(), 0 | 5 | 9) {
+            return Ok(true);
+        }
+        let verdict = self.put_limiter.lock().await.check(peer, 1, now);
+        Self::enforce(verdict, "submits slabs", msg, send_queue).await
+    }
+
+    // Whether a request may go on given a limiter's verdict on it, with
+    // `what` the connection does too fast
+    async fn enforce(
+        verdict: Verdict,
+        what: &str,
+        msg: &(PeerId, Request),
+        send_queue: &async_channel::Sender<(PeerId, Reply)>,
+    ) -> Result<bool> {
+        let (peer, request) = msg;
+        match verdict {
+            Verdict::Allow => Ok(true),
+            Verdict::Throttle => {
+                warn!(
+                    target: "GATEWAY DAEMON",
+                    "Connection {} {} too fast, refusing one",
+                    hex::encode(peer),
+                    what
+                );
+                let reply = Reply::from(request, GatewayError::RateLimited as u32, vec![]);
+                send_queue.send((peer.clone(), reply)).await?;
+                Ok(false)
+            }
+            Verdict::Disconnect => {
+                warn!(
+                    target: "GATEWAY DAEMON",
+                    "Connection {} {} too fast too often, dropping it",
+                    hex::encode(peer),
+                    what
+                );
+                Ok(false)
+            }
+            Verdict::Disconnected => {
+                debug!(
+                    target: "GATEWAY DAEMON",
+                    "Dropping request from rate limited connection {}",
+                    hex::encode(peer)
+                );
+                Ok(false)
+            }
+        }
+    }
+
+    // Store the submitted slabs one at a time, in the order the admission
+    // queue picks
+    async fn admission_loop(
         slabstore: Arc<SlabStore>,
+        admission: Arc<Admission>,
+        signer: Option<Arc<SlabSigner>>,
+        compression: Compression,
         send_queue: async_channel::Sender<(PeerId, Reply)>,
         publish_queue: async_channel::Sender<Vec<u8>>,
     ) -> Result<()> {
-        let request = msg.1;
-        let peer = msg.0;
-        match request.get_command() {
-            0 => {
-                debug!(target: "GATEWAY DAEMON" ,"Received putslab msg");
-                // PUTSLAB
-                let slab = request.get_payload();
+        while admission.ready_r.recv().await.is_ok() {
+            let submission = match admission.queue.lock().await.pop(unix_millis()) {
+                Some(submission) => submission,
+                None => continue,
+            };
+            if let Err(e) = Self::admit(
+                submission,
+                &slabstore,
+                signer.as_deref(),
+                compression,
+                &send_queue,
+                &publish_queue,
+            )
+            .await
+            {
+                warn!(target: "GATEWAY DAEMON", "Admitting slab failed: {}", e);
+            }
+        }
+        Ok(())
+    }
 
-                // add to slabstore
-                let error = slabstore.put(deserialize(&slab)?)?;
+    async fn admit(
+        submission: Submission,
+        slabstore: &SlabStore,
+        signer: Option<&SlabSigner>,
+        compression: Compression,
+        send_queue: &async_channel::Sender<(PeerId, Reply)>,
+        publish_queue: &async_channel::Sender<Vec<u8>>,
+    ) -> Result<()> {
+        let mut slab = submission.slab;
+        if submission.assign_index {
+            slab.set_index(slabstore.get_last_index()? + 1);
+        }
 
-                let mut reply = Reply::from(&request, GatewayError::NoError as u32, vec![]);
+        // add to slabstore
+        let stored = slabstore.put(slab.clone())?;
 
-                if error.is_none() {
-                    reply.set_error(GatewayError::UpdateIndex as u32);
-                }
+        let mut reply = Reply::from(
+            &submission.request,
+            GatewayError::NoError as u32,
+            stored.map(|index| serialize(&index)).unwrap_or_default(),
+        );
 
-                // send reply
-                send_queue.send((peer, reply)).await?;
+        if stored.is_none() {
+            reply.set_error(GatewayError::UpdateIndex as u32);
+        }
 
-                // publish to all subscribes
-                publish_queue.send(slab).await?;
-            }
+        // send reply
+        send_queue.send((submission.peer, reply)).await?;
+
+        // Only slabs that took the next index are published, so
+        // subscribers see the indexes increase one by one. The
+        // client retries a refused one with a fresh index.
+        if stored.is_none() {
+            return Ok(());
+        }
+
+        // publish to all subscribes
+        let signature = signer.map(|signer| signer.sign(&slab)).transpose()?;
+        let published = PublishedSlab {
+            slab,
+            timestamp: unix_millis(),
+            signature,
+        };
+        publish_queue
+            .send(encode_published(&published, compression)?)
+            .await?;
+        Ok(())
+    }
+
+    // Queue a slab for admission, or refuse it if the queue is full
+    async fn submit(
+        admission: &Admission,
+        submission: Submission,
+        fee_rate: u64,
+        send_queue: &async_channel::Sender<(PeerId, Reply)>,
+    ) -> Result<()> {
+        let queued = admission
+            .queue
+            .lock()
+            .await
+            .push(fee_rate, unix_millis(), submission);
+        match queued {
+            Ok(()) => admission.ready_s.send(()).await?,
+            Err(submission) => {
+                warn!(target: "GATEWAY DAEMON", "Admission queue is full, refusing slab");
+                let reply =
+                    Reply::from(&submission.request, GatewayError::QueueFull as u32, vec![]);
+                send_queue.send((submission.peer, reply)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // Whether the submission `request` carries may be taken. Unsigned ones
+    // only may if there's no allowlist of publishers.
+    fn is_authorized(request: &Request, publishers: &[jubjub::SubgroupPoint]) -> Result<bool> {
+        if publishers.is_empty() {
+            return Ok(true);
+        }
+        match request.get_command() {
+            0 | 5 => Ok(false),
+            9 => {
+                let signed: SignedSubmission = deserialize(&request.get_payload())?;
+                Ok(signed.verify(publishers, unix_millis() / 1000))
+            }
+            _ => Ok(true),
+        }
+    }
+
+    // The slab a submission carries, None for other requests
+    fn submitted_slab(request: &Request) -> Result<Option<Slab>> {
+        match request.get_command() {
+            0 => Ok(Some(deserialize(&request.get_payload())?)),
+            5 => {
+                let submission: SlabSubmission = deserialize(&request.get_payload())?;
+                Ok(Some(submission.slab))
+            }
+            9 => {
+                let signed: SignedSubmission = deserialize(&request.get_payload())?;
+                Ok(Some(signed.submission.slab))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn handle_request(
+        msg: (PeerId, Request),
+        slabstore: Arc<SlabStore>,
+        admission: Arc<Admission>,
+        replication: Option<Arc<Replication>>,
+        publishers: Arc<Vec<jubjub::SubgroupPoint>>,
+        signer: Option<Arc<SlabSigner>>,
+        max_slab_size: usize,
+        session: u64,
+        compression: Compression,
+        send_queue: async_channel::Sender<(PeerId, Reply)>,
+    ) -> Result<()> {
+        let request = msg.1;
+        let peer = msg.0;
+
+        if !Self::is_authorized(&request, &publishers)? {
+            warn!(target: "GATEWAY DAEMON", "Refusing slab from an unauthorized publisher");
+            let reply = Reply::from(&request, GatewayError::Unauthorized as u32, vec![]);
+            send_queue.send((peer, reply)).await?;
+            return Ok(());
+        }
+
+        if let Some(slab) = Self::submitted_slab(&request)? {
+            if let Err(reason) = validate_slab(&slab, max_slab_size) {
+                warn!(target: "GATEWAY DAEMON", "Refusing invalid slab: {}", reason);
+                let reply = Reply::from(&request, GatewayError::InvalidSlab as u32, vec![]);
+                send_queue.send((peer, reply)).await?;
+                return Ok(());
+            }
+        }
+
+        // A replicating gateway passes submissions on to the gateway it
+        // follows, which indexes them. They come back with the slabs
+        // replicated from it. Signed submissions are passed on as they
+        // are, so the gateway followed checks the signature too.
+        if let Some(replication) = replication {
+            if matches!(request.get_command(), 0 | 5 | 6 | 9) {
+                debug!(target: "GATEWAY DAEMON", "Forwarding msg {}", request.get_command());
+                let reply = match replication.forward(&request).await {
+                    Ok(reply) => reply,
+                    Err(e) => {
+                        warn!(target: "GATEWAY DAEMON", "Forwarding failed: {}", e);
+                        Reply::from(&request, GatewayError::QueueFull as u32, vec![])
+                    }
+                };
+                send_queue.send((peer, reply)).await?;
+                return Ok(());
+            }
+        }
+
+        match request.get_command() {
+            0 => {
+                debug!(target: "GATEWAY DAEMON" ,"Received putslab msg");
+                // PUTSLAB
+                // From clients that predate fee hints, so it pays nothing
+                let slab: Slab = deserialize(&request.get_payload())?;
+                let submission = Submission {
+                    peer,
+                    request,
+                    slab,
+                    assign_index: false,
+                };
+                Self::submit(&admission, submission, 0, &send_queue).await?;
+            }
             1 => {
                 debug!(target: "GATEWAY DAEMON", "Received getslab msg");
                 let index = request.get_payload();
@@ -163,6 +1237,108 @@ impl GatewayService {
 
                 // GETLASTINDEX
             }
+            3 => {
+                debug!(target: "GATEWAY DAEMON", "Received hello msg");
+                // HELLO
+                // Answer with our version whatever the client's is, so it
+                // can tell the user which side is outdated, and with how
+                // we publish. Compatible clients follow their version with
+                // the compression they read.
+                let payload = request.get_payload();
+                let (version, read): (ProtocolVersion, usize) = deserialize_partial(&payload)?;
+                if !GATEWAY_PROTOCOL_VERSION.is_compatible(&version) {
+                    warn!(
+                        target: "GATEWAY DAEMON",
+                        "Client speaks protocol {}, incompatible with ours ({})",
+                        version, GATEWAY_PROTOCOL_VERSION
+                    );
+                } else {
+                    let readable: Vec<u8> = deserialize(&payload[read..])?;
+                    if !readable.contains(&compression.to_u8()) {
+                        warn!(
+                            target: "GATEWAY DAEMON",
+                            "Client can't read slabs compressed with {}",
+                            compression.as_str()
+                        );
+                    }
+                }
+
+                let mut hello = serialize(&GATEWAY_PROTOCOL_VERSION);
+                hello.push(compression.to_u8());
+                let reply = Reply::from(&request, GatewayError::NoError as u32, hello);
+                send_queue.send((peer, reply)).await?;
+            }
+            4 => {
+                debug!(target: "GATEWAY DAEMON", "Received resume msg");
+                // RESUME
+                let token: ResumeToken = deserialize(&request.get_payload())?;
+                let resume = resume_reply(&slabstore, session, token)?;
+
+                let reply = Reply::from(&request, GatewayError::NoError as u32, serialize(&resume));
+                send_queue.send((peer, reply)).await?;
+            }
+            5 => {
+                debug!(target: "GATEWAY DAEMON", "Received putslabwithfee msg");
+                // PUTSLABWITHFEE
+                let SlabSubmission { slab, fee_rate } = deserialize(&request.get_payload())?;
+                let submission = Submission {
+                    peer,
+                    request,
+                    slab,
+                    assign_index: true,
+                };
+                Self::submit(&admission, submission, fee_rate, &send_queue).await?;
+            }
+            6 => {
+                debug!(target: "GATEWAY DAEMON", "Received getgoingrate msg");
+                // GETGOINGRATE
+                let rate = admission.queue.lock().await.going_rate();
+                let reply = Reply::from(&request, GatewayError::NoError as u32, serialize(&rate));
+                send_queue.send((peer, reply)).await?;
+            }
+            7 => {
+                debug!(target: "GATEWAY DAEMON", "Received ping msg");
+                // PING
+                // The pong carries the last index, so a subscriber can
+                // tell whether it missed anything while it was quiet
+                let index = slabstore.get_last_index_as_bytes()?;
+                let reply = Reply::from(&request, GatewayError::NoError as u32, index);
+                send_queue.send((peer, reply)).await?;
+            }
+            8 => {
+                debug!(target: "GATEWAY DAEMON", "Received getslabs msg");
+                // GETSLABS
+                let range: SlabRange = deserialize(&request.get_payload())?;
+                let slabs = slab_range(&slabstore, range, MAX_SLAB_RANGE_BYTES)?;
+                let reply = Reply::from(&request, GatewayError::NoError as u32, serialize(&slabs));
+                send_queue.send((peer, reply)).await?;
+            }
+            9 => {
+                debug!(target: "GATEWAY DAEMON", "Received putslabsigned msg");
+                // PUTSLABSIGNED
+                // The signature was checked above
+                let signed: SignedSubmission = deserialize(&request.get_payload())?;
+                let SlabSubmission { slab, fee_rate } = signed.submission;
+                let submission = Submission {
+                    peer,
+                    request,
+                    slab,
+                    assign_index: true,
+                };
+                Self::submit(&admission, submission, fee_rate, &send_queue).await?;
+            }
+            10 => {
+                debug!(target: "GATEWAY DAEMON", "Received getslabsignatures msg");
+                // GETSLABSIGNATURES
+                let range: SlabRange = deserialize(&request.get_payload())?;
+                let signatures = signature_range(&slabstore, signer.as_deref(), range)?;
+                let reply = Reply::from(
+                    &request,
+                    GatewayError::NoError as u32,
+                    serialize(&signatures),
+                );
+                send_queue.send((peer, reply)).await?;
+            }
             _ => {
                 return Err(Error::ServicesError("received wrong command"));
             }
@@ -171,59 +1347,365 @@ impl GatewayService {
     }
 }
 
+/// The gateways a client can use, in order of preference, and which one
+/// is in use. Requests and the subscription share it, so they fail over
+/// together.
+pub struct GatewayList {
+    gateways: Vec<(Url, Url)>,
+    active: AtomicUsize,
+    // How tls:// gateways are checked
+    tls: TlsClientConfig,
+    proxy: Option<Socks5Proxy>,
+}
+
+impl GatewayList {
+    /// Pairs of protocol and publisher urls, the first one preferred
+    pub fn new(
+        gateways: Vec<(Url, Url)>,
+        tls: TlsClientConfig,
+        proxy: Option<Socks5Proxy>,
+    ) -> Result<Self> {
+        if gateways.is_empty() {
+            return Err(Error::ServicesError("No gateways configured"));
+        }
+        Ok(Self {
+            gateways,
+            active: AtomicUsize::new(0),
+            tls,
+            proxy,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.gateways.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.gateways.is_empty()
+    }
+
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    pub fn get(&self, index: usize) -> &(Url, Url) {
+        &self.gateways[index]
+    }
+
+    /// Where zmq reaches the gateway url `url`
+    async fn endpoint(&self, url: &Url) -> Result<SocketAddr> {
+        gateway_endpoint(url, &self.tls, self.proxy.as_ref()).await
+    }
+
+    /// Move on from the gateway at `failed` to the next one and return
+    /// the gateway now in use. If something else already moved on, its
+    /// choice stands.
+    pub fn fail_over(&self, failed: usize) -> usize {
+        let next = (failed + 1) % self.gateways.len();
+        match self
+            .active
+            .compare_exchange(failed, next, Ordering::SeqCst, Ordering::SeqCst)
+        {
+            Ok(_) => next,
+            Err(current) => current,
+        }
+    }
+}
+
+pub(super) fn resolve(url: &Url) -> Result<SocketAddr> {
+    (
+        url.host().ok_or(Error::UrlParseError)?.to_string(),
+        url.port().ok_or(Error::UrlParseError)?,
+    )
+        .to_socket_addrs()?
+        .next()
+        .ok_or(Error::UrlParseError)
+}
+
+// A gateway that doesn't answer in time counts as unreachable
+async fn with_timeout<T>(future: impl Future<Output = Result<T>>) -> Result<T> {
+    within(GATEWAY_TIMEOUT, future).await
+}
+
+async fn within<T>(seconds: u32, future: impl Future<Output = Result<T>>) -> Result<T> {
+    futures::select! {
+        result = future.fuse() => result,
+        _ = sleep(seconds).fuse() => Err(Error::ConnectTimeout),
+    }
+}
+
+// Returns the index of the last slab the gateway stored
+async fn ping(protocol: &ReqProtocol) -> Result<u64> {
+    let pong = protocol
+        .request(GatewayCommand::Ping as u8, vec![], Arc::new(handle_error))
+        .await?
+        .ok_or(Error::ServicesError("Gateway refused the ping"))?;
+    deserialize(&pong)
+}
+
 pub struct GatewayClient {
-    protocol: ReqProtocol,
+    protocol: Option<ReqProtocol>,
+    gateways: Arc<GatewayList>,
+    // The gateway the protocol is connected to
+    connected: usize,
     slabstore: Arc<SlabStore>,
     gateway_slabs_sub_s: async_channel::Sender<Slab>,
     gateway_slabs_sub_rv: GatewaySlabsSubscriber,
     is_running: bool,
-    sub_addr: SocketAddr,
+    stats: Arc<Mutex<GatewayStats>>,
+    heartbeat: Heartbeat,
+    // When the protocol last heard from the gateway
+    last_active: Instant,
+    // Seconds a request may go unanswered before the next gateway is tried
+    request_timeout: u32,
+    // Key submissions are signed with, for gateways with an allowlist of
+    // publishers
+    publisher_key: Option<schnorr::SecretKey>,
+    // Keys of the gateways one of which has to sign each slab taken, any
+    // slab is taken if empty
+    gateway_keys: Arc<Vec<jubjub::SubgroupPoint>>,
 }
 
 impl GatewayClient {
-    pub fn new(addr: Url, sub_addr: Url, rocks: RocksColumn<columns::Slabs>) -> Result<Self> {
-        // TODO: We'll want differentiation between TCP and TLS here.
-        let addr_sock = (addr.host().unwrap().to_string(), addr.port().unwrap())
-            .to_socket_addrs()?
-            .next()
-            .ok_or(Error::UrlParseError)?;
-        let protocol = ReqProtocol::new(addr_sock, String::from("GATEWAY CLIENT"));
+    pub fn new(
+        gateways: Vec<(Url, Url)>,
+        tls: TlsClientConfig,
+        proxy: Option<Socks5Proxy>,
+        rocks: KvColumn<columns::Slabs>,
+    ) -> Result<Self> {
+        let gateways = Arc::new(GatewayList::new(gateways, tls, proxy)?);
 
         let slabstore = SlabStore::new(rocks)?;
 
         let (gateway_slabs_sub_s, gateway_slabs_sub_rv) = async_channel::unbounded::<Slab>();
 
-        let sub_addr_sock = (
-            sub_addr.host().unwrap().to_string(),
-            sub_addr.port().unwrap(),
-        )
-            .to_socket_addrs()?
-            .next()
-            .ok_or(Error::UrlParseError)?;
-
         Ok(GatewayClient {
-            protocol,
+            protocol: None,
+            gateways,
+            connected: 0,
             slabstore,
             gateway_slabs_sub_s,
             gateway_slabs_sub_rv,
             is_running: false,
-            sub_addr: sub_addr_sock,
+            stats: Arc::new(Mutex::new(GatewayStats::default())),
+            heartbeat: Heartbeat::default(),
+            last_active: Instant::now(),
+            request_timeout: GATEWAY_TIMEOUT,
+            publisher_key: None,
+            gateway_keys: Arc::new(vec![]),
         })
     }
 
-    pub async fn start(&mut self) -> Result<()> {
-        self.protocol.start().await?;
+    /// Applies to the subscription once it is started
+    pub fn set_heartbeat(&mut self, heartbeat: Heartbeat) {
+        self.heartbeat = heartbeat;
+    }
+
+    /// Seconds a gateway may take to answer a request, GATEWAY_TIMEOUT by
+    /// default. A request that times out is sent to the next gateway.
+    pub fn set_request_timeout(&mut self, seconds: u32) {
+        self.request_timeout = seconds;
+    }
+
+    /// Sign the slabs submitted with `key`, which gateways only taking
+    /// slabs from known publishers have in their allowlist. Gateways that
+    /// predate signed submissions get them unsigned.
+    pub fn set_publisher_key(&mut self, key: schnorr::SecretKey) {
+        self.publisher_key = Some(key);
+    }
+
+    /// Only take slabs signed by one of `keys`, the keys of the gateways
+    /// used. Gateways that predate slab signatures send none, so no slab
+    /// is taken from them. Applies to the subscription once it's started.
+    pub fn set_gateway_keys(&mut self, keys: Vec<jubjub::SubgroupPoint>) {
+        self.gateway_keys = Arc::new(keys);
+    }
+
+    pub async fn start(&mut self, last_applied_index: u64) -> Result<()> {
+        self.connect().await?;
+        self.replay(last_applied_index).await?;
         self.sync().await?;
         self.is_running = true;
         Ok(())
     }
 
+    // Connect to the gateway in use, or to the ones after it in turn while
+    // they are unreachable
+    async fn connect(&mut self) -> Result<()> {
+        let mut index = self.gateways.active();
+        for _ in 0..self.gateways.len() {
+            let url = self.gateways.get(index).0.clone();
+            match Self::connect_to(&url, &self.gateways, self.request_timeout).await {
+                Ok((protocol, version, compression)) => {
+                    debug!(
+                        target: "GATEWAY CLIENT",
+                        "Connected to gateway {} speaking protocol {}, publishing with {} compression",
+                        url,
+                        version,
+                        compression.as_str()
+                    );
+                    self.protocol = Some(protocol);
+                    self.connected = index;
+                    self.last_active = Instant::now();
+                    let mut stats = self.stats.lock().await;
+                    stats.gateway = url.to_string();
+                    stats.protocol_version = Some(version);
+                    stats.slab_compression = Some(compression);
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!(target: "GATEWAY CLIENT", "Can't use gateway {}: {}", url, e);
+                    index = self.gateways.fail_over(index);
+                    self.stats.lock().await.failovers += 1;
+                }
+            }
+        }
+        Err(Error::ServicesError("No gateway is reachable"))
+    }
+
+    // Connect and exchange protocol versions, and learn how the gateway
+    // compresses the slabs it publishes. Only a gateway that answers
+    // counts as reachable.
+    async fn connect_to(
+        url: &Url,
+        gateways: &GatewayList,
+        request_timeout: u32,
+    ) -> Result<(ReqProtocol, ProtocolVersion, Compression)> {
+        let addr = gateways.endpoint(url).await?;
+        let mut protocol = ReqProtocol::new(addr, String::from("GATEWAY CLIENT"));
+        protocol.set_timeout(request_timeout);
+        with_timeout(protocol.start()).await?;
+
+        let readable: Vec<u8> = SUPPORTED_COMPRESSION.iter().map(|c| c.to_u8()).collect();
+        let mut hello = serialize(&GATEWAY_PROTOCOL_VERSION);
+        hello.extend(serialize(&readable));
+
+        let reply = protocol
+            .request(GatewayCommand::Hello as u8, hello, Arc::new(handle_error))
+            .await?
+            .ok_or(Error::ServicesError("Gateway refused the handshake"))?;
+
+        let (version, read): (ProtocolVersion, usize) = deserialize_partial(&reply)?;
+        if !GATEWAY_PROTOCOL_VERSION.is_compatible(&version) {
+            return Err(Error::ProtocolVersionMismatch(format!(
+                "gateway {} speaks {}, this build speaks {}",
+                url, version, GATEWAY_PROTOCOL_VERSION
+            )));
+        }
+
+        let compression: u8 = deserialize(&reply[read..])?;
+        let compression = Compression::from_u8(compression).ok_or_else(|| {
+            Error::ProtocolVersionMismatch(format!(
+                "gateway {} publishes with compression {}, which this build can't read",
+                url, compression
+            ))
+        })?;
+        Ok((protocol, version, compression))
+    }
+
+    // Send a request to the gateway in use, failing over to the next ones
+    // while it doesn't answer. If none does, the last gateway's error is
+    // returned, e.g. Error::RequestTimeout.
+    async fn request(&mut self, command: u8, payload: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let mut failure = Error::ServicesError("No gateway is reachable");
+        for _ in 0..self.gateways.len() {
+            // The subscription may have failed over already
+            if self.protocol.is_none() || self.connected != self.gateways.active() {
+                self.connect().await?;
+            }
+            self.check_idle().await?;
+            let protocol = self
+                .protocol
+                .as_ref()
+                .ok_or(Error::ServicesError("Not connected to a gateway"))?;
+
+            let handle_error = Arc::new(handle_error);
+            match protocol
+                .request(command, payload.clone(), handle_error)
+                .await
+            {
+                Ok(reply) => {
+                    self.last_active = Instant::now();
+                    return Ok(reply);
+                }
+                Err(e) => {
+                    warn!(target: "GATEWAY CLIENT", "Gateway request failed: {}", e);
+                    self.protocol = None;
+                    self.gateways.fail_over(self.connected);
+                    self.stats.lock().await.failovers += 1;
+                    failure = e;
+                }
+            }
+        }
+        Err(failure)
+    }
+
+    // A connection idle past the heartbeat interval may have been dropped
+    // along the way. It's pinged before use and re-established if the
+    // gateway doesn't answer, rather than a request timing out on it.
+    async fn check_idle(&mut self) -> Result<()> {
+        if self.last_active.elapsed().as_secs() < self.heartbeat.interval as u64
+            || !self.supports_ping().await
+        {
+            return Ok(());
+        }
+
+        let pinged = match self.protocol.as_ref() {
+            Some(protocol) => within(self.heartbeat.timeout, ping(protocol)).await,
+            None => return Ok(()),
+        };
+        match pinged {
+            Ok(_) => self.last_active = Instant::now(),
+            Err(e) => {
+                warn!(target: "GATEWAY CLIENT", "Gateway connection went silent, reconnecting: {}", e);
+                self.stats.lock().await.heartbeat_failures += 1;
+                self.connect().await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn supports_ping(&self) -> bool {
+        self.stats
+            .lock()
+            .await
+            .protocol_version
+            .map_or(false, |version| version.supports_ping())
+    }
+
+    // Slabs which were stored locally but never applied (e.g. the node
+    // stopped before processing them) are pushed to the subscriber first,
+    // ahead of the backlog fetched from the gateway.
+    async fn replay(&self, last_applied_index: u64) -> Result<()> {
+        let local_last_index = self.slabstore.get_last_index()?;
+
+        debug!(
+            target: "GATEWAY CLIENT",
+            "Replay slabs {} to {}",
+            last_applied_index + 1,
+            local_last_index
+        );
+
+        for index in (last_applied_index + 1)..(local_last_index + 1) {
+            if let Some(slab) = self.slabstore.get(serialize(&index))? {
+                let slab: Slab = deserialize(&slab)?;
+                self.gateway_slabs_sub_s.send(slab).await?;
+            }
+        }
+
+        Ok(())
+    }
+
     pub async fn sync(&mut self) -> Result<u64> {
         debug!(target: "GATEWAY CLIENT", "Start Syncing");
 
         let local_last_index = self.slabstore.get_last_index()?;
 
         let last_index = self.get_last_index().await?;
+        {
+            let mut stats = self.stats.lock().await;
+            stats.latest_index = stats.latest_index.max(last_index);
+        }
 
         if last_index < local_last_index {
             return Err(Error::SlabsStore(
@@ -233,7 +1715,23 @@ impl GatewayClient {
             ));
         }
 
-        if last_index > 0 {
+        if last_index > 0 && self.supports_slab_ranges().await {
+            // A page at a time, so a fresh node gets the full history in
+            // a few requests rather than one per slab
+            let mut index = local_last_index + 1;
+            while index <= last_index {
+                let slabs = self.get_slabs(index, last_index).await?;
+                if slabs.is_empty() {
+                    break;
+                }
+                index += slabs.len() as u64;
+                if self.slabstore.get_last_index()? + 1 < index {
+                    warn!(target: "GATEWAY CLIENT", "Stopped syncing, the gateway sent slabs we refused");
+                    break;
+                }
+                debug!(target: "GATEWAY CLIENT", "Synced to slab {} of {}", index - 1, last_index);
+            }
+        } else if last_index > 0 {
             for index in (local_last_index + 1)..(last_index + 1) {
                 if self.get_slab(index).await?.is_none() {
                     break;
@@ -248,56 +1746,169 @@ impl GatewayClient {
     pub async fn get_slab(&mut self, index: u64) -> Result<Option<Slab>> {
         debug!(target: "GATEWAY CLIENT","Get slab");
 
-        let handle_error = Arc::new(handle_error);
         let rep = self
-            .protocol
-            .request(
-                GatewayCommand::GetSlab as u8,
-                serialize(&index),
-                handle_error,
-            )
+            .request(GatewayCommand::GetSlab as u8, serialize(&index))
             .await?;
 
         if let Some(slab) = rep {
             let slab: Slab = deserialize(&slab)?;
-            self.gateway_slabs_sub_s.send(slab.clone()).await?;
-            self.slabstore.put(slab.clone())?;
+            let signatures = self.get_slab_signatures(index, index).await?;
+            Self::deliver(
+                slab.clone(),
+                signature_for(&signatures, index),
+                &self.gateway_keys,
+                &self.slabstore,
+                &self.gateway_slabs_sub_s,
+                &self.stats,
+            )
+            .await?;
             return Ok(Some(slab));
         }
 
         Ok(None)
     }
 
-    pub async fn put_slab(&mut self, mut slab: Slab) -> Result<()> {
+    /// Fetch the slabs `from..=to`, at most MAX_SLAB_RANGE of them or
+    /// MAX_SLAB_RANGE_BYTES of slabs, and store them and pass them on
+    /// like published ones. The slabs sent start at `from` and are
+    /// consecutive, but may stop short of `to`.
+    pub async fn get_slabs(&mut self, from: u64, to: u64) -> Result<Vec<Slab>> {
+        debug!(target: "GATEWAY CLIENT", "Get slabs {} to {}", from, to);
+
+        let rep = self
+            .request(
+                GatewayCommand::GetSlabs as u8,
+                serialize(&SlabRange { from, to }),
+            )
+            .await?
+            .ok_or(Error::ServicesError("Gateway refused the slab range"))?;
+        let slabs: Vec<Slab> = deserialize(&rep)?;
+        let signatures = self.get_slab_signatures(from, to).await?;
+
+        for (expected, slab) in (from..).zip(slabs.iter()) {
+            if slab.get_index() != expected || expected > to {
+                return Err(Error::ServicesError(
+                    "Gateway sent slabs outside the range asked for",
+                ));
+            }
+            Self::deliver(
+                slab.clone(),
+                signature_for(&signatures, expected),
+                &self.gateway_keys,
+                &self.slabstore,
+                &self.gateway_slabs_sub_s,
+                &self.stats,
+            )
+            .await?;
+        }
+        Ok(slabs)
+    }
+
+    // The gateway's signatures of the slabs `from..=to`, only asked for
+    // if slabs have to be signed
+    async fn get_slab_signatures(&mut self, from: u64, to: u64) -> Result<Vec<SlabSignature>> {
+        if self.gateway_keys.is_empty() || !supports_slab_signatures(&self.stats).await {
+            return Ok(vec![]);
+        }
+
+        let rep = self
+            .request(
+                GatewayCommand::GetSlabSignatures as u8,
+                serialize(&SlabRange { from, to }),
+            )
+            .await?;
+        match rep {
+            Some(signatures) => deserialize(&signatures),
+            None => Ok(vec![]),
+        }
+    }
+
+    async fn supports_slab_ranges(&self) -> bool {
+        self.stats
+            .lock()
+            .await
+            .protocol_version
+            .map_or(false, |version| version.supports_slab_ranges())
+    }
+
+    // Returns the index the slab was stored under
+    /// Submit a slab paying `fee` for admission. Gateways that predate
+    /// fee hints store it without regard to the fee.
+    pub async fn put_slab(&mut self, mut slab: Slab, fee: u64) -> Result<u64> {
         debug!(target: "GATEWAY CLIENT","Put slab");
 
+        let version = self.stats.lock().await.protocol_version;
+        let signed = version.map_or(false, |version| version.supports_signed_submissions());
+        if let (Some(key), true) = (&self.publisher_key, signed) {
+            let submission = SlabSubmission {
+                fee_rate: fee_rate(fee, slab.get_payload().len()),
+                slab,
+            };
+            let signed = SignedSubmission::sign(submission, key, unix_millis() / 1000);
+            let rep = self
+                .request(GatewayCommand::PutSlabSigned as u8, serialize(&signed))
+                .await?
+                .ok_or(Error::ServicesError(
+                    "Gateway refused the slab, is the publisher key allowed?",
+                ))?;
+            return deserialize(&rep);
+        }
+
+        if self.supports_fee_hints().await {
+            let submission = SlabSubmission {
+                fee_rate: fee_rate(fee, slab.get_payload().len()),
+                slab,
+            };
+            let rep = self
+                .request(GatewayCommand::PutSlabWithFee as u8, serialize(&submission))
+                .await?
+                .ok_or(Error::ServicesError("Gateway refused the slab"))?;
+            return deserialize(&rep);
+        }
+
         loop {
             let last_index = self.sync().await?;
             slab.set_index(last_index + 1);
             let slab = serialize(&slab);
 
-            let handle_error = Arc::new(handle_error);
-
-            let rep = self
-                .protocol
-                .request(GatewayCommand::PutSlab as u8, slab.clone(), handle_error)
-                .await?;
+            let rep = self.request(GatewayCommand::PutSlab as u8, slab).await?;
 
             if rep.is_some() {
-                break;
+                return Ok(last_index + 1);
             }
         }
-        Ok(())
+    }
+
+    /// The fee rate, in fee units per kilobyte, the gateway has been
+    /// admitting slabs at lately. 0 from gateways that predate fee hints.
+    pub async fn get_going_rate(&mut self) -> Result<u64> {
+        debug!(target: "GATEWAY CLIENT", "Get going rate");
+        if !self.supports_fee_hints().await {
+            return Ok(0);
+        }
+
+        let rep = self
+            .request(GatewayCommand::GetGoingRate as u8, vec![])
+            .await?
+            .ok_or(Error::ServicesError(
+                "Gateway refused to report the going rate",
+            ))?;
+        deserialize(&rep)
+    }
+
+    async fn supports_fee_hints(&self) -> bool {
+        self.stats
+            .lock()
+            .await
+            .protocol_version
+            .map_or(false, |version| version.supports_fee_hints())
     }
 
     pub async fn get_last_index(&mut self) -> Result<u64> {
         debug!(target: "GATEWAY CLIENT","Get last index");
 
-        let handle_error = Arc::new(handle_error);
-
         let rep = self
-            .protocol
-            .request(GatewayCommand::GetLastIndex as u8, vec![], handle_error)
+            .request(GatewayCommand::GetLastIndex as u8, vec![])
             .await?;
         if let Some(index) = rep {
             return deserialize(&index);
@@ -315,38 +1926,595 @@ impl GatewayClient {
     ) -> Result<GatewaySlabsSubscriber> {
         debug!(target: "GATEWAY CLIENT","Start subscriber");
 
-        let mut subscriber = Subscriber::new(self.sub_addr, String::from("GATEWAY CLIENT"));
-        subscriber.start().await?;
+        let (subscribed, subscriber, reader) = match Self::subscribe(&self.gateways).await {
+            Ok(subscription) => subscription,
+            Err(e) => {
+                self.gateways.fail_over(self.gateways.active());
+                self.stats.lock().await.failovers += 1;
+                return Err(e);
+            }
+        };
+        self.stats.lock().await.subscribed = true;
+
         executor
             .spawn(Self::subscribe_loop(
+                subscribed,
                 subscriber,
+                reader,
+                self.gateways.clone(),
                 self.slabstore.clone(),
                 self.gateway_slabs_sub_s.clone(),
+                self.stats.clone(),
+                self.gateway_keys.clone(),
+                self.heartbeat,
             ))
             .detach();
         Ok(self.gateway_slabs_sub_rv.clone())
     }
 
+    // Subscribe to the publisher of the gateway in use. Skipped slabs are
+    // fetched from it over a connection of their own.
+    async fn subscribe(gateways: &GatewayList) -> Result<(usize, Subscriber, GatewayReader)> {
+        let index = gateways.active();
+        let (url, sub_url) = gateways.get(index);
+
+        let sub_addr = gateways.endpoint(sub_url).await?;
+        let mut subscriber = Subscriber::new(sub_addr, String::from("GATEWAY CLIENT"));
+        with_timeout(subscriber.start()).await?;
+
+        let mut reader = GatewayReader::with_addr(gateways.endpoint(url).await?);
+        with_timeout(reader.start()).await?;
+
+        Ok((index, subscriber, reader))
+    }
+
+    #[allow(clippy::too_many_arguments)]
     async fn subscribe_loop(
+        mut subscribed: usize,
         mut subscriber: Subscriber,
+        mut reader: GatewayReader,
+        gateways: Arc<GatewayList>,
         slabstore: Arc<SlabStore>,
         gateway_slabs_sub_s: async_channel::Sender<Slab>,
+        stats: Arc<Mutex<GatewayStats>>,
+        gateway_keys: Arc<Vec<jubjub::SubgroupPoint>>,
+        heartbeat: Heartbeat,
     ) -> Result<()> {
         debug!(target: "GATEWAY CLIENT","Start subscribe loop");
 
+        let mut resubscribe = false;
+        let mut resume = true;
+        let mut token = None;
         loop {
-            let slab = subscriber.fetch::<Slab>().await?;
+            // Follow the requests if they failed over to another gateway
+            if gateways.active() != subscribed {
+                resubscribe = true;
+            }
+
+            if resubscribe {
+                match Self::subscribe(&gateways).await {
+                    Ok((index, sub, rd)) => {
+                        subscribed = index;
+                        subscriber = sub;
+                        reader = rd;
+                        resubscribe = false;
+                        resume = true;
+
+                        let mut stats = stats.lock().await;
+                        stats.subscribed = true;
+                        stats.reconnects += 1;
+                    }
+                    Err(e) => {
+                        warn!(target: "GATEWAY CLIENT", "Resubscribe failed: {}", e);
+                        gateways.fail_over(gateways.active());
+                        stats.lock().await.failovers += 1;
+                        sleep(RESUBSCRIBE_DELAY).await;
+                        continue;
+                    }
+                }
+            }
+
+            let can_resume = stats
+                .lock()
+                .await
+                .protocol_version
+                .map_or(false, |version| version.supports_resume());
+            if resume && can_resume {
+                let resumed = with_timeout(Self::resume(
+                    &mut reader,
+                    token,
+                    &slabstore,
+                    &gateway_slabs_sub_s,
+                    &stats,
+                    &gateway_keys,
+                ))
+                .await;
+                match resumed {
+                    Ok(next) => token = Some(next),
+                    Err(e) => warn!(target: "GATEWAY CLIENT", "Resuming failed: {}", e),
+                }
+            }
+            resume = false;
+
+            let fetched = futures::select! {
+                data = subscriber.fetch_bytes().fuse() => Some(data),
+                _ = sleep(heartbeat.interval).fuse() => None,
+            };
+            let fetched = match fetched {
+                Some(fetched) => fetched,
+                None => {
+                    if !Self::subscription_alive(&mut reader, heartbeat, &slabstore, &stats).await {
+                        stats.lock().await.subscribed = false;
+                        resubscribe = true;
+                    }
+                    continue;
+                }
+            };
+
+            let published = match fetched {
+                Ok(data) => match decode_published(&data) {
+                    Ok(published) => published,
+                    Err(e) => {
+                        // Shows up as a gap with the next slab
+                        warn!(target: "GATEWAY CLIENT", "Dropped published slab: {}", e);
+                        continue;
+                    }
+                },
+                Err(e) => {
+                    warn!(target: "GATEWAY CLIENT", "Subscription dropped: {}", e);
+                    stats.lock().await.subscribed = false;
+                    resubscribe = true;
+                    sleep(RESUBSCRIBE_DELAY).await;
+                    continue;
+                }
+            };
             debug!(target: "GATEWAY CLIENT","Received new slab");
-            gateway_slabs_sub_s.send(slab.clone()).await?;
-            slabstore.put(slab)?;
+
+            let index = published.slab.get_index();
+            {
+                let mut stats = stats.lock().await;
+                stats.record_slab(published.timestamp, unix_millis());
+                stats.latest_index = stats.latest_index.max(index);
+            }
+
+            let last_index = slabstore.get_last_index()?;
+
+            // Already fetched while syncing
+            if index <= last_index {
+                Self::check_rewrite(&published, &gateway_keys, &slabstore, &stats).await?;
+                continue;
+            }
+
+            if index > last_index + 1 {
+                debug!(
+                    target: "GATEWAY CLIENT",
+                    "Subscription skipped slabs {} to {}",
+                    last_index + 1,
+                    index - 1
+                );
+                stats.lock().await.gaps += 1;
+
+                let filled = Self::fill_gap(
+                    &mut reader,
+                    last_index + 1,
+                    index,
+                    &slabstore,
+                    &gateway_slabs_sub_s,
+                    &stats,
+                    &gateway_keys,
+                )
+                .await?;
+                if !filled {
+                    // Delivering it now would skip the missing slabs. It
+                    // is fetched along with them when the next one arrives.
+                    warn!(
+                        target: "GATEWAY CLIENT",
+                        "Holding back slab {} until the slabs before it are fetched",
+                        index
+                    );
+                    continue;
+                }
+            }
+
+            Self::deliver(
+                published.slab,
+                published.signature.as_ref(),
+                &gateway_keys,
+                &slabstore,
+                &gateway_slabs_sub_s,
+                &stats,
+            )
+            .await?;
         }
     }
 
+    // A slab the gateway signed that differs from the one stored at its
+    // index is proof it rewrote its history. The signature is logged so
+    // it can be shown to others.
+    async fn check_rewrite(
+        published: &PublishedSlab,
+        gateway_keys: &[jubjub::SubgroupPoint],
+        slabstore: &SlabStore,
+        stats: &Mutex<GatewayStats>,
+    ) -> Result<()> {
+        let signature = match &published.signature {
+            Some(signature) if signature.verify(&published.slab, gateway_keys) => signature,
+            _ => return Ok(()),
+        };
+
+        let index = published.slab.get_index();
+        let stored: Option<Slab> = slabstore.get_value_deserialized(serialize(&index))?;
+        if let Some(stored) = stored {
+            if stored.hash() != published.slab.hash() {
+                warn!(
+                    target: "GATEWAY CLIENT",
+                    "Gateway signed slab {} with hash {} but we have it with hash {}, it rewrote its history. Signature: {}",
+                    index,
+                    hex::encode(published.slab.hash()),
+                    hex::encode(stored.hash()),
+                    hex::encode(serialize(signature))
+                );
+                stats.lock().await.rewrites += 1;
+            }
+        }
+        Ok(())
+    }
+
+    // Ping the gateway after the subscription has been quiet for a while.
+    // It's taken for dead if the gateway doesn't answer, or has stored
+    // slabs that were never published to us.
+    async fn subscription_alive(
+        reader: &mut GatewayReader,
+        heartbeat: Heartbeat,
+        slabstore: &SlabStore,
+        stats: &Mutex<GatewayStats>,
+    ) -> bool {
+        let can_ping = stats
+            .lock()
+            .await
+            .protocol_version
+            .map_or(false, |version| version.supports_ping());
+        if !can_ping {
+            return true;
+        }
+
+        match within(heartbeat.timeout, reader.ping()).await {
+            Ok(last_index) => match slabstore.get_last_index() {
+                Ok(local_last_index) if last_index > local_last_index => {
+                    warn!(
+                        target: "GATEWAY CLIENT",
+                        "Gateway is at slab {} but the subscription stopped at {}, resubscribing",
+                        last_index,
+                        local_last_index
+                    );
+                    stats.lock().await.heartbeat_failures += 1;
+                    false
+                }
+                _ => true,
+            },
+            Err(e) => {
+                warn!(target: "GATEWAY CLIENT", "Gateway missed the heartbeat, resubscribing: {}", e);
+                stats.lock().await.heartbeat_failures += 1;
+                false
+            }
+        }
+    }
+
+    // Fetch the slabs from `from` up to but not including `to` from the
+    // gateway's store and deliver them in order. Returns whether all of
+    // them were.
+    async fn fill_gap(
+        reader: &mut GatewayReader,
+        from: u64,
+        to: u64,
+        slabstore: &SlabStore,
+        gateway_slabs_sub_s: &async_channel::Sender<Slab>,
+        stats: &Mutex<GatewayStats>,
+        gateway_keys: &[jubjub::SubgroupPoint],
+    ) -> Result<bool> {
+        for missing in from..to {
+            match reader.get_slab(missing).await {
+                Ok(Some(slab)) => {
+                    let signatures =
+                        match fetch_signatures(reader, missing, missing, gateway_keys, stats).await
+                        {
+                            Ok(signatures) => signatures,
+                            Err(e) => {
+                                warn!(
+                                    target: "GATEWAY CLIENT",
+                                    "Fetching the signature of slab {} failed: {}",
+                                    missing,
+                                    e
+                                );
+                                return Ok(false);
+                            }
+                        };
+                    let delivered = Self::deliver(
+                        slab,
+                        signature_for(&signatures, missing),
+                        gateway_keys,
+                        slabstore,
+                        gateway_slabs_sub_s,
+                        stats,
+                    )
+                    .await?;
+                    if !delivered {
+                        return Ok(false);
+                    }
+                    stats.lock().await.retransmissions += 1;
+                }
+                Ok(None) => return Ok(false),
+                Err(e) => {
+                    warn!(target: "GATEWAY CLIENT", "Fetching slab {} failed: {}", missing, e);
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    // Store the slab and pass it on to the subscriber, unless its index
+    // isn't the next one, or slabs have to be signed by one of
+    // `gateway_keys` and `signature` isn't valid. Returns whether it was
+    // delivered.
+    async fn deliver(
+        slab: Slab,
+        signature: Option<&SlabSignature>,
+        gateway_keys: &[jubjub::SubgroupPoint],
+        slabstore: &SlabStore,
+        gateway_slabs_sub_s: &async_channel::Sender<Slab>,
+        stats: &Mutex<GatewayStats>,
+    ) -> Result<bool> {
+        if !gateway_keys.is_empty()
+            && !signature.map_or(false, |signature| signature.verify(&slab, gateway_keys))
+        {
+            warn!(
+                target: "GATEWAY CLIENT",
+                "Refusing slab {} without a valid gateway signature",
+                slab.get_index()
+            );
+            stats.lock().await.bad_signatures += 1;
+            return Ok(false);
+        }
+
+        if slabstore.put(slab.clone())?.is_none() {
+            return Ok(false);
+        }
+        gateway_slabs_sub_s.send(slab).await?;
+        Ok(true)
+    }
+
+    // Catch up on the slabs published while we weren't subscribed, if our
+    // token is from the gateway's current session. Otherwise gaps are
+    // found and filled as new slabs arrive. Returns the token to resume
+    // with next time.
+    async fn resume(
+        reader: &mut GatewayReader,
+        token: Option<ResumeToken>,
+        slabstore: &SlabStore,
+        gateway_slabs_sub_s: &async_channel::Sender<Slab>,
+        stats: &Mutex<GatewayStats>,
+        gateway_keys: &[jubjub::SubgroupPoint],
+    ) -> Result<ResumeToken> {
+        let session = token.map_or(0, |token| token.session);
+        loop {
+            let cursor = slabstore.get_last_index()?;
+            let reply = reader
+                .resume(ResumeToken { session, cursor })
+                .await?
+                .ok_or(Error::ServicesError("Gateway refused to resume"))?;
+            if !reply.resumed {
+                debug!(target: "GATEWAY CLIENT", "Gateway session changed, not resuming");
+                return Ok(reply.token);
+            }
+
+            let count = reply.slabs.len() as u64;
+            debug!(target: "GATEWAY CLIENT", "Resumed with {} slabs after {}", count, cursor);
+            let signatures = if count > 0 {
+                fetch_signatures(reader, cursor + 1, cursor + count, gateway_keys, stats).await?
+            } else {
+                vec![]
+            };
+            for slab in reply.slabs {
+                let index = slab.get_index();
+                Self::deliver(
+                    slab,
+                    signature_for(&signatures, index),
+                    gateway_keys,
+                    slabstore,
+                    gateway_slabs_sub_s,
+                    stats,
+                )
+                .await?;
+            }
+            stats.lock().await.retransmissions += count;
+
+            if count < MAX_RESUME_SLABS {
+                return Ok(reply.token);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> Arc<Mutex<GatewayStats>> {
+        self.stats.clone()
+    }
+
     pub fn is_running(&self) -> bool {
         self.is_running
     }
 }
 
+/// Read-only connection to a gateway, used to compare the slabs of
+/// replicated gateways without touching a local slabstore.
+pub struct GatewayReader {
+    protocol: ReqProtocol,
+}
+
+impl GatewayReader {
+    pub fn new(addr: Url) -> Result<Self> {
+        Ok(Self::with_addr(resolve(&addr)?))
+    }
+
+    pub fn with_addr(addr: SocketAddr) -> Self {
+        let mut protocol = ReqProtocol::new(addr, String::from("GATEWAY READER"));
+        protocol.set_timeout(GATEWAY_TIMEOUT);
+        GatewayReader { protocol }
+    }
+
+    pub async fn start(&mut self) -> Result<()> {
+        self.protocol.start().await
+    }
+
+    pub async fn get_last_index(&mut self) -> Result<u64> {
+        let handle_error = Arc::new(handle_error);
+
+        let rep = self
+            .protocol
+            .request(GatewayCommand::GetLastIndex as u8, vec![], handle_error)
+            .await?;
+        if let Some(index) = rep {
+            return deserialize(&index);
+        }
+        Ok(0)
+    }
+
+    pub async fn get_slab(&mut self, index: u64) -> Result<Option<Slab>> {
+        let handle_error = Arc::new(handle_error);
+
+        let rep = self
+            .protocol
+            .request(
+                GatewayCommand::GetSlab as u8,
+                serialize(&index),
+                handle_error,
+            )
+            .await?;
+
+        match rep {
+            Some(slab) => Ok(Some(deserialize(&slab)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The slabs `from..=to` the gateway has, at most MAX_SLAB_RANGE of
+    /// them or MAX_SLAB_RANGE_BYTES of slabs. Gateways that predate slab ranges don't answer.
+    pub async fn get_slabs(&mut self, from: u64, to: u64) -> Result<Vec<Slab>> {
+        let handle_error = Arc::new(handle_error);
+
+        let rep = self
+            .protocol
+            .request(
+                GatewayCommand::GetSlabs as u8,
+                serialize(&SlabRange { from, to }),
+                handle_error,
+            )
+            .await?;
+
+        match rep {
+            Some(slabs) => deserialize(&slabs),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// The gateway's signatures of the slabs `from..=to` it has, none if
+    /// it doesn't sign slabs. Gateways that predate slab signatures don't
+    /// answer.
+    pub async fn get_slab_signatures(&mut self, from: u64, to: u64) -> Result<Vec<SlabSignature>> {
+        let handle_error = Arc::new(handle_error);
+
+        let rep = self
+            .protocol
+            .request(
+                GatewayCommand::GetSlabSignatures as u8,
+                serialize(&SlabRange { from, to }),
+                handle_error,
+            )
+            .await?;
+
+        match rep {
+            Some(signatures) => deserialize(&signatures),
+            None => Ok(vec![]),
+        }
+    }
+
+    // Hash of the slab at the given index, as sent by the gateway
+    pub async fn get_slab_hash(&mut self, index: u64) -> Result<Option<SlabHash>> {
+        Ok(self.get_slab(index).await?.map(|slab| slab.hash()))
+    }
+
+    /// Check the connection is alive. Returns the gateway's last index.
+    pub async fn ping(&mut self) -> Result<u64> {
+        ping(&self.protocol).await
+    }
+
+    /// Ask for the slabs following the token's cursor
+    pub async fn resume(&mut self, token: ResumeToken) -> Result<Option<ResumeReply>> {
+        let handle_error = Arc::new(handle_error);
+
+        let rep = self
+            .protocol
+            .request(
+                GatewayCommand::Resume as u8,
+                serialize(&token),
+                handle_error,
+            )
+            .await?;
+
+        match rep {
+            Some(reply) => Ok(Some(deserialize(&reply)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+async fn supports_slab_signatures(stats: &Mutex<GatewayStats>) -> bool {
+    stats
+        .lock()
+        .await
+        .protocol_version
+        .map_or(false, |version| version.supports_slab_signatures())
+}
+
+// The signatures of the slabs `from..=to` from the gateway `reader` reads,
+// only asked for if slabs have to be signed
+async fn fetch_signatures(
+    reader: &mut GatewayReader,
+    from: u64,
+    to: u64,
+    gateway_keys: &[jubjub::SubgroupPoint],
+    stats: &Mutex<GatewayStats>,
+) -> Result<Vec<SlabSignature>> {
+    if gateway_keys.is_empty() || !supports_slab_signatures(stats).await {
+        return Ok(vec![]);
+    }
+    reader.get_slab_signatures(from, to).await
+}
+
+fn signature_for(signatures: &[SlabSignature], index: u64) -> Option<&SlabSignature> {
+    signatures.iter().find(|signature| signature.index == index)
+}
+
+/// Compare the slab hashes of two gateways over the inclusive range
+/// `from..=to` and return the index of the first slab they disagree on.
+/// A slab missing from only one of the gateways counts as a divergence.
+pub async fn find_divergence(
+    gateway: &mut GatewayReader,
+    mirror: &mut GatewayReader,
+    from: u64,
+    to: u64,
+) -> Result<Option<u64>> {
+    for index in from..(to + 1) {
+        let hash = gateway.get_slab_hash(index).await?;
+        let mirror_hash = mirror.get_slab_hash(index).await?;
+
+        if hash != mirror_hash {
+            debug!(target: "GATEWAY READER", "Slab {} diverges", index);
+            return Ok(Some(index));
+        }
+    }
+    Ok(None)
+}
+
 fn handle_error(status_code: u32) {
     match status_code {
         1 => {
@@ -355,6 +2523,307 @@ fn handle_error(status_code: u32) {
         2 => {
             debug!(target: "GATEWAY SERVICE", "Reply has an Error: Index Not Exist");
         }
+        4 => {
+            warn!(target: "GATEWAY SERVICE", "Reply has an Error: Publisher not authorized");
+        }
+        5 => {
+            warn!(target: "GATEWAY SERVICE", "Reply has an Error: Rate limited");
+        }
+        6 => {
+            warn!(target: "GATEWAY SERVICE", "Reply has an Error: Invalid or oversized slab");
+        }
         _ => {}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::KvBackend;
+
+    #[test]
+    fn test_gateway_stats_grade() {
+        let mut stats = GatewayStats::default();
+        assert_eq!(stats.grade(), "down");
+
+        stats.subscribed = true;
+        for i in 0..200 {
+            stats.record_slab(i * 1000, i * 1000 + 50);
+        }
+        assert_eq!(stats.latencies.len(), LATENCY_WINDOW);
+        assert_eq!(stats.avg_latency_millis(), 50);
+        assert_eq!(stats.grade(), "good");
+
+        // One skip in every 100 slabs
+        stats.gaps = 2;
+        assert_eq!(stats.grade(), "degraded");
+
+        stats.gaps = 0;
+        stats.record_slab(0, POOR_LATENCY_MILLIS * LATENCY_WINDOW as u64);
+        assert_eq!(stats.grade(), "poor");
+
+        let slab = PublishedSlab {
+            slab: Slab::new(vec![1, 2, 3]),
+            timestamp: 1337,
+            signature: None,
+        };
+        let slab: PublishedSlab = deserialize(&serialize(&slab)).unwrap();
+        assert_eq!(slab.timestamp, 1337);
+        assert_eq!(slab.slab.get_payload(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_published_compression() -> Result<()> {
+        let large = PublishedSlab {
+            slab: Slab::new(vec![7; 64 * 1024]),
+            timestamp: 1337,
+            signature: None,
+        };
+        let compressed = encode_published(&large, Compression::Zstd)?;
+        assert_eq!(compressed[0], Compression::Zstd.to_u8());
+        assert!(compressed.len() < serialize(&large).len());
+
+        let plain = encode_published(&large, Compression::None)?;
+        assert_eq!(plain[0], Compression::None.to_u8());
+        assert_eq!(&plain[1..], &serialize(&large)[..]);
+
+        for data in &[compressed, plain] {
+            let decoded = decode_published(data)?;
+            assert_eq!(decoded.timestamp, 1337);
+            assert_eq!(decoded.slab.get_payload(), large.slab.get_payload());
+        }
+
+        assert!(decode_published(&[]).is_err());
+        assert!(decode_published(&[9, 1, 2]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_slab() {
+        let tx = Transaction {
+            clear_inputs: vec![],
+            inputs: vec![],
+            outputs: vec![],
+            fee: 0,
+        };
+        let payload = serialize(&tx);
+        assert!(validate_slab(&Slab::new(payload.clone()), DEFAULT_MAX_SLAB_SIZE).is_ok());
+
+        // Over the limit, however valid
+        assert!(validate_slab(&Slab::new(payload.clone()), payload.len() - 1).is_err());
+
+        // Garbage that would only fail once it reached subscribers
+        assert!(validate_slab(&Slab::new(vec![1, 2, 3]), DEFAULT_MAX_SLAB_SIZE).is_err());
+        assert!(validate_slab(&Slab::new(vec![]), DEFAULT_MAX_SLAB_SIZE).is_err());
+    }
+
+    #[test]
+    fn test_protocol_version() -> Result<()> {
+        let version: ProtocolVersion = deserialize(&serialize(&GATEWAY_PROTOCOL_VERSION))?;
+        assert_eq!(version, GATEWAY_PROTOCOL_VERSION);
+        assert_eq!(ProtocolVersion { major: 1, minor: 7 }.to_string(), "1.7");
+
+        let newer_minor = ProtocolVersion {
+            minor: GATEWAY_PROTOCOL_VERSION.minor + 1,
+            ..GATEWAY_PROTOCOL_VERSION
+        };
+        let newer_major = ProtocolVersion {
+            major: GATEWAY_PROTOCOL_VERSION.major + 1,
+            ..GATEWAY_PROTOCOL_VERSION
+        };
+        assert!(GATEWAY_PROTOCOL_VERSION.is_compatible(&newer_minor));
+        assert!(!GATEWAY_PROTOCOL_VERSION.is_compatible(&newer_major));
+
+        // Gateways from before resume tokens aren't asked to resume
+        assert!(GATEWAY_PROTOCOL_VERSION.supports_resume());
+        assert!(!ProtocolVersion { major: 1, minor: 0 }.supports_resume());
+
+        assert!(GATEWAY_PROTOCOL_VERSION.supports_fee_hints());
+        assert!(!ProtocolVersion { major: 3, minor: 0 }.supports_fee_hints());
+
+        // Nor are older gateways pinged, they'd never answer
+        assert!(GATEWAY_PROTOCOL_VERSION.supports_ping());
+        assert!(!ProtocolVersion { major: 3, minor: 1 }.supports_ping());
+
+        assert!(GATEWAY_PROTOCOL_VERSION.supports_slab_ranges());
+        assert!(!ProtocolVersion { major: 3, minor: 2 }.supports_slab_ranges());
+
+        // Older gateways get unsigned submissions
+        assert!(GATEWAY_PROTOCOL_VERSION.supports_signed_submissions());
+        assert!(!ProtocolVersion { major: 3, minor: 3 }.supports_signed_submissions());
+
+        assert!(GATEWAY_PROTOCOL_VERSION.supports_slab_signatures());
+        assert!(!ProtocolVersion { major: 3, minor: 4 }.supports_slab_signatures());
+        Ok(())
+    }
+
+    #[test]
+    fn test_signed_submission() -> Result<()> {
+        let key = schnorr::SecretKey::random();
+        let publishers = vec![key.public_key().0];
+        let submission = |payload| SlabSubmission {
+            slab: Slab::new(payload),
+            fee_rate: 10,
+        };
+
+        let signed = SignedSubmission::sign(submission(vec![1, 2, 3]), &key, 1000);
+        let signed: SignedSubmission = deserialize(&serialize(&signed))?;
+        assert!(signed.verify(&publishers, 1000));
+        assert!(signed.verify(&publishers, 1000 + SIGNED_SUBMISSION_WINDOW));
+        assert_eq!(signed.submission.slab.get_payload(), vec![1, 2, 3]);
+
+        // Not once it's stale, nor from a key outside the allowlist
+        assert!(!signed.verify(&publishers, 1001 + SIGNED_SUBMISSION_WINDOW));
+        let stranger =
+            SignedSubmission::sign(submission(vec![1]), &schnorr::SecretKey::random(), 1000);
+        assert!(!stranger.verify(&publishers, 1000));
+
+        // Nor with the slab swapped under the signature
+        let mut forged = SignedSubmission::sign(submission(vec![1]), &key, 1000);
+        forged.submission = submission(vec![6, 6, 6]);
+        assert!(!forged.verify(&publishers, 1000));
+        Ok(())
+    }
+
+    #[test]
+    fn test_slab_signature() -> Result<()> {
+        let key = schnorr::SecretKey::random();
+        let gateways = vec![key.public_key().0];
+        let mut slab = Slab::new(vec![1, 2, 3]);
+        slab.set_index(7);
+
+        let signature = SlabSignature::sign(&slab, &key);
+        let signature: SlabSignature = deserialize(&serialize(&signature))?;
+        assert!(signature.verify(&slab, &gateways));
+
+        // Not from a gateway we don't know
+        let stranger = SlabSignature::sign(&slab, &schnorr::SecretKey::random());
+        assert!(!stranger.verify(&slab, &gateways));
+
+        // Nor for another payload or index
+        let mut rewritten = Slab::new(vec![6, 6, 6]);
+        rewritten.set_index(7);
+        assert!(!signature.verify(&rewritten, &gateways));
+        let mut moved = slab.clone();
+        moved.set_index(8);
+        assert!(!signature.verify(&moved, &gateways));
+
+        // The signature travels after the published slab, and unsigned
+        // slabs are encoded as before
+        let published = PublishedSlab {
+            slab: slab.clone(),
+            timestamp: 1337,
+            signature: Some(signature),
+        };
+        for compression in &[Compression::None, Compression::Zstd] {
+            let decoded = decode_published(&encode_published(&published, *compression)?)?;
+            assert_eq!(decoded.timestamp, 1337);
+            assert!(decoded.signature.unwrap().verify(&decoded.slab, &gateways));
+        }
+
+        let unsigned = PublishedSlab {
+            signature: None,
+            ..published
+        };
+        let encoded = encode_published(&unsigned, Compression::None)?;
+        assert_eq!(&encoded[1..], &serialize(&unsigned)[..]);
+        assert!(decode_published(&encoded)?.signature.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_slab_range() -> Result<()> {
+        let range = SlabRange { from: 1, to: 2000 };
+        assert_eq!(deserialize::<SlabRange>(&serialize(&range))?, range);
+
+        // One page at a time, and nothing past the gateway's last slab
+        assert_eq!(range.page_end(10_000), MAX_SLAB_RANGE);
+        assert_eq!(range.page_end(42), 42);
+        assert_eq!(
+            SlabRange {
+                from: 1990,
+                to: 2000
+            }
+            .page_end(10_000),
+            2000
+        );
+
+        // Nothing to send for a range past the last slab
+        assert!(SlabRange { from: 43, to: 50 }.page_end(42) < 43);
+        Ok(())
+    }
+
+    #[test]
+    fn test_slab_range_from_store() -> Result<()> {
+        let path = std::env::temp_dir().join("darkfi_test_gateway_slab_range");
+        let _ = std::fs::remove_dir_all(&path);
+        let slabstore = SlabStore::new(KvColumn::new(KvBackend::default().open(&path)?))?;
+        for index in 1..=5 {
+            let mut slab = Slab::new(vec![index as u8; 1024]);
+            slab.set_index(index);
+            slabstore.put(slab)?;
+        }
+        let size = serialize(&slabstore.get_value_deserialized(serialize(&1u64))?.unwrap()).len();
+
+        // Consecutive from `from`, up to the last slab stored
+        let slabs = slab_range(&slabstore, SlabRange { from: 2, to: 10 }, MAX_SLAB_RANGE_BYTES)?;
+        let indexes: Vec<u64> = slabs.iter().map(|slab| slab.get_index()).collect();
+        assert_eq!(indexes, vec![2, 3, 4, 5]);
+        assert_eq!(slabs[0].get_payload(), vec![2; 1024]);
+
+        // A page ends once it's full, but always has a slab
+        let slabs = slab_range(&slabstore, SlabRange { from: 1, to: 5 }, 2 * size + 1)?;
+        assert_eq!(slabs.len(), 2);
+        let slabs = slab_range(&slabstore, SlabRange { from: 1, to: 5 }, 1)?;
+        assert_eq!(slabs.len(), 1);
+
+        // The largest slabs fit a frame a page at a time
+        assert!(MAX_SLAB_RANGE_BYTES + DEFAULT_MAX_SLAB_SIZE < MAX_FRAME_SIZE);
+
+        drop(slabstore);
+        std::fs::remove_dir_all(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_resume_reply_encoding() -> Result<()> {
+        let reply = ResumeReply {
+            resumed: true,
+            token: ResumeToken {
+                session: 42,
+                cursor: 7,
+            },
+            slabs: vec![Slab::new(vec![1]), Slab::new(vec![2, 3])],
+        };
+        let decoded: ResumeReply = deserialize(&serialize(&reply))?;
+
+        assert!(decoded.resumed);
+        assert_eq!(decoded.token, reply.token);
+        let payloads: Vec<Vec<u8>> = decoded.slabs.iter().map(|s| s.get_payload()).collect();
+        assert_eq!(payloads, vec![vec![1], vec![2, 3]]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_gateway_list_fail_over() -> Result<()> {
+        assert!(GatewayList::new(vec![], TlsClientConfig::default(), None).is_err());
+
+        let url = |port: u16| Url::parse(&format!("tcp://127.0.0.1:{}", port)).unwrap();
+        let gateways = GatewayList::new(
+            vec![(url(1), url(2)), (url(3), url(4))],
+            TlsClientConfig::default(),
+            None,
+        )?;
+        assert_eq!(gateways.active(), 0);
+
+        assert_eq!(gateways.fail_over(0), 1);
+        // The subscription noticing the same failure doesn't skip a gateway
+        assert_eq!(gateways.fail_over(0), 1);
+        assert_eq!(gateways.get(gateways.active()).0, url(3));
+
+        // After the last gateway the first one is tried again
+        assert_eq!(gateways.fail_over(1), 0);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+//! Requests in flight on a shared connection, matched to their replies by
+//! id. Whichever request reads a reply off the connection hands it to the
+//! request it answers, so any number of them can wait on one connection.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+
+use crate::Result;
+
+pub struct InFlight<K, R> {
+    waiting: Mutex<HashMap<K, async_channel::Sender<R>>>,
+}
+
+impl<K: Clone + Eq + Hash, R> InFlight<K, R> {
+    pub fn new() -> Self {
+        Self {
+            waiting: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Wait for the reply to `id`. The request stops waiting when the
+    /// returned handle is dropped, e.g. once it timed out.
+    pub fn register(&self, id: K) -> Waiter<'_, K, R> {
+        let (send, reply) = async_channel::bounded(1);
+        self.waiting.lock().unwrap().insert(id.clone(), send);
+        Waiter {
+            in_flight: self,
+            id,
+            reply,
+        }
+    }
+
+    /// Hand a reply to the request it answers. Returns false if no
+    /// request waits for it, e.g. a stale reply to one that timed out.
+    pub fn deliver(&self, id: &K, reply: R) -> bool {
+        match self.waiting.lock().unwrap().remove(id) {
+            Some(send) => send.try_send(reply).is_ok(),
+            None => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.waiting.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K: Clone + Eq + Hash, R> Default for InFlight<K, R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Waiter<'a, K: Clone + Eq + Hash, R> {
+    in_flight: &'a InFlight<K, R>,
+    id: K,
+    reply: async_channel::Receiver<R>,
+}
+
+impl<'a, K: Clone + Eq + Hash, R> Waiter<'a, K, R> {
+    pub fn id(&self) -> &K {
+        &self.id
+    }
+
+    /// The reply, if another request delivered it already
+    pub fn try_take(&self) -> Option<R> {
+        self.reply.try_recv().ok()
+    }
+
+    /// Wait until another request delivers the reply
+    pub async fn recv(&self) -> Result<R> {
+        Ok(self.reply.recv().await?)
+    }
+}
+
+impl<'a, K: Clone + Eq + Hash, R> Drop for Waiter<'a, K, R> {
+    fn drop(&mut self) {
+        self.in_flight.waiting.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_in_flight() {
+        let in_flight: InFlight<u32, &str> = InFlight::new();
+
+        let first = in_flight.register(1);
+        let second = in_flight.register(2);
+        assert_eq!(in_flight.len(), 2);
+
+        // Replies reach their own request, whatever the order
+        assert!(in_flight.deliver(&2, "two"));
+        assert!(first.try_take().is_none());
+        assert_eq!(second.try_take(), Some("two"));
+        assert!(in_flight.deliver(&1, "one"));
+        assert_eq!(first.try_take(), Some("one"));
+        assert!(in_flight.is_empty());
+
+        // A request that gave up doesn't get its late reply
+        drop(in_flight.register(3));
+        assert!(in_flight.is_empty());
+        assert!(!in_flight.deliver(&3, "three"));
+    }
+}
@@ -1,7 +1,16 @@
-//pub mod cashier;
+pub mod admission;
 pub mod bridge;
+pub mod cashier;
+pub mod compression;
 pub mod gateway;
+pub mod in_flight;
+pub mod rate_limit;
+pub mod recording;
+pub mod replication;
 pub mod reqrep;
+pub mod socks5;
+pub mod tls;
+pub mod wire;
 
 #[cfg(feature = "btc")]
 pub mod btc;
@@ -13,4 +22,13 @@ pub mod sol;
 #[cfg(feature = "sol")]
 pub use sol::{SolClient, SolFailed, SolResult};
 
-pub use gateway::{GatewayClient, GatewayService, GatewaySlabsSubscriber};
+pub use cashier::{CashierClient, CashierHealth};
+pub use compression::Compression;
+pub use gateway::{
+    find_divergence, GatewayClient, GatewayLimits, GatewayList, GatewayReader, GatewayService,
+    GatewaySlabsSubscriber, GatewayStats, Heartbeat, ProtocolVersion, ResumeReply, ResumeToken,
+    SignedSubmission, SlabRange, SlabSignature, SlabSigner, GATEWAY_PROTOCOL_VERSION,
+};
+pub use recording::{read_recording, Frame, FrameKind, RecordingWriter, Replay};
+pub use socks5::Socks5Proxy;
+pub use tls::{certificate_fingerprint, server_identity, TlsClientConfig};
@@ -0,0 +1,232 @@
+//! Token buckets limiting how fast each client may use the gateway, so one
+//! misbehaving client can't starve the others. A client over its limit
+//! is refused until its bucket fills up again, and one that keeps going
+//! over it is disconnected.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// Milliseconds without a violation after which a client's past ones are
+/// forgotten, and a disconnected client may come back
+pub const VIOLATION_WINDOW_MILLIS: u64 = 60_000;
+
+/// `rate` tokens a second, and up to `burst` saved up while idle. A rate
+/// of zero doesn't limit anything.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    pub rate: u64,
+    pub burst: u64,
+}
+
+impl RateLimit {
+    pub fn unlimited() -> Self {
+        Self { rate: 0, burst: 0 }
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.rate == 0
+    }
+}
+
+pub struct TokenBucket {
+    limit: RateLimit,
+    // In thousandths of a token, so a millisecond's refill isn't lost
+    // to rounding
+    millitokens: u64,
+    updated: u64,
+}
+
+impl TokenBucket {
+    /// A full bucket at `now`, in milliseconds
+    pub fn new(limit: RateLimit, now: u64) -> Self {
+        Self {
+            limit,
+            millitokens: limit.burst.saturating_mul(1000),
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: u64) {
+        let elapsed = now.saturating_sub(self.updated);
+        self.millitokens = self
+            .millitokens
+            .saturating_add(elapsed.saturating_mul(self.limit.rate))
+            .min(self.limit.burst.saturating_mul(1000));
+        self.updated = self.updated.max(now);
+    }
+
+    /// Take `cost` tokens at `now` if the bucket has them
+    pub fn take(&mut self, cost: u64, now: u64) -> bool {
+        if self.limit.is_unlimited() {
+            return true;
+        }
+        self.refill(now);
+        let cost = cost.saturating_mul(1000);
+        if self.millitokens < cost {
+            return false;
+        }
+        self.millitokens -= cost;
+        true
+    }
+
+    fn is_full(&mut self, now: u64) -> bool {
+        self.refill(now);
+        self.millitokens >= self.limit.burst.saturating_mul(1000)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verdict {
+    Allow,
+    /// Over the limit, refuse this one
+    Throttle,
+    /// Over the limit too often, drop the client
+    Disconnect,
+    /// Dropped already and not quiet for long enough since
+    Disconnected,
+}
+
+struct ClientState {
+    bucket: TokenBucket,
+    violations: u32,
+    last_violation: u64,
+}
+
+/// A bucket for each client, keyed by connection or address
+pub struct RateLimiter<K> {
+    limit: RateLimit,
+    // Violations within the window after which a client is disconnected
+    max_violations: u32,
+    clients: HashMap<K, ClientState>,
+    pruned: u64,
+}
+
+impl<K: Clone + Eq + Hash> RateLimiter<K> {
+    pub fn new(limit: RateLimit, max_violations: u32) -> Self {
+        Self {
+            limit,
+            max_violations,
+            clients: HashMap::new(),
+            pruned: 0,
+        }
+    }
+
+    /// Charge `client` `cost` tokens at `now`, in milliseconds. Once it's
+    /// told to disconnect, a client stays refused until it keeps quiet
+    /// for the violation window.
+    pub fn check(&mut self, client: &K, cost: u64, now: u64) -> Verdict {
+        if self.limit.is_unlimited() {
+            return Verdict::Allow;
+        }
+        if now.saturating_sub(self.pruned) >= VIOLATION_WINDOW_MILLIS {
+            self.prune(now);
+        }
+
+        let limit = self.limit;
+        let state = self
+            .clients
+            .entry(client.clone())
+            .or_insert_with(|| ClientState {
+                bucket: TokenBucket::new(limit, now),
+                violations: 0,
+                last_violation: 0,
+            });
+
+        if state.violations > 0
+            && now.saturating_sub(state.last_violation) >= VIOLATION_WINDOW_MILLIS
+        {
+            state.violations = 0;
+        }
+        if state.violations >= self.max_violations {
+            state.last_violation = now;
+            return Verdict::Disconnected;
+        }
+        // A chunk larger than the burst could never pass otherwise
+        if state.bucket.take(cost.min(limit.burst), now) {
+            return Verdict::Allow;
+        }
+
+        state.violations += 1;
+        state.last_violation = now;
+        if state.violations >= self.max_violations {
+            Verdict::Disconnect
+        } else {
+            Verdict::Throttle
+        }
+    }
+
+    // Forget the clients with a full bucket and no recent violations,
+    // which are as good as new
+    fn prune(&mut self, now: u64) {
+        self.clients.retain(|_, state| {
+            now.saturating_sub(state.last_violation) < VIOLATION_WINDOW_MILLIS
+                || !state.bucket.is_full(now)
+        });
+        self.pruned = now;
+    }
+
+    /// Number of clients tracked
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(RateLimit { rate: 2, burst: 4 }, 0);
+
+        // The burst, then nothing until it refills at 2 tokens a second
+        assert!(bucket.take(4, 0));
+        assert!(!bucket.take(1, 0));
+        assert!(!bucket.take(1, 499));
+        assert!(bucket.take(1, 500));
+        assert!(bucket.take(2, 1500));
+
+        // Idling saves up no more than the burst
+        assert!(bucket.take(4, 100_000));
+        assert!(!bucket.take(1, 100_000));
+
+        let mut unlimited = TokenBucket::new(RateLimit::unlimited(), 0);
+        assert!(unlimited.take(u64::MAX, 0));
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(RateLimit { rate: 1, burst: 2 }, 3);
+
+        // Clients have buckets of their own
+        assert_eq!(limiter.check(&"a", 2, 0), Verdict::Allow);
+        assert_eq!(limiter.check(&"b", 2, 0), Verdict::Allow);
+
+        // Going over the limit is refused, and too often disconnects
+        assert_eq!(limiter.check(&"a", 1, 0), Verdict::Throttle);
+        assert_eq!(limiter.check(&"a", 1, 10), Verdict::Throttle);
+        assert_eq!(limiter.check(&"a", 1, 20), Verdict::Disconnect);
+        assert_eq!(limiter.check(&"b", 1, 1000), Verdict::Allow);
+
+        // The disconnected client stays refused while it keeps trying
+        assert_eq!(limiter.check(&"a", 1, 30_000), Verdict::Disconnected);
+        assert_eq!(limiter.check(&"a", 1, 60_000), Verdict::Disconnected);
+        assert_eq!(
+            limiter.check(&"a", 1, 60_000 + VIOLATION_WINDOW_MILLIS),
+            Verdict::Allow
+        );
+
+        // Quiet clients are forgotten
+        let later = 10 * VIOLATION_WINDOW_MILLIS;
+        assert_eq!(limiter.check(&"c", 1, later), Verdict::Allow);
+        assert_eq!(limiter.len(), 1);
+
+        let mut unlimited = RateLimiter::new(RateLimit::unlimited(), 1);
+        assert_eq!(unlimited.check(&"a", 1_000_000, 0), Verdict::Allow);
+        assert!(unlimited.is_empty());
+    }
+}
@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Cursor, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use super::reqrep::{Reply, Request};
+use crate::serial::{deserialize, serialize, Decodable, Encodable};
+use crate::{Error, Result};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameKind {
+    /// Request from the client to the gateway
+    Request,
+    /// Reply from the gateway to the client
+    Reply,
+    /// Message the gateway published to its subscribers
+    Publish,
+}
+
+impl FrameKind {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            FrameKind::Request => 0,
+            FrameKind::Reply => 1,
+            FrameKind::Publish => 2,
+        }
+    }
+
+    pub fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            0 => Some(FrameKind::Request),
+            1 => Some(FrameKind::Reply),
+            2 => Some(FrameKind::Publish),
+            _ => None,
+        }
+    }
+}
+
+/// A frame of the traffic between a client and a gateway, stamped with
+/// the milliseconds since the recording started
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub millis: u64,
+    pub kind: FrameKind,
+    pub data: Vec<u8>,
+}
+
+impl Encodable for Frame {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.millis.encode(&mut s)?;
+        len += self.kind.to_u8().encode(&mut s)?;
+        len += self.data.encode(s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for Frame {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        let millis = Decodable::decode(&mut d)?;
+        let kind = FrameKind::from_u8(Decodable::decode(&mut d)?)
+            .ok_or(Error::ParseFailed("Invalid recording frame kind"))?;
+        Ok(Self {
+            millis,
+            kind,
+            data: Decodable::decode(d)?,
+        })
+    }
+}
+
+/// Appends frames to a recording file
+pub struct RecordingWriter {
+    file: BufWriter<File>,
+    started: Instant,
+}
+
+impl RecordingWriter {
+    pub fn create(path: &Path) -> Result<Self> {
+        Ok(Self {
+            file: BufWriter::new(File::create(path)?),
+            started: Instant::now(),
+        })
+    }
+
+    /// Every frame is flushed, so the recording is complete up to the
+    /// last frame even when the proxy is killed
+    pub fn write(&mut self, kind: FrameKind, data: Vec<u8>) -> Result<()> {
+        let frame = Frame {
+            millis: self.started.elapsed().as_millis() as u64,
+            kind,
+            data,
+        };
+        self.file.write_all(&serialize(&frame))?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+pub fn read_recording(path: &Path) -> Result<Vec<Frame>> {
+    let data = fs::read(path)?;
+    let len = data.len() as u64;
+    let mut cursor = Cursor::new(data);
+
+    let mut frames = vec![];
+    while cursor.position() < len {
+        frames.push(Frame::decode(&mut cursor)?);
+    }
+    Ok(frames)
+}
+
+/// A recording laid out for playing the gateway's side of it back
+pub struct Replay {
+    // Recorded reply for each command and payload, the latest one if the
+    // same request was made several times
+    replies: HashMap<(u8, Vec<u8>), Reply>,
+    // Published messages with the time they were published
+    publishes: Vec<(u64, Vec<u8>)>,
+    // Time of the first request, when the client started talking
+    start: u64,
+}
+
+impl Replay {
+    pub fn new(frames: &[Frame]) -> Result<Self> {
+        let mut requests: HashMap<u32, Request> = HashMap::new();
+        let mut replies = HashMap::new();
+        let mut publishes = vec![];
+        let mut start = None;
+
+        for frame in frames {
+            match frame.kind {
+                FrameKind::Request => {
+                    let request: Request = deserialize(&frame.data)?;
+                    start.get_or_insert(frame.millis);
+                    requests.insert(request.get_id(), request);
+                }
+                FrameKind::Reply => {
+                    let reply: Reply = deserialize(&frame.data)?;
+                    if let Some(request) = requests.remove(&reply.get_id()) {
+                        replies.insert((request.get_command(), request.get_payload()), reply);
+                    }
+                }
+                FrameKind::Publish => publishes.push((frame.millis, frame.data.clone())),
+            }
+        }
+
+        Ok(Self {
+            replies,
+            publishes,
+            start: start.unwrap_or(0),
+        })
+    }
+
+    /// The recorded reply to the same request, addressed to this one
+    pub fn reply(&self, request: &Request) -> Option<Reply> {
+        let recorded = self
+            .replies
+            .get(&(request.get_command(), request.get_payload()))?;
+        Some(Reply::from(
+            request,
+            recorded.get_error(),
+            recorded.get_payload(),
+        ))
+    }
+
+    /// Published messages with how long after the client's first request
+    /// to publish them, sped up by the given factor. Messages published
+    /// before the client connected go out right away.
+    pub fn publish_schedule(&self, speed: f64) -> Vec<(Duration, Vec<u8>)> {
+        self.publishes
+            .iter()
+            .map(|(millis, data)| {
+                let offset = millis.saturating_sub(self.start) as f64 / speed;
+                (Duration::from_millis(offset as u64), data.clone())
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recording_replay() -> Result<()> {
+        let path = std::env::temp_dir().join("darkfi_test_recording.bin");
+
+        let get_slab = Request::new(1, serialize(&7u64));
+        let get_missing = Request::new(1, serialize(&8u64));
+        let frames = vec![
+            Frame {
+                millis: 10,
+                kind: FrameKind::Publish,
+                data: vec![1],
+            },
+            Frame {
+                millis: 100,
+                kind: FrameKind::Request,
+                data: serialize(&get_slab),
+            },
+            Frame {
+                millis: 120,
+                kind: FrameKind::Reply,
+                data: serialize(&Reply::from(&get_slab, 0, vec![7; 4])),
+            },
+            Frame {
+                millis: 200,
+                kind: FrameKind::Request,
+                data: serialize(&get_missing),
+            },
+            Frame {
+                millis: 210,
+                kind: FrameKind::Reply,
+                data: serialize(&Reply::from(&get_missing, 2, vec![])),
+            },
+            Frame {
+                millis: 2100,
+                kind: FrameKind::Publish,
+                data: vec![2],
+            },
+        ];
+
+        let mut file = File::create(&path)?;
+        for frame in &frames {
+            file.write_all(&serialize(frame))?;
+        }
+        drop(file);
+        assert_eq!(read_recording(&path)?, frames);
+        fs::remove_file(&path)?;
+
+        let replay = Replay::new(&frames)?;
+
+        // Replies keep the id of the replayed request
+        let request = Request::new(1, serialize(&7u64));
+        let reply = replay.reply(&request).unwrap();
+        assert_eq!(reply.get_id(), request.get_id());
+        assert_eq!(reply.get_payload(), vec![7; 4]);
+        assert!(!reply.has_error());
+
+        let reply = replay.reply(&Request::new(1, serialize(&8u64))).unwrap();
+        assert_eq!(reply.get_error(), 2);
+
+        assert!(replay.reply(&Request::new(2, vec![])).is_none());
+
+        assert_eq!(
+            replay.publish_schedule(1.0),
+            vec![
+                (Duration::from_millis(0), vec![1]),
+                (Duration::from_millis(2000), vec![2])
+            ]
+        );
+        assert_eq!(
+            replay.publish_schedule(4.0)[1],
+            (Duration::from_millis(500), vec![2])
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+//! Gateways replicating each other. A gateway configured with peers
+//! follows the first one of them it can reach: it copies the peer's slabs
+//! into its own store and publishes them to its own subscribers, and it
+//! passes the slabs submitted to it on to the peer. Slabs so get their
+//! index in one place, and clients see the same slab log whichever
+//! gateway they use. A gateway whose log disagrees with its peer's moves
+//! on to the next peer rather than rewrite its history.
+
+use async_std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use log::{debug, warn};
+use url::Url;
+
+use super::compression::Compression;
+use super::gateway::{
+    encode_published, resolve, unix_millis, GatewayReader, PublishedSlab, SlabSigner,
+    GATEWAY_TIMEOUT,
+};
+use super::reqrep::{Reply, ReqProtocol, Request};
+use crate::blockchain::SlabStore;
+use crate::net::utility::sleep;
+use crate::serial::serialize;
+use crate::{Error, Result};
+
+/// Seconds between checks of the peer's slab inventory
+pub const REPLICATION_INTERVAL: u32 = 2;
+
+pub struct Replication {
+    peers: Vec<Url>,
+    // The peer followed
+    active: AtomicUsize,
+    // The connection submissions are passed on over
+    upstream: Mutex<Option<Arc<ReqProtocol>>>,
+}
+
+impl Replication {
+    /// Protocol urls of the gateways to follow, the first one preferred
+    pub fn new(peers: Vec<Url>) -> Result<Arc<Self>> {
+        if peers.is_empty() {
+            return Err(Error::ServicesError("No peers to replicate from"));
+        }
+        Ok(Arc::new(Self {
+            peers,
+            active: AtomicUsize::new(0),
+            upstream: Mutex::new(None),
+        }))
+    }
+
+    fn peer(&self) -> &Url {
+        &self.peers[self.active.load(Ordering::SeqCst)]
+    }
+
+    async fn fail_over(&self) {
+        *self.upstream.lock().await = None;
+        let next = (self.active.load(Ordering::SeqCst) + 1) % self.peers.len();
+        self.active.store(next, Ordering::SeqCst);
+        debug!(target: "GATEWAY REPLICATION", "Following {} now", self.peer());
+    }
+
+    /// Pass a request on to the peer followed, and return its reply
+    pub async fn forward(&self, request: &Request) -> Result<Reply> {
+        let upstream = self.upstream().await?;
+        match upstream.forward(request).await {
+            Ok(reply) => Ok(reply),
+            Err(e) => {
+                self.fail_over().await;
+                Err(e)
+            }
+        }
+    }
+
+    async fn upstream(&self) -> Result<Arc<ReqProtocol>> {
+        let mut upstream = self.upstream.lock().await;
+        if let Some(protocol) = upstream.as_ref() {
+            return Ok(protocol.clone());
+        }
+
+        let mut protocol =
+            ReqProtocol::new(resolve(self.peer())?, String::from("GATEWAY REPLICATION"));
+        protocol.set_timeout(GATEWAY_TIMEOUT);
+        protocol.start().await?;
+        let protocol = Arc::new(protocol);
+        *upstream = Some(protocol.clone());
+        Ok(protocol)
+    }
+
+    /// Keep copying the slabs the peer has and this gateway doesn't,
+    /// publishing each one as it's stored
+    pub async fn run(
+        self: Arc<Self>,
+        slabstore: Arc<SlabStore>,
+        signer: Option<Arc<SlabSigner>>,
+        compression: Compression,
+        publish_queue: async_channel::Sender<Vec<u8>>,
+    ) -> Result<()> {
+        let mut reader: Option<GatewayReader> = None;
+        loop {
+            let replicated = self
+                .replicate(
+                    &mut reader,
+                    &slabstore,
+                    signer.as_deref(),
+                    compression,
+                    &publish_queue,
+                )
+                .await;
+            if let Err(e) = replicated {
+                warn!(
+                    target: "GATEWAY REPLICATION",
+                    "Can't replicate from {}: {}",
+                    self.peer(),
+                    e
+                );
+                reader = None;
+                self.fail_over().await;
+            }
+
+            sleep(REPLICATION_INTERVAL).await;
+        }
+    }
+
+    async fn replicate(
+        &self,
+        reader: &mut Option<GatewayReader>,
+        slabstore: &SlabStore,
+        signer: Option<&SlabSigner>,
+        compression: Compression,
+        publish_queue: &async_channel::Sender<Vec<u8>>,
+    ) -> Result<()> {
+        if reader.is_none() {
+            let mut peer = GatewayReader::new(self.peer().clone())?;
+            peer.start().await?;
+            *reader = Some(peer);
+        }
+        let peer = reader
+            .as_mut()
+            .ok_or(Error::ServicesError("Not connected to the peer"))?;
+        Self::catch_up(peer, slabstore, signer, compression, publish_queue).await
+    }
+
+    // Compare inventories, the last index and the hash of our last slab,
+    // and fetch what we're missing. Copied slabs are signed with our key
+    // like the ones submitted to us.
+    async fn catch_up(
+        peer: &mut GatewayReader,
+        slabstore: &SlabStore,
+        signer: Option<&SlabSigner>,
+        compression: Compression,
+        publish_queue: &async_channel::Sender<Vec<u8>>,
+    ) -> Result<()> {
+        let peer_last_index = peer.get_last_index().await?;
+        let last_index = slabstore.get_last_index()?;
+
+        if last_index > peer_last_index {
+            return Err(Error::ServicesError("Peer is behind this gateway"));
+        }
+        if last_index > 0 {
+            let ours = slabstore
+                .get_value_deserialized(serialize(&last_index))?
+                .map(|slab| slab.hash());
+            if ours != peer.get_slab_hash(last_index).await? {
+                return Err(Error::SlabsStore(format!(
+                    "Slab {} differs from the peer's",
+                    last_index
+                )));
+            }
+        }
+
+        let mut index = last_index + 1;
+        while index <= peer_last_index {
+            let slabs = peer.get_slabs(index, peer_last_index).await?;
+            if slabs.is_empty() {
+                break;
+            }
+
+            for slab in slabs {
+                if slab.get_index() != index {
+                    return Err(Error::ServicesError("Peer sent slabs out of order"));
+                }
+                // Only a slab taking the next index is stored, so a
+                // concurrent write can't fork the log
+                if slabstore.put(slab.clone())?.is_none() {
+                    return Ok(());
+                }
+
+                let signature = signer.map(|signer| signer.sign(&slab)).transpose()?;
+                let published = PublishedSlab {
+                    slab,
+                    timestamp: unix_millis(),
+                    signature,
+                };
+                publish_queue
+                    .send(encode_published(&published, compression)?)
+                    .await?;
+                index += 1;
+            }
+        }
+
+        if index > last_index + 1 {
+            debug!(
+                target: "GATEWAY REPLICATION",
+                "Replicated slabs {} to {}",
+                last_index + 1,
+                index - 1
+            );
+        }
+        Ok(())
+    }
+}
@@ -4,6 +4,7 @@ use std::io;
 use std::net::SocketAddr;
 
 use async_executor::Executor;
+use async_std::sync::Mutex;
 use bytes::Bytes;
 use futures::FutureExt;
 use log::*;
@@ -11,8 +12,15 @@ use rand::Rng;
 use signal_hook::{consts::SIGINT, iterator::Signals};
 use zeromq::*;
 
+use super::in_flight::{InFlight, Waiter};
+use super::recording::FrameKind;
+use super::wire::{decode_frame, encode_frame};
+use crate::net::utility::sleep;
 use crate::serial::{deserialize, serialize};
-use crate::{Decodable, Encodable, Result};
+use crate::{Decodable, Encodable, Error, Result};
+
+/// Seconds a request may go unanswered before it fails
+pub const REQUEST_TIMEOUT: u32 = 30;
 
 pub type PeerId = Vec<u8>;
 
@@ -27,6 +35,11 @@ enum NetEvent {
     Stop,
 }
 
+enum ReqEvent {
+    Receive(zeromq::ZmqMessage),
+    Send(Bytes),
+}
+
 pub fn addr_to_string(addr: SocketAddr) -> String {
     format!("tcp://{}", addr.to_string())
 }
@@ -97,16 +110,26 @@ impl RepProtocol {
                 NetEvent::Receive(msg) => {
                     if let Some(peer) = msg.get(0) {
                         if let Some(request) = msg.get(1) {
-                            let request: Vec<u8> = request.to_vec();
-                            let request: Request = deserialize(&request)?;
-                            self.send_queue.send((peer.to_vec(), request)).await?;
+                            // A bad frame is dropped, the requester
+                            // times out and tries again
+                            let request = decode_frame(FrameKind::Request, &request.to_vec())
+                                .and_then(|request| deserialize::<Request>(&request));
+                            match request {
+                                Ok(request) => {
+                                    self.send_queue.send((peer.to_vec(), request)).await?
+                                }
+                                Err(e) => warn!(
+                                    target: "REP PROTOCOL API",
+                                    "{} SERVICE: Dropped request: {}", self.service_name, e
+                                ),
+                            }
                         }
                     }
                 }
                 NetEvent::Send((peer, reply)) => {
                     let peer = Bytes::from(peer);
                     let mut msg: Vec<Bytes> = vec![peer];
-                    let reply: Vec<u8> = serialize(&reply);
+                    let reply = encode_frame(FrameKind::Reply, &serialize(&reply))?;
                     let reply = Bytes::from(reply);
                     msg.push(reply);
 
@@ -124,75 +147,151 @@ impl RepProtocol {
     }
 }
 
+/// The requesting side of a gateway connection. It can be shared, with
+/// any number of requests in flight at once: each waits for the reply
+/// with its own id, and fails once it waited longer than the timeout.
 pub struct ReqProtocol {
     addr: SocketAddr,
-    socket: zeromq::DealerSocket,
+    socket: Mutex<zeromq::DealerSocket>,
+    // Requests waiting for whoever reads the socket to send them
+    outgoing: (async_channel::Sender<Bytes>, async_channel::Receiver<Bytes>),
+    in_flight: InFlight<u32, Reply>,
+    timeout: u32,
     service_name: String,
 }
 
 impl ReqProtocol {
     pub fn new(addr: SocketAddr, service_name: String) -> ReqProtocol {
-        let socket = zeromq::DealerSocket::new();
+        let socket = Mutex::new(zeromq::DealerSocket::new());
         ReqProtocol {
             addr,
             socket,
+            outgoing: async_channel::unbounded(),
+            in_flight: InFlight::new(),
+            timeout: REQUEST_TIMEOUT,
             service_name,
         }
     }
 
+    /// Seconds a request may go unanswered, REQUEST_TIMEOUT by default
+    pub fn set_timeout(&mut self, seconds: u32) {
+        self.timeout = seconds;
+    }
+
     pub async fn start(&mut self) -> Result<()> {
         let addr = addr_to_string(self.addr);
-        self.socket.connect(addr.as_str()).await?;
+        self.socket.get_mut().connect(addr.as_str()).await?;
         debug!(target: "REQ PROTOCOL API","{} SERVICE: Connected To {}", self.service_name, self.addr);
         Ok(())
     }
 
     pub async fn request(
-        &mut self,
+        &self,
         command: u8,
         data: Vec<u8>,
         handle_error: Arc<dyn Fn(u32) + Send + Sync>,
     ) -> Result<Option<Vec<u8>>> {
         let request = Request::new(command, data);
-        let req = serialize(&request);
-        let req = bytes::Bytes::from(req);
-        let req: zeromq::ZmqMessage = req.into();
-
-        self.socket.send(req).await?;
         debug!(
         target: "REQ PROTOCOL API",
                 "{} SERVICE: Sent Request {{ command: {} }}",
                 self.service_name, command
             );
 
-        let rep: zeromq::ZmqMessage = self.socket.recv().await?;
-        if let Some(reply) = rep.get(0) {
-            let reply: Vec<u8> = reply.to_vec();
+        let reply = self.exchange(&request).await?;
+        debug!(
+        target: "REQ PROTOCOL API",
+                "{} SERVICE: Received Reply {{ error: {} }}",
+                self.service_name,
+                reply.has_error()
+            );
 
-            let reply: Reply = deserialize(&reply)?;
+        if reply.has_error() {
+            handle_error(reply.get_error());
+            return Ok(None);
+        }
 
-            debug!(
-            target: "REQ PROTOCOL API",
-                    "{} SERVICE: Received Reply {{ error: {} }}",
-                    self.service_name,
-                    reply.has_error()
-                );
+        Ok(Some(reply.get_payload()))
+    }
 
-            if reply.has_error() {
-                handle_error(reply.get_error());
-                return Ok(None);
-            }
+    /// Send a request as it is, keeping its id, and return the reply
+    /// whatever its error, e.g. to pass requests on from a proxy
+    pub async fn forward(&self, request: &Request) -> Result<Reply> {
+        self.exchange(request).await
+    }
 
-            if reply.get_id() != request.get_id() {
-                warn!("Reply id is not equal to Request id");
-                return Ok(None);
+    // Send a request and wait for its reply, or fail with
+    // Error::RequestTimeout
+    async fn exchange(&self, request: &Request) -> Result<Reply> {
+        let waiter = self.in_flight.register(request.get_id());
+        let frame = encode_frame(FrameKind::Request, &serialize(request))?;
+        self.outgoing.0.send(Bytes::from(frame)).await?;
+
+        futures::select! {
+            reply = self.wait_for(&waiter).fuse() => reply,
+            _ = sleep(self.timeout).fuse() => Err(Error::RequestTimeout(format!(
+                "{} service got no reply to command {} in {} seconds",
+                self.service_name,
+                request.get_command(),
+                self.timeout
+            ))),
+        }
+    }
+
+    // Until its reply comes in, a request takes its turn reading the
+    // socket whenever no other request does. Meanwhile it passes the
+    // replies to other requests on, and sends the ones that were queued.
+    async fn wait_for(&self, waiter: &Waiter<'_, u32, Reply>) -> Result<Reply> {
+        loop {
+            let mut socket = futures::select! {
+                reply = waiter.recv().fuse() => return reply,
+                socket = self.socket.lock().fuse() => socket,
+            };
+            // The reply may have come in before the socket was free
+            if let Some(reply) = waiter.try_take() {
+                return Ok(reply);
             }
 
-            Ok(Some(reply.get_payload()))
-        } else {
-            Err(crate::Error::ZmqError(
-                "Couldn't parse ZmqMessage".to_string(),
-            ))
+            loop {
+                let event = futures::select! {
+                    msg = socket.recv().fuse() => ReqEvent::Receive(msg?),
+                    msg = self.outgoing.1.recv().fuse() => ReqEvent::Send(msg?),
+                };
+
+                match event {
+                    ReqEvent::Send(msg) => socket.send(msg.into()).await?,
+                    ReqEvent::Receive(msg) => {
+                        let reply = match msg.get(0) {
+                            Some(reply) => decode_frame(FrameKind::Reply, &reply.to_vec())
+                                .and_then(|reply| deserialize::<Reply>(&reply)),
+                            None => Err(Error::ZmqError("Couldn't parse ZmqMessage".to_string())),
+                        };
+                        // A bad reply is dropped, and its request times out
+                        let reply = match reply {
+                            Ok(reply) => reply,
+                            Err(e) => {
+                                warn!(
+                                    target: "REQ PROTOCOL API",
+                                    "{} SERVICE: Dropped reply: {}", self.service_name, e
+                                );
+                                continue;
+                            }
+                        };
+
+                        if reply.get_id() == *waiter.id() {
+                            return Ok(reply);
+                        }
+                        let id = reply.get_id();
+                        if !self.in_flight.deliver(&id, reply) {
+                            warn!(
+                                target: "REQ PROTOCOL API",
+                                "{} SERVICE: Dropped reply to request {} which is no longer waiting",
+                                self.service_name, id
+                            );
+                        }
+                    }
+                }
+            }
         }
     }
 }
@@ -228,7 +327,7 @@ impl Publisher {
     }
 
     async fn publish(&mut self, data: Vec<u8>) -> Result<()> {
-        let data = Bytes::from(data);
+        let data = Bytes::from(encode_frame(FrameKind::Publish, &data)?);
         self.socket.send(data.into()).await?;
         Ok(())
     }
@@ -264,16 +363,32 @@ impl Subscriber {
     }
 
     pub async fn fetch<T: Decodable>(&mut self) -> Result<T> {
-        let data = self.socket.recv().await?;
-        match data.get(0) {
-            Some(d) => {
-                let data = d.to_vec();
-                let data: T = deserialize(&data)?;
-                Ok(data)
+        let data = self.fetch_bytes().await?;
+        let data: T = deserialize(&data)?;
+        Ok(data)
+    }
+
+    /// Fetch a published message without decoding it. Bad frames are
+    /// skipped; a slab lost with one shows up as a gap.
+    pub async fn fetch_bytes(&mut self) -> Result<Vec<u8>> {
+        loop {
+            let data = self.socket.recv().await?;
+            let frame = match data.get(0) {
+                Some(d) => d.to_vec(),
+                None => {
+                    return Err(crate::Error::ZmqError(
+                        "Couldn't parse ZmqMessage".to_string(),
+                    ))
+                }
+            };
+
+            match decode_frame(FrameKind::Publish, &frame) {
+                Ok(payload) => return Ok(payload),
+                Err(e) => warn!(
+                    target: "SUBSCRIBER API",
+                    "{} SERVICE : Dropped published message: {}", self.service_name, e
+                ),
             }
-            None => Err(crate::Error::ZmqError(
-                "Couldn't parse ZmqMessage".to_string(),
-            )),
         }
     }
 }
@@ -0,0 +1,199 @@
+//! Outbound connections through a SOCKS5 proxy (RFC 1928), such as the
+//! one Tor listens on. Host names are passed to the proxy unresolved, so
+//! no DNS lookups leak past it either.
+
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+
+use futures::io::{AsyncReadExt, AsyncWriteExt};
+use log::debug;
+use smol::Async;
+use url::Url;
+
+use crate::{Error, Result};
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Socks5Proxy {
+    addr: SocketAddr,
+}
+
+impl Socks5Proxy {
+    /// A proxy given as socks5://host:port
+    pub fn parse(url: &str) -> Result<Self> {
+        let url = Url::parse(url)?;
+        if url.scheme() != "socks5" {
+            return Err(Error::UrlParseError);
+        }
+        let host = url.host_str().ok_or(Error::UrlParseError)?;
+        let port = url.port().ok_or(Error::UrlParseError)?;
+        let addr = (host, port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or(Error::UrlParseError)?;
+        Ok(Self { addr })
+    }
+
+    /// Open a connection to `host`:`port` through the proxy
+    pub async fn connect(&self, host: &str, port: u16) -> Result<Async<TcpStream>> {
+        debug!(target: "SOCKS5", "Connecting to {}:{} through {}", host, port, self.addr);
+        let mut stream = Async::<TcpStream>::connect(self.addr).await?;
+
+        stream.write_all(&[VERSION, 1, NO_AUTH]).await?;
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).await?;
+        if reply != [VERSION, NO_AUTH] {
+            return Err(Error::ProxyFailed(
+                "Proxy requires an unsupported authentication method".to_string(),
+            ));
+        }
+
+        stream.write_all(&connect_request(host, port)?).await?;
+
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != VERSION {
+            return Err(Error::ProxyFailed("Not a SOCKS5 proxy".to_string()));
+        }
+        if reply[1] != 0 {
+            return Err(Error::ProxyFailed(format!(
+                "Proxy couldn't reach {}:{}: {}",
+                host,
+                port,
+                reply_error(reply[1])
+            )));
+        }
+
+        // The address the proxy bound, which is of no use here
+        let bound = match reply[3] {
+            ATYP_IPV4 => 4,
+            ATYP_IPV6 => 16,
+            ATYP_DOMAIN => {
+                let mut len = [0u8; 1];
+                stream.read_exact(&mut len).await?;
+                len[0] as usize
+            }
+            _ => return Err(Error::ProxyFailed("Malformed proxy reply".to_string())),
+        };
+        let mut skip = vec![0u8; bound + 2];
+        stream.read_exact(&mut skip).await?;
+
+        Ok(stream)
+    }
+}
+
+fn connect_request(host: &str, port: u16) -> Result<Vec<u8>> {
+    let mut request = vec![VERSION, CONNECT, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(ATYP_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(ATYP_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            if host.is_empty() || host.len() > 255 {
+                return Err(Error::UrlParseError);
+            }
+            request.push(ATYP_DOMAIN);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    Ok(request)
+}
+
+fn reply_error(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "connection not allowed",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+/// Connect to `host`:`port` directly, or through the proxy if there is one
+pub async fn connect(
+    proxy: Option<&Socks5Proxy>,
+    host: &str,
+    port: u16,
+) -> Result<Async<TcpStream>> {
+    match proxy {
+        Some(proxy) => proxy.connect(host, port).await,
+        None => {
+            let socket_addr = {
+                let host = host.to_string();
+                smol::unblock(move || (host.as_str(), port).to_socket_addrs())
+                    .await?
+                    .next()
+                    .ok_or(Error::UrlParseError)?
+            };
+            Ok(Async::<TcpStream>::connect(socket_addr).await?)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_socks5_connect() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let proxy = Socks5Proxy::parse(&format!("socks5://{}", listener.local_addr()?))?;
+
+        // A proxy that checks the request and echoes what follows it
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).unwrap();
+            assert_eq!(greeting, [VERSION, 1, NO_AUTH]);
+            stream.write_all(&[VERSION, NO_AUTH]).unwrap();
+
+            let mut request = vec![0u8; 5 + "gateway.onion".len() + 2];
+            stream.read_exact(&mut request).unwrap();
+            assert_eq!(request, connect_request("gateway.onion", 3333).unwrap());
+            stream
+                .write_all(&[VERSION, 0, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+                .unwrap();
+
+            let mut ping = [0u8; 4];
+            stream.read_exact(&mut ping).unwrap();
+            stream.write_all(&ping).unwrap();
+        });
+
+        smol::future::block_on(async {
+            let mut stream = proxy.connect("gateway.onion", 3333).await?;
+            stream.write_all(b"ping").await?;
+            let mut pong = [0u8; 4];
+            stream.read_exact(&mut pong).await?;
+            assert_eq!(&pong, b"ping");
+            Ok::<(), Error>(())
+        })?;
+        server.join().unwrap();
+
+        assert_eq!(
+            connect_request("127.0.0.1", 80)?,
+            vec![VERSION, CONNECT, 0, ATYP_IPV4, 127, 0, 0, 1, 0, 80]
+        );
+        assert!(Socks5Proxy::parse("http://127.0.0.1:9050").is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,605 @@
+//! TLS for the links between darkfid, gatewayd and cashierd.
+//!
+//! Cashiers speak TLS themselves. The gateway's zmq sockets can't, so
+//! gatewayd terminates TLS in front of them and clients reach them
+//! through a local tunnel per connection. Connections going through a
+//! SOCKS5 proxy reach zmq the same way.
+
+use async_std::sync::Arc;
+use std::collections::HashMap;
+use std::io::BufReader;
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use async_executor::Executor;
+use async_rustls::{client::TlsStream, TlsAcceptor, TlsConnector};
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use log::{debug, warn};
+use rustls::internal::pemfile;
+use rustls::Session;
+use sha2::{Digest, Sha256};
+use smol::Async;
+use url::Url;
+
+use super::gateway::unix_millis;
+use super::rate_limit::{RateLimit, RateLimiter, Verdict};
+use super::socks5::{self, Socks5Proxy};
+use crate::net::utility::sleep;
+use crate::util::expand_path;
+use crate::{Error, Result};
+
+/// Hex encoded SHA-256 of a DER encoded certificate, as pinned in the
+/// configs. `openssl x509 -in cert.pem -outform der | sha256sum` gives
+/// the same.
+pub fn certificate_fingerprint(der: &[u8]) -> String {
+    hex::encode(Sha256::digest(der))
+}
+
+// The certificates in a PEM file
+fn load_certs(path: &Path) -> Result<Vec<rustls::Certificate>> {
+    let mut reader = BufReader::new(std::fs::File::open(path)?);
+    match pemfile::certs(&mut reader) {
+        Ok(certs) if !certs.is_empty() => Ok(certs),
+        _ => Err(Error::TlsFailed(format!("No certificate in {}", path.display()))),
+    }
+}
+
+// The first PKCS#8 or RSA key in a PEM file
+fn load_key(path: &Path) -> Result<rustls::PrivateKey> {
+    let pem = std::fs::read(path)?;
+    let mut keys = pemfile::pkcs8_private_keys(&mut &pem[..]).unwrap_or_default();
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut &pem[..]).unwrap_or_default();
+    }
+    keys.into_iter().next().ok_or_else(|| {
+        Error::TlsFailed(format!("No private key in {}", path.display()))
+    })
+}
+
+// Takes whatever certificate the server presents. Only used for pinned
+// connections, whose certificate is checked against the pins once the
+// handshake is done and before anything is sent.
+struct AnyCertificate;
+
+impl rustls::ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _roots: &rustls::RootCertStore,
+        _presented_certs: &[rustls::Certificate],
+        _dns_name: webpki::DNSNameRef<'_>,
+        _ocsp_response: &[u8],
+    ) -> std::result::Result<rustls::ServerCertVerified, rustls::TLSError> {
+        Ok(rustls::ServerCertVerified::assertion())
+    }
+}
+
+/// How TLS connections to gateways and cashiers are checked
+#[derive(Clone, Default)]
+pub struct TlsClientConfig {
+    /// Further certificates to trust besides the webpki roots, e.g. a
+    /// self-signed one
+    ca_certs: Vec<rustls::Certificate>,
+    /// Fingerprints of the only certificates accepted, if any
+    pins: Vec<String>,
+    /// Certificate chain and key presented to servers that ask for one
+    identity: Option<(Vec<rustls::Certificate>, rustls::PrivateKey)>,
+}
+
+impl TlsClientConfig {
+    pub fn load(ca_cert_path: Option<&str>, pins: &[String]) -> Result<Self> {
+        let ca_certs = match ca_cert_path {
+            Some(path) => load_certs(&expand_path(path)?)?,
+            None => vec![],
+        };
+
+        let mut normalized = vec![];
+        for pin in pins {
+            let pin = pin.replace(':', "").to_lowercase();
+            if pin.len() != 64 || hex::decode(&pin).is_err() {
+                return Err(Error::ParseFailed(
+                    "Pinned certificates must be hex encoded SHA-256 fingerprints",
+                ));
+            }
+            normalized.push(pin);
+        }
+
+        Ok(Self {
+            ca_certs,
+            pins: normalized,
+            identity: None,
+        })
+    }
+
+    /// Present the PEM certificate chain at `cert_path` and its key to
+    /// servers that ask for a client certificate. Both or neither have to
+    /// be given.
+    pub fn with_client_cert(
+        mut self,
+        cert_path: Option<&str>,
+        key_path: Option<&str>,
+    ) -> Result<Self> {
+        match (cert_path, key_path) {
+            (Some(cert_path), Some(key_path)) => {
+                let certs = load_certs(&expand_path(cert_path)?)?;
+                self.identity = Some((certs, load_key(&expand_path(key_path)?)?));
+            }
+            (None, None) => {}
+            _ => {
+                return Err(Error::TomlDeserializeError(
+                    "A client certificate needs both its cert and key path".to_string(),
+                ))
+            }
+        }
+        Ok(self)
+    }
+
+    /// Start TLS over a connection to `host`, checking the certificate
+    /// against the pins if there are any. Certificates are only checked
+    /// for DNS names, so hosts given by IP address have to be pinned.
+    pub async fn connect(
+        &self,
+        host: &str,
+        stream: Async<TcpStream>,
+    ) -> Result<TlsStream<Async<TcpStream>>> {
+        let mut config = rustls::ClientConfig::new();
+        config.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+        for cert in &self.ca_certs {
+            config
+                .root_store
+                .add(cert)
+                .map_err(|e| Error::TlsFailed(format!("Bad CA certificate: {}", e)))?;
+        }
+        // A pinned certificate is trusted for itself, whoever signed it
+        // and whatever name it's for
+        if !self.pins.is_empty() {
+            config.dangerous().set_certificate_verifier(Arc::new(AnyCertificate));
+        }
+        if let Some((certs, key)) = &self.identity {
+            config.set_single_client_cert(certs.clone(), key.clone())?;
+        }
+
+        let name = match webpki::DNSNameRef::try_from_ascii_str(host) {
+            Ok(name) => name,
+            Err(_) if !self.pins.is_empty() => {
+                webpki::DNSNameRef::try_from_ascii_str("pinned.invalid").unwrap()
+            }
+            Err(_) => {
+                return Err(Error::TlsFailed(format!(
+                    "{} isn't a DNS name, pin its certificate",
+                    host
+                )))
+            }
+        };
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(name, stream)
+            .await
+            .map_err(|e| Error::TlsFailed(format!("{}: {}", host, e)))?;
+
+        if !self.pins.is_empty() {
+            let der = match stream.get_ref().1.get_peer_certificates() {
+                Some(certs) if !certs.is_empty() => certs[0].0.clone(),
+                _ => return Err(Error::TlsPinMismatch(host.to_string())),
+            };
+            if !self.pins.contains(&certificate_fingerprint(&der)) {
+                return Err(Error::TlsPinMismatch(host.to_string()));
+            }
+        }
+
+        Ok(stream)
+    }
+}
+
+/// The PEM certificate chain and key a daemon serves TLS with, None if
+/// it doesn't
+pub fn server_identity(
+    serve_tls: bool,
+    cert_path: Option<&str>,
+    key_path: Option<&str>,
+) -> Result<Option<(PathBuf, PathBuf)>> {
+    if !serve_tls {
+        return Ok(None);
+    }
+    match (cert_path, key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            Ok(Some((expand_path(cert_path)?, expand_path(key_path)?)))
+        }
+        _ => Err(Error::TomlDeserializeError(
+            "serve_tls needs tls_cert_path and tls_key_path".to_string(),
+        )),
+    }
+}
+
+// Copy both ways until either side closes
+async fn pipe<A, B>(a: A, b: B) -> Result<()>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut a_read, mut a_write) = a.split();
+    let (mut b_read, mut b_write) = b.split();
+
+    futures::future::try_join(
+        async {
+            futures::io::copy(&mut a_read, &mut b_write).await?;
+            b_write.close().await
+        },
+        async {
+            futures::io::copy(&mut b_read, &mut a_write).await?;
+            a_write.close().await
+        },
+    )
+    .await?;
+    Ok(())
+}
+
+// Hand `stream` to the first connection made to a local port
+fn tunnel<S>(stream: S, url: &Url) -> Result<SocketAddr>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let listener = Async::<TcpListener>::bind(([127, 0, 0, 1], 0))?;
+    let local = listener.get_ref().local_addr()?;
+    debug!(target: "TLS TUNNEL", "Tunnel {} to {}", local, url);
+
+    smol::spawn(async move {
+        let result = match listener.accept().await {
+            Ok((local_stream, _)) => pipe(local_stream, stream).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            debug!(target: "TLS TUNNEL", "Tunnel closed: {}", e);
+        }
+    })
+    .detach();
+
+    Ok(local)
+}
+
+/// Address a zmq socket can connect to for the gateway at `url`: the
+/// gateway itself for a direct tcp:// connection, or else a local tunnel
+/// to it carrying TLS, going through the proxy, or both. A tunnel carries
+/// a single connection.
+pub async fn gateway_endpoint(
+    url: &Url,
+    tls: &TlsClientConfig,
+    proxy: Option<&Socks5Proxy>,
+) -> Result<SocketAddr> {
+    let host = url.host_str().ok_or(Error::UrlParseError)?.to_string();
+    let port = url.port().ok_or(Error::UrlParseError)?;
+
+    match (url.scheme(), proxy) {
+        ("tcp", None) => smol::unblock(move || (host.as_str(), port).to_socket_addrs())
+            .await?
+            .next()
+            .ok_or(Error::UrlParseError),
+        // Connect first so an unreachable gateway or a wrong certificate
+        // shows here rather than in zmq
+        ("tcp", Some(_)) => tunnel(socks5::connect(proxy, &host, port).await?, url),
+        ("tls", _) => {
+            let stream = socks5::connect(proxy, &host, port).await?;
+            tunnel(tls.connect(&host, stream).await?, url)
+        }
+        _ => Err(Error::UrlParseError),
+    }
+}
+
+/// Which client certificates a TLS listener asks for
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClientAuth {
+    /// None
+    Off,
+    /// One signed by a CA certificate in the PEM file, from every client
+    Required(PathBuf),
+    /// One signed by a CA certificate in the PEM file, from the clients
+    /// that have one. The others connect without.
+    Optional(PathBuf),
+}
+
+impl ClientAuth {
+    /// Require client certificates signed by the CA at `ca_path` if set
+    pub fn required(ca_path: Option<&str>) -> Result<Self> {
+        match ca_path {
+            Some(path) => Ok(ClientAuth::Required(expand_path(path)?)),
+            None => Ok(ClientAuth::Off),
+        }
+    }
+}
+
+// The CA certificates client certificates have to be signed by
+fn client_roots(ca_path: &Path) -> Result<rustls::RootCertStore> {
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in load_certs(ca_path)? {
+        roots.add(&cert).map_err(|e| Error::TlsFailed(format!("Bad CA certificate: {}", e)))?;
+    }
+    Ok(roots)
+}
+
+/// Serve TLS with a PEM certificate chain and its PKCS#8 or RSA key.
+/// Whether a client presented a certificate shows in its session's
+/// peer certificates.
+pub fn tls_acceptor(
+    cert_path: &Path,
+    key_path: &Path,
+    client_auth: &ClientAuth,
+) -> Result<TlsAcceptor> {
+    let verifier = match client_auth {
+        ClientAuth::Off => rustls::NoClientAuth::new(),
+        ClientAuth::Required(ca_path) => {
+            rustls::AllowAnyAuthenticatedClient::new(client_roots(ca_path)?)
+        }
+        ClientAuth::Optional(ca_path) => {
+            rustls::AllowAnyAnonymousOrAuthenticatedClient::new(client_roots(ca_path)?)
+        }
+    };
+    let mut config = rustls::ServerConfig::new(verifier);
+    config.set_single_cert(load_certs(cert_path)?, load_key(key_path)?)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Limits on each address connecting through a tunnel. The zmq sockets
+/// behind it can't tell one client's address from another's, so this is
+/// where they're enforced.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TunnelLimits {
+    /// Connections all addresses together may have open at once, e.g.
+    /// subscribers to the publisher, unlimited if 0
+    pub max_connections: usize,
+    /// Connections an address may have open at once, unlimited if 0
+    pub max_connections_per_ip: usize,
+    /// Bytes from the target a client may fall behind on before its
+    /// connection is closed, unlimited if 0. A slow subscriber is then
+    /// dropped and resumes, rather than having slabs silently skipped.
+    pub max_queued_bytes: usize,
+    /// Bytes a second an address may send through
+    pub upload: RateLimit,
+    /// Bytes a second an address is sent. What's over it is held back,
+    /// and counts towards max_queued_bytes.
+    pub download: RateLimit,
+    /// Times within the violation window an address may go over the
+    /// upload limit before its connection is closed
+    pub max_violations: u32,
+}
+
+impl Default for TunnelLimits {
+    fn default() -> Self {
+        Self {
+            max_connections: 0,
+            max_connections_per_ip: 0,
+            max_queued_bytes: 0,
+            upload: RateLimit::unlimited(),
+            download: RateLimit::unlimited(),
+            max_violations: u32::MAX,
+        }
+    }
+}
+
+// A connection counted against its address, until it's dropped
+struct ConnectionSlot {
+    ip: IpAddr,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl ConnectionSlot {
+    fn take(
+        open: &Arc<Mutex<HashMap<IpAddr, usize>>>,
+        ip: IpAddr,
+        limits: &TunnelLimits,
+    ) -> Option<Self> {
+        let mut counts = open.lock().unwrap();
+        let total: usize = counts.values().sum();
+        if limits.max_connections > 0 && total >= limits.max_connections {
+            return None;
+        }
+        let count = counts.entry(ip).or_insert(0);
+        if limits.max_connections_per_ip > 0 && *count >= limits.max_connections_per_ip {
+            return None;
+        }
+        *count += 1;
+        Some(Self {
+            ip,
+            open: open.clone(),
+        })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut counts = self.open.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Accept TLS connections on `listen_addr` and pass them on to the plain
+/// socket at `target`, which should only listen on localhost
+pub async fn serve_tls_tunnel(
+    listen_addr: SocketAddr,
+    target: SocketAddr,
+    acceptor: TlsAcceptor,
+    limits: TunnelLimits,
+    executor: Arc<Executor<'_>>,
+) -> Result<()> {
+    let listener = Async::<TcpListener>::bind(listen_addr)?;
+    debug!(target: "TLS TUNNEL", "Listening on tls://{} for {}", listen_addr, target);
+
+    let open = Arc::new(Mutex::new(HashMap::new()));
+    let upload = Arc::new(Mutex::new(RateLimiter::new(
+        limits.upload,
+        limits.max_violations,
+    )));
+    // Only ever held back, the client can't help what the target sends
+    let download = Arc::new(Mutex::new(RateLimiter::new(limits.download, u32::MAX)));
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let slot = match ConnectionSlot::take(&open, peer.ip(), &limits) {
+            Some(slot) => slot,
+            None => {
+                warn!(target: "TLS TUNNEL", "Too many connections, refusing {}", peer.ip());
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let (upload, download) = (upload.clone(), download.clone());
+
+        executor
+            .spawn(async move {
+                let result = async {
+                    let stream = acceptor.accept(stream).await?;
+                    let target = Async::<TcpStream>::connect(target).await?;
+                    let max_queued = limits.max_queued_bytes;
+                    pipe_limited(stream, target, peer.ip(), &upload, &download, max_queued).await
+                };
+                if let Err(e) = result.await {
+                    warn!(target: "TLS TUNNEL", "Connection from {} failed: {}", peer, e);
+                }
+                drop(slot);
+            })
+            .detach();
+    }
+}
+
+// Same as pipe(), with what either side sends charged to the client's
+// address, and what `target` sends queued for the client up to
+// `max_queued` bytes
+async fn pipe_limited<A, B>(
+    client: A,
+    target: B,
+    ip: IpAddr,
+    upload: &Mutex<RateLimiter<IpAddr>>,
+    download: &Mutex<RateLimiter<IpAddr>>,
+    max_queued: usize,
+) -> Result<()>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut client_read, mut client_write) = client.split();
+    let (mut target_read, mut target_write) = target.split();
+    let (queue_s, queue_r) = async_channel::unbounded::<Vec<u8>>();
+    let queued = AtomicUsize::new(0);
+
+    futures::future::try_join3(
+        async {
+            let mut buf = [0u8; 8192];
+            loop {
+                let n = client_read.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                // An address over its limit is held back until its
+                // bucket refills, and cut off if that keeps happening
+                loop {
+                    let verdict = upload.lock().unwrap().check(&ip, n as u64, unix_millis());
+                    match verdict {
+                        Verdict::Allow => break,
+                        Verdict::Throttle => {
+                            warn!(target: "TLS TUNNEL", "{} sends too fast, holding it back", ip);
+                            sleep(1).await;
+                        }
+                        Verdict::Disconnect | Verdict::Disconnected => {
+                            return Err(Error::ServicesError(
+                                "Client kept going over its upload limit",
+                            ));
+                        }
+                    }
+                }
+                target_write.write_all(&buf[..n]).await?;
+            }
+            target_write.close().await?;
+            Ok::<(), Error>(())
+        },
+        // The target is read as fast as it sends, so a slow client
+        // doesn't hold it up. What the client hasn't taken yet is queued.
+        async {
+            let mut buf = [0u8; 8192];
+            loop {
+                let n = target_read.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                let total = queued.fetch_add(n, Ordering::SeqCst) + n;
+                if max_queued > 0 && total > max_queued {
+                    warn!(target: "TLS TUNNEL", "{} fell too far behind, dropping it", ip);
+                    return Err(Error::ServicesError("Client fell too far behind"));
+                }
+                queue_s.send(buf[..n].to_vec()).await?;
+            }
+            queue_s.close();
+            Ok::<(), Error>(())
+        },
+        async {
+            while let Ok(chunk) = queue_r.recv().await {
+                loop {
+                    let n = chunk.len() as u64;
+                    let verdict = download.lock().unwrap().check(&ip, n, unix_millis());
+                    if verdict == Verdict::Allow {
+                        break;
+                    }
+                    debug!(target: "TLS TUNNEL", "{} is sent too much, holding it back", ip);
+                    sleep(1).await;
+                }
+                client_write.write_all(&chunk).await?;
+                queued.fetch_sub(chunk.len(), Ordering::SeqCst);
+            }
+            client_write.close().await?;
+            Ok::<(), Error>(())
+        },
+    )
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tls_client_pins() -> Result<()> {
+        let fingerprint = certificate_fingerprint(b"certificate");
+        assert_eq!(fingerprint.len(), 64);
+
+        // Fingerprints as openssl prints them are accepted too
+        let colons = fingerprint
+            .as_bytes()
+            .chunks(2)
+            .map(|byte| std::str::from_utf8(byte).unwrap().to_uppercase())
+            .collect::<Vec<String>>()
+            .join(":");
+        let config = TlsClientConfig::load(None, &[colons])?;
+        assert_eq!(config.pins, vec![fingerprint]);
+
+        assert!(TlsClientConfig::load(None, &["abcd".to_string()]).is_err());
+        assert!(TlsClientConfig::load(None, &["zz".repeat(32)]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_connection_slots() {
+        let open = Arc::new(Mutex::new(HashMap::new()));
+        let limits = TunnelLimits {
+            max_connections: 3,
+            max_connections_per_ip: 2,
+            ..TunnelLimits::default()
+        };
+        let (a, b) = (IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 2]));
+
+        let first = ConnectionSlot::take(&open, a, &limits).unwrap();
+        let _second = ConnectionSlot::take(&open, a, &limits).unwrap();
+        assert!(ConnectionSlot::take(&open, a, &limits).is_none());
+
+        // Every address counts towards the total
+        let _third = ConnectionSlot::take(&open, b, &limits).unwrap();
+        assert!(ConnectionSlot::take(&open, b, &limits).is_none());
+
+        drop(first);
+        assert!(ConnectionSlot::take(&open, b, &limits).is_some());
+    }
+}
@@ -0,0 +1,114 @@
+//! Framing of the messages between gateway clients and gatewayd. Each
+//! message goes out as a frame of magic, payload length, type and
+//! checksum ahead of the payload, so a corrupted or foreign message is
+//! caught before anything tries to decode it. zmq keeps message
+//! boundaries, so dropping a bad frame is all it takes to be back in sync
+//! with the stream.
+
+use sha2::{Digest, Sha256};
+
+use super::recording::FrameKind;
+use crate::{Error, Result};
+
+pub const FRAME_MAGIC: [u8; 4] = *b"DRKG";
+
+/// Largest payload a frame may carry
+pub const MAX_FRAME_SIZE: usize = 32 * 1024 * 1024;
+
+// Magic, length, type and checksum
+const HEADER_SIZE: usize = 4 + 4 + 1 + 4;
+
+fn checksum(kind: u8, payload: &[u8]) -> [u8; 4] {
+    let digest = Sha256::new().chain(&[kind]).chain(payload).finalize();
+    let mut checksum = [0u8; 4];
+    checksum.copy_from_slice(&digest[..4]);
+    checksum
+}
+
+pub fn encode_frame(kind: FrameKind, payload: &[u8]) -> Result<Vec<u8>> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(Error::BadFrame(format!(
+            "{} byte payload exceeds the frame limit",
+            payload.len()
+        )));
+    }
+
+    let kind = kind.to_u8();
+    let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&FRAME_MAGIC);
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.push(kind);
+    frame.extend_from_slice(&checksum(kind, payload));
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+/// The payload of a frame of the expected kind
+pub fn decode_frame(expected: FrameKind, frame: &[u8]) -> Result<Vec<u8>> {
+    if frame.len() < HEADER_SIZE {
+        return Err(Error::BadFrame("Truncated header".to_string()));
+    }
+    if frame[..4] != FRAME_MAGIC {
+        return Err(Error::BadFrame(
+            "Bad magic, the peer may be an older build".to_string(),
+        ));
+    }
+
+    let mut length = [0u8; 4];
+    length.copy_from_slice(&frame[4..8]);
+    let length = u32::from_le_bytes(length) as usize;
+    if length > MAX_FRAME_SIZE {
+        return Err(Error::BadFrame(format!(
+            "{} byte payload exceeds the frame limit",
+            length
+        )));
+    }
+    if frame.len() - HEADER_SIZE != length {
+        return Err(Error::BadFrame(format!(
+            "Expected {} payload bytes, got {}",
+            length,
+            frame.len() - HEADER_SIZE
+        )));
+    }
+
+    let kind = frame[8];
+    if FrameKind::from_u8(kind) != Some(expected) {
+        return Err(Error::BadFrame(format!("Unexpected frame type {}", kind)));
+    }
+
+    let payload = &frame[HEADER_SIZE..];
+    if frame[9..HEADER_SIZE] != checksum(kind, payload) {
+        return Err(Error::BadFrame("Checksum mismatch".to_string()));
+    }
+
+    Ok(payload.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frames() -> Result<()> {
+        let payload = vec![7u8; 1000];
+        let frame = encode_frame(FrameKind::Publish, &payload)?;
+        assert_eq!(frame.len(), HEADER_SIZE + payload.len());
+        assert_eq!(decode_frame(FrameKind::Publish, &frame)?, payload);
+
+        // A reply can't pass for a published slab
+        assert!(decode_frame(FrameKind::Reply, &frame).is_err());
+
+        // Any single corrupted byte is caught
+        for i in 0..frame.len() {
+            let mut corrupted = frame.clone();
+            corrupted[i] ^= 0x01;
+            assert!(decode_frame(FrameKind::Publish, &corrupted).is_err());
+        }
+
+        assert!(decode_frame(FrameKind::Publish, &frame[..frame.len() - 1]).is_err());
+        assert!(decode_frame(FrameKind::Publish, &payload).is_err());
+        assert!(encode_frame(FrameKind::Request, &vec![0; MAX_FRAME_SIZE + 1]).is_err());
+
+        Ok(())
+    }
+}
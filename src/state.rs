@@ -3,9 +3,16 @@ use std::fmt;
 use bellman::groth16;
 use bls12_381::Bls12;
 use log::debug;
+use serde_json::{json, Value};
 
 use crate::{
-    crypto::{coin::Coin, merkle_node::MerkleNode, note::EncryptedNote, nullifier::Nullifier},
+    blockchain::slab::SlabHash,
+    crypto::{
+        coin::Coin,
+        merkle_node::MerkleNode,
+        note::{EncryptedNote, EncryptedOutgoingNote, Memo},
+        nullifier::Nullifier,
+    },
     tx,
 };
 
@@ -22,6 +29,69 @@ pub struct StateUpdate {
     pub nullifiers: Vec<Nullifier>,
     pub coins: Vec<Coin>,
     pub enc_notes: Vec<EncryptedNote>,
+    pub out_notes: Vec<Option<EncryptedOutgoingNote>>,
+}
+
+/// A change to the client state, broadcast once it's written so other
+/// parts of the node can react to it
+#[derive(Clone, Debug)]
+pub enum StateEvent {
+    /// A coin was sent to one of our keys
+    NewCoinReceived {
+        coin: Coin,
+        value: u64,
+        token_id: jubjub::Fr,
+        memo: Memo,
+        /// The id of the transaction that sent it
+        tx_id: SlabHash,
+        slab_index: u64,
+        /// Sent by one of our own transfers, like change
+        change: bool,
+    },
+    /// The nullifier of one of our coins was revealed, so it's spent
+    NullifierSeen { coin: Coin, slab_index: u64 },
+    /// Coins were added to the merkle tree
+    MerkleRootAdvanced { root: MerkleNode, size: u64 },
+    SlabApplied { index: u64 },
+}
+
+impl StateEvent {
+    pub fn to_json(&self) -> Value {
+        match self {
+            StateEvent::NewCoinReceived {
+                coin,
+                value,
+                token_id,
+                memo,
+                tx_id,
+                slab_index,
+                change,
+            } => json!({
+                "event": "new_coin_received",
+                "coin": hex::encode(coin.repr),
+                "value": value,
+                "token_id": token_id.to_string(),
+                "memo": memo.to_string(),
+                "tx_id": hex::encode(tx_id),
+                "slab_index": slab_index,
+                "change": change,
+            }),
+            StateEvent::NullifierSeen { coin, slab_index } => json!({
+                "event": "nullifier_seen",
+                "coin": hex::encode(coin.repr),
+                "slab_index": slab_index,
+            }),
+            StateEvent::MerkleRootAdvanced { root, size } => json!({
+                "event": "merkle_root_advanced",
+                "root": hex::encode(root.repr),
+                "size": size,
+            }),
+            StateEvent::SlabApplied { index } => json!({
+                "event": "slab_applied",
+                "index": index,
+            }),
+        }
+    }
 }
 
 pub type VerifyResult<T> = std::result::Result<T, VerifyFailed>;
@@ -60,7 +130,7 @@ impl fmt::Display for VerifyFailed {
             }
             VerifyFailed::InputSignature(i) => write!(f, "Invalid signature for input {}", i),
             VerifyFailed::MissingFunds => {
-                f.write_str("Money in does not match money out (value commits)")
+                f.write_str("Money in does not match money out plus fee (value commits)")
             }
             VerifyFailed::AssetMismatch => {
                 f.write_str("Assets don't match some inputs or outputs (token commits)")
@@ -70,8 +140,25 @@ impl fmt::Display for VerifyFailed {
 }
 
 pub fn state_transition<S: ProgramState>(
-    state: &async_std::sync::MutexGuard<S>,
+    state: &S,
+    tx: tx::Transaction,
+) -> VerifyResult<StateUpdate> {
+    check_transition(state, tx, true)
+}
+
+/// Same as state_transition() for a transaction whose proofs were already
+/// checked with tx::Transaction::add_proofs_to_batch()
+pub fn state_transition_batched<S: ProgramState>(
+    state: &S,
+    tx: tx::Transaction,
+) -> VerifyResult<StateUpdate> {
+    check_transition(state, tx, false)
+}
+
+fn check_transition<S: ProgramState>(
+    state: &S,
     tx: tx::Transaction,
+    verify_proofs: bool,
 ) -> VerifyResult<StateUpdate> {
     // Check deposits are legit
 
@@ -109,9 +196,20 @@ pub fn state_transition<S: ProgramState>(
     }
 
     debug!(target: "STATE TRANSITION", "Check the tx Verifies correctly");
-    // Check the tx verifies correctly
-    tx.verify(state.mint_pvk(), state.spend_pvk())?;
+    // Check the tx verifies correctly. This includes the balance check:
+    // sum(inputs) must equal sum(outputs) + fee.
+    if verify_proofs {
+        tx.verify(state.mint_pvk(), state.spend_pvk())?;
+    } else {
+        tx.verify_without_proofs()?;
+    }
+
+    Ok(trusted_transition(tx))
+}
 
+/// The update a transaction makes without checking anything, for a light
+/// client trusting its gateways to only sign valid slabs
+pub fn trusted_transition(tx: tx::Transaction) -> StateUpdate {
     let mut nullifiers = vec![];
     for input in tx.inputs {
         nullifiers.push(input.revealed.nullifier);
@@ -120,15 +218,18 @@ pub fn state_transition<S: ProgramState>(
     // Newly created coins for this tx
     let mut coins = vec![];
     let mut enc_notes = vec![];
+    let mut out_notes = vec![];
     for output in tx.outputs {
         // Gather all the coins
         coins.push(Coin::new(output.revealed.coin));
         enc_notes.push(output.enc_note);
+        out_notes.push(output.out_note);
     }
 
-    Ok(StateUpdate {
+    StateUpdate {
         nullifiers,
         coins,
         enc_notes,
-    })
+        out_notes,
+    }
 }
@@ -1,6 +1,8 @@
+use async_std::sync::Arc;
 use bellman::groth16;
 use bls12_381::Bls12;
 use ff::Field;
+use futures::future::{join, join_all};
 use rand::rngs::OsRng;
 
 use super::{
@@ -8,8 +10,11 @@ use super::{
     Transaction, TransactionClearInput, TransactionInput, TransactionOutput,
 };
 use crate::crypto::{
-    create_mint_proof, create_spend_proof, merkle::MerklePath, merkle_node::MerkleNode, note::Note,
-    schnorr,
+    create_mint_proof, create_spend_proof,
+    merkle::MerklePath,
+    merkle_node::MerkleNode,
+    note::{EncryptedNote, EncryptedOutgoingNote, Memo, Note, OutgoingNote},
+    schnorr, Address, MintRevealedValues, SpendRevealedValues,
 };
 use crate::serial::Encodable;
 
@@ -17,6 +22,10 @@ pub struct TransactionBuilder {
     pub clear_inputs: Vec<TransactionBuilderClearInputInfo>,
     pub inputs: Vec<TransactionBuilderInputInfo>,
     pub outputs: Vec<TransactionBuilderOutputInfo>,
+    pub fee: u64,
+    /// The sender's key. When set, every output also carries a copy
+    /// encrypted to it, so the sender can rebuild its history.
+    pub out_public: Option<jubjub::SubgroupPoint>,
 }
 
 pub struct TransactionBuilderClearInputInfo {
@@ -35,9 +44,42 @@ pub struct TransactionBuilderOutputInfo {
     pub value: u64,
     pub token_id: jubjub::Fr,
     pub public: jubjub::SubgroupPoint,
+    /// Key the note is encrypted to, from the recipient's address
+    pub view_public: jubjub::SubgroupPoint,
+    pub memo: Memo,
 }
 
 impl TransactionBuilder {
+    /// Value the inputs carry beyond what the outputs and the fee pay
+    pub fn change_value(&self) -> u64 {
+        let clear_inputs: u64 = self.clear_inputs.iter().map(|i| i.value).sum();
+        let inputs: u64 = self.inputs.iter().map(|i| i.note.value).sum();
+        let outputs: u64 = self.outputs.iter().map(|o| o.value).sum();
+
+        (clear_inputs + inputs).saturating_sub(outputs + self.fee)
+    }
+
+    /// Pay the change back to `address` so it isn't lost. The change output
+    /// goes first, before the payments. Returns the change value.
+    pub fn add_change_output(&mut self, token_id: jubjub::Fr, address: Address) -> u64 {
+        let value = self.change_value();
+
+        if value > 0 {
+            self.outputs.insert(
+                0,
+                TransactionBuilderOutputInfo {
+                    value,
+                    token_id,
+                    public: address.public,
+                    view_public: address.view_public,
+                    memo: Memo::empty(),
+                },
+            );
+        }
+
+        value
+    }
+
     fn compute_remainder_blind(
         clear_inputs: &[PartialTransactionClearInput],
         input_blinds: &[jubjub::Fr],
@@ -65,7 +107,47 @@ impl TransactionBuilder {
         mint_params: &groth16::Parameters<Bls12>,
         spend_params: &groth16::Parameters<Bls12>,
     ) -> Transaction {
+        let (plan, spend_jobs, mint_jobs) = self.plan();
+
+        let spends = spend_jobs
+            .into_iter()
+            .map(|job| job.prove(spend_params))
+            .collect();
+        let mints = mint_jobs
+            .into_iter()
+            .map(|job| job.prove(mint_params))
+            .collect();
+
+        plan.finish(spends, mints)
+    }
+
+    /// Same as build(), but the proofs are all generated at once on the
+    /// blocking thread pool, leaving the executor free while proving
+    pub async fn build_async(
+        self,
+        mint_params: Arc<groth16::Parameters<Bls12>>,
+        spend_params: Arc<groth16::Parameters<Bls12>>,
+    ) -> Transaction {
+        let (plan, spend_jobs, mint_jobs) = self.plan();
+
+        let spends = join_all(spend_jobs.into_iter().map(|job| {
+            let params = spend_params.clone();
+            smol::unblock(move || job.prove(&params))
+        }));
+        let mints = join_all(mint_jobs.into_iter().map(|job| {
+            let params = mint_params.clone();
+            smol::unblock(move || job.prove(&params))
+        }));
+        let (spends, mints) = join(spends, mints).await;
+
+        plan.finish(spends, mints)
+    }
+
+    // Pick every blind and encrypt the notes up front, so the proofs
+    // left to make don't depend on each other
+    fn plan(self) -> (BuildPlan, Vec<SpendJob>, Vec<MintJob>) {
         let mut clear_inputs = vec![];
+        let mut clear_input_secrets = vec![];
         let token_commit_blind: jubjub::Fr = jubjub::Fr::random(&mut OsRng);
         for input in &self.clear_inputs {
             let signature_public =
@@ -80,18 +162,17 @@ impl TransactionBuilder {
                 signature_public,
             };
             clear_inputs.push(clear_input);
+            clear_input_secrets.push(schnorr::SecretKey(input.signature_secret));
         }
 
-        let mut inputs = vec![];
+        let mut spend_jobs = vec![];
         let mut input_blinds = vec![];
         let mut signature_secrets = vec![];
-        for input in &self.inputs {
+        for input in self.inputs {
             input_blinds.push(input.note.valcom_blind);
 
             let signature_secret: jubjub::Fr = jubjub::Fr::random(&mut OsRng);
 
-            // make proof
-
             // TODO: Some stupid glue code. Need to sort this out
             let auth_path: Vec<(bls12_381::Scalar, bool)> = input
                 .merkle_path
@@ -100,31 +181,24 @@ impl TransactionBuilder {
                 .map(|(node, b)| ((*node).into(), *b))
                 .collect();
 
-            let (proof, revealed) = create_spend_proof(
-                &spend_params,
-                input.note.value,
-                input.note.token_id,
-                input.note.valcom_blind,
+            spend_jobs.push(SpendJob {
+                value: input.note.value,
+                token_id: input.note.token_id,
+                valcom_blind: input.note.valcom_blind,
                 token_commit_blind,
-                input.note.serial,
-                input.note.coin_blind,
-                input.secret,
+                serial: input.note.serial,
+                coin_blind: input.note.coin_blind,
+                secret: input.secret,
                 auth_path,
                 signature_secret,
-            );
+            });
 
             // First we make the tx then sign after
-            let signature_secret = schnorr::SecretKey(signature_secret);
-            signature_secrets.push(signature_secret);
-
-            let input = PartialTransactionInput {
-                spend_proof: proof,
-                revealed,
-            };
-            inputs.push(input);
+            signature_secrets.push(schnorr::SecretKey(signature_secret));
         }
 
-        let mut outputs = vec![];
+        let mut mint_jobs = vec![];
+        let mut notes = vec![];
         let mut output_blinds = vec![];
 
         for (i, output) in self.outputs.iter().enumerate() {
@@ -138,16 +212,15 @@ impl TransactionBuilder {
             let serial: jubjub::Fr = jubjub::Fr::random(&mut OsRng);
             let coin_blind: jubjub::Fr = jubjub::Fr::random(&mut OsRng);
 
-            let (mint_proof, revealed) = create_mint_proof(
-                mint_params,
-                output.value,
-                output.token_id,
+            mint_jobs.push(MintJob {
+                value: output.value,
+                token_id: output.token_id,
                 valcom_blind,
                 token_commit_blind,
                 serial,
                 coin_blind,
-                output.public,
-            );
+                public: output.public,
+            });
 
             // Encrypted note
 
@@ -157,22 +230,77 @@ impl TransactionBuilder {
                 token_id: output.token_id,
                 coin_blind,
                 valcom_blind,
+                memo: output.memo,
             };
 
-            let encrypted_note = note.encrypt(&output.public).unwrap();
+            let encrypted_note = note.encrypt(&output.view_public).unwrap();
 
-            let output = TransactionOutput {
-                mint_proof,
-                revealed,
-                enc_note: encrypted_note,
-            };
-            outputs.push(output);
+            let out_note = self.out_public.map(|out_public| {
+                OutgoingNote {
+                    recipient: output.public,
+                    value: output.value,
+                    token_id: output.token_id,
+                }
+                .encrypt(&out_public)
+                .unwrap()
+            });
+
+            notes.push((encrypted_note, out_note));
         }
 
-        let partial_tx = PartialTransaction {
+        let plan = BuildPlan {
             clear_inputs,
+            clear_input_secrets,
+            signature_secrets,
+            notes,
+            fee: self.fee,
+        };
+
+        (plan, spend_jobs, mint_jobs)
+    }
+}
+
+// What's left of a transaction once its proofs are made
+struct BuildPlan {
+    clear_inputs: Vec<PartialTransactionClearInput>,
+    clear_input_secrets: Vec<schnorr::SecretKey>,
+    signature_secrets: Vec<schnorr::SecretKey>,
+    notes: Vec<(EncryptedNote, Option<EncryptedOutgoingNote>)>,
+    fee: u64,
+}
+
+impl BuildPlan {
+    fn finish(
+        self,
+        spends: Vec<(groth16::Proof<Bls12>, SpendRevealedValues)>,
+        mints: Vec<(groth16::Proof<Bls12>, MintRevealedValues)>,
+    ) -> Transaction {
+        let inputs = spends
+            .into_iter()
+            .map(|(spend_proof, revealed)| PartialTransactionInput {
+                spend_proof,
+                revealed,
+            })
+            .collect();
+
+        let outputs = mints
+            .into_iter()
+            .zip(self.notes)
+            .map(
+                |((mint_proof, revealed), (enc_note, out_note))| TransactionOutput {
+                    mint_proof,
+                    revealed,
+                    enc_note,
+                    out_note,
+                },
+            )
+            .collect();
+
+        let partial_tx = PartialTransaction {
+            clear_inputs: self.clear_inputs,
             inputs,
             outputs,
+            fee: self.fee,
         };
 
         let mut unsigned_tx_data = vec![];
@@ -181,8 +309,11 @@ impl TransactionBuilder {
             .expect("TODO handle this");
 
         let mut clear_inputs = vec![];
-        for (input, info) in partial_tx.clear_inputs.into_iter().zip(self.clear_inputs) {
-            let secret = schnorr::SecretKey(info.signature_secret);
+        for (input, secret) in partial_tx
+            .clear_inputs
+            .into_iter()
+            .zip(self.clear_input_secrets)
+        {
             let signature = secret.sign(&unsigned_tx_data[..]);
             let input = TransactionClearInput::from_partial(input, signature);
             clear_inputs.push(input);
@@ -192,7 +323,7 @@ impl TransactionBuilder {
         for (input, signature_secret) in partial_tx
             .inputs
             .into_iter()
-            .zip(signature_secrets.into_iter())
+            .zip(self.signature_secrets.into_iter())
         {
             let signature = signature_secret.sign(&unsigned_tx_data[..]);
             let input = TransactionInput::from_partial(input, signature);
@@ -203,6 +334,67 @@ impl TransactionBuilder {
             clear_inputs,
             inputs,
             outputs: partial_tx.outputs,
+            fee: partial_tx.fee,
         }
     }
 }
+
+struct SpendJob {
+    value: u64,
+    token_id: jubjub::Fr,
+    valcom_blind: jubjub::Fr,
+    token_commit_blind: jubjub::Fr,
+    serial: jubjub::Fr,
+    coin_blind: jubjub::Fr,
+    secret: jubjub::Fr,
+    auth_path: Vec<(bls12_381::Scalar, bool)>,
+    signature_secret: jubjub::Fr,
+}
+
+impl SpendJob {
+    fn prove(
+        self,
+        params: &groth16::Parameters<Bls12>,
+    ) -> (groth16::Proof<Bls12>, SpendRevealedValues) {
+        create_spend_proof(
+            params,
+            self.value,
+            self.token_id,
+            self.valcom_blind,
+            self.token_commit_blind,
+            self.serial,
+            self.coin_blind,
+            self.secret,
+            self.auth_path,
+            self.signature_secret,
+        )
+    }
+}
+
+struct MintJob {
+    value: u64,
+    token_id: jubjub::Fr,
+    valcom_blind: jubjub::Fr,
+    token_commit_blind: jubjub::Fr,
+    serial: jubjub::Fr,
+    coin_blind: jubjub::Fr,
+    public: jubjub::SubgroupPoint,
+}
+
+impl MintJob {
+    fn prove(
+        self,
+        params: &groth16::Parameters<Bls12>,
+    ) -> (groth16::Proof<Bls12>, MintRevealedValues) {
+        create_mint_proof(
+            params,
+            self.value,
+            self.token_id,
+            self.valcom_blind,
+            self.token_commit_blind,
+            self.serial,
+            self.coin_blind,
+            self.public,
+        )
+    }
+}
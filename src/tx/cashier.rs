@@ -0,0 +1,223 @@
+//! Transactions a cashier mints: a deposit the cashier received is paid out
+//! as coins to the depositor's address, backed by clear inputs the cashier
+//! signs. Nodes accept them only signed by a cashier key they know.
+
+use async_std::sync::Arc;
+use bellman::groth16;
+use bls12_381::Bls12;
+
+use super::{
+    Transaction, TransactionBuilder, TransactionBuilderClearInputInfo, TransactionBuilderOutputInfo,
+};
+use crate::crypto::{
+    coin::Coin, merkle_node::MerkleNode, note::Memo, nullifier::Nullifier, Address,
+};
+use crate::state::{state_transition, ProgramState, StateUpdate, VerifyFailed, VerifyResult};
+
+/// A deposit to credit: `value` of `token_id` paid to `recipient`
+pub struct Deposit {
+    pub value: u64,
+    pub token_id: jubjub::Fr,
+    pub recipient: Address,
+    pub memo: Memo,
+}
+
+/// Builds the mint transactions for deposits, signed with the cashier key
+pub struct Minter {
+    secret: jubjub::Fr,
+}
+
+impl Minter {
+    pub fn new(secret: jubjub::Fr) -> Self {
+        Self { secret }
+    }
+
+    /// The key nodes need to accept this cashier's mints
+    pub fn public(&self) -> jubjub::SubgroupPoint {
+        zcash_primitives::constants::SPENDING_KEY_GENERATOR * &self.secret
+    }
+
+    /// A clear input putting `value` of `token_id` into a transaction
+    pub fn clear_input(
+        &self,
+        value: u64,
+        token_id: jubjub::Fr,
+    ) -> TransactionBuilderClearInputInfo {
+        TransactionBuilderClearInputInfo {
+            value,
+            token_id,
+            signature_secret: self.secret,
+        }
+    }
+
+    /// The builder for a transaction crediting each deposit, with a clear
+    /// input and an output per deposit. The cashier keeps a copy of every
+    /// note, like for any other transaction it sends.
+    pub fn builder(&self, deposits: &[Deposit]) -> TransactionBuilder {
+        let clear_inputs = deposits
+            .iter()
+            .map(|deposit| self.clear_input(deposit.value, deposit.token_id))
+            .collect();
+        let outputs = deposits
+            .iter()
+            .map(|deposit| TransactionBuilderOutputInfo {
+                value: deposit.value,
+                token_id: deposit.token_id,
+                public: deposit.recipient.public,
+                view_public: deposit.recipient.view_public,
+                memo: deposit.memo,
+            })
+            .collect();
+
+        TransactionBuilder {
+            clear_inputs,
+            inputs: vec![],
+            outputs,
+            fee: 0,
+            out_public: Some(self.public()),
+        }
+    }
+
+    pub fn mint(
+        &self,
+        deposits: &[Deposit],
+        mint_params: &groth16::Parameters<Bls12>,
+        spend_params: &groth16::Parameters<Bls12>,
+    ) -> Transaction {
+        self.builder(deposits).build(mint_params, spend_params)
+    }
+
+    /// Same as mint(), proving off the executor
+    pub async fn mint_async(
+        &self,
+        deposits: &[Deposit],
+        mint_params: Arc<groth16::Parameters<Bls12>>,
+        spend_params: Arc<groth16::Parameters<Bls12>>,
+    ) -> Transaction {
+        self.builder(deposits)
+            .build_async(mint_params, spend_params)
+            .await
+    }
+}
+
+/// Checks mint transactions the way nodes do, against a set of cashier
+/// keys. A mint spends no coins, so one that has inputs fails as an
+/// invalid merkle root.
+pub struct MintVerifier<'a> {
+    cashier_publics: Vec<jubjub::SubgroupPoint>,
+    mint_pvk: &'a groth16::PreparedVerifyingKey<Bls12>,
+    spend_pvk: &'a groth16::PreparedVerifyingKey<Bls12>,
+}
+
+impl<'a> MintVerifier<'a> {
+    pub fn new(
+        cashier_publics: Vec<jubjub::SubgroupPoint>,
+        mint_pvk: &'a groth16::PreparedVerifyingKey<Bls12>,
+        spend_pvk: &'a groth16::PreparedVerifyingKey<Bls12>,
+    ) -> Self {
+        Self {
+            cashier_publics,
+            mint_pvk,
+            spend_pvk,
+        }
+    }
+
+    /// The coins the mint creates, if it's valid
+    pub fn verify(&self, tx: Transaction) -> VerifyResult<StateUpdate> {
+        state_transition(self, tx)
+    }
+
+    /// Same as verify(), also checking the clear inputs put in exactly
+    /// the value and token of each deposit, in order
+    pub fn verify_deposits(
+        &self,
+        tx: Transaction,
+        deposits: &[Deposit],
+    ) -> VerifyResult<Vec<Coin>> {
+        if tx.clear_inputs.len() != deposits.len() {
+            return Err(VerifyFailed::MissingFunds);
+        }
+        for (input, deposit) in tx.clear_inputs.iter().zip(deposits) {
+            if input.value != deposit.value {
+                return Err(VerifyFailed::MissingFunds);
+            }
+            if input.token_id != deposit.token_id {
+                return Err(VerifyFailed::AssetMismatch);
+            }
+        }
+
+        Ok(self.verify(tx)?.coins)
+    }
+}
+
+impl<'a> ProgramState for MintVerifier<'a> {
+    fn is_valid_cashier_public_key(&self, public: &jubjub::SubgroupPoint) -> bool {
+        self.cashier_publics.contains(public)
+    }
+
+    fn is_valid_merkle(&self, _merkle: &MerkleNode) -> bool {
+        false
+    }
+
+    fn nullifier_exists(&self, _nullifier: &Nullifier) -> bool {
+        false
+    }
+
+    fn mint_pvk(&self) -> &groth16::PreparedVerifyingKey<Bls12> {
+        self.mint_pvk
+    }
+
+    fn spend_pvk(&self) -> &groth16::PreparedVerifyingKey<Bls12> {
+        self.spend_pvk
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{setup_mint_prover, setup_spend_prover};
+    use ff::Field;
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn test_mint_state_transition() {
+        let mint_params = setup_mint_prover();
+        let spend_params = setup_spend_prover();
+        let mint_pvk = groth16::prepare_verifying_key(&mint_params.vk);
+        let spend_pvk = groth16::prepare_verifying_key(&spend_params.vk);
+
+        let minter = Minter::new(jubjub::Fr::random(&mut OsRng));
+        let token_id = jubjub::Fr::random(&mut OsRng);
+        let deposits = || {
+            vec![Deposit {
+                value: 110,
+                token_id,
+                recipient: Address::from_secret(&jubjub::Fr::random(&mut OsRng)),
+                memo: Memo::empty(),
+            }]
+        };
+
+        // The deposit is credited as one new coin
+        let verifier = MintVerifier::new(vec![minter.public()], &mint_pvk, &spend_pvk);
+        let tx = minter.mint(&deposits(), &mint_params, &spend_params);
+        let coins = verifier.verify_deposits(tx, &deposits()).unwrap();
+        assert_eq!(coins.len(), 1);
+
+        // Claiming more than was minted fails
+        let tx = minter.mint(&deposits(), &mint_params, &spend_params);
+        let mut claimed = deposits();
+        claimed[0].value = 111;
+        assert!(matches!(
+            verifier.verify_deposits(tx, &claimed),
+            Err(VerifyFailed::MissingFunds)
+        ));
+
+        // And so does a mint signed with a key nodes don't know
+        let stranger = Minter::new(jubjub::Fr::random(&mut OsRng));
+        let tx = stranger.mint(&deposits(), &mint_params, &spend_params);
+        assert!(matches!(
+            verifier.verify(tx),
+            Err(VerifyFailed::InvalidCashierKey(0))
+        ));
+    }
+}
@@ -0,0 +1,526 @@
+use bellman::groth16;
+use bls12_381::Bls12;
+use ff::Field;
+use group::Group;
+use rand::rngs::OsRng;
+use std::io;
+
+use super::{
+    partial::PartialTransactionInput, Transaction, TransactionBuilderInputInfo,
+    TransactionBuilderOutputInfo, TransactionInput, TransactionOutput,
+};
+use crate::crypto::{
+    create_mint_proof, create_spend_proof,
+    note::{Memo, Note},
+    schnorr, MintRevealedValues,
+};
+use crate::error::{Error, Result};
+use crate::impl_vec;
+use crate::serial::{Decodable, Encodable, VarInt};
+
+/// The output funded jointly by all parties, e.g. an escrow.
+pub struct CoBuildOutput {
+    pub value: u64,
+    pub token_id: jubjub::Fr,
+    pub public: jubjub::SubgroupPoint,
+}
+
+/// The inputs and change outputs added by one party. `amount` is what the
+/// party puts towards the shared output, and `blind_delta` is the sum of its
+/// input value blinds minus its change output blinds, so anyone can check the
+/// contribution balances without learning the individual blinds.
+pub struct CoBuildContribution {
+    pub amount: u64,
+    pub blind_delta: jubjub::Fr,
+    pub inputs: Vec<PartialTransactionInput>,
+    pub outputs: Vec<TransactionOutput>,
+    pub signatures: Vec<schnorr::Signature>,
+}
+
+/// The minted shared output. Serial and coin blind are published so every
+/// party can check the coin goes to the agreed public key.
+pub struct CoBuildSharedOutput {
+    pub output: TransactionOutput,
+    pub serial: jubjub::Fr,
+    pub coin_blind: jubjub::Fr,
+}
+
+/// A transaction built by several wallets. It is passed around serialized:
+/// each party adds a contribution, one of them finalizes it by minting the
+/// shared output, then every party validates and signs its own inputs.
+/// Signatures cover the whole transaction, so once signed no component can
+/// be altered.
+pub struct CoBuildTransaction {
+    pub output: CoBuildOutput,
+    pub token_commit_blind: jubjub::Fr,
+    pub contributions: Vec<CoBuildContribution>,
+    pub shared_output: Option<CoBuildSharedOutput>,
+}
+
+/// Kept by a party between contributing and signing. Never leaves the wallet.
+pub struct CoBuildSigner {
+    index: usize,
+    contribution: Vec<u8>,
+    signature_secrets: Vec<schnorr::SecretKey>,
+}
+
+fn compute_pedersen_commit(value: jubjub::Fr, blind: &jubjub::Fr) -> jubjub::SubgroupPoint {
+    (zcash_primitives::constants::VALUE_COMMITMENT_VALUE_GENERATOR * value)
+        + (zcash_primitives::constants::VALUE_COMMITMENT_RANDOMNESS_GENERATOR * blind)
+}
+
+impl CoBuildTransaction {
+    pub fn new(output: CoBuildOutput) -> Self {
+        Self {
+            output,
+            token_commit_blind: jubjub::Fr::random(&mut OsRng),
+            contributions: vec![],
+            shared_output: None,
+        }
+    }
+
+    pub fn contribute(
+        &mut self,
+        inputs: Vec<TransactionBuilderInputInfo>,
+        change: Vec<TransactionBuilderOutputInfo>,
+        mint_params: &groth16::Parameters<Bls12>,
+        spend_params: &groth16::Parameters<Bls12>,
+    ) -> Result<CoBuildSigner> {
+        if self.shared_output.is_some() {
+            return Err(Error::CoBuildFailed(
+                "transaction is already finalized".into(),
+            ));
+        }
+
+        let token_id = self.output.token_id;
+        let mut amount: i128 = 0;
+        let mut blind_delta = jubjub::Fr::zero();
+
+        let mut partial_inputs = vec![];
+        let mut signature_secrets = vec![];
+        for input in inputs {
+            if input.note.token_id != token_id {
+                return Err(Error::CoBuildFailed("input token does not match".into()));
+            }
+            amount += input.note.value as i128;
+            blind_delta += input.note.valcom_blind;
+
+            let signature_secret: jubjub::Fr = jubjub::Fr::random(&mut OsRng);
+
+            let auth_path: Vec<(bls12_381::Scalar, bool)> = input
+                .merkle_path
+                .auth_path
+                .iter()
+                .map(|(node, b)| ((*node).into(), *b))
+                .collect();
+
+            let (spend_proof, revealed) = create_spend_proof(
+                &spend_params,
+                input.note.value,
+                input.note.token_id,
+                input.note.valcom_blind,
+                self.token_commit_blind,
+                input.note.serial,
+                input.note.coin_blind,
+                input.secret,
+                auth_path,
+                signature_secret,
+            );
+
+            signature_secrets.push(schnorr::SecretKey(signature_secret));
+            partial_inputs.push(PartialTransactionInput {
+                spend_proof,
+                revealed,
+            });
+        }
+
+        let mut outputs = vec![];
+        for output in change {
+            if output.token_id != token_id {
+                return Err(Error::CoBuildFailed("change token does not match".into()));
+            }
+            amount -= output.value as i128;
+
+            let valcom_blind: jubjub::Fr = jubjub::Fr::random(&mut OsRng);
+            blind_delta -= valcom_blind;
+
+            outputs.push(self.mint(&output, valcom_blind, mint_params).0);
+        }
+
+        if amount <= 0 || amount > self.output.value as i128 {
+            return Err(Error::CoBuildFailed(format!(
+                "invalid contribution amount {}",
+                amount
+            )));
+        }
+
+        let contribution = CoBuildContribution {
+            amount: amount as u64,
+            blind_delta,
+            inputs: partial_inputs,
+            outputs,
+            signatures: vec![],
+        };
+
+        let mut encoded = vec![];
+        contribution.encode_without_signatures(&mut encoded)?;
+        self.contributions.push(contribution);
+
+        Ok(CoBuildSigner {
+            index: self.contributions.len() - 1,
+            contribution: encoded,
+            signature_secrets,
+        })
+    }
+
+    /// Mint the shared output once all parties have contributed. Its value
+    /// blind is the sum of the contribution deltas, which balances the
+    /// transaction.
+    pub fn finalize(&mut self, mint_params: &groth16::Parameters<Bls12>) -> Result<()> {
+        if self.shared_output.is_some() {
+            return Err(Error::CoBuildFailed(
+                "transaction is already finalized".into(),
+            ));
+        }
+        self.check_amounts()?;
+
+        let output = TransactionBuilderOutputInfo {
+            value: self.output.value,
+            token_id: self.output.token_id,
+            public: self.output.public,
+            view_public: self.output.public,
+            memo: Memo::empty(),
+        };
+        let (output, serial, coin_blind) = self.mint(&output, self.shared_blind(), mint_params);
+
+        self.shared_output = Some(CoBuildSharedOutput {
+            output,
+            serial,
+            coin_blind,
+        });
+        Ok(())
+    }
+
+    fn mint(
+        &self,
+        output: &TransactionBuilderOutputInfo,
+        valcom_blind: jubjub::Fr,
+        mint_params: &groth16::Parameters<Bls12>,
+    ) -> (TransactionOutput, jubjub::Fr, jubjub::Fr) {
+        let serial: jubjub::Fr = jubjub::Fr::random(&mut OsRng);
+        let coin_blind: jubjub::Fr = jubjub::Fr::random(&mut OsRng);
+
+        let (mint_proof, revealed) = create_mint_proof(
+            mint_params,
+            output.value,
+            output.token_id,
+            valcom_blind,
+            self.token_commit_blind,
+            serial,
+            coin_blind,
+            output.public,
+        );
+
+        let note = Note {
+            serial,
+            value: output.value,
+            token_id: output.token_id,
+            coin_blind,
+            valcom_blind,
+            memo: output.memo,
+        };
+
+        let enc_note = note.encrypt(&output.view_public).unwrap();
+
+        let output = TransactionOutput {
+            mint_proof,
+            revealed,
+            enc_note,
+            out_note: None,
+        };
+        (output, serial, coin_blind)
+    }
+
+    fn shared_blind(&self) -> jubjub::Fr {
+        self.contributions
+            .iter()
+            .fold(jubjub::Fr::zero(), |total, c| total + c.blind_delta)
+    }
+
+    fn check_amounts(&self) -> Result<()> {
+        if self.contributions.is_empty() {
+            return Err(Error::CoBuildFailed("no contributions".into()));
+        }
+        let total: u128 = self.contributions.iter().map(|c| c.amount as u128).sum();
+        if total != self.output.value as u128 {
+            return Err(Error::CoBuildFailed(format!(
+                "contributions add up to {}, expected {}",
+                total, self.output.value
+            )));
+        }
+        Ok(())
+    }
+
+    /// Check every contribution balances against its declared amount, and
+    /// the shared output commits to the agreed value, token and public key.
+    pub fn validate(&self) -> Result<()> {
+        let shared = match &self.shared_output {
+            Some(shared) => shared,
+            None => return Err(Error::CoBuildFailed("transaction is not finalized".into())),
+        };
+        self.check_amounts()?;
+
+        let token_commit = compute_pedersen_commit(self.output.token_id, &self.token_commit_blind);
+
+        for (i, contribution) in self.contributions.iter().enumerate() {
+            let mut valcom_total = jubjub::SubgroupPoint::identity();
+            for input in &contribution.inputs {
+                if input.revealed.token_commit != token_commit {
+                    return Err(Error::CoBuildFailed(format!(
+                        "contribution {} spends another token",
+                        i
+                    )));
+                }
+                valcom_total += &input.revealed.value_commit;
+            }
+            for output in &contribution.outputs {
+                if output.revealed.token_commit != token_commit {
+                    return Err(Error::CoBuildFailed(format!(
+                        "contribution {} mints another token",
+                        i
+                    )));
+                }
+                valcom_total -= &output.revealed.value_commit;
+            }
+
+            let expected = compute_pedersen_commit(
+                jubjub::Fr::from(contribution.amount),
+                &contribution.blind_delta,
+            );
+            if valcom_total != expected {
+                return Err(Error::CoBuildFailed(format!(
+                    "contribution {} does not balance",
+                    i
+                )));
+            }
+        }
+
+        let revealed = MintRevealedValues::compute(
+            self.output.value,
+            self.output.token_id,
+            &self.shared_blind(),
+            &self.token_commit_blind,
+            &shared.serial,
+            &shared.coin_blind,
+            &self.output.public,
+        );
+        if shared.output.revealed.value_commit != revealed.value_commit
+            || shared.output.revealed.token_commit != revealed.token_commit
+            || shared.output.revealed.coin != revealed.coin
+        {
+            return Err(Error::CoBuildFailed(
+                "shared output does not match the agreed output".into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Sign the inputs of our own contribution, after checking it reached
+    /// the final transaction unaltered.
+    pub fn sign(&mut self, signer: &CoBuildSigner) -> Result<()> {
+        self.validate()?;
+
+        let contribution = self
+            .contributions
+            .get(signer.index)
+            .ok_or_else(|| Error::CoBuildFailed("contribution is missing".into()))?;
+
+        let mut encoded = vec![];
+        contribution.encode_without_signatures(&mut encoded)?;
+        if encoded != signer.contribution {
+            return Err(Error::CoBuildFailed("contribution was altered".into()));
+        }
+
+        let unsigned_tx_data = self.unsigned_tx_data()?;
+        let signatures = signer
+            .signature_secrets
+            .iter()
+            .map(|secret| secret.sign(&unsigned_tx_data[..]))
+            .collect();
+
+        self.contributions[signer.index].signatures = signatures;
+        Ok(())
+    }
+
+    /// Assemble the final transaction once every party has signed.
+    pub fn combine(self) -> Result<Transaction> {
+        self.validate()?;
+        let unsigned_tx_data = self.unsigned_tx_data()?;
+
+        let mut inputs = vec![];
+        let mut outputs = vec![];
+        for (i, contribution) in self.contributions.into_iter().enumerate() {
+            if contribution.signatures.len() != contribution.inputs.len() {
+                return Err(Error::CoBuildFailed(format!(
+                    "contribution {} is not signed",
+                    i
+                )));
+            }
+
+            for (input, signature) in contribution
+                .inputs
+                .into_iter()
+                .zip(contribution.signatures.into_iter())
+            {
+                let public = schnorr::PublicKey(input.revealed.signature_public);
+                if !public.verify(&unsigned_tx_data[..], &signature) {
+                    return Err(Error::CoBuildFailed(format!(
+                        "contribution {} has an invalid signature",
+                        i
+                    )));
+                }
+                inputs.push(TransactionInput::from_partial(input, signature));
+            }
+
+            outputs.extend(contribution.outputs);
+        }
+        outputs.push(self.shared_output.unwrap().output);
+
+        Ok(Transaction {
+            clear_inputs: vec![],
+            inputs,
+            outputs,
+            fee: 0,
+        })
+    }
+
+    // Same layout as the data signed by TransactionBuilder: no clear
+    // inputs, then all inputs and outputs in contribution order with the
+    // shared output last, and a zero fee.
+    fn unsigned_tx_data(&self) -> Result<Vec<u8>> {
+        let shared = match &self.shared_output {
+            Some(shared) => shared,
+            None => return Err(Error::CoBuildFailed("transaction is not finalized".into())),
+        };
+
+        let inputs_len: usize = self.contributions.iter().map(|c| c.inputs.len()).sum();
+        let outputs_len: usize = self.contributions.iter().map(|c| c.outputs.len()).sum();
+
+        let mut data = vec![];
+        VarInt(0).encode(&mut data)?;
+        VarInt(inputs_len as u64).encode(&mut data)?;
+        for contribution in &self.contributions {
+            for input in &contribution.inputs {
+                input.encode(&mut data)?;
+            }
+        }
+        VarInt(outputs_len as u64 + 1).encode(&mut data)?;
+        for contribution in &self.contributions {
+            for output in &contribution.outputs {
+                output.encode(&mut data)?;
+            }
+        }
+        shared.output.encode(&mut data)?;
+        // Co-built transactions carry no fee
+        0u64.encode(&mut data)?;
+        Ok(data)
+    }
+}
+
+impl CoBuildContribution {
+    fn encode_without_signatures<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.amount.encode(&mut s)?;
+        len += self.blind_delta.encode(&mut s)?;
+        len += self.inputs.encode(&mut s)?;
+        len += self.outputs.encode(s)?;
+        Ok(len)
+    }
+}
+
+impl Encodable for CoBuildOutput {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.value.encode(&mut s)?;
+        len += self.token_id.encode(&mut s)?;
+        len += self.public.encode(s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for CoBuildOutput {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self {
+            value: Decodable::decode(&mut d)?,
+            token_id: Decodable::decode(&mut d)?,
+            public: Decodable::decode(d)?,
+        })
+    }
+}
+
+impl Encodable for CoBuildContribution {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.encode_without_signatures(&mut s)?;
+        len += self.signatures.encode(s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for CoBuildContribution {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self {
+            amount: Decodable::decode(&mut d)?,
+            blind_delta: Decodable::decode(&mut d)?,
+            inputs: Decodable::decode(&mut d)?,
+            outputs: Decodable::decode(&mut d)?,
+            signatures: Decodable::decode(d)?,
+        })
+    }
+}
+
+impl Encodable for CoBuildSharedOutput {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.output.encode(&mut s)?;
+        len += self.serial.encode(&mut s)?;
+        len += self.coin_blind.encode(s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for CoBuildSharedOutput {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self {
+            output: Decodable::decode(&mut d)?,
+            serial: Decodable::decode(&mut d)?,
+            coin_blind: Decodable::decode(d)?,
+        })
+    }
+}
+
+impl Encodable for CoBuildTransaction {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.output.encode(&mut s)?;
+        len += self.token_commit_blind.encode(&mut s)?;
+        len += self.contributions.encode(&mut s)?;
+        len += self.shared_output.encode(s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for CoBuildTransaction {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self {
+            output: Decodable::decode(&mut d)?,
+            token_commit_blind: Decodable::decode(&mut d)?,
+            contributions: Decodable::decode(&mut d)?,
+            shared_output: Decodable::decode(d)?,
+        })
+    }
+}
+
+impl_vec!(CoBuildContribution);
+impl_vec!(schnorr::Signature);
@@ -1,4 +1,6 @@
 pub mod builder;
+pub mod cashier;
+pub mod cobuild;
 pub mod partial;
 
 use bellman::groth16;
@@ -8,7 +10,8 @@ use std::io;
 
 use self::partial::{PartialTransactionClearInput, PartialTransactionInput};
 use crate::crypto::{
-    note::EncryptedNote, schnorr, verify_mint_proof, verify_spend_proof, MintRevealedValues,
+    note::{EncryptedNote, EncryptedOutgoingNote},
+    schnorr, verify_mint_proof, verify_spend_proof, BatchVerifier, MintRevealedValues,
     SpendRevealedValues,
 };
 use crate::error::Result;
@@ -20,11 +23,15 @@ pub use self::builder::{
     TransactionBuilder, TransactionBuilderClearInputInfo, TransactionBuilderInputInfo,
     TransactionBuilderOutputInfo,
 };
+pub use self::cashier::{Deposit, MintVerifier, Minter};
+pub use self::cobuild::{CoBuildOutput, CoBuildSigner, CoBuildTransaction};
 
 pub struct Transaction {
     pub clear_inputs: Vec<TransactionClearInput>,
     pub inputs: Vec<TransactionInput>,
     pub outputs: Vec<TransactionOutput>,
+    /// Value of the inputs not paid to any output. Zero for no fee.
+    pub fee: u64,
 }
 
 pub struct TransactionClearInput {
@@ -46,6 +53,8 @@ pub struct TransactionOutput {
     pub mint_proof: groth16::Proof<Bls12>,
     pub revealed: MintRevealedValues,
     pub enc_note: EncryptedNote,
+    /// Copy of the output for the sender, readable with their own key
+    pub out_note: Option<EncryptedOutgoingNote>,
 }
 
 impl Transaction {
@@ -53,7 +62,8 @@ impl Transaction {
         let mut len = 0;
         len += self.clear_inputs.encode_without_signature(&mut s)?;
         len += self.inputs.encode_without_signature(&mut s)?;
-        len += self.outputs.encode(s)?;
+        len += self.outputs.encode(&mut s)?;
+        len += self.fee.encode(s)?;
         Ok(len)
     }
 
@@ -88,23 +98,61 @@ impl Transaction {
         mint_pvk: &groth16::PreparedVerifyingKey<Bls12>,
         spend_pvk: &groth16::PreparedVerifyingKey<Bls12>,
     ) -> state::VerifyResult<()> {
-        let mut valcom_total = jubjub::SubgroupPoint::identity();
-        for input in &self.clear_inputs {
-            let value = jubjub::Fr::from(input.value);
-            valcom_total += Self::compute_pedersen_commit(value, &input.valcom_blind);
-        }
+        self.verify_proofs(mint_pvk, spend_pvk)?;
+        self.verify_without_proofs()
+    }
+
+    /// Only the zk proofs of the transaction, one by one
+    pub fn verify_proofs(
+        &self,
+        mint_pvk: &groth16::PreparedVerifyingKey<Bls12>,
+        spend_pvk: &groth16::PreparedVerifyingKey<Bls12>,
+    ) -> state::VerifyResult<()> {
         for (i, input) in self.inputs.iter().enumerate() {
             if !verify_spend_proof(spend_pvk, &input.spend_proof, &input.revealed) {
                 return Err(state::VerifyFailed::SpendProof(i));
             }
-            valcom_total += &input.revealed.value_commit;
         }
         for (i, output) in self.outputs.iter().enumerate() {
             if !verify_mint_proof(mint_pvk, &output.mint_proof, &output.revealed) {
                 return Err(state::VerifyFailed::MintProof(i));
             }
+        }
+        Ok(())
+    }
+
+    /// Queue the zk proofs to be checked together with those of other
+    /// transactions
+    pub fn add_proofs_to_batch(
+        &self,
+        mint_batch: &mut BatchVerifier,
+        spend_batch: &mut BatchVerifier,
+    ) {
+        for input in &self.inputs {
+            spend_batch.add(&input.spend_proof, &input.revealed.make_outputs());
+        }
+        for output in &self.outputs {
+            mint_batch.add(&output.mint_proof, &output.revealed.make_outputs());
+        }
+    }
+
+    /// Everything verify() checks except the zk proofs, for transactions
+    /// whose proofs already passed in a batch
+    pub fn verify_without_proofs(&self) -> state::VerifyResult<()> {
+        let mut valcom_total = jubjub::SubgroupPoint::identity();
+        for input in &self.clear_inputs {
+            let value = jubjub::Fr::from(input.value);
+            valcom_total += Self::compute_pedersen_commit(value, &input.valcom_blind);
+        }
+        for input in &self.inputs {
+            valcom_total += &input.revealed.value_commit;
+        }
+        for output in &self.outputs {
             valcom_total -= &output.revealed.value_commit;
         }
+        // The fee is public, so it's committed to with a zero blind
+        valcom_total -=
+            Self::compute_pedersen_commit(jubjub::Fr::from(self.fee), &jubjub::Fr::zero());
 
         if valcom_total != jubjub::SubgroupPoint::identity() {
             return Err(state::VerifyFailed::MissingFunds);
@@ -181,7 +229,8 @@ impl Encodable for Transaction {
         let mut len = 0;
         len += self.clear_inputs.encode(&mut s)?;
         len += self.inputs.encode(&mut s)?;
-        len += self.outputs.encode(s)?;
+        len += self.outputs.encode(&mut s)?;
+        len += self.fee.encode(s)?;
         Ok(len)
     }
 }
@@ -191,7 +240,8 @@ impl Decodable for Transaction {
         Ok(Self {
             clear_inputs: Decodable::decode(&mut d)?,
             inputs: Decodable::decode(&mut d)?,
-            outputs: Decodable::decode(d)?,
+            outputs: Decodable::decode(&mut d)?,
+            fee: Decodable::decode(d)?,
         })
     }
 }
@@ -248,6 +298,7 @@ impl Encodable for TransactionOutput {
         len += self.mint_proof.encode(&mut s)?;
         len += self.revealed.encode(&mut s)?;
         len += self.enc_note.encode(&mut s)?;
+        len += self.out_note.encode(&mut s)?;
         Ok(len)
     }
 }
@@ -258,6 +309,7 @@ impl Decodable for TransactionOutput {
             mint_proof: Decodable::decode(&mut d)?,
             revealed: Decodable::decode(&mut d)?,
             enc_note: Decodable::decode(&mut d)?,
+            out_note: Decodable::decode(&mut d)?,
         })
     }
 }
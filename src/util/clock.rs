@@ -0,0 +1,126 @@
+//! Wall time for the scheduled and time-limited parts of the daemons, so
+//! tests can drive them with a clock they move forward themselves.
+
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+
+#[async_trait]
+pub trait Clock: Send + Sync {
+    /// Seconds since the unix epoch
+    fn now(&self) -> u64;
+
+    async fn sleep(&self, duration: Duration);
+}
+
+/// The system's clock
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+#[async_trait]
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        smol::Timer::after(duration).await;
+    }
+}
+
+struct MockState {
+    now: Duration,
+    sleepers: Vec<(Duration, async_channel::Sender<()>)>,
+}
+
+/// A clock for tests which only moves when advanced. Sleeps end once the
+/// clock is advanced past them.
+pub struct MockClock {
+    state: Mutex<MockState>,
+}
+
+impl MockClock {
+    pub fn new(now: u64) -> Self {
+        Self {
+            state: Mutex::new(MockState {
+                now: Duration::from_secs(now),
+                sleepers: vec![],
+            }),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.now += duration;
+
+        let now = state.now;
+        state.sleepers.retain(|(until, wake)| {
+            if *until > now {
+                return true;
+            }
+            let _ = wake.try_send(());
+            false
+        });
+    }
+
+    /// Number of sleeps waiting for the clock to advance
+    pub fn sleepers(&self) -> usize {
+        self.state.lock().unwrap().sleepers.len()
+    }
+}
+
+#[async_trait]
+impl Clock for MockClock {
+    fn now(&self) -> u64 {
+        self.state.lock().unwrap().now.as_secs()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        let (wake_s, wake_r) = async_channel::bounded(1);
+        {
+            let mut state = self.state.lock().unwrap();
+            if duration == Duration::from_secs(0) {
+                return;
+            }
+            let until = state.now + duration;
+            state.sleepers.push((until, wake_s));
+        }
+        let _ = wake_r.recv().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = Arc::new(MockClock::new(1000));
+        assert_eq!(clock.now(), 1000);
+
+        let sleeper = {
+            let clock = clock.clone();
+            thread::spawn(move || {
+                smol::future::block_on(clock.sleep(Duration::from_secs(60)));
+                clock.now()
+            })
+        };
+        while clock.sleepers() == 0 {
+            thread::yield_now();
+        }
+
+        // Not far enough yet
+        clock.advance(Duration::from_secs(59));
+        assert_eq!(clock.sleepers(), 1);
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(sleeper.join().unwrap(), 1060);
+        assert_eq!(clock.sleepers(), 0);
+    }
+}
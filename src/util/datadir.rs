@@ -0,0 +1,162 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+
+use super::expand_path;
+use crate::Result;
+
+/// Version of the datadir layout. Every version lives in its own
+/// subdirectory so a layout change never has to touch files in place.
+pub const DATADIR_VERSION: u32 = 1;
+
+pub const DEFAULT_DATADIR: &str = "~/.config/darkfi";
+
+/// The on-disk layout shared by the daemons:
+///
+/// ```text
+/// <datadir>/v1/mint.params
+/// <datadir>/v1/spend.params
+/// <datadir>/v1/<daemon>/...
+/// ```
+///
+/// The params can be kept elsewhere with `set_params_path`, e.g. to share
+/// them between datadirs.
+pub struct Datadir {
+    root: PathBuf,
+    params: PathBuf,
+    daemon: String,
+}
+
+impl Datadir {
+    pub fn open(path: &str, daemon: &str) -> Result<Self> {
+        let root = expand_path(path)?.join(format!("v{}", DATADIR_VERSION));
+        fs::create_dir_all(root.join(daemon))?;
+
+        Ok(Self {
+            params: root.clone(),
+            root,
+            daemon: daemon.to_string(),
+        })
+    }
+
+    /// Keep the params in the given directory instead of the datadir
+    pub fn set_params_path(&mut self, path: &str) -> Result<()> {
+        let params = expand_path(path)?;
+        fs::create_dir_all(&params)?;
+        self.params = params;
+        Ok(())
+    }
+
+    pub fn mint_params(&self) -> PathBuf {
+        self.params.join("mint.params")
+    }
+
+    pub fn spend_params(&self) -> PathBuf {
+        self.params.join("spend.params")
+    }
+
+    /// Directory of the files belonging to this daemon
+    pub fn daemon_dir(&self) -> PathBuf {
+        self.root.join(&self.daemon)
+    }
+
+    /// Path of a file belonging to this daemon
+    pub fn file(&self, name: &str) -> PathBuf {
+        self.root.join(&self.daemon).join(name)
+    }
+
+    /// Move a file or database from its pre-datadir location to `target`,
+    /// and return the path it should be used from. `legacy` is the value of
+    /// the deprecated config key, `fallback` the old default location.
+    /// Nothing in the datadir is ever overwritten, and if the file can't be
+    /// moved the old location keeps being used.
+    pub fn migrate(
+        &self,
+        legacy: Option<&str>,
+        fallback: &str,
+        target: PathBuf,
+    ) -> Result<PathBuf> {
+        let legacy = match legacy {
+            Some(path) => expand_path(path)?,
+            None => expand_path(fallback)?,
+        };
+
+        if target.exists() || !legacy.exists() {
+            return Ok(target);
+        }
+
+        match Self::move_path(&legacy, &target) {
+            Ok(()) => {
+                info!(target: "DATADIR", "Migrated {:?} to {:?}", legacy, target);
+                Ok(target)
+            }
+            Err(e) => {
+                warn!(
+                    target: "DATADIR",
+                    "Unable to migrate {:?} to {:?}: {}. Using the old location.",
+                    legacy, target, e
+                );
+                Ok(legacy)
+            }
+        }
+    }
+
+    fn move_path(from: &Path, to: &Path) -> std::io::Result<()> {
+        match fs::rename(from, to) {
+            Ok(()) => Ok(()),
+            // A rename can't cross filesystems. Plain files are copied
+            // instead, leaving the original where it was.
+            Err(_) if from.is_file() => fs::copy(from, to).map(|_| ()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_datadir_migration() -> Result<()> {
+        let base = std::env::temp_dir().join("darkfi_datadir_test");
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(&base)?;
+
+        let legacy = base.join("mint.params");
+        fs::write(&legacy, b"params")?;
+
+        let datadir = Datadir::open(base.join("data").to_str().unwrap(), "darkfid")?;
+        let path = datadir.migrate(legacy.to_str(), "mint.params", datadir.mint_params())?;
+
+        assert_eq!(path, base.join("data").join("v1").join("mint.params"));
+        assert_eq!(fs::read(&path)?, b"params");
+        assert!(!legacy.exists());
+
+        // An existing file in the datadir is left alone
+        fs::write(&legacy, b"stale")?;
+        let path = datadir.migrate(legacy.to_str(), "mint.params", datadir.mint_params())?;
+        assert_eq!(fs::read(&path)?, b"params");
+
+        let wallet = datadir.migrate(None, "/nonexistent/wallet.db", datadir.file("wallet.db"))?;
+        assert_eq!(
+            wallet,
+            base.join("data")
+                .join("v1")
+                .join("darkfid")
+                .join("wallet.db")
+        );
+
+        let mut datadir = datadir;
+        datadir.set_params_path(base.join("params").to_str().unwrap())?;
+        assert_eq!(
+            datadir.mint_params(),
+            base.join("params").join("mint.params")
+        );
+        assert!(base.join("params").is_dir());
+
+        fs::remove_dir_all(&base)?;
+
+        Ok(())
+    }
+}
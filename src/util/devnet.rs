@@ -0,0 +1,242 @@
+//! A development network in a single darkfid, for demos, tutorials and
+//! integration tests. Instead of connecting to a gateway and cashiers,
+//! darkfid serves its own gateway in-process and trusts a mock cashier
+//! whose key it holds, which funds the wallet on the first run. Everything
+//! is kept in one directory, away from the regular datadir.
+
+use async_std::sync::{Arc, Mutex};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use async_executor::Executor;
+use bellman::groth16;
+use bls12_381::Bls12;
+use ff::Field;
+use log::{info, warn};
+use rand::rngs::OsRng;
+
+use super::{encode_base10, generate_id, Datadir, NetworkName};
+use crate::blockchain::{kv::columns, KvBackend, KvColumn, SlabStore};
+use crate::cli::DarkfidConfig;
+use crate::client::Client;
+use crate::crypto::{note::Memo, Address};
+use crate::serial::{deserialize, serialize};
+use crate::service::{Compression, GatewayLimits, GatewayService};
+use crate::tx::{Deposit, Minter};
+use crate::Result;
+
+pub const DEFAULT_DEVNET_DIR: &str = "~/.config/darkfi/devnet";
+
+pub const DEVNET_RPC_ADDRESS: &str = "127.0.0.1:18000";
+pub const DEVNET_GATEWAY_ADDRESS: &str = "127.0.0.1:13333";
+pub const DEVNET_PUBLISHER_ADDRESS: &str = "127.0.0.1:14444";
+
+pub const DEVNET_WALLET_PASSWORD: &str = "devnet";
+
+/// What the mock cashier pays the wallet on the first run, 1000 BTC
+pub const DEVNET_FUNDS: u64 = 1000 * 100_000_000;
+
+// The funds are credited as BTC, which every node knows the symbol of
+const DEVNET_TOKEN: &str = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+
+pub struct Devnet {
+    dir: PathBuf,
+    // Secret key of the mock cashier
+    cashier: jubjub::Fr,
+}
+
+impl Devnet {
+    /// The devnet kept in `dir`. The cashier key is made on the first run
+    /// and kept, so the coins it minted stay valid across restarts.
+    pub fn open(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+
+        let key_path = dir.join("cashier.key");
+        let cashier = if key_path.exists() {
+            deserialize(&bs58::decode(fs::read_to_string(&key_path)?.trim()).into_vec()?)?
+        } else {
+            let cashier = jubjub::Fr::random(&mut OsRng);
+            fs::write(&key_path, bs58::encode(serialize(&cashier)).into_string())?;
+            cashier
+        };
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            cashier,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The key nodes trust the mock cashier's mints with
+    pub fn cashier_public(&self) -> jubjub::SubgroupPoint {
+        Minter::new(self.cashier).public()
+    }
+
+    /// The darkfid config for the devnet. The deprecated paths point into
+    /// the devnet, so nothing is migrated from the regular datadir.
+    pub fn config(&self) -> Result<DarkfidConfig> {
+        let datadir_path = self.dir.join("node");
+        let datadir_str = datadir_path.to_string_lossy();
+        let datadir = Datadir::open(&datadir_str, "darkfid")?;
+
+        let config = format!(
+            r#"
+rpc_listen_address = "{rpc}"
+serve_tls = false
+gateway_protocol_url = "tcp://{gateway}"
+gateway_publisher_url = "tcp://{publisher}"
+datadir = {datadir:?}
+mint_params_path = {mint:?}
+spend_params_path = {spend:?}
+database_path = {database:?}
+wallet_path = {wallet:?}
+wallet_password = "{password}"
+provisioning_public_key = ""
+"#,
+            rpc = DEVNET_RPC_ADDRESS,
+            gateway = DEVNET_GATEWAY_ADDRESS,
+            publisher = DEVNET_PUBLISHER_ADDRESS,
+            datadir = datadir_str,
+            mint = datadir.mint_params().to_string_lossy(),
+            spend = datadir.spend_params().to_string_lossy(),
+            database = datadir.file("client.db").to_string_lossy(),
+            wallet = datadir.file("wallet.db").to_string_lossy(),
+            password = DEVNET_WALLET_PASSWORD,
+        );
+        Ok(toml::from_str(&config)?)
+    }
+
+    /// Serve the devnet gateway from this process, with its slabs kept in
+    /// the devnet
+    pub fn start_gateway(&self, executor: Arc<Executor<'_>>) -> Result<()> {
+        let store = KvBackend::default().open(&self.dir.join("gateway.db"))?;
+        let slabstore = SlabStore::new(KvColumn::<columns::Slabs>::new(store))?;
+
+        let gateway = GatewayService::new(
+            DEVNET_GATEWAY_ADDRESS.parse::<SocketAddr>()?,
+            DEVNET_PUBLISHER_ADDRESS.parse::<SocketAddr>()?,
+            slabstore,
+            Compression::None,
+            vec![],
+            vec![],
+            None,
+            GatewayLimits::default(),
+        )?;
+
+        let ex = executor.clone();
+        executor
+            .spawn(async move {
+                if let Err(e) = gateway.start(ex).await {
+                    warn!(target: "DEVNET", "Devnet gateway stopped: {}", e);
+                }
+            })
+            .detach();
+        Ok(())
+    }
+
+    /// Have the mock cashier pay DEVNET_FUNDS to the wallet, the first
+    /// time only. Returns whether it did.
+    pub async fn fund(
+        &self,
+        client: &Mutex<Client>,
+        mint_params: Arc<groth16::Parameters<Bls12>>,
+        spend_params: Arc<groth16::Parameters<Bls12>>,
+    ) -> Result<bool> {
+        let marker = self.dir.join("funded");
+        if marker.exists() {
+            return Ok(false);
+        }
+
+        let deposit = Deposit {
+            value: DEVNET_FUNDS,
+            token_id: generate_id(DEVNET_TOKEN, &NetworkName::Bitcoin)?,
+            recipient: client.lock().await.address(),
+            memo: Memo::empty(),
+        };
+        info!(target: "DEVNET", "Minting the devnet funds");
+        let tx = Minter::new(self.cashier)
+            .mint_async(&[deposit], mint_params, spend_params)
+            .await;
+        client.lock().await.broadcast(tx).await?;
+
+        fs::write(&marker, b"")?;
+        Ok(true)
+    }
+
+    /// Write a drk config pointed at the devnet darkfid and return where
+    pub fn write_drk_config(&self, config: &DarkfidConfig) -> Result<PathBuf> {
+        let path = self.dir.join("drk.toml");
+        fs::write(
+            &path,
+            format!(
+                "darkfid_rpc_url = \"tcp://{}\"\n",
+                config.rpc_listen_address
+            ),
+        )?;
+        Ok(path)
+    }
+
+    /// What a user needs to start using the devnet
+    pub fn credentials(
+        &self,
+        config: &DarkfidConfig,
+        address: &Address,
+        drk_config: &Path,
+    ) -> String {
+        format!(
+            "DarkFi devnet is ready\n\
+             \n  RPC:             tcp://{}\
+             \n  Wallet password: {}\
+             \n  Wallet address:  {}\
+             \n  Funds:           {} BTC, once the mint is applied\
+             \n  Gateway:         {}\
+             \n  Devnet data:     {}\
+             \n\n  drk -c {} wallet --balances\n",
+            config.rpc_listen_address,
+            config.wallet_password,
+            address,
+            encode_base10(DEVNET_FUNDS, 8),
+            config.gateway_protocol_url,
+            self.dir.display(),
+            drk_config.display(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_devnet_config() -> Result<()> {
+        let dir = std::env::temp_dir().join("darkfi_test_devnet");
+        let _ = fs::remove_dir_all(&dir);
+
+        let devnet = Devnet::open(&dir)?;
+        let config = devnet.config()?;
+        assert_eq!(config.rpc_listen_address.to_string(), DEVNET_RPC_ADDRESS);
+        assert_eq!(config.wallet_password, DEVNET_WALLET_PASSWORD);
+        assert!(config.cashiers.is_empty());
+
+        // Nothing points outside the devnet
+        for path in &[
+            &config.datadir,
+            config.wallet_path.as_ref().unwrap(),
+            config.database_path.as_ref().unwrap(),
+            config.mint_params_path.as_ref().unwrap(),
+        ] {
+            assert!(Path::new(path).starts_with(&dir));
+        }
+
+        // The cashier is the same one on the next run
+        let reopened = Devnet::open(&dir)?;
+        assert_eq!(reopened.cashier_public(), devnet.cashier_public());
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
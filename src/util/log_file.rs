@@ -0,0 +1,197 @@
+//! A debug log written to a file besides the log on stdout, for daemons
+//! that run for long. The file is rotated once it grows past a size or
+//! age: log becomes log.1, log.1 becomes log.2 and so on, and the oldest
+//! past the number kept is deleted.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::Result;
+
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    /// Seconds a file is written to, 0 for no limit
+    max_age: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+    opened: u64,
+}
+
+impl RotatingFile {
+    /// Append to the file at `path`, which is rotated when it's written
+    /// to `max_age` seconds after `now`
+    pub fn open(path: &Path, max_size: u64, max_age: u64, keep: usize, now: u64) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_size,
+            max_age,
+            keep,
+            file,
+            size,
+            opened: now,
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.as_os_str().to_owned();
+        path.push(format!(".{}", n));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self, now: u64) -> io::Result<()> {
+        if self.keep > 0 {
+            let _ = fs::remove_file(self.rotated(self.keep));
+            for n in (1..self.keep).rev() {
+                let from = self.rotated(n);
+                if from.exists() {
+                    fs::rename(&from, self.rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = File::create(&self.path)?;
+        self.size = 0;
+        self.opened = now;
+        Ok(())
+    }
+
+    /// Write a line, after rotating the file if it's due at `now`
+    pub fn write_line(&mut self, line: &str, now: u64) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        let too_big = self.size > 0 && self.size + len > self.max_size;
+        let too_old = self.max_age > 0 && now >= self.opened.saturating_add(self.max_age);
+        if too_big || too_old {
+            self.rotate(now)?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+}
+
+/// Logs up to `level` on stdout and everything up to debug to the file
+struct FileLogger {
+    level: Level,
+    file: Mutex<RotatingFile>,
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Debug
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let line = format!(
+            "{} {:<5} [{}] {}",
+            format_time(now),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+
+        if record.level() <= self.level {
+            println!("{}", line);
+        }
+        if let Ok(mut file) = self.file.lock() {
+            if let Err(e) = file.write_line(&line, now) {
+                eprintln!("Writing the log file failed: {}", e);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut file) = self.file.lock() {
+            let _ = file.file.flush();
+        }
+    }
+}
+
+/// Log to stdout up to `level` and to `file`, in place of simple_logger
+pub fn init_file_logger(level: Level, file: RotatingFile) -> Result<()> {
+    log::set_boxed_logger(Box::new(FileLogger {
+        level,
+        file: Mutex::new(file),
+    }))?;
+    log::set_max_level(LevelFilter::Debug);
+    Ok(())
+}
+
+// Seconds since the epoch as a UTC date and time, after the civil date
+// algorithm of Howard Hinnant
+fn format_time(secs: u64) -> String {
+    let (days, secs) = ((secs / 86400) as i64, secs % 86400);
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotating_file() -> Result<()> {
+        let dir = std::env::temp_dir().join("darkfi_test_rotating_file");
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("darkfid.log");
+
+        // Room for two 9-byte lines per file
+        let mut log = RotatingFile::open(&path, 20, 3600, 2, 1000)?;
+        for i in 0..7 {
+            log.write_line(&format!("line {:03}", i), 1000)?;
+        }
+        assert_eq!(fs::read_to_string(&path)?, "line 006\n");
+        assert_eq!(fs::read_to_string(log.rotated(1))?, "line 004\nline 005\n");
+        assert_eq!(fs::read_to_string(log.rotated(2))?, "line 002\nline 003\n");
+        assert!(!log.rotated(3).exists());
+
+        // An old file is rotated however small
+        log.write_line("line 007", 1000 + 3600)?;
+        assert_eq!(fs::read_to_string(&path)?, "line 007\n");
+        assert_eq!(fs::read_to_string(log.rotated(1))?, "line 006\n");
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(0), "1970-01-01 00:00:00");
+        assert_eq!(format_time(951825600), "2000-02-29 12:00:00");
+        assert_eq!(format_time(1634390096), "2021-10-16 13:14:56");
+    }
+}
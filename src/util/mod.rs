@@ -1,9 +1,25 @@
+pub mod clock;
+pub mod datadir;
+pub mod devnet;
+pub mod log_file;
 pub mod net_name;
+pub mod params_fetch;
 pub mod parse;
 pub mod path;
+pub mod provisioning;
+pub mod support_bundle;
 pub mod token_list;
+pub mod webhook;
 
+pub use clock::{Clock, MockClock, SystemClock};
+pub use datadir::{Datadir, DATADIR_VERSION, DEFAULT_DATADIR};
+pub use devnet::{Devnet, DEFAULT_DEVNET_DIR};
+pub use log_file::{init_file_logger, RotatingFile};
 pub use net_name::NetworkName;
+pub use params_fetch::fetch_params;
 pub use parse::{assign_id, decode_base10, encode_base10, generate_id};
 pub use path::{expand_path, join_config_path};
+pub use provisioning::CashierProvisioning;
+pub use support_bundle::SupportBundle;
 pub use token_list::{DrkTokenList, SolTokenList};
+pub use webhook::Webhook;
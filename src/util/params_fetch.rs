@@ -0,0 +1,280 @@
+//! Download of the canonical mint and spend params. Proofs only verify
+//! against the params they were made with, so nodes that each generate
+//! their own can't check each other's transactions.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use async_std::io::{BufReadExt, BufReader, Read, ReadExt, Write as AsyncWrite, WriteExt};
+use log::{info, warn};
+use smol::{Async, Timer};
+use url::Url;
+
+use crate::crypto::{params_hash, params_hash_path};
+use crate::{Error, Result};
+
+/// Attempts at a download, each one resuming where the last one stopped
+const FETCH_ATTEMPTS: u64 = 5;
+
+// The download is kept in <path>.part until it is complete, so it can be
+// resumed by a later attempt or a later run
+fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+/// Download the params at `url` to `path`, unless they are already there.
+/// They must hash to `hash`, which is recorded next to them for
+/// `load_params` to check.
+pub async fn fetch_params(url: &str, hash: &str, path: &Path) -> Result<()> {
+    if path.exists() {
+        if params_hash(&fs::read(path)?) == hash {
+            return Ok(());
+        }
+        warn!(
+            "{} aren't the canonical params, move them away to fetch those",
+            path.display()
+        );
+        return Err(Error::ParamsHashMismatch(path.display().to_string()));
+    }
+
+    let url = Url::parse(url)?;
+    let part = part_path(path);
+
+    let mut attempt = 1;
+    loop {
+        match download(&url, &part).await {
+            Ok(()) => break,
+            Err(e) if attempt < FETCH_ATTEMPTS => {
+                warn!("Fetching {} failed: {}. Resuming.", url, e);
+                Timer::after(Duration::from_secs(attempt)).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    if params_hash(&fs::read(&part)?) != hash {
+        // Resuming a corrupted download would only corrupt it further
+        fs::remove_file(&part)?;
+        return Err(Error::ParamsHashMismatch(url.to_string()));
+    }
+
+    fs::rename(&part, path)?;
+    fs::write(params_hash_path(path), hash)?;
+    info!("Fetched {} from {}", path.display(), url);
+    Ok(())
+}
+
+async fn download(url: &Url, part: &Path) -> Result<()> {
+    let host = url.host_str().ok_or(Error::UrlParseError)?.to_string();
+    let port = url.port_or_known_default().ok_or(Error::UrlParseError)?;
+
+    let socket_addr = {
+        let host = host.clone();
+        smol::unblock(move || (host.as_str(), port).to_socket_addrs())
+            .await?
+            .next()
+            .ok_or(Error::UrlParseError)?
+    };
+
+    let stream = Async::<TcpStream>::connect(socket_addr).await?;
+
+    match url.scheme() {
+        "http" => get(stream, url, &host, part).await,
+        "https" => {
+            let stream = async_native_tls::connect(&host, stream).await?;
+            get(stream, url, &host, part).await
+        }
+        _ => Err(Error::UrlParseError),
+    }
+}
+
+// A plain HTTP/1.0 GET for the rest of the file, which keeps servers from
+// chunking the reply
+async fn get<S: Read + AsyncWrite + Unpin>(
+    mut stream: S,
+    url: &Url,
+    host: &str,
+    part: &Path,
+) -> Result<()> {
+    let offset = fs::metadata(part).map(|m| m.len()).unwrap_or(0);
+
+    let mut target = url.path().to_string();
+    if let Some(query) = url.query() {
+        target.push('?');
+        target.push_str(query);
+    }
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nRange: bytes={}-\r\nUser-Agent: darkfid\r\n\r\n",
+        target, host, offset
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let status = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(|| Error::ParamsFetchFailed(format!("Bad status line {:?}", line.trim())))?;
+
+    let mut content_length = None;
+    let mut content_range = None;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(Error::ParamsFetchFailed(
+                "Connection closed in the headers".to_string(),
+            ));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(colon) = header.find(':') {
+            let (name, value) = (&header[..colon], header[colon + 1..].trim());
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.parse::<u64>().ok();
+            } else if name.eq_ignore_ascii_case("content-range") {
+                content_range = Some(value.to_string());
+            }
+        }
+    }
+
+    let mut file = match status {
+        206 => {
+            let expected = format!("bytes {}-", offset);
+            if !content_range.map_or(false, |range| range.starts_with(&expected)) {
+                fs::remove_file(part)?;
+                return Err(Error::ParamsFetchFailed(
+                    "Server sent another range, starting over".to_string(),
+                ));
+            }
+            OpenOptions::new().append(true).create(true).open(part)?
+        }
+        // The server ignored the range and sent the whole file
+        200 => File::create(part)?,
+        // Nothing is left past the end of what we have
+        416 => return Ok(()),
+        _ => {
+            return Err(Error::ParamsFetchFailed(format!(
+                "{} replied with status {}",
+                url, status
+            )))
+        }
+    };
+
+    let mut received = 0u64;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        file.write_all(&buf[..n])?;
+        received += n as u64;
+    }
+    file.flush()?;
+
+    if let Some(length) = content_length {
+        if received < length {
+            return Err(Error::ParamsFetchFailed(format!(
+                "Interrupted after {} of {} bytes",
+                received, length
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader as StdBufReader};
+    use std::net::TcpListener;
+    use std::thread;
+
+    // Serves `data`, honouring the Range header. The first reply is cut
+    // off halfway through.
+    fn serve(listener: TcpListener, data: Vec<u8>, connections: usize) {
+        for i in 0..connections {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = StdBufReader::new(stream.try_clone().unwrap());
+
+            let mut offset = 0;
+            let mut line = String::new();
+            loop {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+                if line.trim_end().is_empty() {
+                    break;
+                }
+                if let Some(range) = line.strip_prefix("Range: bytes=") {
+                    offset = range.trim_end().trim_end_matches('-').parse().unwrap();
+                }
+            }
+
+            let body = &data[offset..];
+            let head = if offset == 0 {
+                format!("HTTP/1.0 200 OK\r\nContent-Length: {}\r\n\r\n", body.len())
+            } else {
+                format!(
+                    "HTTP/1.0 206 Partial Content\r\nContent-Length: {}\r\n\
+                     Content-Range: bytes {}-{}/{}\r\n\r\n",
+                    body.len(),
+                    offset,
+                    data.len() - 1,
+                    data.len()
+                )
+            };
+            stream.write_all(head.as_bytes()).unwrap();
+            let body = if i == 0 {
+                &body[..body.len() / 2]
+            } else {
+                body
+            };
+            stream.write_all(body).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_fetch_params_resumes() -> Result<()> {
+        let path = std::env::temp_dir().join("darkfi_test_fetch.params");
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(part_path(&path));
+
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let hash = params_hash(&data);
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}/mint.params", listener.local_addr()?);
+        let server = {
+            let data = data.clone();
+            thread::spawn(move || serve(listener, data, 2))
+        };
+
+        smol::future::block_on(fetch_params(&url, &hash, &path))?;
+        server.join().unwrap();
+
+        assert_eq!(fs::read(&path)?, data);
+        assert_eq!(fs::read_to_string(params_hash_path(&path))?, hash);
+        assert!(!part_path(&path).exists());
+
+        // Present params with the right hash aren't fetched again, and
+        // ones with another hash are refused
+        smol::future::block_on(fetch_params("http://127.0.0.1:1/", &hash, &path))?;
+        let other = params_hash(b"other");
+        assert!(smol::future::block_on(fetch_params(&url, &other, &path)).is_err());
+
+        fs::remove_file(&path)?;
+        fs::remove_file(params_hash_path(&path))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,101 @@
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cli::cli_config::Cashier;
+use crate::crypto::schnorr;
+use crate::serial::{deserialize, serialize, Encodable, VarInt};
+use crate::{Error, Result};
+
+/// A list of cashiers signed by the network's provisioning key. darkfid
+/// only trusts cashier public keys that come from a file like this, so a
+/// cashier can't be added by editing a config file alone.
+///
+/// ```json
+/// {
+///     "cashiers": [{"name": "...", "rpc_url": "...", "public_key": "..."}],
+///     "signature": "<base58 schnorr signature>"
+/// }
+/// ```
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CashierProvisioning {
+    pub cashiers: Vec<Cashier>,
+    pub signature: String,
+}
+
+impl CashierProvisioning {
+    pub fn sign(cashiers: Vec<Cashier>, secret: &schnorr::SecretKey) -> Result<Self> {
+        let signature = secret.sign(&Self::message(&cashiers)?);
+
+        Ok(Self {
+            cashiers,
+            signature: bs58::encode(serialize(&signature)).into_string(),
+        })
+    }
+
+    /// Read a provisioning file and return its cashiers, provided it is
+    /// signed by `authority`.
+    pub fn load(path: &Path, authority: &schnorr::PublicKey) -> Result<Vec<Cashier>> {
+        let provisioning: Self = serde_json::from_str(&fs::read_to_string(path)?)?;
+        provisioning.verify(authority)?;
+        Ok(provisioning.cashiers)
+    }
+
+    pub fn verify(&self, authority: &schnorr::PublicKey) -> Result<()> {
+        let signature: schnorr::Signature = deserialize(&bs58::decode(&self.signature).into_vec()?)
+            .map_err(|_| Error::CashierProvisioningFailed("Malformed signature"))?;
+
+        if !authority.verify(&Self::message(&self.cashiers)?, &signature) {
+            return Err(Error::CashierProvisioningFailed("Invalid signature"));
+        }
+        Ok(())
+    }
+
+    fn message(cashiers: &[Cashier]) -> Result<Vec<u8>> {
+        let mut message = b"DarkFi_CashierProvisioning".to_vec();
+        VarInt(cashiers.len() as u64).encode(&mut message)?;
+        for cashier in cashiers {
+            cashier.name.encode(&mut message)?;
+            cashier.rpc_url.encode(&mut message)?;
+            cashier.public_key.encode(&mut message)?;
+        }
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cashier_provisioning() -> Result<()> {
+        let secret = schnorr::SecretKey::random();
+        let authority = secret.public_key();
+
+        let cashiers = vec![Cashier {
+            name: "cashier".into(),
+            rpc_url: "tcp://127.0.0.1:9000".into(),
+            public_key: "5aXW4cJrFbaA8oGcEhZmhDvcb7vPRmGK8kXjbaAq8jAf".into(),
+        }];
+
+        let provisioning = CashierProvisioning::sign(cashiers, &secret)?;
+        assert!(provisioning.verify(&authority).is_ok());
+
+        // Signed by someone else
+        let other = schnorr::SecretKey::random().public_key();
+        assert!(provisioning.verify(&other).is_err());
+
+        // A cashier key swapped in after signing
+        let mut tampered = provisioning.clone();
+        tampered.cashiers[0].public_key = "8kXjbaAq8jAf5aXW4cJrFbaA8oGcEhZmhDvcb7vPRmGK".into();
+        assert!(tampered.verify(&authority).is_err());
+
+        // A cashier added after signing
+        let mut tampered = provisioning;
+        tampered.cashiers.push(tampered.cashiers[0].clone());
+        assert!(tampered.verify(&authority).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,139 @@
+//! JSON payloads POSTed to a URL of the operator's, e.g. so a merchant's
+//! backend can credit a customer as soon as their payment comes in. The
+//! receiving end may be restarting, so a delivery is tried a few times
+//! before it's given up on.
+
+use std::net::{TcpStream, ToSocketAddrs};
+
+use async_std::io::{BufReadExt, BufReader, Read, Write, WriteExt};
+use futures::FutureExt;
+use log::warn;
+use serde_json::Value;
+use smol::Async;
+use url::Url;
+
+use crate::net::utility::sleep;
+use crate::{Error, Result};
+
+/// Attempts at delivering a payload before it's dropped
+pub const WEBHOOK_ATTEMPTS: u32 = 5;
+
+/// Seconds the receiving end has to answer an attempt
+const WEBHOOK_TIMEOUT: u32 = 10;
+
+pub struct Webhook {
+    url: Url,
+}
+
+impl Webhook {
+    pub fn new(url: &str) -> Result<Self> {
+        let url = Url::parse(url)?;
+        if !matches!(url.scheme(), "http" | "https") || url.host_str().is_none() {
+            return Err(Error::UrlParseError);
+        }
+        Ok(Self { url })
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// POST `payload`, trying again after 2, 4, 8... seconds until it's
+    /// taken or WEBHOOK_ATTEMPTS have failed
+    pub async fn post(&self, payload: &Value) -> Result<()> {
+        let body = payload.to_string();
+        let mut attempt = 1;
+        loop {
+            let result = futures::select! {
+                result = self.post_once(&body).fuse() => result,
+                _ = sleep(WEBHOOK_TIMEOUT).fuse() => Err(Error::WebhookFailed(format!(
+                    "no answer in {} seconds",
+                    WEBHOOK_TIMEOUT
+                ))),
+            };
+            match result {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt < WEBHOOK_ATTEMPTS => {
+                    warn!("Webhook {} failed: {}. Retrying.", self.url, e);
+                    sleep(1 << attempt).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn post_once(&self, body: &str) -> Result<()> {
+        let host = self.url.host_str().ok_or(Error::UrlParseError)?.to_string();
+        let port = self.url.port_or_known_default().ok_or(Error::UrlParseError)?;
+
+        let socket_addr = {
+            let host = host.clone();
+            smol::unblock(move || (host.as_str(), port).to_socket_addrs())
+                .await?
+                .next()
+                .ok_or(Error::UrlParseError)?
+        };
+
+        let stream = Async::<TcpStream>::connect(socket_addr).await?;
+
+        match self.url.scheme() {
+            "http" => self.send(stream, &host, body).await,
+            "https" => {
+                let stream = async_native_tls::connect(&host, stream).await?;
+                self.send(stream, &host, body).await
+            }
+            _ => Err(Error::UrlParseError),
+        }
+    }
+
+    // A plain HTTP/1.0 POST, where any 2xx status means the payload was
+    // taken
+    async fn send<S: Read + Write + Unpin>(
+        &self,
+        mut stream: S,
+        host: &str,
+        body: &str,
+    ) -> Result<()> {
+        let mut target = self.url.path().to_string();
+        if let Some(query) = self.url.query() {
+            target.push('?');
+            target.push_str(query);
+        }
+        let request = format!(
+            "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nUser-Agent: darkfid\r\n\r\n{}",
+            target,
+            host,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await?;
+
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).await?;
+        let status = line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse::<u16>().ok())
+            .ok_or_else(|| Error::WebhookFailed(format!("Bad status line {:?}", line.trim())))?;
+
+        if !(200..300).contains(&status) {
+            return Err(Error::WebhookFailed(format!("replied with status {}", status)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_webhook_url() {
+        assert!(Webhook::new("https://shop.example/darkfi/paid?key=1").is_ok());
+        assert!(Webhook::new("http://127.0.0.1:8080/").is_ok());
+        assert!(Webhook::new("tcp://127.0.0.1:8080").is_err());
+        assert!(Webhook::new("not a url").is_err());
+    }
+}
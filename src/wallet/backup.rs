@@ -0,0 +1,503 @@
+//! Scheduled backups of the wallet. The wallet is archived encrypted with
+//! its password, uploaded to the configured destination, and the oldest
+//! archives there are deleted to keep the configured number.
+
+use async_std::sync::{Arc, Mutex};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use async_executor::Executor;
+use log::{debug, warn};
+use serde_json::{json, Value};
+use url::Url;
+
+use super::WalletPtr;
+use crate::cli::BackupConfig;
+use crate::util::{expand_path, Clock};
+use crate::{Error, Result};
+
+const ARCHIVE_PREFIX: &str = "darkfi-wallet-";
+const ARCHIVE_SUFFIX: &str = ".db";
+
+// Archives are named after the time they were made, so the newest ones
+// are known without trusting the destination's timestamps
+fn archive_name(timestamp: u64) -> String {
+    format!("{}{}{}", ARCHIVE_PREFIX, timestamp, ARCHIVE_SUFFIX)
+}
+
+fn archive_time(name: &str) -> Option<u64> {
+    let name = name.rsplit('/').next()?;
+    if !name.starts_with(ARCHIVE_PREFIX) || !name.ends_with(ARCHIVE_SUFFIX) {
+        return None;
+    }
+    name[ARCHIVE_PREFIX.len()..name.len() - ARCHIVE_SUFFIX.len()]
+        .parse()
+        .ok()
+}
+
+/// The archives among `names` beyond the newest `keep`. Files that aren't
+/// archives are left alone.
+pub fn expired_archives(names: &[String], keep: usize) -> Vec<String> {
+    let mut archives: Vec<(u64, &String)> = names
+        .iter()
+        .filter_map(|name| archive_time(name).map(|time| (time, name)))
+        .collect();
+    archives.sort();
+    let expired = archives.len().saturating_sub(keep.max(1));
+    archives[..expired]
+        .iter()
+        .map(|(_, name)| name.to_string())
+        .collect()
+}
+
+pub enum BackupDestination {
+    /// A directory, which can be a mounted network share
+    Local(PathBuf),
+    /// A directory on an SSH server, reached with the system sftp client
+    /// and its keys
+    Sftp {
+        host: String,
+        port: Option<u16>,
+        dir: String,
+    },
+    /// A bucket on an S3-compatible service
+    #[cfg(feature = "s3")]
+    S3 {
+        bucket: s3::bucket::Bucket,
+        /// Prepended to the archive names, empty or ending in a slash
+        prefix: String,
+    },
+}
+
+impl BackupDestination {
+    /// Parse a destination: a local directory, sftp://user@host/dir or,
+    /// with the s3 feature, s3://bucket/prefix
+    pub fn from_config(config: &BackupConfig) -> Result<Self> {
+        if !config.destination.contains("://") {
+            return Ok(Self::Local(expand_path(&config.destination)?));
+        }
+
+        let url = Url::parse(&config.destination)?;
+        match url.scheme() {
+            "file" => Ok(Self::Local(PathBuf::from(url.path()))),
+            "sftp" => {
+                let host = url.host_str().ok_or(Error::UrlParseError)?;
+                let host = match url.username() {
+                    "" => host.to_string(),
+                    user => format!("{}@{}", user, host),
+                };
+                Ok(Self::Sftp {
+                    host,
+                    port: url.port(),
+                    dir: url.path().to_string(),
+                })
+            }
+            #[cfg(feature = "s3")]
+            "s3" => {
+                let missing = || Error::BackupFailed("Incomplete s3 settings".into());
+                let region = s3::Region::Custom {
+                    region: config.s3_region.clone().unwrap_or_default(),
+                    endpoint: config.s3_endpoint.clone().ok_or_else(missing)?,
+                };
+                let credentials = s3::creds::Credentials::new(
+                    Some(config.s3_access_key.as_ref().ok_or_else(missing)?),
+                    Some(config.s3_secret_key.as_ref().ok_or_else(missing)?),
+                    None,
+                    None,
+                    None,
+                )
+                .map_err(|e| Error::BackupFailed(e.to_string()))?;
+                let name = url.host_str().ok_or(Error::UrlParseError)?;
+                let bucket = s3::bucket::Bucket::new_with_path_style(name, region, credentials)
+                    .map_err(|e| Error::BackupFailed(e.to_string()))?;
+                let mut prefix = url.path().trim_matches('/').to_string();
+                if !prefix.is_empty() {
+                    prefix.push('/');
+                }
+                Ok(Self::S3 { bucket, prefix })
+            }
+            _ => Err(Error::BackupFailed(format!(
+                "Unsupported backup destination {}",
+                url.scheme()
+            ))),
+        }
+    }
+
+    async fn upload(&self, archive: &Path, name: &str) -> Result<()> {
+        match self {
+            Self::Local(dir) => {
+                // Copied under another name first, so a half written copy
+                // is never taken for an archive
+                fs::create_dir_all(dir)?;
+                let partial = dir.join(format!("{}.part", name));
+                fs::copy(archive, &partial)?;
+                fs::rename(partial, dir.join(name))?;
+                Ok(())
+            }
+            Self::Sftp { host, port, dir } => {
+                let script = format!(
+                    "-mkdir {dir}\nput {} {dir}/{name}.part\nrename {dir}/{name}.part {dir}/{name}\n",
+                    archive.display(),
+                    dir = dir,
+                    name = name
+                );
+                sftp(host.clone(), *port, script).await?;
+                Ok(())
+            }
+            #[cfg(feature = "s3")]
+            Self::S3 { bucket, prefix } => {
+                let content = fs::read(archive)?;
+                let (_, code) = bucket
+                    .put_object(format!("{}{}", prefix, name), &content)
+                    .await
+                    .map_err(|e| Error::BackupFailed(e.to_string()))?;
+                if code != 200 {
+                    return Err(Error::BackupFailed(format!("Upload failed with {}", code)));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        match self {
+            Self::Local(dir) => {
+                let mut names = vec![];
+                for entry in fs::read_dir(dir)? {
+                    names.push(entry?.file_name().to_string_lossy().into_owned());
+                }
+                Ok(names)
+            }
+            Self::Sftp { host, port, dir } => {
+                let output = sftp(host.clone(), *port, format!("ls -1 {}\n", dir)).await?;
+                // Batch mode echoes the commands it runs
+                Ok(output
+                    .lines()
+                    .filter(|line| !line.starts_with("sftp>"))
+                    .filter_map(|line| line.trim().rsplit('/').next())
+                    .map(|name| name.to_string())
+                    .collect())
+            }
+            #[cfg(feature = "s3")]
+            Self::S3 { bucket, prefix } => {
+                let results = bucket
+                    .list(prefix.clone(), None)
+                    .await
+                    .map_err(|e| Error::BackupFailed(e.to_string()))?;
+                Ok(results
+                    .into_iter()
+                    .flat_map(|result| result.contents)
+                    .map(|object| object.key)
+                    .collect())
+            }
+        }
+    }
+
+    async fn remove(&self, name: &str) -> Result<()> {
+        match self {
+            Self::Local(dir) => {
+                fs::remove_file(dir.join(name))?;
+                Ok(())
+            }
+            Self::Sftp { host, port, dir } => {
+                sftp(host.clone(), *port, format!("rm {}/{}\n", dir, name)).await?;
+                Ok(())
+            }
+            #[cfg(feature = "s3")]
+            Self::S3 { bucket, .. } => {
+                // Listed names are already full keys
+                bucket
+                    .delete_object(name)
+                    .await
+                    .map_err(|e| Error::BackupFailed(e.to_string()))?;
+                Ok(())
+            }
+        }
+    }
+
+    /// The destination without credentials, for status reports
+    pub fn describe(&self) -> String {
+        match self {
+            Self::Local(dir) => dir.display().to_string(),
+            Self::Sftp { host, dir, .. } => format!("sftp://{}{}", host, dir),
+            #[cfg(feature = "s3")]
+            Self::S3 { bucket, prefix } => format!("s3://{}/{}", bucket.name, prefix),
+        }
+    }
+}
+
+// Run a batch of sftp commands, failing on the first one that fails
+async fn sftp(host: String, port: Option<u16>, script: String) -> Result<String> {
+    smol::unblock(move || {
+        let mut command = Command::new("sftp");
+        command.args(&["-q", "-b", "-", "-o", "BatchMode=yes"]);
+        if let Some(port) = port {
+            command.arg("-P").arg(port.to_string());
+        }
+        let mut child = command
+            .arg(&host)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        child
+            .stdin
+            .take()
+            .expect("sftp stdin is piped")
+            .write_all(script.as_bytes())?;
+
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(Error::BackupFailed(format!(
+                "sftp to {} failed: {}",
+                host,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    })
+    .await
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BackupStatus {
+    pub destination: String,
+    /// When the last backup was attempted
+    pub last_attempt: Option<u64>,
+    /// When the last backup was uploaded
+    pub last_success: Option<u64>,
+    /// Why the last attempt failed, if it did
+    pub last_error: Option<String>,
+    /// Archives kept at the destination after the last backup
+    pub archives: usize,
+}
+
+impl BackupStatus {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "destination": self.destination,
+            "last_attempt": self.last_attempt,
+            "last_success": self.last_success,
+            "last_error": self.last_error,
+            "archives": self.archives,
+        })
+    }
+}
+
+pub struct WalletBackup {
+    wallet: WalletPtr,
+    destination: BackupDestination,
+    interval: Duration,
+    keep: usize,
+    // The archive is written here before it's uploaded
+    staging: PathBuf,
+    status: Arc<Mutex<BackupStatus>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl WalletBackup {
+    pub fn new(
+        wallet: WalletPtr,
+        config: &BackupConfig,
+        staging: PathBuf,
+        clock: Arc<dyn Clock>,
+    ) -> Result<Self> {
+        let destination = BackupDestination::from_config(config)?;
+        let status = BackupStatus {
+            destination: destination.describe(),
+            ..BackupStatus::default()
+        };
+
+        Ok(Self {
+            wallet,
+            destination,
+            interval: Duration::from_secs(config.interval),
+            keep: config.keep,
+            staging,
+            status: Arc::new(Mutex::new(status)),
+            clock,
+        })
+    }
+
+    pub fn status(&self) -> Arc<Mutex<BackupStatus>> {
+        self.status.clone()
+    }
+
+    /// Back up the wallet now and then every interval. Failures are
+    /// reported in the status and retried at the next interval.
+    pub fn start(self: Arc<Self>, executor: Arc<Executor<'_>>) {
+        executor
+            .spawn(async move {
+                loop {
+                    if let Err(e) = self.backup().await {
+                        warn!(target: "BACKUP", "Wallet backup failed: {}", e);
+                    }
+                    self.clock.sleep(self.interval).await;
+                }
+            })
+            .detach();
+    }
+
+    pub async fn backup(&self) -> Result<()> {
+        self.backup_at(self.clock.now()).await
+    }
+
+    async fn backup_at(&self, now: u64) -> Result<()> {
+        self.status.lock().await.last_attempt = Some(now);
+
+        let result = self.upload_archive(now).await;
+        // The staged archive is encrypted, but needn't stay around
+        let _ = fs::remove_file(&self.staging);
+
+        let mut status = self.status.lock().await;
+        match result {
+            Ok(archives) => {
+                status.last_success = Some(now);
+                status.last_error = None;
+                status.archives = archives;
+                Ok(())
+            }
+            Err(e) => {
+                status.last_error = Some(e.to_string());
+                Err(e)
+            }
+        }
+    }
+
+    // Returns the number of archives left at the destination
+    async fn upload_archive(&self, now: u64) -> Result<usize> {
+        if self.staging.exists() {
+            fs::remove_file(&self.staging)?;
+        }
+        self.wallet.archive(&self.staging)?;
+
+        let name = archive_name(now);
+        self.destination.upload(&self.staging, &name).await?;
+        debug!(target: "BACKUP", "Uploaded {} to {}", name, self.destination.describe());
+
+        let names = self.destination.list().await?;
+        let expired = expired_archives(&names, self.keep);
+        for name in &expired {
+            debug!(target: "BACKUP", "Remove expired archive {}", name);
+            self.destination.remove(name).await?;
+        }
+
+        let archives = names
+            .iter()
+            .filter(|name| archive_time(name).is_some())
+            .count();
+        Ok(archives - expired.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::{MockClock, SystemClock};
+
+    #[test]
+    fn test_local_backup_retention() -> Result<()> {
+        let dir = std::env::temp_dir().join("darkfi_test_backups");
+        let _ = fs::remove_dir_all(&dir);
+        let wallet_path = std::env::temp_dir().join("darkfi_test_backup_wallet.db");
+        let staging = std::env::temp_dir().join("darkfi_test_backup_staging.db");
+
+        let wallet = crate::wallet::WalletDb::new(&wallet_path, "darkfi".into())?;
+        smol::future::block_on(wallet.init_db())?;
+
+        let config = BackupConfig {
+            destination: dir.to_str().unwrap().to_string(),
+            interval: 60,
+            keep: 2,
+            s3_endpoint: None,
+            s3_region: None,
+            s3_access_key: None,
+            s3_secret_key: None,
+        };
+        let backup = WalletBackup::new(wallet, &config, staging.clone(), Arc::new(SystemClock))?;
+
+        // Other files at the destination are left alone
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join("notes.txt"), "keep me")?;
+
+        for now in &[100, 200, 300] {
+            smol::future::block_on(backup.backup_at(*now))?;
+        }
+
+        let mut names: Vec<String> = fs::read_dir(&dir)?
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                archive_name(200),
+                archive_name(300),
+                "notes.txt".to_string()
+            ]
+        );
+        assert!(!staging.exists());
+
+        let status = smol::future::block_on(backup.status().lock()).clone();
+        assert_eq!(status.last_success, Some(300));
+        assert_eq!(status.last_error, None);
+        assert_eq!(status.archives, 2);
+
+        assert_eq!(
+            expired_archives(&[archive_name(30), archive_name(4), "x.db".into()], 1),
+            vec![archive_name(4)]
+        );
+
+        fs::remove_dir_all(&dir)?;
+        fs::remove_file(wallet_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup_schedule() -> Result<()> {
+        let dir = std::env::temp_dir().join("darkfi_test_scheduled_backups");
+        let _ = fs::remove_dir_all(&dir);
+        let wallet_path = std::env::temp_dir().join("darkfi_test_schedule_wallet.db");
+        let staging = std::env::temp_dir().join("darkfi_test_schedule_staging.db");
+
+        let wallet = crate::wallet::WalletDb::new(&wallet_path, "darkfi".into())?;
+        smol::future::block_on(wallet.init_db())?;
+
+        let config = BackupConfig {
+            destination: dir.to_str().unwrap().to_string(),
+            interval: 3600,
+            keep: 2,
+            s3_endpoint: None,
+            s3_region: None,
+            s3_access_key: None,
+            s3_secret_key: None,
+        };
+        let clock = Arc::new(MockClock::new(1000));
+        let backup = Arc::new(WalletBackup::new(wallet, &config, staging, clock.clone())?);
+        let status = backup.status();
+
+        let executor = Arc::new(Executor::new());
+        backup.start(executor.clone());
+
+        smol::future::block_on(executor.run(async {
+            // A backup is made on start, then one an hour, and none
+            // in between
+            for (advance, last_success) in &[(0, 1000), (1800, 1000), (1800, 4600), (3600, 8200)] {
+                clock.advance(Duration::from_secs(*advance));
+                while clock.sleepers() == 0 {
+                    smol::future::yield_now().await;
+                }
+                assert_eq!(status.lock().await.last_success, Some(*last_success));
+            }
+        }));
+
+        assert_eq!(smol::future::block_on(status.lock()).archives, 2);
+
+        fs::remove_dir_all(&dir)?;
+        fs::remove_file(wallet_path)?;
+        Ok(())
+    }
+}
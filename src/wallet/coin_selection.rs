@@ -0,0 +1,169 @@
+use std::str::FromStr;
+
+use crate::crypto::OwnCoins;
+use crate::{Error, Result};
+
+/// Picks which of our coins fund a transaction
+pub trait CoinSelection {
+    /// Return coins from `coins` worth at least `amount` in total, or None
+    /// if all of them together aren't enough.
+    fn select(&self, coins: OwnCoins, amount: u64) -> Option<OwnCoins>;
+}
+
+/// Spend the smallest coins first, consolidating dust.
+pub struct SmallestFirst;
+
+/// Spend the largest coins first, using as few inputs as possible.
+pub struct LargestFirst;
+
+/// Avoid linking coins together: pay with a single coin whenever one is
+/// large enough, and otherwise merge as few coins as possible.
+pub struct Privacy;
+
+fn accumulate(coins: OwnCoins, amount: u64) -> Option<OwnCoins> {
+    let mut selected = vec![];
+    let mut total: u64 = 0;
+
+    for coin in coins {
+        if total >= amount {
+            break;
+        }
+        total += coin.note.value;
+        selected.push(coin);
+    }
+
+    if total < amount {
+        return None;
+    }
+    Some(selected)
+}
+
+impl CoinSelection for SmallestFirst {
+    fn select(&self, mut coins: OwnCoins, amount: u64) -> Option<OwnCoins> {
+        coins.sort_by_key(|c| c.note.value);
+        accumulate(coins, amount)
+    }
+}
+
+impl CoinSelection for LargestFirst {
+    fn select(&self, mut coins: OwnCoins, amount: u64) -> Option<OwnCoins> {
+        coins.sort_by_key(|c| std::cmp::Reverse(c.note.value));
+        accumulate(coins, amount)
+    }
+}
+
+impl CoinSelection for Privacy {
+    fn select(&self, mut coins: OwnCoins, amount: u64) -> Option<OwnCoins> {
+        coins.sort_by_key(|c| c.note.value);
+
+        if let Some(pos) = coins.iter().position(|c| c.note.value >= amount) {
+            return Some(vec![coins.swap_remove(pos)]);
+        }
+
+        LargestFirst.select(coins, amount)
+    }
+}
+
+/// The coin selection strategies that can be chosen in the darkfid config
+/// and per transfer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoinSelectionStrategy {
+    SmallestFirst,
+    LargestFirst,
+    Privacy,
+}
+
+impl CoinSelectionStrategy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CoinSelectionStrategy::SmallestFirst => "smallest-first",
+            CoinSelectionStrategy::LargestFirst => "largest-first",
+            CoinSelectionStrategy::Privacy => "privacy",
+        }
+    }
+}
+
+impl Default for CoinSelectionStrategy {
+    fn default() -> Self {
+        CoinSelectionStrategy::Privacy
+    }
+}
+
+impl FromStr for CoinSelectionStrategy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "smallest-first" => Ok(CoinSelectionStrategy::SmallestFirst),
+            "largest-first" => Ok(CoinSelectionStrategy::LargestFirst),
+            "privacy" => Ok(CoinSelectionStrategy::Privacy),
+            _ => Err(Error::ParseFailed("Unknown coin selection strategy")),
+        }
+    }
+}
+
+impl CoinSelection for CoinSelectionStrategy {
+    fn select(&self, coins: OwnCoins, amount: u64) -> Option<OwnCoins> {
+        match self {
+            CoinSelectionStrategy::SmallestFirst => SmallestFirst.select(coins, amount),
+            CoinSelectionStrategy::LargestFirst => LargestFirst.select(coins, amount),
+            CoinSelectionStrategy::Privacy => Privacy.select(coins, amount),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{
+        coin::Coin,
+        merkle::{CommitmentTree, IncrementalWitness},
+        note::{Memo, Note},
+        OwnCoin,
+    };
+    use ff::Field;
+    use rand::rngs::OsRng;
+
+    fn coins(values: &[u64]) -> OwnCoins {
+        values
+            .iter()
+            .map(|value| OwnCoin {
+                coin: Coin::new([*value as u8; 32]),
+                note: Note {
+                    serial: jubjub::Fr::random(&mut OsRng),
+                    value: *value,
+                    token_id: jubjub::Fr::one(),
+                    coin_blind: jubjub::Fr::random(&mut OsRng),
+                    valcom_blind: jubjub::Fr::random(&mut OsRng),
+                    memo: Memo::empty(),
+                },
+                secret: jubjub::Fr::random(&mut OsRng),
+                witness: IncrementalWitness::from_tree(&CommitmentTree::empty()),
+            })
+            .collect()
+    }
+
+    fn values(coins: Option<OwnCoins>) -> Vec<u64> {
+        coins.unwrap().iter().map(|c| c.note.value).collect()
+    }
+
+    #[test]
+    fn test_coin_selection() {
+        let available = coins(&[5, 40, 10, 20]);
+
+        assert_eq!(
+            values(SmallestFirst.select(available.clone(), 12)),
+            vec![5, 10]
+        );
+        assert_eq!(values(LargestFirst.select(available.clone(), 12)), vec![40]);
+        assert_eq!(values(Privacy.select(available.clone(), 12)), vec![20]);
+        assert_eq!(values(Privacy.select(available.clone(), 55)), vec![40, 20]);
+        assert!(SmallestFirst.select(available, 100).is_none());
+
+        assert_eq!(
+            "largest-first".parse::<CoinSelectionStrategy>().unwrap(),
+            CoinSelectionStrategy::LargestFirst
+        );
+        assert!("random".parse::<CoinSelectionStrategy>().is_err());
+    }
+}
@@ -0,0 +1,89 @@
+//! Binding the wallet to the machine it lives on. The wallet database key
+//! is derived from the password and a secret kept by the platform key
+//! store, so a copied wallet file can't be opened elsewhere even with the
+//! password. With the `secure-enclave` feature the secret goes to the OS
+//! keyring: the macOS Keychain, the Windows Credential Manager or, on
+//! Linux, the Secret Service. Despite the feature's name, nothing is sealed
+//! to a TPM or secure enclave: the secret is as safe as the user's login
+//! keyring, and anything running as the user can read it. Without the
+//! feature, or when the key store can't be used, the wallet is encrypted
+//! with the password alone.
+
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+
+use crate::Result;
+
+/// Size of the secret a key store keeps for a wallet
+pub const ENCLAVE_SECRET_SIZE: usize = 32;
+
+pub trait KeyEnclave: Send + Sync {
+    /// Name of the key store, for the logs
+    fn name(&self) -> &'static str;
+
+    /// The secret kept for the wallet `id`, if there is one. A wallet's ID
+    /// is the salt at the start of its database, so it's kept when the
+    /// wallet is moved.
+    fn load(&self, id: &str) -> Result<Option<Vec<u8>>>;
+
+    fn store(&self, id: &str, secret: &[u8]) -> Result<()>;
+}
+
+pub fn new_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; ENCLAVE_SECRET_SIZE];
+    OsRng.fill_bytes(&mut secret);
+    secret
+}
+
+/// The database key of a wallet bound to a key store secret
+pub fn wallet_key(password: &str, secret: &[u8]) -> String {
+    let digest = Sha256::new()
+        .chain(b"darkfi wallet key")
+        .chain(password.as_bytes())
+        .chain(secret)
+        .finalize();
+    hex::encode(digest)
+}
+
+/// The key store of the platform, if built with one
+pub fn platform_enclave() -> Option<Box<dyn KeyEnclave>> {
+    #[cfg(feature = "secure-enclave")]
+    return Some(Box::new(platform::PlatformEnclave));
+
+    #[cfg(not(feature = "secure-enclave"))]
+    None
+}
+
+#[cfg(feature = "secure-enclave")]
+mod platform {
+    use super::KeyEnclave;
+    use crate::{Error, Result};
+
+    const SERVICE: &str = "darkfi-wallet";
+
+    /// The OS keyring, not a TPM: secrets are kept as hex passwords of the
+    /// darkfi-wallet service, readable by anything running as the user
+    pub struct PlatformEnclave;
+
+    impl KeyEnclave for PlatformEnclave {
+        fn name(&self) -> &'static str {
+            "OS keyring"
+        }
+
+        fn load(&self, id: &str) -> Result<Option<Vec<u8>>> {
+            match keyring::Entry::new(SERVICE, id).get_password() {
+                Ok(secret) => Ok(Some(
+                    hex::decode(secret).map_err(|e| Error::EnclaveFailed(e.to_string()))?,
+                )),
+                Err(keyring::Error::NoEntry) => Ok(None),
+                Err(e) => Err(Error::EnclaveFailed(e.to_string())),
+            }
+        }
+
+        fn store(&self, id: &str, secret: &[u8]) -> Result<()> {
+            keyring::Entry::new(SERVICE, id)
+                .set_password(&hex::encode(secret))
+                .map_err(|e| Error::EnclaveFailed(e.to_string()))
+        }
+    }
+}
@@ -0,0 +1,113 @@
+//! Transfers that failed, kept with why they failed and what the user can
+//! do about it, so they can be looked into and sent again later.
+
+use serde_json::{json, Value};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FailureReason {
+    /// The coins of the token didn't cover the amount and fee
+    InsufficientFunds,
+    /// Our own check of the transaction failed, e.g. because it was built
+    /// against a merkle root the network no longer accepts
+    ProofRejected,
+    /// The gateway refused the transaction or didn't answer
+    GatewayRejected,
+    /// An emergency stop was in effect
+    WalletFrozen,
+    /// The recipients or amounts were invalid
+    InvalidRequest,
+    /// A payment was over its limit or an unconfirmed first payment
+    PolicyRefused,
+    Other,
+}
+
+impl FailureReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailureReason::InsufficientFunds => "insufficient_funds",
+            FailureReason::ProofRejected => "proof_rejected",
+            FailureReason::GatewayRejected => "gateway_rejected",
+            FailureReason::WalletFrozen => "wallet_frozen",
+            FailureReason::InvalidRequest => "invalid_request",
+            FailureReason::PolicyRefused => "policy_refused",
+            FailureReason::Other => "other",
+        }
+    }
+
+    pub fn from_str(s: &str) -> Self {
+        match s {
+            "insufficient_funds" => FailureReason::InsufficientFunds,
+            "proof_rejected" => FailureReason::ProofRejected,
+            "gateway_rejected" => FailureReason::GatewayRejected,
+            "wallet_frozen" => FailureReason::WalletFrozen,
+            "invalid_request" => FailureReason::InvalidRequest,
+            "policy_refused" => FailureReason::PolicyRefused,
+            _ => FailureReason::Other,
+        }
+    }
+
+    pub fn remediation(&self) -> &'static str {
+        match self {
+            FailureReason::InsufficientFunds => {
+                "Wait for incoming payments or deposit more of the token, \
+                 counting the fee, then retry"
+            }
+            FailureReason::ProofRejected => {
+                "Let the wallet finish syncing, rescan it with `drk rescan` \
+                 so its coins are spendable again, then send the transfer anew"
+            }
+            FailureReason::GatewayRejected => {
+                "The transfer may still have gone through. Check the history and \
+                 balance once synced before sending it again"
+            }
+            FailureReason::WalletFrozen => "Unlock the wallet with `drk unlock`, then retry",
+            FailureReason::InvalidRequest => "Correct the addresses or amounts and send anew",
+            FailureReason::PolicyRefused => {
+                "Raise the recipient's limit with `drk contacts add`, then retry, or \
+                 send a first payment anew with `drk transfer --confirm`"
+            }
+            FailureReason::Other => "See the error detail",
+        }
+    }
+
+    /// Whether the transfer can be sent again as it was. Only failures
+    /// that happen before any coin is reserved or anything is broadcast
+    /// qualify, so a retry can't pay twice.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            FailureReason::InsufficientFunds
+                | FailureReason::WalletFrozen
+                | FailureReason::PolicyRefused
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FailedOperation {
+    pub id: i64,
+    pub timestamp: u64,
+    /// The RPC method the operation was requested with
+    pub method: String,
+    /// Its params, as JSON
+    pub params: String,
+    pub reason: FailureReason,
+    pub detail: String,
+    /// Whether it was already retried
+    pub retried: bool,
+}
+
+impl FailedOperation {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "timestamp": self.timestamp,
+            "method": self.method,
+            "params": serde_json::from_str::<Value>(&self.params).unwrap_or(Value::Null),
+            "reason": self.reason.as_str(),
+            "detail": self.detail,
+            "remediation": self.reason.remediation(),
+            "retryable": self.reason.is_retryable() && !self.retried,
+        })
+    }
+}
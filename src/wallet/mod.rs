@@ -1,7 +1,24 @@
+pub mod backup;
 pub mod cashierdb;
+pub mod coin_selection;
+pub mod enclave;
+pub mod failed_ops;
+pub mod payment_risk;
+pub mod policy;
+pub mod privacy;
+pub mod rescan;
+pub mod tx_status;
 pub mod wallet_api;
 pub mod walletdb;
 
+pub use backup::{BackupDestination, BackupStatus, WalletBackup};
 pub use cashierdb::{CashierDb, CashierDbPtr};
+pub use coin_selection::{CoinSelection, CoinSelectionStrategy};
+pub use enclave::{platform_enclave, KeyEnclave};
+pub use failed_ops::{FailedOperation, FailureReason};
+pub use payment_risk::{score_payment, PaymentRisk, PaymentRiskInputs};
+pub use policy::{Contact, PaymentPolicy, PolicyViolation};
+pub use rescan::{RescanProgress, RescanStatus};
+pub use tx_status::TxStatus;
 pub use wallet_api::WalletApi;
-pub use walletdb::{Keypair, WalletDb, WalletPtr};
+pub use walletdb::{Keypair, TxDirection, TxHistory, WalletDb, WalletPtr};
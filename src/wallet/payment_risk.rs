@@ -0,0 +1,146 @@
+//! Risk scores for received payments, for merchants deciding whether to
+//! hand over goods before a payment is buried under later slabs.
+
+use serde_json::{json, Value};
+
+use super::{TxDirection, TxHistory};
+
+/// Slabs on top of a payment after which its depth adds no risk
+pub const SETTLED_DEPTH: u64 = 6;
+
+/// Payments of this many tokens (8 decimals) or more carry the full
+/// amount risk
+const LARGE_AMOUNT: u64 = 100 * 100_000_000;
+
+/// Scores up to this are low enough to release goods
+const RELEASE_SCORE: u8 = 20;
+
+const NO_RECEIPT_RISK: u64 = 40;
+const DEPTH_RISK: u64 = 30;
+const UNKNOWN_SENDER_RISK: u64 = 15;
+const AMOUNT_RISK: u64 = 15;
+
+/// What is known about a received payment
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PaymentRiskInputs {
+    /// Whether the gateway has served the slab carrying the payment
+    pub gateway_receipt: bool,
+    /// Slabs accepted by the gateway since the payment's slab
+    pub depth: u64,
+    /// Whether the payment can be attributed to a known sender
+    pub known_sender: bool,
+    pub amount: u64,
+}
+
+impl PaymentRiskInputs {
+    /// Inputs for a received entry of the wallet history, given the latest
+    /// slab index the gateway has announced. Received notes don't name
+    /// their sender, so only entries with a counterparty are attributed.
+    pub fn from_history(entry: &TxHistory, latest_index: u64) -> Option<Self> {
+        if entry.direction != TxDirection::Received {
+            return None;
+        }
+
+        let slab_index = entry.slab_index.filter(|index| *index <= latest_index);
+        Some(Self {
+            gateway_receipt: entry.slab_hash.is_some() && slab_index.is_some(),
+            depth: slab_index.map_or(0, |index| latest_index - index),
+            known_sender: entry.counterparty.is_some(),
+            amount: entry.amount,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PaymentRisk {
+    /// From 0, settled, to 100
+    pub score: u8,
+    /// Whether the score is low enough to release goods
+    pub release: bool,
+    pub inputs: PaymentRiskInputs,
+}
+
+impl PaymentRisk {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "score": self.score,
+            "release": self.release,
+            "gateway_receipt": self.inputs.gateway_receipt,
+            "depth": self.inputs.depth,
+            "known_sender": self.inputs.known_sender,
+        })
+    }
+}
+
+/// Score a received payment. The missing gateway receipt and a shallow
+/// depth weigh the most, since those are what a double spend undoes.
+pub fn score_payment(inputs: PaymentRiskInputs) -> PaymentRisk {
+    let mut score = 0;
+
+    if !inputs.gateway_receipt {
+        score += NO_RECEIPT_RISK;
+    }
+    let remaining = SETTLED_DEPTH - inputs.depth.min(SETTLED_DEPTH);
+    score += DEPTH_RISK * remaining / SETTLED_DEPTH;
+    if !inputs.known_sender {
+        score += UNKNOWN_SENDER_RISK;
+    }
+    score += AMOUNT_RISK * inputs.amount.min(LARGE_AMOUNT) / LARGE_AMOUNT;
+
+    let score = score as u8;
+    PaymentRisk {
+        score,
+        release: score <= RELEASE_SCORE,
+        inputs,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_score_payment() {
+        let fresh = PaymentRiskInputs {
+            gateway_receipt: false,
+            depth: 0,
+            known_sender: false,
+            amount: LARGE_AMOUNT * 2,
+        };
+        let risk = score_payment(fresh);
+        assert_eq!(risk.score, 100);
+        assert!(!risk.release);
+
+        // Buried payments from known senders are settled whatever the amount
+        let settled = PaymentRiskInputs {
+            gateway_receipt: true,
+            depth: SETTLED_DEPTH + 10,
+            known_sender: true,
+            ..fresh
+        };
+        assert_eq!(score_payment(settled).score, 15);
+        assert!(score_payment(settled).release);
+
+        // Risk falls as the payment gets deeper
+        let mut last = 100;
+        for depth in 0..=SETTLED_DEPTH {
+            let score = score_payment(PaymentRiskInputs {
+                gateway_receipt: true,
+                depth,
+                ..fresh
+            })
+            .score;
+            assert!(score < last);
+            last = score;
+        }
+
+        // A small unattributed payment is released once it is deep enough
+        let small = PaymentRiskInputs {
+            gateway_receipt: true,
+            depth: SETTLED_DEPTH,
+            known_sender: false,
+            amount: 100_000,
+        };
+        assert!(score_payment(small).release);
+    }
+}
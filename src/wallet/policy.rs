@@ -0,0 +1,292 @@
+//! The rules outgoing payments are checked against before a transaction
+//! is built. A payment to a contact in the address book is capped by the
+//! contact's own limit, which may be none at all, e.g. for one's own cold
+//! wallet. A payment to any other address is capped by the limit for
+//! unknown addresses. Limits apply to each payment, whatever the token.
+//! Whoever the recipient, the first payment to an address the wallet
+//! never paid before has to be confirmed explicitly, and so does a payment
+//! repeating one sent a short while ago, which is more often a user unsure
+//! the first one went through than a second purchase.
+
+use serde_json::{json, Value};
+
+use super::{TxDirection, TxHistory};
+use crate::util::encode_base10;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Contact {
+    pub name: String,
+    /// The address as it was entered
+    pub address: String,
+    /// Key of the address, which payments are matched on
+    pub public: jubjub::SubgroupPoint,
+    /// Largest payment to the contact, unlimited if None
+    pub limit: Option<u64>,
+}
+
+impl Contact {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "name": self.name,
+            "address": self.address,
+            "limit": self.limit,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PaymentPolicy {
+    /// Largest payment to an address not in the address book, unlimited
+    /// if None
+    pub unknown_limit: Option<u64>,
+    /// Seconds during which sending the same amount of the same token to
+    /// the same address again counts as a duplicate. Zero never does.
+    pub duplicate_window: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PolicyViolation {
+    /// The payment is above the recipient's limit
+    OverLimit {
+        recipient: String,
+        amount: u64,
+        limit: u64,
+    },
+    /// The wallet never paid the recipient, and the payment wasn't
+    /// confirmed
+    NeedsConfirmation(String),
+    /// The same payment was sent this many seconds ago, and the repeat
+    /// wasn't allowed
+    Duplicate {
+        recipient: String,
+        amount: u64,
+        seconds_ago: u64,
+    },
+}
+
+impl std::fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PolicyViolation::OverLimit {
+                recipient,
+                amount,
+                limit,
+            } => write!(
+                f,
+                "Payment of {} to {} is over its limit of {}",
+                encode_base10(*amount, 8),
+                recipient,
+                encode_base10(*limit, 8)
+            ),
+            PolicyViolation::NeedsConfirmation(recipient) => write!(
+                f,
+                "First payment to {}, it needs to be confirmed",
+                recipient
+            ),
+            PolicyViolation::Duplicate {
+                recipient,
+                amount,
+                seconds_ago,
+            } => write!(
+                f,
+                "{} was sent to {} {} seconds ago already, allow the duplicate to send it again",
+                encode_base10(*amount, 8),
+                recipient,
+                seconds_ago
+            ),
+        }
+    }
+}
+
+impl PaymentPolicy {
+    /// Check a payment of `amount` to `recipient`, which is `contact` in
+    /// the address book if it is there. `paid_before` tells whether the wallet
+    /// sent to the address before, and `confirmed` whether the user
+    /// confirmed this payment.
+    pub fn check(
+        &self,
+        recipient: &str,
+        contact: Option<&Contact>,
+        amount: u64,
+        paid_before: bool,
+        confirmed: bool,
+    ) -> std::result::Result<(), PolicyViolation> {
+        let (recipient, limit) = match contact {
+            Some(contact) => (contact.name.clone(), contact.limit),
+            None => (recipient.to_string(), self.unknown_limit),
+        };
+
+        if let Some(limit) = limit {
+            if amount > limit {
+                return Err(PolicyViolation::OverLimit {
+                    recipient,
+                    amount,
+                    limit,
+                });
+            }
+        }
+
+        if !paid_before && !confirmed {
+            return Err(PolicyViolation::NeedsConfirmation(recipient));
+        }
+
+        Ok(())
+    }
+
+    /// Check the payment of `amount` of `token_id` to the key `public`
+    /// doesn't repeat one in the wallet `history` sent within the window
+    /// before `now`, unless the user `allowed` it.
+    #[allow(clippy::too_many_arguments)]
+    pub fn check_duplicate(
+        &self,
+        recipient: &str,
+        public: &jubjub::SubgroupPoint,
+        amount: u64,
+        token_id: &jubjub::Fr,
+        history: &[TxHistory],
+        now: u64,
+        allowed: bool,
+    ) -> std::result::Result<(), PolicyViolation> {
+        if allowed || self.duplicate_window == 0 {
+            return Ok(());
+        }
+
+        let since = now.saturating_sub(self.duplicate_window);
+        let last = history
+            .iter()
+            .filter(|entry| {
+                entry.direction == TxDirection::Sent
+                    && entry.counterparty.as_ref() == Some(public)
+                    && entry.amount == amount
+                    && entry.token_id == *token_id
+                    && entry.timestamp >= since
+            })
+            .map(|entry| entry.timestamp)
+            .max();
+
+        match last {
+            Some(timestamp) => Err(PolicyViolation::Duplicate {
+                recipient: recipient.to_string(),
+                amount,
+                seconds_ago: now.saturating_sub(timestamp),
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::note::Memo;
+    use ff::Field;
+    use group::Group;
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn test_payment_policy() {
+        let policy = PaymentPolicy {
+            unknown_limit: Some(100),
+            duplicate_window: 0,
+        };
+        let cold_wallet = Contact {
+            name: "cold wallet".to_string(),
+            address: "tdrk1cold".to_string(),
+            public: jubjub::SubgroupPoint::random(&mut OsRng),
+            limit: None,
+        };
+        let shop = Contact {
+            name: "shop".to_string(),
+            limit: Some(10),
+            ..cold_wallet.clone()
+        };
+
+        // Unlimited to the cold wallet, capped to the shop
+        assert!(policy
+            .check("tdrk1cold", Some(&cold_wallet), 1_000_000, true, false)
+            .is_ok());
+        assert!(policy
+            .check("tdrk1shop", Some(&shop), 10, true, false)
+            .is_ok());
+        assert_eq!(
+            policy.check("tdrk1shop", Some(&shop), 11, true, true),
+            Err(PolicyViolation::OverLimit {
+                recipient: "shop".to_string(),
+                amount: 11,
+                limit: 10,
+            })
+        );
+
+        // Unknown addresses get the default limit
+        assert!(policy.check("tdrk1other", None, 100, true, false).is_ok());
+        assert!(policy.check("tdrk1other", None, 101, true, true).is_err());
+        assert!(PaymentPolicy::default()
+            .check("tdrk1other", None, u64::MAX, true, false)
+            .is_ok());
+
+        // A first payment needs confirming, even to a contact
+        assert_eq!(
+            policy.check("tdrk1cold", Some(&cold_wallet), 1, false, false),
+            Err(PolicyViolation::NeedsConfirmation(
+                "cold wallet".to_string()
+            ))
+        );
+        assert!(policy
+            .check("tdrk1cold", Some(&cold_wallet), 1, false, true)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_duplicate_payment() {
+        let policy = PaymentPolicy {
+            unknown_limit: None,
+            duplicate_window: 600,
+        };
+        let public = jubjub::SubgroupPoint::random(&mut OsRng);
+        let token_id = jubjub::Fr::random(&mut OsRng);
+        let history = vec![TxHistory {
+            direction: TxDirection::Sent,
+            amount: 10,
+            token_id,
+            counterparty: Some(public),
+            slab_index: None,
+            slab_hash: None,
+            timestamp: 1_000,
+            memo: Memo::empty(),
+        }];
+        let check = |public, amount, token_id: &jubjub::Fr, now, allowed| {
+            policy.check_duplicate(
+                "tdrk1shop",
+                public,
+                amount,
+                token_id,
+                &history,
+                now,
+                allowed,
+            )
+        };
+
+        // The same payment again within the window
+        assert_eq!(
+            check(&public, 10, &token_id, 1_300, false),
+            Err(PolicyViolation::Duplicate {
+                recipient: "tdrk1shop".to_string(),
+                amount: 10,
+                seconds_ago: 300,
+            })
+        );
+        assert!(check(&public, 10, &token_id, 1_300, true).is_ok());
+
+        // Another amount, token or recipient, or once the window passed
+        assert!(check(&public, 11, &token_id, 1_300, false).is_ok());
+        assert!(check(&public, 10, &jubjub::Fr::one(), 1_300, false).is_ok());
+        let other = jubjub::SubgroupPoint::random(&mut OsRng);
+        assert!(check(&other, 10, &token_id, 1_300, false).is_ok());
+        assert!(check(&public, 10, &token_id, 1_601, false).is_ok());
+
+        // Without a window nothing is a duplicate
+        assert!(PaymentPolicy::default()
+            .check_duplicate("tdrk1shop", &public, 10, &token_id, &history, 1_300, false)
+            .is_ok());
+    }
+}
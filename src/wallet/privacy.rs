@@ -0,0 +1,191 @@
+use std::collections::{BTreeSet, HashMap};
+
+use serde_json::{json, Value};
+
+use super::WalletDb;
+use crate::serial::serialize;
+use crate::Result;
+
+/// Amounts that are a whole number of tokens (8 decimals) are considered round
+const ROUND_AMOUNT: u64 = 100_000_000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PrivacyIssue {
+    AddressReuse,
+    AddressMerge,
+    CrossAccountMerge,
+    RoundAmount,
+}
+
+impl PrivacyIssue {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PrivacyIssue::AddressReuse => "address_reuse",
+            PrivacyIssue::AddressMerge => "address_merge",
+            PrivacyIssue::CrossAccountMerge => "cross_account_merge",
+            PrivacyIssue::RoundAmount => "round_amount",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PrivacyFinding {
+    pub issue: PrivacyIssue,
+    pub explanation: String,
+}
+
+impl PrivacyFinding {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "issue": self.issue.name(),
+            "explanation": self.explanation,
+        })
+    }
+}
+
+fn address(secret: &jubjub::Fr) -> String {
+    let public = zcash_primitives::constants::SPENDING_KEY_GENERATOR * secret;
+    bs58::encode(serialize(&public)).into_string()
+}
+
+pub fn privacy_report(wallet: &WalletDb) -> Result<Vec<PrivacyFinding>> {
+    let coins = wallet.get_coin_history()?;
+    let spends = wallet.get_spends()?;
+    let accounts = wallet.get_key_accounts()?;
+    Ok(analyze(&coins, &spends, &accounts))
+}
+
+/// Look for privacy leaks in the wallet history:
+/// * receive addresses that were paid more than once
+/// * transactions that spent coins from several addresses or accounts together
+/// * coins with round amounts
+pub fn analyze(
+    coins: &[(Vec<u8>, u64, jubjub::Fr)],
+    spends: &[Vec<Vec<u8>>],
+    accounts: &[(jubjub::Fr, Option<u32>)],
+) -> Vec<PrivacyFinding> {
+    let mut findings = vec![];
+
+    // Number of coins received per address, keyed by the serialized secret
+    let mut received: HashMap<Vec<u8>, (jubjub::Fr, u64)> = HashMap::new();
+    let mut coin_owner: HashMap<&Vec<u8>, &jubjub::Fr> = HashMap::new();
+
+    for (coin, value, secret) in coins {
+        received.entry(serialize(secret)).or_insert((*secret, 0)).1 += 1;
+        coin_owner.insert(coin, secret);
+
+        if *value > 0 && value % ROUND_AMOUNT == 0 {
+            findings.push(PrivacyFinding {
+                issue: PrivacyIssue::RoundAmount,
+                explanation: format!(
+                    "Address {} received exactly {} tokens. Round amounts stand out and make \
+                     it easier to match a payment with the deposit or withdrawal it came from.",
+                    address(secret),
+                    value / ROUND_AMOUNT
+                ),
+            });
+        }
+    }
+
+    let mut reused: Vec<&(jubjub::Fr, u64)> = received.values().filter(|r| r.1 > 1).collect();
+    reused.sort_by_key(|r| address(&r.0));
+    for (secret, count) in reused {
+        findings.push(PrivacyFinding {
+            issue: PrivacyIssue::AddressReuse,
+            explanation: format!(
+                "Address {} received {} payments. Anyone who paid it can tell these payments \
+                 went to the same wallet; use `drk address new` for each payment.",
+                address(secret),
+                count
+            ),
+        });
+    }
+
+    for (i, spend) in spends.iter().enumerate() {
+        let mut addresses = BTreeSet::new();
+        let mut spend_accounts = BTreeSet::new();
+
+        for coin in spend {
+            if let Some(secret) = coin_owner.get(coin) {
+                addresses.insert(address(secret));
+                let account = accounts
+                    .iter()
+                    .find(|(private, _)| private == *secret)
+                    .and_then(|(_, account)| *account);
+                spend_accounts.insert(account);
+            }
+        }
+
+        if spend_accounts.len() > 1 {
+            let names: Vec<String> = spend_accounts
+                .iter()
+                .map(|account| match account {
+                    Some(account) => account.to_string(),
+                    None => "imported".to_string(),
+                })
+                .collect();
+            findings.push(PrivacyFinding {
+                issue: PrivacyIssue::CrossAccountMerge,
+                explanation: format!(
+                    "Transaction #{} spent coins from accounts {} together. Accounts are meant \
+                     to be unlinkable; this transaction ties them to the same owner.",
+                    i + 1,
+                    names.join(", ")
+                ),
+            });
+        } else if addresses.len() > 1 {
+            findings.push(PrivacyFinding {
+                issue: PrivacyIssue::AddressMerge,
+                explanation: format!(
+                    "Transaction #{} spent coins received on {} different addresses together. \
+                     Their senders can now tell these addresses belong to the same wallet.",
+                    i + 1,
+                    addresses.len()
+                ),
+            });
+        }
+    }
+
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ff::Field;
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn test_analyze() {
+        let key1 = jubjub::Fr::random(&mut OsRng);
+        let key2 = jubjub::Fr::random(&mut OsRng);
+        let key3 = jubjub::Fr::random(&mut OsRng);
+        let accounts = vec![(key1, Some(0)), (key2, Some(0)), (key3, Some(1))];
+
+        let coins = vec![
+            (vec![1], 150_000_000, key1),
+            (vec![2], 12_345, key1),
+            (vec![3], 300_000_000, key2),
+            (vec![4], 54_321, key3),
+        ];
+
+        let spends = vec![
+            vec![vec![1]],
+            vec![vec![2], vec![3]],
+            vec![vec![3], vec![4]],
+        ];
+
+        let findings = analyze(&coins, &spends, &accounts);
+        let issues: Vec<PrivacyIssue> = findings.into_iter().map(|f| f.issue).collect();
+
+        assert_eq!(
+            issues,
+            vec![
+                PrivacyIssue::RoundAmount,
+                PrivacyIssue::AddressReuse,
+                PrivacyIssue::AddressMerge,
+                PrivacyIssue::CrossAccountMerge,
+            ]
+        );
+    }
+}
@@ -0,0 +1,237 @@
+use std::mem;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use easy_parallel::Parallel;
+use log::debug;
+
+use crate::crypto::{
+    merkle::{CommitmentTree, IncrementalWitness},
+    merkle_node::{MerkleNode, SAPLING_COMMITMENT_TREE_DEPTH},
+};
+use crate::Result;
+
+/// Memory the witnesses being rebuilt may take at once. Coins beyond it
+/// wait for the next batch.
+pub const WITNESS_MEMORY_BUDGET: usize = 64 * 1024 * 1024;
+
+// A witness holds the tree frontier, the filled nodes and a cursor tree,
+// each at most a node per level
+const WITNESS_SIZE: usize = 3 * SAPLING_COMMITMENT_TREE_DEPTH * mem::size_of::<MerkleNode>();
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RescanStatus {
+    pub running: bool,
+    pub slabs_done: u64,
+    pub slabs_total: u64,
+    pub witnesses_done: u64,
+    pub witnesses_total: u64,
+}
+
+/// Progress of the current rescan. It's shared outside the client lock so
+/// it can be read while the rescan holds it.
+#[derive(Debug, Default)]
+pub struct RescanProgress {
+    running: AtomicBool,
+    slabs_done: AtomicU64,
+    slabs_total: AtomicU64,
+    witnesses_done: AtomicU64,
+    witnesses_total: AtomicU64,
+}
+
+impl RescanProgress {
+    /// Mark a rescan as running. Returns false if one already was.
+    pub fn start(&self) -> bool {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        self.slabs_done.store(0, Ordering::SeqCst);
+        self.slabs_total.store(0, Ordering::SeqCst);
+        self.witnesses_done.store(0, Ordering::SeqCst);
+        self.witnesses_total.store(0, Ordering::SeqCst);
+        true
+    }
+
+    pub fn set_slabs_total(&self, slabs_total: u64) {
+        self.slabs_total.store(slabs_total, Ordering::SeqCst);
+    }
+
+    pub fn slab_done(&self) {
+        self.slabs_done.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn finish(&self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    pub fn status(&self) -> RescanStatus {
+        RescanStatus {
+            running: self.running.load(Ordering::SeqCst),
+            slabs_done: self.slabs_done.load(Ordering::SeqCst),
+            slabs_total: self.slabs_total.load(Ordering::SeqCst),
+            witnesses_done: self.witnesses_done.load(Ordering::SeqCst),
+            witnesses_total: self.witnesses_total.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// Bring the witnesses of coins found during a rescan up to date with the
+/// tree. Each witness was taken from the tree frontier when its coin was
+/// added, so it only needs the leaves that came after it, which are
+/// appended on every thread at once for a batch of coins.
+pub fn rebuild_witnesses(
+    witnesses: Vec<(Vec<u8>, IncrementalWitness<MerkleNode>)>,
+    leaves: &[MerkleNode],
+    progress: &RescanProgress,
+    mut save: impl FnMut(&Vec<u8>, IncrementalWitness<MerkleNode>) -> Result<()>,
+) -> Result<()> {
+    progress
+        .witnesses_total
+        .store(witnesses.len() as u64, Ordering::SeqCst);
+
+    let batch_size = (WITNESS_MEMORY_BUDGET / WITNESS_SIZE).max(1);
+    let nthreads = num_cpus::get();
+
+    debug!(
+        target: "RESCAN",
+        "Rebuild {} witnesses over {} leaves on {} threads",
+        witnesses.len(),
+        leaves.len(),
+        nthreads
+    );
+
+    let mut witnesses = witnesses.into_iter().peekable();
+
+    while witnesses.peek().is_some() {
+        let batch: Vec<_> = witnesses.by_ref().take(batch_size).collect();
+        let chunk_size = (batch.len() + nthreads - 1) / nthreads;
+
+        let mut chunks = vec![];
+        let mut batch = batch.into_iter().peekable();
+        while batch.peek().is_some() {
+            chunks.push(batch.by_ref().take(chunk_size).collect::<Vec<_>>());
+        }
+
+        let rebuilt = Parallel::new()
+            .each(chunks, |mut chunk| -> Result<Vec<_>> {
+                for (_, witness) in chunk.iter_mut() {
+                    for node in leaves.iter().skip(witness.position() + 1) {
+                        witness.append(*node)?;
+                    }
+                    progress.witnesses_done.fetch_add(1, Ordering::SeqCst);
+                }
+                Ok(chunk)
+            })
+            .run();
+
+        for chunk in rebuilt {
+            for (coin, witness) in chunk? {
+                save(&coin, witness)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Take the witness of each coin again from the tree as it was when the
+/// coin was added at its position, e.g. after a rollback removed leaves
+/// a witness already holds. `rebuild_witnesses` catches them up.
+pub fn frontier_witnesses(
+    mut positions: Vec<(Vec<u8>, usize)>,
+    leaves: &[MerkleNode],
+) -> Result<Vec<(Vec<u8>, IncrementalWitness<MerkleNode>)>> {
+    positions.sort_by_key(|(_, position)| *position);
+    let mut positions = positions.into_iter().peekable();
+
+    let mut tree = CommitmentTree::empty();
+    let mut witnesses = vec![];
+    for (position, node) in leaves.iter().enumerate() {
+        tree.append(*node)?;
+        while let Some((coin, _)) = positions.next_if(|(_, p)| *p == position) {
+            witnesses.push((coin, IncrementalWitness::from_tree(&tree)));
+        }
+    }
+    Ok(witnesses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::coin::Coin;
+
+    #[test]
+    fn test_rebuild_witnesses() -> Result<()> {
+        let leaves: Vec<MerkleNode> = (0..20u8)
+            .map(|i| MerkleNode::from_coin(&Coin::new([i; 32])))
+            .collect();
+
+        // Witnesses kept up to date leaf by leaf, as the client does
+        let mut tree = CommitmentTree::empty();
+        let mut expected: Vec<IncrementalWitness<MerkleNode>> = vec![];
+        // Witnesses taken from the frontier and left behind, as in a rescan
+        let mut stale = vec![];
+
+        for (i, node) in leaves.iter().enumerate() {
+            tree.append(*node)?;
+            for witness in expected.iter_mut() {
+                witness.append(*node)?;
+            }
+            if i % 3 == 0 {
+                expected.push(IncrementalWitness::from_tree(&tree));
+                stale.push((vec![i as u8], IncrementalWitness::from_tree(&tree)));
+            }
+        }
+
+        let progress = RescanProgress::default();
+        let mut rebuilt = vec![];
+        rebuild_witnesses(stale, &leaves, &progress, |coin, witness| {
+            rebuilt.push((coin.clone(), witness));
+            Ok(())
+        })?;
+
+        assert_eq!(rebuilt.len(), expected.len());
+        for (coin, witness) in rebuilt {
+            let expected = &expected[coin[0] as usize / 3];
+            assert_eq!(witness.position(), expected.position());
+            assert_eq!(witness.root(), tree.root());
+            assert_eq!(witness.path(), expected.path());
+        }
+
+        let status = progress.status();
+        assert_eq!(status.witnesses_done, status.witnesses_total);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_frontier_witnesses() -> Result<()> {
+        let leaves: Vec<MerkleNode> = (0..10u8)
+            .map(|i| MerkleNode::from_coin(&Coin::new([i; 32])))
+            .collect();
+
+        let mut tree = CommitmentTree::empty();
+        let mut expected = vec![];
+        for (i, node) in leaves.iter().enumerate() {
+            tree.append(*node)?;
+            if i == 2 || i == 7 {
+                expected.push(IncrementalWitness::from_tree(&tree));
+            }
+        }
+
+        // Coins past the last leaf were rolled back and get no witness
+        let positions = vec![(vec![7], 7), (vec![2], 2), (vec![12], 12)];
+        let witnesses = frontier_witnesses(positions, &leaves)?;
+
+        assert_eq!(witnesses.len(), 2);
+        for ((coin, witness), expected) in witnesses.iter().zip(expected.iter()) {
+            assert_eq!(witness.position(), coin[0] as usize);
+            assert_eq!(witness.position(), expected.position());
+            assert_eq!(witness.root(), expected.root());
+        }
+        Ok(())
+    }
+}
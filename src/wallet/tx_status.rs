@@ -0,0 +1,45 @@
+//! Where a transaction we published is on its way into a slab, so wallets
+//! can show it instead of guessing from the balance.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TxStatus {
+    /// Built and being handed to the gateway
+    Pending,
+    /// Taken by the gateway, waiting for its slab to come back
+    Published,
+    /// Its slab was applied at this index
+    Confirmed(u64),
+    /// The gateway refused it or didn't answer. It may still come back.
+    Rejected,
+    /// It never came back and is no longer rebroadcast
+    Expired,
+}
+
+impl TxStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TxStatus::Pending => "pending",
+            TxStatus::Published => "published",
+            TxStatus::Confirmed(_) => "confirmed",
+            TxStatus::Rejected => "rejected",
+            TxStatus::Expired => "expired",
+        }
+    }
+
+    pub fn from_row(status: &str, slab_index: Option<u64>) -> Self {
+        match (status, slab_index) {
+            ("confirmed", Some(index)) => TxStatus::Confirmed(index),
+            ("published", _) => TxStatus::Published,
+            ("rejected", _) => TxStatus::Rejected,
+            ("expired", _) => TxStatus::Expired,
+            _ => TxStatus::Pending,
+        }
+    }
+
+    pub fn slab_index(&self) -> Option<u64> {
+        match self {
+            TxStatus::Confirmed(index) => Some(*index),
+            _ => None,
+        }
+    }
+}
@@ -1,22 +1,48 @@
 use async_std::sync::{Arc, Mutex};
 use std::collections::HashMap;
+use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use ff::Field;
 use log::*;
 use rand::rngs::OsRng;
 use rusqlite::{named_params, params, Connection};
 
-use super::WalletApi;
+use super::enclave::{new_secret, wallet_key, KeyEnclave};
+use super::{Contact, FailedOperation, FailureReason, TxStatus, WalletApi};
+use crate::blockchain::SlabHash;
 use crate::client::ClientFailed;
 use crate::crypto::{
-    coin::Coin, merkle::IncrementalWitness, merkle_node::MerkleNode, note::Note, OwnCoin, OwnCoins,
+    address::DIVERSIFIER_SIZE,
+    coin::Coin,
+    hd::ExtendedSpendingKey,
+    merkle::IncrementalWitness,
+    merkle_node::MerkleNode,
+    mnemonic,
+    note::{Memo, Note},
+    nullifier::Nullifier,
+    Diversifier, OwnCoin, OwnCoins, ViewingKey,
 };
 use crate::serial;
 use crate::{Error, Result};
 
 pub type WalletPtr = Arc<WalletDb>;
 
+/// Account used for the keys derived from the wallet seed
+pub const DEFAULT_ACCOUNT: u32 = 0;
+
+/// Unused keys in a row looked ahead for past the last key paid to when
+/// a wallet is restored
+pub const GAP_LIMIT: u32 = 20;
+
+/// Seconds after an emergency stop before the wallet can be unlocked
+pub const UNLOCK_COOLDOWN: u64 = 60 * 60;
+
+/// Size of the salt SQLCipher writes in the clear at the start of a
+/// database
+const WALLET_SALT_SIZE: usize = 16;
+
 #[derive(Debug, Clone)]
 pub struct Keypair {
     pub public: jubjub::SubgroupPoint,
@@ -47,10 +73,48 @@ impl Balances {
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum TxDirection {
+    Sent,
+    Received,
+}
+
+impl TxDirection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TxDirection::Sent => "sent",
+            TxDirection::Received => "received",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TxHistory {
+    pub direction: TxDirection,
+    pub amount: u64,
+    pub token_id: jubjub::Fr,
+    // Recipient of a sent transfer. The sender of a received one is unknown.
+    pub counterparty: Option<jubjub::SubgroupPoint>,
+    // Unknown until the slab carrying a sent transfer is accepted
+    pub slab_index: Option<u64>,
+    pub slab_hash: Option<SlabHash>,
+    pub timestamp: u64,
+    pub memo: Memo,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditEvent {
+    pub timestamp: u64,
+    pub event: String,
+    pub detail: String,
+}
+
 //#[derive(Clone)]
 pub struct WalletDb {
     pub path: PathBuf,
     pub password: String,
+    // The database key, the password unless bound to a key store
+    key: String,
     pub initialized: Mutex<bool>,
 }
 
@@ -66,20 +130,94 @@ impl WalletApi for WalletDb {
 impl WalletDb {
     pub fn new(path: &Path, password: String) -> Result<WalletPtr> {
         debug!(target: "WALLETDB", "new() Constructor called");
+        Ok(Arc::new(Self {
+            path: path.to_owned(),
+            key: password.clone(),
+            password,
+            initialized: Mutex::new(false),
+        }))
+    }
+
+    /// Open the wallet with its key bound to a secret kept by `enclave`,
+    /// see `enclave`. A wallet encrypted with the password alone is
+    /// rekeyed. If the key store can't be used, the wallet stays on the
+    /// password alone.
+    pub fn new_sealed(
+        path: &Path,
+        password: String,
+        enclave: &dyn KeyEnclave,
+    ) -> Result<WalletPtr> {
+        let key = match Self::enclave_key(path, &password, enclave) {
+            Ok(key) => key,
+            Err(e) => {
+                warn!(
+                    target: "WALLETDB",
+                    "Can't use the {}, the wallet is protected by its password alone: {}",
+                    enclave.name(),
+                    e
+                );
+                password.clone()
+            }
+        };
+
         Ok(Arc::new(Self {
             path: path.to_owned(),
             password,
+            key,
             initialized: Mutex::new(false),
         }))
     }
 
+    fn enclave_key(path: &Path, password: &str, enclave: &dyn KeyEnclave) -> Result<String> {
+        if !path.exists() {
+            // A new wallet has no ID until its database is written
+            let conn = Connection::open(path)?;
+            conn.pragma_update(None, "key", &password)?;
+            conn.execute_batch(include_str!("../../sql/schema.sql"))?;
+        }
+
+        let id = wallet_id(path)?;
+        let secret = match enclave.load(&id)? {
+            Some(secret) => secret,
+            // Wallets used to be bound under their path
+            None => match enclave.load(&path.to_string_lossy())? {
+                Some(secret) => {
+                    enclave.store(&id, &secret)?;
+                    secret
+                }
+                None => {
+                    // Stored before rekeying, so the wallet can't end up
+                    // on a key whose secret was never kept
+                    let secret = new_secret();
+                    enclave.store(&id, &secret)?;
+                    secret
+                }
+            },
+        };
+        let key = wallet_key(password, &secret);
+
+        if !opens_with(path, &key) {
+            if !opens_with(path, password) {
+                return Err(Error::EnclaveFailed(
+                    "The wallet opens neither with the key store secret nor the password".into(),
+                ));
+            }
+            let conn = Connection::open(path)?;
+            conn.pragma_update(None, "key", &password)?;
+            conn.pragma_update(None, "rekey", &key)?;
+            info!(target: "WALLETDB", "Wallet bound to the {}", enclave.name());
+        }
+
+        Ok(key)
+    }
+
     pub async fn init_db(&self) -> Result<()> {
         if !*self.initialized.lock().await {
             if !self.password.trim().is_empty() {
                 let contents = include_str!("../../sql/schema.sql");
                 let conn = Connection::open(&self.path)?;
                 debug!(target: "WALLETDB", "OPENED CONNECTION AT PATH {:?}", self.path);
-                conn.pragma_update(None, "key", &self.password)?;
+                conn.pragma_update(None, "key", &self.key)?;
                 conn.execute_batch(contents)?;
                 *self.initialized.lock().await = true;
             } else {
@@ -96,20 +234,321 @@ impl WalletDb {
         Ok(())
     }
 
+    // Generate a new keypair and add it to the wallet. The wallet can hold
+    // any number of keypairs; the first one is used as the main keypair.
+    // Wallets with a seed derive the next key in the default account.
     pub fn key_gen(&self) -> Result<()> {
         debug!(target: "WALLETDB", "Attempting to generate keys...");
-        let conn = Connection::open(&self.path)?;
-        conn.pragma_update(None, "key", &self.password)?;
-        let mut stmt = conn.prepare("SELECT * FROM keys WHERE key_id > ?")?;
-        let key_check = stmt.exists(params!["0"])?;
-        if !key_check {
-            let secret: jubjub::Fr = jubjub::Fr::random(&mut OsRng);
-            let public = zcash_primitives::constants::SPENDING_KEY_GENERATOR * secret;
-            self.put_keypair(&public, &secret)?;
-        } else {
-            debug!(target: "WALLETDB", "Keys already exist.");
+        if self.get_mnemonic()?.is_some() {
+            self.derive_keypair(DEFAULT_ACCOUNT)?;
+            return Ok(());
+        }
+        let secret: jubjub::Fr = jubjub::Fr::random(&mut OsRng);
+        let public = zcash_primitives::constants::SPENDING_KEY_GENERATOR * secret;
+        self.put_keypair(&public, &secret)?;
+        Ok(())
+    }
+
+    // Generate a new mnemonic seed and add the keypair derived from it to
+    // the wallet. Returns the seed phrase so it can be backed up.
+    pub fn seed_gen(&self) -> Result<String> {
+        debug!(target: "WALLETDB", "Attempting to generate seed...");
+        if self.get_mnemonic()?.is_some() {
             return Err(Error::from(ClientFailed::KeyExists));
         }
+        let phrase = mnemonic::generate_mnemonic()?;
+        self.put_mnemonic(&phrase)?;
+        self.put_seed_keypair(&phrase)?;
+        Ok(phrase)
+    }
+
+    // Replace the wallet seed, keys and coins with the main keypair derived
+    // from the given mnemonic. The other keys are looked ahead for with
+    // `extend_lookahead` and the coins recovered with rescans.
+    pub fn restore_mnemonic(&self, phrase: &str) -> Result<()> {
+        debug!(target: "WALLETDB", "Restoring wallet from mnemonic...");
+        // Validate before touching the wallet
+        mnemonic::mnemonic_to_seed(phrase)?;
+
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+        conn.execute_batch(
+            "DELETE FROM seed; DELETE FROM keys; DELETE FROM lookahead_keys;
+            DELETE FROM diversifiers; DELETE FROM lookahead_diversifiers;
+            DELETE FROM address_labels; DELETE FROM coins;",
+        )?;
+
+        self.put_mnemonic(phrase)?;
+        self.put_seed_keypair(phrase)?;
+        Ok(())
+    }
+
+    fn put_seed_keypair(&self, phrase: &str) -> Result<()> {
+        let seed = mnemonic::mnemonic_to_seed(phrase)?;
+        let xsk = ExtendedSpendingKey::from_seed(&seed, DEFAULT_ACCOUNT, 0);
+        self.put_derived_keypair(&xsk.public(), &xsk.secret(), DEFAULT_ACCOUNT, 0)
+    }
+
+    // Derive the next unused keypair of an account from the wallet seed
+    // and add it to the wallet.
+    pub fn derive_keypair(&self, account: u32) -> Result<Keypair> {
+        debug!(target: "WALLETDB", "Deriving new keypair for account {}", account);
+        let phrase = self
+            .get_mnemonic()?
+            .ok_or_else(|| Error::MnemonicError("Wallet has no mnemonic seed".to_string()))?;
+        let seed = mnemonic::mnemonic_to_seed(&phrase)?;
+
+        let index = self.get_next_key_index(account)?;
+        let xsk = ExtendedSpendingKey::from_seed(&seed, account, index);
+
+        let keypair = Keypair {
+            public: xsk.public(),
+            private: xsk.secret(),
+        };
+        // A key looked ahead for by a restore is already in the wallet
+        if !self.take_lookahead_key(&keypair.public)? {
+            self.put_derived_keypair(&keypair.public, &keypair.private, account, index)?;
+        }
+        Ok(keypair)
+    }
+
+    // Derive the keys a restored wallet may have handed out: in every
+    // account paid to, and the first one that wasn't, the GAP_LIMIT keys
+    // past the last one paid to, and as many diversified addresses of the
+    // main key. They're watched for coins like the other keys, but not
+    // counted as handed out. Returns whether any key was added, whose
+    // coins are then found by another rescan.
+    pub fn extend_lookahead(&self) -> Result<bool> {
+        debug!(target: "WALLETDB", "Extending the lookahead keys");
+        let phrase = self
+            .get_mnemonic()?
+            .ok_or_else(|| Error::MnemonicError("Wallet has no mnemonic seed".to_string()))?;
+        let seed = mnemonic::mnemonic_to_seed(&phrase)?;
+
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let mut added = false;
+        let mut account = DEFAULT_ACCOUNT;
+        loop {
+            let last_used = self.get_last_used_key_index(account)?;
+            let end = last_used.map_or(GAP_LIMIT, |index| index + 1 + GAP_LIMIT);
+            for index in 0..end {
+                let exists: bool = conn.query_row(
+                    "SELECT EXISTS(SELECT 1 FROM keys WHERE account = ?1 AND key_index = ?2)",
+                    params![account, index],
+                    |row| row.get(0),
+                )?;
+                if exists {
+                    continue;
+                }
+
+                let xsk = ExtendedSpendingKey::from_seed(&seed, account, index);
+                self.put_derived_keypair(&xsk.public(), &xsk.secret(), account, index)?;
+                conn.execute(
+                    "INSERT INTO lookahead_keys(key_public) VALUES (?1)",
+                    params![serial::serialize(&xsk.public())],
+                )?;
+                added = true;
+            }
+
+            if last_used.is_none() {
+                break;
+            }
+            account += 1;
+        }
+
+        let main = self.get_keypairs()?[0].clone();
+        let last_used = self.get_last_used_diversifier_index(&main)?;
+        let end = last_used.map_or(GAP_LIMIT, |index| index + 1 + GAP_LIMIT);
+        for index in 0..end {
+            let diversifier = Diversifier::derive(&main.private, index);
+            let exists: bool = conn.query_row(
+                "SELECT EXISTS(SELECT 1 FROM diversifiers WHERE diversifier = ?1)",
+                params![diversifier.0.to_vec()],
+                |row| row.get(0),
+            )?;
+            if exists {
+                continue;
+            }
+
+            conn.execute(
+                "INSERT INTO diversifiers(key_public, diversifier) VALUES (?1, ?2)",
+                params![serial::serialize(&main.public), diversifier.0.to_vec()],
+            )?;
+            conn.execute(
+                "INSERT INTO lookahead_diversifiers(diversifier) VALUES (?1)",
+                params![diversifier.0.to_vec()],
+            )?;
+            added = true;
+        }
+
+        Ok(added)
+    }
+
+    // Once a restore is done, count the keys up to the last one paid to
+    // in each account as handed out, so keys are derived after them
+    pub fn settle_lookahead(&self) -> Result<()> {
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        conn.execute(
+            "DELETE FROM lookahead_keys WHERE key_public IN (
+                SELECT k.key_public FROM keys k WHERE k.key_index <= (
+                    SELECT MAX(u.key_index) FROM keys u
+                    JOIN coins c ON c.secret = u.key_private
+                    WHERE u.account = k.account))",
+            [],
+        )?;
+
+        let main = self.get_keypairs()?[0].clone();
+        if let Some(last_used) = self.get_last_used_diversifier_index(&main)? {
+            for index in 0..=last_used {
+                conn.execute(
+                    "DELETE FROM lookahead_diversifiers WHERE diversifier = ?1",
+                    params![Diversifier::derive(&main.private, index).0.to_vec()],
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    // Index of the last key of an account that coins were paid to
+    fn get_last_used_key_index(&self, account: u32) -> Result<Option<u32>> {
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let last_index: Option<u32> = conn.query_row(
+            "SELECT MAX(k.key_index) FROM keys k JOIN coins c ON c.secret = k.key_private
+            WHERE k.account = ?1",
+            params![account],
+            |row| row.get(0),
+        )?;
+        Ok(last_index)
+    }
+
+    // Index of the last diversified address of a key that coins were paid
+    // to. The diversifiers derived from the key are at most as many as
+    // the ones kept for it.
+    fn get_last_used_diversifier_index(&self, keypair: &Keypair) -> Result<Option<u32>> {
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let count: u32 = conn.query_row(
+            "SELECT COUNT(*) FROM diversifiers WHERE key_public = ?1",
+            params![serial::serialize(&keypair.public)],
+            |row| row.get(0),
+        )?;
+
+        let mut last_used = None;
+        for index in 0..count {
+            let secret = Diversifier::derive(&keypair.private, index).secret(&keypair.private);
+            let used: bool = conn.query_row(
+                "SELECT EXISTS(SELECT 1 FROM coins WHERE secret = ?1)",
+                params![serial::serialize(&secret)],
+                |row| row.get(0),
+            )?;
+            if used {
+                last_used = Some(index);
+            }
+        }
+        Ok(last_used)
+    }
+
+    // Count a lookahead key as handed out. False if it wasn't one.
+    fn take_lookahead_key(&self, key_public: &jubjub::SubgroupPoint) -> Result<bool> {
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let taken = conn.execute(
+            "DELETE FROM lookahead_keys WHERE key_public = ?1",
+            params![serial::serialize(key_public)],
+        )?;
+        Ok(taken > 0)
+    }
+
+    pub fn get_next_key_index(&self, account: u32) -> Result<u32> {
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        // Keys looked ahead for by a restore weren't handed out
+        let last_index: Option<u32> = conn.query_row(
+            "SELECT MAX(key_index) FROM keys WHERE account = ?1
+            AND key_public NOT IN (SELECT key_public FROM lookahead_keys)",
+            params![account],
+            |row| row.get(0),
+        )?;
+
+        Ok(last_index.map_or(0, |i| i + 1))
+    }
+
+    pub fn put_derived_keypair(
+        &self,
+        key_public: &jubjub::SubgroupPoint,
+        key_private: &jubjub::Fr,
+        account: u32,
+        index: u32,
+    ) -> Result<()> {
+        let conn = Connection::open(&self.path)?;
+
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let key_public = serial::serialize(key_public);
+        let key_private = serial::serialize(key_private);
+
+        conn.execute(
+            "INSERT INTO keys(key_public, key_private, account, key_index)
+            VALUES (?1, ?2, ?3, ?4)",
+            params![key_public, key_private, account, index],
+        )?;
+        Ok(())
+    }
+
+    pub fn put_mnemonic(&self, phrase: &str) -> Result<()> {
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        conn.execute(
+            "INSERT INTO seed(mnemonic) VALUES (?1)",
+            params![phrase.as_bytes()],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_mnemonic(&self) -> Result<Option<String>> {
+        debug!(target: "WALLETDB", "Returning mnemonic...");
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+        let mut stmt = conn.prepare("SELECT mnemonic FROM seed ORDER BY seed_id LIMIT 1")?;
+        let mut rows = stmt.query_map([], |row| row.get(0))?;
+
+        match rows.next() {
+            Some(row) => {
+                let phrase: Vec<u8> = row?;
+                let phrase =
+                    String::from_utf8(phrase).map_err(|e| Error::MnemonicError(e.to_string()))?;
+                Ok(Some(phrase))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn remove_own_coins(&self) -> Result<()> {
+        debug!(target: "WALLETDB", "Remove own coins");
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+        conn.execute("DELETE FROM coins;", [])?;
+        Ok(())
+    }
+
+    // Forget a coin, e.g. one whose slab was rolled back
+    pub fn remove_own_coin(&self, coin: &Coin) -> Result<()> {
+        debug!(target: "WALLETDB", "Remove own coin");
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let coin = self.get_value_serialized(&coin.repr)?;
+        conn.execute("DELETE FROM coins WHERE coin = ?1;", params![coin])?;
         Ok(())
     }
 
@@ -120,7 +559,7 @@ impl WalletDb {
     ) -> Result<()> {
         let conn = Connection::open(&self.path)?;
 
-        conn.pragma_update(None, "key", &self.password)?;
+        conn.pragma_update(None, "key", &self.key)?;
 
         let key_public = serial::serialize(key_public);
         let key_private = serial::serialize(key_private);
@@ -135,7 +574,7 @@ impl WalletDb {
     pub fn get_keypairs(&self) -> Result<Vec<Keypair>> {
         debug!(target: "WALLETDB", "Returning keypairs...");
         let conn = Connection::open(&self.path)?;
-        conn.pragma_update(None, "key", &self.password)?;
+        conn.pragma_update(None, "key", &self.key)?;
         let mut stmt = conn.prepare("SELECT * FROM keys")?;
         // this just gets the first key. maybe we should randomize this
         let key_iter = stmt.query_map([], |row| Ok((row.get(1)?, row.get(2)?)))?;
@@ -153,6 +592,120 @@ impl WalletDb {
         Ok(keypairs)
     }
 
+    // The wallet keys, followed by the keys owning the diversified
+    // addresses handed out for them
+    pub fn get_private_keys(&self) -> Result<Vec<jubjub::Fr>> {
+        debug!(target: "WALLETDB", "Returning private keys...");
+        let keypairs = self.get_keypairs()?;
+        let mut private_keys: Vec<jubjub::Fr> = keypairs.iter().map(|k| k.private).collect();
+
+        for keypair in keypairs.iter() {
+            for diversifier in self.get_diversifiers(&keypair.public)? {
+                private_keys.push(diversifier.secret(&keypair.private));
+            }
+        }
+
+        Ok(private_keys)
+    }
+
+    // Derive the next diversifier of a key and remember it, so payments to
+    // the address it gives are found
+    pub fn new_diversifier(&self, keypair: &Keypair) -> Result<Diversifier> {
+        debug!(target: "WALLETDB", "New diversifier");
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let key_public = self.get_value_serialized(&keypair.public)?;
+        let handed_out: u32 = conn.query_row(
+            "SELECT COUNT(*) FROM diversifiers WHERE key_public = ?1
+            AND diversifier NOT IN (SELECT diversifier FROM lookahead_diversifiers)",
+            params![key_public],
+            |row| row.get(0),
+        )?;
+        let diversifier = Diversifier::derive(&keypair.private, handed_out);
+
+        // One looked ahead for by a restore is already in the wallet
+        let taken = conn.execute(
+            "DELETE FROM lookahead_diversifiers WHERE diversifier = ?1",
+            params![diversifier.0.to_vec()],
+        )?;
+        if taken == 0 {
+            conn.execute(
+                "INSERT INTO diversifiers(key_public, diversifier) VALUES (?1, ?2)",
+                params![key_public, diversifier.0.to_vec()],
+            )?;
+        }
+        Ok(diversifier)
+    }
+
+    pub fn get_diversifiers(&self, key_public: &jubjub::SubgroupPoint) -> Result<Vec<Diversifier>> {
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let key_public = self.get_value_serialized(key_public)?;
+        let mut stmt =
+            conn.prepare("SELECT diversifier FROM diversifiers WHERE key_public = ?1")?;
+        let rows = stmt.query_map(params![key_public], |row| row.get(0))?;
+
+        let mut diversifiers = vec![];
+        for row in rows {
+            let row: Vec<u8> = row?;
+            if row.len() != DIVERSIFIER_SIZE {
+                return Err(Error::ParseFailed("Invalid diversifier length"));
+            }
+            let mut diversifier = [0u8; DIVERSIFIER_SIZE];
+            diversifier.copy_from_slice(&row);
+            diversifiers.push(Diversifier(diversifier));
+        }
+        Ok(diversifiers)
+    }
+
+    // Name the address a diversifier gives, e.g. after who it was handed
+    // out to
+    pub fn set_address_label(&self, diversifier: &Diversifier, label: &str) -> Result<()> {
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        conn.execute(
+            "INSERT OR REPLACE INTO address_labels(diversifier, label) VALUES (?1, ?2)",
+            params![diversifier.0.to_vec(), label],
+        )?;
+        Ok(())
+    }
+
+    // The diversifiers of a key in the order they were handed out, with
+    // the labels of their addresses. The ones a restore looked ahead for
+    // aren't handed out yet.
+    pub fn get_labeled_diversifiers(
+        &self,
+        key_public: &jubjub::SubgroupPoint,
+    ) -> Result<Vec<(Diversifier, Option<String>)>> {
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let key_public = self.get_value_serialized(key_public)?;
+        let mut stmt = conn.prepare(
+            "SELECT d.diversifier, l.label FROM diversifiers d
+            LEFT JOIN address_labels l ON l.diversifier = d.diversifier
+            WHERE d.key_public = ?1
+            AND d.diversifier NOT IN (SELECT diversifier FROM lookahead_diversifiers)
+            ORDER BY d.rowid",
+        )?;
+        let rows = stmt.query_map(params![key_public], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+        let mut diversifiers = vec![];
+        for row in rows {
+            let (row, label): (Vec<u8>, Option<String>) = row?;
+            if row.len() != DIVERSIFIER_SIZE {
+                return Err(Error::ParseFailed("Invalid diversifier length"));
+            }
+            let mut diversifier = [0u8; DIVERSIFIER_SIZE];
+            diversifier.copy_from_slice(&row);
+            diversifiers.push((Diversifier(diversifier), label));
+        }
+        Ok(diversifiers)
+    }
+
     pub fn get_own_coins(&self) -> Result<OwnCoins> {
         debug!(target: "WALLETDB", "Get own coins");
 
@@ -160,7 +713,7 @@ impl WalletDb {
 
         let conn = Connection::open(&self.path)?;
         // unlock database
-        conn.pragma_update(None, "key", &self.password)?;
+        conn.pragma_update(None, "key", &self.key)?;
 
         let mut coins = conn.prepare("SELECT * FROM coins WHERE is_spent = :is_spent ;")?;
         let rows = coins.query_map(&[(":is_spent", &is_spent)], |row| {
@@ -173,6 +726,7 @@ impl WalletDb {
                 row.get(5)?,
                 row.get(6)?,
                 row.get(7)?,
+                row.get(9)?,
             ))
         })?;
 
@@ -188,6 +742,7 @@ impl WalletDb {
             let valcom_blind = self.get_value_deserialized(&row.3)?;
             let value: u64 = row.4;
             let token_id = self.get_value_deserialized(&row.5)?;
+            let memo = self.get_value_deserialized(&row.8)?;
 
             let note = Note {
                 serial,
@@ -195,6 +750,7 @@ impl WalletDb {
                 token_id,
                 coin_blind,
                 valcom_blind,
+                memo,
             };
 
             let witness = self.get_value_deserialized(&row.6)?;
@@ -213,14 +769,64 @@ impl WalletDb {
         Ok(own_coins)
     }
 
+    // The nullifier of each of our coins, spent or not, to spot them
+    // being spent on chain
+    pub fn get_own_nullifiers(&self) -> Result<Vec<(Coin, Nullifier)>> {
+        debug!(target: "WALLETDB", "Get own nullifiers");
+
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let mut coins = conn.prepare("SELECT coin, serial, secret FROM coins;")?;
+        let rows = coins.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+
+        let mut nullifiers = vec![];
+        for row in rows {
+            let row: (Vec<u8>, Vec<u8>, Vec<u8>) = row?;
+            let coin = Coin::new(self.get_value_deserialized(&row.0)?);
+            let serial: jubjub::Fr = self.get_value_deserialized(&row.1)?;
+            let secret: jubjub::Fr = self.get_value_deserialized(&row.2)?;
+            nullifiers.push((coin, Nullifier::derive(&secret, &serial)));
+        }
+        Ok(nullifiers)
+    }
+
     pub fn put_own_coins(&self, own_coin: OwnCoin) -> Result<()> {
         debug!(target: "WALLETDB", "Put own coins");
 
         // open connection
         let conn = Connection::open(&self.path)?;
         // unlock database
-        conn.pragma_update(None, "key", &self.password)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        self.insert_own_coin(&conn, own_coin)
+    }
+
+    /// Write what a slab changed in our coins in one transaction: the
+    /// coins it sent us and the witnesses of the unspent ones, keyed by
+    /// their serialized coin. Either all of it is written or none.
+    pub fn put_slab_coins(
+        &self,
+        own_coins: Vec<OwnCoin>,
+        witnesses: &HashMap<Vec<u8>, IncrementalWitness<MerkleNode>>,
+    ) -> Result<()> {
+        debug!(target: "WALLETDB", "Put slab coins");
+
+        let mut conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
 
+        let tx = conn.transaction()?;
+        for own_coin in own_coins {
+            self.insert_own_coin(&tx, own_coin)?;
+        }
+        for (coin, witness) in witnesses {
+            self.set_witness(&tx, coin, witness)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn insert_own_coin(&self, conn: &Connection, own_coin: OwnCoin) -> Result<()> {
         let coin = self.get_value_serialized(&own_coin.coin.repr)?;
 
         let serial = self.get_value_serialized(&own_coin.note.serial)?;
@@ -231,12 +837,15 @@ impl WalletDb {
         let witness = self.get_value_serialized(&own_coin.witness)?;
         let secret = self.get_value_serialized(&own_coin.secret)?;
         let is_spent = self.get_value_serialized(&false)?;
+        let memo = self.get_value_serialized(&own_coin.note.memo)?;
 
         conn.execute(
             "INSERT OR REPLACE INTO coins
-            (coin, serial, value, token_id, coin_blind, valcom_blind, witness, secret, is_spent)
+            (coin, serial, value, token_id, coin_blind, valcom_blind, witness, secret, is_spent,
+            memo)
             VALUES
-            (:coin, :serial, :value, :token_id, :coin_blind, :valcom_blind, :witness, :secret, :is_spent);",
+            (:coin, :serial, :value, :token_id, :coin_blind, :valcom_blind, :witness, :secret,
+            :is_spent, :memo);",
             named_params! {
                 ":coin": coin,
                 ":serial": serial,
@@ -247,6 +856,7 @@ impl WalletDb {
                 ":witness": witness,
                 ":secret": secret,
                 ":is_spent": is_spent,
+                ":memo": memo,
             },
         )?;
         Ok(())
@@ -260,7 +870,7 @@ impl WalletDb {
         // open connection
         let conn = Connection::open(&self.path)?;
         // unlock database
-        conn.pragma_update(None, "key", &self.password)?;
+        conn.pragma_update(None, "key", &self.key)?;
 
         let is_spent = self.get_value_serialized(&true)?;
 
@@ -274,9 +884,319 @@ impl WalletDb {
         Ok(())
     }
 
+    // Record the coins spent together in one transaction, so the privacy
+    // report can tell which coins have been linked.
+    pub fn put_spend(&self, coins: &[Coin]) -> Result<()> {
+        debug!(target: "WALLETDB", "Put spend");
+
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let spend_id: u64 = conn.query_row(
+            "SELECT COALESCE(MAX(spend_id), 0) + 1 FROM spends",
+            [],
+            |row| row.get(0),
+        )?;
+
+        for coin in coins {
+            let coin = self.get_value_serialized(coin)?;
+            conn.execute(
+                "INSERT INTO spends(spend_id, coin) VALUES (?1, ?2)",
+                params![spend_id, coin],
+            )?;
+        }
+        Ok(())
+    }
+
+    // Coins grouped by the transaction that spent them
+    pub fn get_spends(&self) -> Result<Vec<Vec<Vec<u8>>>> {
+        debug!(target: "WALLETDB", "Get spends");
+
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let mut stmt = conn.prepare("SELECT spend_id, coin FROM spends ORDER BY spend_id")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+        let mut spends: Vec<Vec<Vec<u8>>> = vec![];
+        let mut last_id: Option<u64> = None;
+        for row in rows {
+            let (spend_id, coin): (u64, Vec<u8>) = row?;
+            if last_id != Some(spend_id) {
+                spends.push(vec![]);
+                last_id = Some(spend_id);
+            }
+            spends.last_mut().unwrap().push(coin);
+        }
+
+        Ok(spends)
+    }
+
+    // Every coin the wallet has received, spent or not, as
+    // (coin, value, secret key it was received with)
+    pub fn get_coin_history(&self) -> Result<Vec<(Vec<u8>, u64, jubjub::Fr)>> {
+        debug!(target: "WALLETDB", "Get coin history");
+
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let mut stmt = conn.prepare("SELECT coin, value, secret FROM coins")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+
+        let mut coins = vec![];
+        for row in rows {
+            let (coin, value, secret): (Vec<u8>, u64, Vec<u8>) = row?;
+            let secret: jubjub::Fr = self.get_value_deserialized(&secret)?;
+            coins.push((coin, value, secret));
+        }
+
+        Ok(coins)
+    }
+
+    // Private keys along with the HD account they were derived in, if any
+    pub fn get_key_accounts(&self) -> Result<Vec<(jubjub::Fr, Option<u32>)>> {
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let mut stmt = conn.prepare("SELECT key_private, account FROM keys")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+        let mut accounts = vec![];
+        for row in rows {
+            let (private, account): (Vec<u8>, Option<u32>) = row?;
+            let private: jubjub::Fr = self.get_value_deserialized(&private)?;
+            accounts.push((private, account));
+        }
+
+        Ok(accounts)
+    }
+
+    // Add an entry to the transaction history and return its id. For sent
+    // transfers, `change_coin` is the coin we pay back to ourselves, so it
+    // isn't listed as received once the slab is applied.
+    pub fn put_tx_history(&self, entry: &TxHistory, change_coin: Option<&Coin>) -> Result<i64> {
+        debug!(target: "WALLETDB", "Put tx history");
+
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let token_id = self.get_value_serialized(&entry.token_id)?;
+        let counterparty = match &entry.counterparty {
+            Some(public) => Some(self.get_value_serialized(public)?),
+            None => None,
+        };
+        let slab_hash = match &entry.slab_hash {
+            Some(hash) => Some(self.get_value_serialized(hash)?),
+            None => None,
+        };
+        let change_coin = match change_coin {
+            Some(coin) => Some(self.get_value_serialized(coin)?),
+            None => None,
+        };
+        let memo = self.get_value_serialized(&entry.memo)?;
+
+        conn.execute(
+            "INSERT INTO tx_history
+            (direction, amount, token_id, counterparty, slab_index, slab_hash, timestamp,
+            change_coin, memo)
+            VALUES
+            (:direction, :amount, :token_id, :counterparty, :slab_index, :slab_hash, :timestamp,
+            :change_coin, :memo);",
+            named_params! {
+                ":direction": entry.direction.as_str(),
+                ":amount": entry.amount,
+                ":token_id": token_id,
+                ":counterparty": counterparty,
+                ":slab_index": entry.slab_index,
+                ":slab_hash": slab_hash,
+                ":timestamp": entry.timestamp,
+                ":change_coin": change_coin,
+                ":memo": memo,
+            },
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    pub fn set_tx_history_slab(
+        &self,
+        tx_id: i64,
+        slab_index: u64,
+        slab_hash: &SlabHash,
+    ) -> Result<()> {
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let slab_hash = self.get_value_serialized(slab_hash)?;
+        conn.execute(
+            "UPDATE tx_history SET slab_index = ?1, slab_hash = ?2 WHERE tx_id = ?3",
+            params![slab_index, slab_hash, tx_id],
+        )?;
+        Ok(())
+    }
+
+    /// Record where our transaction `tx_hash` is at. Confirmed is final, so
+    /// a late update from the sending side doesn't take it back.
+    pub fn set_tx_status(&self, tx_hash: &SlabHash, status: TxStatus) -> Result<()> {
+        debug!(target: "WALLETDB", "Set tx status {}", status.as_str());
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let tx_hash = self.get_value_serialized(tx_hash)?;
+        conn.execute(
+            "INSERT INTO tx_status(tx_hash, status, slab_index, updated)
+            VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT(tx_hash) DO UPDATE SET status = excluded.status,
+            slab_index = excluded.slab_index, updated = excluded.updated
+            WHERE tx_status.status != 'confirmed'",
+            params![tx_hash, status.as_str(), status.slab_index(), unix_timestamp()],
+        )?;
+        Ok(())
+    }
+
+    /// The status of our transaction `tx_hash` and when it was last updated
+    pub fn get_tx_status(&self, tx_hash: &SlabHash) -> Result<Option<(TxStatus, u64)>> {
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let tx_hash = self.get_value_serialized(tx_hash)?;
+        let mut stmt =
+            conn.prepare("SELECT status, slab_index, updated FROM tx_status WHERE tx_hash = ?1")?;
+        let mut rows = stmt.query_map(params![tx_hash], |row| {
+            let status: String = row.get(0)?;
+            Ok((TxStatus::from_row(&status, row.get(1)?), row.get(2)?))
+        })?;
+
+        match rows.next() {
+            Some(row) => Ok(Some(row?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Our transactions whose slab hasn't been seen yet
+    pub fn get_unconfirmed_txs(&self) -> Result<Vec<SlabHash>> {
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let mut stmt = conn.prepare("SELECT tx_hash FROM tx_status WHERE status != 'confirmed'")?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+
+        let mut txs = vec![];
+        for row in rows {
+            let tx_hash: Vec<u8> = row?;
+            txs.push(self.get_value_deserialized(&tx_hash)?);
+        }
+        Ok(txs)
+    }
+
+    /// Whether `entry` is already in the history. Entries not yet assigned
+    /// to a slab match any slab, since they may be waiting for its index.
+    pub fn has_tx_history(&self, entry: &TxHistory) -> Result<bool> {
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let token_id = self.get_value_serialized(&entry.token_id)?;
+        let counterparty = match &entry.counterparty {
+            Some(public) => Some(self.get_value_serialized(public)?),
+            None => None,
+        };
+        let slab_hash = match &entry.slab_hash {
+            Some(hash) => Some(self.get_value_serialized(hash)?),
+            None => None,
+        };
+
+        let mut stmt = conn.prepare(
+            "SELECT * FROM tx_history
+            WHERE direction = :direction AND amount = :amount AND token_id = :token_id
+            AND counterparty IS :counterparty
+            AND (slab_hash IS NULL OR slab_hash = :slab_hash)",
+        )?;
+        let exists = stmt.exists(named_params! {
+            ":direction": entry.direction.as_str(),
+            ":amount": entry.amount,
+            ":token_id": token_id,
+            ":counterparty": counterparty,
+            ":slab_hash": slab_hash,
+        })?;
+        Ok(exists)
+    }
+
+    pub fn is_change_coin(&self, coin: &Coin) -> Result<bool> {
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let coin = self.get_value_serialized(coin)?;
+        let mut stmt = conn.prepare("SELECT * FROM tx_history WHERE change_coin = ?")?;
+        let exists = stmt.exists(params![coin])?;
+        Ok(exists)
+    }
+
+    pub fn get_tx_history(&self) -> Result<Vec<TxHistory>> {
+        debug!(target: "WALLETDB", "Get tx history");
+
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let mut stmt = conn.prepare(
+            "SELECT direction, amount, token_id, counterparty, slab_index, slab_hash, timestamp,
+            memo FROM tx_history ORDER BY tx_id",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+                row.get(5)?,
+                row.get(6)?,
+                row.get(7)?,
+            ))
+        })?;
+
+        let mut history = vec![];
+        for row in rows {
+            let row: (
+                String,
+                u64,
+                Vec<u8>,
+                Option<Vec<u8>>,
+                Option<u64>,
+                Option<Vec<u8>>,
+                u64,
+                Vec<u8>,
+            ) = row?;
+
+            let direction = match row.0.as_str() {
+                "sent" => TxDirection::Sent,
+                _ => TxDirection::Received,
+            };
+            let counterparty = match row.3 {
+                Some(public) => Some(self.get_value_deserialized(&public)?),
+                None => None,
+            };
+            let slab_hash = match row.5 {
+                Some(hash) => Some(self.get_value_deserialized(&hash)?),
+                None => None,
+            };
+
+            history.push(TxHistory {
+                direction,
+                amount: row.1,
+                token_id: self.get_value_deserialized(&row.2)?,
+                counterparty,
+                slab_index: row.4,
+                slab_hash,
+                timestamp: row.6,
+                memo: self.get_value_deserialized(&row.7)?,
+            });
+        }
+
+        Ok(history)
+    }
+
     pub fn get_witnesses(&self) -> Result<HashMap<Vec<u8>, IncrementalWitness<MerkleNode>>> {
         let conn = Connection::open(&self.path)?;
-        conn.pragma_update(None, "key", &self.password)?;
+        conn.pragma_update(None, "key", &self.key)?;
 
         let is_spent = self.get_value_serialized(&false)?;
 
@@ -306,9 +1226,18 @@ impl WalletDb {
         debug!(target: "WALLETDB", "Updating witness");
 
         let conn = Connection::open(&self.path)?;
-        conn.pragma_update(None, "key", &self.password)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        self.set_witness(&conn, coin, &witness)
+    }
 
-        let witness = self.get_value_serialized(&witness)?;
+    fn set_witness(
+        &self,
+        conn: &Connection,
+        coin: &[u8],
+        witness: &IncrementalWitness<MerkleNode>,
+    ) -> Result<()> {
+        let witness = self.get_value_serialized(witness)?;
         let is_spent = self.get_value_serialized(&false)?;
 
         conn.execute(
@@ -322,7 +1251,7 @@ impl WalletDb {
     pub fn get_balances(&self) -> Result<Balances> {
         debug!(target: "WALLETDB", "Get token and balances...");
         let conn = Connection::open(&self.path)?;
-        conn.pragma_update(None, "key", &self.password)?;
+        conn.pragma_update(None, "key", &self.key)?;
 
         let is_spent = self.get_value_serialized(&false)?;
 
@@ -344,49 +1273,399 @@ impl WalletDb {
         Ok(balances)
     }
 
+    /// Watch the incoming payments of someone else's address
+    pub fn put_view_key(&self, view_key: &ViewingKey) -> Result<()> {
+        debug!(target: "WALLETDB", "Put view key");
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let view_key = self.get_value_serialized(view_key)?;
+        conn.execute(
+            "INSERT OR IGNORE INTO view_keys(view_key) VALUES (?1)",
+            params![view_key],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_view_keys(&self) -> Result<Vec<ViewingKey>> {
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let mut stmt = conn.prepare("SELECT view_key FROM view_keys")?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+
+        let mut view_keys = vec![];
+        for row in rows {
+            let row: Vec<u8> = row?;
+            view_keys.push(self.get_value_deserialized(&row)?);
+        }
+        Ok(view_keys)
+    }
+
+    pub fn put_watched_coin(&self, coin: &Coin, note: &Note) -> Result<()> {
+        debug!(target: "WALLETDB", "Put watched coin");
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let coin = self.get_value_serialized(coin)?;
+        let token_id = self.get_value_serialized(&note.token_id)?;
+        let memo = self.get_value_serialized(&note.memo)?;
+        conn.execute(
+            "INSERT OR REPLACE INTO watched_coins(coin, value, token_id, memo)
+            VALUES (?1, ?2, ?3, ?4)",
+            params![coin, note.value, token_id, memo],
+        )?;
+        Ok(())
+    }
+
+    /// Total received by the watched addresses. A viewing key can't tell
+    /// when a coin is spent, so spends aren't subtracted.
+    pub fn get_watched_balances(&self) -> Result<Balances> {
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let mut stmt = conn.prepare("SELECT value, token_id FROM watched_coins")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+        let mut balances = Balances { list: Vec::new() };
+        for row in rows {
+            let row: (u64, Vec<u8>) = row?;
+            let token_id: jubjub::Fr = self.get_value_deserialized(&row.1)?;
+            balances.add(&Balance {
+                token_id,
+                value: row.0,
+            });
+        }
+        Ok(balances)
+    }
+
     pub fn get_token_id(&self) -> Result<Vec<jubjub::Fr>> {
         debug!(target: "WALLETDB", "Get token ID...");
         let conn = Connection::open(&self.path)?;
-        conn.pragma_update(None, "key", &self.password)?;
+        conn.pragma_update(None, "key", &self.key)?;
 
         let is_spent = self.get_value_serialized(&false)?;
 
         let mut stmt = conn.prepare("SELECT token_id FROM coins WHERE is_spent = :is_spent ;")?;
         let rows = stmt.query_map(&[(":is_spent", &is_spent)], |row| row.get(0))?;
 
-        let mut token_ids = Vec::new();
+        let mut token_ids = Vec::new();
+        for row in rows {
+            let row = row?;
+            let token_id = self.get_value_deserialized(&row).unwrap();
+
+            token_ids.push(token_id);
+        }
+
+        Ok(token_ids)
+    }
+
+    pub fn token_id_exists(&self, token_id: &jubjub::Fr) -> Result<bool> {
+        debug!(target: "WALLETDB", "Check tokenID exists");
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let id = self.get_value_serialized(token_id)?;
+        let is_spent = self.get_value_serialized(&false)?;
+
+        let mut stmt = conn.prepare("SELECT * FROM coins WHERE token_id = ? AND is_spent = ? ;")?;
+        let id_check = stmt.exists(params![id, is_spent])?;
+        Ok(id_check)
+    }
+
+    // Freeze the wallet after a suspected key compromise. Transfers are
+    // refused, including ones about to be broadcast, until it is unlocked.
+    pub fn emergency_stop(&self, reason: &str) -> Result<()> {
+        warn!(target: "WALLETDB", "Emergency stop: {}", reason);
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let now = unix_timestamp();
+        if !self.is_frozen()? {
+            conn.execute("INSERT INTO freeze(frozen_at) VALUES (?1)", params![now])?;
+        }
+        self.put_audit_event(&conn, now, "emergency_stop", reason)
+    }
+
+    pub fn is_frozen(&self) -> Result<bool> {
+        Ok(self.get_frozen_at()?.is_some())
+    }
+
+    fn get_frozen_at(&self) -> Result<Option<u64>> {
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let frozen_at: Option<u64> =
+            conn.query_row("SELECT MIN(frozen_at) FROM freeze", [], |row| row.get(0))?;
+        Ok(frozen_at)
+    }
+
+    // Lift an emergency stop. It takes the wallet password, and the
+    // cooldown must have passed since the stop.
+    pub fn unlock(&self, passphrase: &str) -> Result<()> {
+        self.unlock_at(passphrase, unix_timestamp())
+    }
+
+    fn unlock_at(&self, passphrase: &str, now: u64) -> Result<()> {
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let frozen_at = match self.get_frozen_at()? {
+            Some(frozen_at) => frozen_at,
+            None => {
+                return Err(Error::from(ClientFailed::UnlockRefused(
+                    "Wallet is not frozen".to_string(),
+                )))
+            }
+        };
+
+        if passphrase != self.password {
+            self.put_audit_event(&conn, now, "unlock_refused", "wrong passphrase")?;
+            return Err(Error::from(ClientFailed::UnlockRefused(
+                "Wrong passphrase".to_string(),
+            )));
+        }
+
+        let unlocks_at = frozen_at + UNLOCK_COOLDOWN;
+        if now < unlocks_at {
+            self.put_audit_event(&conn, now, "unlock_refused", "cooldown")?;
+            return Err(Error::from(ClientFailed::UnlockRefused(format!(
+                "Cooldown ends in {} seconds",
+                unlocks_at - now
+            ))));
+        }
+
+        conn.execute("DELETE FROM freeze", [])?;
+        self.put_audit_event(&conn, now, "unlock", "")
+    }
+
+    fn put_audit_event(
+        &self,
+        conn: &Connection,
+        timestamp: u64,
+        event: &str,
+        detail: &str,
+    ) -> Result<()> {
+        conn.execute(
+            "INSERT INTO audit_log(timestamp, event, detail) VALUES (?1, ?2, ?3)",
+            params![timestamp, event, detail],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_audit_log(&self) -> Result<Vec<AuditEvent>> {
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let mut stmt =
+            conn.prepare("SELECT timestamp, event, detail FROM audit_log ORDER BY event_id")?;
+        let rows = stmt.query_map([], |row| {
+            Ok(AuditEvent {
+                timestamp: row.get(0)?,
+                event: row.get(1)?,
+                detail: row.get(2)?,
+            })
+        })?;
+
+        let mut events = vec![];
+        for event in rows {
+            events.push(event?);
+        }
+        Ok(events)
+    }
+
+    pub fn put_failed_operation(
+        &self,
+        method: &str,
+        params: &str,
+        reason: FailureReason,
+        detail: &str,
+    ) -> Result<i64> {
+        debug!(target: "WALLETDB", "Record failed {}: {}", method, detail);
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        conn.execute(
+            "INSERT INTO failed_operations(timestamp, method, params, reason, detail)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![unix_timestamp(), method, params, reason.as_str(), detail],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    pub fn get_failed_operations(&self) -> Result<Vec<FailedOperation>> {
+        self.query_failed_operations(None)
+    }
+
+    pub fn get_failed_operation(&self, id: i64) -> Result<Option<FailedOperation>> {
+        Ok(self.query_failed_operations(Some(id))?.pop())
+    }
+
+    fn query_failed_operations(&self, id: Option<i64>) -> Result<Vec<FailedOperation>> {
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let mut stmt = conn.prepare(
+            "SELECT op_id, timestamp, method, params, reason, detail, retried
+            FROM failed_operations WHERE ?1 IS NULL OR op_id = ?1 ORDER BY op_id",
+        )?;
+        let rows = stmt.query_map(params![id], |row| {
+            let reason: String = row.get(4)?;
+            Ok(FailedOperation {
+                id: row.get(0)?,
+                timestamp: row.get(1)?,
+                method: row.get(2)?,
+                params: row.get(3)?,
+                reason: FailureReason::from_str(&reason),
+                detail: row.get(5)?,
+                retried: row.get(6)?,
+            })
+        })?;
+
+        let mut ops = vec![];
+        for op in rows {
+            ops.push(op?);
+        }
+        Ok(ops)
+    }
+
+    pub fn set_failed_operation_retried(&self, id: i64) -> Result<()> {
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        conn.execute(
+            "UPDATE failed_operations SET retried = 1 WHERE op_id = ?1",
+            params![id],
+        )?;
+        Ok(())
+    }
+
+    /// Add a contact to the address book, or replace the one of that name
+    pub fn put_contact(&self, contact: &Contact) -> Result<()> {
+        debug!(target: "WALLETDB", "Put contact {}", contact.name);
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let public = self.get_value_serialized(&contact.public)?;
+        conn.execute(
+            "INSERT OR REPLACE INTO contacts(name, address, key_public, payment_limit)
+            VALUES (?1, ?2, ?3, ?4)",
+            params![contact.name, contact.address, public, contact.limit],
+        )?;
+        Ok(())
+    }
+
+    /// Returns whether there was a contact of that name
+    pub fn remove_contact(&self, name: &str) -> Result<bool> {
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let removed = conn.execute("DELETE FROM contacts WHERE name = ?1", params![name])?;
+        Ok(removed > 0)
+    }
+
+    pub fn get_contacts(&self) -> Result<Vec<Contact>> {
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+
+        let mut stmt = conn.prepare(
+            "SELECT name, address, key_public, payment_limit FROM contacts ORDER BY name",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })?;
+
+        let mut contacts = vec![];
         for row in rows {
-            let row = row?;
-            let token_id = self.get_value_deserialized(&row).unwrap();
-
-            token_ids.push(token_id);
+            let (name, address, public, limit): (String, String, Vec<u8>, Option<u64>) = row?;
+            contacts.push(Contact {
+                name,
+                address,
+                public: self.get_value_deserialized(&public)?,
+                limit,
+            });
         }
+        Ok(contacts)
+    }
 
-        Ok(token_ids)
+    /// The contact paid through the key `public`, if any
+    pub fn get_contact(&self, public: &jubjub::SubgroupPoint) -> Result<Option<Contact>> {
+        Ok(self
+            .get_contacts()?
+            .into_iter()
+            .find(|contact| contact.public == *public))
     }
 
-    pub fn token_id_exists(&self, token_id: &jubjub::Fr) -> Result<bool> {
-        debug!(target: "WALLETDB", "Check tokenID exists");
+    /// Whether the wallet ever sent a payment to the key `public`
+    pub fn has_paid(&self, public: &jubjub::SubgroupPoint) -> Result<bool> {
         let conn = Connection::open(&self.path)?;
-        conn.pragma_update(None, "key", &self.password)?;
+        conn.pragma_update(None, "key", &self.key)?;
 
-        let id = self.get_value_serialized(token_id)?;
-        let is_spent = self.get_value_serialized(&false)?;
+        let public = self.get_value_serialized(public)?;
+        let mut stmt =
+            conn.prepare("SELECT * FROM tx_history WHERE direction = ?1 AND counterparty = ?2")?;
+        let exists = stmt.exists(params![TxDirection::Sent.as_str(), public])?;
+        Ok(exists)
+    }
 
-        let mut stmt = conn.prepare("SELECT * FROM coins WHERE token_id = ? AND is_spent = ? ;")?;
-        let id_check = stmt.exists(params![id, is_spent])?;
-        Ok(id_check)
+    /// Write a consistent copy of the wallet to `path`, encrypted with the
+    /// wallet password like the wallet itself
+    pub fn archive(&self, path: &Path) -> Result<()> {
+        debug!(target: "WALLETDB", "Archive wallet to {:?}", path);
+        let archive = path
+            .to_str()
+            .ok_or(Error::ParseFailed("Archive path is not valid UTF-8"))?;
+
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.key)?;
+        conn.execute(
+            "ATTACH DATABASE ?1 AS archive KEY ?2",
+            params![archive, self.password],
+        )?;
+        conn.query_row("SELECT sqlcipher_export('archive')", [], |_| Ok(()))?;
+        conn.execute("DETACH DATABASE archive", [])?;
+        Ok(())
     }
 
     pub fn test_wallet(&self) -> Result<()> {
         let conn = Connection::open(&self.path)?;
-        conn.pragma_update(None, "key", &self.password)?;
+        conn.pragma_update(None, "key", &self.key)?;
         let mut stmt = conn.prepare("SELECT * FROM keys")?;
         let _rows = stmt.query([])?;
         Ok(())
     }
 }
 
+// The ID a wallet's secret is kept under in a key store: the random salt
+// SQLCipher writes in the clear at the start of the database. It stays the
+// same when the database is rekeyed, moved or renamed.
+fn wallet_id(path: &Path) -> Result<String> {
+    let mut salt = [0u8; WALLET_SALT_SIZE];
+    std::fs::File::open(path)?.read_exact(&mut salt)?;
+    Ok(hex::encode(salt))
+}
+
+// Whether the database at `path` can be read with `key`
+fn opens_with(path: &Path, key: &str) -> bool {
+    let conn = match Connection::open(path) {
+        Ok(conn) => conn,
+        Err(_) => return false,
+    };
+    conn.pragma_update(None, "key", &key).is_ok()
+        && conn
+            .query_row("SELECT count(*) FROM sqlite_master", [], |row| {
+                row.get::<_, i64>(0)
+            })
+            .is_ok()
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -394,6 +1673,47 @@ mod tests {
     use crate::crypto::{coin::Coin, OwnCoin};
     use crate::util::join_config_path;
     use ff::PrimeField;
+    use std::sync::Mutex as SyncMutex;
+
+    // A key store that keeps its secrets in memory
+    #[derive(Default)]
+    struct MemoryEnclave {
+        secrets: SyncMutex<HashMap<String, Vec<u8>>>,
+    }
+
+    impl KeyEnclave for MemoryEnclave {
+        fn name(&self) -> &'static str {
+            "memory"
+        }
+
+        fn load(&self, id: &str) -> Result<Option<Vec<u8>>> {
+            Ok(self.secrets.lock().unwrap().get(id).cloned())
+        }
+
+        fn store(&self, id: &str, secret: &[u8]) -> Result<()> {
+            self.secrets
+                .lock()
+                .unwrap()
+                .insert(id.to_string(), secret.to_vec());
+            Ok(())
+        }
+    }
+
+    struct BrokenEnclave;
+
+    impl KeyEnclave for BrokenEnclave {
+        fn name(&self) -> &'static str {
+            "broken"
+        }
+
+        fn load(&self, _id: &str) -> Result<Option<Vec<u8>>> {
+            Err(Error::EnclaveFailed("locked".into()))
+        }
+
+        fn store(&self, _id: &str, _secret: &[u8]) -> Result<()> {
+            Err(Error::EnclaveFailed("locked".into()))
+        }
+    }
 
     pub fn init_db(path: &PathBuf, password: String) -> Result<()> {
         if !password.trim().is_empty() {
@@ -431,6 +1751,7 @@ mod tests {
             token_id,
             coin_blind: jubjub::Fr::random(&mut OsRng),
             valcom_blind: jubjub::Fr::random(&mut OsRng),
+            memo: Memo::empty(),
         };
 
         let coin = Coin::new(bls12_381::Scalar::random(&mut OsRng).to_repr());
@@ -486,6 +1807,7 @@ mod tests {
             token_id,
             coin_blind: jubjub::Fr::random(&mut OsRng),
             valcom_blind: jubjub::Fr::random(&mut OsRng),
+            memo: Memo::empty(),
         };
 
         let coin = Coin::new(bls12_381::Scalar::random(&mut OsRng).to_repr());
@@ -540,6 +1862,230 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    pub fn test_key_gen_multiple_keypairs() -> Result<()> {
+        let walletdb_path = std::env::temp_dir().join("darkfi_test6_wallet.db");
+        let password: String = "darkfi".into();
+        let wallet = WalletDb::new(&walletdb_path, password.clone())?;
+        init_db(&walletdb_path, password)?;
+
+        wallet.key_gen()?;
+        wallet.key_gen()?;
+        wallet.key_gen()?;
+
+        let keypairs = wallet.get_keypairs()?;
+        let private_keys = wallet.get_private_keys()?;
+
+        assert_eq!(keypairs.len(), 3);
+        assert_eq!(private_keys.len(), 3);
+        assert_ne!(keypairs[0].public, keypairs[1].public);
+
+        for (keypair, private) in keypairs.iter().zip(private_keys.iter()) {
+            assert_eq!(keypair.private, *private);
+        }
+
+        std::fs::remove_file(walletdb_path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_seed_gen_and_restore() -> Result<()> {
+        let walletdb_path = std::env::temp_dir().join("darkfi_test7_wallet.db");
+        let password: String = "darkfi".into();
+        let wallet = WalletDb::new(&walletdb_path, password.clone())?;
+        init_db(&walletdb_path, password)?;
+
+        let phrase = wallet.seed_gen()?;
+        assert_eq!(wallet.get_mnemonic()?, Some(phrase.clone()));
+        assert!(wallet.seed_gen().is_err());
+
+        let keypair = wallet.get_keypairs()?[0].clone();
+
+        wallet.key_gen()?;
+        assert_eq!(wallet.get_keypairs()?.len(), 2);
+
+        assert!(wallet.restore_mnemonic("not a valid mnemonic").is_err());
+        assert_eq!(wallet.get_keypairs()?.len(), 2);
+
+        wallet.restore_mnemonic(&phrase)?;
+        let keypairs = wallet.get_keypairs()?;
+        assert_eq!(keypairs.len(), 1);
+        assert_eq!(keypairs[0].public, keypair.public);
+        assert_eq!(keypairs[0].private, keypair.private);
+
+        std::fs::remove_file(walletdb_path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_derive_keypairs() -> Result<()> {
+        let walletdb_path = std::env::temp_dir().join("darkfi_test8_wallet.db");
+        let password: String = "darkfi".into();
+        let wallet = WalletDb::new(&walletdb_path, password.clone())?;
+        init_db(&walletdb_path, password)?;
+
+        assert!(wallet.derive_keypair(DEFAULT_ACCOUNT).is_err());
+
+        let phrase = wallet.seed_gen()?;
+        assert_eq!(wallet.get_next_key_index(DEFAULT_ACCOUNT)?, 1);
+
+        let keypair = wallet.derive_keypair(DEFAULT_ACCOUNT)?;
+        wallet.key_gen()?;
+        assert_eq!(wallet.get_next_key_index(DEFAULT_ACCOUNT)?, 3);
+        assert_eq!(wallet.get_next_key_index(1)?, 0);
+
+        let keypairs = wallet.get_keypairs()?;
+        assert_eq!(keypairs.len(), 3);
+        assert_eq!(keypairs[1].public, keypair.public);
+        assert_ne!(keypairs[1].public, keypairs[2].public);
+
+        let seed = mnemonic::mnemonic_to_seed(&phrase)?;
+        let xsk = ExtendedSpendingKey::from_seed(&seed, DEFAULT_ACCOUNT, 1);
+        assert_eq!(xsk.secret(), keypair.private);
+
+        std::fs::remove_file(walletdb_path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_watched_coins() -> Result<()> {
+        let walletdb_path = std::env::temp_dir().join("darkfi_test10_wallet.db");
+        let password: String = "darkfi".into();
+        let wallet = WalletDb::new(&walletdb_path, password.clone())?;
+        init_db(&walletdb_path, password)?;
+
+        let view_key = ViewingKey::from_secret(&jubjub::Fr::random(&mut OsRng));
+        wallet.put_view_key(&view_key)?;
+        wallet.put_view_key(&view_key)?;
+        assert_eq!(wallet.get_view_keys()?, vec![view_key]);
+
+        let token_id = jubjub::Fr::random(&mut OsRng);
+        for (i, value) in [110, 50].iter().enumerate() {
+            let note = Note {
+                serial: jubjub::Fr::random(&mut OsRng),
+                value: *value,
+                token_id,
+                coin_blind: jubjub::Fr::random(&mut OsRng),
+                valcom_blind: jubjub::Fr::random(&mut OsRng),
+                memo: Memo::empty(),
+            };
+            wallet.put_watched_coin(&Coin::new([i as u8; 32]), &note)?;
+        }
+
+        let balances = wallet.get_watched_balances()?;
+        assert_eq!(balances.list.len(), 1);
+        assert_eq!(balances.list[0].value, 160);
+        assert!(wallet.get_balances()?.list.is_empty());
+
+        std::fs::remove_file(walletdb_path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_diversifiers() -> Result<()> {
+        let walletdb_path = std::env::temp_dir().join("darkfi_test11_wallet.db");
+        let password: String = "darkfi".into();
+        let wallet = WalletDb::new(&walletdb_path, password.clone())?;
+        init_db(&walletdb_path, password)?;
+
+        wallet.key_gen()?;
+        let keypair = wallet.get_keypairs()?[0].clone();
+        assert_eq!(wallet.get_private_keys()?, vec![keypair.private]);
+
+        let d1 = wallet.new_diversifier(&keypair)?;
+        let d2 = wallet.new_diversifier(&keypair)?;
+        assert_eq!(d1, Diversifier::derive(&keypair.private, 0));
+        assert_eq!(d2, Diversifier::derive(&keypair.private, 1));
+
+        let diversifiers = wallet.get_diversifiers(&keypair.public)?;
+        assert_eq!(diversifiers.len(), 2);
+        assert!(diversifiers.contains(&d1) && diversifiers.contains(&d2));
+
+        // The keys owning the diversified addresses are scanned with the rest
+        let private_keys = wallet.get_private_keys()?;
+        assert_eq!(private_keys.len(), 3);
+        assert!(private_keys.contains(&d1.secret(&keypair.private)));
+        assert!(private_keys.contains(&d2.secret(&keypair.private)));
+
+        wallet.set_address_label(&d2, "alice")?;
+        let labeled = wallet.get_labeled_diversifiers(&keypair.public)?;
+        assert_eq!(labeled, vec![(d1, None), (d2, Some("alice".to_string()))]);
+
+        std::fs::remove_file(walletdb_path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_tx_history() -> Result<()> {
+        let walletdb_path = std::env::temp_dir().join("darkfi_test9_wallet.db");
+        let password: String = "darkfi".into();
+        let wallet = WalletDb::new(&walletdb_path, password.clone())?;
+        init_db(&walletdb_path, password)?;
+
+        let token_id = jubjub::Fr::random(&mut OsRng);
+        let secret = jubjub::Fr::random(&mut OsRng);
+        let public = zcash_primitives::constants::SPENDING_KEY_GENERATOR * secret;
+        let change_coin = Coin::new([7; 32]);
+
+        let sent = TxHistory {
+            direction: TxDirection::Sent,
+            amount: 110,
+            token_id,
+            counterparty: Some(public),
+            slab_index: None,
+            slab_hash: None,
+            timestamp: 1337,
+            memo: "invoice #42".parse()?,
+        };
+        let tx_id = wallet.put_tx_history(&sent, Some(&change_coin))?;
+        wallet.set_tx_history_slab(tx_id, 4, &[4; 32])?;
+
+        let received = TxHistory {
+            direction: TxDirection::Received,
+            amount: 50,
+            token_id,
+            counterparty: None,
+            slab_index: Some(5),
+            slab_hash: Some([5; 32]),
+            timestamp: 1338,
+            memo: Memo::empty(),
+        };
+        wallet.put_tx_history(&received, None)?;
+
+        assert!(wallet.is_change_coin(&change_coin)?);
+        assert!(!wallet.is_change_coin(&Coin::new([8; 32]))?);
+
+        // The same payment seen again in its slab is already known
+        let mut recovered = TxHistory {
+            slab_index: Some(4),
+            slab_hash: Some([4; 32]),
+            ..sent
+        };
+        assert!(wallet.has_tx_history(&recovered)?);
+        recovered.slab_hash = Some([6; 32]);
+        assert!(!wallet.has_tx_history(&recovered)?);
+
+        let history = wallet.get_tx_history()?;
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].direction, TxDirection::Sent);
+        assert_eq!(history[0].slab_index, Some(4));
+        assert_eq!(history[0].slab_hash, Some([4; 32]));
+        assert_eq!(history[0].counterparty, Some(public));
+        assert_eq!(history[0].memo.to_string(), "invoice #42");
+        assert_eq!(history[1].direction, TxDirection::Received);
+        assert_eq!(history[1].amount, 50);
+        assert_eq!(history[1].counterparty, None);
+
+        std::fs::remove_file(walletdb_path)?;
+
+        Ok(())
+    }
+
     #[test]
     pub fn test_put_and_get_own_coins() -> Result<()> {
         let walletdb_path = join_config_path(&PathBuf::from("test4_wallet.db"))?;
@@ -558,6 +2104,7 @@ mod tests {
             token_id: jubjub::Fr::random(&mut OsRng),
             coin_blind: jubjub::Fr::random(&mut OsRng),
             valcom_blind: jubjub::Fr::random(&mut OsRng),
+            memo: "invoice #42".parse()?,
         };
 
         let coin = Coin::new(bls12_381::Scalar::random(&mut OsRng).to_repr());
@@ -579,6 +2126,7 @@ mod tests {
 
         assert_eq!(&own_coin.note.valcom_blind, &note.valcom_blind);
         assert_eq!(&own_coin.note.coin_blind, &note.coin_blind);
+        assert_eq!(own_coin.note.memo, note.memo);
         assert_eq!(own_coin.secret, secret);
         assert_eq!(own_coin.witness.root(), witness.root());
         assert_eq!(own_coin.witness.path(), witness.path());
@@ -614,6 +2162,7 @@ mod tests {
             token_id: jubjub::Fr::random(&mut OsRng),
             coin_blind: jubjub::Fr::random(&mut OsRng),
             valcom_blind: jubjub::Fr::random(&mut OsRng),
+            memo: Memo::empty(),
         };
 
         let coin = Coin::new(bls12_381::Scalar::random(&mut OsRng).to_repr());
@@ -656,4 +2205,333 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    pub fn test_put_slab_coins() -> Result<()> {
+        let walletdb_path = std::env::temp_dir().join("darkfi_test_put_slab_coins.db");
+        let _ = std::fs::remove_file(&walletdb_path);
+        let password: String = "darkfi".into();
+        let wallet = WalletDb::new(&walletdb_path, password.clone())?;
+        init_db(&walletdb_path, password)?;
+
+        let secret: jubjub::Fr = jubjub::Fr::random(&mut OsRng);
+        let token_id = jubjub::Fr::random(&mut OsRng);
+        let mut tree = crate::crypto::merkle::CommitmentTree::empty();
+        let mut own_coin = |value| -> Result<OwnCoin> {
+            let coin = Coin::new(bls12_381::Scalar::random(&mut OsRng).to_repr());
+            tree.append(MerkleNode::from_coin(&coin))?;
+            Ok(OwnCoin {
+                coin,
+                note: Note {
+                    serial: jubjub::Fr::random(&mut OsRng),
+                    value,
+                    token_id,
+                    coin_blind: jubjub::Fr::random(&mut OsRng),
+                    valcom_blind: jubjub::Fr::random(&mut OsRng),
+                    memo: Memo::empty(),
+                },
+                secret,
+                witness: IncrementalWitness::from_tree(&tree),
+            })
+        };
+
+        let first = own_coin(10)?;
+        wallet.put_own_coins(first.clone())?;
+
+        // A slab sends us a second coin, after which the first's witness
+        // has to move on too
+        let mut second = own_coin(20)?;
+        let mut witness = first.witness.clone();
+        witness.append(MerkleNode::from_coin(&second.coin))?;
+        let other = Coin::new(bls12_381::Scalar::random(&mut OsRng).to_repr());
+        let node = MerkleNode::from_coin(&other);
+        witness.append(node)?;
+        second.witness.append(node)?;
+        tree.append(node)?;
+
+        let mut witnesses = HashMap::new();
+        witnesses.insert(serial::serialize(&first.coin.repr), witness);
+        witnesses.insert(serial::serialize(&second.coin.repr), second.witness.clone());
+        wallet.put_slab_coins(vec![second], &witnesses)?;
+
+        let witnesses = wallet.get_witnesses()?;
+        assert_eq!(witnesses.len(), 2);
+        for witness in witnesses.values() {
+            assert_eq!(witness.root(), tree.root());
+        }
+        assert_eq!(wallet.get_balances()?.list[0].value, 30);
+
+        std::fs::remove_file(walletdb_path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_emergency_stop() -> Result<()> {
+        let walletdb_path = std::env::temp_dir().join("darkfi_test12_wallet.db");
+        let password: String = "darkfi".into();
+        let wallet = WalletDb::new(&walletdb_path, password.clone())?;
+        init_db(&walletdb_path, password)?;
+
+        assert!(!wallet.is_frozen()?);
+        assert!(wallet.unlock("darkfi").is_err());
+
+        wallet.emergency_stop("lost laptop")?;
+        assert!(wallet.is_frozen()?);
+        let frozen_at = wallet.get_frozen_at()?.unwrap();
+
+        // Stopping again keeps the cooldown running from the first stop
+        wallet.emergency_stop("again")?;
+        assert_eq!(wallet.get_frozen_at()?, Some(frozen_at));
+
+        assert!(wallet
+            .unlock_at("wrong", frozen_at + UNLOCK_COOLDOWN)
+            .is_err());
+        assert!(wallet
+            .unlock_at("darkfi", frozen_at + UNLOCK_COOLDOWN - 1)
+            .is_err());
+        assert!(wallet.is_frozen()?);
+
+        wallet.unlock_at("darkfi", frozen_at + UNLOCK_COOLDOWN)?;
+        assert!(!wallet.is_frozen()?);
+
+        let events: Vec<(String, String)> = wallet
+            .get_audit_log()?
+            .into_iter()
+            .map(|e| (e.event, e.detail))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                ("emergency_stop".to_string(), "lost laptop".to_string()),
+                ("emergency_stop".to_string(), "again".to_string()),
+                ("unlock_refused".to_string(), "wrong passphrase".to_string()),
+                ("unlock_refused".to_string(), "cooldown".to_string()),
+                ("unlock".to_string(), "".to_string()),
+            ]
+        );
+
+        std::fs::remove_file(walletdb_path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_archive() -> Result<()> {
+        let walletdb_path = std::env::temp_dir().join("darkfi_test13_wallet.db");
+        let archive_path = std::env::temp_dir().join("darkfi_test13_archive.db");
+        let password: String = "darkfi".into();
+        let wallet = WalletDb::new(&walletdb_path, password.clone())?;
+        init_db(&walletdb_path, password.clone())?;
+
+        let secret: jubjub::Fr = jubjub::Fr::random(&mut OsRng);
+        let public = zcash_primitives::constants::SPENDING_KEY_GENERATOR * secret;
+        wallet.put_keypair(&public, &secret)?;
+
+        wallet.archive(&archive_path)?;
+
+        // The archive opens with the wallet password and nothing else
+        let archive = WalletDb::new(&archive_path, password)?;
+        let keypairs = archive.get_keypairs()?;
+        assert_eq!(keypairs.len(), 1);
+        assert_eq!(keypairs[0].private, secret);
+        assert!(WalletDb::new(&archive_path, "wrong".into())?
+            .get_keypairs()
+            .is_err());
+
+        std::fs::remove_file(walletdb_path)?;
+        std::fs::remove_file(archive_path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_failed_operations() -> Result<()> {
+        let walletdb_path = std::env::temp_dir().join("darkfi_test14_wallet.db");
+        let password: String = "darkfi".into();
+        let wallet = WalletDb::new(&walletdb_path, password.clone())?;
+        init_db(&walletdb_path, password)?;
+
+        let params = r#"["DRK","addr","1.5"]"#;
+        let first = wallet.put_failed_operation(
+            "transfer",
+            params,
+            FailureReason::InsufficientFunds,
+            "Not enough value 100",
+        )?;
+        let second = wallet.put_failed_operation(
+            "transfer_many",
+            "[]",
+            FailureReason::GatewayRejected,
+            "timeout",
+        )?;
+
+        let ops = wallet.get_failed_operations()?;
+        assert_eq!(ops.len(), 2);
+        assert_eq!(ops[0].id, first);
+        assert_eq!(ops[0].method, "transfer");
+        assert_eq!(ops[0].params, params);
+        assert_eq!(ops[0].reason, FailureReason::InsufficientFunds);
+        assert!(!ops[0].retried);
+        assert_eq!(ops[1].reason, FailureReason::GatewayRejected);
+        assert_eq!(ops[1].to_json()["retryable"], false);
+
+        wallet.set_failed_operation_retried(first)?;
+        let op = wallet.get_failed_operation(first)?.unwrap();
+        assert!(op.retried);
+        assert_eq!(op.to_json()["retryable"], false);
+        assert_eq!(wallet.get_failed_operation(second)?.unwrap().id, second);
+        assert!(wallet.get_failed_operation(second + 1)?.is_none());
+
+        std::fs::remove_file(walletdb_path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_tx_status() -> Result<()> {
+        let walletdb_path = std::env::temp_dir().join("darkfi_test17_wallet.db");
+        let password: String = "darkfi".into();
+        let wallet = WalletDb::new(&walletdb_path, password.clone())?;
+        init_db(&walletdb_path, password)?;
+
+        assert!(wallet.get_tx_status(&[1; 32])?.is_none());
+
+        wallet.set_tx_status(&[1; 32], TxStatus::Pending)?;
+        wallet.set_tx_status(&[2; 32], TxStatus::Pending)?;
+        wallet.set_tx_status(&[1; 32], TxStatus::Published)?;
+        assert_eq!(
+            wallet.get_tx_status(&[1; 32])?.map(|(status, _)| status),
+            Some(TxStatus::Published)
+        );
+
+        wallet.set_tx_status(&[2; 32], TxStatus::Confirmed(9))?;
+        assert_eq!(wallet.get_unconfirmed_txs()?, vec![[1; 32]]);
+
+        // A confirmation isn't taken back, e.g. by the sender marking the
+        // transaction published after its slab already came back
+        wallet.set_tx_status(&[2; 32], TxStatus::Published)?;
+        assert_eq!(
+            wallet.get_tx_status(&[2; 32])?.map(|(status, _)| status),
+            Some(TxStatus::Confirmed(9))
+        );
+
+        std::fs::remove_file(walletdb_path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_contacts() -> Result<()> {
+        let walletdb_path = std::env::temp_dir().join("darkfi_test16_wallet.db");
+        let password: String = "darkfi".into();
+        let wallet = WalletDb::new(&walletdb_path, password.clone())?;
+        init_db(&walletdb_path, password)?;
+
+        let secret: jubjub::Fr = jubjub::Fr::random(&mut OsRng);
+        let public = zcash_primitives::constants::SPENDING_KEY_GENERATOR * secret;
+        let contact = Contact {
+            name: "cold wallet".to_string(),
+            address: "tdrk1cold".to_string(),
+            public,
+            limit: None,
+        };
+        wallet.put_contact(&contact)?;
+        assert_eq!(wallet.get_contacts()?, vec![contact.clone()]);
+        assert_eq!(wallet.get_contact(&public)?, Some(contact.clone()));
+
+        // Putting it again under the same name changes it
+        let capped = Contact {
+            limit: Some(500),
+            ..contact
+        };
+        wallet.put_contact(&capped)?;
+        assert_eq!(wallet.get_contacts()?, vec![capped]);
+
+        assert!(!wallet.has_paid(&public)?);
+        let entry = TxHistory {
+            direction: TxDirection::Sent,
+            amount: 10,
+            token_id: jubjub::Fr::random(&mut OsRng),
+            counterparty: Some(public),
+            slab_index: None,
+            slab_hash: None,
+            timestamp: 0,
+            memo: Memo::empty(),
+        };
+        wallet.put_tx_history(&entry, None)?;
+        assert!(wallet.has_paid(&public)?);
+
+        assert!(wallet.remove_contact("cold wallet")?);
+        assert!(!wallet.remove_contact("cold wallet")?);
+        assert_eq!(wallet.get_contact(&public)?, None);
+
+        std::fs::remove_file(walletdb_path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_sealed_wallet() -> Result<()> {
+        let walletdb_path = std::env::temp_dir().join("darkfi_test15_wallet.db");
+        let password: String = "darkfi".into();
+        let wallet = WalletDb::new(&walletdb_path, password.clone())?;
+        init_db(&walletdb_path, password.clone())?;
+
+        let secret: jubjub::Fr = jubjub::Fr::random(&mut OsRng);
+        let public = zcash_primitives::constants::SPENDING_KEY_GENERATOR * secret;
+        wallet.put_keypair(&public, &secret)?;
+
+        // Binding rekeys the existing wallet
+        let enclave = MemoryEnclave::default();
+        let sealed = WalletDb::new_sealed(&walletdb_path, password.clone(), &enclave)?;
+        assert_ne!(sealed.key, password);
+        assert_eq!(sealed.get_keypairs()?[0].private, secret);
+
+        // The password alone no longer opens it
+        assert!(WalletDb::new(&walletdb_path, password.clone())?
+            .get_keypairs()
+            .is_err());
+
+        let reopened = WalletDb::new_sealed(&walletdb_path, password.clone(), &enclave)?;
+        assert_eq!(reopened.key, sealed.key);
+        assert_eq!(reopened.get_keypairs()?[0].private, secret);
+
+        // Nor does the password with another machine's key store
+        let elsewhere =
+            WalletDb::new_sealed(&walletdb_path, password.clone(), &MemoryEnclave::default())?;
+        assert!(elsewhere.get_keypairs().is_err());
+
+        // The binding follows the wallet when it's moved
+        let moved_path = std::env::temp_dir().join("darkfi_test15_wallet_moved.db");
+        std::fs::rename(&walletdb_path, &moved_path)?;
+        let moved = WalletDb::new_sealed(&moved_path, password.clone(), &enclave)?;
+        assert_eq!(moved.key, sealed.key);
+        assert_eq!(moved.get_keypairs()?[0].private, secret);
+        std::fs::remove_file(&moved_path)?;
+
+        // A wallet bound under its path is bound under its ID instead
+        init_db(&walletdb_path, password.clone())?;
+        let legacy = MemoryEnclave::default();
+        let legacy_secret = new_secret();
+        legacy.store(&walletdb_path.to_string_lossy(), &legacy_secret)?;
+        let legacy_key = wallet_key(&password, &legacy_secret);
+        let conn = Connection::open(&walletdb_path)?;
+        conn.pragma_update(None, "key", &password)?;
+        conn.pragma_update(None, "rekey", &legacy_key)?;
+        drop(conn);
+        let rebound = WalletDb::new_sealed(&walletdb_path, password.clone(), &legacy)?;
+        assert_eq!(rebound.key, legacy_key);
+        assert_eq!(legacy.load(&wallet_id(&walletdb_path)?)?, Some(legacy_secret));
+
+        std::fs::remove_file(&walletdb_path)?;
+
+        // A key store that can't be used leaves the password alone
+        let fallback = WalletDb::new_sealed(&walletdb_path, password.clone(), &BrokenEnclave)?;
+        assert_eq!(fallback.key, password);
+
+        std::fs::remove_file(&walletdb_path)?;
+
+        Ok(())
+    }
 }